@@ -0,0 +1,137 @@
+use crate::{AudioFormat, AudioIoError, Result};
+use std::path::Path;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::CODEC_TYPE_NULL;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::probe::Hint;
+
+impl From<SymphoniaError> for AudioIoError {
+    fn from(e: SymphoniaError) -> Self {
+        match e {
+            SymphoniaError::IoError(e) => Self::Io(e),
+            SymphoniaError::Unsupported(what) => Self::Unsupported(format!("unsupported {what}")),
+            e => Self::Decode(e.to_string()),
+        }
+    }
+}
+
+/// Decode an audio file to mono `f32` samples, multi-channel audio is downmixed. Returns the
+/// samples and their sample rate.
+pub fn decode(path: impl AsRef<Path>) -> Result<(Vec<f32>, u32)> {
+    let path = path.as_ref();
+    let file = std::fs::File::open(path)?;
+    decode_source(Box::new(file), AudioFormat::from_path(path))
+}
+
+/// Like [`decode`] for an in-memory file, `format` is a hint for the probe.
+pub fn decode_bytes(bytes: Vec<u8>, format: Option<AudioFormat>) -> Result<(Vec<f32>, u32)> {
+    decode_source(Box::new(std::io::Cursor::new(bytes)), format)
+}
+
+fn decode_source(
+    src: Box<dyn MediaSource>,
+    format: Option<AudioFormat>,
+) -> Result<(Vec<f32>, u32)> {
+    let mss = MediaSourceStream::new(src, Default::default());
+    let mut hint = Hint::new();
+    if let Some(format) = format {
+        hint.with_extension(format.extension());
+    }
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        mss,
+        &Default::default(),
+        &Default::default(),
+    )?;
+    let mut reader = probed.format;
+    let track = reader
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| AudioIoError::Decode("no audio track".to_string()))?;
+    let track_id = track.id;
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .ok_or_else(|| AudioIoError::Decode("unknown sample rate".to_string()))?;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &Default::default())?;
+
+    let mut pcm = Vec::new();
+    let mut buf: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match reader.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break;
+            }
+            Err(SymphoniaError::ResetRequired) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupted packet is skipped, as players do.
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let spec = *decoded.spec();
+        let channels = spec.channels.count().max(1);
+        let buf = match &mut buf {
+            Some(buf) if buf.capacity() >= decoded.capacity() * channels => buf,
+            buf => buf.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buf.copy_interleaved_ref(decoded);
+        if channels == 1 {
+            pcm.extend_from_slice(buf.samples());
+        } else {
+            let scale = 1.0 / channels as f32;
+            pcm.extend(
+                buf.samples()
+                    .chunks(channels)
+                    .map(|f| f.iter().sum::<f32>() * scale),
+            );
+        }
+    }
+    Ok((pcm, sample_rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stereo_wav_is_downmixed() {
+        let path = crate::tests::tmp_path("stereo.wav");
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 16000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut w = hound::WavWriter::create(&path, spec).unwrap();
+        for _ in 0..100 {
+            w.write_sample(16384i16).unwrap();
+            w.write_sample(0i16).unwrap();
+        }
+        w.finalize().unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let (pcm, sample_rate) = decode_bytes(bytes, None).unwrap();
+        assert_eq!(sample_rate, 16000);
+        assert_eq!(pcm.len(), 100);
+        assert!(pcm.iter().all(|v| (v - 0.25).abs() < 1e-4));
+    }
+
+    #[test]
+    fn garbage_is_an_error() {
+        assert!(decode_bytes(b"not an audio file".to_vec(), None).is_err());
+        assert!(matches!(
+            decode("/nonexistent/file.wav"),
+            Err(AudioIoError::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,356 @@
+//! Streaming FLAC encoder for mono 16-bit audio.
+//!
+//! Each block of 4096 samples is coded with the fixed predictor (orders 0 to 4) that gives
+//! the shortest Rice coded residual, or verbatim when prediction does not help. This gets
+//! most of the compression of the reference encoder on speech without the LPC analysis.
+//! The STREAMINFO block is rewritten with the sample count and frame sizes on finalize, the
+//! MD5 signature is left unset which decoders treat as unknown.
+
+use crate::{AudioIoError, Result};
+use std::io::{Seek, SeekFrom, Write};
+
+const BLOCK_SIZE: usize = 4096;
+const BITS_PER_SAMPLE: u32 = 16;
+const MAX_ORDER: usize = 4;
+const MAX_RICE_PARAM: u32 = 14;
+/// Offset of the STREAMINFO block, after the stream marker and the block header.
+const STREAM_INFO_OFFSET: u64 = 8;
+
+pub struct FlacWriter<W: Write + Seek> {
+    w: W,
+    sample_rate: u32,
+    block: Vec<i32>,
+    frames: u64,
+    samples: u64,
+    min_frame: u32,
+    max_frame: u32,
+}
+
+impl<W: Write + Seek> FlacWriter<W> {
+    pub fn new(w: W, sample_rate: u32) -> Result<Self> {
+        if sample_rate == 0 || sample_rate >= 1 << 20 {
+            return Err(AudioIoError::Unsupported(format!(
+                "flac sample rate {sample_rate}"
+            )));
+        }
+        let mut this = Self {
+            w,
+            sample_rate,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            frames: 0,
+            samples: 0,
+            min_frame: 0,
+            max_frame: 0,
+        };
+        this.w.write_all(b"fLaC")?;
+        // Last metadata block, of type STREAMINFO and 34 bytes long.
+        this.w.write_all(&[0x80, 0, 0, 34])?;
+        let info = this.stream_info();
+        this.w.write_all(&info)?;
+        Ok(this)
+    }
+
+    pub fn write(&mut self, pcm: &[f32]) -> Result<()> {
+        for &v in pcm {
+            self.block
+                .push((v.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i32);
+            if self.block.len() == BLOCK_SIZE {
+                self.flush_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finalize(mut self) -> Result<W> {
+        if !self.block.is_empty() {
+            self.flush_block()?;
+        }
+        let end = self.w.stream_position()?;
+        self.w.seek(SeekFrom::Start(STREAM_INFO_OFFSET))?;
+        let info = self.stream_info();
+        self.w.write_all(&info)?;
+        self.w.seek(SeekFrom::Start(end))?;
+        self.w.flush()?;
+        Ok(self.w)
+    }
+
+    fn flush_block(&mut self) -> Result<()> {
+        let frame = encode_frame(&self.block, self.frames);
+        self.w.write_all(&frame)?;
+        let len = frame.len() as u32;
+        self.min_frame = if self.frames == 0 {
+            len
+        } else {
+            self.min_frame.min(len)
+        };
+        self.max_frame = self.max_frame.max(len);
+        self.frames += 1;
+        self.samples += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    fn stream_info(&self) -> [u8; 34] {
+        let mut b = BitWriter::default();
+        b.put(BLOCK_SIZE as u64, 16);
+        b.put(BLOCK_SIZE as u64, 16);
+        b.put(self.min_frame as u64, 24);
+        b.put(self.max_frame as u64, 24);
+        b.put(self.sample_rate as u64, 20);
+        // Channels and bits per sample, minus one.
+        b.put(0, 3);
+        b.put((BITS_PER_SAMPLE - 1) as u64, 5);
+        b.put(self.samples, 36);
+        // MD5 signature, unknown.
+        b.put(0, 64);
+        b.put(0, 64);
+        let mut info = [0u8; 34];
+        info.copy_from_slice(&b.finish());
+        info
+    }
+}
+
+fn encode_frame(block: &[i32], frame_idx: u64) -> Vec<u8> {
+    let mut b = BitWriter::default();
+    // Sync code and fixed block size strategy.
+    b.put(0xfff8, 16);
+    // Block size stored as 16 bits at the end of the header, sample rate from STREAMINFO.
+    b.put(0b0111, 4);
+    b.put(0b0000, 4);
+    // Mono, 16 bits per sample, reserved bit.
+    b.put(0b0000, 4);
+    b.put(0b100, 3);
+    b.put(0, 1);
+    for byte in utf8_number(frame_idx) {
+        b.put(byte as u64, 8);
+    }
+    b.put(block.len() as u64 - 1, 16);
+    let crc = crc8(&b.bytes);
+    b.put(crc as u64, 8);
+    encode_subframe(&mut b, block);
+    b.align();
+    let mut frame = b.finish();
+    let crc = crc16(&frame);
+    frame.extend_from_slice(&crc.to_be_bytes());
+    frame
+}
+
+fn encode_subframe(b: &mut BitWriter, block: &[i32]) {
+    // Zero padding bit and wasted bits flag surround the subframe type.
+    if block.iter().all(|&v| v == block[0]) {
+        b.put(0b0000000, 7);
+        b.put(0, 1);
+        b.put_signed(block[0], BITS_PER_SAMPLE);
+        return;
+    }
+    let verbatim_bits = block.len() as u64 * BITS_PER_SAMPLE as u64;
+    let best = (0..=MAX_ORDER.min(block.len() - 1))
+        .map(|order| {
+            let residual = fixed_residual(block, order);
+            let (param, bits) = rice_param(&residual);
+            (
+                order,
+                residual,
+                param,
+                bits + (order as u64) * BITS_PER_SAMPLE as u64 + 10,
+            )
+        })
+        .min_by_key(|(_, _, _, bits)| *bits);
+    match best {
+        Some((order, residual, param, bits)) if bits < verbatim_bits => {
+            b.put(0b0001000 | order as u64, 7);
+            b.put(0, 1);
+            for &v in &block[..order] {
+                b.put_signed(v, BITS_PER_SAMPLE);
+            }
+            // Rice coding with 4-bit parameters, a single partition.
+            b.put(0b00, 2);
+            b.put(0, 4);
+            b.put(param as u64, 4);
+            for &r in &residual {
+                let u = zigzag(r);
+                b.put_unary(u >> param);
+                b.put(u as u64 & ((1 << param) - 1), param);
+            }
+        }
+        _ => {
+            b.put(0b0000001, 7);
+            b.put(0, 1);
+            for &v in block {
+                b.put_signed(v, BITS_PER_SAMPLE);
+            }
+        }
+    }
+}
+
+/// Residual of the fixed polynomial predictor of the given order, for the samples after the
+/// `order` warm-up ones.
+fn fixed_residual(block: &[i32], order: usize) -> Vec<i32> {
+    (order..block.len())
+        .map(|i| {
+            let s = |k: usize| block[i - k];
+            match order {
+                0 => s(0),
+                1 => s(0) - s(1),
+                2 => s(0) - 2 * s(1) + s(2),
+                3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+                _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+            }
+        })
+        .collect()
+}
+
+fn zigzag(v: i32) -> u32 {
+    ((v << 1) ^ (v >> 31)) as u32
+}
+
+/// The Rice parameter minimizing the coded size of `residual`, and that size in bits.
+fn rice_param(residual: &[i32]) -> (u32, u64) {
+    let us: Vec<u32> = residual.iter().map(|&r| zigzag(r)).collect();
+    (0..=MAX_RICE_PARAM)
+        .map(|k| {
+            let bits: u64 = us.iter().map(|&u| (u >> k) as u64 + 1 + k as u64).sum();
+            (k, bits)
+        })
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap_or((0, 0))
+}
+
+/// The frame number in the extended UTF-8 coding of the frame headers.
+fn utf8_number(v: u64) -> Vec<u8> {
+    if v < 0x80 {
+        return vec![v as u8];
+    }
+    let len = (2..=7usize)
+        .find(|&len| v < 1 << (5 * len + 1))
+        .unwrap_or(7);
+    let mut out = vec![(0xff00u32 >> len) as u8 | (v >> (6 * (len - 1))) as u8];
+    for i in (0..len - 1).rev() {
+        out.push(0x80 | ((v >> (6 * i)) & 0x3f) as u8);
+    }
+    out
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |c, _| {
+            if c & 0x80 != 0 {
+                (c << 1) ^ 0x07
+            } else {
+                c << 1
+            }
+        })
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |c, _| {
+            if c & 0x8000 != 0 {
+                (c << 1) ^ 0x8005
+            } else {
+                c << 1
+            }
+        })
+    })
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    n: u32,
+}
+
+impl BitWriter {
+    /// Append the `n` low bits of `v`, most significant first, `n` is at most 64.
+    fn put(&mut self, v: u64, n: u32) {
+        if n > 32 {
+            self.put(v >> 32, n - 32);
+            self.put(v & 0xffff_ffff, 32);
+            return;
+        }
+        if n == 0 {
+            return;
+        }
+        self.acc = (self.acc << n) | (v & ((1u64 << n) - 1));
+        self.n += n;
+        while self.n >= 8 {
+            self.n -= 8;
+            self.bytes.push((self.acc >> self.n) as u8);
+        }
+    }
+
+    fn put_signed(&mut self, v: i32, n: u32) {
+        self.put(v as u32 as u64, n)
+    }
+
+    fn put_unary(&mut self, zeros: u32) {
+        let mut zeros = zeros;
+        while zeros >= 32 {
+            self.put(0, 32);
+            zeros -= 32;
+        }
+        self.put(1, zeros + 1);
+    }
+
+    fn align(&mut self) {
+        if self.n > 0 {
+            self.put(0, 8 - self.n);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{sine, tmp_path};
+
+    #[test]
+    fn frame_numbers() {
+        assert_eq!(utf8_number(0x7f), [0x7f]);
+        assert_eq!(utf8_number(0x80), [0xc2, 0x80]);
+        assert_eq!(utf8_number(0x7ff), [0xdf, 0xbf]);
+        assert_eq!(utf8_number(0x800), [0xe0, 0xa0, 0x80]);
+    }
+
+    #[test]
+    fn checksums() {
+        // Check values of CRC-8/SMBUS and CRC-16/UMTS.
+        assert_eq!(crc8(b"123456789"), 0xf4);
+        assert_eq!(crc16(b"123456789"), 0xfee8);
+    }
+
+    #[test]
+    fn round_trip_through_symphonia() {
+        let mut pcm = sine(3 * BLOCK_SIZE + 123);
+        // Silence gives constant subframes, noise verbatim ones.
+        pcm[..BLOCK_SIZE].fill(0.);
+        let mut seed = 1u32;
+        for v in pcm[BLOCK_SIZE..2 * BLOCK_SIZE].iter_mut() {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            *v = (seed >> 8) as f32 / (1 << 23) as f32 - 1.0;
+        }
+        let path = tmp_path("round-trip.flac");
+        let mut w = crate::AudioWriter::create(&path, 24000).unwrap();
+        for chunk in pcm.chunks(1920) {
+            w.write(chunk).unwrap();
+        }
+        w.finalize().unwrap();
+        let size = std::fs::metadata(&path).unwrap().len() as usize;
+        let (decoded, sample_rate) = crate::decode(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(sample_rate, 24000);
+        assert_eq!(decoded.len(), pcm.len());
+        for (a, b) in decoded.iter().zip(pcm.iter()) {
+            assert!((a - b).abs() < 1e-4, "{a} {b}");
+        }
+        // The noise block is stored as is, the sine ones are predicted.
+        assert!(size < pcm.len() * 2 * 3 / 4, "{size}");
+    }
+}
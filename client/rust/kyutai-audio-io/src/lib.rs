@@ -0,0 +1,228 @@
+//! Audio file decoding and streaming writers shared by the Kyutai clients.
+//!
+//! WAV is always available, FLAC (read and write) is behind the default `flac` feature and
+//! MP3 decoding behind the `mp3` feature. Writers take mono `f32` samples as produced by the
+//! TTS server and the microphone capture.
+
+mod decode;
+#[cfg(feature = "flac")]
+pub mod flac;
+
+pub use decode::{decode, decode_bytes};
+
+use std::io::{BufWriter, Seek, Write};
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AudioIoError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("WAV error: {0}")]
+    Wav(#[from] hound::Error),
+    #[error("Decode error: {0}")]
+    Decode(String),
+    #[error("{0}")]
+    Unsupported(String),
+}
+
+pub type Result<T> = std::result::Result<T, AudioIoError>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioFormat {
+    Wav,
+    Flac,
+    Mp3,
+}
+
+impl AudioFormat {
+    /// The format matching the extension of `path`, if any.
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        let ext = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "wav" | "wave" => Some(Self::Wav),
+            "flac" => Some(Self::Flac),
+            "mp3" => Some(Self::Mp3),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Wav => "wav",
+            Self::Flac => "flac",
+            Self::Mp3 => "mp3",
+        }
+    }
+
+    /// Whether this build can write the format.
+    pub fn can_write(&self) -> bool {
+        match self {
+            Self::Wav => true,
+            Self::Flac => cfg!(feature = "flac"),
+            Self::Mp3 => false,
+        }
+    }
+}
+
+impl std::str::FromStr for AudioFormat {
+    type Err = AudioIoError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::from_path(format!("audio.{s}"))
+            .ok_or_else(|| AudioIoError::Unsupported(format!("unknown audio format {s}")))
+    }
+}
+
+enum Inner<W: Write + Seek> {
+    Wav(hound::WavWriter<W>),
+    #[cfg(feature = "flac")]
+    Flac(flac::FlacWriter<W>),
+}
+
+/// Streaming mono writer, samples are appended as they arrive and the headers are completed
+/// by [`AudioWriter::finalize`].
+pub struct AudioWriter<W: Write + Seek> {
+    inner: Inner<W>,
+    samples: u64,
+}
+
+impl AudioWriter<BufWriter<std::fs::File>> {
+    /// Create `path` in the format given by its extension, WAV when it has none.
+    pub fn create(path: impl AsRef<Path>, sample_rate: u32) -> Result<Self> {
+        let format = AudioFormat::from_path(&path).unwrap_or(AudioFormat::Wav);
+        Self::create_with_format(path, format, sample_rate)
+    }
+
+    pub fn create_with_format(
+        path: impl AsRef<Path>,
+        format: AudioFormat,
+        sample_rate: u32,
+    ) -> Result<Self> {
+        // Check before creating the file so that an unsupported format leaves nothing behind.
+        if !format.can_write() {
+            return Err(unsupported_writer(format));
+        }
+        let file = BufWriter::new(std::fs::File::create(path)?);
+        Self::new(file, format, sample_rate)
+    }
+}
+
+impl<W: Write + Seek> AudioWriter<W> {
+    pub fn new(w: W, format: AudioFormat, sample_rate: u32) -> Result<Self> {
+        let inner = match format {
+            AudioFormat::Wav => {
+                let spec = hound::WavSpec {
+                    channels: 1,
+                    sample_rate,
+                    bits_per_sample: 32,
+                    sample_format: hound::SampleFormat::Float,
+                };
+                Inner::Wav(hound::WavWriter::new(w, spec)?)
+            }
+            #[cfg(feature = "flac")]
+            AudioFormat::Flac => Inner::Flac(flac::FlacWriter::new(w, sample_rate)?),
+            format => return Err(unsupported_writer(format)),
+        };
+        Ok(Self { inner, samples: 0 })
+    }
+
+    pub fn write(&mut self, pcm: &[f32]) -> Result<()> {
+        match &mut self.inner {
+            Inner::Wav(w) => {
+                for &s in pcm {
+                    w.write_sample(s)?
+                }
+            }
+            #[cfg(feature = "flac")]
+            Inner::Flac(w) => w.write(pcm)?,
+        }
+        self.samples += pcm.len() as u64;
+        Ok(())
+    }
+
+    /// Number of samples written so far.
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    pub fn finalize(self) -> Result<()> {
+        match self.inner {
+            Inner::Wav(w) => w.finalize()?,
+            #[cfg(feature = "flac")]
+            Inner::Flac(w) => {
+                w.finalize()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn unsupported_writer(format: AudioFormat) -> AudioIoError {
+    let msg = match format {
+        AudioFormat::Mp3 => "mp3 encoding is not supported, use wav or flac".to_string(),
+        f => format!("{} support is not enabled in this build", f.extension()),
+    };
+    AudioIoError::Unsupported(msg)
+}
+
+/// Write `pcm` to `path` in one go, see [`AudioWriter::create`].
+pub fn write(path: impl AsRef<Path>, pcm: &[f32], sample_rate: u32) -> Result<()> {
+    let mut w = AudioWriter::create(path, sample_rate)?;
+    w.write(pcm)?;
+    w.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    pub(crate) fn tmp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("kyutai-audio-io-{}-{name}", std::process::id()))
+    }
+
+    pub(crate) fn sine(len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| 0.5 * (i as f32 * 440. * 2. * std::f32::consts::PI / 24000.).sin())
+            .collect()
+    }
+
+    #[test]
+    fn format_from_path() {
+        assert_eq!(AudioFormat::from_path("a/b.WAV"), Some(AudioFormat::Wav));
+        assert_eq!(AudioFormat::from_path("b.flac"), Some(AudioFormat::Flac));
+        assert_eq!(AudioFormat::from_path("b.mp3"), Some(AudioFormat::Mp3));
+        assert_eq!(AudioFormat::from_path("b.txt"), None);
+        assert_eq!(AudioFormat::from_path("b"), None);
+        assert_eq!("flac".parse::<AudioFormat>().unwrap(), AudioFormat::Flac);
+    }
+
+    #[test]
+    fn wav_round_trip() {
+        let pcm = sine(5000);
+        let path = tmp_path("round-trip.wav");
+        let mut w = AudioWriter::create(&path, 24000).unwrap();
+        // Written in chunks as a stream would be.
+        for chunk in pcm.chunks(1920) {
+            w.write(chunk).unwrap();
+        }
+        assert_eq!(w.samples(), 5000);
+        w.finalize().unwrap();
+        let (decoded, sample_rate) = decode(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(sample_rate, 24000);
+        assert_eq!(decoded, pcm);
+    }
+
+    #[test]
+    fn mp3_cannot_be_written() {
+        let err = AudioWriter::new(Cursor::new(vec![]), AudioFormat::Mp3, 24000)
+            .err()
+            .unwrap();
+        assert!(matches!(err, AudioIoError::Unsupported(_)));
+        let path = tmp_path("unsupported.mp3");
+        assert!(AudioWriter::create(&path, 24000).is_err());
+        assert!(!path.exists());
+    }
+}
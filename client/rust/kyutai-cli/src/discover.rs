@@ -0,0 +1,58 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use kyutai_client_core::discovery::{self, DiscoveredServer};
+use std::time::Duration;
+
+#[derive(Args, Debug)]
+pub struct DiscoverArgs {
+    /// How long to listen for mDNS announcements (milliseconds)
+    #[arg(long, default_value = "3000")]
+    pub timeout_ms: u64,
+
+    /// Output the discovered servers as JSON, from the global --json
+    #[arg(skip)]
+    pub json: bool,
+}
+
+pub async fn run_discover(args: DiscoverArgs) -> Result<()> {
+    let servers = discovery::discover(Duration::from_millis(args.timeout_ms)).await?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&servers)?);
+        return Ok(());
+    }
+    if servers.is_empty() {
+        eprintln!("No moshi-server found on the local network.");
+        return Ok(());
+    }
+    for server in servers.iter() {
+        let base = server
+            .base_url()
+            .unwrap_or_else(|| "<no address>".to_string());
+        let version = server.version.as_deref().unwrap_or("unknown");
+        println!(
+            "{}  {}  (host {}, version {})",
+            server.name, base, server.host, version
+        );
+        for module in server.modules.iter() {
+            println!(
+                "    {:<16} {:<12} {}",
+                module.name, module.kind, module.path
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Resolve a server advertised on the LAN by name and return the URL of its first module of `kinds`.
+pub async fn resolve_server_url(name: &str, kinds: &[&str], timeout_ms: u64) -> Result<String> {
+    let server: DiscoveredServer =
+        discovery::find_server(name, Duration::from_millis(timeout_ms)).await?;
+    let url = server.module_url(kinds).with_context(|| {
+        format!(
+            "server '{name}' does not advertise a {} module",
+            kinds.join("/")
+        )
+    })?;
+    eprintln!("Discovered '{}' at {url}", server.name);
+    Ok(url)
+}
@@ -0,0 +1,268 @@
+use crate::stt::{FileStreamConfig, transcribe_file};
+use anyhow::{Context, Result};
+use clap::Args;
+use kyutai_client::stt::wer::{self, Edit, ErrorCounts};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+const AUDIO_EXTENSIONS: &[&str] = &["wav", "mp3", "flac", "ogg", "opus", "m4a"];
+
+#[derive(Args, Debug)]
+pub struct EvalArgs {
+    /// Audio file, or directory of audio files each with a `<stem>.txt` reference next to it
+    pub path: PathBuf,
+
+    /// Reference transcript for a single audio file (defaults to `<stem>.txt`)
+    #[arg(long)]
+    pub reference: Option<PathBuf>,
+
+    /// Write a per-file CSV summary to this path
+    #[arg(long)]
+    pub csv: Option<PathBuf>,
+
+    /// Do not print the word alignment diff
+    #[arg(long)]
+    pub no_diff: bool,
+
+    /// Disable colors in the alignment diff
+    #[arg(long)]
+    pub no_color: bool,
+
+    /// Stream at this real-time factor instead of as fast as possible
+    #[arg(long)]
+    pub rtf: Option<f64>,
+
+    /// Auto-generate a token using --secret/BETTER_AUTH_SECRET
+    #[arg(long)]
+    pub auto_token: bool,
+
+    /// Use high-quality resampling
+    #[arg(long)]
+    pub hq_resample: bool,
+}
+
+struct EvalResult {
+    audio: PathBuf,
+    words: ErrorCounts,
+    chars: ErrorCounts,
+}
+
+pub async fn run_eval(
+    url: String,
+    auth_token: Option<String>,
+    query_token: Option<String>,
+    args: EvalArgs,
+) -> Result<()> {
+    let pairs = collect_pairs(&args.path, args.reference.as_deref())?;
+    if pairs.is_empty() {
+        anyhow::bail!(
+            "no audio file with a reference transcript found in {}",
+            args.path.display()
+        );
+    }
+    let cfg = FileStreamConfig {
+        rtf: args.rtf.filter(|v| v.is_finite() && *v > 0.0),
+        silence_prefix_ms: 0,
+        hq_resample: args.hq_resample,
+    };
+    let color = !args.no_color && std::io::stdout().is_terminal();
+
+    let mut results = Vec::with_capacity(pairs.len());
+    for (audio, reference) in pairs.iter() {
+        let reference_text = std::fs::read_to_string(reference)
+            .with_context(|| format!("failed to read reference {}", reference.display()))?;
+        let words = transcribe_file(
+            &url,
+            auth_token.as_deref(),
+            query_token.as_deref(),
+            audio,
+            cfg,
+        )
+        .await?;
+        let hypothesis = words.join(" ");
+        let alignment = wer::word_alignment(&reference_text, &hypothesis);
+        let result = EvalResult {
+            audio: audio.clone(),
+            words: alignment.counts(),
+            chars: wer::char_alignment(&reference_text, &hypothesis).counts(),
+        };
+        println!(
+            "{}: WER {:.2}% (S {} D {} I {} / {} words), CER {:.2}%",
+            audio.display(),
+            result.words.rate() * 100.0,
+            result.words.substitutions,
+            result.words.deletions,
+            result.words.insertions,
+            result.words.reference_len,
+            result.chars.rate() * 100.0,
+        );
+        if !args.no_diff {
+            println!("  {}", render_diff(&alignment.edits, color));
+        }
+        results.push(result);
+    }
+
+    let mut total_words = ErrorCounts::default();
+    let mut total_chars = ErrorCounts::default();
+    for r in results.iter() {
+        total_words.add(&r.words);
+        total_chars.add(&r.chars);
+    }
+    if results.len() > 1 {
+        println!(
+            "TOTAL ({} files): WER {:.2}%, CER {:.2}%",
+            results.len(),
+            total_words.rate() * 100.0,
+            total_chars.rate() * 100.0
+        );
+    }
+    if let Some(csv) = args.csv.as_deref() {
+        write_csv(csv, &results, &total_words, &total_chars)?;
+        eprintln!("Wrote summary to {}", csv.display());
+    }
+    Ok(())
+}
+
+fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| AUDIO_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+/// Pair every audio file with its reference transcript.
+fn collect_pairs(path: &Path, reference: Option<&Path>) -> Result<Vec<(PathBuf, PathBuf)>> {
+    if !path.is_dir() {
+        let reference = reference
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| path.with_extension("txt"));
+        return Ok(vec![(path.to_path_buf(), reference)]);
+    }
+    if reference.is_some() {
+        anyhow::bail!("--reference can only be used with a single audio file");
+    }
+    let mut audio_files = std::fs::read_dir(path)
+        .with_context(|| format!("failed to list {}", path.display()))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.is_file() && is_audio(p))
+        .collect::<Vec<_>>();
+    audio_files.sort();
+    let mut pairs = Vec::with_capacity(audio_files.len());
+    for audio in audio_files {
+        let reference = audio.with_extension("txt");
+        if reference.is_file() {
+            pairs.push((audio, reference));
+        } else {
+            eprintln!("Skipping {}: no reference transcript", audio.display());
+        }
+    }
+    Ok(pairs)
+}
+
+/// Word-diff style rendering: `[-deleted-]`, `{+inserted+}`, substitutions show both.
+fn render_diff(edits: &[Edit<String>], color: bool) -> String {
+    let paint = |code: &str, s: String| {
+        if color {
+            format!("\x1b[{code}m{s}\x1b[0m")
+        } else {
+            s
+        }
+    };
+    let del = |w: &str| paint("31", format!("[-{w}-]"));
+    let ins = |w: &str| paint("32", format!("{{+{w}+}}"));
+    edits
+        .iter()
+        .map(|edit| match edit {
+            Edit::Equal(w) => w.clone(),
+            Edit::Delete(w) => del(w),
+            Edit::Insert(w) => ins(w),
+            Edit::Substitute {
+                reference,
+                hypothesis,
+            } => {
+                format!("{}{}", del(reference), ins(hypothesis))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn csv_row(name: &str, words: &ErrorCounts, chars: &ErrorCounts) -> String {
+    format!(
+        "{},{},{},{},{},{:.4},{},{},{:.4}",
+        csv_field(name),
+        words.reference_len,
+        words.substitutions,
+        words.deletions,
+        words.insertions,
+        words.rate(),
+        chars.reference_len,
+        chars.errors(),
+        chars.rate(),
+    )
+}
+
+fn write_csv(
+    path: &Path,
+    results: &[EvalResult],
+    total_words: &ErrorCounts,
+    total_chars: &ErrorCounts,
+) -> Result<()> {
+    let mut f = std::io::BufWriter::new(
+        std::fs::File::create(path)
+            .with_context(|| format!("failed to create {}", path.display()))?,
+    );
+    writeln!(
+        f,
+        "file,ref_words,substitutions,deletions,insertions,wer,ref_chars,char_errors,cer"
+    )?;
+    for r in results.iter() {
+        writeln!(
+            f,
+            "{}",
+            csv_row(&r.audio.display().to_string(), &r.words, &r.chars)
+        )?;
+    }
+    writeln!(f, "{}", csv_row("TOTAL", total_words, total_chars))?;
+    f.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_diff_marks_edits() {
+        let a = wer::word_alignment("the cat sat", "a cat sat down");
+        assert_eq!(
+            render_diff(&a.edits, false),
+            "[-the-]{+a+} cat sat {+down+}"
+        );
+    }
+
+    #[test]
+    fn csv_row_quotes_paths() {
+        let words = ErrorCounts {
+            reference_len: 4,
+            substitutions: 1,
+            ..Default::default()
+        };
+        let chars = ErrorCounts {
+            reference_len: 10,
+            deletions: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            csv_row("a,b.wav", &words, &chars),
+            "\"a,b.wav\",4,1,0,0,0.2500,10,1,0.1000"
+        );
+    }
+}
@@ -0,0 +1,104 @@
+//! Keyboard controls of the interactive mic transcription.
+//!
+//! Keys are read with the terminal in raw mode so that they take effect without Enter. Raw
+//! mode also stops the terminal from turning `\n` into `\r\n`, output lines end with
+//! [`newline`] for that reason.
+
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures_util::StreamExt;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::mpsc;
+
+static RAW_MODE: AtomicBool = AtomicBool::new(false);
+
+pub const HELP: &str = "Keys: space pause/resume, m marker, n new paragraph, q finish and exit";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyAction {
+    TogglePause,
+    Marker,
+    NewParagraph,
+    Quit,
+}
+
+fn action(key: KeyEvent) -> Option<KeyAction> {
+    if key.kind == KeyEventKind::Release {
+        return None;
+    }
+    match key.code {
+        KeyCode::Char(' ') => Some(KeyAction::TogglePause),
+        KeyCode::Char('m') => Some(KeyAction::Marker),
+        KeyCode::Char('n') => Some(KeyAction::NewParagraph),
+        // Raw mode delivers Ctrl+C as a key rather than as a signal.
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            Some(KeyAction::Quit)
+        }
+        KeyCode::Char('q') | KeyCode::Esc => Some(KeyAction::Quit),
+        _ => None,
+    }
+}
+
+/// Reads the keyboard until dropped, which restores the terminal.
+pub struct KeyControls {
+    rx: mpsc::Receiver<KeyAction>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl KeyControls {
+    /// Start reading the keys, `None` unless both stdin and stdout are terminals.
+    pub fn start() -> Option<Self> {
+        if !std::io::stdout().is_terminal() || !std::io::stdin().is_terminal() {
+            return None;
+        }
+        if let Err(err) = crossterm::terminal::enable_raw_mode() {
+            tracing::warn!(?err, "cannot enable raw mode, keyboard controls disabled");
+            return None;
+        }
+        RAW_MODE.store(true, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(16);
+        let task = tokio::spawn(async move {
+            let mut events = EventStream::new();
+            while let Some(Ok(ev)) = events.next().await {
+                let Event::Key(key) = ev else { continue };
+                if let Some(action) = action(key)
+                    && tx.send(action).await.is_err()
+                {
+                    break;
+                }
+            }
+        });
+        Some(Self { rx, task })
+    }
+
+    /// The next key action, never resolves once the keyboard cannot be read anymore.
+    pub async fn recv(&mut self) -> KeyAction {
+        match self.rx.recv().await {
+            Some(action) => action,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+impl Drop for KeyControls {
+    fn drop(&mut self) {
+        self.task.abort();
+        RAW_MODE.store(false, Ordering::Relaxed);
+        let _ = crossterm::terminal::disable_raw_mode();
+    }
+}
+
+/// Line ending for the terminal output, `\r\n` while in raw mode.
+pub fn newline() -> &'static str {
+    if RAW_MODE.load(Ordering::Relaxed) {
+        "\r\n"
+    } else {
+        "\n"
+    }
+}
+
+/// Like `eprintln!` but with the line endings of [`newline`].
+pub fn eprint_line(text: &str) {
+    let nl = newline();
+    eprint!("{}{nl}", text.replace('\n', nl));
+}
@@ -1,23 +1,82 @@
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use tracing_subscriber::EnvFilter;
 
+mod discover;
+mod eval;
+mod keys;
+mod mimi;
+mod out_file;
+mod profile;
+mod radio;
+mod script;
+mod server;
 mod stt;
+mod token;
 mod tts;
 
+/// User agent of the generated tokens.
+pub(crate) const USER_AGENT: &str = concat!("kyutai-cli/", env!("CARGO_PKG_VERSION"));
+
 #[derive(Parser, Debug)]
-#[command(author, version, about = "Kyutai Unified CLI for STT and TTS")]
+#[command(
+    name = "kyutai",
+    author,
+    version,
+    about = "Kyutai Unified CLI for STT and TTS"
+)]
 struct Cli {
+    /// Profile from ~/.config/kyutai/config.toml providing defaults for the commands
+    #[arg(long, global = true, env = "KYUTAI_PROFILE")]
+    profile: Option<String>,
+
+    #[command(flatten)]
+    global: GlobalArgs,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Flags shared by all the commands, accepted before or after the subcommand.
+#[derive(Args, Debug, Default)]
+pub(crate) struct GlobalArgs {
+    /// Server URL: the WebSocket endpoint for stt/tts [default: ws://localhost:8080/api/...],
+    /// any URL of the server for status/bench
+    #[arg(long, global = true)]
+    pub url: Option<String>,
+
+    /// Bearer token for authentication
+    #[arg(long, global = true, visible_alias = "auth-token")]
+    pub token: Option<String>,
+
+    /// Better Auth server to fetch the token from when --token is not given, signing in with
+    /// MOSHI_AUTH_API_KEY or MOSHI_AUTH_EMAIL and MOSHI_AUTH_PASSWORD
+    #[arg(long, global = true, env = "MOSHI_AUTH_URL")]
+    pub auth_url: Option<String>,
+
+    /// Machine-readable output, one JSON object per line
+    #[arg(long, global = true)]
+    pub json: bool,
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Speech-to-Text commands
     Stt(stt::SttArgs),
     /// Text-to-Speech commands
     Tts(tts::TtsArgs),
+    /// Generate a JWT token from BETTER_AUTH_SECRET, or fetch one from --auth-url
+    Token(token::TokenArgs),
+    /// Show the health and the capacity of a server
+    Status(server::StatusArgs),
+    /// Run the latency self-benchmark of a server on an idle slot
+    Bench(server::BenchArgs),
+    /// List moshi-server instances advertised on the local network (mDNS)
+    Discover(discover::DiscoverArgs),
+    /// Publish to or listen to a Mimi room
+    Mimi(mimi::MimiArgs),
+    /// Manage the client config profiles
+    Profile(profile::ProfileArgs),
 }
 
 #[tokio::main]
@@ -27,10 +86,42 @@ async fn main() -> Result<()> {
         .init();
 
     let cli = Cli::parse();
+    let mut global = cli.global;
 
     match cli.command {
-        Commands::Stt(args) => stt::run_stt(args).await?,
-        Commands::Tts(args) => tts::run_tts(args).await?,
+        Commands::Stt(mut args) => {
+            let profile = profile::load_active(cli.profile.as_deref())?;
+            args.token_source = token::fetch_token(&mut global).await?;
+            (args.url, args.token, args.json) = (global.url, global.token, global.json);
+            stt::run_stt(args, profile.as_ref()).await?
+        }
+        Commands::Tts(mut args) => {
+            let profile = profile::load_active(cli.profile.as_deref())?;
+            token::fetch_token(&mut global).await?;
+            (args.url, args.token, args.json) = (global.url, global.token, global.json);
+            tts::run_tts(args, profile.as_ref()).await?
+        }
+        Commands::Token(args) => token::run_token(args, &mut global).await?,
+        Commands::Status(args) => {
+            let profile = profile::load_active(cli.profile.as_deref())?;
+            server::run_status(args, &global, profile.as_ref()).await?
+        }
+        Commands::Bench(args) => {
+            let profile = profile::load_active(cli.profile.as_deref())?;
+            token::fetch_token(&mut global).await?;
+            server::run_bench(args, &global, profile.as_ref()).await?
+        }
+        Commands::Discover(mut args) => {
+            args.json = global.json;
+            discover::run_discover(args).await?
+        }
+        Commands::Mimi(mut args) => {
+            let profile = profile::load_active(cli.profile.as_deref())?;
+            token::fetch_token(&mut global).await?;
+            (args.url, args.token) = (global.url, global.token);
+            mimi::run_mimi(args, profile.as_ref()).await?
+        }
+        Commands::Profile(args) => profile::run_profile(args)?,
     }
 
     Ok(())
@@ -0,0 +1,151 @@
+use crate::profile::Profile;
+use crate::tts::{SAMPLE_RATE, play_pcm};
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand};
+use kyutai_client::stt::audio::{MicCapture, MicCaptureConfig, ResampleQuality};
+use kyutai_client_core::audio::{AudioPlayer, DynResampler};
+use kyutai_mimi_client::{MimiCodec, PublisherBuilder, RoomEvent, SubscriberBuilder};
+
+const DEFAULT_SEND_URL: &str = "ws://localhost:8080/api/mimi/send";
+const DEFAULT_RECV_URL: &str = "ws://localhost:8080/api/mimi/recv";
+
+#[derive(Args, Debug)]
+pub struct MimiArgs {
+    /// WebSocket URL of the send or recv path, from the global --url
+    #[arg(skip)]
+    pub url: Option<String>,
+
+    /// Bearer token for authentication, from the global --token
+    #[arg(skip)]
+    pub token: Option<String>,
+
+    #[command(subcommand)]
+    pub command: MimiCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MimiCommand {
+    /// Publish the microphone to a room
+    Publish {
+        /// Room to publish to
+        #[arg(long)]
+        room: String,
+
+        /// Name of the publisher in the room, assigned by the server when unset
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Input device name for the microphone
+        #[arg(long)]
+        device: Option<String>,
+
+        /// High quality resampling of the microphone
+        #[arg(long)]
+        hq_resample: bool,
+
+        /// Mimi weights (local path or hf://) to send codes encoded locally instead of Ogg/Opus
+        #[arg(long)]
+        weights: Option<String>,
+    },
+    /// Play the audio of a room and print its text messages
+    Listen {
+        /// Room to listen to
+        #[arg(long)]
+        room: String,
+
+        /// Prebuffer duration in ms for cpal
+        #[arg(long, default_value = "200")]
+        prebuffer_ms: u32,
+
+        /// Max buffer duration in ms for cpal
+        #[arg(long, default_value = "2000")]
+        max_buffer_ms: u32,
+    },
+}
+
+pub async fn run_mimi(mut args: MimiArgs, profile: Option<&Profile>) -> Result<()> {
+    if args.token.is_none()
+        && let Some(profile) = profile
+    {
+        args.token = profile.resolve_token()?;
+    }
+    match args.command {
+        MimiCommand::Publish {
+            room,
+            name,
+            device,
+            hq_resample,
+            weights,
+        } => {
+            let url = args.url.as_deref().unwrap_or(DEFAULT_SEND_URL);
+            let mut builder = PublisherBuilder::new(url).room(room);
+            if let Some(name) = name {
+                builder = builder.name(name);
+            }
+            if let Some(token) = args.token {
+                builder = builder.auth_token(token);
+            }
+            if let Some(weights) = weights {
+                let device = candle::Device::cuda_if_available(0)?;
+                let codec = MimiCodec::load(&weights, &device)
+                    .with_context(|| format!("cannot load the Mimi weights {weights}"))?;
+                builder = builder.codec(codec);
+            }
+            let device = device.or_else(|| profile.and_then(|p| p.device.clone()));
+            let resample_quality = if hq_resample {
+                ResampleQuality::High
+            } else {
+                ResampleQuality::Polyphase
+            };
+            let mut mic = MicCapture::start_default_with_config(MicCaptureConfig {
+                resample_quality,
+                device,
+                ..Default::default()
+            })?;
+            let mut session = builder.connect().await?;
+            eprintln!("Publishing, press Ctrl+C to stop");
+            loop {
+                tokio::select! {
+                    chunk = mic.recv() => match chunk {
+                        Some(chunk) => session.send_pcm(&chunk.samples).await?,
+                        None => break,
+                    },
+                    _ = tokio::signal::ctrl_c() => break,
+                }
+            }
+            session.close().await?;
+        }
+        MimiCommand::Listen {
+            room,
+            prebuffer_ms,
+            max_buffer_ms,
+        } => {
+            let url = args.url.as_deref().unwrap_or(DEFAULT_RECV_URL);
+            let mut builder = SubscriberBuilder::new(url).room(room);
+            if let Some(token) = args.token {
+                builder = builder.auth_token(token);
+            }
+            let mut player = AudioPlayer::setup(prebuffer_ms, max_buffer_ms, None, None, true)?;
+            let mut resampler = DynResampler::new(
+                SAMPLE_RATE,
+                player.output_sample_rate as u32,
+                ResampleQuality::High,
+            )?;
+            let mut session = builder.connect().await?;
+            loop {
+                let event = tokio::select! {
+                    event = session.recv() => event?,
+                    _ = tokio::signal::ctrl_c() => break,
+                };
+                match event {
+                    Some(RoomEvent::Audio { pcm }) => {
+                        play_pcm(&mut player, resampler.as_mut(), &pcm).await?
+                    }
+                    Some(RoomEvent::Text(text)) => println!("{text}"),
+                    None => break,
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,181 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// When to start a new transcript file, parsed from `30min`, `2h` or `10MB`, `512KB`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    Every(Duration),
+    Size(u64),
+}
+
+impl std::str::FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (num, unit) = s.split_at(split);
+        let num: u64 = num.parse().map_err(|_| format!("invalid rotation '{s}'"))?;
+        if num == 0 {
+            return Err("rotation must be greater than zero".to_string());
+        }
+        let rotation = match unit.trim().to_ascii_lowercase().as_str() {
+            "min" => Self::Every(Duration::from_secs(num * 60)),
+            "h" => Self::Every(Duration::from_secs(num * 3600)),
+            "b" => Self::Size(num),
+            "kb" => Self::Size(num << 10),
+            "mb" => Self::Size(num << 20),
+            "gb" => Self::Size(num << 30),
+            _ => {
+                return Err(format!(
+                    "invalid rotation '{s}', expected e.g. 30min, 2h or 10MB"
+                ));
+            }
+        };
+        Ok(rotation)
+    }
+}
+
+/// Transcript written to disk one utterance per line, `[HH:MM:SS.mmm] text`.
+///
+/// Lines are fsynced as soon as the utterance ends so that the file survives the terminal
+/// going away. With a rotation, files are named `<stem>.<n>.<ext>` and a new one is started
+/// at the first utterance boundary after the limit is reached.
+pub struct TranscriptFile {
+    path: PathBuf,
+    rotation: Option<Rotation>,
+    file: File,
+    index: usize,
+    opened: Instant,
+    bytes: u64,
+    utterance: Option<(u64, String)>,
+}
+
+impl TranscriptFile {
+    pub fn create(path: &Path, rotation: Option<Rotation>) -> Result<Self> {
+        let (file, index) = match rotation {
+            None => (open(path)?, 0),
+            Some(_) => {
+                let index = (0..)
+                    .find(|&i| !rotated_path(path, i).exists())
+                    .unwrap_or(0);
+                (open(&rotated_path(path, index))?, index)
+            }
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            rotation,
+            file,
+            index,
+            opened: Instant::now(),
+            bytes: 0,
+            utterance: None,
+        })
+    }
+
+    pub fn push_word(&mut self, start_ms: u64, text: &str) {
+        let text = text.trim();
+        if text.is_empty() {
+            return;
+        }
+        let (_, line) = self
+            .utterance
+            .get_or_insert_with(|| (start_ms, String::new()));
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(text);
+    }
+
+    /// Write the pending utterance, sync it to disk and rotate the file if needed.
+    pub fn end_utterance(&mut self) -> Result<()> {
+        let Some((start_ms, text)) = self.utterance.take() else {
+            return Ok(());
+        };
+        let line = format!("[{}] {text}\n", format_ms(start_ms));
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        self.bytes += line.len() as u64;
+        let rotate = match self.rotation {
+            None => false,
+            Some(Rotation::Every(d)) => self.opened.elapsed() >= d,
+            Some(Rotation::Size(s)) => self.bytes >= s,
+        };
+        if rotate {
+            self.index += 1;
+            self.file = open(&rotated_path(&self.path, self.index))?;
+            self.opened = Instant::now();
+            self.bytes = 0;
+        }
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> Result<File> {
+    File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open transcript file {}", path.display()))
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy())
+        .unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}.{index:03}.{}", ext.to_string_lossy()),
+        None => format!("{stem}.{index:03}"),
+    };
+    path.with_file_name(name)
+}
+
+fn format_ms(ms: u64) -> String {
+    let s = ms / 1000;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        s / 3600,
+        (s % 3600) / 60,
+        s % 60,
+        ms % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rotation() {
+        assert_eq!(
+            "30min".parse(),
+            Ok(Rotation::Every(Duration::from_secs(1800)))
+        );
+        assert_eq!("2h".parse(), Ok(Rotation::Every(Duration::from_secs(7200))));
+        assert_eq!("10MB".parse(), Ok(Rotation::Size(10 << 20)));
+        assert!("10m".parse::<Rotation>().is_err());
+        assert!("0min".parse::<Rotation>().is_err());
+    }
+
+    #[test]
+    fn rotates_on_utterance_boundaries() {
+        let dir = std::env::temp_dir().join(format!("kyutai-out-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("captions.txt");
+        let mut f = TranscriptFile::create(&path, Some(Rotation::Size(10))).unwrap();
+        f.push_word(1250, "hello");
+        f.push_word(1500, " world");
+        f.end_utterance().unwrap();
+        f.push_word(3_723_000, "again");
+        f.end_utterance().unwrap();
+        let first = std::fs::read_to_string(dir.join("captions.000.txt")).unwrap();
+        let second = std::fs::read_to_string(dir.join("captions.001.txt")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(first, "[00:00:01.250] hello world\n");
+        assert_eq!(second, "[01:02:03.000] again\n");
+    }
+}
@@ -0,0 +1,329 @@
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Keys accepted by `profile set`/`profile unset`.
+const STRING_KEYS: &[&str] = &[
+    "stt_url",
+    "tts_url",
+    "server",
+    "token_env",
+    "token_file",
+    "env",
+    "voice",
+    "device",
+    "play_backend",
+];
+const BOOL_KEYS: &[&str] = &["auto_token", "hq_resample", "timestamps", "json"];
+
+/// Defaults applied to the STT/TTS commands, explicit command line arguments always win.
+///
+/// Tokens are never stored in the file, only where to read them from.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub stt_url: Option<String>,
+    pub tts_url: Option<String>,
+    /// Server name resolved over mDNS, used when no URL is given.
+    pub server: Option<String>,
+    /// Environment variable holding the bearer token.
+    pub token_env: Option<String>,
+    /// File holding the bearer token.
+    pub token_file: Option<String>,
+    /// Generate a token from BETTER_AUTH_SECRET.
+    pub auto_token: Option<bool>,
+    /// Environment name for loading `.env.<env>` when generating tokens.
+    pub env: Option<String>,
+    pub voice: Option<String>,
+    /// Input device name for the microphone.
+    pub device: Option<String>,
+    pub play_backend: Option<String>,
+    pub hq_resample: Option<bool>,
+    pub timestamps: Option<bool>,
+    pub json: Option<bool>,
+}
+
+impl Profile {
+    fn string_field(&mut self, key: &str) -> Option<&mut Option<String>> {
+        let field = match key {
+            "stt_url" => &mut self.stt_url,
+            "tts_url" => &mut self.tts_url,
+            "server" => &mut self.server,
+            "token_env" => &mut self.token_env,
+            "token_file" => &mut self.token_file,
+            "env" => &mut self.env,
+            "voice" => &mut self.voice,
+            "device" => &mut self.device,
+            "play_backend" => &mut self.play_backend,
+            _ => return None,
+        };
+        Some(field)
+    }
+
+    fn bool_field(&mut self, key: &str) -> Option<&mut Option<bool>> {
+        let field = match key {
+            "auto_token" => &mut self.auto_token,
+            "hq_resample" => &mut self.hq_resample,
+            "timestamps" => &mut self.timestamps,
+            "json" => &mut self.json,
+            _ => return None,
+        };
+        Some(field)
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        if let Some(field) = self.string_field(key) {
+            *field = Some(value.to_string());
+        } else if let Some(field) = self.bool_field(key) {
+            let value = value
+                .parse::<bool>()
+                .with_context(|| format!("'{key}' expects true or false, got '{value}'"))?;
+            *field = Some(value);
+        } else {
+            anyhow::bail!("{}", unknown_key(key));
+        }
+        Ok(())
+    }
+
+    pub fn unset(&mut self, key: &str) -> Result<()> {
+        if let Some(field) = self.string_field(key) {
+            *field = None;
+        } else if let Some(field) = self.bool_field(key) {
+            *field = None;
+        } else {
+            anyhow::bail!("{}", unknown_key(key));
+        }
+        Ok(())
+    }
+
+    /// Read the bearer token from `token_env`, then `token_file`.
+    pub fn resolve_token(&self) -> Result<Option<String>> {
+        if let Some(var) = self.token_env.as_deref()
+            && let Ok(token) = std::env::var(var)
+            && !token.trim().is_empty()
+        {
+            return Ok(Some(token.trim().to_string()));
+        }
+        if let Some(path) = self.token_file.as_deref() {
+            let path = expand_home(path);
+            let token = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read token file {}", path.display()))?;
+            let token = token.trim();
+            if !token.is_empty() {
+                return Ok(Some(token.to_string()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn unknown_key(key: &str) -> String {
+    format!(
+        "unknown profile key '{key}', expected one of: {}, {}",
+        STRING_KEYS.join(", "),
+        BOOL_KEYS.join(", ")
+    )
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Profile used when `--profile` is not given.
+    pub default_profile: Option<String>,
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl ClientConfig {
+    /// `$KYUTAI_CONFIG`, or `$XDG_CONFIG_HOME/kyutai/config.toml` defaulting to `~/.config`.
+    pub fn default_path() -> Result<PathBuf> {
+        if let Some(path) = std::env::var_os("KYUTAI_CONFIG") {
+            return Ok(PathBuf::from(path));
+        }
+        let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => dirs::home_dir()
+                .context("cannot determine the home directory")?
+                .join(".config"),
+        };
+        Ok(config_dir.join("kyutai").join("config.toml"))
+    }
+
+    /// Load the config, a missing file yields an empty config.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content)
+                .with_context(|| format!("failed to parse {}", path.display())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        std::fs::write(path, toml::to_string_pretty(self)?)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(())
+    }
+
+    /// The profile selected by `name`, falling back on `default_profile`.
+    pub fn profile(&self, name: Option<&str>) -> Result<Option<&Profile>> {
+        let Some(name) = name.or(self.default_profile.as_deref()) else {
+            return Ok(None);
+        };
+        self.profiles
+            .get(name)
+            .map(Some)
+            .with_context(|| format!("profile '{name}' not found in the client config"))
+    }
+}
+
+/// Load the profile to apply to the STT/TTS commands.
+pub fn load_active(name: Option<&str>) -> Result<Option<Profile>> {
+    let config = ClientConfig::load(&ClientConfig::default_path()?)?;
+    Ok(config.profile(name)?.cloned())
+}
+
+#[derive(Args, Debug)]
+pub struct ProfileArgs {
+    #[command(subcommand)]
+    pub command: ProfileCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ProfileCommand {
+    /// Set a value in a profile, creating the profile if needed
+    Set {
+        name: String,
+        key: String,
+        value: String,
+    },
+    /// Remove a value from a profile
+    Unset { name: String, key: String },
+    /// Make a profile the default one
+    Use { name: String },
+    /// Delete a profile
+    Remove { name: String },
+    /// List the profiles
+    List,
+    /// Print a profile (the default one if no name is given)
+    Show { name: Option<String> },
+}
+
+pub fn run_profile(args: ProfileArgs) -> Result<()> {
+    let path = ClientConfig::default_path()?;
+    let mut config = ClientConfig::load(&path)?;
+    match args.command {
+        ProfileCommand::Set { name, key, value } => {
+            config.profiles.entry(name).or_default().set(&key, &value)?;
+            config.save(&path)?;
+        }
+        ProfileCommand::Unset { name, key } => {
+            config
+                .profiles
+                .get_mut(&name)
+                .with_context(|| format!("profile '{name}' not found"))?
+                .unset(&key)?;
+            config.save(&path)?;
+        }
+        ProfileCommand::Use { name } => {
+            if !config.profiles.contains_key(&name) {
+                anyhow::bail!("profile '{name}' not found");
+            }
+            config.default_profile = Some(name);
+            config.save(&path)?;
+        }
+        ProfileCommand::Remove { name } => {
+            if config.profiles.remove(&name).is_none() {
+                anyhow::bail!("profile '{name}' not found");
+            }
+            if config.default_profile.as_deref() == Some(name.as_str()) {
+                config.default_profile = None;
+            }
+            config.save(&path)?;
+        }
+        ProfileCommand::List => {
+            for name in config.profiles.keys() {
+                let marker = if config.default_profile.as_deref() == Some(name) {
+                    "*"
+                } else {
+                    " "
+                };
+                println!("{marker} {name}");
+            }
+        }
+        ProfileCommand::Show { name } => match config.profile(name.as_deref())? {
+            Some(profile) => print!("{}", toml::to_string_pretty(profile)?),
+            None => eprintln!("No default profile, pass a profile name."),
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_unset_keys() {
+        let mut p = Profile::default();
+        p.set("stt_url", "ws://gpu:8080/api/asr-streaming").unwrap();
+        p.set("hq_resample", "true").unwrap();
+        assert_eq!(
+            p.stt_url.as_deref(),
+            Some("ws://gpu:8080/api/asr-streaming")
+        );
+        assert_eq!(p.hq_resample, Some(true));
+        assert!(p.set("hq_resample", "yes").is_err());
+        assert!(p.set("token", "secret").is_err());
+        p.unset("stt_url").unwrap();
+        assert!(p.stt_url.is_none());
+    }
+
+    #[test]
+    fn config_roundtrip_and_default_profile() {
+        let content = r#"
+default_profile = "lab"
+
+[profiles.lab]
+tts_url = "ws://lab:8080/api/tts_streaming"
+voice = "expresso/ex03-ex01_happy_001_channel1_334s.wav"
+auto_token = true
+"#;
+        let config: ClientConfig = toml::from_str(content).unwrap();
+        let lab = config.profile(None).unwrap().unwrap();
+        assert_eq!(lab.auto_token, Some(true));
+        assert!(config.profile(Some("missing")).is_err());
+        let again: ClientConfig =
+            toml::from_str(&toml::to_string_pretty(&config).unwrap()).unwrap();
+        assert_eq!(again, config);
+    }
+
+    #[test]
+    fn token_from_env() {
+        let p = Profile {
+            token_env: Some("KYUTAI_CLI_TEST_PROFILE_TOKEN".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(p.resolve_token().unwrap(), None);
+        // SAFETY: the variable is only used by this test.
+        unsafe { std::env::set_var("KYUTAI_CLI_TEST_PROFILE_TOKEN", " abc \n") };
+        assert_eq!(p.resolve_token().unwrap().as_deref(), Some("abc"));
+    }
+}
@@ -0,0 +1,340 @@
+use crate::tts::{SAMPLE_RATE, TtsArgs, audio_player, client_builder, play_pcm};
+use anyhow::{Context, Result};
+use kyutai_client::tts::InMsg;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Instant;
+
+/// The synthesis stops after this many items failed in a row, the server is most likely down.
+const MAX_CONSECUTIVE_FAILURES: usize = 5;
+/// Samples quieter than this (-60 dBFS) at the ends of an item are trimmed before the crossfade.
+const SILENCE_THRESHOLD: f32 = 1e-3;
+/// Silence kept at the ends of an item after trimming.
+const SILENCE_MARGIN_S: f64 = 0.05;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct PlaylistItem {
+    pub text: String,
+    #[serde(default)]
+    pub voice: Option<String>,
+}
+
+/// Items of a directory of `.txt` files sorted by name, of a JSONL file, or of a text file with
+/// one item per line.
+pub fn load_playlist(path: &Path) -> Result<Vec<PlaylistItem>> {
+    let item = |text: &str| PlaylistItem {
+        text: text.trim().to_string(),
+        voice: None,
+    };
+    let items: Vec<PlaylistItem> = if path.is_dir() {
+        let mut files = std::fs::read_dir(path)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        files.retain(|p| p.extension().is_some_and(|e| e == "txt"));
+        files.sort();
+        files
+            .iter()
+            .map(|p| {
+                Ok(item(
+                    &std::fs::read_to_string(p).with_context(|| p.display().to_string())?,
+                ))
+            })
+            .collect::<Result<_>>()?
+    } else {
+        let content = std::fs::read_to_string(path).with_context(|| path.display().to_string())?;
+        let lines = content
+            .lines()
+            .enumerate()
+            .filter(|(_, l)| !l.trim().is_empty());
+        if path.extension().is_some_and(|e| e == "jsonl") {
+            lines
+                .map(|(idx, l)| {
+                    serde_json::from_str(l).with_context(|| format!("line {}", idx + 1))
+                })
+                .collect::<Result<_>>()?
+        } else {
+            lines.map(|(_, l)| item(l)).collect()
+        }
+    };
+    let items: Vec<_> = items
+        .into_iter()
+        .filter(|i| !i.text.trim().is_empty())
+        .collect();
+    if items.is_empty() {
+        anyhow::bail!("empty playlist {}", path.display())
+    }
+    Ok(items)
+}
+
+/// Joins consecutive items, the end of each item is mixed with the start of the next one with
+/// an equal-power fade.
+pub struct Crossfader {
+    len: usize,
+    tail: Vec<f32>,
+}
+
+impl Crossfader {
+    pub fn new(len: usize) -> Self {
+        Self { len, tail: vec![] }
+    }
+
+    /// Samples ready to be played once `item` is queued, the end of `item` is held back for the
+    /// next crossfade.
+    pub fn push(&mut self, mut item: Vec<f32>) -> Vec<f32> {
+        let n = self.tail.len().min(item.len());
+        let mut out = std::mem::take(&mut self.tail);
+        let fade_start = out.len() - n;
+        for (i, (o, v)) in out[fade_start..].iter_mut().zip(item.iter()).enumerate() {
+            let t = (i as f32 + 0.5) / n as f32 * std::f32::consts::FRAC_PI_2;
+            *o = *o * t.cos() + v * t.sin();
+        }
+        let hold = self.len.min(item.len() - n);
+        self.tail = item.split_off(item.len() - hold);
+        out.extend_from_slice(&item[n..]);
+        out
+    }
+
+    pub fn finish(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.tail)
+    }
+}
+
+/// Drops the silence at the start and at the end of `pcm`, but for a short margin.
+pub fn trim_silence(pcm: &mut Vec<f32>, sample_rate: u32) {
+    let margin = (SILENCE_MARGIN_S * sample_rate as f64) as usize;
+    let loud = |v: &f32| v.abs() > SILENCE_THRESHOLD;
+    let Some(first) = pcm.iter().position(loud) else {
+        pcm.clear();
+        return;
+    };
+    let last = pcm.iter().rposition(loud).unwrap_or(first);
+    pcm.truncate((last + 1 + margin).min(pcm.len()));
+    pcm.drain(..first.saturating_sub(margin));
+}
+
+#[derive(Debug, Serialize)]
+struct ItemReport {
+    pass: usize,
+    item: usize,
+    ok: bool,
+    error: Option<String>,
+    ttfb_ms: Option<f64>,
+    audio_seconds: f64,
+    wall_seconds: f64,
+}
+
+async fn synthesize(args: &TtsArgs, item: &PlaylistItem) -> Result<(Vec<f32>, Option<f64>)> {
+    let start = Instant::now();
+    let mut session = client_builder(args, item.voice.as_deref())?
+        .connect()
+        .await?;
+    session.send_text(&item.text).await?;
+    let mut pcm_all = vec![];
+    let mut ttfb_ms = None;
+    while let Some(msg) = session.recv().await? {
+        match msg {
+            InMsg::Audio { pcm } => {
+                ttfb_ms.get_or_insert_with(|| start.elapsed().as_secs_f64() * 1000.0);
+                pcm_all.extend_from_slice(&pcm)
+            }
+            InMsg::Error { message } => anyhow::bail!("server error: {message}"),
+            _ => {}
+        }
+    }
+    if pcm_all.is_empty() {
+        anyhow::bail!("no audio received")
+    }
+    Ok((pcm_all, ttfb_ms))
+}
+
+/// Synthesizes the playlist ahead of the playback, one session per item as the server ends
+/// the session with the text.
+async fn produce(
+    args: TtsArgs,
+    items: Vec<PlaylistItem>,
+    tx: tokio::sync::mpsc::Sender<Vec<f32>>,
+) -> Result<()> {
+    let mut failures = 0;
+    for pass in 0.. {
+        for (idx, item) in items.iter().enumerate() {
+            let start = Instant::now();
+            let result = synthesize(&args, item).await;
+            let wall_seconds = start.elapsed().as_secs_f64();
+            let report = match &result {
+                Ok((pcm, ttfb_ms)) => ItemReport {
+                    pass,
+                    item: idx,
+                    ok: true,
+                    error: None,
+                    ttfb_ms: *ttfb_ms,
+                    audio_seconds: pcm.len() as f64 / SAMPLE_RATE as f64,
+                    wall_seconds,
+                },
+                Err(err) => ItemReport {
+                    pass,
+                    item: idx,
+                    ok: false,
+                    error: Some(err.to_string()),
+                    ttfb_ms: None,
+                    audio_seconds: 0.0,
+                    wall_seconds,
+                },
+            };
+            if args.json {
+                println!("{}", serde_json::to_string(&report)?);
+            } else if let Some(err) = &report.error {
+                eprintln!("[{pass}:{idx}] failed: {err}");
+            } else {
+                eprintln!(
+                    "[{pass}:{idx}] {:.1}s of audio in {:.1}s, ttfb {:.0}ms",
+                    report.audio_seconds,
+                    wall_seconds,
+                    report.ttfb_ms.unwrap_or_default()
+                );
+            }
+            match result {
+                Ok((pcm, _)) => {
+                    failures = 0;
+                    if tx.send(pcm).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                Err(err) => {
+                    failures += 1;
+                    if failures >= MAX_CONSECUTIVE_FAILURES {
+                        return Err(err.context(format!("{failures} items failed in a row")));
+                    }
+                }
+            }
+        }
+        if !args.loop_playlist {
+            break;
+        }
+    }
+    Ok(())
+}
+
+pub async fn run_radio(args: &TtsArgs, playlist: &str) -> Result<()> {
+    let items = load_playlist(Path::new(playlist))?;
+    if !args.json {
+        eprintln!(
+            "Radio mode: {} items{}",
+            items.len(),
+            if args.loop_playlist { ", looping" } else { "" }
+        );
+    }
+    let mut player = audio_player(args)?;
+    let mut writer = match &args.output {
+        Some(path) => Some(kyutai_audio_io::AudioWriter::create(path, SAMPLE_RATE)?),
+        None => None,
+    };
+    if player.is_none() && writer.is_none() {
+        anyhow::bail!("no audio output device, use --output to write the playlist to a file")
+    }
+
+    // Only the next item is synthesized ahead, the player buffer paces the playback.
+    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+    let producer = tokio::spawn(produce(args.clone(), items, tx));
+    let crossfade = (args.crossfade_ms as u64 * SAMPLE_RATE as u64 / 1000) as usize;
+    let mut fader = Crossfader::new(crossfade);
+    let mut interrupted = false;
+    loop {
+        let mut pcm = tokio::select! {
+            pcm = rx.recv() => match pcm {
+                Some(pcm) => pcm,
+                None => break,
+            },
+            _ = tokio::signal::ctrl_c() => {
+                interrupted = true;
+                break;
+            }
+        };
+        trim_silence(&mut pcm, SAMPLE_RATE);
+        let out = fader.push(pcm);
+        if let Some((p, r)) = player.as_mut() {
+            play_pcm(p, r.as_mut(), &out).await?;
+        }
+        if let Some(w) = writer.as_mut() {
+            w.write(&out)?;
+        }
+    }
+    drop(rx);
+    let out = fader.finish();
+    if let Some(mut w) = writer {
+        w.write(&out)?;
+        w.finalize()?;
+    }
+    if let Some((mut p, mut r)) = player
+        && !interrupted
+    {
+        play_pcm(&mut p, r.as_mut(), &out).await?;
+        p.drain().await;
+    }
+    if interrupted {
+        producer.abort();
+        return Ok(());
+    }
+    producer.await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crossfade_overlaps_consecutive_items() {
+        let mut fader = Crossfader::new(4);
+        assert_eq!(fader.push(vec![1.0; 10]), vec![1.0; 6]);
+        let out = fader.push(vec![1.0; 10]);
+        // The 4 held samples are mixed with the first 4 of the new item.
+        assert_eq!(out.len(), 4 + 2);
+        assert!(out[..4].iter().all(|v| (1.0..1.42).contains(v)));
+        // A short item is mixed entirely with the end of the previous one.
+        let out = fader.push(vec![0.0; 2]);
+        assert_eq!(out.len(), 4);
+        assert_eq!(fader.finish(), Vec::<f32>::new());
+        assert_eq!(fader.push(vec![0.5; 3]), Vec::<f32>::new());
+        assert_eq!(fader.finish(), vec![0.5; 3]);
+    }
+
+    #[test]
+    fn trims_silence_with_margin() {
+        let mut pcm = vec![0.0; 100];
+        pcm[40] = 0.5;
+        pcm[50] = -0.5;
+        trim_silence(&mut pcm, 100);
+        assert_eq!(pcm.len(), 21);
+        assert_eq!(pcm[5], 0.5);
+        let mut silent = vec![0.0; 10];
+        trim_silence(&mut silent, 100);
+        assert!(silent.is_empty());
+    }
+
+    #[test]
+    fn playlist_formats() {
+        let dir = std::env::temp_dir().join(format!("kyutai-radio-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("items")).unwrap();
+        std::fs::write(dir.join("items/b.txt"), "second").unwrap();
+        std::fs::write(dir.join("items/a.txt"), "first\n").unwrap();
+        std::fs::write(dir.join("items/notes.md"), "skipped").unwrap();
+        std::fs::write(
+            dir.join("list.jsonl"),
+            "{\"text\": \"hi\", \"voice\": \"v.wav\"}\n\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("list.txt"), "one\n\ntwo\n").unwrap();
+        let texts =
+            |items: Vec<PlaylistItem>| items.into_iter().map(|i| i.text).collect::<Vec<_>>();
+        assert_eq!(
+            texts(load_playlist(&dir.join("items")).unwrap()),
+            ["first", "second"]
+        );
+        let jsonl = load_playlist(&dir.join("list.jsonl")).unwrap();
+        assert_eq!(jsonl[0].voice.as_deref(), Some("v.wav"));
+        assert_eq!(
+            texts(load_playlist(&dir.join("list.txt")).unwrap()),
+            ["one", "two"]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
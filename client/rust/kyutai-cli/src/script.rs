@@ -0,0 +1,119 @@
+//! Teleprompter mode of `stt --script`: the recognized words are followed along the script
+//! being read, the deviations are marked in the transcript as in the `eval` diffs and the
+//! accuracy so far is reported after each utterance.
+
+use anyhow::{Context, Result};
+use kyutai_client::stt::transcript::align::{ScriptAligner, ScriptEvent};
+use serde::Serialize;
+use std::io::IsTerminal;
+use std::path::Path;
+
+pub struct ScriptFollower {
+    aligner: ScriptAligner,
+    color: bool,
+}
+
+/// Accuracy over the script words read so far, the `script_summary` line of `--json`.
+#[derive(Debug, Serialize)]
+pub struct ScriptSummary {
+    pub read: usize,
+    pub words: usize,
+    pub accuracy: f64,
+    pub substitutions: usize,
+    pub deletions: usize,
+    pub insertions: usize,
+}
+
+impl ScriptFollower {
+    pub fn load(path: &Path) -> Result<Self> {
+        let script = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read script {}", path.display()))?;
+        let aligner = ScriptAligner::new(&script);
+        if aligner.is_empty() {
+            anyhow::bail!("script {} holds no words", path.display());
+        }
+        Ok(Self {
+            aligner,
+            color: std::io::stdout().is_terminal(),
+        })
+    }
+
+    pub fn push(&mut self, text: &str) -> Vec<ScriptEvent> {
+        self.aligner.push(text)
+    }
+
+    pub fn finish(&mut self) -> Vec<ScriptEvent> {
+        self.aligner.finish()
+    }
+
+    pub fn word(&self, index: usize) -> &str {
+        self.aligner.word(index)
+    }
+
+    /// Transcript text of an event: `[-skipped-]`, `{+extra+}`, misread words show both.
+    pub fn render(&self, event: &ScriptEvent) -> String {
+        let paint = |code: &str, s: String| {
+            if self.color {
+                format!("\x1b[{code}m{s}\x1b[0m")
+            } else {
+                s
+            }
+        };
+        let del = |index: usize| paint("31", format!("[-{}-]", self.word(index)));
+        let ins = |w: &str| paint("32", format!("{{+{w}+}}"));
+        match event {
+            ScriptEvent::Read { index } => format!(" {}", self.word(*index)),
+            ScriptEvent::Misread { index, heard } => format!(" {}{}", del(*index), ins(heard)),
+            ScriptEvent::Skipped { index } => format!(" {}", del(*index)),
+            ScriptEvent::Extra { heard } => format!(" {}", ins(heard)),
+        }
+    }
+
+    pub fn summary(&self) -> ScriptSummary {
+        let counts = self.aligner.counts();
+        ScriptSummary {
+            read: self.aligner.position(),
+            words: self.aligner.len(),
+            accuracy: self.aligner.accuracy(),
+            substitutions: counts.substitutions,
+            deletions: counts.deletions,
+            insertions: counts.insertions,
+        }
+    }
+
+    pub fn summary_line(&self) -> String {
+        let s = self.summary();
+        format!(
+            "Script: {}/{} words, accuracy {:.1}% (S {} D {} I {})",
+            s.read,
+            s.words,
+            s.accuracy * 100.0,
+            s.substitutions,
+            s.deletions,
+            s.insertions
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_deviations() {
+        let follower = ScriptFollower {
+            aligner: ScriptAligner::new("the cat sat"),
+            color: false,
+        };
+        let misread = ScriptEvent::Misread {
+            index: 1,
+            heard: "bat".to_string(),
+        };
+        assert_eq!(follower.render(&misread), " [-cat-]{+bat+}");
+        assert_eq!(follower.render(&ScriptEvent::Read { index: 2 }), " sat");
+        assert_eq!(
+            follower.summary_line(),
+            "Script: 0/3 words, accuracy 100.0% (S 0 D 0 I 0)"
+        );
+    }
+}
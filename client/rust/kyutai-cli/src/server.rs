@@ -0,0 +1,331 @@
+use crate::profile::Profile;
+use anyhow::{Context, Result};
+use clap::Args;
+use kyutai_client_core::{auth, discovery};
+use serde_json::Value;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+const DEFAULT_BASE_URL: &str = "http://localhost:8080";
+const DISCOVERY_TIMEOUT_MS: u64 = 5000;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const TTS_LOAD_TEXT: &str = "The quick brown fox jumps over the lazy dog, then naps in the sun.";
+
+#[derive(Args, Debug)]
+pub struct StatusArgs {
+    /// Query a server discovered on the local network by name (overrides --url)
+    #[arg(long)]
+    pub server: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// Query a server discovered on the local network by name (overrides --url)
+    #[arg(long)]
+    pub server: Option<String>,
+
+    /// Seconds of synthetic audio streamed by the server [default: 5]
+    #[arg(long)]
+    pub seconds: Option<f64>,
+
+    /// Path of the batched ASR module to benchmark [default: the first one]
+    #[arg(long)]
+    pub module: Option<String>,
+
+    /// Keep N tts requests running during the benchmark, to time the steps under mixed load
+    #[arg(long, value_name = "N", requires = "tts_voice")]
+    pub tts_load: Option<usize>,
+
+    /// Voice of the --tts-load requests
+    #[arg(long)]
+    pub tts_voice: Option<String>,
+
+    /// Path of the tts module for --tts-load
+    #[arg(long, default_value = "/api/tts")]
+    pub tts_path: String,
+}
+
+/// The HTTP root of the server of `url`, which can be the WebSocket URL of one of its modules.
+pub fn http_base(url: &str) -> Result<url::Url> {
+    let mut url = url::Url::parse(url).with_context(|| format!("invalid url '{url}'"))?;
+    let scheme = match url.scheme() {
+        "ws" | "http" => "http",
+        "wss" | "https" => "https",
+        other => anyhow::bail!("unsupported scheme '{other}' in {url}"),
+    };
+    url.set_scheme(scheme)
+        .map_err(|()| anyhow::anyhow!("cannot use {scheme} for {url}"))?;
+    url.set_path("/");
+    url.set_query(None);
+    url.set_fragment(None);
+    Ok(url)
+}
+
+/// `--server`, then `--url`, then the URLs and the server of the profile, then localhost.
+async fn resolve_base(
+    url: Option<&str>,
+    server: Option<&str>,
+    profile: Option<&Profile>,
+) -> Result<url::Url> {
+    if let Some(name) = server {
+        return discover_base(name).await;
+    }
+    let profile_url = profile.and_then(|p| p.stt_url.as_deref().or(p.tts_url.as_deref()));
+    if let Some(url) = url.or(profile_url) {
+        return http_base(url);
+    }
+    match profile.and_then(|p| p.server.as_deref()) {
+        Some(name) => discover_base(name).await,
+        None => http_base(DEFAULT_BASE_URL),
+    }
+}
+
+async fn discover_base(name: &str) -> Result<url::Url> {
+    let found = discovery::find_server(name, Duration::from_millis(DISCOVERY_TIMEOUT_MS)).await?;
+    let base = found
+        .base_url()
+        .with_context(|| format!("server '{name}' has no address"))?;
+    eprintln!("Discovered '{}' at {base}", found.name);
+    http_base(&base)
+}
+
+/// GET `path` and return the JSON body, the `code` and `message` of an error body end up in
+/// the error.
+async fn get_json(
+    base: &url::Url,
+    path: &str,
+    query: &[(&str, String)],
+    token: Option<&str>,
+    timeout: Duration,
+) -> Result<Value> {
+    let url = base.join(path)?;
+    let client = reqwest::Client::builder().timeout(timeout).build()?;
+    let mut request = client.get(url.clone()).query(query);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("cannot reach {url}"))?;
+    let status = response.status();
+    let body = response.text().await?;
+    let value: Option<Value> = serde_json::from_str(&body).ok();
+    if !status.is_success() {
+        let detail = match &value {
+            Some(v) => format!(
+                "{} ({})",
+                v["code"].as_str().unwrap_or("error"),
+                v["message"].as_str().unwrap_or(body.trim())
+            ),
+            None => body.trim().to_string(),
+        };
+        anyhow::bail!("{url} answered {status}: {detail}")
+    }
+    value.with_context(|| format!("{url} did not answer JSON"))
+}
+
+/// Keep `n` requests to the tts module at `path` in flight until `stop`, counting the ones
+/// that completed. Each request has its own seed so that the tts cache does not answer it.
+fn spawn_tts_load(
+    url: url::Url,
+    voice: String,
+    token: Option<String>,
+    n: usize,
+    stop: Arc<AtomicBool>,
+    done: Arc<AtomicUsize>,
+) -> Result<Vec<tokio::task::JoinHandle<()>>> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(120))
+        .build()?;
+    let seed = Arc::new(AtomicUsize::new(0));
+    let tasks = (0..n)
+        .map(|_| {
+            let (client, url) = (client.clone(), url.clone());
+            let (voice, token) = (voice.clone(), token.clone());
+            let (stop, done, seed) = (stop.clone(), done.clone(), seed.clone());
+            tokio::spawn(async move {
+                while !stop.load(Ordering::Relaxed) {
+                    let body = serde_json::json!({
+                        "text": [TTS_LOAD_TEXT],
+                        "voice": voice,
+                        "seed": seed.fetch_add(1, Ordering::Relaxed),
+                        "temperature": 0.8,
+                        "top_k": 250,
+                    });
+                    let mut request = client.post(url.clone()).json(&body);
+                    if let Some(token) = token.as_deref() {
+                        request = request.bearer_auth(token);
+                    }
+                    let response = request.send().await.and_then(|r| r.error_for_status());
+                    match response {
+                        Ok(response) => {
+                            if response.bytes().await.is_ok() {
+                                done.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                        Err(err) => {
+                            eprintln!("tts load request failed: {err}");
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    Ok(tasks)
+}
+
+fn resolve_token(token: Option<&str>, profile: Option<&Profile>) -> Result<Option<String>> {
+    if token.is_some() {
+        return Ok(token.map(str::to_string));
+    }
+    if let Some(token) = profile.map(Profile::resolve_token).transpose()?.flatten() {
+        return Ok(Some(token));
+    }
+    auth::AuthResolver::new(crate::USER_AGENT).resolve(false)
+}
+
+fn format_uptime(seconds: u64) -> String {
+    match seconds {
+        s if s >= 86400 => format!("{}d{}h", s / 86400, (s % 86400) / 3600),
+        s if s >= 3600 => format!("{}h{:02}m", s / 3600, (s % 3600) / 60),
+        s => format!("{}m{:02}s", s / 60, s % 60),
+    }
+}
+
+pub async fn run_status(
+    args: StatusArgs,
+    global: &crate::GlobalArgs,
+    profile: Option<&Profile>,
+) -> Result<()> {
+    let base = resolve_base(global.url.as_deref(), args.server.as_deref(), profile).await?;
+    let status = get_json(&base, "api/status", &[], None, REQUEST_TIMEOUT).await?;
+    if global.json {
+        println!("{}", serde_json::to_string(&status)?);
+        return Ok(());
+    }
+    let build = &status["build"];
+    println!(
+        "{base}  {}, up {} (version {})",
+        status["status"].as_str().unwrap_or("unknown"),
+        format_uptime(status["uptime_seconds"].as_u64().unwrap_or(0)),
+        build["git_describe"].as_str().unwrap_or("unknown"),
+    );
+    let capacity = &status["capacity"];
+    println!(
+        "Slots: {} used of {}",
+        capacity["used_slots"].as_u64().unwrap_or(0),
+        capacity["total_slots"].as_u64().unwrap_or(0)
+    );
+    for module in capacity["modules"].as_array().into_iter().flatten() {
+        println!(
+            "    {:<24} {:<12} {}/{}",
+            module["name"].as_str().unwrap_or(""),
+            module["module_type"].as_str().unwrap_or(""),
+            module["used_slots"].as_u64().unwrap_or(0),
+            module["total_slots"].as_u64().unwrap_or(0)
+        );
+    }
+    Ok(())
+}
+
+pub async fn run_bench(
+    args: BenchArgs,
+    global: &crate::GlobalArgs,
+    profile: Option<&Profile>,
+) -> Result<()> {
+    let base = resolve_base(global.url.as_deref(), args.server.as_deref(), profile).await?;
+    let token = resolve_token(global.token.as_deref(), profile)?;
+    let mut query = vec![];
+    if let Some(seconds) = args.seconds {
+        query.push(("seconds", seconds.to_string()));
+    }
+    if let Some(module) = args.module {
+        query.push(("module", module));
+    }
+    // The server streams the audio in real time before answering.
+    let timeout = REQUEST_TIMEOUT + Duration::from_secs_f64(args.seconds.unwrap_or(5.0).max(0.0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let done = Arc::new(AtomicUsize::new(0));
+    let load = match (args.tts_load, args.tts_voice) {
+        (Some(n), Some(voice)) if n > 0 => {
+            let url = base.join(args.tts_path.trim_start_matches('/'))?;
+            let tasks = spawn_tts_load(url, voice, token.clone(), n, stop.clone(), done.clone())?;
+            // Let the tts requests start generating before the steps are timed.
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            tasks
+        }
+        _ => vec![],
+    };
+    let report = get_json(
+        &base,
+        "api/bench/latency",
+        &query,
+        token.as_deref(),
+        timeout,
+    )
+    .await;
+    stop.store(true, Ordering::Relaxed);
+    for task in load.iter() {
+        task.abort();
+    }
+    let mut report = report?;
+    if let Some(n) = args.tts_load.filter(|_| !load.is_empty()) {
+        report["tts_load"] =
+            serde_json::json!({ "concurrency": n, "completed": done.load(Ordering::Relaxed) });
+    }
+    if global.json {
+        println!("{}", serde_json::to_string(&report)?);
+        return Ok(());
+    }
+    let ms = |key: &str| report[key].as_f64().unwrap_or(f64::NAN);
+    println!(
+        "{}: {} steps over {:.1}s of audio, RTF {:.2}",
+        report["module"].as_str().unwrap_or("?"),
+        report["steps"].as_u64().unwrap_or(0),
+        ms("audio_s"),
+        ms("real_time_factor")
+    );
+    println!(
+        "Step latency: mean {:.1}ms, p50 {:.1}ms, p90 {:.1}ms, p99 {:.1}ms, max {:.1}ms",
+        ms("mean_ms"),
+        ms("p50_ms"),
+        ms("p90_ms"),
+        ms("p99_ms"),
+        ms("max_ms")
+    );
+    if let Some(load) = report.get("tts_load") {
+        println!(
+            "Under tts load: {} concurrent requests, {} completed during the run",
+            load["concurrency"], load["completed"]
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_base_of_module_urls() {
+        let base = |url| http_base(url).unwrap().to_string();
+        assert_eq!(base("ws://gpu:8080/api/asr-streaming"), "http://gpu:8080/");
+        assert_eq!(
+            base("wss://stt.example.com/api/tts-streaming?x=1"),
+            "https://stt.example.com/"
+        );
+        assert_eq!(base("http://localhost:8080"), "http://localhost:8080/");
+        assert!(http_base("ftp://gpu/").is_err());
+        assert!(http_base("gpu:8080").is_err());
+    }
+
+    #[test]
+    fn uptime() {
+        assert_eq!(format_uptime(75), "1m15s");
+        assert_eq!(format_uptime(3 * 3600 + 120), "3h02m");
+        assert_eq!(format_uptime(2 * 86400 + 5 * 3600), "2d5h");
+    }
+}
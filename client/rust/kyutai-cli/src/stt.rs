@@ -1,14 +1,26 @@
-use anyhow::{Result, Context};
+use crate::keys::{self, KeyAction, KeyControls, eprint_line};
+use crate::out_file::{Rotation, TranscriptFile};
+use crate::profile::Profile;
+use crate::script::{ScriptFollower, ScriptSummary};
+use anyhow::{Context, Result};
 use clap::{Args, Subcommand};
 use kyutai_client::stt::audio::{
-    AudioLevel, LevelMeter, MicCapture, MicCaptureConfig, ResampleQuality,
+    AudioLevel, CaptureBackend, CaptureSource, ClipDetector, LevelMeter, MicCapture,
+    MicCaptureConfig, ResampleQuality,
 };
-use kyutai_client::stt::protocol::InMsg;
-use kyutai_client::stt::{SttClientBuilder, SttEvent};
+use kyutai_client::stt::local::LocalAsrConfig;
+use kyutai_client::stt::mimi::LocalMimiConfig;
+use kyutai_client::stt::protocol::{InMsg, OtherSpeech};
+use kyutai_client::stt::spill::SpillConfig;
+use kyutai_client::stt::transcript::align::ScriptEvent;
+use kyutai_client::stt::{Engine, SttClientBuilder, SttEvent};
+use kyutai_client_core::audio::DynResampler as FileResampler;
 use kyutai_client_core::auth;
-use kyutai_client_core::audio::{DynResampler as FileResampler};
+use serde::Serialize;
 use std::io::{IsTerminal, Write};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use tokio::sync::{mpsc, watch};
 use tokio::time::{Instant, interval, sleep_until};
@@ -19,16 +31,53 @@ const OUTPUT_CHUNK_SAMPLES: usize = 1920;
 const FILE_INPUT_CHUNK_SAMPLES: usize = 4096;
 const LEVEL_RENDER_INTERVAL: Duration = Duration::from_millis(50);
 const PROGRESS_RENDER_INTERVAL: Duration = Duration::from_millis(200);
+const DISCOVERY_TIMEOUT_MS: u64 = 5000;
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+/// Audio over which the clipped samples are counted, and the time between two clipping
+/// warnings.
+const CLIP_WINDOW_SAMPLES: usize = 2 * OUTPUT_SAMPLE_RATE_HZ;
+const CLIP_WARN_EVERY: Duration = Duration::from_secs(10);
+/// Silence sent after the last mic audio when finishing with `q`, so that the words still in
+/// the model delay come out, and how long to wait for them.
+const FINISH_SILENCE_MS: u64 = 2000;
+const FINISH_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_URL: &str = "ws://localhost:8080/api/asr-streaming";
 
 #[derive(Args, Debug)]
 pub struct SttArgs {
-    /// WebSocket URL for the STT server
-    #[arg(long, default_value = "ws://localhost:8080/api/asr-streaming")]
-    pub url: String,
+    /// WebSocket URL for the STT server, from the global --url
+    #[arg(skip)]
+    pub url: Option<String>,
 
-    /// Bearer token for authentication
+    /// Connect to a server discovered on the local network by name (overrides --url)
     #[arg(long)]
-    pub auth_token: Option<String>,
+    pub server: Option<String>,
+
+    /// Server to fail over to when the current one fails, can be repeated
+    #[arg(long = "failover-url", value_name = "URL")]
+    pub failover_urls: Vec<url::Url>,
+
+    /// Continue with a local model when no server can be reached, the model is read from
+    /// this STT server config (a .gguf lm_model_file is loaded quantized)
+    #[arg(long, value_name = "CONFIG")]
+    pub fallback_local: Option<PathBuf>,
+
+    /// Send the audio as Mimi codes, about 50 times less data than pcm, encoded with the Mimi
+    /// of this STT server config (batched asr only)
+    #[arg(long, value_name = "CONFIG")]
+    pub mimi_codes: Option<PathBuf>,
+
+    /// Bearer token for authentication, from the global --token
+    #[arg(skip)]
+    pub token: Option<String>,
+
+    /// Auth server renewing the token, from the global --auth-url
+    #[arg(skip)]
+    pub token_source: Option<Arc<auth::BetterAuthClient>>,
+
+    /// Print the words and markers as JSON lines, from the global --json
+    #[arg(skip)]
+    pub json: bool,
 
     /// Query parameter token for authentication
     #[arg(long)]
@@ -46,6 +95,41 @@ pub struct SttArgs {
     #[arg(long)]
     pub buffered_output: bool,
 
+    /// Also write the transcript to this file, one timestamped line per utterance
+    #[arg(long)]
+    pub out_file: Option<PathBuf>,
+
+    /// Start a new --out-file after a duration or size, e.g. 30min, 2h, 10MB
+    #[arg(long, requires = "out_file")]
+    pub rotate: Option<Rotation>,
+
+    /// Print the input level, clipping, speech ratio, speaking rate and overlapping speech
+    /// measured by the server
+    #[arg(long)]
+    pub stats: bool,
+
+    /// Print the round trip to the server and the delay between speaking a word and seeing it
+    #[arg(long)]
+    pub latency: bool,
+
+    /// Mark the transcript when this phrase is heard, e.g. --spot "next slide", repeatable
+    #[arg(long)]
+    pub spot: Vec<String>,
+
+    /// Write the numbers with digits, e.g. "twenty three dollars" as "$23"
+    #[arg(long)]
+    pub itn: bool,
+
+    /// Ask the server to send the messages of each N milliseconds in a single frame, which
+    /// saves frames when transcribing files faster than real time
+    #[arg(long, value_name = "N")]
+    pub coalesce_ms: Option<u64>,
+
+    /// Teleprompter mode: follow the words along this script, mark the deviations and report
+    /// the accuracy after each utterance
+    #[arg(long, value_name = "FILE")]
+    pub script: Option<PathBuf>,
+
     #[command(subcommand)]
     pub command: SttCommand,
 }
@@ -54,10 +138,12 @@ pub struct SttArgs {
 pub enum SttCommand {
     /// Stream audio from microphone
     Mic(MicArgs),
+    /// Caption the audio played by this machine, from a monitor or loopback device
+    SystemAudio(MicArgs),
     /// Stream audio from file
     File(FileArgs),
-    /// Generate a JWT token
-    Token(TokenArgs),
+    /// Transcribe audio and score it against reference transcripts (WER/CER)
+    Eval(crate::eval::EvalArgs),
 }
 
 #[derive(Args, Debug)]
@@ -85,6 +171,56 @@ pub struct MicArgs {
     /// Use high-quality resampling
     #[arg(long)]
     pub hq_resample: bool,
+
+    /// Input device name (defaults to the system default input)
+    #[arg(long)]
+    pub device: Option<String>,
+
+    /// Software gain applied to the input, in dB, e.g. --gain 12 for a quiet laptop mic
+    #[arg(
+        long,
+        value_name = "DB",
+        default_value = "0",
+        allow_negative_numbers = true
+    )]
+    pub gain: f32,
+
+    /// Adjust the gain to keep speech at a steady level, starting from --gain
+    #[arg(long)]
+    pub auto_gain: bool,
+
+    /// Also record the microphone audio to this file, .wav or .flac
+    #[arg(long)]
+    pub record: Option<PathBuf>,
+
+    /// Only transcribe the voice of this clip (2 to 10s of your speech), other speakers show
+    /// as [other]
+    #[arg(long, value_name = "FILE")]
+    pub enroll: Option<PathBuf>,
+
+    /// With --enroll, drop the words of other speakers instead of showing [other]
+    #[arg(long, requires = "enroll")]
+    pub suppress_other: bool,
+
+    /// Keep the audio on disk while the connection is stalled and replay it once it recovers,
+    /// for outages of up to this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub spill_max_lag_s: Option<u64>,
+
+    /// Capture through a native PipeWire stream, --device then names a PipeWire node
+    #[cfg(feature = "pipewire")]
+    #[arg(long)]
+    pub pipewire: bool,
+}
+
+impl MicArgs {
+    fn capture_backend(&self) -> CaptureBackend {
+        #[cfg(feature = "pipewire")]
+        if self.pipewire {
+            return CaptureBackend::PipeWire;
+        }
+        CaptureBackend::Cpal
+    }
 }
 
 #[derive(Args, Debug)]
@@ -115,105 +251,243 @@ pub struct FileArgs {
     pub hq_resample: bool,
 }
 
-#[derive(Args, Debug)]
-pub struct TokenArgs {
-    /// Token validity in hours
-    #[arg(long, default_value = "1.0")]
-    pub hours: f64,
+impl SttArgs {
+    /// Fill the arguments that were not given on the command line from `profile`.
+    fn apply_profile(&mut self, profile: &Profile) -> Result<()> {
+        if self.url.is_none() && self.server.is_none() {
+            self.url = profile.stt_url.clone();
+            if self.url.is_none() {
+                self.server = profile.server.clone();
+            }
+        }
+        if self.token.is_none() {
+            self.token = profile.resolve_token()?;
+        }
+        if self.env.is_none() {
+            self.env = profile.env.clone();
+        }
+        self.json |= profile.json.unwrap_or(false);
+        let auto_token = profile.auto_token.unwrap_or(false);
+        let hq_resample = profile.hq_resample.unwrap_or(false);
+        match &mut self.command {
+            SttCommand::Mic(mic) => {
+                mic.auto_token |= auto_token;
+                mic.hq_resample |= hq_resample;
+                mic.timestamps |= profile.timestamps.unwrap_or(false);
+                if mic.device.is_none() {
+                    mic.device = profile.device.clone();
+                }
+            }
+            SttCommand::SystemAudio(mic) => {
+                mic.auto_token |= auto_token;
+                mic.hq_resample |= hq_resample;
+                mic.timestamps |= profile.timestamps.unwrap_or(false);
+            }
+            SttCommand::File(file) => {
+                file.auto_token |= auto_token;
+                file.hq_resample |= hq_resample;
+            }
+            SttCommand::Eval(eval) => {
+                eval.auto_token |= auto_token;
+                eval.hq_resample |= hq_resample;
+            }
+        }
+        Ok(())
+    }
 }
 
-pub async fn run_stt(args: SttArgs) -> Result<()> {
+pub async fn run_stt(mut args: SttArgs, profile: Option<&Profile>) -> Result<()> {
+    if let Some(profile) = profile {
+        args.apply_profile(profile)?;
+    }
+    let mut url = args.url.clone().unwrap_or_else(|| DEFAULT_URL.to_string());
+    if let Some(server) = args.server.as_deref() {
+        url = crate::discover::resolve_server_url(
+            server,
+            kyutai_client_core::discovery::STT_KINDS,
+            DISCOVERY_TIMEOUT_MS,
+        )
+        .await?;
+    }
+    let source = match &args.command {
+        SttCommand::SystemAudio(_) => CaptureSource::Loopback,
+        _ => CaptureSource::Input,
+    };
     match args.command {
-        SttCommand::Mic(mic_args) => {
+        SttCommand::Mic(mic_args) | SttCommand::SystemAudio(mic_args) => {
             let auth_token = resolve_auth_token(
-                &args.auth_token,
+                &args.token,
                 &args.secret,
                 args.env.as_deref(),
                 mic_args.auto_token,
             )?;
-            run_mic(
-                args.url,
+            let out_file = open_out_file(args.out_file.as_deref(), args.rotate)?;
+            let builder = client_builder(
+                url,
+                args.failover_urls,
                 auth_token,
                 args.query_token,
-                mic_args,
-                args.buffered_output,
-            )
-            .await?
+                args.fallback_local.as_deref(),
+                args.stats,
+                args.latency,
+            )?
+            .spot(args.spot)
+            .itn(args.itn);
+            let builder = match args.token_source {
+                Some(source) => builder.token_source(source),
+                None => builder,
+            };
+            let builder = match args.coalesce_ms {
+                Some(ms) => builder.coalesce(Duration::from_millis(ms)),
+                None => builder,
+            };
+            let builder = match args.mimi_codes.as_deref() {
+                Some(path) => builder.local_mimi(LocalMimiConfig::from_server_config(path)?),
+                None => builder,
+            };
+            let mut transcript = TranscriptOutput::new(args.buffered_output, args.json);
+            transcript.script = args.script.as_deref().map(ScriptFollower::load).transpose()?;
+            run_mic(builder, mic_args, source, transcript, out_file).await?
         }
         SttCommand::File(file_args) => {
             let auth_token = resolve_auth_token(
-                &args.auth_token,
+                &args.token,
                 &args.secret,
                 args.env.as_deref(),
                 file_args.auto_token,
             )?;
-            run_file(
-                args.url,
+            let out_file = open_out_file(args.out_file.as_deref(), args.rotate)?;
+            let builder = client_builder(
+                url,
+                args.failover_urls,
                 auth_token,
                 args.query_token,
-                file_args,
-                args.buffered_output,
-            )
-            .await?
+                args.fallback_local.as_deref(),
+                args.stats,
+                args.latency,
+            )?
+            .spot(args.spot)
+            .itn(args.itn);
+            let builder = match args.token_source {
+                Some(source) => builder.token_source(source),
+                None => builder,
+            };
+            let builder = match args.coalesce_ms {
+                Some(ms) => builder.coalesce(Duration::from_millis(ms)),
+                None => builder,
+            };
+            let builder = match args.mimi_codes.as_deref() {
+                Some(path) => builder.local_mimi(LocalMimiConfig::from_server_config(path)?),
+                None => builder,
+            };
+            let mut transcript = TranscriptOutput::new(args.buffered_output, args.json);
+            transcript.script = args.script.as_deref().map(ScriptFollower::load).transpose()?;
+            run_file(builder, file_args, transcript, out_file).await?
+        }
+        SttCommand::Eval(eval_args) => {
+            let auth_token = resolve_auth_token(
+                &args.token,
+                &args.secret,
+                args.env.as_deref(),
+                eval_args.auto_token,
+            )?;
+            crate::eval::run_eval(url, auth_token, args.query_token, eval_args).await?
         }
-        SttCommand::Token(token_args) => run_token(&args.secret, args.env.as_deref(), token_args)?,
     }
     Ok(())
 }
 
+fn client_builder(
+    url: String,
+    failover_urls: Vec<url::Url>,
+    auth_token: Option<String>,
+    query_token: Option<String>,
+    fallback_local: Option<&std::path::Path>,
+    stats: bool,
+    latency: bool,
+) -> Result<SttClientBuilder> {
+    let mut builder = SttClientBuilder::new().url(url).urls(failover_urls);
+    if let Some(token) = auth_token {
+        builder = builder.auth_token(token);
+    }
+    if let Some(token) = query_token {
+        builder = builder.query_token(token);
+    }
+    if let Some(path) = fallback_local {
+        builder = builder.local_fallback(LocalAsrConfig::from_server_config(path)?);
+    }
+    if stats {
+        builder = builder.stats(STATS_INTERVAL).speakers(STATS_INTERVAL);
+    }
+    Ok(builder.latency(latency))
+}
+
+/// Prefix the first word of each engine run with a `[local]`/`[server]` tag.
+fn tag_engine(text: &str, engine: Engine, last: &mut Engine) -> String {
+    if engine == *last {
+        return text.to_string();
+    }
+    *last = engine;
+    match engine {
+        Engine::Local => format!(" [local] {}", text.trim_start()),
+        Engine::Server => format!(" [server] {}", text.trim_start()),
+    }
+}
+
+fn open_out_file(
+    path: Option<&std::path::Path>,
+    rotate: Option<Rotation>,
+) -> Result<Option<TranscriptFile>> {
+    path.map(|p| TranscriptFile::create(p, rotate)).transpose()
+}
+
 fn resolve_auth_token(
     auth_token: &Option<String>,
     secret: &Option<String>,
     env_name: Option<&str>,
     auto_token: bool,
 ) -> Result<Option<String>> {
-    let resolver = auth::AuthResolver::new("kyutai-cli/0.1.0")
+    let resolver = auth::AuthResolver::new(crate::USER_AGENT)
         .with_token(auth_token.as_deref())
         .with_secret(secret.as_deref())
         .with_env(env_name);
 
-    let token = resolver.resolve(auto_token)?;
-    if auto_token && token.is_some() {
-        eprintln!("Generated auth token (valid for 1 hour)");
-    }
-    Ok(token)
-}
-
-fn run_token(secret: &Option<String>, env_name: Option<&str>, args: TokenArgs) -> Result<()> {
-    let resolver = auth::AuthResolver::new("kyutai-cli/0.1.0")
-        .with_secret(secret.as_deref())
-        .with_env(env_name);
-
-    let base_dir = std::env::current_dir()?;
-    let secret_val = auth::resolve_secret(resolver.secret, &base_dir, resolver.env_name)?;
-    let token = auth::generate_token(&secret_val, args.hours, resolver.user_agent)
-        .map_err(|e| anyhow::anyhow!("Failed to generate token: {}", e))?;
-
-    println!("{token}");
-    Ok(())
+    resolver.resolve(auto_token)
 }
 
 async fn run_mic(
-    url: String,
-    auth_token: Option<String>,
-    query_token: Option<String>,
+    builder: SttClientBuilder,
     mic_args: MicArgs,
-    buffered_output: bool,
+    source: CaptureSource,
+    mut transcript: TranscriptOutput,
+    mut out_file: Option<TranscriptFile>,
 ) -> Result<()> {
-    let mut builder = SttClientBuilder::new().url(url);
-    if let Some(token) = auth_token {
-        builder = builder.auth_token(token);
-    }
-    if let Some(token) = query_token {
-        builder = builder.query_token(token);
-    }
-
     eprintln!("Connecting to STT server...");
+    let builder = match mic_args.spill_max_lag_s {
+        Some(s) => builder.spill(SpillConfig::new(Duration::from_secs(s))),
+        None => builder,
+    };
     let session = builder.connect().await?;
     let mut events = session.into_event_stream();
-    eprintln!("Connected! Listening for speech... (Ctrl+C to stop)");
+    let mut keys = KeyControls::start();
+    match keys {
+        Some(_) => eprint_line(&format!(
+            "Connected! Listening for speech...\n{}",
+            keys::HELP
+        )),
+        None => eprintln!("Connected! Listening for speech... (Ctrl+C to stop)"),
+    }
 
     let sender = events.sender();
+    if let Some(path) = mic_args.enroll.as_deref() {
+        let pcm = load_enrollment(path)?;
+        let other = if mic_args.suppress_other {
+            OtherSpeech::Suppress
+        } else {
+            OtherSpeech::Tag
+        };
+        sender.send(InMsg::Enroll { pcm, other }).await?;
+    }
     if mic_args.silence_prefix_ms > 0 {
         send_silence_prefix(&sender, mic_args.silence_prefix_ms).await?;
     }
@@ -221,17 +495,21 @@ async fn run_mic(
     let resample_quality = if mic_args.hq_resample {
         ResampleQuality::High
     } else {
-        ResampleQuality::Linear
+        ResampleQuality::Polyphase
     };
     let mut mic = MicCapture::start_default_with_config(MicCaptureConfig {
         resample_quality,
+        device: mic_args.device.clone(),
+        source,
+        gain_db: mic_args.gain,
+        auto_gain: mic_args.auto_gain,
+        backend: mic_args.capture_backend(),
     })?;
     let stderr_is_tty = std::io::stderr().is_terminal();
     let show_level = mic_args.show_level && stderr_is_tty;
-    let stdout_is_tty = std::io::stdout().is_terminal();
-    let mut transcript = TranscriptOutput::new(buffered_output || !stdout_is_tty);
 
     let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+    let paused = Arc::new(AtomicBool::new(false));
 
     let (level_tx, level_rx) = if show_level {
         let (tx, rx) = mpsc::channel::<AudioLevel>(16);
@@ -240,80 +518,204 @@ async fn run_mic(
         (None, None)
     };
 
-    let audio_task = tokio::spawn({
+    let mut recorder = match mic_args.record.as_deref() {
+        Some(path) => Some(
+            kyutai_audio_io::AudioWriter::create(path, OUTPUT_SAMPLE_RATE_HZ as u32)
+                .with_context(|| format!("Failed to create {}", path.display()))?,
+        ),
+        None => None,
+    };
+    let mut audio_task = Some(tokio::spawn({
         let level_tx = level_tx.clone();
+        let paused = paused.clone();
+        let verbose = mic_args.verbose;
         async move {
             let mut meter = LevelMeter::default();
+            let mut latency_shown = false;
+            let mut clips = ClipDetector::new(CLIP_WINDOW_SAMPLES);
+            let mut last_clip_warning: Option<Instant> = None;
             loop {
                 tokio::select! {
                     _ = shutdown_rx.changed() => break,
                     chunk = mic.recv() => {
                         let Some(chunk) = chunk else { break; };
+                        if verbose && !latency_shown && let Some(latency) = mic.latency() {
+                            latency_shown = true;
+                            eprint_line(&format!(
+                                "\ncapture latency: {:.1}ms",
+                                latency.as_secs_f64() * 1000.0
+                            ));
+                        }
                         if let Some(tx) = &level_tx {
                             let level = meter.process(&chunk.samples);
                             let _ = tx.try_send(level);
                         }
+                        if let Some(ratio) = clips.process(&chunk.samples)
+                            && last_clip_warning.is_none_or(|t| t.elapsed() >= CLIP_WARN_EVERY)
+                        {
+                            last_clip_warning = Some(Instant::now());
+                            eprint_line(&format!(
+                                "\nwarning: the input is clipping ({:.1}% of samples), lower \
+                                 --gain or the input volume",
+                                ratio * 100.0
+                            ));
+                        }
+                        if paused.load(Ordering::Relaxed) { continue; }
+                        if let Some(w) = recorder.as_mut() { w.write(&chunk.samples)?; }
                         sender.send(InMsg::Audio { pcm: chunk.samples }).await?;
                     }
                 }
             }
+            if let Some(w) = recorder {
+                w.finalize()?;
+            }
             Ok::<(), anyhow::Error>(())
         }
-    });
+    }));
 
     let level_task = level_rx.map(|rx| spawn_level_task(rx, stderr_is_tty));
+    let mut engine = Engine::Server;
+    let mut stats = StatsReport::default();
+    let mut next_marker: i64 = 1;
+    // Marker closing the session once `q` is pressed, and the time to give up waiting for it.
+    let mut finish: Option<(i64, Instant)> = None;
 
     loop {
         tokio::select! {
             _ = tokio::signal::ctrl_c() => break,
+            _ = sleep_until(finish.map_or_else(Instant::now, |(_, deadline)| deadline)), if finish.is_some() => {
+                eprint_line("\nTimed out waiting for the last words");
+                break;
+            }
+            action = next_key(&mut keys) => {
+                if show_level { clear_status_line(stderr_is_tty); }
+                match action {
+                    KeyAction::TogglePause => {
+                        let was_paused = paused.fetch_xor(true, Ordering::Relaxed);
+                        transcript.flush()?;
+                        eprint_line(if was_paused { "\n[resumed]" } else { "\n[paused]" });
+                    }
+                    KeyAction::Marker => {
+                        events.sender().send(InMsg::Marker { id: next_marker }).await?;
+                        next_marker += 1;
+                    }
+                    KeyAction::NewParagraph => {
+                        transcript.new_paragraph()?;
+                        if let Some(f) = out_file.as_mut() { f.end_utterance()?; }
+                    }
+                    // A second `q` does not wait for the last words.
+                    KeyAction::Quit if finish.is_some() => break,
+                    KeyAction::Quit => {
+                        transcript.flush()?;
+                        eprint_line("\nFinishing...");
+                        let _ = shutdown_tx.send(true);
+                        let capture = audio_task.take();
+                        let sender = events.sender();
+                        let id = next_marker;
+                        // The capture is over before the silence and the marker go out.
+                        audio_task = Some(tokio::spawn(async move {
+                            if let Some(task) = capture { task.await??; }
+                            send_silence_prefix(&sender, FINISH_SILENCE_MS).await?;
+                            sender.send(InMsg::Marker { id }).await?;
+                            Ok(())
+                        }));
+                        finish = Some((id, Instant::now() + FINISH_TIMEOUT));
+                    }
+                }
+            }
             ev = events.recv() => {
                 let ev = ev?;
+                if let Some(line) = stats.line(&ev) {
+                    if show_level { clear_status_line(stderr_is_tty); }
+                    transcript.flush()?;
+                    eprint_line(&format!("\n{line}"));
+                }
                 match ev {
-                    SttEvent::WordReceived { text, start_ms } => {
+                    SttEvent::WordReceived { text, start_ms, engine: word_engine } => {
                         if show_level { clear_status_line(stderr_is_tty); }
-                        if mic_args.timestamps {
-                            transcript.write_timestamped(start_ms, &text)?;
+                        let tagged = tag_engine(&text, word_engine, &mut engine);
+                        if let Some(f) = out_file.as_mut() { f.push_word(start_ms, &tagged); }
+                        if transcript.script.is_some() {
+                            transcript.write_script_word(&text)?;
+                        } else if transcript.json {
+                            transcript.write_json_word(start_ms, &text, word_engine)?;
+                        } else if mic_args.timestamps {
+                            transcript.write_timestamped(start_ms, &tagged)?;
                         } else {
-                            transcript.write_word(&text)?;
+                            transcript.write_word(&tagged)?;
                         }
                     }
                     SttEvent::Error { message } => {
                         if show_level { clear_status_line(stderr_is_tty); }
                         transcript.flush()?;
-                        eprintln!("stt error: {message}");
+                        eprint_line(&format!("stt error: {message}"));
                     }
-                    SttEvent::VadStep { step_idx, prs, buffered_pcm } => {
-                        if mic_args.verbose {
-                            info!(step = step_idx, buffered_samples = buffered_pcm, "VAD step: prs={:?}", prs);
+                    SttEvent::UtteranceFinal(_) => {
+                        if let Some(f) = out_file.as_mut() { f.end_utterance()?; }
+                        if let Some(line) = transcript.script_summary_line() {
+                            if show_level { clear_status_line(stderr_is_tty); }
+                            transcript.flush()?;
+                            eprint_line(&format!("\n{line}"));
                         }
                     }
+                    SttEvent::EngineChanged { .. } => {
+                        if show_level { clear_status_line(stderr_is_tty); }
+                        transcript.flush()?;
+                        eprint_line("\nNo server reachable, transcribing locally (words tagged [local])");
+                    }
+                    SttEvent::Enrolled { voiced_ms } => {
+                        if show_level { clear_status_line(stderr_is_tty); }
+                        eprint_line(&format!("Enrolled voice ({:.1}s of speech)", voiced_ms as f64 / 1000.0));
+                    }
+                    SttEvent::PhraseSpotted { phrase, start_ms } => {
+                        if show_level { clear_status_line(stderr_is_tty); }
+                        transcript.write_phrase(&phrase, start_ms)?;
+                    }
+                    SttEvent::ConfigUpdate { asr_delay_ms, temperature } => {
+                        if show_level { clear_status_line(stderr_is_tty); }
+                        transcript.flush()?;
+                        eprint_line(&format!("\nServer retuned: delay {asr_delay_ms}ms, temperature {temperature}"));
+                    }
+                    SttEvent::StreamMarker { id, .. } if finish.is_some_and(|(last, _)| last == id) => break,
+                    SttEvent::StreamMarker { id, sample_idx, .. } => {
+                        if show_level { clear_status_line(stderr_is_tty); }
+                        transcript.write_marker(id, sample_idx / 24)?;
+                    }
+                    SttEvent::VadStep { step_idx, prs, buffered_pcm } if mic_args.verbose => {
+                        info!(step = step_idx, buffered_samples = buffered_pcm, "VAD step: prs={:?}", prs);
+                    }
                     _ => {}
                 }
             }
         }
     }
 
+    drop(keys);
     let _ = shutdown_tx.send(true);
     drop(level_tx);
-    let _ = audio_task.await;
+    if let Some(task) = audio_task
+        && let Ok(Err(err)) = task.await
+    {
+        eprintln!("audio error: {err:#}");
+    }
     if let Some(task) = level_task { let _ = task.await; }
+    transcript.finish_script()?;
     transcript.flush()?;
+    if let Some(f) = out_file.as_mut() {
+        f.end_utterance()?;
+    }
     events.shutdown().await?;
     Ok(())
 }
 
 async fn run_file(
-    url: String,
-    auth_token: Option<String>,
-    query_token: Option<String>,
+    builder: SttClientBuilder,
     file_args: FileArgs,
-    buffered_output: bool,
+    mut transcript: TranscriptOutput,
+    mut out_file: Option<TranscriptFile>,
 ) -> Result<()> {
-    let mut builder = SttClientBuilder::new().url(url);
-    if let Some(token) = auth_token { builder = builder.auth_token(token); }
-    if let Some(token) = query_token { builder = builder.query_token(token); }
-
-    let (pcm, sr_in) = kaudio::pcm_decode(&file_args.path).context("Failed to decode audio file")?;
+    let (pcm, sr_in) =
+        kyutai_audio_io::decode(&file_args.path).context("Failed to decode audio file")?;
     let rtf = file_args.rtf.filter(|v| v.is_finite() && *v > 0.0);
     let silence_prefix_samples = silence_samples_from_ms(file_args.silence_prefix_ms, OUTPUT_SAMPLE_RATE_HZ);
     let audio_samples = if sr_in as usize == OUTPUT_SAMPLE_RATE_HZ { pcm.len() } else {
@@ -327,8 +729,6 @@ async fn run_file(
     let sender = events.sender();
     let stderr_is_tty = std::io::stderr().is_terminal();
     let show_progress = file_args.progress && stderr_is_tty;
-    let stdout_is_tty = std::io::stdout().is_terminal();
-    let mut transcript = TranscriptOutput::new(buffered_output || !stdout_is_tty);
 
     let (progress_tx, progress_rx) = if show_progress {
         let (tx, rx) = mpsc::channel::<ProgressUpdate>(16);
@@ -338,100 +738,261 @@ async fn run_file(
     let progress_task = progress_rx.map(|rx| spawn_progress_task(rx, total_duration, stderr_is_tty));
 
     let marker_id: i64 = 1;
-    let silence_prefix_ms = file_args.silence_prefix_ms;
-    let progress_tx_for_send = progress_tx.clone();
-    let send_task: tokio::task::JoinHandle<Result<()>> = tokio::spawn(async move {
-        let chunk_duration = Duration::from_secs_f64(OUTPUT_CHUNK_SAMPLES as f64 / OUTPUT_SAMPLE_RATE_HZ as f64);
-        let start = Instant::now();
-        let mut chunk_idx: u64 = 0;
-        let mut samples_sent: usize = 0;
-        let quality = if file_args.hq_resample { ResampleQuality::High } else { ResampleQuality::Linear };
-        let mut resampler = FileResampler::new(sr_in, OUTPUT_SAMPLE_RATE_HZ as u32, quality)?;
-        let mut resample_buf = Vec::<f32>::with_capacity(FILE_INPUT_CHUNK_SAMPLES);
-        let mut pending = Vec::<f32>::with_capacity(OUTPUT_CHUNK_SAMPLES * 4);
-        let mut pending_read_idx = 0usize;
-
-        if silence_prefix_ms > 0 {
-            let silence_samples = silence_samples_from_ms(silence_prefix_ms, OUTPUT_SAMPLE_RATE_HZ);
-            let silence_chunks = silence_samples.div_ceil(OUTPUT_CHUNK_SAMPLES);
-            let silence_chunk = vec![0.0f32; OUTPUT_CHUNK_SAMPLES];
-            for idx in 0..silence_chunks {
-                sender.send(InMsg::Audio { pcm: silence_chunk.clone() }).await?;
-                samples_sent += silence_samples.saturating_sub(idx * OUTPUT_CHUNK_SAMPLES).min(OUTPUT_CHUNK_SAMPLES);
-                if let Some(tx) = &progress_tx_for_send {
-                    let _ = tx.try_send(ProgressUpdate { audio_elapsed: Duration::from_secs_f64(samples_sent as f64 / OUTPUT_SAMPLE_RATE_HZ as f64), wall_elapsed: start.elapsed() });
+    let mut engine = Engine::Server;
+    let mut stats = StatsReport::default();
+    let cfg = FileStreamConfig {
+        rtf,
+        silence_prefix_ms: file_args.silence_prefix_ms,
+        hq_resample: file_args.hq_resample,
+    };
+    let send_task: tokio::task::JoinHandle<Result<()>> = tokio::spawn(send_file_audio(
+        sender,
+        pcm,
+        sr_in,
+        cfg,
+        progress_tx.clone(),
+        marker_id,
+    ));
+
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            ev = events.recv() => {
+                let ev = ev?;
+                if let Some(line) = stats.line(&ev) {
+                    transcript.flush()?;
+                    eprintln!("\n{line}");
+                }
+                match ev {
+                    SttEvent::WordReceived { text, start_ms, engine: word_engine } => {
+                        let tagged = tag_engine(&text, word_engine, &mut engine);
+                        if let Some(f) = out_file.as_mut() { f.push_word(start_ms, &tagged); }
+                        if transcript.script.is_some() {
+                            transcript.write_script_word(&text)?;
+                        } else if transcript.json {
+                            transcript.write_json_word(start_ms, &text, word_engine)?;
+                        } else {
+                            transcript.write_word(&tagged)?;
+                        }
+                    }
+                    SttEvent::EngineChanged { .. } => {
+                        transcript.flush()?;
+                        eprintln!("\nNo server reachable, transcribing locally (words tagged [local])");
+                    }
+                    SttEvent::UtteranceFinal(_) => {
+                        if let Some(f) = out_file.as_mut() { f.end_utterance()?; }
+                        if let Some(line) = transcript.script_summary_line() {
+                            transcript.flush()?;
+                            eprintln!("\n{line}");
+                        }
+                    }
+                    SttEvent::PhraseSpotted { phrase, start_ms } => transcript.write_phrase(&phrase, start_ms)?,
+                    SttEvent::ConfigUpdate { asr_delay_ms, temperature } => {
+                        transcript.flush()?;
+                        eprintln!("server retuned: delay {asr_delay_ms}ms, temperature {temperature}");
+                    }
+                    SttEvent::StreamMarker { id, .. } if id == marker_id => break,
+                    SttEvent::Error { message } => { transcript.flush()?; eprintln!("stt error: {message}"); }
+                    _ => {}
                 }
-                chunk_idx += 1;
-                if let Some(rtf) = rtf { sleep_until(start + chunk_duration.mul_f64(chunk_idx as f64 / rtf)).await; }
             }
         }
+    }
+    transcript.finish_script()?;
+    transcript.flush()?;
+    if let Some(f) = out_file.as_mut() {
+        f.end_utterance()?;
+    }
+    events.shutdown().await?;
+    let _ = send_task.await;
+    if let Some(task) = progress_task {
+        let _ = task.await;
+    }
+    Ok(())
+}
 
-        for input_chunk in pcm.chunks(FILE_INPUT_CHUNK_SAMPLES) {
-            let samples = match resampler.as_mut() {
-                Some(r) => { r.process_into(input_chunk, &mut resample_buf)?; resample_buf.as_slice() }
-                None => input_chunk,
-            };
-            if samples.is_empty() { continue; }
-            pending.extend_from_slice(samples);
-            while pending.len().saturating_sub(pending_read_idx) >= OUTPUT_CHUNK_SAMPLES {
-                let chunk = pending[pending_read_idx..pending_read_idx + OUTPUT_CHUNK_SAMPLES].to_vec();
-                pending_read_idx += OUTPUT_CHUNK_SAMPLES;
-                sender.send(InMsg::Audio { pcm: chunk }).await?;
-                chunk_idx += 1;
-                samples_sent += OUTPUT_CHUNK_SAMPLES;
-                if let Some(tx) = &progress_tx_for_send {
-                    let _ = tx.try_send(ProgressUpdate { audio_elapsed: Duration::from_secs_f64(samples_sent as f64 / OUTPUT_SAMPLE_RATE_HZ as f64), wall_elapsed: start.elapsed() });
-                }
-                if let Some(rtf) = rtf { sleep_until(start + chunk_duration.mul_f64(chunk_idx as f64 / rtf)).await; }
+/// Stream an audio file without printing anything and return the recognized words.
+pub(crate) async fn transcribe_file(
+    url: &str,
+    auth_token: Option<&str>,
+    query_token: Option<&str>,
+    path: &std::path::Path,
+    cfg: FileStreamConfig,
+) -> Result<Vec<String>> {
+    let mut builder = SttClientBuilder::new().url(url);
+    if let Some(token) = auth_token {
+        builder = builder.auth_token(token);
+    }
+    if let Some(token) = query_token {
+        builder = builder.query_token(token);
+    }
+
+    let (pcm, sr_in) = kyutai_audio_io::decode(path)
+        .with_context(|| format!("Failed to decode audio file {}", path.display()))?;
+    let session = builder.connect().await?;
+    let mut events = session.into_event_stream();
+    let marker_id: i64 = 1;
+    let send_task = tokio::spawn(send_file_audio(
+        events.sender(),
+        pcm,
+        sr_in,
+        cfg,
+        None,
+        marker_id,
+    ));
+
+    let mut words = Vec::new();
+    loop {
+        match events.recv().await? {
+            SttEvent::WordReceived { text, .. } => words.push(text),
+            SttEvent::StreamMarker { id, .. } if id == marker_id => break,
+            SttEvent::Error { message } => anyhow::bail!("stt error: {message}"),
+            _ => {}
+        }
+    }
+    events.shutdown().await?;
+    send_task.await??;
+    Ok(words)
+}
+
+/// How a decoded file is paced and prefixed when streamed to the server.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct FileStreamConfig {
+    pub rtf: Option<f64>,
+    pub silence_prefix_ms: u64,
+    pub hq_resample: bool,
+}
+
+/// Resample `pcm` to 24kHz, stream it in 80ms chunks and send `marker_id` once done.
+async fn send_file_audio(
+    sender: kyutai_client::stt::SttSender,
+    pcm: Vec<f32>,
+    sr_in: u32,
+    cfg: FileStreamConfig,
+    progress_tx: Option<mpsc::Sender<ProgressUpdate>>,
+    marker_id: i64,
+) -> Result<()> {
+    let chunk_duration =
+        Duration::from_secs_f64(OUTPUT_CHUNK_SAMPLES as f64 / OUTPUT_SAMPLE_RATE_HZ as f64);
+    let start = Instant::now();
+    let mut chunk_idx: u64 = 0;
+    let mut samples_sent: usize = 0;
+    let quality = if cfg.hq_resample {
+        ResampleQuality::High
+    } else {
+        ResampleQuality::Polyphase
+    };
+    let mut resampler = FileResampler::new(sr_in, OUTPUT_SAMPLE_RATE_HZ as u32, quality)?;
+    let mut resample_buf = Vec::<f32>::with_capacity(FILE_INPUT_CHUNK_SAMPLES);
+    let mut pending = Vec::<f32>::with_capacity(OUTPUT_CHUNK_SAMPLES * 4);
+    let mut pending_read_idx = 0usize;
+
+    if cfg.silence_prefix_ms > 0 {
+        let silence_samples = silence_samples_from_ms(cfg.silence_prefix_ms, OUTPUT_SAMPLE_RATE_HZ);
+        let silence_chunks = silence_samples.div_ceil(OUTPUT_CHUNK_SAMPLES);
+        let silence_chunk = vec![0.0f32; OUTPUT_CHUNK_SAMPLES];
+        for idx in 0..silence_chunks {
+            sender
+                .send(InMsg::Audio {
+                    pcm: silence_chunk.clone(),
+                })
+                .await?;
+            samples_sent += silence_samples
+                .saturating_sub(idx * OUTPUT_CHUNK_SAMPLES)
+                .min(OUTPUT_CHUNK_SAMPLES);
+            if let Some(tx) = &progress_tx {
+                let _ = tx.try_send(ProgressUpdate {
+                    audio_elapsed: Duration::from_secs_f64(
+                        samples_sent as f64 / OUTPUT_SAMPLE_RATE_HZ as f64,
+                    ),
+                    wall_elapsed: start.elapsed(),
+                });
+            }
+            chunk_idx += 1;
+            if let Some(rtf) = cfg.rtf {
+                sleep_until(start + chunk_duration.mul_f64(chunk_idx as f64 / rtf)).await;
             }
-            if pending_read_idx >= OUTPUT_CHUNK_SAMPLES * 4 { pending.drain(..pending_read_idx); pending_read_idx = 0; }
         }
+    }
 
-        if let Some(r) = resampler.as_mut() {
-            r.flush(&mut resample_buf)?;
-            pending.extend_from_slice(&resample_buf);
+    for input_chunk in pcm.chunks(FILE_INPUT_CHUNK_SAMPLES) {
+        let samples = match resampler.as_mut() {
+            Some(r) => {
+                resample_buf.clear();
+                r.process_into(input_chunk, &mut resample_buf)?;
+                resample_buf.as_slice()
+            }
+            None => input_chunk,
+        };
+        if samples.is_empty() {
+            continue;
         }
+        pending.extend_from_slice(samples);
         while pending.len().saturating_sub(pending_read_idx) >= OUTPUT_CHUNK_SAMPLES {
             let chunk = pending[pending_read_idx..pending_read_idx + OUTPUT_CHUNK_SAMPLES].to_vec();
             pending_read_idx += OUTPUT_CHUNK_SAMPLES;
             sender.send(InMsg::Audio { pcm: chunk }).await?;
             chunk_idx += 1;
             samples_sent += OUTPUT_CHUNK_SAMPLES;
-            if let Some(tx) = &progress_tx_for_send {
+            if let Some(tx) = &progress_tx {
                 let _ = tx.try_send(ProgressUpdate { audio_elapsed: Duration::from_secs_f64(samples_sent as f64 / OUTPUT_SAMPLE_RATE_HZ as f64), wall_elapsed: start.elapsed() });
             }
-            if let Some(rtf) = rtf { sleep_until(start + chunk_duration.mul_f64(chunk_idx as f64 / rtf)).await; }
+            if let Some(rtf) = cfg.rtf { sleep_until(start + chunk_duration.mul_f64(chunk_idx as f64 / rtf)).await; }
         }
-        let rem = pending.len().saturating_sub(pending_read_idx);
-        if rem > 0 {
-            let mut tail = vec![0.0; OUTPUT_CHUNK_SAMPLES];
-            tail[..rem].copy_from_slice(&pending[pending_read_idx..pending_read_idx + rem]);
-            sender.send(InMsg::Audio { pcm: tail }).await?;
+        if pending_read_idx >= OUTPUT_CHUNK_SAMPLES * 4 {
+            pending.drain(..pending_read_idx);
+            pending_read_idx = 0;
         }
-        sender.send(InMsg::Marker { id: marker_id }).await?;
-        Ok(())
-    });
+    }
 
-    loop {
-        tokio::select! {
-            _ = tokio::signal::ctrl_c() => break,
-            ev = events.recv() => {
-                match ev? {
-                    SttEvent::WordReceived { text, .. } => { transcript.write_word(&text)?; }
-                    SttEvent::StreamMarker { id } if id == marker_id => break,
-                    SttEvent::Error { message } => { transcript.flush()?; eprintln!("stt error: {message}"); }
-                    _ => {}
-                }
-            }
+    if let Some(r) = resampler.as_mut() {
+        resample_buf.clear();
+        r.flush(&mut resample_buf)?;
+        pending.extend_from_slice(&resample_buf);
+    }
+    while pending.len().saturating_sub(pending_read_idx) >= OUTPUT_CHUNK_SAMPLES {
+        let chunk = pending[pending_read_idx..pending_read_idx + OUTPUT_CHUNK_SAMPLES].to_vec();
+        pending_read_idx += OUTPUT_CHUNK_SAMPLES;
+        sender.send(InMsg::Audio { pcm: chunk }).await?;
+        chunk_idx += 1;
+        samples_sent += OUTPUT_CHUNK_SAMPLES;
+        if let Some(tx) = &progress_tx {
+            let _ = tx.try_send(ProgressUpdate {
+                audio_elapsed: Duration::from_secs_f64(
+                    samples_sent as f64 / OUTPUT_SAMPLE_RATE_HZ as f64,
+                ),
+                wall_elapsed: start.elapsed(),
+            });
+        }
+        if let Some(rtf) = cfg.rtf {
+            sleep_until(start + chunk_duration.mul_f64(chunk_idx as f64 / rtf)).await;
         }
     }
-    transcript.flush()?;
-    events.shutdown().await?;
-    let _ = send_task.await;
-    if let Some(task) = progress_task { let _ = task.await; }
+    let rem = pending.len().saturating_sub(pending_read_idx);
+    if rem > 0 {
+        let mut tail = vec![0.0; OUTPUT_CHUNK_SAMPLES];
+        tail[..rem].copy_from_slice(&pending[pending_read_idx..pending_read_idx + rem]);
+        sender.send(InMsg::Audio { pcm: tail }).await?;
+    }
+    sender.send(InMsg::Marker { id: marker_id }).await?;
     Ok(())
 }
 
+/// Decode an enrollment clip to 24kHz.
+fn load_enrollment(path: &std::path::Path) -> Result<Vec<f32>> {
+    let (pcm, sr_in) = kyutai_audio_io::decode(path)
+        .with_context(|| format!("Failed to decode enrollment clip {}", path.display()))?;
+    let Some(mut resampler) =
+        FileResampler::new(sr_in, OUTPUT_SAMPLE_RATE_HZ as u32, ResampleQuality::High)?
+    else {
+        return Ok(pcm);
+    };
+    let mut out = Vec::with_capacity(pcm.len() * OUTPUT_SAMPLE_RATE_HZ / sr_in as usize + 1);
+    resampler.process_into(&pcm, &mut out)?;
+    resampler.flush(&mut out)?;
+    Ok(out)
+}
+
 fn silence_samples_from_ms(prefix_ms: u64, sample_rate_hz: usize) -> usize {
     (prefix_ms as u128 * sample_rate_hz as u128).div_ceil(1000) as usize
 }
@@ -449,6 +1010,75 @@ async fn send_silence_prefix(sender: &kyutai_client::stt::SttSender, prefix_ms:
     Ok(())
 }
 
+/// Audio statistics sent by the server with `--stats`, printed along with the speaking rate
+/// that follows them, and the `--latency` reports.
+#[derive(Default)]
+struct StatsReport {
+    audio: Option<(f32, f32, f32)>,
+}
+
+impl StatsReport {
+    fn line(&mut self, ev: &SttEvent) -> Option<String> {
+        match *ev {
+            SttEvent::AudioStats {
+                rms_db,
+                clipping_ratio,
+                speech_ratio,
+            } => {
+                self.audio = Some((rms_db, clipping_ratio, speech_ratio));
+                None
+            }
+            SttEvent::SpeechRate { wpm } => {
+                let (rms_db, clipping_ratio, speech_ratio) = self.audio.take()?;
+                let mut line = format!(
+                    "Stats: level {rms_db:.1} dBFS, clipping {:.1}%, speech {:.0}%, {wpm:.0} wpm",
+                    clipping_ratio * 100.0,
+                    speech_ratio * 100.0,
+                );
+                if clipping_ratio > 0.001 {
+                    line.push_str(" (input clipping, lower the gain)");
+                } else if speech_ratio < 0.05 && rms_db < -60.0 {
+                    line.push_str(" (no signal, check the input device)");
+                } else if rms_db < -40.0 {
+                    line.push_str(" (input very quiet, raise the gain)");
+                }
+                Some(line)
+            }
+            SttEvent::Overlap { ratio } if ratio > 0.0 => {
+                Some(format!("Overlap: {:.0}% of the speech", ratio * 100.0))
+            }
+            SttEvent::SpeakerCount { n } => {
+                let mut line = format!("Speakers: about {n}");
+                if n > 1 {
+                    line.push_str(" (several voices, consider diarization)");
+                }
+                Some(line)
+            }
+            SttEvent::LatencyReport {
+                rtt_ms,
+                clock_offset_ms,
+                word_latency_ms,
+                words,
+            } => {
+                let mut line =
+                    format!("Latency: rtt {rtt_ms}ms, clock offset {clock_offset_ms:+}ms");
+                if let Some(ms) = word_latency_ms {
+                    line.push_str(&format!(", words {ms}ms (median of {words})"));
+                }
+                Some(line)
+            }
+            _ => None,
+        }
+    }
+}
+
+async fn next_key(keys: &mut Option<KeyControls>) -> KeyAction {
+    match keys {
+        Some(keys) => keys.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
 fn clear_status_line(stderr_is_tty: bool) { if stderr_is_tty { eprint!("\r\x1b[2K"); let _ = std::io::stderr().flush(); } }
 
 fn render_level_meter(level: &AudioLevel, stderr_is_tty: bool) {
@@ -508,9 +1138,145 @@ fn format_duration(d: Duration) -> String {
     format!("{:02}:{:02}:{:02}", s / 3600, (s % 3600) / 60, s % 60)
 }
 
-struct TranscriptOutput { buffered: bool, buffer: String, last_flush: Instant }
+/// One JSON line of the `--json` transcript.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonLine<'a> {
+    Word {
+        text: &'a str,
+        start_ms: u64,
+        engine: &'static str,
+    },
+    Marker {
+        id: i64,
+        at_ms: u64,
+    },
+    PhraseSpotted {
+        phrase: &'a str,
+        start_ms: u64,
+    },
+    /// A script word or a recognized word resolved by `--script`.
+    Script {
+        event: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        index: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        word: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        heard: Option<&'a str>,
+    },
+    ScriptSummary(ScriptSummary),
+}
+
+struct TranscriptOutput {
+    buffered: bool,
+    json: bool,
+    buffer: String,
+    last_flush: Instant,
+    script: Option<ScriptFollower>,
+}
 impl TranscriptOutput {
-    fn new(buffered: bool) -> Self { Self { buffered, buffer: String::new(), last_flush: Instant::now() } }
+    fn new(buffered: bool, json: bool) -> Self {
+        let buffered = buffered || !std::io::stdout().is_terminal();
+        Self {
+            buffered,
+            json,
+            buffer: String::new(),
+            last_flush: Instant::now(),
+            script: None,
+        }
+    }
+    /// Follow a recognized word along the script and write the words it resolved.
+    fn write_script_word(&mut self, text: &str) -> Result<()> {
+        let Some(script) = self.script.as_mut() else {
+            return Ok(());
+        };
+        let events = script.push(text);
+        self.write_script_events(&events)
+    }
+    fn write_script_events(&mut self, events: &[ScriptEvent]) -> Result<()> {
+        let Some(script) = self.script.take() else {
+            return Ok(());
+        };
+        for ev in events {
+            if self.json {
+                let (event, index, heard) = match ev {
+                    ScriptEvent::Read { index } => ("read", Some(*index), None),
+                    ScriptEvent::Misread { index, heard } => {
+                        ("misread", Some(*index), Some(heard.as_str()))
+                    }
+                    ScriptEvent::Skipped { index } => ("skipped", Some(*index), None),
+                    ScriptEvent::Extra { heard } => ("extra", None, Some(heard.as_str())),
+                };
+                let word = index.map(|i| script.word(i));
+                self.write_json(&JsonLine::Script {
+                    event,
+                    index,
+                    word,
+                    heard,
+                })?;
+            } else {
+                self.write_word(&script.render(ev))?;
+            }
+        }
+        self.script = Some(script);
+        Ok(())
+    }
+    /// Accuracy so far, printed after each utterance outside of `--json`.
+    fn script_summary_line(&self) -> Option<String> {
+        self.script
+            .as_ref()
+            .filter(|_| !self.json)
+            .map(ScriptFollower::summary_line)
+    }
+    /// Resolve the last words of the script and report the accuracy.
+    fn finish_script(&mut self) -> Result<()> {
+        let Some(script) = self.script.as_mut() else {
+            return Ok(());
+        };
+        let events = script.finish();
+        self.write_script_events(&events)?;
+        let Some(script) = self.script.as_ref() else {
+            return Ok(());
+        };
+        if self.json {
+            self.write_json(&JsonLine::ScriptSummary(script.summary()))
+        } else {
+            let line = script.summary_line();
+            self.flush()?;
+            eprint_line(&format!("\n{line}"));
+            Ok(())
+        }
+    }
+    fn write_json(&mut self, line: &JsonLine) -> Result<()> {
+        println!("{}", serde_json::to_string(line)?);
+        Ok(())
+    }
+    fn write_json_word(&mut self, start_ms: u64, text: &str, engine: Engine) -> Result<()> {
+        let engine = match engine {
+            Engine::Server => "server",
+            Engine::Local => "local",
+        };
+        self.write_json(&JsonLine::Word {
+            text: text.trim(),
+            start_ms,
+            engine,
+        })
+    }
+    fn write_marker(&mut self, id: i64, at_ms: u64) -> Result<()> {
+        if self.json {
+            self.write_json(&JsonLine::Marker { id, at_ms })
+        } else {
+            self.write_word(&format!(" [marker {id}]"))
+        }
+    }
+    fn write_phrase(&mut self, phrase: &str, start_ms: u64) -> Result<()> {
+        if self.json {
+            self.write_json(&JsonLine::PhraseSpotted { phrase, start_ms })
+        } else {
+            self.write_word(&format!(" [spotted: {phrase}]"))
+        }
+    }
     fn write_word(&mut self, text: &str) -> Result<()> {
         if self.buffered { self.buffer.push_str(text); if self.last_flush.elapsed() > Duration::from_millis(200) { self.flush()?; } }
         else { print!("{text}"); let _ = std::io::stdout().flush(); }
@@ -518,7 +1284,21 @@ impl TranscriptOutput {
     }
     fn write_timestamped(&mut self, ms: u64, text: &str) -> Result<()> {
         self.flush()?;
-        println!("[{}] {text}", format_duration(Duration::from_millis(ms)));
+        print!(
+            "[{}] {text}{}",
+            format_duration(Duration::from_millis(ms)),
+            keys::newline()
+        );
+        Ok(())
+    }
+    fn new_paragraph(&mut self) -> Result<()> {
+        if self.json {
+            return Ok(());
+        }
+        self.flush()?;
+        let nl = keys::newline();
+        print!("{nl}{nl}");
+        let _ = std::io::stdout().flush();
         Ok(())
     }
     fn flush(&mut self) -> Result<()> {
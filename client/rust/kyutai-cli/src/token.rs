@@ -0,0 +1,98 @@
+use crate::GlobalArgs;
+use anyhow::Result;
+use clap::Args;
+use kyutai_client_core::auth;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Args, Debug)]
+pub struct TokenArgs {
+    /// Token validity in hours
+    #[arg(long, default_value = "1.0")]
+    pub hours: f64,
+
+    /// BETTER_AUTH_SECRET for generating JWT tokens
+    #[arg(long, env = "BETTER_AUTH_SECRET")]
+    pub secret: Option<String>,
+
+    /// Environment name for loading .env.<env> when the secret is not given
+    #[arg(long, env = "ENV")]
+    pub env: Option<String>,
+
+    /// Print the dev token that the other commands use, cached in ~/.cache/kyutai/token.json
+    /// until shortly before it expires, rather than a new token valid for --hours
+    #[arg(long, conflicts_with = "hours")]
+    pub print_token: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct TokenReport {
+    token: String,
+    expires_in_s: u64,
+}
+
+/// Fill `--token` from the auth server of `--auth-url` when it is not given, the client is
+/// returned to renew the token of long sessions.
+pub async fn fetch_token(global: &mut GlobalArgs) -> Result<Option<Arc<auth::BetterAuthClient>>> {
+    let Some(url) = global
+        .auth_url
+        .as_deref()
+        .filter(|_| global.token.is_none())
+    else {
+        return Ok(None);
+    };
+    let credentials = auth::Credentials::from_env()?;
+    let client = auth::BetterAuthClient::new(url, credentials, crate::USER_AGENT)?;
+    global.token = Some(client.token().await?);
+    Ok(Some(Arc::new(client)))
+}
+
+/// Generate a JWT from BETTER_AUTH_SECRET, found in the arguments, the environment or the
+/// `.env` files of the current directory, or fetch one from the auth server of `--auth-url`.
+pub async fn run_token(args: TokenArgs, global: &mut GlobalArgs) -> Result<()> {
+    let json = global.json;
+    if fetch_token(global).await?.is_some()
+        && let Some(token) = global.token.take()
+    {
+        let expires_in_s = auth::token_expiry(&token)? - unix_now();
+        print_token(token, expires_in_s.max(0) as u64, json)?;
+        return Ok(());
+    }
+
+    let resolver = auth::AuthResolver::new(crate::USER_AGENT)
+        .with_secret(args.secret.as_deref())
+        .with_env(args.env.as_deref());
+    if args.print_token {
+        let token = resolver.dev_token()?;
+        let expires_in_s = token.expires_in_s();
+        return print_token(token.token, expires_in_s, json);
+    }
+
+    let base_dir = std::env::current_dir()?;
+    let secret = auth::resolve_secret(resolver.secret, &base_dir, resolver.env_name)?;
+    let token = auth::generate_token(&secret, args.hours, resolver.user_agent)
+        .map_err(|e| anyhow::anyhow!("Failed to generate token: {}", e))?;
+
+    print_token(token, (args.hours * 3600.0).round() as u64, json)
+}
+
+fn print_token(token: String, expires_in_s: u64, json: bool) -> Result<()> {
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string(&TokenReport {
+                token,
+                expires_in_s
+            })?
+        );
+    } else {
+        println!("{token}");
+    }
+    Ok(())
+}
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
@@ -1,34 +1,74 @@
-use anyhow::{Result, Context};
-use clap::{Args, ValueEnum};
-use kyutai_client::tts::{TtsClientBuilder, InMsg};
+use crate::profile::Profile;
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand, ValueEnum};
+use kyutai_client::tts::{InMsg, TtsClientBuilder};
+use kyutai_client_core::audio::{AudioPlayer, DynResampler, ResampleQuality};
 use kyutai_client_core::auth;
-use kyutai_client_core::audio::{
-    AudioPlayer, DynResampler, ResampleQuality,
-};
-use ringbuf::traits::*;
-use std::io::BufRead;
-use std::sync::atomic::Ordering;
-use std::time::{Duration as StdDuration, Instant};
 use serde::Serialize;
+use std::io::{BufRead, IsTerminal, Write};
+use std::time::{Duration as StdDuration, Instant};
 
-const SAMPLE_RATE: u32 = 24000;
+pub(crate) const SAMPLE_RATE: u32 = 24000;
+const DISCOVERY_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_URL: &str = "ws://localhost:8080/api/tts-streaming";
 
-#[derive(Args, Debug)]
+#[derive(Args, Clone, Debug)]
 pub struct TtsArgs {
-    /// WebSocket URL for the TTS server
-    #[arg(long, default_value = "ws://localhost:8080/api/tts-streaming")]
-    pub url: String,
+    /// WebSocket URL for the TTS server, from the global --url
+    #[arg(skip)]
+    pub url: Option<String>,
 
-    /// Bearer token for authentication
+    /// Connect to a server discovered on the local network by name (overrides --url)
     #[arg(long)]
+    pub server: Option<String>,
+
+    /// Bearer token for authentication, from the global --token
+    #[arg(skip)]
     pub token: Option<String>,
 
+    /// Voice to use, relative to the server voice directory
+    #[arg(long, global = true)]
+    pub voice: Option<String>,
+
+    /// Voices to blend, comma-separated (used with --voice-weights instead of --voice)
+    #[arg(
+        long,
+        value_delimiter = ',',
+        requires = "voice_weights",
+        conflicts_with = "voice"
+    )]
+    pub voices: Vec<String>,
+
+    /// Interpolation weights for --voices, e.g. 0.7,0.3
+    #[arg(long, value_delimiter = ',', requires = "voices")]
+    pub voice_weights: Vec<f32>,
+
+    /// Language of the input text, translated server side when it differs from --speak-language
+    #[arg(long, requires = "speak_language")]
+    pub input_language: Option<String>,
+
+    /// Language to speak in, e.g. en
+    #[arg(long, requires = "input_language")]
+    pub speak_language: Option<String>,
+
+    /// Request the speech energy envelope with this hop in ms and show it as a ducking meter
+    #[arg(long)]
+    pub envelope_hop_ms: Option<u32>,
+
+    /// Write the envelope as `time_s,rms` CSV lines to this file
+    #[arg(long, requires = "envelope_hop_ms")]
+    pub envelope_output: Option<String>,
+
+    /// Speech speed from 0.5 to 2, time-stretched server side without changing the pitch
+    #[arg(long, global = true)]
+    pub speed: Option<f32>,
+
     /// Text to synthesize (if not provided, interactive mode)
     #[arg(long, short = 'i')]
     pub input: Option<String>,
 
-    /// Output WAV file path
-    #[arg(long, short = 'o')]
+    /// Output audio file path, .wav or .flac
+    #[arg(long, short = 'o', global = true)]
     pub output: Option<String>,
 
     /// Interactive mode
@@ -39,9 +79,9 @@ pub struct TtsArgs {
     #[arg(long, default_value = "1")]
     pub runs: usize,
 
-    /// Audio playback backend
-    #[arg(long, default_value = "cpal")]
-    pub play_backend: PlayBackend,
+    /// Audio playback backend [default: cpal]
+    #[arg(long)]
+    pub play_backend: Option<PlayBackend>,
 
     /// Prebuffer duration in ms for cpal
     #[arg(long, default_value = "200")]
@@ -67,15 +107,57 @@ pub struct TtsArgs {
     #[arg(long, default_value = "20")]
     pub pulse_process_time_ms: u32,
 
-    /// Output benchmarking results as JSON
+    /// PipeWire specific: period asked of the graph in ms, its default quantum when unset
+    #[cfg(feature = "pipewire")]
     #[arg(long)]
+    pub pipewire_latency_ms: Option<u32>,
+
+    /// Output benchmarking results as JSON, from the global --json
+    #[arg(skip)]
     pub json: bool,
+
+    /// Radio mode: synthesize the texts of a directory of .txt files, a JSONL file of
+    /// `{"text": ..., "voice": ...}` lines or a text file with one item per line, one after
+    /// the other
+    #[arg(long, conflicts_with_all = ["input", "interactive"])]
+    pub playlist: Option<String>,
+
+    /// Radio mode: crossfade between items, in ms
+    #[arg(long, default_value = "500", requires = "playlist")]
+    pub crossfade_ms: u32,
+
+    /// Radio mode: start over at the end of the playlist, until Ctrl+C
+    #[arg(long = "loop", requires = "playlist", conflicts_with = "output")]
+    pub loop_playlist: bool,
+
+    #[command(subcommand)]
+    pub command: Option<TtsCommand>,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum TtsCommand {
+    /// Speak the given text, or write it to --output
+    Say {
+        /// Text to synthesize
+        text: String,
+    },
+    /// Speak the content of a text file, or write it to --output
+    File {
+        /// Text file to synthesize
+        path: std::path::PathBuf,
+    },
 }
 
 #[derive(Debug, Clone, ValueEnum)]
 pub enum PlayBackend {
     Cpal,
     Pulse,
+    /// Native PipeWire stream at 24kHz, without resampling in the client
+    #[cfg(feature = "pipewire")]
+    Pipewire,
+    /// WASAPI exclusive mode on the default output device, for the lowest latency
+    #[cfg(all(windows, feature = "wasapi-exclusive"))]
+    WasapiExclusive,
 }
 
 #[derive(Debug, Serialize)]
@@ -91,12 +173,72 @@ struct BenchResult {
     wall_seconds: Option<f64>,
     rtf: Option<f64>,
     x_real_time: Option<f64>,
+    /// Output latency reported by the playback backend.
+    output_latency_ms: Option<f64>,
+    /// Gaps in the playback, when the audio came in slower than it played.
+    underruns: Option<u64>,
 }
 
-pub async fn run_tts(mut args: TtsArgs) -> Result<()> {
+impl TtsArgs {
+    /// Fill the arguments that were not given on the command line from `profile`.
+    fn apply_profile(&mut self, profile: &Profile) -> Result<()> {
+        if self.url.is_none() && self.server.is_none() {
+            self.url = profile.tts_url.clone();
+            if self.url.is_none() {
+                self.server = profile.server.clone();
+            }
+        }
+        if self.token.is_none() {
+            self.token = profile.resolve_token()?;
+        }
+        if self.voice.is_none() {
+            self.voice = profile.voice.clone();
+        }
+        if self.play_backend.is_none()
+            && let Some(backend) = profile.play_backend.as_deref()
+        {
+            let backend = PlayBackend::from_str(backend, true)
+                .map_err(|e| anyhow::anyhow!("invalid play_backend in profile: {e}"))?;
+            self.play_backend = Some(backend);
+        }
+        self.json |= profile.json.unwrap_or(false);
+        Ok(())
+    }
+
+    fn url(&self) -> &str {
+        self.url.as_deref().unwrap_or(DEFAULT_URL)
+    }
+}
+
+pub async fn run_tts(mut args: TtsArgs, profile: Option<&Profile>) -> Result<()> {
+    if let Some(profile) = profile {
+        args.apply_profile(profile)?;
+    }
     ensure_token(&mut args)?;
+    if let Some(server) = args.server.as_deref() {
+        args.url = Some(
+            crate::discover::resolve_server_url(
+                server,
+                kyutai_client_core::discovery::TTS_KINDS,
+                DISCOVERY_TIMEOUT_MS,
+            )
+            .await?,
+        );
+    }
 
-    if args.interactive || (args.input.is_none() && args.output.is_none()) {
+    if let Some(command) = args.command.clone() {
+        if args.playlist.is_some() {
+            anyhow::bail!("--playlist cannot be used with `tts say` or `tts file`");
+        }
+        let text = match command {
+            TtsCommand::Say { text } => text,
+            TtsCommand::File { path } => std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?,
+        };
+        run_tts_text(&args, &text).await
+    } else if let Some(playlist) = args.playlist.clone() {
+        crate::radio::run_radio(&args, &playlist).await
+    } else if args.interactive || (args.input.is_none() && args.output.is_none()) {
         run_tts_interactive_mode(args).await
     } else if let (Some(input), Some(output)) = (&args.input, &args.output) {
         run_tts_file_mode(&args, input, output).await
@@ -106,7 +248,7 @@ pub async fn run_tts(mut args: TtsArgs) -> Result<()> {
 }
 
 fn ensure_token(args: &mut TtsArgs) -> Result<()> {
-    let resolver = auth::AuthResolver::new("kyutai-cli/0.1.0")
+    let resolver = auth::AuthResolver::new(crate::USER_AGENT)
         .with_token(args.token.as_deref());
     if let Some(token) = resolver.resolve(true)? {
         args.token = Some(token);
@@ -114,6 +256,109 @@ fn ensure_token(args: &mut TtsArgs) -> Result<()> {
     Ok(())
 }
 
+/// Session settings from the arguments, `voice` replaces the voice of the arguments.
+pub(crate) fn client_builder(args: &TtsArgs, voice: Option<&str>) -> Result<TtsClientBuilder> {
+    let mut builder = TtsClientBuilder::new(args.url());
+    if let Some(token) = &args.token {
+        builder = builder.auth_token(token);
+    }
+    if let Some(voice) = voice {
+        builder = builder.voice(voice);
+    } else if !args.voices.is_empty() {
+        if args.voices.len() != args.voice_weights.len() {
+            anyhow::bail!(
+                "got {} --voice-weights for {} --voices",
+                args.voice_weights.len(),
+                args.voices.len()
+            );
+        }
+        builder = builder.voice_mix(args.voices.clone(), args.voice_weights.clone());
+    } else if let Some(voice) = &args.voice {
+        builder = builder.voice(voice);
+    }
+    if let (Some(input), Some(speak)) = (&args.input_language, &args.speak_language) {
+        builder = builder.translate(input, speak);
+    }
+    if let Some(hop_ms) = args.envelope_hop_ms {
+        builder = builder.envelope(hop_ms);
+    }
+    if let Some(speed) = args.speed {
+        if !(0.5..=2.0).contains(&speed) {
+            anyhow::bail!("--speed must be between 0.5 and 2, got {speed}");
+        }
+        builder = builder.speed(speed);
+    }
+    Ok(builder)
+}
+
+/// The player of the selected backend, `None` for the pulse backend or when no output device
+/// is available.
+pub(crate) fn audio_player(args: &TtsArgs) -> Result<Option<(AudioPlayer, Option<DynResampler>)>> {
+    let verbose = !args.json;
+    let player = match args.play_backend {
+        None | Some(PlayBackend::Cpal) => AudioPlayer::setup(
+            args.prebuffer_ms,
+            args.max_buffer_ms,
+            args.cpal_sample_rate_hz,
+            args.cpal_buffer_frames,
+            verbose,
+        ),
+        Some(PlayBackend::Pulse) => return Ok(None),
+        #[cfg(feature = "pipewire")]
+        Some(PlayBackend::Pipewire) => AudioPlayer::setup_pipewire(
+            args.prebuffer_ms,
+            args.max_buffer_ms,
+            args.pipewire_latency_ms,
+            verbose,
+        ),
+        #[cfg(all(windows, feature = "wasapi-exclusive"))]
+        Some(PlayBackend::WasapiExclusive) => {
+            AudioPlayer::setup_wasapi_exclusive(args.prebuffer_ms, args.max_buffer_ms, verbose)
+        }
+    };
+    let player = match player {
+        Ok(player) => player,
+        Err(err) => {
+            if verbose && args.play_backend.is_some() {
+                eprintln!("Warning: no audio playback: {err:#}");
+            }
+            return Ok(None);
+        }
+    };
+    let resampler = DynResampler::new(
+        SAMPLE_RATE,
+        player.output_sample_rate as u32,
+        ResampleQuality::High,
+    )?;
+    Ok(Some((player, resampler)))
+}
+
+/// Queues 24kHz `pcm` on the player, waiting for room in its buffer.
+pub(crate) async fn play_pcm(
+    player: &mut AudioPlayer,
+    resampler: Option<&mut DynResampler>,
+    pcm: &[f32],
+) -> Result<()> {
+    let mut out = Vec::new();
+    let out = match resampler {
+        Some(r) => {
+            r.process_into(pcm, &mut out)?;
+            &out
+        }
+        None => pcm,
+    };
+    let mut pos = 0;
+    while pos < out.len() {
+        let pushed = player.push(&out[pos..]);
+        if pushed == 0 {
+            tokio::time::sleep(StdDuration::from_millis(5)).await;
+            continue;
+        }
+        pos += pushed;
+    }
+    Ok(())
+}
+
 async fn run_tts_once(
     args: &TtsArgs,
     text: &str,
@@ -122,59 +367,77 @@ async fn run_tts_once(
     play_audio: bool,
 ) -> Result<BenchResult> {
     let start = Instant::now();
-    let mut builder = TtsClientBuilder::new(&args.url);
-    if let Some(token) = &args.token {
-        builder = builder.auth_token(token);
-    }
-
+    let builder = client_builder(args, None)?;
     let mut session = builder.connect().await?;
     session.send_text(text).await?;
 
-    let mut cpal_player = if play_audio && matches!(args.play_backend, PlayBackend::Cpal) {
-        AudioPlayer::setup(args.prebuffer_ms, args.max_buffer_ms, args.cpal_sample_rate_hz, args.cpal_buffer_frames, !args.json).ok()
-    } else { None };
-
-    let mut resampler = match cpal_player.as_ref() {
-        Some(p) => DynResampler::new(SAMPLE_RATE, p.output_sample_rate as u32, ResampleQuality::High)?,
-        None => None,
+    let mut player = if play_audio {
+        audio_player(args)?
+    } else {
+        None
     };
 
     let mut audio_samples = 0;
     let mut tt_ready_ms = None;
     let mut ttfb_ms = None;
-    let mut writer: Option<hound::WavWriter<std::io::BufWriter<std::fs::File>>> = None;
+    let mut writer: Option<kyutai_audio_io::AudioWriter<_>> = None;
+    let mut envelope_writer = match &args.envelope_output {
+        Some(path) => Some(std::io::BufWriter::new(std::fs::File::create(path)?)),
+        None => None,
+    };
+    let mut envelope_windows = 0u64;
+    let show_envelope =
+        args.envelope_hop_ms.is_some() && !args.json && std::io::stderr().is_terminal();
 
     while let Some(msg) = session.recv().await? {
         match msg {
-            InMsg::Ready => { if tt_ready_ms.is_none() { tt_ready_ms = Some(start.elapsed().as_secs_f64() * 1000.0); } }
+            InMsg::Ready if tt_ready_ms.is_none() => { tt_ready_ms = Some(start.elapsed().as_secs_f64() * 1000.0); }
             InMsg::Audio { pcm } => {
                 if ttfb_ms.is_none() { ttfb_ms = Some(start.elapsed().as_secs_f64() * 1000.0); }
                 audio_samples += pcm.len();
 
-                if let (Some(p), Some(r)) = (cpal_player.as_mut(), resampler.as_mut()) {
-                    let mut out = Vec::new();
-                    r.process_into(&pcm, &mut out)?;
-                    let mut pos = 0;
-                    while pos < out.len() {
-                        let pushed = p.producer.push_slice(&out[pos..]);
-                        if pushed == 0 { tokio::time::sleep(StdDuration::from_millis(5)).await; continue; }
-                        p.queued_samples.fetch_add(pushed, Ordering::AcqRel);
-                        pos += pushed;
-                    }
+                if let Some((p, r)) = player.as_mut() {
+                    play_pcm(p, r.as_mut(), &pcm).await?;
                 }
 
                 if let Some(out_path) = output {
                     if writer.is_none() {
-                        let f = std::fs::File::create(out_path)?;
-                        writer = Some(hound::WavWriter::new(std::io::BufWriter::new(f), hound::WavSpec { channels: 1, sample_rate: SAMPLE_RATE, bits_per_sample: 32, sample_format: hound::SampleFormat::Float })?);
+                        writer = Some(kyutai_audio_io::AudioWriter::create(out_path, SAMPLE_RATE)?);
                     }
-                    if let Some(w) = writer.as_mut() { for s in pcm { w.write_sample(s)?; } }
+                    if let Some(w) = writer.as_mut() { w.write(&pcm)?; }
                 }
             }
             InMsg::Error { message } => return Err(anyhow::anyhow!("Server error: {message}")),
+            InMsg::Translation { translated, .. } if !args.json => eprintln!("> {translated}"),
+            InMsg::Envelope { rms, hop_ms } => {
+                if let Some(w) = envelope_writer.as_mut() {
+                    for v in &rms {
+                        writeln!(w, "{:.3},{v:.6}", envelope_windows as f64 * hop_ms as f64 / 1000.0)?;
+                        envelope_windows += 1;
+                    }
+                }
+                if show_envelope && let Some(peak) = rms.iter().copied().reduce(f32::max) {
+                    render_envelope(peak);
+                }
+            }
             _ => {}
         }
     }
+    if show_envelope {
+        eprint!("\r\x1b[2K");
+    }
+    let (mut output_latency_ms, mut underruns) = (None, None);
+    if let Some((p, _)) = player.as_ref() {
+        p.drain().await;
+        output_latency_ms = p.latency().map(|d| d.as_secs_f64() * 1000.0);
+        underruns = Some(p.underruns());
+    }
+    if let Some(mut w) = envelope_writer {
+        w.flush()?;
+    }
+    if let Some(w) = writer {
+        w.finalize()?;
+    }
 
     let total_ms = start.elapsed().as_secs_f64() * 1000.0;
     let audio_seconds = audio_samples as f64 / SAMPLE_RATE as f64;
@@ -182,10 +445,22 @@ async fn run_tts_once(
     Ok(BenchResult {
         run_idx, ok: audio_samples > 0, error: None, tt_ready_ms, ttfb_ms, total_ms: Some(total_ms),
         audio_samples, audio_seconds, wall_seconds: Some(total_ms / 1000.0),
-        rtf: None, x_real_time: None,
+        rtf: None, x_real_time: None, output_latency_ms, underruns,
     })
 }
 
+/// Show how much a background track would be ducked under the speech, on a dB scale.
+fn render_envelope(rms: f32) {
+    const BAR_WIDTH: usize = 40;
+    let db = 20.0 * rms.max(1e-6).log10();
+    let filled = (((db + 60.0) / 60.0).clamp(0.0, 1.0) * BAR_WIDTH as f32) as usize;
+    let bar: String = (0..BAR_WIDTH)
+        .map(|i| if i < filled { '█' } else { '░' })
+        .collect();
+    eprint!("\r\x1b[2KDuck: [{bar}] {db:6.1} dB");
+    let _ = std::io::stderr().flush();
+}
+
 async fn run_tts_file_mode(args: &TtsArgs, input: &str, output: &str) -> Result<()> {
     let text = std::fs::read_to_string(input).context("Failed to read input file")?;
     let res = run_tts_once(args, &text, 0, Some(output), false).await?;
@@ -194,6 +469,18 @@ async fn run_tts_file_mode(args: &TtsArgs, input: &str, output: &str) -> Result<
     Ok(())
 }
 
+/// `tts say` and `tts file`: play `text`, or write it to --output.
+async fn run_tts_text(args: &TtsArgs, text: &str) -> Result<()> {
+    let output = args.output.as_deref();
+    let res = run_tts_once(args, text, 0, output, output.is_none()).await?;
+    if args.json {
+        println!("{}", serde_json::to_string(&res)?);
+    } else if output.is_some() {
+        println!("TTS completed: {} samples", res.audio_samples);
+    }
+    Ok(())
+}
+
 async fn run_tts_interactive_mode(args: TtsArgs) -> Result<()> {
     println!("TTS Interactive Mode. Type text and press Enter. (Ctrl+D to exit)");
     let stdin = std::io::stdin();
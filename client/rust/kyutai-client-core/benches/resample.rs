@@ -0,0 +1,82 @@
+//! Criterion benchmarks for the mic hot path: stereo 48kHz capture downmixed and resampled to
+//! 24kHz mono, one second of audio fed in 10ms callbacks as cpal does.
+//!
+//! Run on the target device, e.g. a Raspberry Pi, with
+//! `cargo bench -p kyutai-client-core --features audio --bench resample`.
+
+use criterion::{Criterion, Throughput, black_box, criterion_group, criterion_main};
+use kyutai_client_core::audio::{DynResampler, ResampleQuality, downmix_f32_to_mono_into};
+
+const IN_RATE: u32 = 48_000;
+const CALLBACK_FRAMES: usize = 480;
+
+fn stereo_second() -> Vec<f32> {
+    (0..2 * IN_RATE as usize)
+        .map(|i| ((i / 2) as f32 * 0.0131).sin() * 0.5)
+        .collect()
+}
+
+/// The downmix before the lane-blocked version, as a baseline.
+fn downmix_scalar(data: &[f32], channels: usize, out: &mut Vec<f32>) {
+    out.clear();
+    let frames = data.len() / channels;
+    for frame_idx in 0..frames {
+        let mut sum = 0.0;
+        for ch in 0..channels {
+            sum += data[frame_idx * channels + ch];
+        }
+        out.push(sum / channels as f32);
+    }
+}
+
+fn bench_downmix(c: &mut Criterion) {
+    let data = stereo_second();
+    let mut group = c.benchmark_group("downmix_stereo");
+    group.throughput(Throughput::Elements(IN_RATE as u64));
+    let mut out = Vec::new();
+    group.bench_function("scalar", |b| {
+        b.iter(|| {
+            for chunk in data.chunks(2 * CALLBACK_FRAMES) {
+                downmix_scalar(black_box(chunk), 2, &mut out);
+            }
+        })
+    });
+    group.bench_function("lanes", |b| {
+        b.iter(|| {
+            for chunk in data.chunks(2 * CALLBACK_FRAMES) {
+                downmix_f32_to_mono_into(black_box(chunk), 2, &mut out);
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_mic_path(c: &mut Criterion) {
+    let data = stereo_second();
+    let mut group = c.benchmark_group("mic_48k_stereo_to_24k");
+    group.throughput(Throughput::Elements(IN_RATE as u64));
+    for (name, quality) in [
+        ("linear", ResampleQuality::Linear),
+        ("polyphase", ResampleQuality::Polyphase),
+        ("rubato_fft", ResampleQuality::High),
+    ] {
+        let mut resampler = DynResampler::new(IN_RATE, 24_000, quality)
+            .unwrap()
+            .unwrap();
+        let (mut mono, mut out) = (Vec::new(), Vec::new());
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for chunk in data.chunks(2 * CALLBACK_FRAMES) {
+                    out.clear();
+                    resampler
+                        .process_interleaved_into(black_box(chunk), 2, &mut mono, &mut out)
+                        .unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_downmix, bench_mic_path);
+criterion_main!(benches);
@@ -1,6 +1,12 @@
-use anyhow::{Result, Context};
+use anyhow::{Context, Result};
+#[cfg(feature = "audio")]
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+#[cfg(feature = "audio")]
+use ringbuf::{HeapRb, traits::*};
+#[cfg(feature = "audio")]
+use std::process::Stdio;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 #[cfg(feature = "audio")]
 use std::time::Duration as StdDuration;
 #[cfg(feature = "audio")]
@@ -9,12 +15,6 @@ use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
 #[cfg(feature = "audio")]
 use tokio::sync::mpsc;
-#[cfg(feature = "audio")]
-use std::process::Stdio;
-#[cfg(feature = "audio")]
-use ringbuf::{HeapRb, traits::*};
-#[cfg(feature = "audio")]
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 
 pub const DEFAULT_SAMPLE_RATE: u32 = 24000;
 
@@ -26,11 +26,24 @@ pub struct AudioChunk {
 
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub enum ResampleQuality {
-    #[default]
+    /// Linear interpolation, the cheapest but it aliases when downsampling.
     Linear,
+    /// Windowed-sinc polyphase filter, see [`PolyphaseResampler`].
+    #[default]
+    Polyphase,
+    /// FFT resampling with rubato, requires the `audio` feature.
     High,
 }
 
+/// Samples processed together by the filter and downmix loops. One accumulator per lane lets
+/// the compiler turn the loops into SIMD (NEON, SSE, AVX) without reassociating floats.
+const LANES: usize = 16;
+
+/// Zero crossings of the sinc on each side of the polyphase filter, at the lower rate.
+const POLYPHASE_ZERO_CROSSINGS: usize = 16;
+/// Ratios needing more phases fall back to linear interpolation.
+const POLYPHASE_MAX_PHASES: usize = 1024;
+
 /// Audio level measurements in decibels.
 #[derive(Clone, Copy, Debug, Default)]
 pub struct AudioLevel {
@@ -130,6 +143,100 @@ impl LevelMeter {
     }
 }
 
+pub fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// RMS level, in dBFS, that the automatic gain brings speech to.
+const AUTO_GAIN_TARGET_DB: f32 = -20.0;
+/// Chunks quieter than this before the gain are taken as silence and leave the gain alone.
+const AUTO_GAIN_GATE_DB: f32 = -55.0;
+/// Peak level, in dBFS, that the automatic gain keeps the chunks under.
+const AUTO_GAIN_PEAK_DB: f32 = -1.0;
+const AUTO_GAIN_MIN_DB: f32 = -12.0;
+const AUTO_GAIN_MAX_DB: f32 = 30.0;
+/// Largest gain increase per chunk. Decreases apply at once so that a louder voice does not
+/// clip, increases are slow so that the noise floor does not pump up between words.
+const AUTO_GAIN_STEP_DB: f32 = 0.5;
+
+/// Software gain of captured audio, fixed or adjusted to the speech level. The samples are
+/// clamped to full scale after the gain, see [`ClipDetector`] to notice a gain set too high.
+#[derive(Clone, Debug)]
+pub struct Gain {
+    gain_db: f32,
+    auto: bool,
+}
+
+impl Gain {
+    /// `gain_db` is the fixed gain, or the starting gain when `auto` is set.
+    pub fn new(gain_db: f32, auto: bool) -> Self {
+        Self { gain_db, auto }
+    }
+
+    /// Current gain in dB.
+    pub fn gain_db(&self) -> f32 {
+        self.gain_db
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if self.auto {
+            let level = AudioLevel::compute(samples);
+            if level.rms_db > AUTO_GAIN_GATE_DB {
+                let wanted = (AUTO_GAIN_TARGET_DB - level.rms_db)
+                    .min(AUTO_GAIN_PEAK_DB - level.peak_db)
+                    .clamp(AUTO_GAIN_MIN_DB, AUTO_GAIN_MAX_DB);
+                self.gain_db = wanted.min(self.gain_db + AUTO_GAIN_STEP_DB);
+            }
+        }
+        if self.gain_db == 0.0 {
+            return;
+        }
+        let gain = db_to_linear(self.gain_db);
+        for sample in samples.iter_mut() {
+            *sample = (*sample * gain).clamp(-1.0, 1.0);
+        }
+    }
+}
+
+/// Magnitude from which a sample is taken as clipped.
+pub const CLIP_LEVEL: f32 = 0.999;
+/// Share of clipped samples in a window from which [`ClipDetector`] reports it, a few clipped
+/// peaks are barely audible while sustained clipping hurts the recognition.
+pub const CLIP_REPORT_RATIO: f32 = 1e-3;
+
+/// Share of clipped samples over consecutive windows of audio.
+#[derive(Clone, Debug)]
+pub struct ClipDetector {
+    window: usize,
+    samples: usize,
+    clipped: usize,
+}
+
+impl ClipDetector {
+    /// Windows of `window` samples, e.g. a couple of seconds.
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.max(1),
+            samples: 0,
+            clipped: 0,
+        }
+    }
+
+    /// Count the clipped samples and, at the end of each window where at least
+    /// [`CLIP_REPORT_RATIO`] of the samples clipped, return their share.
+    pub fn process(&mut self, samples: &[f32]) -> Option<f32> {
+        self.samples += samples.len();
+        self.clipped += samples.iter().filter(|s| s.abs() >= CLIP_LEVEL).count();
+        if self.samples < self.window {
+            return None;
+        }
+        let ratio = self.clipped as f32 / self.samples as f32;
+        self.samples = 0;
+        self.clipped = 0;
+        (ratio >= CLIP_REPORT_RATIO).then_some(ratio)
+    }
+}
+
 pub struct LinearResampler {
     in_rate_hz: u32,
     out_rate_hz: u32,
@@ -149,8 +256,8 @@ impl LinearResampler {
         }
     }
 
+    /// Resample `input`, appending to `out`.
     pub fn process_into(&mut self, input: &[f32], out: &mut Vec<f32>) {
-        out.clear();
         if input.is_empty() {
             return;
         }
@@ -182,6 +289,123 @@ impl LinearResampler {
     }
 }
 
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    let mut acc = [0f32; LANES];
+    for (a, b) in a.chunks_exact(LANES).zip(b.chunks_exact(LANES)) {
+        let (a, b): (&[f32; LANES], &[f32; LANES]) = (a.try_into().unwrap(), b.try_into().unwrap());
+        for ((acc, a), b) in acc.iter_mut().zip(a).zip(b) {
+            *acc += a * b;
+        }
+    }
+    acc.iter().sum()
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Windowed-sinc resampler for the rational ratio of the two rates, e.g. 1/2 for a 48kHz mic
+/// and 80/147 for 44.1kHz. Each output sample is the dot product of one phase of a Blackman
+/// windowed low-pass filter with the latest input samples, so downsampling does not alias.
+pub struct PolyphaseResampler {
+    up: usize,
+    down: usize,
+    /// Filter length of each phase, a multiple of the SIMD lanes.
+    taps: usize,
+    /// Filter bank, phase after phase, each phase reversed to run forward over the input.
+    filters: Vec<f32>,
+    /// Input samples, starting with the history needed by the next output.
+    buf: Vec<f32>,
+    /// Position of the next output in `buf`, in 1/up of an input sample.
+    pos: usize,
+}
+
+impl PolyphaseResampler {
+    /// `None` when the reduced ratio needs more than 1024 phases.
+    pub fn new(in_rate_hz: u32, out_rate_hz: u32) -> Option<Self> {
+        use std::f64::consts::PI;
+
+        let (in_rate, out_rate) = (in_rate_hz as usize, out_rate_hz as usize);
+        if in_rate == 0 || out_rate == 0 {
+            return None;
+        }
+        let g = gcd(in_rate, out_rate);
+        let (up, down) = (out_rate / g, in_rate / g);
+        if up > POLYPHASE_MAX_PHASES {
+            return None;
+        }
+        let ratio = (down as f64 / up as f64).max(1.0);
+        let taps = ((2 * POLYPHASE_ZERO_CROSSINGS) as f64 * ratio).ceil() as usize;
+        let taps = taps.next_multiple_of(LANES);
+        // Cutoff at 90% of the lower Nyquist frequency, in cycles per upsampled sample.
+        let cutoff = 0.45 / up.max(down) as f64;
+        let len = taps * up;
+        let center = (len - 1) as f64 / 2.0;
+        let prototype: Vec<f64> = (0..len)
+            .map(|n| {
+                let x = 2.0 * cutoff * (n as f64 - center);
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * x).sin() / (PI * x)
+                };
+                let w = 2.0 * PI * n as f64 / (len - 1) as f64;
+                sinc * (0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos())
+            })
+            .collect();
+        let mut filters = Vec::with_capacity(len);
+        for phase in 0..up {
+            let coefs: Vec<f64> = (0..taps).rev().map(|j| prototype[phase + j * up]).collect();
+            // Unit gain at DC for every phase.
+            let sum: f64 = coefs.iter().sum();
+            filters.extend(coefs.iter().map(|c| (c / sum) as f32));
+        }
+        Some(Self {
+            up,
+            down,
+            taps,
+            filters,
+            buf: vec![0.0; taps - 1],
+            pos: 0,
+        })
+    }
+
+    /// Resample `input`, appending to `out`.
+    pub fn process_into(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        self.buf.extend_from_slice(input);
+        self.run(out);
+    }
+
+    /// Downmix interleaved `data` to mono straight into the input buffer and resample it,
+    /// appending to `out`.
+    pub fn process_interleaved(&mut self, data: &[f32], channels: usize, out: &mut Vec<f32>) {
+        downmix_extend(data, channels, &mut self.buf, |s| s);
+        self.run(out);
+    }
+
+    /// Push the samples still held by the filter to `out`.
+    pub fn flush(&mut self, out: &mut Vec<f32>) {
+        self.buf.resize(self.buf.len() + self.taps / 2, 0.0);
+        self.run(out);
+    }
+
+    fn run(&mut self, out: &mut Vec<f32>) {
+        out.reserve((self.buf.len() * self.up).saturating_sub(self.pos) / self.down + 1);
+        loop {
+            let (i, phase) = (self.pos / self.up, self.pos % self.up);
+            if i + self.taps > self.buf.len() {
+                break;
+            }
+            let filter = &self.filters[phase * self.taps..(phase + 1) * self.taps];
+            out.push(dot(filter, &self.buf[i..i + self.taps]));
+            self.pos += self.down;
+        }
+        let consumed = self.pos / self.up;
+        self.buf.drain(..consumed);
+        self.pos -= consumed * self.up;
+    }
+}
+
 #[cfg(feature = "audio")]
 pub struct HqResampler {
     resampler: rubato::FftFixedInOut<f32>,
@@ -210,6 +434,7 @@ impl HqResampler {
         })
     }
 
+    /// Resample `input`, appending to `output`.
     pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) -> Result<()> {
         use rubato::Resampler as _;
 
@@ -250,21 +475,27 @@ impl HqResampler {
 
 pub enum DynResampler {
     Linear(LinearResampler),
+    Polyphase(PolyphaseResampler),
     #[cfg(feature = "audio")]
     High(Box<HqResampler>),
 }
 
 impl DynResampler {
-    pub fn new(in_rate_hz: u32, out_rate_hz: u32, quality: ResampleQuality) -> Result<Option<Self>> {
+    pub fn new(
+        in_rate_hz: u32,
+        out_rate_hz: u32,
+        quality: ResampleQuality,
+    ) -> Result<Option<Self>> {
         if in_rate_hz == out_rate_hz {
             return Ok(None);
         }
 
+        let linear = || Self::Linear(LinearResampler::new(in_rate_hz, out_rate_hz));
         match quality {
-            ResampleQuality::Linear => Ok(Some(Self::Linear(LinearResampler::new(
-                in_rate_hz,
-                out_rate_hz,
-            )))),
+            ResampleQuality::Linear => Ok(Some(linear())),
+            ResampleQuality::Polyphase => Ok(Some(
+                PolyphaseResampler::new(in_rate_hz, out_rate_hz).map_or_else(linear, Self::Polyphase),
+            )),
             ResampleQuality::High => {
                 #[cfg(feature = "audio")]
                 {
@@ -282,21 +513,45 @@ impl DynResampler {
         }
     }
 
+    /// Resample `input`, appending to `out`.
     pub fn process_into(&mut self, input: &[f32], out: &mut Vec<f32>) -> Result<()> {
         match self {
             DynResampler::Linear(resampler) => {
                 resampler.process_into(input, out);
                 Ok(())
             }
+            DynResampler::Polyphase(resampler) => {
+                resampler.process_into(input, out);
+                Ok(())
+            }
             #[cfg(feature = "audio")]
             DynResampler::High(resampler) => resampler.process(input, out),
         }
     }
 
+    /// Downmix interleaved `data` to mono and resample it, appending to `out`. The polyphase
+    /// resampler downmixes straight into its input buffer, the others go through `mono`.
+    pub fn process_interleaved_into(
+        &mut self,
+        data: &[f32],
+        channels: usize,
+        mono: &mut Vec<f32>,
+        out: &mut Vec<f32>,
+    ) -> Result<()> {
+        if let DynResampler::Polyphase(resampler) = self {
+            resampler.process_interleaved(data, channels, out);
+            return Ok(());
+        }
+        downmix_f32_to_mono_into(data, channels, mono);
+        self.process_into(mono, out)
+    }
+
+    /// Push the samples still held by the resampler to `out`.
     pub fn flush(&mut self, out: &mut Vec<f32>) -> Result<()> {
         match self {
-            DynResampler::Linear(resampler) => {
-                resampler.process_into(&[], out);
+            DynResampler::Linear(_) => Ok(()),
+            DynResampler::Polyphase(resampler) => {
+                resampler.flush(out);
                 Ok(())
             }
             #[cfg(feature = "audio")]
@@ -305,83 +560,230 @@ impl DynResampler {
     }
 }
 
-pub fn downmix_f32_to_mono_into(data: &[f32], channels: usize, out: &mut Vec<f32>) {
-    out.clear();
-    if channels <= 1 {
-        out.extend_from_slice(data);
-        return;
-    }
-
-    let frames = data.len() / channels;
-    out.reserve(frames);
-
-    for frame_idx in 0..frames {
-        let mut sum = 0.0;
-        let base = frame_idx * channels;
-        for ch in 0..channels {
-            sum += data[base + ch];
+/// Append the mono mix of interleaved `data`. Stereo, the common mic layout, is mixed a
+/// block of lanes at a time.
+fn downmix_extend<T: Copy>(
+    data: &[T],
+    channels: usize,
+    out: &mut Vec<f32>,
+    to_f32: impl Fn(T) -> f32,
+) {
+    match channels {
+        0 | 1 => out.extend(data.iter().map(|&s| to_f32(s))),
+        2 => {
+            out.reserve(data.len() / 2);
+            let blocks = data.chunks_exact(2 * LANES);
+            let rest = blocks.remainder();
+            for block in blocks {
+                let mut lanes = [0f32; LANES];
+                for (lane, frame) in lanes.iter_mut().zip(block.chunks_exact(2)) {
+                    *lane = (to_f32(frame[0]) + to_f32(frame[1])) * 0.5;
+                }
+                out.extend_from_slice(&lanes);
+            }
+            out.extend(
+                rest.chunks_exact(2)
+                    .map(|f| (to_f32(f[0]) + to_f32(f[1])) * 0.5),
+            );
+        }
+        _ => {
+            let frames = data.chunks_exact(channels);
+            out.extend(frames.map(|f| f.iter().map(|&s| to_f32(s)).sum::<f32>() / channels as f32));
         }
-        out.push(sum / channels as f32);
     }
 }
 
-pub fn downmix_i16_to_mono_into(data: &[i16], channels: usize, out: &mut Vec<f32>) {
+pub fn downmix_f32_to_mono_into(data: &[f32], channels: usize, out: &mut Vec<f32>) {
     out.clear();
-    if channels <= 1 {
-        out.reserve(data.len());
-        for &sample in data {
-            out.push(sample as f32 / 32768.0);
-        }
-        return;
-    }
-
-    let frames = data.len() / channels;
-    out.reserve(frames);
+    downmix_extend(data, channels, out, |s| s);
+}
 
-    for frame_idx in 0..frames {
-        let mut sum = 0.0;
-        let base = frame_idx * channels;
-        for ch in 0..channels {
-            sum += data[base + ch] as f32 / 32768.0;
-        }
-        out.push(sum / channels as f32);
-    }
+pub fn downmix_i16_to_mono_into(data: &[i16], channels: usize, out: &mut Vec<f32>) {
+    out.clear();
+    downmix_extend(data, channels, out, |s| s as f32 / 32768.0);
 }
 
 pub fn downmix_u16_to_mono_into(data: &[u16], channels: usize, out: &mut Vec<f32>) {
     out.clear();
-    if channels <= 1 {
-        out.reserve(data.len());
-        for &sample in data {
-            out.push((sample as f32 - 32768.0) / 32768.0);
-        }
-        return;
-    }
+    downmix_extend(data, channels, out, |s| (s as f32 - 32768.0) / 32768.0);
+}
+
+/// Output stream of an [`AudioPlayer`], stopped on drop.
+#[cfg(feature = "audio")]
+pub enum PlayerStream {
+    Cpal(cpal::Stream),
+    #[cfg(feature = "pipewire")]
+    PipeWire(crate::pipewire::PwStream),
+    #[cfg(all(windows, feature = "wasapi-exclusive"))]
+    Wasapi(crate::wasapi::ExclusiveStream),
+}
+
+/// Where the output callback is in the queued audio.
+#[cfg(feature = "audio")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FeedState {
+    /// Nothing queued yet, or the queue was drained.
+    Idle,
+    /// The queue ran dry in the middle of the audio, the next chunk resumes after a gap.
+    Starved,
+    /// Audio queued, to start at this frame of the output timeline.
+    Scheduled(u64),
+    Playing,
+}
+
+/// State shared by the player and its output callback.
+#[cfg(feature = "audio")]
+#[derive(Debug, Default)]
+struct PlaybackShared {
+    /// Set by [`AudioPlayer::drain`], cleared by the callback once the queue has played.
+    ending: AtomicBool,
+    underruns: AtomicU64,
+}
 
-    let frames = data.len() / channels;
-    out.reserve(frames);
+/// Consumer side of the player ring buffer, run by the output callback of each backend.
+///
+/// The first chunk queued while idle is scheduled `prebuffer_frames` after the frame it was
+/// seen at, the following ones play back to back. When the queue runs dry before the end of
+/// the audio, this counts as an underrun and the next chunk is scheduled again, rather than
+/// played as soon as a few samples come in.
+#[cfg(feature = "audio")]
+struct PlaybackFeed {
+    consumer: ringbuf::HeapCons<f32>,
+    shared: Arc<PlaybackShared>,
+    prebuffer_frames: u64,
+    state: FeedState,
+    /// Frame of the output timeline following the last callback.
+    next_frame: u64,
+}
 
-    for frame_idx in 0..frames {
-        let mut sum = 0.0;
-        let base = frame_idx * channels;
-        for ch in 0..channels {
-            sum += (data[base + ch] as f32 - 32768.0) / 32768.0;
+#[cfg(feature = "audio")]
+impl PlaybackFeed {
+    /// Fill the interleaved `data` with the queued mono samples, silence when nothing is due.
+    /// `frame` is the position of `data` in the output timeline when the backend timestamps
+    /// its callbacks, e.g. after an xrun, the callbacks are assumed contiguous otherwise.
+    fn fill(&mut self, data: &mut [f32], channels: usize, frame: Option<u64>) {
+        data.fill(0.);
+        let frame = frame.map_or(self.next_frame, |f| f.max(self.next_frame));
+        let ending = self.shared.ending.load(Ordering::Acquire);
+        for (idx, out) in data.chunks_mut(channels).enumerate() {
+            let pos = frame + idx as u64;
+            if matches!(self.state, FeedState::Idle | FeedState::Starved) {
+                if self.consumer.is_empty() {
+                    continue;
+                }
+                if self.state == FeedState::Starved {
+                    self.shared.underruns.fetch_add(1, Ordering::Relaxed);
+                }
+                self.state = FeedState::Scheduled(pos + self.prebuffer_frames);
+            }
+            if let FeedState::Scheduled(start) = self.state {
+                // Once the audio is complete, waiting would only delay it.
+                if pos < start && !ending {
+                    continue;
+                }
+                self.state = FeedState::Playing;
+            }
+            match self.consumer.try_pop() {
+                Some(v) => out.fill(v),
+                None if ending => self.state = FeedState::Idle,
+                None => self.state = FeedState::Starved,
+            }
+        }
+        self.next_frame = frame + (data.len() / channels.max(1)) as u64;
+        if ending && self.consumer.is_empty() && self.state != FeedState::Playing {
+            self.state = FeedState::Idle;
+            self.shared.ending.store(false, Ordering::Release);
         }
-        out.push(sum / channels as f32);
     }
 }
 
 #[cfg(feature = "audio")]
 pub struct AudioPlayer {
-    pub _stream: cpal::Stream,
-    pub producer: ringbuf::HeapProd<f32>,
-    pub queued_samples: Arc<AtomicUsize>,
-    pub started: Arc<AtomicBool>,
+    _stream: PlayerStream,
+    producer: ringbuf::HeapProd<f32>,
+    shared: Arc<PlaybackShared>,
     pub output_sample_rate: usize,
+    /// Delay from the output callback to the speakers, in microseconds, as last reported by
+    /// the backend. Zero until the stream has run.
+    pub latency_us: Arc<AtomicU64>,
 }
 
 #[cfg(feature = "audio")]
 impl AudioPlayer {
+    /// Ring buffer for `output_sample_rate`, with its producer and the feed to run in the
+    /// output callback.
+    fn buffers(
+        output_sample_rate: usize,
+        prebuffer_ms: u32,
+        max_buffer_ms: u32,
+    ) -> (ringbuf::HeapProd<f32>, PlaybackFeed) {
+        let prebuffer_frames = ((output_sample_rate as u64 * prebuffer_ms as u64) / 1000) as usize;
+        let max_buffer_samples =
+            ((output_sample_rate as u64 * max_buffer_ms as u64) / 1000) as usize;
+        let prebuffer_frames = usize::max(prebuffer_frames, output_sample_rate / 20);
+        let max_buffer_samples = usize::max(max_buffer_samples, prebuffer_frames.saturating_mul(2));
+
+        let rb = HeapRb::<f32>::new(max_buffer_samples);
+        let (producer, consumer) = rb.split();
+        let feed = PlaybackFeed {
+            consumer,
+            shared: Arc::new(PlaybackShared::default()),
+            prebuffer_frames: prebuffer_frames as u64,
+            state: FeedState::Idle,
+            next_frame: 0,
+        };
+        (producer, feed)
+    }
+
+    fn new(
+        stream: PlayerStream,
+        producer: ringbuf::HeapProd<f32>,
+        shared: Arc<PlaybackShared>,
+        output_sample_rate: usize,
+        latency_us: Arc<AtomicU64>,
+    ) -> Self {
+        Self {
+            _stream: stream,
+            producer,
+            shared,
+            output_sample_rate,
+            latency_us,
+        }
+    }
+
+    /// Queue samples at the output rate, returns how many fit in the buffer.
+    pub fn push(&mut self, samples: &[f32]) -> usize {
+        self.producer.push_slice(samples)
+    }
+
+    /// Samples queued and not played yet.
+    pub fn queued(&self) -> usize {
+        self.producer.occupied_len()
+    }
+
+    /// Gaps in the audio since the player started, when the queue ran dry before the audio was
+    /// complete.
+    pub fn underruns(&self) -> u64 {
+        self.shared.underruns.load(Ordering::Relaxed)
+    }
+
+    /// Play what is queued without waiting for its scheduled start and return once it has
+    /// been played, the end of the queue not counting as an underrun.
+    pub async fn drain(&self) {
+        self.shared.ending.store(true, Ordering::Release);
+        while self.shared.ending.load(Ordering::Acquire) {
+            tokio::time::sleep(StdDuration::from_millis(10)).await;
+        }
+    }
+
+    /// Output latency reported by the backend, `None` until the stream has run.
+    pub fn latency(&self) -> Option<StdDuration> {
+        match self.latency_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(StdDuration::from_micros(us)),
+        }
+    }
+
     pub fn setup(
         prebuffer_ms: u32,
         max_buffer_ms: u32,
@@ -422,19 +824,11 @@ impl AudioPlayer {
         let channels = config.channels as usize;
 
         let output_sample_rate = config.sample_rate.0 as usize;
-
-        let min_buffer_samples = ((output_sample_rate as u64 * prebuffer_ms as u64) / 1000) as usize;
-        let max_buffer_samples = ((output_sample_rate as u64 * max_buffer_ms as u64) / 1000) as usize;
-        let min_buffer_samples = usize::max(min_buffer_samples, output_sample_rate / 20);
-        let max_buffer_samples = usize::max(max_buffer_samples, min_buffer_samples.saturating_mul(2));
-
-        let rb = HeapRb::<f32>::new(max_buffer_samples);
-        let (producer, mut consumer) = rb.split();
-        let queued_samples = Arc::new(AtomicUsize::new(0));
-        let started = Arc::new(AtomicBool::new(false));
-        let qs = queued_samples.clone();
-        let started_cb = started.clone();
-        let mut last_elem_state = 0.0f32;
+        let (producer, mut feed) = Self::buffers(output_sample_rate, prebuffer_ms, max_buffer_ms);
+        let shared = feed.shared.clone();
+        let latency_us = Arc::new(AtomicU64::new(0));
+        let latency_cb = latency_us.clone();
+        let mut origin: Option<cpal::StreamInstant> = None;
 
         if verbose {
             let device_name = device.name().unwrap_or_else(|_| "unk".to_string());
@@ -446,55 +840,117 @@ impl AudioPlayer {
 
         let stream = device.build_output_stream(
             &config,
-            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                data.fill(0.);
-
-                if !started_cb.load(Ordering::Acquire) {
-                    if qs.load(Ordering::Acquire) < min_buffer_samples {
-                        return;
-                    }
-                    started_cb.store(true, Ordering::Release);
-                }
-
-                let mut last_elem = last_elem_state;
-                let mut popped = 0usize;
-                for (idx, elem) in data.iter_mut().enumerate() {
-                    if idx % channels == 0 {
-                        let v_opt = consumer.try_pop();
-                        match v_opt {
-                            None => {
-                                break;
-                            }
-                            Some(v) => {
-                                last_elem = v;
-                                popped = popped.saturating_add(1);
-                                *elem = v;
-                            }
-                        }
-                    } else {
-                        *elem = last_elem
-                    }
+            move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+                let ts = info.timestamp();
+                if let Some(delay) = ts.playback.duration_since(&ts.callback) {
+                    latency_cb.store(delay.as_micros() as u64, Ordering::Relaxed);
                 }
-
-                if popped > 0 {
-                    let _ = qs.fetch_update(Ordering::AcqRel, Ordering::Acquire, |v0| {
-                        Some(v0.saturating_sub(popped))
-                    });
-                }
-                last_elem_state = last_elem;
+                // Frames are placed by their playback time, so that the schedule holds when
+                // the device skips callbacks.
+                let origin = *origin.get_or_insert(ts.playback);
+                let frame = ts
+                    .playback
+                    .duration_since(&origin)
+                    .map(|d| (d.as_secs_f64() * output_sample_rate as f64).round() as u64);
+                feed.fill(data, channels, frame);
             },
             move |err| eprintln!("cpal error: {err}"),
             None,
         )?;
         stream.play()?;
 
-        Ok(AudioPlayer {
-            _stream: stream,
+        Ok(Self::new(
+            PlayerStream::Cpal(stream),
             producer,
-            queued_samples,
-            started,
+            shared,
             output_sample_rate,
-        })
+            latency_us,
+        ))
+    }
+
+    /// Play through a native PipeWire stream at 24kHz mono, so that the samples need no
+    /// resampling, the graph converting them for the device. `latency_ms` is the period asked
+    /// of the graph, its default quantum when unset.
+    #[cfg(feature = "pipewire")]
+    pub fn setup_pipewire(
+        prebuffer_ms: u32,
+        max_buffer_ms: u32,
+        latency_ms: Option<u32>,
+        verbose: bool,
+    ) -> Result<Self> {
+        use crate::pipewire::{Direction, PwStream, PwStreamConfig};
+
+        let cfg = PwStreamConfig {
+            name: "kyutai-tts".to_string(),
+            direction: Direction::Playback,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            channels: 1,
+            latency_frames: latency_ms.map(|ms| DEFAULT_SAMPLE_RATE * ms / 1000),
+            target: None,
+        };
+        let output_sample_rate = cfg.sample_rate as usize;
+        let (producer, mut feed) = Self::buffers(output_sample_rate, prebuffer_ms, max_buffer_ms);
+        let shared = feed.shared.clone();
+        let latency_us = Arc::new(AtomicU64::new(0));
+        let stream = PwStream::new(&cfg, latency_us.clone(), move |data| {
+            feed.fill(data, 1, None)
+        })?;
+        if verbose {
+            eprintln!(
+                "pipewire stream: sample_rate={} latency={:?}",
+                cfg.sample_rate, cfg.latency_frames
+            );
+        }
+        let stream = PlayerStream::PipeWire(stream);
+        Ok(Self::new(
+            stream,
+            producer,
+            shared,
+            output_sample_rate,
+            latency_us,
+        ))
+    }
+
+    /// Play on the default output device in WASAPI exclusive mode, at the rate and channel
+    /// count of the device. The device is not available to other applications meanwhile.
+    #[cfg(all(windows, feature = "wasapi-exclusive"))]
+    pub fn setup_wasapi_exclusive(
+        prebuffer_ms: u32,
+        max_buffer_ms: u32,
+        verbose: bool,
+    ) -> Result<Self> {
+        use crate::wasapi::ExclusiveStream;
+
+        // The rate is only known once the device is open, the feed is handed over after that.
+        let (feed_tx, feed_rx) = std::sync::mpsc::channel::<PlaybackFeed>();
+        let mut feed: Option<PlaybackFeed> = None;
+        let latency_us = Arc::new(AtomicU64::new(0));
+        let (stream, format) = ExclusiveStream::new(latency_us.clone(), move |data, channels| {
+            if feed.is_none() {
+                feed = feed_rx.try_recv().ok();
+            }
+            if let Some(feed) = feed.as_mut() {
+                feed.fill(data, channels, None)
+            }
+        })?;
+        let output_sample_rate = format.sample_rate as usize;
+        let (producer, feed) = Self::buffers(output_sample_rate, prebuffer_ms, max_buffer_ms);
+        let shared = feed.shared.clone();
+        let _ = feed_tx.send(feed);
+        if verbose {
+            eprintln!(
+                "wasapi exclusive: sample_rate={} channels={}",
+                format.sample_rate, format.channels
+            );
+        }
+        let stream = PlayerStream::Wasapi(stream);
+        Ok(Self::new(
+            stream,
+            producer,
+            shared,
+            output_sample_rate,
+            latency_us,
+        ))
     }
 }
 
@@ -653,4 +1109,129 @@ mod tests {
         assert!((level.rms_db - 0.0).abs() < 0.1);
         assert!((level.peak_db - 0.0).abs() < 0.1);
     }
+
+    #[test]
+    fn auto_gain_raises_quiet_speech() {
+        let quiet: Vec<f32> = (0..1920).map(|i| 0.01 * (i as f32 * 0.1).sin()).collect();
+        let mut gain = Gain::new(0.0, true);
+        for _ in 0..200 {
+            let mut chunk = quiet.clone();
+            gain.process(&mut chunk);
+        }
+        // The tone is at -43 dBFS, brought to the -20 dBFS target.
+        assert!((gain.gain_db() - 23.0).abs() < 0.5, "{}", gain.gain_db());
+        let mut loud = vec![0.9; 1920];
+        gain.process(&mut loud);
+        assert!(gain.gain_db() < 0.0);
+        assert!(loud.iter().all(|s| s.abs() < CLIP_LEVEL));
+        // Silence leaves the gain alone.
+        let before = gain.gain_db();
+        gain.process(&mut [0.0; 1920]);
+        assert_eq!(gain.gain_db(), before);
+    }
+
+    #[test]
+    fn fixed_gain_clips() {
+        let mut samples = vec![0.1, -0.5, 0.2];
+        Gain::new(12.0, false).process(&mut samples);
+        assert!((samples[0] - 0.398).abs() < 1e-3);
+        assert_eq!(samples[1], -1.0);
+
+        let mut detector = ClipDetector::new(1000);
+        assert_eq!(detector.process(&samples), None);
+        assert_eq!(detector.process(&[0.0; 997]), Some(0.001));
+        assert_eq!(detector.process(&[0.0; 1000]), None);
+    }
+
+    fn tone(hz: f32, rate: u32, len: usize) -> Vec<f32> {
+        let step = 2.0 * std::f32::consts::PI * hz / rate as f32;
+        (0..len).map(|i| (i as f32 * step).sin()).collect()
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    fn polyphase(input: &[f32], in_rate: u32, out_rate: u32) -> Vec<f32> {
+        let mut resampler = PolyphaseResampler::new(in_rate, out_rate).unwrap();
+        let mut out = Vec::new();
+        for chunk in input.chunks(480) {
+            resampler.process_into(chunk, &mut out);
+        }
+        resampler.flush(&mut out);
+        out
+    }
+
+    #[test]
+    fn test_polyphase_length_and_gain() {
+        let out = polyphase(&vec![1.0; 48_000], 48_000, 24_000);
+        assert!(out.len().abs_diff(24_000) <= 32, "{}", out.len());
+        assert!(out[1000..20_000].iter().all(|s| (s - 1.0).abs() < 1e-3));
+        let out = polyphase(&vec![1.0; 44_100], 44_100, 24_000);
+        assert!(out.len().abs_diff(24_000) <= 32, "{}", out.len());
+        let out = polyphase(&tone(1000.0, 48_000, 48_000), 48_000, 24_000);
+        assert!((rms(&out[1000..20_000]) - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_polyphase_rejects_aliases() {
+        // Above the 12kHz Nyquist frequency of the output, decimating would fold it to 4kHz.
+        let out = polyphase(&tone(16_000.0, 48_000, 48_000), 48_000, 24_000);
+        assert!(rms(&out[1000..20_000]) < 0.01);
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn playback_feed_schedules_chunks() {
+        // 1kHz output, the prebuffer is 100 frames.
+        let (mut producer, mut feed) = AudioPlayer::buffers(1000, 100, 1000);
+        let mut out = vec![0.0; 50];
+        producer.push_slice(&[1.0; 30]);
+        feed.fill(&mut out, 1, None);
+        assert!(out.iter().all(|&v| v == 0.0));
+        let mut out = vec![0.0; 100];
+        feed.fill(&mut out, 1, None);
+        assert_eq!(&out[..50], &[0.0; 50]);
+        assert_eq!(&out[50..80], &[1.0; 30]);
+        assert_eq!(feed.shared.underruns.load(Ordering::Relaxed), 0);
+
+        // Ran dry before the end, the next chunk is scheduled again after the prebuffer.
+        producer.push_slice(&[2.0; 10]);
+        let mut out = vec![0.0; 200];
+        feed.fill(&mut out, 1, None);
+        assert_eq!(feed.shared.underruns.load(Ordering::Relaxed), 1);
+        assert_eq!(out.iter().position(|&v| v == 2.0), Some(100));
+        assert_eq!(&out[100..110], &[2.0; 10]);
+
+        // The end of the audio plays at once, on every channel, and is not an underrun.
+        feed.shared.ending.store(true, Ordering::Release);
+        let mut out = vec![0.0; 20];
+        feed.fill(&mut out, 1, None);
+        assert_eq!(feed.state, FeedState::Idle);
+        assert!(!feed.shared.ending.load(Ordering::Acquire));
+        feed.shared.ending.store(true, Ordering::Release);
+        producer.push_slice(&[0.5; 5]);
+        feed.fill(&mut out, 2, Some(2000));
+        assert_eq!(&out[..10], &[0.5; 10]);
+        assert_eq!(feed.next_frame, 2010);
+        assert!(!feed.shared.ending.load(Ordering::Acquire));
+        assert_eq!(feed.state, FeedState::Idle);
+        assert_eq!(feed.shared.underruns.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_downmix_stereo() {
+        let data: Vec<f32> = (0..2 * 37).map(|i| i as f32).collect();
+        let mut out = Vec::new();
+        downmix_f32_to_mono_into(&data, 2, &mut out);
+        let expected: Vec<f32> = data.chunks(2).map(|f| (f[0] + f[1]) / 2.0).collect();
+        assert_eq!(out, expected);
+
+        let mut fused = PolyphaseResampler::new(48_000, 24_000).unwrap();
+        let mut split = PolyphaseResampler::new(48_000, 24_000).unwrap();
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        fused.process_interleaved(&data, 2, &mut a);
+        split.process_into(&out, &mut b);
+        assert_eq!(a, b);
+    }
 }
@@ -1,9 +1,19 @@
-use anyhow::{Result, Context};
+use anyhow::{Context, Result};
 use chrono::{DateTime, Duration, SecondsFormat, Utc};
 use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 
+mod cache;
+#[cfg(feature = "better-auth")]
+mod remote;
+pub use cache::{CachedToken, TokenCache};
+#[cfg(feature = "better-auth")]
+pub use remote::{BetterAuthClient, Credentials, token_expiry};
+
+/// Validity of the tokens generated by [`AuthResolver`].
+pub const DEV_TOKEN_HOURS: f64 = 1.0;
+
 /// Session claims matching moshi-server's BetterAuthClaims.session.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -127,10 +137,7 @@ struct DevBetterAuthClaims {
 }
 
 /// Generate a dev JWT for moshi-server using the Better Auth claims format.
-pub fn generate_dev_jwt(
-    secret: &str,
-    hours: i64,
-) -> Result<String, jsonwebtoken::errors::Error> {
+pub fn generate_dev_jwt(secret: &str, hours: i64) -> Result<String, jsonwebtoken::errors::Error> {
     let user_id = std::env::var("MOSHI_USER_ID").unwrap_or_else(|_| "local-dev-user".to_string());
     let session_id =
         std::env::var("MOSHI_SESSION_ID").unwrap_or_else(|_| "local-dev-session".to_string());
@@ -166,7 +173,11 @@ pub fn generate_dev_jwt(
     let mut header = Header::new(Algorithm::HS256);
     header.typ = Some("JWT".to_string());
 
-    encode(&header, &claims, &EncodingKey::from_secret(secret.as_bytes()))
+    encode(
+        &header,
+        &claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )
 }
 
 pub fn env_is_set_nonempty(key: &str) -> bool {
@@ -176,68 +187,6 @@ pub fn env_is_set_nonempty(key: &str) -> bool {
     }
 }
 
-pub fn maybe_set_env_from_file(path: &Path, key: &str) -> Result<bool> {
-    if !path.exists() {
-        return Ok(false);
-    }
-
-    let contents = std::fs::read_to_string(path)
-        .with_context(|| format!("Failed to read env file: {}", path.display()))?;
-
-    for line in contents.lines() {
-        let line = line.trim();
-        if line.is_empty() || line.starts_with('#') {
-            continue;
-        }
-        let Some((name, value)) = line.split_once('=') else {
-            continue;
-        };
-        let name = name.trim();
-        let name = name.strip_prefix("export ").unwrap_or(name).trim();
-        if name == key {
-            let value = value.trim().trim_matches('"').trim_matches('\'');
-            if !value.is_empty() {
-                unsafe {
-                    std::env::set_var(key, value);
-                }
-                return Ok(true);
-            }
-        }
-    }
-
-    Ok(false)
-}
-
-pub fn load_better_auth_secret_from_env_files_if_needed(root: &Path) -> Result<()> {
-    if env_is_set_nonempty("BETTER_AUTH_SECRET") {
-        return Ok(());
-    }
-
-    let env_name = std::env::var("MOSHI_ENV")
-        .ok()
-        .filter(|value| !value.trim().is_empty())
-        .or_else(|| std::env::var("NODE_ENV").ok().filter(|value| !value.trim().is_empty()))
-        .unwrap_or_else(|| "development".to_string());
-
-    let candidates = [
-        root.join(format!("env.{env_name}")),
-        root.join(format!(".env.{env_name}")),
-        root.join("env.development"),
-        root.join(".env.development"),
-        root.join("env.production"),
-        root.join(".env.production"),
-        root.join(".env"),
-    ];
-
-    for candidate in candidates {
-        if maybe_set_env_from_file(&candidate, "BETTER_AUTH_SECRET")? {
-            break;
-        }
-    }
-
-    Ok(())
-}
-
 pub fn read_env_value(path: &Path, key: &str) -> Result<Option<String>> {
     let contents = std::fs::read_to_string(path)
         .with_context(|| format!("Failed to read env file: {}", path.display()))?;
@@ -259,16 +208,43 @@ pub fn read_env_value(path: &Path, key: &str) -> Result<Option<String>> {
     Ok(None)
 }
 
-pub fn load_secret_from_env_files(base_dir: &Path, env_name: Option<&str>) -> Result<Option<String>> {
-    let env_name = env_name.unwrap_or("development");
-    let candidates = [format!(".env.{env_name}"), ".env".to_string()];
+/// Environment of the `env.<env>` files: `env_name`, `MOSHI_ENV`, `NODE_ENV`, or `development`.
+fn env_file_name(env_name: Option<&str>) -> String {
+    let var = |key: &str| {
+        std::env::var(key)
+            .ok()
+            .filter(|value| !value.trim().is_empty())
+    };
+    env_name
+        .map(str::to_string)
+        .or_else(|| var("MOSHI_ENV"))
+        .or_else(|| var("NODE_ENV"))
+        .unwrap_or_else(|| "development".to_string())
+}
+
+pub fn load_secret_from_env_files(
+    base_dir: &Path,
+    env_name: Option<&str>,
+) -> Result<Option<String>> {
+    let env_name = env_file_name(env_name);
+    let candidates = [
+        format!("env.{env_name}"),
+        format!(".env.{env_name}"),
+        "env.development".to_string(),
+        ".env.development".to_string(),
+        "env.production".to_string(),
+        ".env.production".to_string(),
+        ".env".to_string(),
+    ];
 
     for file_name in candidates {
         let path = base_dir.join(file_name);
         if !path.exists() {
             continue;
         }
-        if let Some(secret) = read_env_value(&path, "BETTER_AUTH_SECRET")? {
+        if let Some(secret) = read_env_value(&path, "BETTER_AUTH_SECRET")?
+            && !secret.is_empty()
+        {
             return Ok(Some(secret));
         }
     }
@@ -276,17 +252,28 @@ pub fn load_secret_from_env_files(base_dir: &Path, env_name: Option<&str>) -> Re
     Ok(None)
 }
 
-pub fn resolve_secret(explicit: Option<&str>, base_dir: &Path, env_name: Option<&str>) -> Result<String> {
+/// BETTER_AUTH_SECRET from `explicit`, the environment, then the first of the `env.<env>`,
+/// `.env.<env>`, `env.development`, `.env.development`, `env.production`, `.env.production` and
+/// `.env` files of `base_dir` that sets it.
+pub fn resolve_secret(
+    explicit: Option<&str>,
+    base_dir: &Path,
+    env_name: Option<&str>,
+) -> Result<String> {
     if let Some(secret) = explicit {
         return Ok(secret.to_string());
     }
 
+    if env_is_set_nonempty("BETTER_AUTH_SECRET") {
+        return Ok(std::env::var("BETTER_AUTH_SECRET")?);
+    }
+
     if let Some(secret) = load_secret_from_env_files(base_dir, env_name)? {
         return Ok(secret);
     }
 
     anyhow::bail!(
-        "--secret/BETTER_AUTH_SECRET or .env(.<env>) with BETTER_AUTH_SECRET is required"
+        "--secret/BETTER_AUTH_SECRET or (.)env(.<env>) with BETTER_AUTH_SECRET is required"
     )
 }
 
@@ -295,6 +282,8 @@ pub struct AuthResolver<'a> {
     pub secret: Option<&'a str>,
     pub env_name: Option<&'a str>,
     pub user_agent: &'a str,
+    /// Where generated tokens are cached, [`TokenCache::default_path`] by default.
+    pub cache: Option<TokenCache>,
 }
 
 impl<'a> AuthResolver<'a> {
@@ -304,6 +293,7 @@ impl<'a> AuthResolver<'a> {
             secret: None,
             env_name: None,
             user_agent,
+            cache: TokenCache::default_path().map(TokenCache::new),
         }
     }
 
@@ -322,6 +312,31 @@ impl<'a> AuthResolver<'a> {
         self
     }
 
+    pub fn with_cache(mut self, cache: Option<TokenCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// A dev token signed with the resolved secret, reused from the cache while it is valid for
+    /// long enough.
+    pub fn dev_token(&self) -> Result<CachedToken> {
+        let base_dir = std::env::current_dir()?;
+        let secret = resolve_secret(self.secret, &base_dir, self.env_name)?;
+        match self.cache.as_ref() {
+            Some(cache) => cache.get_or_generate(&secret, DEV_TOKEN_HOURS, self.user_agent),
+            None => {
+                let token = generate_token(&secret, DEV_TOKEN_HOURS, self.user_agent)
+                    .map_err(|e| anyhow::anyhow!("Failed to generate token: {}", e))?;
+                let expires_at = Utc::now().timestamp() + (DEV_TOKEN_HOURS * 3600.0) as i64;
+                Ok(CachedToken {
+                    token,
+                    expires_at,
+                    generated: true,
+                })
+            }
+        }
+    }
+
     pub fn resolve(&self, auto_token: bool) -> Result<Option<String>> {
         if let Some(token) = self.token {
             return Ok(Some(token.to_string()));
@@ -334,12 +349,7 @@ impl<'a> AuthResolver<'a> {
         }
 
         if auto_token {
-            let base_dir = std::env::current_dir()?;
-            load_better_auth_secret_from_env_files_if_needed(&base_dir)?;
-            let secret = resolve_secret(self.secret, &base_dir, self.env_name)?;
-            let token = generate_token(&secret, 1.0, self.user_agent)
-                .map_err(|e| anyhow::anyhow!("Failed to generate token: {}", e))?;
-            return Ok(Some(token));
+            return Ok(Some(self.dev_token()?.token));
         }
 
         Ok(None)
@@ -0,0 +1,204 @@
+//! Dev tokens generated from BETTER_AUTH_SECRET, cached on disk so that successive CLI runs
+//! reuse the same token rather than signing a new one each time.
+//!
+//! The cached token is only reused when it verifies against the current secret, was issued for
+//! the requested validity and has more than a quarter of it left, a new one replaces it
+//! otherwise.
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use jsonwebtoken::{DecodingKey, Validation, decode};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheFile {
+    token: String,
+    /// Seconds since the epoch.
+    expires_at: i64,
+}
+
+/// A dev token and whether it was just generated or read from the cache.
+#[derive(Debug, Clone)]
+pub struct CachedToken {
+    pub token: String,
+    /// Seconds since the epoch.
+    pub expires_at: i64,
+    pub generated: bool,
+}
+
+impl CachedToken {
+    pub fn expires_in_s(&self) -> u64 {
+        (self.expires_at - Utc::now().timestamp()).max(0) as u64
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenCache {
+    path: PathBuf,
+}
+
+impl TokenCache {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// `$XDG_CACHE_HOME/kyutai/token.json` defaulting to `~/.cache`, `None` without a home
+    /// directory.
+    pub fn default_path() -> Option<PathBuf> {
+        let cache_dir = match std::env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => dirs::home_dir()?.join(".cache"),
+        };
+        Some(cache_dir.join("kyutai").join("token.json"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The cached token when it is still good for `secret` and `hours`, a new token valid for
+    /// `hours` otherwise. Failing to write the cache is not an error, the token is then generated
+    /// again on the next call.
+    pub fn get_or_generate(
+        &self,
+        secret: &str,
+        hours: f64,
+        user_agent: &str,
+    ) -> Result<CachedToken> {
+        if let Some(cached) = self.read(secret, hours) {
+            return Ok(cached);
+        }
+        let token = super::generate_token(secret, hours, user_agent)
+            .map_err(|e| anyhow::anyhow!("Failed to generate token: {}", e))?;
+        let expires_at = Utc::now().timestamp() + (hours * 3600.0) as i64;
+        let _ = self.write(&CacheFile {
+            token: token.clone(),
+            expires_at,
+        });
+        Ok(CachedToken {
+            token,
+            expires_at,
+            generated: true,
+        })
+    }
+
+    /// Remove the cached token, e.g. after the secret was rotated.
+    pub fn clear(&self) -> Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("cannot remove {}", self.path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn read(&self, secret: &str, hours: f64) -> Option<CachedToken> {
+        let json = std::fs::read_to_string(&self.path).ok()?;
+        let file: CacheFile = serde_json::from_str(&json).ok()?;
+        let mut validation = Validation::default();
+        validation.validate_exp = false;
+        let key = DecodingKey::from_secret(secret.as_bytes());
+        let claims = decode::<super::BetterAuthClaims>(&file.token, &key, &validation)
+            .ok()?
+            .claims;
+        if claims.exp - claims.iat != (hours * 3600.0) as i64 {
+            return None;
+        }
+        let remaining = claims.exp - Utc::now().timestamp();
+        if remaining * 4 <= claims.exp - claims.iat {
+            return None;
+        }
+        Some(CachedToken {
+            token: file.token,
+            expires_at: claims.exp,
+            generated: false,
+        })
+    }
+
+    fn write(&self, file: &CacheFile) -> Result<()> {
+        use std::io::Write;
+
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut out = options.open(&self.path)?;
+        out.write_all(serde_json::to_string(file)?.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_the_token_until_close_to_expiry() {
+        let dir = std::env::temp_dir().join(format!("kyutai-token-cache-{}", std::process::id()));
+        let cache = TokenCache::new(dir.join("token.json"));
+        let first = cache.get_or_generate("secret", 1.0, "test").unwrap();
+        assert!(first.generated);
+        assert!(first.expires_in_s() > 3500);
+        let second = cache.get_or_generate("secret", 1.0, "test").unwrap();
+        assert!(!second.generated);
+        assert_eq!(second.token, first.token);
+
+        // Signed with another secret.
+        assert!(
+            cache
+                .get_or_generate("rotated", 1.0, "test")
+                .unwrap()
+                .generated
+        );
+
+        // Issued 40 and 45 minutes ago, out of one hour.
+        let issued = |ago_min: i64| {
+            let key = DecodingKey::from_secret(b"secret");
+            let mut claims =
+                decode::<crate::auth::BetterAuthClaims>(&first.token, &key, &Validation::default())
+                    .unwrap()
+                    .claims;
+            (claims.iat, claims.exp) = (claims.iat - ago_min * 60, claims.exp - ago_min * 60);
+            let key = jsonwebtoken::EncodingKey::from_secret(b"secret");
+            let token = jsonwebtoken::encode(&Default::default(), &claims, &key).unwrap();
+            CacheFile {
+                token,
+                expires_at: claims.exp,
+            }
+        };
+        // Asked for another validity.
+        let day = cache.get_or_generate("secret", 24.0, "test").unwrap();
+        assert!(day.generated);
+        assert!(day.expires_in_s() > 23 * 3600);
+        assert!(
+            cache
+                .get_or_generate("secret", 1.0, "test")
+                .unwrap()
+                .generated
+        );
+
+        cache.write(&issued(40)).unwrap();
+        assert!(
+            !cache
+                .get_or_generate("secret", 1.0, "test")
+                .unwrap()
+                .generated
+        );
+        cache.write(&issued(45)).unwrap();
+        assert!(
+            cache
+                .get_or_generate("secret", 1.0, "test")
+                .unwrap()
+                .generated
+        );
+
+        cache.clear().unwrap();
+        cache.clear().unwrap();
+        assert!(!cache.path().exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,338 @@
+//! Tokens fetched from a Better Auth server, for clients that do not hold BETTER_AUTH_SECRET.
+//!
+//! The auth server caches its sessions in a JWT cookie (`session.cookieCache.strategy = "jwt"`),
+//! and that JWT is what moshi-server validates. The client signs in once, keeps the session
+//! cookie, and asks the session for a new JWT shortly before the current one expires, signing
+//! in again when the session itself has expired.
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use url::Url;
+
+const SESSION_TOKEN_COOKIE: &str = "better-auth.session_token";
+const SESSION_DATA_COOKIE: &str = "better-auth.session_data";
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// How the client signs in to the auth server.
+#[derive(Clone)]
+pub enum Credentials {
+    EmailPassword {
+        email: String,
+        password: String,
+    },
+    /// Key of the Better Auth API key plugin, sent with each request.
+    ApiKey(String),
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EmailPassword { email, .. } => write!(f, "EmailPassword({email})"),
+            Self::ApiKey(_) => write!(f, "ApiKey(..)"),
+        }
+    }
+}
+
+impl Credentials {
+    /// `MOSHI_AUTH_API_KEY`, or `MOSHI_AUTH_EMAIL` and `MOSHI_AUTH_PASSWORD`.
+    pub fn from_env() -> Result<Self> {
+        let var = |key: &str| std::env::var(key).ok().filter(|v| !v.trim().is_empty());
+        if let Some(key) = var("MOSHI_AUTH_API_KEY") {
+            return Ok(Self::ApiKey(key));
+        }
+        match (var("MOSHI_AUTH_EMAIL"), var("MOSHI_AUTH_PASSWORD")) {
+            (Some(email), Some(password)) => Ok(Self::EmailPassword { email, password }),
+            _ => anyhow::bail!(
+                "MOSHI_AUTH_API_KEY, or MOSHI_AUTH_EMAIL and MOSHI_AUTH_PASSWORD, are required"
+            ),
+        }
+    }
+}
+
+/// Outcome of a failed request, only the transient ones are retried.
+enum Failure {
+    /// Transport errors, 429 and 5xx answers.
+    Transient(anyhow::Error),
+    /// Other answers, e.g. wrong credentials or an expired session.
+    Rejected(anyhow::Error),
+}
+
+impl Failure {
+    fn into_error(self) -> anyhow::Error {
+        match self {
+            Self::Transient(e) | Self::Rejected(e) => e,
+        }
+    }
+}
+
+/// Cookies set by an answer of the auth server, as `name=value` pairs.
+#[derive(Debug, Default, PartialEq)]
+struct SetCookies {
+    session_token: Option<String>,
+    session_data: Option<String>,
+}
+
+impl SetCookies {
+    fn parse<'a>(headers: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut cookies = Self::default();
+        for header in headers {
+            let pair = header.split(';').next().unwrap_or_default().trim();
+            let Some((name, value)) = pair.split_once('=') else {
+                continue;
+            };
+            // Deleted cookies are set to an empty value. The names get a `__Secure-` prefix
+            // over https.
+            if value.is_empty() {
+                continue;
+            }
+            if name.ends_with(SESSION_TOKEN_COOKIE) {
+                cookies.session_token = Some(pair.to_string());
+            } else if name.ends_with(SESSION_DATA_COOKIE) {
+                cookies.session_data = Some(value.to_string());
+            }
+        }
+        cookies
+    }
+}
+
+/// Expiry of `jwt` in seconds since the epoch, the signature is left to the server.
+pub fn token_expiry(jwt: &str) -> Result<i64> {
+    #[derive(serde::Deserialize)]
+    struct Exp {
+        exp: i64,
+    }
+    let data = jsonwebtoken::dangerous::insecure_decode::<Exp>(jwt)
+        .context("the auth server did not answer a JWT")?;
+    Ok(data.claims.exp)
+}
+
+#[derive(Default)]
+struct State {
+    /// Current JWT and its expiry.
+    jwt: Option<(String, i64)>,
+    /// Session cookie of the email sign-in, as `name=value`.
+    session: Option<String>,
+}
+
+/// Source of moshi-server tokens backed by a Better Auth server, shared by the connections of
+/// a client. [`BetterAuthClient::token`] answers the cached JWT until it gets close to its
+/// expiry, [`BetterAuthClient::refresh`] forces a new one, e.g. after the server closed the
+/// connection with `token expired`.
+pub struct BetterAuthClient {
+    base_url: Url,
+    credentials: Credentials,
+    http: reqwest::Client,
+    refresh_before: Duration,
+    max_attempts: usize,
+    retry_delay: Duration,
+    state: Mutex<State>,
+}
+
+impl std::fmt::Debug for BetterAuthClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BetterAuthClient")
+            .field("base_url", &self.base_url.as_str())
+            .field("credentials", &self.credentials)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BetterAuthClient {
+    /// `base_url` is the root of the auth server, the routes are under `/api/auth/`.
+    pub fn new(base_url: &str, credentials: Credentials, user_agent: &str) -> Result<Self> {
+        let mut base_url =
+            Url::parse(base_url).with_context(|| format!("invalid auth server url {base_url}"))?;
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        let http = reqwest::Client::builder()
+            .user_agent(user_agent)
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        Ok(Self {
+            base_url,
+            credentials,
+            http,
+            refresh_before: Duration::from_secs(60),
+            max_attempts: 3,
+            retry_delay: Duration::from_millis(500),
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    /// Fetch a new token when the current one expires within `margin`, one minute by default.
+    pub fn with_refresh_before(mut self, margin: Duration) -> Self {
+        self.refresh_before = margin;
+        self
+    }
+
+    /// Try the transient failures `max_attempts` times, the delay doubling after each attempt.
+    pub fn with_retries(mut self, max_attempts: usize, delay: Duration) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.retry_delay = delay;
+        self
+    }
+
+    /// A token valid for at least the refresh margin.
+    pub async fn token(&self) -> Result<String> {
+        let mut state = self.state.lock().await;
+        if let Some((jwt, exp)) = state.jwt.as_ref()
+            && Utc::now().timestamp() + (self.refresh_before.as_secs() as i64) < *exp
+        {
+            return Ok(jwt.clone());
+        }
+        self.fetch(&mut state).await
+    }
+
+    /// A new token, whatever the expiry of the current one.
+    pub async fn refresh(&self) -> Result<String> {
+        let mut state = self.state.lock().await;
+        state.jwt = None;
+        self.fetch(&mut state).await
+    }
+
+    async fn fetch(&self, state: &mut State) -> Result<String> {
+        let jwt = match (&self.credentials, state.session.clone()) {
+            (Credentials::ApiKey(_), _) => {
+                self.session_jwt(None).await.map_err(Failure::into_error)?
+            }
+            (Credentials::EmailPassword { .. }, Some(session)) => {
+                match self.session_jwt(Some(&session)).await {
+                    Ok(jwt) => jwt,
+                    Err(Failure::Rejected(err)) => {
+                        tracing::debug!(?err, "session rejected, signing in again");
+                        state.session = None;
+                        self.sign_in(state).await?
+                    }
+                    Err(failure) => return Err(failure.into_error()),
+                }
+            }
+            (Credentials::EmailPassword { .. }, None) => self.sign_in(state).await?,
+        };
+        let exp = token_expiry(&jwt)?;
+        tracing::debug!(
+            expires_in_s = exp - Utc::now().timestamp(),
+            "fetched auth token"
+        );
+        state.jwt = Some((jwt.clone(), exp));
+        Ok(jwt)
+    }
+
+    async fn sign_in(&self, state: &mut State) -> Result<String> {
+        let Credentials::EmailPassword { email, password } = &self.credentials else {
+            anyhow::bail!("signing in requires an email and a password")
+        };
+        let body = serde_json::json!({ "email": email, "password": password });
+        let url = self.url("sign-in/email")?;
+        let cookies = self
+            .send(|| self.http.post(url.clone()).json(&body))
+            .await
+            .map_err(Failure::into_error)
+            .context("cannot sign in to the auth server")?;
+        let session = cookies
+            .session_token
+            .context("the auth server did not answer a session cookie")?;
+        state.session = Some(session.clone());
+        match cookies.session_data {
+            Some(jwt) => Ok(jwt),
+            None => self
+                .session_jwt(Some(&session))
+                .await
+                .map_err(Failure::into_error),
+        }
+    }
+
+    /// JWT of the session of the cookie, or of the API key when `session` is unset.
+    async fn session_jwt(&self, session: Option<&str>) -> Result<String, Failure> {
+        let url = self.url("get-session").map_err(Failure::Rejected)?;
+        let cookies = self
+            .send(|| {
+                // Ask for the session to be read from the database, the cookie cache
+                // would answer the current JWT.
+                let request = self
+                    .http
+                    .get(url.clone())
+                    .query(&[("disableCookieCache", "true")]);
+                match (session, &self.credentials) {
+                    (Some(session), _) => request.header(reqwest::header::COOKIE, session),
+                    (None, Credentials::ApiKey(key)) => request.header(API_KEY_HEADER, key),
+                    (None, _) => request,
+                }
+            })
+            .await?;
+        cookies
+            .session_data
+            .ok_or_else(|| Failure::Rejected(anyhow::anyhow!("no session on the auth server")))
+    }
+
+    fn url(&self, route: &str) -> Result<Url> {
+        Ok(self.base_url.join("api/auth/")?.join(route)?)
+    }
+
+    async fn send(
+        &self,
+        request: impl Fn() -> reqwest::RequestBuilder,
+    ) -> Result<SetCookies, Failure> {
+        let mut delay = self.retry_delay;
+        let mut attempt = 1;
+        loop {
+            let failure = match request().send().await {
+                Ok(response) if response.status().is_success() => {
+                    let headers = response.headers().get_all(reqwest::header::SET_COOKIE);
+                    return Ok(SetCookies::parse(
+                        headers.iter().filter_map(|v| v.to_str().ok()),
+                    ));
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    let body = response.text().await.unwrap_or_default();
+                    let err = anyhow::anyhow!("auth server answered {status}: {}", body.trim());
+                    let throttled = status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+                    if status.is_server_error() || throttled {
+                        Failure::Transient(err)
+                    } else {
+                        Failure::Rejected(err)
+                    }
+                }
+                Err(err) => Failure::Transient(err.into()),
+            };
+            match failure {
+                Failure::Transient(err) if attempt < self.max_attempts => {
+                    tracing::debug!(?err, attempt, "auth server request failed, retrying");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                failure => return Err(failure),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_session_cookies() {
+        let cookies = SetCookies::parse([
+            "__Secure-better-auth.session_token=abc.def; Max-Age=604800; Path=/; HttpOnly",
+            "__Secure-better-auth.session_data=eyJ.x.y; Max-Age=300; Path=/; HttpOnly",
+            "other=1; Path=/",
+        ]);
+        assert_eq!(
+            cookies.session_token.as_deref(),
+            Some("__Secure-better-auth.session_token=abc.def")
+        );
+        assert_eq!(cookies.session_data.as_deref(), Some("eyJ.x.y"));
+        let deleted = SetCookies::parse(["better-auth.session_data=; Max-Age=0"]);
+        assert_eq!(deleted, SetCookies::default());
+
+        let jwt = crate::auth::generate_token("secret", 1.0, "test").unwrap();
+        let exp = token_expiry(&jwt).unwrap();
+        assert!((exp - Utc::now().timestamp() - 3600).abs() < 5);
+        assert!(token_expiry("not-a-jwt").is_err());
+    }
+}
@@ -0,0 +1,153 @@
+//! Protocol features of a server, from its `/api/capabilities` endpoint.
+//!
+//! Servers ignore the query parameters they do not know, so an option sent to an older server
+//! is silently dropped. The clients fetch the capabilities before connecting and leave out the
+//! options whose feature is not listed for the endpoint, with a warning, so that a fleet mixing
+//! server versions gets the options each server actually supports.
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+use url::Url;
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct Capabilities {
+    pub server_version: String,
+    /// Enabled features, with the endpoint paths supporting them.
+    pub features: BTreeMap<String, Vec<String>>,
+}
+
+impl Capabilities {
+    /// Whether the endpoint at `path` supports `feature`. A path that no feature lists, e.g.
+    /// behind a reverse proxy rewriting the paths, is taken to support everything, as it cannot
+    /// be checked.
+    pub fn supports(&self, feature: &str, path: &str) -> bool {
+        let listed = |paths: &Vec<String>| paths.iter().any(|p| p == path);
+        if !self.features.values().any(listed) {
+            return true;
+        }
+        self.features.get(feature).is_some_and(listed)
+    }
+
+    /// Remove from `query` the parameters that `params` maps to a feature the endpoint at
+    /// `path` does not support, returns the names of the removed parameters.
+    pub fn retain<'a>(
+        &self,
+        path: &str,
+        query: &mut Vec<(&'a str, &'a str)>,
+        params: &[(&str, &str)],
+    ) -> Vec<&'a str> {
+        let mut removed = vec![];
+        query.retain(|(name, _)| {
+            let feature = params
+                .iter()
+                .find(|(param, _)| param == name)
+                .map(|(_, f)| *f);
+            let keep = feature.is_none_or(|feature| self.supports(feature, path));
+            if !keep {
+                removed.push(*name);
+            }
+            keep
+        });
+        removed
+    }
+}
+
+/// The capabilities endpoint of the server at `url`, a websocket or http url.
+pub fn capabilities_url(url: &str) -> Result<Url> {
+    let mut url = Url::parse(url)?;
+    let scheme = match url.scheme() {
+        "ws" | "http" => "http",
+        "wss" | "https" => "https",
+        scheme => anyhow::bail!("unsupported url scheme {scheme}"),
+    };
+    url.set_scheme(scheme)
+        .map_err(|()| anyhow::anyhow!("cannot set the scheme of {url}"))?;
+    url.set_path("/api/capabilities");
+    url.set_query(None);
+    url.set_fragment(None);
+    Ok(url)
+}
+
+/// Fetch the capabilities of the server at `url`. Servers that predate the endpoint answer
+/// with an error, the callers then keep all their options.
+#[cfg(feature = "capabilities")]
+pub async fn fetch(url: &str, timeout: std::time::Duration) -> Result<Capabilities> {
+    let url = capabilities_url(url)?;
+    let http = reqwest::Client::builder().timeout(timeout).build()?;
+    let caps = http
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(caps)
+}
+
+/// Remove from `query` the parameters of `params` that the server at `url` does not support,
+/// with a warning. Nothing is fetched when `query` has none of them, and everything is kept
+/// when the capabilities cannot be fetched.
+#[cfg(feature = "capabilities")]
+pub async fn negotiate<'a>(
+    url: &str,
+    query: &mut Vec<(&'a str, &'a str)>,
+    params: &[(&str, &str)],
+    timeout: std::time::Duration,
+) {
+    if !query
+        .iter()
+        .any(|(name, _)| params.iter().any(|(param, _)| param == name))
+    {
+        return;
+    }
+    let caps = match fetch(url, timeout).await {
+        Ok(caps) => caps,
+        Err(err) => {
+            tracing::debug!(
+                ?err,
+                "cannot fetch the server capabilities, keeping all options"
+            );
+            return;
+        }
+    };
+    let path = Url::parse(url)
+        .map(|url| url.path().to_string())
+        .unwrap_or_default();
+    for param in caps.retain(&path, query, params) {
+        tracing::warn!(
+            param,
+            path,
+            server_version = caps.server_version,
+            "the server does not support this option, leaving it out"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        let url = capabilities_url("wss://host:8080/api/asr-streaming?token=x").unwrap();
+        assert_eq!(url.as_str(), "https://host:8080/api/capabilities");
+        let url = capabilities_url("ws://host/api/tts_streaming").unwrap();
+        assert_eq!(url.as_str(), "http://host/api/capabilities");
+        assert!(capabilities_url("ftp://host").is_err());
+
+        let caps: Capabilities = serde_json::from_str(
+            r#"{"server_version":"0.6.4","features":{"itn":["/api/asr-streaming"],"tts_speed":["/api/tts_streaming"]}}"#,
+        )
+        .unwrap();
+        assert!(caps.supports("itn", "/api/asr-streaming"));
+        assert!(!caps.supports("coalesce", "/api/asr-streaming"));
+        assert!(!caps.supports("itn", "/api/tts_streaming"));
+        assert!(caps.supports("coalesce", "/proxied/asr"));
+
+        let mut query = vec![("stream_id", "a"), ("itn", "true"), ("coalesce_ms", "50")];
+        let params = [("itn", "itn"), ("coalesce_ms", "coalesce")];
+        let removed = caps.retain("/api/asr-streaming", &mut query, &params);
+        assert_eq!(removed, ["coalesce_ms"]);
+        assert_eq!(query, [("stream_id", "a"), ("itn", "true")]);
+    }
+}
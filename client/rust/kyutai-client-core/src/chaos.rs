@@ -0,0 +1,348 @@
+//! Fault injection on websocket connections, to test clients and servers on slow and lossy
+//! networks.
+//!
+//! [`wrap`] puts a [`ChaosWs`] between a socket and its user. Each direction goes through a
+//! [`Link`] that delays, reorders and drops messages as described by a [`LinkProfile`]. The
+//! profiles are plain data that tests load from TOML fixtures, and their random decisions are
+//! seeded so that a failing run can be replayed. Control frames are delayed like the others but
+//! never dropped nor reordered, the connection itself stays healthy.
+
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+/// How long a message held back for reordering waits for the next one before it is delivered
+/// anyway, so that a request waiting for its answer does not stall.
+pub const REORDER_HOLD: Duration = Duration::from_millis(200);
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkProfile {
+    /// Delay of every message.
+    pub latency_ms: u64,
+    /// Random extra delay, uniform up to this bound. Messages keep their order, a message is
+    /// never delivered before the one sent ahead of it.
+    pub jitter_ms: u64,
+    /// Probability that a data message is delivered after the next one.
+    pub reorder: f64,
+    /// Probability that a data message is lost.
+    pub drop: f64,
+    /// Throughput of the link, 0 for unlimited. The link does not read its input faster, so a
+    /// slow downlink holds back the socket and the server sees a slow reader.
+    pub bytes_per_s: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChaosProfile {
+    /// Messages sent on the wrapped socket.
+    pub up: LinkProfile,
+    /// Messages received from the wrapped socket.
+    pub down: LinkProfile,
+    /// Seed of the random decisions, the same seed replays the same faults.
+    pub seed: u64,
+}
+
+/// Schedule of the messages of one direction.
+pub struct Link<T> {
+    profile: LinkProfile,
+    rng: StdRng,
+    /// Messages with their delivery time, in delivery order.
+    queue: std::collections::VecDeque<(Instant, T)>,
+    held: Option<(Instant, T)>,
+    last: Option<Instant>,
+    dropped: u64,
+}
+
+impl<T> Link<T> {
+    pub fn new(profile: LinkProfile, seed: u64) -> Self {
+        Self {
+            profile,
+            rng: StdRng::seed_from_u64(seed),
+            queue: Default::default(),
+            held: None,
+            last: None,
+            dropped: 0,
+        }
+    }
+
+    /// Queue a message sent at `now`, only `data` messages are dropped or reordered.
+    pub fn push(&mut self, now: Instant, msg: T, data: bool) {
+        if data && self.rng.random_bool(self.profile.drop.clamp(0., 1.)) {
+            self.dropped += 1;
+            return;
+        }
+        let jitter = match self.profile.jitter_ms {
+            0 => 0,
+            j => self.rng.random_range(0..=j),
+        };
+        let mut deadline = now + Duration::from_millis(self.profile.latency_ms + jitter);
+        if let Some(last) = self.last {
+            deadline = deadline.max(last);
+        }
+        self.last = Some(deadline);
+        if data {
+            if let Some((_, held)) = self.held.take() {
+                self.queue.push_back((deadline, msg));
+                self.queue.push_back((deadline, held));
+                return;
+            }
+            if self.rng.random_bool(self.profile.reorder.clamp(0., 1.)) {
+                self.held = Some((deadline + REORDER_HOLD, msg));
+                return;
+            }
+        }
+        self.queue.push_back((deadline, msg));
+    }
+
+    /// Next message due at `now`.
+    pub fn pop_due(&mut self, now: Instant) -> Option<T> {
+        if self
+            .held
+            .as_ref()
+            .is_some_and(|(release, _)| *release <= now)
+        {
+            let (release, msg) = self.held.take()?;
+            self.last = self.last.map(|last| last.max(release));
+            return Some(msg);
+        }
+        match self.queue.front() {
+            Some((deadline, _)) if *deadline <= now => self.queue.pop_front().map(|(_, m)| m),
+            _ => None,
+        }
+    }
+
+    /// When the next message is due.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let queued = self.queue.front().map(|(deadline, _)| *deadline);
+        let held = self.held.as_ref().map(|(release, _)| *release);
+        match (queued, held) {
+            (Some(q), Some(h)) => Some(q.min(h)),
+            (q, h) => q.or(h),
+        }
+    }
+
+    /// Messages lost so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/// Messages that can be dropped and reordered, text and binary ones for [`wrap`].
+pub type DataFilter = fn(&Message) -> bool;
+
+fn is_data(msg: &Message) -> bool {
+    msg.is_binary() || msg.is_text()
+}
+
+/// A websocket behind faulty links, used as the wrapped socket would be.
+pub struct ChaosWs {
+    tx: Option<mpsc::UnboundedSender<Message>>,
+    rx: mpsc::Receiver<Result<Message, WsError>>,
+    dropped: Arc<[AtomicU64; 2]>,
+}
+
+impl ChaosWs {
+    /// Messages lost on the uplink and on the downlink.
+    pub fn dropped(&self) -> (u64, u64) {
+        (
+            self.dropped[0].load(Ordering::Relaxed),
+            self.dropped[1].load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Wrap `socket` with the faults of `profile`, on a tokio runtime.
+pub fn wrap<S>(socket: S, profile: &ChaosProfile) -> ChaosWs
+where
+    S: Stream<Item = Result<Message, WsError>> + Sink<Message, Error = WsError> + Send + 'static,
+{
+    wrap_with(socket, profile, is_data)
+}
+
+/// Same as [`wrap`], with `data` telling which messages can be dropped and reordered, e.g. to
+/// keep the markers of a session and lose its audio.
+pub fn wrap_with<S>(socket: S, profile: &ChaosProfile, data: DataFilter) -> ChaosWs
+where
+    S: Stream<Item = Result<Message, WsError>> + Sink<Message, Error = WsError> + Send + 'static,
+{
+    let (mut sink, stream) = socket.split();
+    let dropped = Arc::new([AtomicU64::new(0), AtomicU64::new(0)]);
+
+    let (tx, mut up_rx) = mpsc::unbounded_channel();
+    let (up_tx, mut up_out) = mpsc::channel::<Result<Message, WsError>>(1);
+    let up_in = futures_util::stream::poll_fn(move |cx| up_rx.poll_recv(cx).map(|m| m.map(Ok)));
+    let up = Link::new(profile.up.clone(), profile.seed);
+    tokio::spawn(run_link(up_in, up_tx, up, data, dropped.clone(), 0));
+    tokio::spawn(async move {
+        while let Some(Ok(msg)) = up_out.recv().await {
+            if sink.send(msg).await.is_err() {
+                return;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    let (down_tx, rx) = mpsc::channel(1);
+    let down = Link::new(profile.down.clone(), profile.seed.wrapping_add(1));
+    tokio::spawn(run_link(stream, down_tx, down, data, dropped.clone(), 1));
+    ChaosWs {
+        tx: Some(tx),
+        rx,
+        dropped,
+    }
+}
+
+/// Move the messages of `input` through `link` to `output`, until the input ends and the link
+/// is empty or the output is gone. An input error is delivered after the queued messages.
+async fn run_link<I>(
+    mut input: I,
+    output: mpsc::Sender<Result<Message, WsError>>,
+    mut link: Link<Message>,
+    data: DataFilter,
+    dropped: Arc<[AtomicU64; 2]>,
+    direction: usize,
+) where
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let bytes_per_s = link.profile.bytes_per_s;
+    let mut input_done = false;
+    let mut error = None;
+    // The link is busy sending the previous message until then.
+    let mut readable_at = Instant::now();
+    loop {
+        let now = Instant::now();
+        let readable = !input_done && readable_at <= now;
+        let wake = match (link.next_deadline(), !input_done && !readable) {
+            (Some(d), true) => Some(d.min(readable_at)),
+            (Some(d), false) => Some(d),
+            (None, true) => Some(readable_at),
+            (None, false) => None,
+        };
+        if input_done && wake.is_none() {
+            break;
+        }
+        let sleep = tokio::time::sleep_until(wake.unwrap_or(now));
+        tokio::select! {
+            msg = input.next(), if readable => match msg {
+                Some(Ok(msg)) => {
+                    if bytes_per_s > 0 {
+                        let wire = Duration::from_secs_f64(msg.len() as f64 / bytes_per_s as f64);
+                        readable_at = Instant::now() + wire;
+                    }
+                    let is_data = data(&msg);
+                    link.push(Instant::now(), msg, is_data);
+                    dropped[direction].store(link.dropped(), Ordering::Relaxed);
+                }
+                Some(Err(err)) => {
+                    error = Some(err);
+                    input_done = true;
+                }
+                None => input_done = true,
+            },
+            _ = sleep, if wake.is_some() => {
+                while let Some(msg) = link.pop_due(Instant::now()) {
+                    if output.send(Ok(msg)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+    if let Some(err) = error {
+        let _ = output.send(Err(err)).await;
+    }
+}
+
+impl Stream for ChaosWs {
+    type Item = Result<Message, WsError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+impl Sink<Message> for ChaosWs {
+    type Error = WsError;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+        match self.tx.as_ref() {
+            Some(tx) if !tx.is_closed() => Poll::Ready(Ok(())),
+            _ => Poll::Ready(Err(WsError::AlreadyClosed)),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), WsError> {
+        match self.tx.as_ref() {
+            Some(tx) => tx.send(item).map_err(|_| WsError::AlreadyClosed),
+            None => Err(WsError::AlreadyClosed),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Closes the uplink, the socket is closed once the queued messages are sent.
+    fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+        self.tx = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_delays_reorders_and_drops() {
+        let now = Instant::now();
+        let ms = |v| now + Duration::from_millis(v);
+
+        let profile = LinkProfile {
+            latency_ms: 50,
+            ..Default::default()
+        };
+        let mut link = Link::new(profile, 0);
+        link.push(now, 1, true);
+        link.push(ms(10), 2, true);
+        assert_eq!(link.next_deadline(), Some(ms(50)));
+        assert_eq!(link.pop_due(ms(49)), None);
+        assert_eq!(link.pop_due(ms(50)), Some(1));
+        assert_eq!(link.pop_due(ms(60)), Some(2));
+
+        let profile = LinkProfile {
+            reorder: 1.,
+            ..Default::default()
+        };
+        let mut link = Link::new(profile, 0);
+        link.push(now, 1, true);
+        link.push(now, 2, false);
+        link.push(now, 3, true);
+        link.push(now, 4, true);
+        let order: Vec<_> = std::iter::from_fn(|| link.pop_due(now)).collect();
+        assert_eq!(order, vec![2, 3, 1]);
+        // The last message is held back, then released after REORDER_HOLD.
+        assert_eq!(link.next_deadline(), Some(now + REORDER_HOLD));
+        assert_eq!(link.pop_due(now + REORDER_HOLD), Some(4));
+
+        let profile = LinkProfile {
+            drop: 1.,
+            ..Default::default()
+        };
+        let mut link = Link::new(profile, 0);
+        link.push(now, 1, true);
+        link.push(now, 2, false);
+        assert_eq!(link.pop_due(now), Some(2));
+        assert_eq!(link.dropped(), 1);
+    }
+}
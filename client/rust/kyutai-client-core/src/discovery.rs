@@ -0,0 +1,229 @@
+//! LAN discovery of moshi-server instances advertised over mDNS (`_moshi._tcp`).
+
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::time::Duration;
+
+pub const SERVICE_TYPE: &str = "_moshi._tcp.local.";
+pub const MODULE_KEY_PREFIX: &str = "m.";
+
+/// Module kinds that serve speech-to-text streams.
+pub const STT_KINDS: &[&str] = &["batched_asr", "asr"];
+/// Module kinds that serve text-to-speech streams.
+pub const TTS_KINDS: &[&str] = &["tts"];
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DiscoveredModule {
+    pub name: String,
+    pub kind: String,
+    pub path: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DiscoveredServer {
+    /// Service instance name (the part before `._moshi._tcp.local.`).
+    pub name: String,
+    pub host: String,
+    pub addrs: Vec<IpAddr>,
+    pub port: u16,
+    pub version: Option<String>,
+    pub instance: Option<String>,
+    pub modules: Vec<DiscoveredModule>,
+}
+
+impl DiscoveredServer {
+    /// Build a server entry from the resolved service name and its TXT properties.
+    pub fn from_parts<'a>(
+        fullname: &str,
+        host: &str,
+        mut addrs: Vec<IpAddr>,
+        port: u16,
+        props: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Self {
+        let name = fullname
+            .strip_suffix(SERVICE_TYPE)
+            .map(|s| s.trim_end_matches('.'))
+            .unwrap_or(fullname)
+            .to_string();
+        let mut version = None;
+        let mut instance = None;
+        let mut modules = Vec::new();
+        for (key, value) in props {
+            match key {
+                "version" => version = Some(value.to_string()),
+                "instance" => instance = Some(value.to_string()),
+                _ => {
+                    if let Some(module_name) = key.strip_prefix(MODULE_KEY_PREFIX)
+                        && let Some((kind, path)) = value.split_once(':')
+                    {
+                        modules.push(DiscoveredModule {
+                            name: module_name.to_string(),
+                            kind: kind.to_string(),
+                            path: path.to_string(),
+                        });
+                    }
+                }
+            }
+        }
+        modules.sort_by(|a, b| a.name.cmp(&b.name));
+        // Prefer IPv4 addresses, link-local IPv6 addresses need a scope id to be usable.
+        addrs.sort_by_key(|a| (a.is_ipv6(), *a));
+        Self {
+            name,
+            host: host.trim_end_matches('.').to_string(),
+            addrs,
+            port,
+            version,
+            instance,
+            modules,
+        }
+    }
+
+    /// Whether `name` designates this server (service name, instance name or host name).
+    pub fn matches(&self, name: &str) -> bool {
+        let host = self.host.trim_end_matches(".local");
+        self.name.eq_ignore_ascii_case(name)
+            || self
+                .instance
+                .as_deref()
+                .is_some_and(|i| i.eq_ignore_ascii_case(name))
+            || self.host.eq_ignore_ascii_case(name)
+            || host.eq_ignore_ascii_case(name)
+    }
+
+    /// The `ws://` base URL for this server, using the first advertised address.
+    pub fn base_url(&self) -> Option<String> {
+        let addr = self.addrs.first()?;
+        Some(match addr {
+            IpAddr::V4(ip) => format!("ws://{ip}:{}", self.port),
+            IpAddr::V6(ip) => format!("ws://[{ip}]:{}", self.port),
+        })
+    }
+
+    /// The full WebSocket URL for the first module matching one of `kinds`.
+    pub fn module_url(&self, kinds: &[&str]) -> Option<String> {
+        let base = self.base_url()?;
+        kinds.iter().find_map(|kind| {
+            self.modules
+                .iter()
+                .find(|m| m.kind == *kind)
+                .map(|m| format!("{base}{}", m.path))
+        })
+    }
+}
+
+fn resolved_server(info: &mdns_sd::ServiceInfo) -> DiscoveredServer {
+    DiscoveredServer::from_parts(
+        info.get_fullname(),
+        info.get_hostname(),
+        info.get_addresses().iter().copied().collect(),
+        info.get_port(),
+        info.get_properties().iter().map(|p| (p.key(), p.val_str())),
+    )
+}
+
+async fn browse(
+    timeout: Duration,
+    mut stop: impl FnMut(&DiscoveredServer) -> bool,
+) -> Result<Vec<DiscoveredServer>> {
+    let daemon = mdns_sd::ServiceDaemon::new()?;
+    let receiver = daemon.browse(SERVICE_TYPE)?;
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut servers: Vec<DiscoveredServer> = Vec::new();
+    loop {
+        let event = match tokio::time::timeout_at(deadline, receiver.recv_async()).await {
+            Ok(Ok(event)) => event,
+            Ok(Err(_)) | Err(_) => break,
+        };
+        match event {
+            mdns_sd::ServiceEvent::ServiceResolved(info) => {
+                let server = resolved_server(&info);
+                let done = stop(&server);
+                servers.retain(|s| s.name != server.name);
+                servers.push(server);
+                if done {
+                    break;
+                }
+            }
+            mdns_sd::ServiceEvent::ServiceRemoved(_, fullname) => {
+                servers.retain(|s| !fullname.starts_with(&format!("{}.", s.name)));
+            }
+            _ => {}
+        }
+    }
+    let _ = daemon.stop_browse(SERVICE_TYPE);
+    let _ = daemon.shutdown();
+    servers.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(servers)
+}
+
+/// Browse the local network for `timeout` and return every server that answered.
+pub async fn discover(timeout: Duration) -> Result<Vec<DiscoveredServer>> {
+    browse(timeout, |_| false).await
+}
+
+/// Browse until a server matching `name` is found or `timeout` elapses.
+pub async fn find_server(name: &str, timeout: Duration) -> Result<DiscoveredServer> {
+    browse(timeout, |s| s.matches(name))
+        .await?
+        .into_iter()
+        .find(|s| s.matches(name))
+        .ok_or_else(|| anyhow!("no moshi-server named '{name}' found on the local network"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> DiscoveredServer {
+        DiscoveredServer::from_parts(
+            "lab-gpu._moshi._tcp.local.",
+            "lab-gpu.local.",
+            vec!["fe80::1".parse().unwrap(), "192.168.1.20".parse().unwrap()],
+            8080,
+            [
+                ("version", "v0.6.4"),
+                ("instance", "config-stt-en-hf"),
+                ("m.tts", "tts:/api/tts_streaming"),
+                ("m.asr", "batched_asr:/api/asr-streaming"),
+                ("unrelated", "x"),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_from_parts() {
+        let s = server();
+        assert_eq!(s.name, "lab-gpu");
+        assert_eq!(s.host, "lab-gpu.local");
+        assert_eq!(s.version.as_deref(), Some("v0.6.4"));
+        assert_eq!(s.modules.len(), 2);
+        assert_eq!(s.modules[0].name, "asr");
+        assert_eq!(s.modules[0].kind, "batched_asr");
+    }
+
+    #[test]
+    fn test_module_url_prefers_ipv4() {
+        let s = server();
+        assert_eq!(
+            s.module_url(STT_KINDS).as_deref(),
+            Some("ws://192.168.1.20:8080/api/asr-streaming")
+        );
+        assert_eq!(
+            s.module_url(TTS_KINDS).as_deref(),
+            Some("ws://192.168.1.20:8080/api/tts_streaming")
+        );
+        assert!(s.module_url(&["lm"]).is_none());
+    }
+
+    #[test]
+    fn test_matches() {
+        let s = server();
+        assert!(s.matches("lab-gpu"));
+        assert!(s.matches("LAB-GPU"));
+        assert!(s.matches("config-stt-en-hf"));
+        assert!(s.matches("lab-gpu.local"));
+        assert!(!s.matches("other"));
+    }
+}
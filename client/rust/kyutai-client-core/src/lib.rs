@@ -1,5 +1,14 @@
+#[cfg(feature = "audio")]
+pub mod audio;
 pub mod auth;
+pub mod capabilities;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+#[cfg(feature = "discovery")]
+pub mod discovery;
+#[cfg(feature = "pipewire")]
+pub mod pipewire;
+#[cfg(all(windows, feature = "wasapi-exclusive"))]
+pub mod wasapi;
 #[cfg(feature = "ws")]
 pub mod ws;
-#[cfg(feature = "audio")]
-pub mod audio;
@@ -0,0 +1,456 @@
+//! Native PipeWire playback and capture streams, an alternative to cpal going through the ALSA
+//! plugin of PipeWire and to the `pacat` subprocess.
+//!
+//! libpipewire-0.3 (0.3.50 or later) is loaded at runtime rather than linked, so building needs
+//! no PipeWire headers and the binaries still start on machines without it. Streams carry f32
+//! samples at the rate and channel count asked for, PipeWire converts them to and from the
+//! format of the device.
+
+use anyhow::{Context, Result};
+use std::ffi::{CString, c_char, c_int, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+
+const LIBRARY: &str = "libpipewire-0.3.so.0";
+
+const SPA_DIRECTION_INPUT: u32 = 0;
+const SPA_DIRECTION_OUTPUT: u32 = 1;
+const PW_ID_ANY: u32 = u32::MAX;
+const PW_STREAM_FLAG_AUTOCONNECT: u32 = 1 << 0;
+const PW_STREAM_FLAG_MAP_BUFFERS: u32 = 1 << 2;
+const PW_STREAM_FLAG_RT_PROCESS: u32 = 1 << 4;
+
+// spa/utils/type.h, spa/param/param.h, spa/param/format.h and spa/param/audio/raw.h.
+const SPA_TYPE_ID: u32 = 3;
+const SPA_TYPE_INT: u32 = 4;
+const SPA_TYPE_OBJECT: u32 = 15;
+const SPA_TYPE_OBJECT_FORMAT: u32 = 0x40003;
+const SPA_PARAM_ENUM_FORMAT: u32 = 3;
+const SPA_FORMAT_MEDIA_TYPE: u32 = 1;
+const SPA_FORMAT_MEDIA_SUBTYPE: u32 = 2;
+const SPA_FORMAT_AUDIO_FORMAT: u32 = 0x10001;
+const SPA_FORMAT_AUDIO_RATE: u32 = 0x10003;
+const SPA_FORMAT_AUDIO_CHANNELS: u32 = 0x10004;
+const SPA_MEDIA_TYPE_AUDIO: u32 = 1;
+const SPA_MEDIA_SUBTYPE_RAW: u32 = 1;
+#[cfg(target_endian = "little")]
+const SPA_AUDIO_FORMAT_F32: u32 = 0x11b;
+#[cfg(target_endian = "big")]
+const SPA_AUDIO_FORMAT_F32: u32 = 0x11c;
+
+#[repr(C)]
+struct SpaChunk {
+    offset: u32,
+    size: u32,
+    stride: i32,
+    flags: i32,
+}
+
+#[repr(C)]
+struct SpaData {
+    type_: u32,
+    flags: u32,
+    fd: i64,
+    mapoffset: u32,
+    maxsize: u32,
+    data: *mut c_void,
+    chunk: *mut SpaChunk,
+}
+
+#[repr(C)]
+struct SpaBuffer {
+    n_metas: u32,
+    n_datas: u32,
+    metas: *mut c_void,
+    datas: *mut SpaData,
+}
+
+#[repr(C)]
+struct PwBuffer {
+    buffer: *mut SpaBuffer,
+    user_data: *mut c_void,
+    size: u64,
+    /// Frames the graph asks for, 0 when unknown.
+    requested: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct PwTime {
+    now: i64,
+    rate_num: u32,
+    rate_denom: u32,
+    ticks: u64,
+    /// Delay to the device, in `rate` units.
+    delay: i64,
+    queued: u64,
+    /// Frames held by the stream converters.
+    buffered: u64,
+    queued_buffers: u32,
+    avail_buffers: u32,
+}
+
+/// `struct pw_stream_events` up to `drained`, i.e. version 0.
+#[repr(C)]
+struct PwStreamEvents {
+    version: u32,
+    /// `destroy` to `remove_buffer`, unused.
+    unused: [*const c_void; 7],
+    process: Option<unsafe extern "C" fn(*mut c_void)>,
+    drained: *const c_void,
+}
+
+/// The functions of libpipewire used here.
+struct Api {
+    thread_loop_new: unsafe extern "C" fn(*const c_char, *const c_void) -> *mut c_void,
+    thread_loop_get_loop: unsafe extern "C" fn(*mut c_void) -> *mut c_void,
+    thread_loop_start: unsafe extern "C" fn(*mut c_void) -> c_int,
+    thread_loop_stop: unsafe extern "C" fn(*mut c_void),
+    thread_loop_destroy: unsafe extern "C" fn(*mut c_void),
+    thread_loop_lock: unsafe extern "C" fn(*mut c_void),
+    thread_loop_unlock: unsafe extern "C" fn(*mut c_void),
+    properties_new: unsafe extern "C" fn(*const c_char, ...) -> *mut c_void,
+    properties_set: unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char) -> c_int,
+    stream_new_simple: unsafe extern "C" fn(
+        *mut c_void,
+        *const c_char,
+        *mut c_void,
+        *const PwStreamEvents,
+        *mut c_void,
+    ) -> *mut c_void,
+    stream_connect:
+        unsafe extern "C" fn(*mut c_void, u32, u32, u32, *mut *const c_void, u32) -> c_int,
+    stream_destroy: unsafe extern "C" fn(*mut c_void),
+    stream_dequeue_buffer: unsafe extern "C" fn(*mut c_void) -> *mut PwBuffer,
+    stream_queue_buffer: unsafe extern "C" fn(*mut c_void, *mut PwBuffer) -> c_int,
+    stream_get_time_n: unsafe extern "C" fn(*mut c_void, *mut PwTime, usize) -> c_int,
+    // Unloading libpipewire is not supported, the library stays loaded with the api.
+    _lib: libloading::Library,
+}
+
+impl Api {
+    fn load() -> Result<Self> {
+        unsafe {
+            let lib = libloading::Library::new(LIBRARY)
+                .with_context(|| format!("cannot load {LIBRARY}, is PipeWire installed?"))?;
+            let init: unsafe extern "C" fn(*mut c_int, *mut *mut *mut c_char) =
+                *lib.get(b"pw_init\0")?;
+            init(ptr::null_mut(), ptr::null_mut());
+            Ok(Self {
+                thread_loop_new: *lib.get(b"pw_thread_loop_new\0")?,
+                thread_loop_get_loop: *lib.get(b"pw_thread_loop_get_loop\0")?,
+                thread_loop_start: *lib.get(b"pw_thread_loop_start\0")?,
+                thread_loop_stop: *lib.get(b"pw_thread_loop_stop\0")?,
+                thread_loop_destroy: *lib.get(b"pw_thread_loop_destroy\0")?,
+                thread_loop_lock: *lib.get(b"pw_thread_loop_lock\0")?,
+                thread_loop_unlock: *lib.get(b"pw_thread_loop_unlock\0")?,
+                properties_new: *lib.get(b"pw_properties_new\0")?,
+                properties_set: *lib.get(b"pw_properties_set\0")?,
+                stream_new_simple: *lib.get(b"pw_stream_new_simple\0")?,
+                stream_connect: *lib.get(b"pw_stream_connect\0")?,
+                stream_destroy: *lib.get(b"pw_stream_destroy\0")?,
+                stream_dequeue_buffer: *lib.get(b"pw_stream_dequeue_buffer\0")?,
+                stream_queue_buffer: *lib.get(b"pw_stream_queue_buffer\0")?,
+                stream_get_time_n: *lib.get(b"pw_stream_get_time_n\0")?,
+                _lib: lib,
+            })
+        }
+    }
+
+    fn get() -> Result<&'static Self> {
+        static API: OnceLock<std::result::Result<Api, String>> = OnceLock::new();
+        API.get_or_init(|| Api::load().map_err(|e| format!("{e:#}")))
+            .as_ref()
+            .map_err(|e| anyhow::anyhow!("{e}"))
+    }
+}
+
+/// Whether libpipewire can be loaded on this machine.
+pub fn is_available() -> bool {
+    Api::get().is_ok()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Playback,
+    /// Record from an input device.
+    Capture,
+    /// Record what an output device plays, the default sink unless a target is set.
+    Monitor,
+}
+
+#[derive(Clone, Debug)]
+pub struct PwStreamConfig {
+    /// Application name shown by `pw-top` and the mixers.
+    pub name: String,
+    pub direction: Direction,
+    pub sample_rate: u32,
+    pub channels: u32,
+    /// Frames per period asked of the graph, `node.latency`, the graph default when unset.
+    pub latency_frames: Option<u32>,
+    /// Node name or serial to connect to, the default device when unset.
+    pub target: Option<String>,
+}
+
+type Callback = Box<dyn FnMut(&mut [f32]) + Send>;
+
+/// State shared with the process callback, which runs on the realtime thread of PipeWire.
+struct Process {
+    api: &'static Api,
+    stream: *mut c_void,
+    channels: usize,
+    sample_rate: u32,
+    capture: bool,
+    latency_us: Arc<AtomicU64>,
+    callback: Callback,
+}
+
+unsafe extern "C" fn on_process(data: *mut c_void) {
+    let state = unsafe { &mut *(data as *mut Process) };
+    let api = state.api;
+    let buffer = unsafe { (api.stream_dequeue_buffer)(state.stream) };
+    if buffer.is_null() {
+        return;
+    }
+    let stride = size_of::<f32>() * state.channels;
+    unsafe {
+        let spa = &*(*buffer).buffer;
+        if spa.n_datas > 0 && !(*spa.datas).data.is_null() {
+            let d = &mut *spa.datas;
+            let chunk = &mut *d.chunk;
+            if state.capture {
+                let offset = chunk.offset.min(d.maxsize);
+                let size = chunk.size.min(d.maxsize - offset) as usize;
+                let data = d.data.byte_add(offset as usize) as *mut f32;
+                (state.callback)(std::slice::from_raw_parts_mut(
+                    data,
+                    size / size_of::<f32>(),
+                ));
+            } else {
+                let mut frames = d.maxsize as usize / stride;
+                if (*buffer).requested > 0 {
+                    frames = frames.min((*buffer).requested as usize);
+                }
+                let data = d.data as *mut f32;
+                (state.callback)(std::slice::from_raw_parts_mut(
+                    data,
+                    frames * state.channels,
+                ));
+                chunk.offset = 0;
+                chunk.stride = stride as i32;
+                chunk.size = (frames * stride) as u32;
+            }
+        }
+        (api.stream_queue_buffer)(state.stream, buffer);
+
+        let mut time = PwTime::default();
+        let res = (api.stream_get_time_n)(state.stream, &mut time, size_of::<PwTime>());
+        if res == 0 && time.rate_denom > 0 {
+            let delay_us = time.delay.max(0) as u64 * 1_000_000 * time.rate_num as u64
+                / time.rate_denom as u64;
+            let buffered_us = time.buffered * 1_000_000 / state.sample_rate as u64;
+            state
+                .latency_us
+                .store(delay_us + buffered_us, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A connected PipeWire stream, disconnected on drop.
+pub struct PwStream {
+    api: &'static Api,
+    thread_loop: *mut c_void,
+    stream: *mut c_void,
+    _events: Box<PwStreamEvents>,
+    _process: Box<Process>,
+}
+
+// The stream is only touched from its thread loop, and under the loop lock on drop.
+unsafe impl Send for PwStream {}
+
+impl PwStream {
+    /// Connect a stream, `callback` fills the interleaved playback buffers or reads the
+    /// captured ones. `latency_us` is updated with the delay to the device after each period.
+    pub fn new(
+        cfg: &PwStreamConfig,
+        latency_us: Arc<AtomicU64>,
+        callback: impl FnMut(&mut [f32]) + Send + 'static,
+    ) -> Result<Self> {
+        let api = Api::get()?;
+        let capture = cfg.direction != Direction::Playback;
+        let category = if capture { "Capture" } else { "Playback" };
+        let mut props = vec![
+            ("media.type", "Audio".to_string()),
+            ("media.category", category.to_string()),
+            (
+                "media.role",
+                if capture {
+                    "Communication"
+                } else {
+                    "Assistant"
+                }
+                .to_string(),
+            ),
+        ];
+        if let Some(frames) = cfg.latency_frames {
+            props.push(("node.latency", format!("{frames}/{}", cfg.sample_rate)));
+        }
+        if cfg.direction == Direction::Monitor {
+            props.push(("stream.capture.sink", "true".to_string()));
+        }
+        if let Some(target) = cfg.target.as_ref() {
+            props.push(("target.object", target.clone()));
+        }
+        let name = CString::new(cfg.name.as_str())?;
+        let props = props
+            .into_iter()
+            .map(|(key, value)| Ok((CString::new(key)?, CString::new(value)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let events = Box::new(PwStreamEvents {
+            version: 0,
+            unused: [ptr::null(); 7],
+            process: Some(on_process),
+            drained: ptr::null(),
+        });
+        let mut process = Box::new(Process {
+            api,
+            stream: ptr::null_mut(),
+            channels: cfg.channels as usize,
+            sample_rate: cfg.sample_rate,
+            capture,
+            latency_us,
+            callback: Box::new(callback),
+        });
+        let format = format_pod(cfg.sample_rate, cfg.channels);
+
+        unsafe {
+            let thread_loop = (api.thread_loop_new)(name.as_ptr(), ptr::null());
+            if thread_loop.is_null() {
+                anyhow::bail!("cannot create the pipewire thread loop")
+            }
+            // Owned by the stream, even when it cannot be created.
+            let properties = (api.properties_new)(ptr::null::<c_char>());
+            for (key, value) in props.iter() {
+                (api.properties_set)(properties, key.as_ptr(), value.as_ptr());
+            }
+            (api.thread_loop_lock)(thread_loop);
+            let process_ptr: *mut Process = &mut *process;
+            let stream = (api.stream_new_simple)(
+                (api.thread_loop_get_loop)(thread_loop),
+                name.as_ptr(),
+                properties,
+                &*events,
+                process_ptr as *mut c_void,
+            );
+            if stream.is_null() {
+                (api.thread_loop_unlock)(thread_loop);
+                (api.thread_loop_destroy)(thread_loop);
+                anyhow::bail!("cannot create the pipewire stream")
+            }
+            // The stream is not connected yet, the callback cannot run.
+            (*process_ptr).stream = stream;
+            let this = Self {
+                api,
+                thread_loop,
+                stream,
+                _events: events,
+                _process: process,
+            };
+            let mut params = [format.as_ptr() as *const c_void];
+            let direction = if capture {
+                SPA_DIRECTION_INPUT
+            } else {
+                SPA_DIRECTION_OUTPUT
+            };
+            let flags =
+                PW_STREAM_FLAG_AUTOCONNECT | PW_STREAM_FLAG_MAP_BUFFERS | PW_STREAM_FLAG_RT_PROCESS;
+            let res =
+                (api.stream_connect)(stream, direction, PW_ID_ANY, flags, params.as_mut_ptr(), 1);
+            (api.thread_loop_unlock)(thread_loop);
+            if res < 0 {
+                anyhow::bail!("cannot connect the pipewire stream: error {res}")
+            }
+            if (api.thread_loop_start)(thread_loop) < 0 {
+                anyhow::bail!("cannot start the pipewire thread loop")
+            }
+            Ok(this)
+        }
+    }
+}
+
+impl Drop for PwStream {
+    fn drop(&mut self) {
+        unsafe {
+            (self.api.thread_loop_lock)(self.thread_loop);
+            (self.api.stream_destroy)(self.stream);
+            (self.api.thread_loop_unlock)(self.thread_loop);
+            (self.api.thread_loop_stop)(self.thread_loop);
+            (self.api.thread_loop_destroy)(self.thread_loop);
+        }
+    }
+}
+
+/// `SPA_PARAM_EnumFormat` object for interleaved f32 audio, as 8-byte aligned words.
+fn format_pod(sample_rate: u32, channels: u32) -> Vec<u64> {
+    let props = [
+        (SPA_FORMAT_MEDIA_TYPE, SPA_TYPE_ID, SPA_MEDIA_TYPE_AUDIO),
+        (SPA_FORMAT_MEDIA_SUBTYPE, SPA_TYPE_ID, SPA_MEDIA_SUBTYPE_RAW),
+        (SPA_FORMAT_AUDIO_FORMAT, SPA_TYPE_ID, SPA_AUDIO_FORMAT_F32),
+        (SPA_FORMAT_AUDIO_RATE, SPA_TYPE_INT, sample_rate),
+        (SPA_FORMAT_AUDIO_CHANNELS, SPA_TYPE_INT, channels),
+    ];
+    // Pod header (body size, type) then object type and id.
+    let mut words = vec![
+        0,
+        SPA_TYPE_OBJECT,
+        SPA_TYPE_OBJECT_FORMAT,
+        SPA_PARAM_ENUM_FORMAT,
+    ];
+    for (key, pod_type, value) in props {
+        // Key, flags, then a 4 bytes pod padded to 8 bytes.
+        words.extend([key, 0, 4, pod_type, value, 0]);
+    }
+    words[0] = ((words.len() - 2) * 4) as u32;
+    words
+        .chunks_exact(2)
+        .map(|w| {
+            let mut bytes = [0u8; 8];
+            bytes[..4].copy_from_slice(&w[0].to_ne_bytes());
+            bytes[4..].copy_from_slice(&w[1].to_ne_bytes());
+            u64::from_ne_bytes(bytes)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_pod_layout() {
+        let pod = format_pod(24000, 1);
+        let bytes: Vec<u8> = pod.iter().flat_map(|w| w.to_ne_bytes()).collect();
+        let words: Vec<u32> = bytes
+            .chunks_exact(4)
+            .map(|b| u32::from_ne_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(words.len(), 4 + 5 * 6);
+        assert_eq!(
+            words[..4],
+            [128, SPA_TYPE_OBJECT, SPA_TYPE_OBJECT_FORMAT, 3]
+        );
+        assert_eq!(
+            words[4..10],
+            [SPA_FORMAT_MEDIA_TYPE, 0, 4, SPA_TYPE_ID, 1, 0]
+        );
+        assert_eq!(
+            words[28..],
+            [SPA_FORMAT_AUDIO_CHANNELS, 0, 4, SPA_TYPE_INT, 1, 0]
+        );
+        assert_eq!(
+            words[22..27],
+            [SPA_FORMAT_AUDIO_RATE, 0, 4, SPA_TYPE_INT, 24000]
+        );
+    }
+}
@@ -0,0 +1,292 @@
+//! WASAPI exclusive-mode playback on Windows. The stream owns the output device and skips the
+//! shared-mode mixer, so the only latency left is one device period, 3ms on most devices
+//! instead of the 10ms period and mixer buffer of shared mode.
+//!
+//! Exclusive mode plays the device format as is: the stream keeps the rate and channel count
+//! of the device mix format and picks the first of f32, i32 and i16 samples that the device
+//! takes. Other applications cannot play on the device while the stream is open.
+
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use windows::Win32::Foundation::{CloseHandle, HANDLE, S_OK, WAIT_OBJECT_0};
+use windows::Win32::Media::Audio::{
+    AUDCLNT_BUFFERFLAGS_SILENT, AUDCLNT_E_BUFFER_SIZE_NOT_ALIGNED, AUDCLNT_SHAREMODE_EXCLUSIVE,
+    AUDCLNT_STREAMFLAGS_EVENTCALLBACK, IAudioClient, IAudioRenderClient, IMMDeviceEnumerator,
+    MMDeviceEnumerator, WAVEFORMATEX, WAVEFORMATEXTENSIBLE, WAVEFORMATEXTENSIBLE_0, eConsole,
+    eRender,
+};
+use windows::Win32::Media::KernelStreaming::{
+    KSAUDIO_SPEAKER_DIRECTOUT, KSDATAFORMAT_SUBTYPE_PCM, WAVE_FORMAT_EXTENSIBLE,
+};
+use windows::Win32::Media::Multimedia::KSDATAFORMAT_SUBTYPE_IEEE_FLOAT;
+use windows::Win32::System::Com::{
+    CLSCTX_ALL, COINIT_MULTITHREADED, CoCreateInstance, CoInitializeEx, CoTaskMemFree,
+    CoUninitialize,
+};
+use windows::Win32::System::Threading::{
+    AvSetMmThreadCharacteristicsW, CreateEventA, WaitForSingleObject,
+};
+use windows::core::{PCSTR, w};
+
+/// Format of the device, the samples given to the fill callback are interleaved f32 in any
+/// case.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExclusiveFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Sample {
+    F32,
+    I32,
+    I16,
+}
+
+impl Sample {
+    fn bits(self) -> u16 {
+        match self {
+            Self::F32 | Self::I32 => 32,
+            Self::I16 => 16,
+        }
+    }
+
+    /// Write f32 samples to a device buffer with room for them.
+    unsafe fn write(self, samples: &[f32], out: *mut u8) {
+        unsafe {
+            match self {
+                Self::F32 => {
+                    std::ptr::copy_nonoverlapping(samples.as_ptr(), out as *mut f32, samples.len())
+                }
+                Self::I32 => {
+                    let out = std::slice::from_raw_parts_mut(out as *mut i32, samples.len());
+                    for (o, s) in out.iter_mut().zip(samples) {
+                        *o = (s.clamp(-1.0, 1.0) as f64 * i32::MAX as f64) as i32;
+                    }
+                }
+                Self::I16 => {
+                    let out = std::slice::from_raw_parts_mut(out as *mut i16, samples.len());
+                    for (o, s) in out.iter_mut().zip(samples) {
+                        *o = (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn wave_format(format: ExclusiveFormat, sample: Sample) -> WAVEFORMATEXTENSIBLE {
+    let bits = sample.bits();
+    let block_align = format.channels * bits / 8;
+    WAVEFORMATEXTENSIBLE {
+        Format: WAVEFORMATEX {
+            wFormatTag: WAVE_FORMAT_EXTENSIBLE as u16,
+            nChannels: format.channels,
+            nSamplesPerSec: format.sample_rate,
+            nAvgBytesPerSec: format.sample_rate * block_align as u32,
+            nBlockAlign: block_align,
+            wBitsPerSample: bits,
+            cbSize: (size_of::<WAVEFORMATEXTENSIBLE>() - size_of::<WAVEFORMATEX>()) as u16,
+        },
+        Samples: WAVEFORMATEXTENSIBLE_0 {
+            wValidBitsPerSample: bits,
+        },
+        dwChannelMask: KSAUDIO_SPEAKER_DIRECTOUT,
+        SubFormat: match sample {
+            Sample::F32 => KSDATAFORMAT_SUBTYPE_IEEE_FLOAT,
+            Sample::I32 | Sample::I16 => KSDATAFORMAT_SUBTYPE_PCM,
+        },
+    }
+}
+
+/// An initialized exclusive-mode client, owned by the render thread.
+struct Device {
+    client: IAudioClient,
+    render: IAudioRenderClient,
+    event: HANDLE,
+    format: ExclusiveFormat,
+    sample: Sample,
+    /// Frames written each period.
+    frames: u32,
+}
+
+impl Device {
+    unsafe fn open() -> Result<Self> {
+        unsafe {
+            let enumerator: IMMDeviceEnumerator =
+                CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
+            let device = enumerator
+                .GetDefaultAudioEndpoint(eRender, eConsole)
+                .context("no output device available")?;
+            let client: IAudioClient = device.Activate(CLSCTX_ALL, None)?;
+            let mix = client.GetMixFormat()?;
+            let format = ExclusiveFormat {
+                sample_rate: (*mix).nSamplesPerSec,
+                channels: (*mix).nChannels,
+            };
+            CoTaskMemFree(Some(mix as *const _));
+            let sample = [Sample::F32, Sample::I32, Sample::I16]
+                .into_iter()
+                .find(|s| {
+                    let wave = wave_format(format, *s);
+                    client.IsFormatSupported(AUDCLNT_SHAREMODE_EXCLUSIVE, &wave.Format, None)
+                        == S_OK
+                })
+                .with_context(|| format!("no exclusive mode format for {format:?}"))?;
+            let wave = wave_format(format, sample);
+
+            let mut period = 0i64;
+            client.GetDevicePeriod(None, Some(&mut period))?;
+            let client = match Self::initialize(&client, &wave, period) {
+                Err(err) if err.code() == AUDCLNT_E_BUFFER_SIZE_NOT_ALIGNED => {
+                    // Retry with a period that is a whole number of aligned frames, on a new
+                    // client as the failed one cannot be initialized again.
+                    let frames = client.GetBufferSize()? as i64;
+                    let period = (10_000_000 * frames + format.sample_rate as i64 / 2)
+                        / format.sample_rate as i64;
+                    let client: IAudioClient = device.Activate(CLSCTX_ALL, None)?;
+                    Self::initialize(&client, &wave, period)?;
+                    client
+                }
+                res => {
+                    res?;
+                    client
+                }
+            };
+            let event = CreateEventA(None, false, false, PCSTR::null())?;
+            client.SetEventHandle(event)?;
+            let render: IAudioRenderClient = client.GetService()?;
+            let frames = client.GetBufferSize()?;
+            Ok(Self {
+                client,
+                render,
+                event,
+                format,
+                sample,
+                frames,
+            })
+        }
+    }
+
+    unsafe fn initialize(
+        client: &IAudioClient,
+        wave: &WAVEFORMATEXTENSIBLE,
+        period: i64,
+    ) -> windows::core::Result<()> {
+        unsafe {
+            client.Initialize(
+                AUDCLNT_SHAREMODE_EXCLUSIVE,
+                AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
+                period,
+                period,
+                &wave.Format,
+                None,
+            )
+        }
+    }
+
+    /// Latency from the buffer to the speakers, in microseconds.
+    unsafe fn latency_us(&self) -> u64 {
+        let buffer_us = self.frames as u64 * 1_000_000 / self.format.sample_rate as u64;
+        let stream_us = unsafe { self.client.GetStreamLatency() }
+            .unwrap_or(0)
+            .max(0) as u64
+            / 10;
+        buffer_us + stream_us
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = self.client.Stop();
+            let _ = CloseHandle(self.event);
+        }
+    }
+}
+
+/// An exclusive-mode output stream, stopped on drop.
+pub struct ExclusiveStream {
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl ExclusiveStream {
+    /// Open the default output device, `fill` gets the interleaved f32 buffer of each period and
+    /// the number of channels. `latency_us` is set to the output latency once the stream runs.
+    pub fn new(
+        latency_us: Arc<AtomicU64>,
+        mut fill: impl FnMut(&mut [f32], usize) + Send + 'static,
+    ) -> Result<(Self, ExclusiveFormat)> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel::<Result<ExclusiveFormat>>();
+        let thread_stop = stop.clone();
+        let thread = std::thread::Builder::new()
+            .name("wasapi-exclusive".to_string())
+            .spawn(move || unsafe {
+                if let Err(err) = CoInitializeEx(None, COINIT_MULTITHREADED).ok() {
+                    let _ = ready_tx.send(Err(err.into()));
+                    return;
+                }
+                let mut task_index = 0u32;
+                let _ = AvSetMmThreadCharacteristicsW(w!("Pro Audio"), &mut task_index);
+                let res = Device::open().and_then(|device| {
+                    // Start on a silent buffer, as the device reads it right away.
+                    device.render.GetBuffer(device.frames)?;
+                    let silent = AUDCLNT_BUFFERFLAGS_SILENT.0 as u32;
+                    device.render.ReleaseBuffer(device.frames, silent)?;
+                    device.client.Start()?;
+                    Ok(device)
+                });
+                let device = match res {
+                    Ok(device) => device,
+                    Err(err) => {
+                        let _ = ready_tx.send(Err(err));
+                        CoUninitialize();
+                        return;
+                    }
+                };
+                let _ = ready_tx.send(Ok(device.format));
+                latency_us.store(device.latency_us(), Ordering::Relaxed);
+                let channels = device.format.channels as usize;
+                let mut samples = vec![0f32; device.frames as usize * channels];
+                while !thread_stop.load(Ordering::Acquire) {
+                    if WaitForSingleObject(device.event, 2000) != WAIT_OBJECT_0 {
+                        eprintln!("wasapi exclusive: the device stopped requesting audio");
+                        break;
+                    }
+                    let Ok(buffer) = device.render.GetBuffer(device.frames) else {
+                        break;
+                    };
+                    samples.fill(0.0);
+                    fill(&mut samples, channels);
+                    device.sample.write(&samples, buffer);
+                    if device.render.ReleaseBuffer(device.frames, 0).is_err() {
+                        break;
+                    }
+                }
+                drop(device);
+                CoUninitialize();
+            })?;
+        let format = ready_rx
+            .recv()
+            .context("wasapi exclusive thread exited")??;
+        Ok((
+            Self {
+                stop,
+                thread: Some(thread),
+            },
+            format,
+        ))
+    }
+}
+
+impl Drop for ExclusiveStream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
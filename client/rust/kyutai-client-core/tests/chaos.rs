@@ -0,0 +1,228 @@
+//! Runs of the fixtures of `tests/fixtures/chaos`, each describing a slow or lossy client.
+//!
+//! The loopback tests check the faults against a local echo server. The server tests stream
+//! audio to a running moshi-server through each fixture and check that the session keeps
+//! answering within the bounds of the fixture; they are ignored by default, run them with
+//! `cargo test --test chaos -- --ignored` and `KYUTAI_CHAOS_URL` set, e.g. to
+//! `ws://localhost:8080`, with `KYUTAI_CHAOS_TOKEN` for servers requiring auth.
+
+use futures_util::{SinkExt, StreamExt};
+use kyutai_client_core::chaos::{self, ChaosProfile};
+use kyutai_client_core::ws::{build_ws_url, connect_ws};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::Message;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fixture {
+    profile: ChaosProfile,
+    /// Longest wait for the answer to a marker sent after the audio.
+    max_marker_delay_ms: u64,
+}
+
+fn fixtures() -> Vec<(String, Fixture)> {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/chaos");
+    let mut paths: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "toml"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no fixtures in {dir:?}");
+    paths
+        .into_iter()
+        .map(|p| {
+            let name = p.file_stem().unwrap().to_string_lossy().to_string();
+            let fixture = toml::from_str(&std::fs::read_to_string(&p).unwrap())
+                .unwrap_or_else(|e| panic!("invalid fixture {name}: {e}"));
+            (name, fixture)
+        })
+        .collect()
+}
+
+async fn echo_server() -> url::Url {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                while let Some(Ok(msg)) = ws.next().await {
+                    if msg.is_binary() && ws.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    url::Url::parse(&format!("ws://{addr}")).unwrap()
+}
+
+#[tokio::test]
+async fn loopback_fixtures() {
+    const MESSAGES: usize = 50;
+    const SIZE: usize = 16;
+
+    let url = echo_server().await;
+    for (name, fixture) in fixtures() {
+        let profile = &fixture.profile;
+        let ws = connect_ws(&url, None).await.unwrap();
+        let mut ws = chaos::wrap(ws, profile);
+        let start = Instant::now();
+        for idx in 0..MESSAGES {
+            let mut payload = vec![0u8; SIZE];
+            payload[0] = idx as u8;
+            ws.send(Message::binary(payload)).await.unwrap();
+        }
+
+        let wire = |bytes_per_s: u64| match bytes_per_s {
+            0 => 0.,
+            b => (MESSAGES * SIZE) as f64 / b as f64,
+        };
+        let max = profile.up.latency_ms + profile.up.jitter_ms;
+        let max = max + profile.down.latency_ms + profile.down.jitter_ms;
+        let wires = wire(profile.up.bytes_per_s) + wire(profile.down.bytes_per_s);
+        // Generous slack, loaded CI machines schedule the timers late.
+        let max = Duration::from_millis(max)
+            + 2 * chaos::REORDER_HOLD
+            + Duration::from_secs_f64(wires)
+            + Duration::from_secs(10);
+        let mut received = vec![];
+        while let Ok(Some(msg)) = tokio::time::timeout(max, ws.next()).await {
+            received.push(msg.unwrap().into_data()[0]);
+            let (up, down) = ws.dropped();
+            if received.len() as u64 + up + down == MESSAGES as u64 {
+                break;
+            }
+        }
+        let elapsed = start.elapsed();
+
+        let (up, down) = ws.dropped();
+        eprintln!(
+            "{name}: {} received, {up}+{down} dropped in {elapsed:?}",
+            received.len()
+        );
+        assert_eq!(
+            received.len() as u64 + up + down,
+            MESSAGES as u64,
+            "{name}: messages lost"
+        );
+        let mut unique = received.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), received.len(), "{name}: duplicated messages");
+        if profile.up.drop == 0. && profile.down.drop == 0. {
+            assert_eq!(received.len(), MESSAGES, "{name}: messages dropped");
+        }
+        if profile.up.reorder == 0. && profile.down.reorder == 0. {
+            assert!(received.is_sorted(), "{name}: messages reordered");
+        }
+        // The timers can also fire a bit early, only check most of the injected delay.
+        let min = Duration::from_millis(profile.up.latency_ms + profile.down.latency_ms);
+        let min = min.max(Duration::from_secs_f64(wire(profile.down.bytes_per_s))) * 8 / 10;
+        assert!(
+            elapsed >= min,
+            "{name}: {elapsed:?} faster than the link allows"
+        );
+        assert!(elapsed <= max, "{name}: {elapsed:?} slower than {max:?}");
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum InMsg {
+    Audio { pcm: Vec<f32> },
+    Marker { id: i64 },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum OutMsg {
+    Marker {
+        id: i64,
+    },
+    Error {
+        message: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+fn encode(msg: &InMsg) -> Message {
+    let mut buf = vec![];
+    msg.serialize(&mut rmp_serde::Serializer::new(&mut buf).with_struct_map())
+        .unwrap();
+    Message::binary(buf)
+}
+
+/// Only the audio frames are lost or reordered, the marker has to make it.
+fn is_audio(msg: &Message) -> bool {
+    #[derive(Deserialize)]
+    struct Tag {
+        #[serde(rename = "type")]
+        kind: String,
+    }
+    msg.is_binary()
+        && rmp_serde::from_slice::<Tag>(msg.clone().into_data().as_ref())
+            .is_ok_and(|t| t.kind == "Audio")
+}
+
+#[tokio::test]
+#[ignore = "needs a running moshi-server at KYUTAI_CHAOS_URL"]
+async fn server_fixtures() {
+    const FRAME: Duration = Duration::from_millis(80);
+    const AUDIO: Duration = Duration::from_secs(5);
+    const MARKER_ID: i64 = 4242;
+
+    let base = std::env::var("KYUTAI_CHAOS_URL").expect("KYUTAI_CHAOS_URL is not set");
+    let token = std::env::var("KYUTAI_CHAOS_TOKEN").ok();
+    let url = build_ws_url(&base, "/api/asr-streaming", &[], None).unwrap();
+    let silence = || {
+        encode(&InMsg::Audio {
+            pcm: vec![0.; 1920],
+        })
+    };
+    for (name, fixture) in fixtures() {
+        let ws = connect_ws(&url, token.as_deref()).await.unwrap();
+        let ws = chaos::wrap_with(ws, &fixture.profile, is_audio);
+        let (mut tx, mut rx) = ws.split();
+
+        let mut ticker = tokio::time::interval(FRAME);
+        let start = Instant::now();
+        let mut marker_sent = None;
+        let max = Duration::from_millis(fixture.max_marker_delay_ms);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    if marker_sent.is_none() && start.elapsed() >= AUDIO {
+                        tx.send(encode(&InMsg::Marker { id: MARKER_ID })).await.unwrap();
+                        marker_sent = Some(Instant::now());
+                    }
+                    if let Some(sent) = marker_sent {
+                        assert!(sent.elapsed() <= max, "{name}: no marker after {max:?}");
+                    }
+                    // Silence keeps flowing so that the marker goes through the model delay.
+                    tx.send(silence()).await.unwrap();
+                }
+                msg = rx.next() => {
+                    let msg = match msg {
+                        Some(Ok(msg)) => msg,
+                        other => panic!("{name}: connection ended with {other:?}"),
+                    };
+                    if !msg.is_binary() {
+                        continue;
+                    }
+                    match rmp_serde::from_slice::<OutMsg>(msg.into_data().as_ref()) {
+                        Ok(OutMsg::Marker { id: MARKER_ID }) => break,
+                        Ok(OutMsg::Error { message }) => panic!("{name}: server error {message}"),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        let delay = marker_sent.unwrap().elapsed();
+        eprintln!("{name}: marker answered after {delay:?}");
+        let _ = tx.close().await;
+    }
+}
@@ -0,0 +1,19 @@
+//! Decoding of the frames sent by the stt and tts servers.
+//!
+//! Run with `cargo +nightly fuzz run server_msgs`. Besides panics, the target fails when decoding
+//! a frame allocates much more than the frame size.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[global_allocator]
+static GLOBAL: fuzz_alloc::Tracking = fuzz_alloc::Tracking;
+
+fuzz_target!(|data: &[u8]| {
+    fuzz_alloc::check_alloc(data, |d| {
+        let _ = kyutai_client::stt::protocol::decode_out_msgs(d);
+    });
+    fuzz_alloc::check_alloc(data, |d| {
+        let _ = kyutai_client::tts::protocol::decode_in_msg(d);
+    });
+});
@@ -1,5 +1,5 @@
 #[cfg(feature = "stt")]
 pub mod stt;
 
-#[cfg(feature = "tts")]
+#[cfg(all(feature = "tts", not(target_arch = "wasm32")))]
 pub mod tts;
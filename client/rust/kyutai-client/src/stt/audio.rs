@@ -1,5 +1,5 @@
 pub use kyutai_client_core::audio::{
-    AudioChunk, ResampleQuality, AudioLevel, LevelMeter,
+    AudioChunk, AudioLevel, ClipDetector, Gain, LevelMeter, ResampleQuality,
 };
 
 #[cfg(feature = "mic")]
@@ -9,4 +9,4 @@ pub mod mic;
 pub use mic::MicCapture;
 
 #[cfg(feature = "mic")]
-pub use mic::MicCaptureConfig;
+pub use mic::{CaptureBackend, CaptureSource, MicCaptureConfig};
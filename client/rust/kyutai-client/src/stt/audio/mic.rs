@@ -1,26 +1,69 @@
 use crate::stt::audio::{AudioChunk, ResampleQuality};
 use crate::stt::error::{Result, SttError};
 use kyutai_client_core::audio::{
-    DynResampler, downmix_f32_to_mono_into, downmix_i16_to_mono_into, downmix_u16_to_mono_into,
+    DynResampler, Gain, downmix_f32_to_mono_into, downmix_i16_to_mono_into,
+    downmix_u16_to_mono_into,
 };
 
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{SampleFormat, StreamConfig};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use tokio::sync::mpsc;
 use tracing::warn;
 
 const OUTPUT_SAMPLE_RATE_HZ: u32 = 24_000;
 const OUTPUT_CHUNK_SAMPLES: usize = 1920;
 
-#[derive(Clone, Copy, Debug)]
+/// Where the audio is captured from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CaptureSource {
+    /// A microphone or another input device.
+    #[default]
+    Input,
+    /// The audio played by the machine: a PulseAudio/PipeWire monitor source on Linux, the
+    /// WASAPI loopback of an output device on Windows with the `wasapi-loopback` feature, or a
+    /// virtual loopback device such as BlackHole picked with `device` elsewhere.
+    Loopback,
+}
+
+/// Audio API the capture runs on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CaptureBackend {
+    /// The default host of cpal: ALSA on Linux, WASAPI on Windows, CoreAudio on macOS.
+    #[default]
+    Cpal,
+    /// A native PipeWire stream, which records at 24kHz mono with the resampling done by the
+    /// graph and connects to monitors without the `PULSE_SOURCE` workaround. `device` is a
+    /// PipeWire node name or serial.
+    #[cfg(feature = "pipewire")]
+    PipeWire,
+}
+
+#[derive(Clone, Debug)]
 pub struct MicCaptureConfig {
     pub resample_quality: ResampleQuality,
+    /// Input device name, the host default input device is used when unset.
+    pub device: Option<String>,
+    pub source: CaptureSource,
+    /// Software gain applied to the captured audio, in dB, the starting gain with
+    /// `auto_gain`. Samples beyond full scale are clipped.
+    pub gain_db: f32,
+    /// Adjust the gain to bring speech to a steady level, for quiet laptop microphones.
+    pub auto_gain: bool,
+    pub backend: CaptureBackend,
 }
 
 impl Default for MicCaptureConfig {
     fn default() -> Self {
         Self {
-            resample_quality: ResampleQuality::Linear,
+            resample_quality: ResampleQuality::Polyphase,
+            device: None,
+            source: CaptureSource::Input,
+            gain_db: 0.0,
+            auto_gain: false,
+            backend: CaptureBackend::Cpal,
         }
     }
 }
@@ -29,7 +72,10 @@ pub struct MicCapture {
     sample_rate_hz: u32,
     channels: u16,
     rx: mpsc::Receiver<AudioChunk>,
-    _stream: cpal::Stream,
+    latency_us: Arc<AtomicU64>,
+    _stream: Option<cpal::Stream>,
+    #[cfg(feature = "pipewire")]
+    _pipewire: Option<kyutai_client_core::pipewire::PwStream>,
 }
 
 impl MicCapture {
@@ -38,21 +84,42 @@ impl MicCapture {
     }
 
     pub fn start_default_with_config(config: MicCaptureConfig) -> Result<Self> {
-        let host = cpal::default_host();
-        let device = host
-            .default_input_device()
-            .ok_or_else(|| SttError::Message("no default input device available".to_string()))?;
+        match config.backend {
+            CaptureBackend::Cpal => Self::start_cpal(config),
+            #[cfg(feature = "pipewire")]
+            CaptureBackend::PipeWire => Self::start_pipewire(config),
+        }
+    }
 
-        let input_config = device
-            .default_input_config()
-            .map_err(|e| SttError::Message(e.to_string()))?;
+    fn start_cpal(config: MicCaptureConfig) -> Result<Self> {
+        let host = cpal::default_host();
+        let (device, input_config) = match config.source {
+            CaptureSource::Input => {
+                let device = match config.device.as_deref() {
+                    Some(name) => find_input_device(&host, |n| n == name)?.ok_or_else(|| {
+                        SttError::Message(format!("input device '{name}' not found"))
+                    })?,
+                    None => host.default_input_device().ok_or_else(|| {
+                        SttError::Message("no default input device available".to_string())
+                    })?,
+                };
+                let input_config = device
+                    .default_input_config()
+                    .map_err(|e| SttError::Message(e.to_string()))?;
+                (device, input_config)
+            }
+            CaptureSource::Loopback => loopback_device(&host, config.device.as_deref())?,
+        };
 
         let input_sample_rate_hz = input_config.sample_rate().0;
         let input_channels = input_config.channels();
         let stream_config: StreamConfig = input_config.clone().into();
         let resample_quality = config.resample_quality;
+        let gain = Gain::new(config.gain_db, config.auto_gain);
 
         let (tx, rx) = mpsc::channel::<AudioChunk>(8);
+        let latency_us = Arc::new(AtomicU64::new(0));
+        let chunker = Chunker::new(tx, gain);
 
         let stream = match input_config.sample_format() {
             SampleFormat::F32 => build_stream_f32(
@@ -60,24 +127,27 @@ impl MicCapture {
                 &stream_config,
                 input_channels,
                 input_sample_rate_hz,
-                tx.clone(),
+                chunker,
                 resample_quality,
+                latency_us.clone(),
             )?,
             SampleFormat::I16 => build_stream_i16(
                 &device,
                 &stream_config,
                 input_channels,
                 input_sample_rate_hz,
-                tx.clone(),
+                chunker,
                 resample_quality,
+                latency_us.clone(),
             )?,
             SampleFormat::U16 => build_stream_u16(
                 &device,
                 &stream_config,
                 input_channels,
                 input_sample_rate_hz,
-                tx,
+                chunker,
                 resample_quality,
+                latency_us.clone(),
             )?,
             other => {
                 return Err(SttError::Message(format!(
@@ -94,7 +164,43 @@ impl MicCapture {
             sample_rate_hz: OUTPUT_SAMPLE_RATE_HZ,
             channels: 1,
             rx,
-            _stream: stream,
+            latency_us,
+            _stream: Some(stream),
+            #[cfg(feature = "pipewire")]
+            _pipewire: None,
+        })
+    }
+
+    #[cfg(feature = "pipewire")]
+    fn start_pipewire(config: MicCaptureConfig) -> Result<Self> {
+        use kyutai_client_core::pipewire::{Direction, PwStream, PwStreamConfig};
+
+        let direction = match config.source {
+            CaptureSource::Input => Direction::Capture,
+            CaptureSource::Loopback => Direction::Monitor,
+        };
+        let stream_config = PwStreamConfig {
+            name: "kyutai-stt".to_string(),
+            direction,
+            sample_rate: OUTPUT_SAMPLE_RATE_HZ,
+            channels: 1,
+            latency_frames: None,
+            target: config.device.clone(),
+        };
+        let (tx, rx) = mpsc::channel::<AudioChunk>(8);
+        let latency_us = Arc::new(AtomicU64::new(0));
+        let mut chunker = Chunker::new(tx, Gain::new(config.gain_db, config.auto_gain));
+        let stream = PwStream::new(&stream_config, latency_us.clone(), move |data| {
+            chunker.push(data)
+        })
+        .map_err(|e| SttError::Message(format!("{e:#}")))?;
+        Ok(Self {
+            sample_rate_hz: OUTPUT_SAMPLE_RATE_HZ,
+            channels: 1,
+            rx,
+            latency_us,
+            _stream: None,
+            _pipewire: Some(stream),
         })
     }
 
@@ -106,18 +212,152 @@ impl MicCapture {
         self.channels
     }
 
+    /// Delay from the capture by the device to the callback, as last reported by the backend,
+    /// `None` until audio came in or when the backend does not report it.
+    pub fn latency(&self) -> Option<Duration> {
+        match self.latency_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(Duration::from_micros(us)),
+        }
+    }
+
     pub async fn recv(&mut self) -> Option<AudioChunk> {
         self.rx.recv().await
     }
 }
 
+fn find_input_device(
+    host: &cpal::Host,
+    matches: impl Fn(&str) -> bool,
+) -> Result<Option<cpal::Device>> {
+    let mut devices = host
+        .input_devices()
+        .map_err(|e| SttError::Message(e.to_string()))?;
+    Ok(devices.find(|d| d.name().is_ok_and(|n| matches(&n))))
+}
+
+/// Device and stream config capturing what the machine plays, see [`CaptureSource::Loopback`].
+fn loopback_device(
+    host: &cpal::Host,
+    name: Option<&str>,
+) -> Result<(cpal::Device, cpal::SupportedStreamConfig)> {
+    let input = |device: cpal::Device| -> Result<(cpal::Device, cpal::SupportedStreamConfig)> {
+        let config = device
+            .default_input_config()
+            .map_err(|e| SttError::Message(e.to_string()))?;
+        Ok((device, config))
+    };
+    // WASAPI records the output devices in loopback mode when an input stream is built on them.
+    #[cfg(all(windows, feature = "wasapi-loopback"))]
+    {
+        let output = match name {
+            Some(name) => host
+                .output_devices()
+                .map_err(|e| SttError::Message(e.to_string()))?
+                .find(|d| d.name().is_ok_and(|n| n == name)),
+            None => host.default_output_device(),
+        };
+        if let Some(device) = output {
+            let config = device
+                .default_output_config()
+                .map_err(|e| SttError::Message(e.to_string()))?;
+            return Ok((device, config));
+        }
+    }
+    if let Some(name) = name {
+        return match find_input_device(host, |n| n == name)? {
+            Some(device) => input(device),
+            None => Err(SttError::Message(format!(
+                "loopback device '{name}' not found"
+            ))),
+        };
+    }
+    if let Some(device) = find_input_device(host, |n| n.to_lowercase().contains("monitor"))? {
+        return input(device);
+    }
+    // The PulseAudio and PipeWire ALSA plugins record the source named by PULSE_SOURCE, e.g.
+    // `@DEFAULT_MONITOR@`, the monitor of the default sink.
+    if std::env::var_os("PULSE_SOURCE").is_some()
+        && let Some(device) = find_input_device(host, |n| n == "pulse" || n == "pipewire")?
+    {
+        return input(device);
+    }
+    Err(SttError::Message(
+        "no loopback source found: set PULSE_SOURCE=@DEFAULT_MONITOR@ with PulseAudio or \
+         PipeWire, or pick a loopback device by name"
+            .to_string(),
+    ))
+}
+
+/// Cuts the captured 24kHz mono audio into chunks of [`OUTPUT_CHUNK_SAMPLES`], applies the
+/// gain and sends them, dropping the pending audio when the receiver lags behind.
+struct Chunker {
+    tx: mpsc::Sender<AudioChunk>,
+    gain: Gain,
+    pending: Vec<f32>,
+    pending_read_idx: usize,
+}
+
+impl Chunker {
+    fn new(tx: mpsc::Sender<AudioChunk>, gain: Gain) -> Self {
+        Self {
+            tx,
+            gain,
+            pending: Vec::with_capacity(OUTPUT_CHUNK_SAMPLES * 4),
+            pending_read_idx: 0,
+        }
+    }
+
+    fn push(&mut self, samples: &[f32]) {
+        if samples.is_empty() {
+            return;
+        }
+
+        self.pending.reserve(samples.len());
+        self.pending.extend_from_slice(samples);
+
+        while self.pending.len().saturating_sub(self.pending_read_idx) >= OUTPUT_CHUNK_SAMPLES {
+            let start = self.pending_read_idx;
+            let end = self.pending_read_idx + OUTPUT_CHUNK_SAMPLES;
+            let mut chunk = self.pending[start..end].to_vec();
+            self.gain.process(&mut chunk);
+            self.pending_read_idx = end;
+            if self
+                .tx
+                .try_send(AudioChunk {
+                    samples: chunk,
+                    sample_rate_hz: OUTPUT_SAMPLE_RATE_HZ,
+                })
+                .is_err()
+            {
+                self.pending.clear();
+                self.pending_read_idx = 0;
+                break;
+            }
+        }
+
+        if self.pending_read_idx >= OUTPUT_CHUNK_SAMPLES * 4 {
+            self.pending.drain(..self.pending_read_idx);
+            self.pending_read_idx = 0;
+        }
+    }
+}
+
+fn record_latency(latency_us: &AtomicU64, info: &cpal::InputCallbackInfo) {
+    let ts = info.timestamp();
+    if let Some(delay) = ts.callback.duration_since(&ts.capture) {
+        latency_us.store(delay.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
 fn build_stream_f32(
     device: &cpal::Device,
     config: &StreamConfig,
     channels: u16,
     input_sample_rate_hz: u32,
-    tx: mpsc::Sender<AudioChunk>,
+    mut chunker: Chunker,
     resample_quality: ResampleQuality,
+    latency_us: Arc<AtomicU64>,
 ) -> Result<cpal::Stream> {
     let channels_usize = usize::from(channels);
     let mut resampler =
@@ -125,54 +365,33 @@ fn build_stream_f32(
             .map_err(|e| SttError::Message(e.to_string()))?;
     let mut mono_buf = Vec::<f32>::with_capacity(OUTPUT_CHUNK_SAMPLES * channels_usize);
     let mut resample_buf = Vec::<f32>::with_capacity(OUTPUT_CHUNK_SAMPLES);
-    let mut pending = Vec::<f32>::with_capacity(OUTPUT_CHUNK_SAMPLES * 4);
-    let mut pending_read_idx = 0usize;
 
     device
         .build_input_stream(
             config,
-            move |data: &[f32], _info| {
-                downmix_f32_to_mono_into(data, channels_usize, &mut mono_buf);
+            move |data: &[f32], info| {
+                record_latency(&latency_us, info);
                 let samples = match resampler.as_mut() {
                     Some(r) => {
-                        if let Err(err) = r.process_into(&mono_buf, &mut resample_buf) {
+                        resample_buf.clear();
+                        let res = r.process_interleaved_into(
+                            data,
+                            channels_usize,
+                            &mut mono_buf,
+                            &mut resample_buf,
+                        );
+                        if let Err(err) = res {
                             warn!(error = %err, "mic resampling failed");
                             return;
                         }
                         resample_buf.as_slice()
                     }
-                    None => mono_buf.as_slice(),
-                };
-
-                if samples.is_empty() {
-                    return;
-                }
-
-                pending.reserve(samples.len());
-                pending.extend_from_slice(samples);
-
-                while pending.len().saturating_sub(pending_read_idx) >= OUTPUT_CHUNK_SAMPLES {
-                    let start = pending_read_idx;
-                    let end = pending_read_idx + OUTPUT_CHUNK_SAMPLES;
-                    let chunk = pending[start..end].to_vec();
-                    pending_read_idx = end;
-                    if tx
-                        .try_send(AudioChunk {
-                            samples: chunk,
-                            sample_rate_hz: OUTPUT_SAMPLE_RATE_HZ,
-                        })
-                        .is_err()
-                    {
-                        pending.clear();
-                        pending_read_idx = 0;
-                        break;
+                    None => {
+                        downmix_f32_to_mono_into(data, channels_usize, &mut mono_buf);
+                        mono_buf.as_slice()
                     }
-                }
-
-                if pending_read_idx >= OUTPUT_CHUNK_SAMPLES * 4 {
-                    pending.drain(..pending_read_idx);
-                    pending_read_idx = 0;
-                }
+                };
+                chunker.push(samples);
             },
             move |err| {
                 warn!(error = %err, "mic input stream error");
@@ -187,8 +406,9 @@ fn build_stream_i16(
     config: &StreamConfig,
     channels: u16,
     input_sample_rate_hz: u32,
-    tx: mpsc::Sender<AudioChunk>,
+    mut chunker: Chunker,
     resample_quality: ResampleQuality,
+    latency_us: Arc<AtomicU64>,
 ) -> Result<cpal::Stream> {
     let channels_usize = usize::from(channels);
     let mut resampler =
@@ -196,16 +416,16 @@ fn build_stream_i16(
             .map_err(|e| SttError::Message(e.to_string()))?;
     let mut mono_buf = Vec::<f32>::with_capacity(OUTPUT_CHUNK_SAMPLES * channels_usize);
     let mut resample_buf = Vec::<f32>::with_capacity(OUTPUT_CHUNK_SAMPLES);
-    let mut pending = Vec::<f32>::with_capacity(OUTPUT_CHUNK_SAMPLES * 4);
-    let mut pending_read_idx = 0usize;
 
     device
         .build_input_stream(
             config,
-            move |data: &[i16], _info| {
+            move |data: &[i16], info| {
+                record_latency(&latency_us, info);
                 downmix_i16_to_mono_into(data, channels_usize, &mut mono_buf);
                 let samples = match resampler.as_mut() {
                     Some(r) => {
+                        resample_buf.clear();
                         if let Err(err) = r.process_into(&mono_buf, &mut resample_buf) {
                             warn!(error = %err, "mic resampling failed");
                             return;
@@ -214,36 +434,7 @@ fn build_stream_i16(
                     }
                     None => mono_buf.as_slice(),
                 };
-
-                if samples.is_empty() {
-                    return;
-                }
-
-                pending.reserve(samples.len());
-                pending.extend_from_slice(samples);
-
-                while pending.len().saturating_sub(pending_read_idx) >= OUTPUT_CHUNK_SAMPLES {
-                    let start = pending_read_idx;
-                    let end = pending_read_idx + OUTPUT_CHUNK_SAMPLES;
-                    let chunk = pending[start..end].to_vec();
-                    pending_read_idx = end;
-                    if tx
-                        .try_send(AudioChunk {
-                            samples: chunk,
-                            sample_rate_hz: OUTPUT_SAMPLE_RATE_HZ,
-                        })
-                        .is_err()
-                    {
-                        pending.clear();
-                        pending_read_idx = 0;
-                        break;
-                    }
-                }
-
-                if pending_read_idx >= OUTPUT_CHUNK_SAMPLES * 4 {
-                    pending.drain(..pending_read_idx);
-                    pending_read_idx = 0;
-                }
+                chunker.push(samples);
             },
             move |err| {
                 warn!(error = %err, "mic input stream error");
@@ -258,8 +449,9 @@ fn build_stream_u16(
     config: &StreamConfig,
     channels: u16,
     input_sample_rate_hz: u32,
-    tx: mpsc::Sender<AudioChunk>,
+    mut chunker: Chunker,
     resample_quality: ResampleQuality,
+    latency_us: Arc<AtomicU64>,
 ) -> Result<cpal::Stream> {
     let channels_usize = usize::from(channels);
     let mut resampler =
@@ -267,16 +459,16 @@ fn build_stream_u16(
             .map_err(|e| SttError::Message(e.to_string()))?;
     let mut mono_buf = Vec::<f32>::with_capacity(OUTPUT_CHUNK_SAMPLES * channels_usize);
     let mut resample_buf = Vec::<f32>::with_capacity(OUTPUT_CHUNK_SAMPLES);
-    let mut pending = Vec::<f32>::with_capacity(OUTPUT_CHUNK_SAMPLES * 4);
-    let mut pending_read_idx = 0usize;
 
     device
         .build_input_stream(
             config,
-            move |data: &[u16], _info| {
+            move |data: &[u16], info| {
+                record_latency(&latency_us, info);
                 downmix_u16_to_mono_into(data, channels_usize, &mut mono_buf);
                 let samples = match resampler.as_mut() {
                     Some(r) => {
+                        resample_buf.clear();
                         if let Err(err) = r.process_into(&mono_buf, &mut resample_buf) {
                             warn!(error = %err, "mic resampling failed");
                             return;
@@ -285,36 +477,7 @@ fn build_stream_u16(
                     }
                     None => mono_buf.as_slice(),
                 };
-
-                if samples.is_empty() {
-                    return;
-                }
-
-                pending.reserve(samples.len());
-                pending.extend_from_slice(samples);
-
-                while pending.len().saturating_sub(pending_read_idx) >= OUTPUT_CHUNK_SAMPLES {
-                    let start = pending_read_idx;
-                    let end = pending_read_idx + OUTPUT_CHUNK_SAMPLES;
-                    let chunk = pending[start..end].to_vec();
-                    pending_read_idx = end;
-                    if tx
-                        .try_send(AudioChunk {
-                            samples: chunk,
-                            sample_rate_hz: OUTPUT_SAMPLE_RATE_HZ,
-                        })
-                        .is_err()
-                    {
-                        pending.clear();
-                        pending_read_idx = 0;
-                        break;
-                    }
-                }
-
-                if pending_read_idx >= OUTPUT_CHUNK_SAMPLES * 4 {
-                    pending.drain(..pending_read_idx);
-                    pending_read_idx = 0;
-                }
+                chunker.push(samples);
             },
             move |err| {
                 warn!(error = %err, "mic input stream error");
@@ -9,4 +9,163 @@ pub enum SttError {
 
     #[error("unimplemented: {0}")]
     Unimplemented(&'static str),
+
+    /// The server closed the session, after the reconnects and failovers if any.
+    #[error("{0}")]
+    Closed(ServerClose),
+}
+
+impl SttError {
+    /// The close frame of the server, for errors that come from one.
+    pub fn server_close(&self) -> Option<&ServerClose> {
+        match self {
+            Self::Closed(close) => Some(close),
+            _ => None,
+        }
+    }
+}
+
+/// How a session closed by the server can be retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retry {
+    /// Retrying the same request fails the same way.
+    Never,
+    /// The server is busy or restarting, retry after a delay.
+    AfterDelay,
+    /// The credentials were turned down, retry with a new token.
+    WithNewToken,
+}
+
+/// WebSocket close codes sent by the server, see `moshi_server::protocol::CloseCode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CloseCode {
+    GoingAway,
+    MessageTooBig,
+    InternalError,
+    ServiceRestart,
+    TryAgainLater,
+    ServerAtCapacity,
+    AuthenticationFailed,
+    SessionTimeout,
+    InvalidMessage,
+    RateLimited,
+    ResourceUnavailable,
+    ClientTimeout,
+    TokenExpired,
+    Forbidden,
+    Other(u16),
+}
+
+impl CloseCode {
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            1001 => Self::GoingAway,
+            1009 => Self::MessageTooBig,
+            1011 => Self::InternalError,
+            1012 => Self::ServiceRestart,
+            1013 => Self::TryAgainLater,
+            4000 => Self::ServerAtCapacity,
+            4001 => Self::AuthenticationFailed,
+            4002 => Self::SessionTimeout,
+            4003 => Self::InvalidMessage,
+            4004 => Self::RateLimited,
+            4005 => Self::ResourceUnavailable,
+            4006 => Self::ClientTimeout,
+            4007 => Self::TokenExpired,
+            4008 => Self::Forbidden,
+            other => Self::Other(other),
+        }
+    }
+
+    pub fn code(self) -> u16 {
+        match self {
+            Self::GoingAway => 1001,
+            Self::MessageTooBig => 1009,
+            Self::InternalError => 1011,
+            Self::ServiceRestart => 1012,
+            Self::TryAgainLater => 1013,
+            Self::ServerAtCapacity => 4000,
+            Self::AuthenticationFailed => 4001,
+            Self::SessionTimeout => 4002,
+            Self::InvalidMessage => 4003,
+            Self::RateLimited => 4004,
+            Self::ResourceUnavailable => 4005,
+            Self::ClientTimeout => 4006,
+            Self::TokenExpired => 4007,
+            Self::Forbidden => 4008,
+            Self::Other(code) => code,
+        }
+    }
+
+    /// The codes that `auto_reconnect` retries, with a token from the token source for
+    /// [`Retry::WithNewToken`]. A missing resource, e.g. a room that does not exist, is not
+    /// retried as it would fail the same way.
+    pub fn retry(self) -> Retry {
+        match self {
+            Self::ServerAtCapacity
+            | Self::RateLimited
+            | Self::ClientTimeout
+            | Self::ServiceRestart
+            | Self::TryAgainLater => Retry::AfterDelay,
+            Self::AuthenticationFailed | Self::TokenExpired => Retry::WithNewToken,
+            _ => Retry::Never,
+        }
+    }
+}
+
+/// Close frame of the server, with the reason it gave.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerClose {
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+impl ServerClose {
+    pub fn new(code: u16, reason: &str) -> Self {
+        Self {
+            code: CloseCode::from_code(code),
+            reason: reason.trim().to_string(),
+        }
+    }
+
+    pub fn retry(&self) -> Retry {
+        self.code.retry()
+    }
+}
+
+impl std::fmt::Display for ServerClose {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&crate::stt::events::close_code_message(
+            self.code.code(),
+            &self.reason,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn close_codes_round_trip_with_retry_hints() {
+        let codes = [
+            1001, 1009, 1011, 1012, 1013, 4000, 4001, 4002, 4003, 4004, 4005, 4006, 4007,
+        ];
+        for code in codes.into_iter().chain([4008, 4999]) {
+            assert_eq!(CloseCode::from_code(code).code(), code);
+        }
+        let err = SttError::Closed(ServerClose::new(4004, " too many sessions "));
+        let close = err.server_close().unwrap();
+        assert_eq!(close.code, CloseCode::RateLimited);
+        assert_eq!(close.retry(), Retry::AfterDelay);
+        assert_eq!(
+            err.to_string(),
+            "rate limited (close code 4004) (reason: too many sessions)"
+        );
+        assert_eq!(CloseCode::from_code(4007).retry(), Retry::WithNewToken);
+        assert_eq!(CloseCode::from_code(4008).retry(), Retry::Never);
+        assert_eq!(CloseCode::from_code(4005).retry(), Retry::Never);
+        assert!(SttError::Message("x".to_string()).server_close().is_none());
+    }
 }
@@ -0,0 +1,234 @@
+use crate::stt::protocol::OutMsg;
+use crate::stt::transcript::TranscriptAssembler;
+use crate::stt::types::{Engine, SttEvent, Utterance, WordTiming};
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Sent on the output channel when the session switches to the local model, so that the
+/// event stream knows which engine the following words come from.
+pub(crate) const LOCAL_ENGINE_MARKER_ID: i64 = i64::MIN + 2;
+
+/// Turns the server messages into [`SttEvent`]s, for the event streams of every transport.
+/// Times are measured from the creation of the stream, the transports have different clocks.
+#[derive(Debug)]
+pub(crate) struct EventAssembler {
+    transcript: TranscriptAssembler,
+    pending: VecDeque<SttEvent>,
+    utterance_text: String,
+    pub(crate) utterance_finalize_delay: Duration,
+    utterance_deadline: Option<Duration>,
+    pub(crate) utterance_partial_min_interval: Duration,
+    last_partial_emit: Option<Duration>,
+    engine: Engine,
+}
+
+impl EventAssembler {
+    pub(crate) fn new() -> Self {
+        Self {
+            transcript: TranscriptAssembler::new(),
+            pending: VecDeque::new(),
+            utterance_text: String::new(),
+            utterance_finalize_delay: Duration::from_millis(1500),
+            utterance_deadline: None,
+            utterance_partial_min_interval: Duration::from_millis(100),
+            last_partial_emit: None,
+            engine: Engine::Server,
+        }
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<SttEvent> {
+        self.pending.pop_front()
+    }
+
+    pub(crate) fn push(&mut self, ev: SttEvent) {
+        self.pending.push_back(ev)
+    }
+
+    /// When the current utterance is final if no other word comes in.
+    pub(crate) fn deadline(&self) -> Option<Duration> {
+        self.utterance_deadline
+    }
+
+    /// `Echo` answers are left to the transport, which knows when the probe was sent.
+    pub(crate) fn handle(&mut self, msg: OutMsg, now: Duration) {
+        match msg {
+            OutMsg::Ready {
+                request_id,
+                trace_id,
+            } => {
+                self.pending.push_back(SttEvent::Ready {
+                    request_id,
+                    trace_id,
+                });
+            }
+            OutMsg::Word {
+                text,
+                start_time,
+                lang,
+            } => {
+                self.pending.push_back(SttEvent::WordReceived {
+                    text: text.clone(),
+                    start_ms: sec_to_ms(start_time),
+                    engine: self.engine,
+                });
+
+                if let Some(word) = self.transcript.push_word_lang(text, start_time, lang) {
+                    self.push_word_finalized(word, now);
+                }
+            }
+            OutMsg::EndWord { stop_time } => {
+                if let Some(word) = self.transcript.push_end_word(stop_time) {
+                    self.push_word_finalized(word, now);
+                }
+            }
+            OutMsg::Step {
+                step_idx,
+                prs,
+                buffered_pcm,
+            } => {
+                self.pending.push_back(SttEvent::VadStep {
+                    step_idx,
+                    prs,
+                    buffered_pcm,
+                });
+            }
+            OutMsg::Marker {
+                id: LOCAL_ENGINE_MARKER_ID,
+                ..
+            } => {
+                self.engine = Engine::Local;
+                self.pending.push_back(SttEvent::EngineChanged {
+                    engine: Engine::Local,
+                });
+            }
+            OutMsg::Marker {
+                id,
+                sample_idx,
+                step_idx,
+            } => {
+                self.pending.push_back(SttEvent::StreamMarker {
+                    id,
+                    sample_idx,
+                    step_idx,
+                });
+            }
+            OutMsg::SegmentBoundary { index, start_s } => {
+                self.pending.push_back(SttEvent::SegmentBoundary {
+                    index,
+                    start_ms: sec_to_ms(start_s),
+                });
+            }
+            OutMsg::AudioStats {
+                rms_db,
+                clipping_ratio,
+                speech_ratio,
+            } => {
+                self.pending.push_back(SttEvent::AudioStats {
+                    rms_db,
+                    clipping_ratio,
+                    speech_ratio,
+                });
+            }
+            OutMsg::SpeechRate { wpm } => {
+                self.pending.push_back(SttEvent::SpeechRate { wpm });
+            }
+            OutMsg::Overlap { ratio } => {
+                self.pending.push_back(SttEvent::Overlap { ratio });
+            }
+            OutMsg::SpeakerCountEstimate { n } => {
+                self.pending.push_back(SttEvent::SpeakerCount { n });
+            }
+            OutMsg::PhraseSpotted { phrase, time_s } => {
+                let start_ms = sec_to_ms(time_s);
+                self.pending
+                    .push_back(SttEvent::PhraseSpotted { phrase, start_ms });
+            }
+            OutMsg::ConfigUpdate {
+                asr_delay,
+                temperature,
+            } => {
+                let asr_delay_ms = asr_delay as u64 * 80;
+                self.pending.push_back(SttEvent::ConfigUpdate {
+                    asr_delay_ms,
+                    temperature,
+                });
+            }
+            OutMsg::Enrolled { voiced_s } => {
+                self.pending.push_back(SttEvent::Enrolled {
+                    voiced_ms: sec_to_ms(voiced_s),
+                });
+            }
+            OutMsg::Echo { .. } => {}
+            OutMsg::Error { message } => {
+                self.pending.push_back(SttEvent::Error { message });
+            }
+        }
+    }
+
+    fn push_word_finalized(&mut self, mut word: WordTiming, now: Duration) {
+        word.engine = self.engine;
+        let word_text = word.word.clone();
+        self.pending.push_back(SttEvent::WordFinalized(word));
+
+        if !self.utterance_text.is_empty() {
+            self.utterance_text.push(' ');
+        }
+        self.utterance_text.push_str(&word_text);
+        let should_emit = self.utterance_partial_min_interval.is_zero()
+            || self
+                .last_partial_emit
+                .is_none_or(|last| now.saturating_sub(last) >= self.utterance_partial_min_interval);
+        if should_emit {
+            self.pending
+                .push_back(SttEvent::UtterancePartial(Utterance {
+                    text: self.utterance_text.clone(),
+                }));
+            self.last_partial_emit = Some(now);
+        }
+
+        self.utterance_deadline = Some(now + self.utterance_finalize_delay);
+    }
+
+    pub(crate) fn finalize_utterance(&mut self) -> Option<SttEvent> {
+        self.utterance_deadline = None;
+        if self.utterance_text.is_empty() {
+            return None;
+        }
+
+        let text = std::mem::take(&mut self.utterance_text);
+        self.last_partial_emit = None;
+        Some(SttEvent::UtteranceFinal(Utterance { text }))
+    }
+}
+
+pub(crate) fn close_code_message(code: u16, reason: &str) -> String {
+    let reason = reason.trim();
+    let reason_suffix = if reason.is_empty() {
+        String::new()
+    } else {
+        format!(" (reason: {reason})")
+    };
+
+    match code {
+        1009 => format!("message too big (close code 1009){reason_suffix}"),
+        4000 => format!("server at capacity (close code 4000){reason_suffix}"),
+        4001 => format!("authentication failed (close code 4001){reason_suffix}"),
+        4002 => format!("session timeout (close code 4002){reason_suffix}"),
+        4003 => format!("invalid message (close code 4003){reason_suffix}"),
+        4004 => format!("rate limited (close code 4004){reason_suffix}"),
+        4005 => format!("resource unavailable (close code 4005){reason_suffix}"),
+        4006 => format!("client timeout (close code 4006){reason_suffix}"),
+        4007 => format!("token expired (close code 4007){reason_suffix}"),
+        4008 => format!("forbidden (close code 4008){reason_suffix}"),
+        other => format!("websocket closed (code {other}){reason_suffix}"),
+    }
+}
+
+fn sec_to_ms(s: f64) -> u64 {
+    if !s.is_finite() || s.is_sign_negative() {
+        return 0;
+    }
+
+    (s * 1000.0).round() as u64
+}
@@ -0,0 +1,109 @@
+use crate::stt::error::{Result, SttError};
+
+use kyutai_client_core::ws::{WsStream, connect_ws, redact_ws_url};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use url::Url;
+
+pub(crate) const SAMPLE_RATE_HZ: u64 = 24_000;
+
+/// Connect to the first server answering within `timeout`, trying them in order from `start`
+/// and wrapping around.
+pub(crate) async fn connect_first(
+    servers: &[Url],
+    start: usize,
+    auth_token: Option<&str>,
+    timeout: Duration,
+) -> Result<(usize, WsStream)> {
+    let mut errors = Vec::new();
+    for i in 0..servers.len() {
+        let idx = (start + i) % servers.len();
+        let url = &servers[idx];
+        match tokio::time::timeout(timeout, connect_ws(url, auth_token)).await {
+            Ok(Ok(ws_stream)) => return Ok((idx, ws_stream)),
+            Ok(Err(e)) if servers.len() == 1 => return Err(SttError::Message(e.to_string())),
+            Ok(Err(e)) => errors.push(format!("{}: {e}", redact_ws_url(url))),
+            Err(_) => errors.push(format!(
+                "{}: no answer within {timeout:?}",
+                redact_ws_url(url)
+            )),
+        }
+    }
+    Err(SttError::Message(format!(
+        "no healthy server ({})",
+        errors.join("; ")
+    )))
+}
+
+/// Audio sent to the current server that is not covered by a finalized word yet, replayed to
+/// the next server on failover.
+pub(crate) struct AudioJournal {
+    pcm: VecDeque<f32>,
+    /// Index of the first buffered sample since the start of the session.
+    start: u64,
+    max_samples: usize,
+    /// End of the last finalized word in samples, updated by the receive task.
+    confirmed: Arc<AtomicU64>,
+}
+
+impl AudioJournal {
+    pub(crate) fn new(max: Duration, confirmed: Arc<AtomicU64>) -> Self {
+        let max_samples = (max.as_secs_f64() * SAMPLE_RATE_HZ as f64) as usize;
+        Self {
+            pcm: VecDeque::new(),
+            start: 0,
+            max_samples,
+            confirmed,
+        }
+    }
+
+    fn trim(&mut self) {
+        let confirmed = self.confirmed.load(Ordering::Relaxed);
+        let n = (confirmed.saturating_sub(self.start) as usize).min(self.pcm.len());
+        self.drop_front(n);
+    }
+
+    fn drop_front(&mut self, n: usize) {
+        self.pcm.drain(..n);
+        self.start += n as u64;
+    }
+
+    pub(crate) fn push(&mut self, pcm: &[f32]) {
+        self.trim();
+        self.pcm.extend(pcm);
+        let excess = self.pcm.len().saturating_sub(self.max_samples);
+        self.drop_front(excess);
+    }
+
+    /// The audio to replay and the session time in seconds at which it starts.
+    pub(crate) fn pending(&mut self) -> (Vec<f32>, f64) {
+        self.trim();
+        let offset = self.start as f64 / SAMPLE_RATE_HZ as f64;
+        (self.pcm.iter().copied().collect(), offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journal_keeps_unconfirmed_audio() {
+        let confirmed = Arc::new(AtomicU64::new(0));
+        let mut journal = AudioJournal::new(Duration::from_secs(1), confirmed.clone());
+        journal.push(&[0.1; 12_000]);
+        journal.push(&[0.2; 12_000]);
+        confirmed.store(18_000, Ordering::Relaxed);
+        let (pcm, offset) = journal.pending();
+        assert_eq!(pcm.len(), 6_000);
+        assert_eq!(offset, 0.75);
+
+        // Older audio is dropped past the buffer duration.
+        journal.push(&[0.3; 30_000]);
+        let (pcm, offset) = journal.pending();
+        assert_eq!(pcm.len(), 24_000);
+        assert_eq!(offset, 1.25);
+    }
+}
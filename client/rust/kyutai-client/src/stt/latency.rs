@@ -0,0 +1,142 @@
+//! End-to-end latency of a session, see [`SttClientBuilder::latency`].
+//!
+//! [`SttClientBuilder::latency`]: crate::stt::SttClientBuilder::latency
+
+use crate::stt::failover::SAMPLE_RATE_HZ;
+use crate::stt::types::SttEvent;
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long the send times of the audio are kept around, words come back well before that.
+const SENT_RETENTION: Duration = Duration::from_secs(60);
+
+pub(crate) fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Shared by the sender, which records when the audio is handed over, and by the event stream,
+/// which times the words and the echo answers.
+#[derive(Debug)]
+pub(crate) struct LatencyMeter {
+    rate_hz: u32,
+    sent_s: f64,
+    /// Position in the session audio at the end of each chunk, with the time it was sent.
+    sent: VecDeque<(f64, Instant)>,
+    word_latencies_ms: Vec<u64>,
+}
+
+impl LatencyMeter {
+    pub(crate) fn new() -> Self {
+        Self {
+            rate_hz: SAMPLE_RATE_HZ as u32,
+            sent_s: 0.0,
+            sent: VecDeque::new(),
+            word_latencies_ms: vec![],
+        }
+    }
+
+    pub(crate) fn set_rate(&mut self, hz: u32) {
+        if hz > 0 {
+            self.rate_hz = hz
+        }
+    }
+
+    pub(crate) fn audio_sent(&mut self, samples: usize, now: Instant) {
+        self.sent_s += samples as f64 / self.rate_hz as f64;
+        self.sent.push_back((self.sent_s, now));
+        while let Some(&(_, t)) = self.sent.front()
+            && now.duration_since(t) > SENT_RETENTION
+        {
+            self.sent.pop_front();
+        }
+    }
+
+    /// A word starting at `start_s` in the session audio reached the application at `now`.
+    pub(crate) fn word(&mut self, start_s: f64, now: Instant) {
+        let idx = self.sent.partition_point(|&(end_s, _)| end_s <= start_s);
+        let Some(&(end_s, sent_at)) = self.sent.get(idx) else {
+            return;
+        };
+        // The audio is captured in real time, the start of the word was recorded that long
+        // before its chunk was sent.
+        let captured_at = sent_at.checked_sub(Duration::from_secs_f64(end_s - start_s));
+        if let Some(latency) = captured_at.map(|t| now.saturating_duration_since(t)) {
+            self.word_latencies_ms.push(latency.as_millis() as u64)
+        }
+    }
+
+    /// Report for the answer to an `Echo` sent at `client_ts_ms` and received at `now_ms`, with
+    /// the median latency of the words received since the previous report.
+    pub(crate) fn report(
+        &mut self,
+        client_ts_ms: u64,
+        server_recv_ms: u64,
+        server_send_ms: u64,
+        now_ms: u64,
+    ) -> SttEvent {
+        let (t0, t1, t2, t3) = (
+            client_ts_ms as i64,
+            server_recv_ms as i64,
+            server_send_ms as i64,
+            now_ms as i64,
+        );
+        let rtt_ms = ((t3 - t0) - (t2 - t1)).max(0) as u64;
+        let clock_offset_ms = ((t1 - t0) + (t2 - t3)) / 2;
+        let mut latencies = std::mem::take(&mut self.word_latencies_ms);
+        latencies.sort_unstable();
+        let word_latency_ms = latencies.get(latencies.len() / 2).copied();
+        SttEvent::LatencyReport {
+            rtt_ms,
+            clock_offset_ms,
+            word_latency_ms,
+            words: latencies.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn word_latency_is_measured_from_capture() {
+        let mut meter = LatencyMeter::new();
+        let start = Instant::now();
+        for i in 1..=10 {
+            meter.audio_sent(1920, start + Duration::from_millis(80 * i));
+        }
+        // Sent at 160ms with the chunk ending at 0.16s, so captured at 100ms.
+        meter.word(0.1, start + Duration::from_millis(640));
+        meter.word(0.5, start + Duration::from_millis(900));
+        meter.word(0.7, start + Duration::from_millis(950));
+        // Not sent yet.
+        meter.word(2.0, start + Duration::from_millis(950));
+        match meter.report(1000, 1520, 1530, 1050) {
+            SttEvent::LatencyReport {
+                rtt_ms,
+                clock_offset_ms,
+                word_latency_ms,
+                words,
+            } => {
+                assert_eq!(rtt_ms, 40);
+                assert_eq!(clock_offset_ms, 500);
+                assert_eq!(words, 3);
+                assert_eq!(word_latency_ms, Some(400));
+            }
+            other => panic!("unexpected event: {other:?}"),
+        }
+        match meter.report(1000, 1000, 1000, 1000) {
+            SttEvent::LatencyReport {
+                word_latency_ms,
+                words,
+                ..
+            } => {
+                assert_eq!((word_latency_ms, words), (None, 0))
+            }
+            other => panic!("unexpected event: {other:?}"),
+        }
+    }
+}
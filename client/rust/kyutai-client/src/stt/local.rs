@@ -0,0 +1,268 @@
+//! In-process ASR engine, used to keep transcribing when no server can be reached.
+//!
+//! The model is described by the `[modules.<name>]` section of a server STT config, so the
+//! same files run locally and on the server. A `.gguf` language model is loaded quantized.
+
+use crate::stt::error::{Result, SttError};
+use crate::stt::protocol::{InMsg, OutMsg};
+
+use anyhow::Context;
+use candle::{DType, Device, Tensor};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+
+const FRAME_SIZE: usize = 1920;
+
+/// Model files and settings of the local engine, in the server config format.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct LocalAsrConfig {
+    pub lm_model_file: String,
+    pub text_tokenizer_file: String,
+    pub audio_tokenizer_file: String,
+    pub asr_delay_in_tokens: usize,
+    pub model: moshi::lm::Config,
+    #[serde(default)]
+    pub conditioning_delay: Option<f32>,
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    /// Directory that relative model paths are resolved against.
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+}
+
+impl LocalAsrConfig {
+    /// Read the first `Asr` or `BatchedAsr` module of a server config file.
+    pub fn from_server_config(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| SttError::Message(format!("cannot read {}: {e}", path.display())))?;
+        let mut cfg = Self::from_server_config_str(&text)
+            .map_err(|e| SttError::Message(format!("{}: {e}", path.display())))?;
+        cfg.base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(cfg)
+    }
+
+    fn from_server_config_str(text: &str) -> std::result::Result<Self, String> {
+        crate::stt::mimi::server_module(text)
+    }
+
+    fn resolve(&self, file: &str) -> anyhow::Result<PathBuf> {
+        crate::stt::mimi::resolve(&self.base_dir, file)
+    }
+}
+
+pub(crate) struct LocalAsr {
+    state: moshi::asr::State,
+    conditions: Option<moshi::conditioner::Condition>,
+    text_tokenizer: sentencepiece::SentencePieceProcessor,
+    asr_delay_in_tokens: usize,
+    pcm: Vec<f32>,
+    /// Session time in seconds at which the local audio starts.
+    offset: f64,
+    /// Session time of the end of the received audio, in samples, for the marker answers.
+    received: u64,
+    steps: usize,
+}
+
+impl LocalAsr {
+    pub(crate) fn load(cfg: &LocalAsrConfig, offset: f64) -> anyhow::Result<Self> {
+        let dev = Device::cuda_if_available(0)?;
+        let dtype = if dev.is_cuda() {
+            DType::BF16
+        } else {
+            DType::F32
+        };
+        let lm_file = cfg.resolve(&cfg.lm_model_file)?;
+        let lm = moshi::lm::load_lm_model(cfg.model.clone(), &lm_file, dtype, &dev)
+            .with_context(|| lm_file.display().to_string())?;
+        let conditions = match lm.condition_provider() {
+            None => None,
+            Some(cp) => {
+                let delay = cfg
+                    .conditioning_delay
+                    .context("missing conditioning_delay in config")?;
+                Some(cp.condition_cont("delay", -delay)?)
+            }
+        };
+        let audio_tokenizer = {
+            let file = cfg.resolve(&cfg.audio_tokenizer_file)?;
+            let vb = unsafe {
+                candle_nn::VarBuilder::from_mmaped_safetensors(&[&file], DType::F32, &dev)?
+            };
+            let mut mimi_cfg = moshi::mimi::Config::v0_1(Some(cfg.model.audio_codebooks));
+            // The mimi transformer runs at 25Hz.
+            mimi_cfg.transformer.max_seq_len = cfg.model.transformer.max_seq_len * 2;
+            moshi::mimi::Mimi::new(mimi_cfg, vb)?
+        };
+        let tokenizer_file = cfg.resolve(&cfg.text_tokenizer_file)?;
+        let text_tokenizer = sentencepiece::SentencePieceProcessor::open(&tokenizer_file)
+            .with_context(|| tokenizer_file.display().to_string())?;
+        let state = moshi::asr::State::new(
+            1,
+            cfg.asr_delay_in_tokens,
+            cfg.temperature.unwrap_or(0.0),
+            audio_tokenizer,
+            lm,
+        )?;
+        Ok(Self {
+            state,
+            conditions,
+            text_tokenizer,
+            asr_delay_in_tokens: cfg.asr_delay_in_tokens,
+            pcm: Vec::new(),
+            offset,
+            received: (offset * 24_000.0).round() as u64,
+            steps: 0,
+        })
+    }
+
+    fn step_frame(&mut self, frame: &[f32], out: &mut Vec<OutMsg>) -> anyhow::Result<()> {
+        let pcm = Tensor::new(frame, self.state.device())?.reshape((1, 1, ()))?;
+        let msgs = self
+            .state
+            .step_pcm(pcm, self.conditions.as_ref(), &().into(), |_, _, _| ())?;
+        self.steps += 1;
+        for msg in msgs {
+            match msg {
+                moshi::asr::AsrMsg::Word {
+                    tokens, start_time, ..
+                } => out.push(OutMsg::Word {
+                    text: self.text_tokenizer.decode_piece_ids(&tokens)?,
+                    start_time: start_time + self.offset,
+                    lang: None,
+                }),
+                moshi::asr::AsrMsg::EndWord { stop_time, .. } => out.push(OutMsg::EndWord {
+                    stop_time: stop_time + self.offset,
+                }),
+                // The model already retried or reset the slot.
+                moshi::asr::AsrMsg::Step { .. } | moshi::asr::AsrMsg::NonFinite { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn push(&mut self, pcm: &[f32]) -> anyhow::Result<Vec<OutMsg>> {
+        let mut out = Vec::new();
+        self.received += pcm.len() as u64;
+        self.pcm.extend_from_slice(pcm);
+        let frames = self.pcm.len() / FRAME_SIZE;
+        let pcm = std::mem::take(&mut self.pcm);
+        for frame in pcm.chunks_exact(FRAME_SIZE) {
+            self.step_frame(frame, &mut out)?;
+        }
+        self.pcm = pcm[frames * FRAME_SIZE..].to_vec();
+        Ok(out)
+    }
+
+    /// Complete the buffered audio and run the model over the ASR delay with silence, so that
+    /// every word spoken so far is output.
+    pub(crate) fn flush(&mut self) -> anyhow::Result<Vec<OutMsg>> {
+        let mut out = Vec::new();
+        let mut frame = std::mem::take(&mut self.pcm);
+        let frames = self.asr_delay_in_tokens + usize::from(!frame.is_empty());
+        // The model clock runs over the silence, the session clock does not.
+        let silence = frames * FRAME_SIZE - frame.len();
+        self.offset -= silence as f64 / 24_000.0;
+        frame.resize(FRAME_SIZE, 0.0);
+        for _ in 0..frames {
+            self.step_frame(&frame, &mut out)?;
+            frame.fill(0.0);
+        }
+        Ok(out)
+    }
+
+    /// Answer to a marker sent after the audio pushed so far.
+    pub(crate) fn marker(&self, id: i64) -> OutMsg {
+        OutMsg::Marker {
+            id,
+            sample_idx: self.received,
+            step_idx: self.steps,
+        }
+    }
+}
+
+/// Run the local engine on its own thread, its output goes to `out_tx` the same way as the
+/// messages of a server. Markers are answered once the audio sent before them is flushed.
+pub(crate) fn spawn(
+    cfg: LocalAsrConfig,
+    offset: f64,
+    out_tx: mpsc::Sender<OutMsg>,
+) -> mpsc::UnboundedSender<InMsg> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<InMsg>();
+    std::thread::spawn(move || {
+        let mut asr = match LocalAsr::load(&cfg, offset) {
+            Ok(asr) => asr,
+            Err(e) => {
+                let message = format!("cannot load the local model: {e:#}");
+                let _ = out_tx.blocking_send(OutMsg::Error { message });
+                return;
+            }
+        };
+        tracing::info!("local model loaded");
+        while let Some(msg) = rx.blocking_recv() {
+            let msgs = match msg {
+                InMsg::Audio { pcm } => asr.push(&pcm),
+                InMsg::Marker { id } => asr.flush().map(|mut msgs| {
+                    msgs.push(asr.marker(id));
+                    msgs
+                }),
+                InMsg::Init | InMsg::OggOpus { .. } | InMsg::Ping | InMsg::RefreshToken { .. } => {
+                    Ok(vec![])
+                }
+                InMsg::SetSampleRate { hz } => {
+                    tracing::warn!(hz, "the local model only takes 24kHz audio");
+                    Ok(vec![])
+                }
+                InMsg::Enroll { .. } => {
+                    tracing::warn!("the local model transcribes all the speakers");
+                    Ok(vec![])
+                }
+                InMsg::AudioCodes { .. } => {
+                    tracing::warn!("the local model only takes pcm audio");
+                    Ok(vec![])
+                }
+                InMsg::Echo { client_ts_ms } => {
+                    let now_ms = crate::stt::latency::unix_ms();
+                    Ok(vec![OutMsg::Echo {
+                        client_ts_ms,
+                        server_recv_ms: now_ms,
+                        server_send_ms: now_ms,
+                    }])
+                }
+            };
+            let msgs = match msgs {
+                Ok(msgs) => msgs,
+                Err(e) => vec![OutMsg::Error {
+                    message: format!("local model error: {e:#}"),
+                }],
+            };
+            for msg in msgs {
+                if out_tx.blocking_send(msg).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_is_read_from_server_config() {
+        let text = std::fs::read_to_string(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../../configs/stt/config-stt-en_fr-hf.toml"
+        ))
+        .unwrap();
+        let cfg = LocalAsrConfig::from_server_config_str(&text).unwrap();
+        assert_eq!(cfg.asr_delay_in_tokens, 6);
+        assert_eq!(cfg.model.audio_codebooks, 32);
+        assert!(cfg.lm_model_file.starts_with("hf://"));
+
+        let err = LocalAsrConfig::from_server_config_str("[modules.tts]\ntype = \"Py\"\n");
+        assert!(err.unwrap_err().contains("no Asr"));
+    }
+}
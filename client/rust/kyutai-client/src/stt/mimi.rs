@@ -0,0 +1,131 @@
+//! Local Mimi encoding of the audio sent to a batched asr server. Each 80ms frame goes out as
+//! `AudioCodes`, about 100 bytes with 32 codebooks, instead of 7.7KB of f32 pcm.
+//!
+//! The codes have to come from the audio tokenizer of the server model, so the encoder is read
+//! from the `[modules.<name>]` section of the same server STT config as the local fallback.
+
+use crate::stt::error::{Result, SttError};
+
+use anyhow::Context;
+use candle::{DType, Device, IndexOp, Tensor};
+use std::path::{Path, PathBuf};
+
+/// Mimi of an STT model, in the server config format.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct LocalMimiConfig {
+    pub audio_tokenizer_file: String,
+    pub model: moshi::lm::Config,
+    /// Directory that relative model paths are resolved against.
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+}
+
+impl LocalMimiConfig {
+    /// Read the first `Asr` or `BatchedAsr` module of a server config file.
+    pub fn from_server_config(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| SttError::Message(format!("cannot read {}: {e}", path.display())))?;
+        let mut cfg: Self = server_module(&text)
+            .map_err(|e| SttError::Message(format!("{}: {e}", path.display())))?;
+        cfg.base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(cfg)
+    }
+}
+
+/// The first `Asr` or `BatchedAsr` module of a server config.
+pub(crate) fn server_module<T: serde::de::DeserializeOwned>(
+    text: &str,
+) -> std::result::Result<T, String> {
+    let table: toml::Table = toml::from_str(text).map_err(|e| e.to_string())?;
+    let modules = table.get("modules").and_then(|m| m.as_table());
+    let module = modules
+        .into_iter()
+        .flat_map(|m| m.values())
+        .find(|m| {
+            matches!(
+                m.get("type").and_then(|t| t.as_str()),
+                Some("Asr" | "BatchedAsr")
+            )
+        })
+        .ok_or_else(|| "no Asr or BatchedAsr module".to_string())?;
+    module
+        .clone()
+        .try_into()
+        .map_err(|e: toml::de::Error| e.to_string())
+}
+
+/// Path of a model file, downloaded first for `hf://<org>/<repo>/<file>` paths.
+pub(crate) fn resolve(base_dir: &Path, file: &str) -> anyhow::Result<PathBuf> {
+    if let Some(path) = file.strip_prefix("hf://") {
+        let s: Vec<&str> = path.split('/').collect();
+        if s.len() < 3 {
+            anyhow::bail!("unexpected format for hf path {file}")
+        }
+        let api = hf_hub::api::sync::ApiBuilder::from_env().build()?;
+        let repo = api.model(format!("{}/{}", s[0], s[1]));
+        Ok(repo.get(&s[2..].join("/"))?)
+    } else {
+        Ok(base_dir.join(file))
+    }
+}
+
+pub(crate) struct MimiEncoder {
+    mimi: moshi::mimi::Mimi,
+    device: Device,
+}
+
+impl MimiEncoder {
+    pub(crate) fn load(cfg: &LocalMimiConfig) -> anyhow::Result<Self> {
+        let device = Device::cuda_if_available(0)?;
+        let file = resolve(&cfg.base_dir, &cfg.audio_tokenizer_file)?;
+        let vb = unsafe {
+            candle_nn::VarBuilder::from_mmaped_safetensors(&[&file], DType::F32, &device)?
+        };
+        let mut mimi_cfg = moshi::mimi::Config::v0_1(Some(cfg.model.audio_codebooks));
+        // The mimi transformer runs at 25Hz.
+        mimi_cfg.transformer.max_seq_len = cfg.model.transformer.max_seq_len * 2;
+        let mimi =
+            moshi::mimi::Mimi::new(mimi_cfg, vb).with_context(|| file.display().to_string())?;
+        Ok(Self { mimi, device })
+    }
+
+    /// Encode 24kHz mono audio, returning the codes of each frame completed so far. Samples
+    /// left over are kept for the next call.
+    pub(crate) fn encode(&mut self, pcm: &[f32]) -> anyhow::Result<Vec<Vec<u32>>> {
+        let pcm = Tensor::from_slice(pcm, (1, 1, pcm.len()), &self.device)?;
+        let codes = self.mimi.encode_step(&pcm.into(), &().into())?;
+        let Some(codes) = codes.as_option() else {
+            return Ok(vec![]);
+        };
+        // (1, codebooks, steps) -> one vec of codes per step.
+        Ok(codes.i(0)?.t()?.to_vec2::<u32>()?)
+    }
+
+    /// Start over, for the audio replayed to a server taking over the session.
+    pub(crate) fn reset(&mut self) {
+        self.mimi.reset_state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mimi_is_read_from_server_config() {
+        let text = std::fs::read_to_string(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../../configs/stt/config-stt-en_fr-hf.toml"
+        ))
+        .unwrap();
+        let cfg: LocalMimiConfig = server_module(&text).unwrap();
+        assert_eq!(cfg.model.audio_codebooks, 32);
+        assert!(
+            cfg.audio_tokenizer_file
+                .starts_with("hf://kyutai/stt-1b-en_fr-candle/mimi")
+        );
+        let path = resolve(Path::new("/models"), "mimi.safetensors").unwrap();
+        assert_eq!(path, Path::new("/models/mimi.safetensors"));
+    }
+}
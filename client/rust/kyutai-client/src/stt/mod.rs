@@ -1,12 +1,31 @@
 mod error;
+mod events;
+#[cfg(not(target_arch = "wasm32"))]
+mod failover;
+#[cfg(not(target_arch = "wasm32"))]
+mod latency;
 
+#[cfg(not(target_arch = "wasm32"))]
 pub mod audio;
+#[cfg(all(feature = "local", not(target_arch = "wasm32")))]
+pub mod local;
+#[cfg(all(feature = "local-mimi", not(target_arch = "wasm32")))]
+pub mod mimi;
 pub mod protocol;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod spill;
 pub mod transcript;
+#[cfg(all(feature = "web", target_arch = "wasm32"))]
+pub mod web;
+pub mod wer;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod ws;
 
 mod types;
 
-pub use error::{Result, SttError};
-pub use types::{SttEvent, Utterance, WordTiming};
+pub use error::{CloseCode, Result, Retry, ServerClose, SttError};
+pub use types::{Engine, SttEvent, Utterance, WordTiming};
+#[cfg(all(feature = "web", target_arch = "wasm32"))]
+pub use web::{SttClientBuilder, SttEventStream, SttSender, SttSession};
+#[cfg(not(target_arch = "wasm32"))]
 pub use ws::{SttClientBuilder, SttSender, SttSession};
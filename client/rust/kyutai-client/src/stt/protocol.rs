@@ -6,13 +6,60 @@ use serde::{Deserialize, Serialize};
 pub enum InMsg {
     Init,
 
-    Audio { pcm: Vec<f32> },
+    Audio {
+        pcm: Vec<f32>,
+    },
 
-    OggOpus { data: Vec<u8> },
+    OggOpus {
+        data: Vec<u8>,
+    },
 
-    Marker { id: i64 },
+    /// Audio encoded by the Mimi of the server model, one array of `audio_codebooks` codes per
+    /// 80ms frame (batched asr only).
+    AudioCodes {
+        codes: Vec<Vec<u32>>,
+    },
+
+    Marker {
+        id: i64,
+    },
 
     Ping,
+
+    /// Sample rate of the following `Audio` messages, when the capture device does not run at
+    /// 24kHz. It can be sent again mid-stream when the device changes.
+    SetSampleRate {
+        hz: u32,
+    },
+
+    /// New JWT for a long-running session, to be sent before the current token expires. The
+    /// server closes the socket with 4007 once the token has expired past its grace period.
+    RefreshToken {
+        jwt: String,
+    },
+
+    /// A few seconds of the voice to transcribe, 24kHz mono. Once the server answers
+    /// `Enrolled`, the words of other voices are tagged `[other]` or dropped.
+    Enroll {
+        pcm: Vec<f32>,
+        #[serde(default)]
+        other: OtherSpeech,
+    },
+
+    /// Latency probe, answered right away with `Echo` and the server clock.
+    Echo {
+        client_ts_ms: u64,
+    },
+}
+
+/// What the server does with the words of other speakers once a voice is enrolled.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OtherSpeech {
+    /// A single `[other]` word for each stretch of other speech.
+    #[default]
+    Tag,
+    Suppress,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -21,6 +68,9 @@ pub enum OutMsg {
     Word {
         text: String,
         start_time: f64,
+        /// Language of the word (`en`, `fr`), sent by servers with `word_lang` enabled.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lang: Option<String>,
     },
 
     EndWord {
@@ -33,11 +83,80 @@ pub enum OutMsg {
         buffered_pcm: usize,
     },
 
+    /// Answers a `Marker`, with the session time of the marker in 24kHz samples and the model
+    /// step that consumed it. Both are 0 with servers that predate them.
     Marker {
         id: i64,
+        #[serde(default)]
+        sample_idx: u64,
+        #[serde(default)]
+        step_idx: usize,
+    },
+
+    /// Start of a new segment of a long session, the words of the previous segment are final.
+    SegmentBoundary {
+        index: usize,
+        start_s: f64,
+    },
+
+    /// Level of the audio received over the last stats interval, in dBFS, with the fraction
+    /// of clipped samples and of frames loud enough to hold speech.
+    AudioStats {
+        rms_db: f32,
+        clipping_ratio: f32,
+        speech_ratio: f32,
+    },
+
+    /// Words per minute over the last minute of the session.
+    SpeechRate {
+        wpm: f32,
+    },
+
+    /// Fraction of the speech since the last report in which two voices overlap.
+    Overlap {
+        ratio: f32,
+    },
+
+    /// Number of voices heard in the session, sent before the answer to each `Marker`.
+    SpeakerCountEstimate {
+        n: usize,
+    },
+
+    /// A phrase requested with `spot` was heard, sent right after its last word. `time_s` is
+    /// the start of its first word.
+    PhraseSpotted {
+        phrase: String,
+        time_s: f64,
+    },
+
+    /// An admin retuned the module, the values apply from the next model step. `asr_delay` is
+    /// in 80ms steps.
+    ConfigUpdate {
+        asr_delay: usize,
+        temperature: f64,
     },
 
-    Ready,
+    /// Answers `Enroll`, with the duration of the speech found in the clip.
+    Enrolled {
+        voiced_s: f64,
+    },
+
+    /// Answers `Echo`, with the server wall clock in milliseconds since the unix epoch when the
+    /// probe was received and when the answer was sent.
+    Echo {
+        client_ts_ms: u64,
+        server_recv_ms: u64,
+        server_send_ms: u64,
+    },
+
+    /// With the `X-Request-Id` and the `traceparent` trace id of the upgrade request, when the
+    /// gateway in front of the server set them.
+    Ready {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        trace_id: Option<String>,
+    },
 
     Error {
         message: String,
@@ -57,13 +176,33 @@ pub fn encode_in_msg(msg: &InMsg) -> Result<Vec<u8>> {
     Ok(buf)
 }
 
+/// Nesting allowed in server messages, deeper frames are rejected before serde buffers a
+/// preallocated sequence for each level.
+pub(crate) const MAX_MSG_DEPTH: usize = 4;
+
 pub fn decode_out_msg(bytes: &[u8]) -> Result<OutMsg> {
-    rmp_serde::from_slice::<OutMsg>(bytes).map_err(|e| SttError::Message(e.to_string()))
+    let mut de = rmp_serde::Deserializer::from_read_ref(bytes);
+    de.set_max_depth(MAX_MSG_DEPTH);
+    OutMsg::deserialize(&mut de).map_err(|e| SttError::Message(e.to_string()))
+}
+
+/// Decode a server frame, either a single message or, for sessions connected with
+/// `coalesce_ms`, a msgpack array of the messages produced within the flush interval.
+pub fn decode_out_msgs(bytes: &[u8]) -> Result<Vec<OutMsg>> {
+    match bytes.first() {
+        Some(0x90..=0x9f | 0xdc | 0xdd) => {
+            let mut de = rmp_serde::Deserializer::from_read_ref(bytes);
+            de.set_max_depth(MAX_MSG_DEPTH + 1);
+            Vec::<OutMsg>::deserialize(&mut de).map_err(|e| SttError::Message(e.to_string()))
+        }
+        _ => decode_out_msg(bytes).map(|msg| vec![msg]),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn roundtrip_audio_with_vec_f32() {
@@ -91,6 +230,30 @@ mod tests {
             OutMsg::Word {
                 text: "hello".to_string(),
                 start_time: 1.5,
+                lang: None,
+            }
+        );
+    }
+
+    #[test]
+    fn decode_marker_without_position() {
+        #[derive(serde::Serialize)]
+        struct OldMarker {
+            r#type: &'static str,
+            id: i64,
+        }
+        let bytes = rmp_serde::to_vec_named(&OldMarker {
+            r#type: "Marker",
+            id: 7,
+        })
+        .unwrap();
+        let msg = decode_out_msg(&bytes).unwrap();
+        assert_eq!(
+            msg,
+            OutMsg::Marker {
+                id: 7,
+                sample_idx: 0,
+                step_idx: 0
             }
         );
     }
@@ -107,4 +270,119 @@ mod tests {
 
         assert_eq!(bytes, buf);
     }
+
+    #[test]
+    fn decode_single_and_coalesced_frames() {
+        let msgs = vec![
+            OutMsg::Word {
+                text: "hello".to_string(),
+                start_time: 1.5,
+                lang: None,
+            },
+            OutMsg::EndWord { stop_time: 1.9 },
+        ];
+        let bytes = rmp_serde::to_vec_named(&msgs).unwrap();
+        assert_eq!(decode_out_msgs(&bytes).unwrap(), msgs);
+        let bytes = rmp_serde::to_vec_named(&msgs[1]).unwrap();
+        assert_eq!(decode_out_msgs(&bytes).unwrap(), msgs[1..]);
+        assert!(decode_out_msgs(&[0x91, 0xc0]).is_err());
+    }
+
+    #[test]
+    fn deep_nesting_is_rejected() {
+        let mut bytes = vec![
+            0x82, 0xa4, b't', b'y', b'p', b'e', 0xa5, b'R', b'e', b'a', b'd', b'y',
+        ];
+        bytes.extend([0xa1, b'x']);
+        bytes.extend([0xdd, 0xff, 0xff, 0xff, 0xff].repeat(100));
+        let err = decode_out_msg(&bytes).unwrap_err();
+        assert!(err.to_string().contains("depth limit exceeded"), "{err}");
+    }
+
+    fn out_msg() -> impl Strategy<Value = OutMsg> {
+        prop_oneof![
+            (
+                prop::option::of("[a-z0-9-]{1,16}"),
+                prop::option::of("[0-9a-f]{32}")
+            )
+                .prop_map(|(request_id, trace_id)| OutMsg::Ready {
+                    request_id,
+                    trace_id
+                }),
+            (".*", 0.0f64..1e4, prop::option::of("en|fr")).prop_map(|(text, start_time, lang)| {
+                OutMsg::Word {
+                    text,
+                    start_time,
+                    lang,
+                }
+            }),
+            (0.0f64..1e4).prop_map(|stop_time| OutMsg::EndWord { stop_time }),
+            (
+                any::<usize>(),
+                prop::collection::vec(0.0f32..1.0, 0..8),
+                any::<usize>()
+            )
+                .prop_map(|(step_idx, prs, buffered_pcm)| OutMsg::Step {
+                    step_idx,
+                    prs,
+                    buffered_pcm
+                }),
+            (any::<i64>(), any::<u64>(), any::<usize>()).prop_map(|(id, sample_idx, step_idx)| {
+                OutMsg::Marker {
+                    id,
+                    sample_idx,
+                    step_idx,
+                }
+            }),
+            (any::<usize>(), 0.0f64..1e4)
+                .prop_map(|(index, start_s)| OutMsg::SegmentBoundary { index, start_s }),
+            (-100.0f32..0.0, 0.0f32..1.0, 0.0f32..1.0).prop_map(
+                |(rms_db, clipping_ratio, speech_ratio)| OutMsg::AudioStats {
+                    rms_db,
+                    clipping_ratio,
+                    speech_ratio
+                }
+            ),
+            (0.0f32..400.0).prop_map(|wpm| OutMsg::SpeechRate { wpm }),
+            (0.0f32..1.0).prop_map(|ratio| OutMsg::Overlap { ratio }),
+            (0usize..10).prop_map(|n| OutMsg::SpeakerCountEstimate { n }),
+            (".*", 0.0f64..1000.0)
+                .prop_map(|(phrase, time_s)| OutMsg::PhraseSpotted { phrase, time_s }),
+            (1usize..100, 0.0f64..2.0).prop_map(|(asr_delay, temperature)| {
+                OutMsg::ConfigUpdate {
+                    asr_delay,
+                    temperature,
+                }
+            }),
+            (0.0f64..10.0).prop_map(|voiced_s| OutMsg::Enrolled { voiced_s }),
+            any::<(u64, u64, u64)>().prop_map(|(client_ts_ms, server_recv_ms, server_send_ms)| {
+                OutMsg::Echo {
+                    client_ts_ms,
+                    server_recv_ms,
+                    server_send_ms,
+                }
+            }),
+            ".*".prop_map(|message| OutMsg::Error { message }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn server_messages_round_trip(msg in out_msg()) {
+            let bytes = rmp_serde::to_vec_named(&msg).unwrap();
+            prop_assert_eq!(decode_out_msg(&bytes).unwrap(), msg);
+        }
+
+        #[test]
+        fn truncated_messages_are_errors(msg in out_msg(), cut in any::<prop::sample::Index>()) {
+            let mut bytes = rmp_serde::to_vec_named(&msg).unwrap();
+            bytes.truncate(cut.index(bytes.len()));
+            prop_assert!(matches!(decode_out_msg(&bytes), Err(SttError::Message(_))));
+        }
+
+        #[test]
+        fn arbitrary_bytes_do_not_panic(bytes in prop::collection::vec(any::<u8>(), 0..256)) {
+            let _ = decode_out_msg(&bytes);
+        }
+    }
 }
@@ -0,0 +1,385 @@
+//! Spill of the messages that a stalled connection cannot take. Once more than `threshold` of
+//! audio waits for the socket, the messages of the sender go to a file in arrival order and
+//! are replayed once the connection drains, faster than real time so that the transcript
+//! catches up. The oldest audio is dropped beyond `max_lag`.
+
+use crate::stt::error::{Result, SttError};
+use crate::stt::failover::SAMPLE_RATE_HZ;
+use crate::stt::protocol::{InMsg, OutMsg, encode_in_msg};
+use crate::stt::ws::SendCmd;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// How often the spill checks whether the connection drained.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Spill of the audio that the connection cannot take, see
+/// [`SttClientBuilder::spill`](crate::stt::SttClientBuilder::spill).
+#[derive(Clone, Debug)]
+pub struct SpillConfig {
+    /// Audio waiting for the socket beyond which new messages go to disk.
+    pub threshold: Duration,
+    /// Most audio kept on disk, the oldest is dropped beyond it.
+    pub max_lag: Duration,
+    /// Speed of the replay relative to real time, above 1 for the transcript to catch up.
+    pub replay_speed: f32,
+    /// Directory of the spill file.
+    pub dir: PathBuf,
+}
+
+impl SpillConfig {
+    /// Spill after 2s of unsent audio, replay at twice real time, in the temp directory.
+    pub fn new(max_lag: Duration) -> Self {
+        Self {
+            threshold: Duration::from_secs(2),
+            max_lag,
+            replay_speed: 2.0,
+            dir: std::env::temp_dir(),
+        }
+    }
+}
+
+fn io_error(e: std::io::Error) -> SttError {
+    SttError::Message(format!("audio spill: {e}"))
+}
+
+/// Append-only file read from the front, emptied whenever the reader catches up. The file has
+/// no name and is only readable by the owner, the system deletes it even after a crash.
+struct SpillFile {
+    file: File,
+    read: u64,
+    write: u64,
+}
+
+impl SpillFile {
+    fn create(dir: &std::path::Path) -> std::io::Result<Self> {
+        Ok(Self {
+            file: tempfile::tempfile_in(dir)?,
+            read: 0,
+            write: 0,
+        })
+    }
+
+    fn append(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.write))?;
+        self.file.write_all(bytes)?;
+        self.write += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn take(&mut self, len: usize) -> std::io::Result<Vec<u8>> {
+        let mut bytes = vec![0u8; len];
+        self.file.seek(SeekFrom::Start(self.read))?;
+        self.file.read_exact(&mut bytes)?;
+        self.read += len as u64;
+        if self.read == self.write {
+            self.file.set_len(0)?;
+            (self.read, self.write) = (0, 0);
+        }
+        Ok(bytes)
+    }
+}
+
+pub(crate) struct Spill {
+    cfg: SpillConfig,
+    /// Audio messages written to the socket, counted by the send loop.
+    sent: Arc<AtomicU64>,
+    /// Audio messages given to the send loop.
+    forwarded: u64,
+    /// Seconds of the audio messages given to the send loop and not written yet, oldest first.
+    inflight: VecDeque<f64>,
+    /// Sample rate of the audio, as last set by `SetSampleRate`.
+    hz: f64,
+    file: Option<SpillFile>,
+    /// Encoded size and seconds of audio of the messages in the file, oldest first.
+    frames: VecDeque<(usize, f64)>,
+    spilled_s: f64,
+    /// Messages read from the file while dropping audio, ahead of the ones left in it.
+    held: VecDeque<InMsg>,
+    /// Start of the replay and the seconds of audio replayed since.
+    replay: Option<(Instant, f64)>,
+    dropped_s: f64,
+    warned: bool,
+}
+
+impl Spill {
+    pub(crate) fn new(cfg: SpillConfig, sent: Arc<AtomicU64>) -> Self {
+        Self {
+            cfg,
+            sent,
+            forwarded: 0,
+            inflight: VecDeque::new(),
+            hz: SAMPLE_RATE_HZ as f64,
+            file: None,
+            frames: VecDeque::new(),
+            spilled_s: 0.0,
+            held: VecDeque::new(),
+            replay: None,
+            dropped_s: 0.0,
+            warned: false,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.frames.is_empty() && self.held.is_empty()
+    }
+
+    /// Seconds of audio given to the send loop and not written to the socket yet.
+    fn backlog_s(&mut self) -> f64 {
+        let sent = self.sent.load(Ordering::Relaxed);
+        let pending = self.forwarded.saturating_sub(sent) as usize;
+        let done = self.inflight.len().saturating_sub(pending);
+        self.inflight.drain(..done);
+        self.inflight.iter().sum()
+    }
+
+    fn stalled(&mut self) -> bool {
+        self.backlog_s() >= self.cfg.threshold.as_secs_f64()
+    }
+
+    fn audio_s(&mut self, msg: &InMsg) -> f64 {
+        match msg {
+            InMsg::Audio { pcm } => pcm.len() as f64 / self.hz,
+            InMsg::SetSampleRate { hz } => {
+                self.hz = (*hz).max(1) as f64;
+                0.0
+            }
+            _ => 0.0,
+        }
+    }
+
+    fn forward(&mut self, msg: InMsg, audio_s: f64) -> InMsg {
+        if matches!(msg, InMsg::Audio { .. }) {
+            self.forwarded += 1;
+            self.inflight.push_back(audio_s);
+        }
+        msg
+    }
+
+    /// The message to send right away, or `None` when it went to the spill file.
+    pub(crate) fn push(&mut self, msg: InMsg) -> Result<Option<InMsg>> {
+        let audio_s = self.audio_s(&msg);
+        if self.is_empty() && !self.stalled() {
+            return Ok(Some(self.forward(msg, audio_s)));
+        }
+        let bytes = encode_in_msg(&msg)?;
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => self
+                .file
+                .insert(SpillFile::create(&self.cfg.dir).map_err(io_error)?),
+        };
+        if self.frames.is_empty() && self.held.is_empty() {
+            tracing::warn!(dir = %self.cfg.dir.display(), "connection stalled, spilling audio");
+        }
+        file.append(&bytes).map_err(io_error)?;
+        self.frames.push_back((bytes.len(), audio_s));
+        self.spilled_s += audio_s;
+        self.trim()?;
+        Ok(None)
+    }
+
+    fn take_frame(&mut self) -> Result<Option<(InMsg, f64)>> {
+        let (Some((len, audio_s)), Some(file)) = (self.frames.pop_front(), self.file.as_mut())
+        else {
+            return Ok(None);
+        };
+        let bytes = file.take(len).map_err(io_error)?;
+        self.spilled_s -= audio_s;
+        let msg = rmp_serde::from_slice(&bytes).map_err(|e| SttError::Message(e.to_string()))?;
+        Ok(Some((msg, audio_s)))
+    }
+
+    /// Drop the oldest audio beyond the max lag, keeping the other messages in order.
+    fn trim(&mut self) -> Result<()> {
+        while self.spilled_s > self.cfg.max_lag.as_secs_f64() {
+            let Some((msg, audio_s)) = self.take_frame()? else {
+                break;
+            };
+            match msg {
+                InMsg::Audio { .. } => self.dropped_s += audio_s,
+                msg => self.held.push_back(msg),
+            }
+        }
+        Ok(())
+    }
+
+    /// The next spilled message to send, once the connection drained and the replay speed
+    /// allows it.
+    pub(crate) fn pop(&mut self, now: Instant) -> Result<Option<InMsg>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        if self.stalled() {
+            self.replay = None;
+            return Ok(None);
+        }
+        let (start, replayed) = *self.replay.get_or_insert((now, 0.0));
+        let speed = self.cfg.replay_speed.max(1.0) as f64;
+        if replayed > (now - start).as_secs_f64() * speed {
+            return Ok(None);
+        }
+        let (msg, audio_s) = match self.held.pop_front() {
+            Some(msg) => (msg, 0.0),
+            None => match self.take_frame()? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            },
+        };
+        if let Some((_, replayed)) = self.replay.as_mut() {
+            *replayed += audio_s;
+        }
+        if self.is_empty() {
+            tracing::info!(dropped_s = self.dropped_s, "spilled audio replayed");
+            (self.replay, self.dropped_s, self.warned) = (None, 0.0, false);
+        }
+        Ok(Some(self.forward(msg, audio_s)))
+    }
+
+    /// Warning sent once per stall when audio starts being dropped.
+    fn drop_notice(&mut self) -> Option<String> {
+        if self.dropped_s == 0.0 || self.warned {
+            return None;
+        }
+        self.warned = true;
+        let max_lag = self.cfg.max_lag.as_secs_f64();
+        Some(format!(
+            "connection stalled for over {max_lag}s, dropping the oldest audio"
+        ))
+    }
+}
+
+/// Pass the commands of the sender to the send loop, through the spill file while the
+/// connection is stalled. A spill that cannot be written falls back to sending right away.
+pub(crate) async fn run(
+    mut spill: Spill,
+    mut rx: mpsc::Receiver<SendCmd>,
+    tx: mpsc::Sender<SendCmd>,
+    out_tx: mpsc::Sender<OutMsg>,
+) {
+    let mut open = true;
+    let mut closing = false;
+    loop {
+        if spill.is_empty() {
+            if closing {
+                let _ = tx.send(SendCmd::Close).await;
+                return;
+            }
+            if !open {
+                return;
+            }
+        }
+        let cmd = tokio::select! {
+            cmd = rx.recv(), if open && !closing => cmd,
+            _ = tokio::time::sleep(POLL_INTERVAL), if !spill.is_empty() => {
+                loop {
+                    match spill.pop(Instant::now()) {
+                        Ok(Some(msg)) => {
+                            if tx.send(SendCmd::Msg(msg)).await.is_err() {
+                                return;
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            let _ = out_tx.send(OutMsg::Error { message: e.to_string() }).await;
+                            return;
+                        }
+                    }
+                }
+                continue;
+            }
+        };
+        let cmd = match cmd {
+            None => {
+                open = false;
+                continue;
+            }
+            Some(SendCmd::Close) => {
+                closing = true;
+                continue;
+            }
+            Some(SendCmd::Msg(msg)) => match spill.push(msg.clone()) {
+                Ok(Some(msg)) => SendCmd::Msg(msg),
+                Ok(None) => {
+                    if let Some(message) = spill.drop_notice() {
+                        let _ = out_tx.send(OutMsg::Error { message }).await;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    tracing::warn!(%e, "cannot spill, sending right away");
+                    SendCmd::Msg(msg)
+                }
+            },
+            Some(cmd) => cmd,
+        };
+        if tx.send(cmd).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn audio(ms: usize) -> InMsg {
+        InMsg::Audio {
+            pcm: vec![0.1; ms * 24],
+        }
+    }
+
+    #[test]
+    fn spills_while_stalled_and_replays_in_order() {
+        let sent = Arc::new(AtomicU64::new(0));
+        let mut cfg = SpillConfig::new(Duration::from_secs(1));
+        cfg.threshold = Duration::from_millis(200);
+        let mut spill = Spill::new(cfg, sent.clone());
+
+        // Nothing is written to the socket, three 80ms chunks go out below the threshold.
+        assert!(spill.push(audio(80)).unwrap().is_some());
+        assert!(spill.push(audio(80)).unwrap().is_some());
+        assert!(spill.push(audio(80)).unwrap().is_some());
+        assert!(spill.push(audio(80)).unwrap().is_none());
+        assert!(spill.push(InMsg::Marker { id: 1 }).unwrap().is_none());
+        for _ in 0..15 {
+            assert!(spill.push(audio(80)).unwrap().is_none());
+        }
+        // 1.28s spilled, the oldest 320ms are dropped and the marker stays.
+        assert!((spill.dropped_s - 0.32).abs() < 1e-9);
+        assert!(spill.drop_notice().is_some());
+        assert!(spill.drop_notice().is_none());
+        let start = Instant::now();
+        assert!(spill.pop(start).unwrap().is_none());
+
+        // The connection drains and the replay goes at twice real time.
+        sent.store(3, Ordering::Relaxed);
+        assert_eq!(spill.pop(start).unwrap(), Some(InMsg::Marker { id: 1 }));
+        assert_eq!(spill.pop(start).unwrap(), Some(audio(80)));
+        assert!(spill.pop(start).unwrap().is_none());
+        sent.store(4, Ordering::Relaxed);
+        assert_eq!(
+            spill.pop(start + Duration::from_millis(40)).unwrap(),
+            Some(audio(80))
+        );
+        let mut now = start + Duration::from_millis(100);
+        let mut replayed = 2;
+        while let Some(msg) = spill.pop(now).unwrap() {
+            assert_eq!(msg, audio(80));
+            replayed += 1;
+            sent.store(replayed + 3, Ordering::Relaxed);
+            now += Duration::from_millis(50);
+        }
+        assert_eq!(replayed, 12);
+        assert!(spill.is_empty());
+        assert_eq!(spill.file.as_ref().unwrap().write, 0);
+        assert!(spill.push(audio(80)).unwrap().is_some());
+    }
+}
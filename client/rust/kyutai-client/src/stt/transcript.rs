@@ -1,4 +1,6 @@
-use crate::stt::types::WordTiming;
+pub mod align;
+
+use crate::stt::types::{Engine, WordTiming};
 
 #[derive(Clone, Debug, Default)]
 pub struct TranscriptAssembler {
@@ -9,6 +11,7 @@ pub struct TranscriptAssembler {
 struct PendingWord {
     text: String,
     start_time_s: f64,
+    lang: Option<String>,
 }
 
 impl TranscriptAssembler {
@@ -17,16 +20,29 @@ impl TranscriptAssembler {
     }
 
     pub fn push_word(&mut self, text: String, start_time: f64) -> Option<WordTiming> {
+        self.push_word_lang(text, start_time, None)
+    }
+
+    /// Same as [`Self::push_word`] for a word tagged with its language.
+    pub fn push_word_lang(
+        &mut self,
+        text: String,
+        start_time: f64,
+        lang: Option<String>,
+    ) -> Option<WordTiming> {
         let flushed = self.pending_word.take().map(|pending| WordTiming {
             word: pending.text,
             start_ms: sec_to_ms(pending.start_time_s),
             end_ms: sec_to_ms(pending.start_time_s),
             confidence: None,
+            lang: pending.lang,
+            engine: Engine::Server,
         });
 
         self.pending_word = Some(PendingWord {
             text,
             start_time_s: start_time,
+            lang,
         });
 
         flushed
@@ -47,6 +63,8 @@ impl TranscriptAssembler {
             start_ms,
             end_ms,
             confidence: None,
+            lang: pending.lang,
+            engine: Engine::Server,
         })
     }
 }
@@ -109,4 +127,16 @@ mod tests {
         assert_eq!(w.start_ms, 200);
         assert_eq!(w.end_ms, 300);
     }
+
+    #[test]
+    fn language_tag_is_kept() {
+        let mut a = TranscriptAssembler::new();
+        assert!(
+            a.push_word_lang("bonjour".to_string(), 0.1, Some("fr".to_string()))
+                .is_none()
+        );
+        let flushed = a.push_word("hello".to_string(), 0.5).unwrap();
+        assert_eq!(flushed.lang.as_deref(), Some("fr"));
+        assert!(a.push_end_word(0.8).unwrap().lang.is_none());
+    }
 }
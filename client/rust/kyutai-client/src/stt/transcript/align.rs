@@ -0,0 +1,256 @@
+//! Live alignment of the recognized words against the script being read, e.g. a speech on a
+//! teleprompter.
+//!
+//! A recognized word equal to one of the next few script words moves the reading position
+//! there. The script words jumped over are paired with the recognized words that matched
+//! nothing since the previous match as misreadings, the rest being skipped script words or
+//! extra words, so that off-script words are resolved on the next match. A reader jumping
+//! further ahead, e.g. to the next page, is found again once a few consecutive words match
+//! there. Going back over the script counts as extra words until the reader catches up.
+
+use crate::stt::wer::{ErrorCounts, normalize_words};
+
+/// Script words ahead of the reading position that a recognized word can match.
+const LOOKAHEAD: usize = 6;
+/// Consecutive words matching further in the script to move the reading position there.
+const RESYNC_WORDS: usize = 3;
+/// Unmatched words kept for the next match, older ones are reported as extra words.
+const MAX_PENDING: usize = 12;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScriptEvent {
+    /// The script word at `index` was read.
+    Read { index: usize },
+    /// The script word at `index` was read as `heard`.
+    Misread { index: usize, heard: String },
+    /// The script word at `index` was left out.
+    Skipped { index: usize },
+    /// A recognized word that is not in the script.
+    Extra { heard: String },
+}
+
+#[derive(Clone, Debug)]
+pub struct ScriptAligner {
+    /// Words of the script as written.
+    words: Vec<String>,
+    /// The same words normalized as in [`normalize_words`].
+    normalized: Vec<String>,
+    /// Index of the next script word expected.
+    position: usize,
+    /// Recognized words that matched nothing since the last match, as heard and normalized.
+    pending: Vec<(String, String)>,
+    counts: ErrorCounts,
+}
+
+impl ScriptAligner {
+    pub fn new(script: &str) -> Self {
+        let (words, normalized) = script
+            .split_whitespace()
+            .filter_map(|w| normalize_words(w).pop().map(|n| (w.to_string(), n)))
+            .unzip();
+        Self {
+            words,
+            normalized,
+            position: 0,
+            pending: vec![],
+            counts: ErrorCounts::default(),
+        }
+    }
+
+    /// Align a recognized word, or several separated by whitespace, and return the script
+    /// words and the recognized words resolved by it.
+    pub fn push(&mut self, text: &str) -> Vec<ScriptEvent> {
+        let mut events = vec![];
+        for heard in text.split_whitespace() {
+            if let Some(normalized) = normalize_words(heard).pop() {
+                self.push_word(heard.to_string(), normalized, &mut events)
+            }
+        }
+        events
+    }
+
+    fn push_word(&mut self, heard: String, normalized: String, events: &mut Vec<ScriptEvent>) {
+        let end = (self.position + LOOKAHEAD).min(self.normalized.len());
+        let ahead = &self.normalized[self.position..end];
+        if let Some(k) = ahead.iter().position(|w| *w == normalized) {
+            self.advance(self.position + k, events);
+            self.read(self.position + 1, events);
+            return;
+        }
+        self.pending.push((heard, normalized));
+        if let Some(start) = self.resync() {
+            let tail = self.pending.split_off(self.pending.len() - RESYNC_WORDS);
+            self.advance(start, events);
+            self.read(start + tail.len(), events);
+            return;
+        }
+        if self.pending.len() > MAX_PENDING {
+            let (heard, _) = self.pending.remove(0);
+            self.counts.insertions += 1;
+            events.push(ScriptEvent::Extra { heard });
+        }
+    }
+
+    /// Start of the last pending words in the script, past the lookahead.
+    fn resync(&self) -> Option<usize> {
+        let n = self.pending.len().checked_sub(RESYNC_WORDS)?;
+        let tail: Vec<&str> = self.pending[n..].iter().map(|(_, w)| w.as_str()).collect();
+        let from = (self.position + LOOKAHEAD).min(self.normalized.len());
+        self.normalized[from..]
+            .windows(RESYNC_WORDS)
+            .position(|window| window.iter().zip(tail.iter()).all(|(a, b)| a == b))
+            .map(|i| from + i)
+    }
+
+    /// Move the position to `target`, resolving the script words in between with the pending
+    /// words.
+    fn advance(&mut self, target: usize, events: &mut Vec<ScriptEvent>) {
+        let mut pending = std::mem::take(&mut self.pending).into_iter();
+        for index in self.position..target {
+            self.counts.reference_len += 1;
+            match pending.next() {
+                Some((heard, _)) => {
+                    self.counts.substitutions += 1;
+                    events.push(ScriptEvent::Misread { index, heard })
+                }
+                None => {
+                    self.counts.deletions += 1;
+                    events.push(ScriptEvent::Skipped { index })
+                }
+            }
+        }
+        for (heard, _) in pending {
+            self.counts.insertions += 1;
+            events.push(ScriptEvent::Extra { heard })
+        }
+        self.position = target;
+    }
+
+    /// Mark the script words from the position to `end` as read.
+    fn read(&mut self, end: usize, events: &mut Vec<ScriptEvent>) {
+        for index in self.position..end {
+            self.counts.reference_len += 1;
+            events.push(ScriptEvent::Read { index })
+        }
+        self.position = end;
+    }
+
+    /// Resolve the pending words at the end of the reading, as misreadings of the next script
+    /// words. The script words after them are not counted.
+    pub fn finish(&mut self) -> Vec<ScriptEvent> {
+        let mut events = vec![];
+        let target = (self.position + self.pending.len()).min(self.words.len());
+        self.advance(target, &mut events);
+        events
+    }
+
+    /// Script word at `index`, as written.
+    pub fn word(&self, index: usize) -> &str {
+        &self.words[index]
+    }
+
+    /// Number of words of the script.
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// Index of the next script word expected.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Errors over the script words read so far.
+    pub fn counts(&self) -> ErrorCounts {
+        self.counts
+    }
+
+    /// Share of the script words read so far that were read correctly, extra words counting
+    /// against it.
+    pub fn accuracy(&self) -> f64 {
+        if self.counts.reference_len == 0 {
+            return 1.0;
+        }
+        (1.0 - self.counts.rate()).max(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_all(aligner: &mut ScriptAligner, text: &str) -> Vec<ScriptEvent> {
+        let mut events: Vec<_> = text.split(' ').flat_map(|w| aligner.push(w)).collect();
+        events.extend(aligner.finish());
+        events
+    }
+
+    #[test]
+    fn follows_the_script() {
+        let mut aligner = ScriptAligner::new("Four score, and seven years ago our fathers");
+        assert_eq!(aligner.len(), 8);
+        let events = push_all(&mut aligner, "four score and eleven years ago um our");
+        assert_eq!(
+            events[3..],
+            [
+                ScriptEvent::Misread {
+                    index: 3,
+                    heard: "eleven".to_string()
+                },
+                ScriptEvent::Read { index: 4 },
+                ScriptEvent::Read { index: 5 },
+                ScriptEvent::Extra {
+                    heard: "um".to_string()
+                },
+                ScriptEvent::Read { index: 6 },
+            ]
+        );
+        assert_eq!(aligner.position(), 7);
+        let counts = aligner.counts();
+        assert_eq!(
+            (
+                counts.reference_len,
+                counts.substitutions,
+                counts.insertions
+            ),
+            (7, 1, 1)
+        );
+        assert!((aligner.accuracy() - 5.0 / 7.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn skipped_words_and_jumps() {
+        let script = "one two three four five six seven eight nine ten eleven twelve thirteen";
+        let mut aligner = ScriptAligner::new(script);
+        let events = push_all(&mut aligner, "one three");
+        assert_eq!(events[1], ScriptEvent::Skipped { index: 1 });
+        assert_eq!(aligner.position(), 3);
+        // Too far for the lookahead, found again after three words.
+        assert!(aligner.push("eleven").is_empty());
+        assert!(aligner.push("twelve").is_empty());
+        let events = aligner.push("thirteen");
+        assert_eq!(events.len(), 10);
+        assert_eq!(events[0], ScriptEvent::Skipped { index: 3 });
+        assert_eq!(events[9], ScriptEvent::Read { index: 12 });
+        assert_eq!(aligner.position(), 13);
+        assert_eq!(aligner.counts().deletions, 8);
+    }
+
+    #[test]
+    fn unmatched_words_at_the_end() {
+        let mut aligner = ScriptAligner::new("hello world");
+        let events = push_all(&mut aligner, "hello word");
+        assert_eq!(
+            events[1],
+            ScriptEvent::Misread {
+                index: 1,
+                heard: "word".to_string()
+            }
+        );
+        assert_eq!(aligner.word(1), "world");
+        assert!(ScriptAligner::new(" -- ").is_empty());
+    }
+}
@@ -1,9 +1,21 @@
+/// Where a word was recognized.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Engine {
+    #[default]
+    Server,
+    /// The in-process model used when no server can be reached.
+    Local,
+}
+
 #[derive(Clone, Debug)]
 pub struct WordTiming {
     pub word: String,
     pub start_ms: u64,
     pub end_ms: u64,
     pub confidence: Option<f32>,
+    /// Language of the word, for multilingual models on servers with `word_lang` enabled.
+    pub lang: Option<String>,
+    pub engine: Engine,
 }
 
 #[derive(Clone, Debug)]
@@ -13,10 +25,16 @@ pub struct Utterance {
 
 #[derive(Clone, Debug)]
 pub enum SttEvent {
-    Ready,
+    /// The session is set up. `request_id` and `trace_id` identify the upgrade request in the
+    /// logs and traces of the gateway and the server, when the gateway set them.
+    Ready {
+        request_id: Option<String>,
+        trace_id: Option<String>,
+    },
     WordReceived {
         text: String,
         start_ms: u64,
+        engine: Engine,
     },
     WordFinalized(WordTiming),
     UtterancePartial(Utterance),
@@ -26,8 +44,69 @@ pub enum SttEvent {
         prs: Vec<f32>,
         buffered_pcm: usize,
     },
+    /// Words from now on come from `engine`.
+    EngineChanged {
+        engine: Engine,
+    },
+    /// Answer to a marker, once the audio sent before it has been transcribed. `sample_idx` is
+    /// the position of the marker in the session audio, in 24kHz samples, on the same timeline
+    /// as the word timestamps, and `step_idx` the model step that consumed it.
     StreamMarker {
         id: i64,
+        sample_idx: u64,
+        step_idx: usize,
+    },
+    /// The server started segment `index` of a long session at `start_ms`, the words before it
+    /// are final.
+    SegmentBoundary {
+        index: usize,
+        start_ms: u64,
+    },
+    /// Periodic statistics of the audio received by the server, see
+    /// [`SttClientBuilder::stats`](crate::stt::SttClientBuilder::stats).
+    AudioStats {
+        rms_db: f32,
+        clipping_ratio: f32,
+        speech_ratio: f32,
+    },
+    SpeechRate {
+        wpm: f32,
+    },
+    /// Periodic share of overlapping speech, see
+    /// [`SttClientBuilder::speakers`](crate::stt::SttClientBuilder::speakers).
+    Overlap {
+        ratio: f32,
+    },
+    /// Rough number of voices heard so far, received before the matching `StreamMarker`.
+    SpeakerCount {
+        n: usize,
+    },
+    /// One of the phrases of
+    /// [`SttClientBuilder::spot`](crate::stt::SttClientBuilder::spot) was heard, starting at
+    /// `start_ms`.
+    PhraseSpotted {
+        phrase: String,
+        start_ms: u64,
+    },
+    /// The server settings changed during the session, the words that follow are produced
+    /// with them.
+    ConfigUpdate {
+        asr_delay_ms: u64,
+        temperature: f64,
+    },
+    /// The server enrolled the voice sent with `InMsg::Enroll`.
+    Enrolled {
+        voiced_ms: u64,
+    },
+    /// Answer to a latency probe, see
+    /// [`SttClientBuilder::latency`](crate::stt::SttClientBuilder::latency). The clock offset
+    /// is the server clock minus the local one, the word latency is the median over the `words`
+    /// received since the previous report, from the capture of their first sample.
+    LatencyReport {
+        rtt_ms: u64,
+        clock_offset_ms: i64,
+        word_latency_ms: Option<u64>,
+        words: usize,
     },
     Error {
         message: String,
@@ -0,0 +1,363 @@
+//! Browser transport, over the `WebSocket` of the page (`web` feature on wasm32).
+//!
+//! The API follows the `ws` module of native targets so that the same code drives both.
+//! Browsers cannot set headers on a WebSocket, the JWT goes in the `token` query parameter.
+//! There is no failover, reconnection nor local fallback, the audio comes from the page (an
+//! `AudioWorklet` for instance) as `InMsg::Audio`.
+
+use crate::stt::error::{Result, ServerClose, SttError};
+use crate::stt::events::EventAssembler;
+use crate::stt::protocol::{InMsg, OutMsg, decode_out_msgs, encode_in_msg};
+use crate::stt::types::SttEvent;
+
+use futures_util::future::{Either, select};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use url::Url;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{BinaryType, CloseEvent, Event, MessageEvent, WebSocket};
+
+fn js_error(err: JsValue) -> SttError {
+    SttError::Message(format!("{err:?}"))
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SttClientBuilder {
+    url: Option<String>,
+    query_token: Option<String>,
+    stream_id: Option<String>,
+    segment_s: Option<f64>,
+    stats_interval: Option<Duration>,
+    speakers_interval: Option<Duration>,
+    spot: Vec<String>,
+    itn: bool,
+    coalesce: Option<Duration>,
+}
+
+impl SttClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+
+    /// JWT sent in the `token` query parameter.
+    pub fn query_token(mut self, token: impl Into<String>) -> Self {
+        self.query_token = Some(token.into());
+        self
+    }
+
+    pub fn stream_id(mut self, id: impl Into<String>) -> Self {
+        self.stream_id = Some(id.into());
+        self
+    }
+
+    pub fn segment_s(mut self, seconds: f64) -> Self {
+        self.segment_s = Some(seconds);
+        self
+    }
+
+    pub fn stats(mut self, interval: Duration) -> Self {
+        self.stats_interval = Some(interval);
+        self
+    }
+
+    pub fn speakers(mut self, interval: Duration) -> Self {
+        self.speakers_interval = Some(interval);
+        self
+    }
+
+    pub fn spot<S: Into<String>>(mut self, phrases: impl IntoIterator<Item = S>) -> Self {
+        self.spot = phrases.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn itn(mut self, enabled: bool) -> Self {
+        self.itn = enabled;
+        self
+    }
+
+    pub fn coalesce(mut self, interval: Duration) -> Self {
+        self.coalesce = Some(interval);
+        self
+    }
+
+    pub async fn connect(self) -> Result<SttSession> {
+        let base = self
+            .url
+            .ok_or_else(|| SttError::Message("missing websocket url".to_string()))?;
+        let mut url = Url::parse(&base).map_err(|e| SttError::Message(e.to_string()))?;
+        {
+            let mut pairs = url.query_pairs_mut();
+            if let Some(id) = self.stream_id.as_deref() {
+                pairs.append_pair("stream_id", id);
+            }
+            if let Some(segment_s) = self.segment_s {
+                pairs.append_pair("segment_s", &segment_s.to_string());
+            }
+            if let Some(interval) = self.stats_interval {
+                pairs.append_pair("stats_interval_s", &interval.as_secs_f64().to_string());
+            }
+            if let Some(interval) = self.speakers_interval {
+                pairs.append_pair("speakers_interval_s", &interval.as_secs_f64().to_string());
+            }
+            if !self.spot.is_empty() {
+                let spot = serde_json::to_string(&self.spot)
+                    .map_err(|e| SttError::Message(e.to_string()))?;
+                pairs.append_pair("spot", &spot);
+            }
+            if self.itn {
+                pairs.append_pair("itn", "true");
+            }
+            if let Some(interval) = self.coalesce {
+                pairs.append_pair("coalesce_ms", &interval.as_millis().to_string());
+            }
+            if let Some(token) = self.query_token.as_deref() {
+                pairs.append_pair("token", token);
+            }
+        }
+
+        let ws = WebSocket::new(url.as_str()).map_err(js_error)?;
+        ws.set_binary_type(BinaryType::Arraybuffer);
+        let (out_tx, out_rx) = mpsc::unbounded_channel::<OutMsg>();
+        let (open_tx, open_rx) = oneshot::channel::<Result<()>>();
+        let open_tx = Rc::new(RefCell::new(Some(open_tx)));
+        // Dropped on close so that the event stream ends.
+        let out_tx = Rc::new(RefCell::new(Some(out_tx)));
+        let closed = Rc::new(RefCell::new(None));
+
+        let on_open = Closure::<dyn FnMut(Event)>::new({
+            let open_tx = open_tx.clone();
+            move |_: Event| {
+                if let Some(tx) = open_tx.borrow_mut().take() {
+                    let _ = tx.send(Ok(()));
+                }
+            }
+        });
+        let on_message = Closure::<dyn FnMut(MessageEvent)>::new({
+            let out_tx = out_tx.clone();
+            move |ev: MessageEvent| {
+                let data = js_sys::Uint8Array::new(&ev.data()).to_vec();
+                let msgs = match decode_out_msgs(&data) {
+                    Ok(msgs) => msgs,
+                    Err(err) => {
+                        tracing::warn!(%err, "failed to decode OutMsg, skipping message");
+                        return;
+                    }
+                };
+                if let Some(tx) = out_tx.borrow().as_ref() {
+                    for msg in msgs {
+                        let _ = tx.send(msg);
+                    }
+                }
+            }
+        });
+        let on_error = Closure::<dyn FnMut(Event)>::new({
+            let open_tx = open_tx.clone();
+            move |_: Event| {
+                if let Some(tx) = open_tx.borrow_mut().take() {
+                    let _ = tx.send(Err(SttError::Message("websocket error".to_string())));
+                }
+            }
+        });
+        let on_close = Closure::<dyn FnMut(CloseEvent)>::new({
+            let closed = closed.clone();
+            move |ev: CloseEvent| {
+                let close = ServerClose::new(ev.code(), &ev.reason());
+                if let Some(tx) = open_tx.borrow_mut().take() {
+                    let _ = tx.send(Err(SttError::Closed(close)));
+                    return;
+                }
+                if let Some(tx) = out_tx.borrow_mut().take()
+                    && ev.code() != 1000
+                {
+                    let _ = tx.send(OutMsg::Error {
+                        message: close.to_string(),
+                    });
+                    *closed.borrow_mut() = Some(close);
+                }
+            }
+        });
+        ws.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        ws.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        ws.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+        ws.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+        let socket = Socket {
+            ws: ws.clone(),
+            _on_open: on_open,
+            _on_message: on_message,
+            _on_error: on_error,
+            _on_close: on_close,
+        };
+
+        match open_rx.await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => return Err(err),
+            Err(_) => return Err(SttError::Message("websocket closed".to_string())),
+        }
+        Ok(SttSession {
+            sender: SttSender { ws },
+            out_rx,
+            closed,
+            _socket: socket,
+        })
+    }
+}
+
+/// The socket and its handlers, which are removed before the closures are dropped.
+struct Socket {
+    ws: WebSocket,
+    _on_open: Closure<dyn FnMut(Event)>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_error: Closure<dyn FnMut(Event)>,
+    _on_close: Closure<dyn FnMut(CloseEvent)>,
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        self.ws.set_onopen(None);
+        self.ws.set_onmessage(None);
+        self.ws.set_onerror(None);
+        self.ws.set_onclose(None);
+        let _ = self.ws.close();
+    }
+}
+
+pub struct SttSession {
+    sender: SttSender,
+    out_rx: mpsc::UnboundedReceiver<OutMsg>,
+    /// Set when the server closed the socket with an error code.
+    closed: Rc<RefCell<Option<ServerClose>>>,
+    _socket: Socket,
+}
+
+impl SttSession {
+    pub fn sender(&self) -> SttSender {
+        self.sender.clone()
+    }
+
+    pub async fn recv(&mut self) -> Result<OutMsg> {
+        let msg = self.out_rx.recv().await;
+        msg.ok_or_else(|| self.ended())
+    }
+
+    /// Error for a session whose messages are over, [`SttError::Closed`] when the server
+    /// closed it.
+    fn ended(&self) -> SttError {
+        match self.closed.borrow().clone() {
+            Some(close) => SttError::Closed(close),
+            None => SttError::Message("websocket closed".to_string()),
+        }
+    }
+
+    /// Closes the socket, words still in flight are lost: send a `Marker` after the audio
+    /// and wait for it first to get them.
+    pub async fn shutdown(self) -> Result<()> {
+        self.sender.close().await
+    }
+
+    pub fn into_event_stream(self) -> SttEventStream {
+        SttEventStream {
+            session: self,
+            events: EventAssembler::new(),
+            start_ms: js_sys::Date::now(),
+        }
+    }
+}
+
+pub struct SttEventStream {
+    session: SttSession,
+    events: EventAssembler,
+    start_ms: f64,
+}
+
+impl SttEventStream {
+    pub fn utterance_finalize_delay(mut self, delay: Duration) -> Self {
+        self.events.utterance_finalize_delay = delay;
+        self
+    }
+
+    pub fn utterance_partial_interval(mut self, interval: Duration) -> Self {
+        self.events.utterance_partial_min_interval = interval;
+        self
+    }
+
+    pub fn sender(&self) -> SttSender {
+        self.session.sender()
+    }
+
+    fn elapsed(&self) -> Duration {
+        Duration::from_secs_f64(((js_sys::Date::now() - self.start_ms) / 1000.0).max(0.0))
+    }
+
+    pub async fn recv(&mut self) -> Result<SttEvent> {
+        loop {
+            if let Some(ev) = self.events.pop() {
+                return Ok(ev);
+            }
+
+            let wait = self
+                .events
+                .deadline()
+                .map(|d| d.saturating_sub(self.elapsed()));
+            let recv = self.session.out_rx.recv();
+            let msg = match wait {
+                Some(wait) => match select(std::pin::pin!(recv), std::pin::pin!(sleep(wait))).await
+                {
+                    Either::Left((msg, _)) => msg,
+                    Either::Right(_) => {
+                        if let Some(ev) = self.events.finalize_utterance() {
+                            return Ok(ev);
+                        }
+                        continue;
+                    }
+                },
+                None => recv.await,
+            };
+            let msg = msg.ok_or_else(|| self.session.ended())?;
+            let now = self.elapsed();
+            self.events.handle(msg, now);
+        }
+    }
+
+    pub async fn shutdown(self) -> Result<()> {
+        self.session.shutdown().await
+    }
+}
+
+/// Resolves after `duration`, with the `setTimeout` of the page or of the worker.
+async fn sleep(duration: Duration) {
+    let ms = duration.as_millis().min(i32::MAX as u128) as i32;
+    let promise = js_sys::Promise::new(&mut |resolve, _| {
+        let set_timeout = js_sys::Reflect::get(&js_sys::global(), &JsValue::from_str("setTimeout"))
+            .ok()
+            .and_then(|f| f.dyn_into::<js_sys::Function>().ok());
+        if let Some(set_timeout) = set_timeout {
+            let _ = set_timeout.call2(&JsValue::NULL, &resolve, &JsValue::from(ms));
+        }
+    });
+    let _ = wasm_bindgen_futures::JsFuture::from(promise).await;
+}
+
+#[derive(Clone, Debug)]
+pub struct SttSender {
+    ws: WebSocket,
+}
+
+impl SttSender {
+    /// Queues the message on the socket, the browser buffers it without back-pressure.
+    pub async fn send(&self, msg: InMsg) -> Result<()> {
+        let bytes = encode_in_msg(&msg)?;
+        self.ws.send_with_u8_array(&bytes).map_err(js_error)
+    }
+
+    pub async fn close(&self) -> Result<()> {
+        self.ws.close().map_err(js_error)
+    }
+}
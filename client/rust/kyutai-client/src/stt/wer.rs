@@ -0,0 +1,194 @@
+//! Word and character error rates with the underlying edit alignment.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Edit<T> {
+    Equal(T),
+    Substitute { reference: T, hypothesis: T },
+    Delete(T),
+    Insert(T),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ErrorCounts {
+    pub reference_len: usize,
+    pub substitutions: usize,
+    pub deletions: usize,
+    pub insertions: usize,
+}
+
+impl ErrorCounts {
+    pub fn errors(&self) -> usize {
+        self.substitutions + self.deletions + self.insertions
+    }
+
+    /// Error rate relative to the reference length, an empty reference counts as one token.
+    pub fn rate(&self) -> f64 {
+        self.errors() as f64 / self.reference_len.max(1) as f64
+    }
+
+    pub fn add(&mut self, other: &ErrorCounts) {
+        self.reference_len += other.reference_len;
+        self.substitutions += other.substitutions;
+        self.deletions += other.deletions;
+        self.insertions += other.insertions;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Alignment<T> {
+    pub edits: Vec<Edit<T>>,
+}
+
+impl<T> Alignment<T> {
+    pub fn counts(&self) -> ErrorCounts {
+        let mut counts = ErrorCounts::default();
+        for edit in self.edits.iter() {
+            match edit {
+                Edit::Equal(_) => counts.reference_len += 1,
+                Edit::Substitute { .. } => {
+                    counts.reference_len += 1;
+                    counts.substitutions += 1
+                }
+                Edit::Delete(_) => {
+                    counts.reference_len += 1;
+                    counts.deletions += 1
+                }
+                Edit::Insert(_) => counts.insertions += 1,
+            }
+        }
+        counts
+    }
+}
+
+/// Minimum edit distance alignment between `reference` and `hypothesis`.
+pub fn align<T: PartialEq + Clone>(reference: &[T], hypothesis: &[T]) -> Alignment<T> {
+    let (n, m) = (reference.len(), hypothesis.len());
+    let width = m + 1;
+    let mut cost = vec![0usize; (n + 1) * width];
+    for i in 0..=n {
+        cost[i * width] = i;
+    }
+    for (j, c) in cost[..width].iter_mut().enumerate() {
+        *c = j;
+    }
+    for i in 1..=n {
+        for j in 1..=m {
+            let sub = usize::from(reference[i - 1] != hypothesis[j - 1]);
+            let diag = cost[(i - 1) * width + j - 1] + sub;
+            let del = cost[(i - 1) * width + j] + 1;
+            let ins = cost[i * width + j - 1] + 1;
+            cost[i * width + j] = diag.min(del).min(ins);
+        }
+    }
+
+    let mut edits = Vec::with_capacity(n.max(m));
+    let (mut i, mut j) = (n, m);
+    // On ties, prefer matches, then insertions and deletions, then substitutions so that an
+    // extra or missing word does not shift the alignment into a run of substitutions.
+    while i > 0 || j > 0 {
+        let c = cost[i * width + j];
+        if i > 0
+            && j > 0
+            && reference[i - 1] == hypothesis[j - 1]
+            && c == cost[(i - 1) * width + j - 1]
+        {
+            edits.push(Edit::Equal(reference[i - 1].clone()));
+            i -= 1;
+            j -= 1;
+        } else if j > 0 && c == cost[i * width + j - 1] + 1 {
+            edits.push(Edit::Insert(hypothesis[j - 1].clone()));
+            j -= 1;
+        } else if i > 0 && c == cost[(i - 1) * width + j] + 1 {
+            edits.push(Edit::Delete(reference[i - 1].clone()));
+            i -= 1;
+        } else {
+            edits.push(Edit::Substitute {
+                reference: reference[i - 1].clone(),
+                hypothesis: hypothesis[j - 1].clone(),
+            });
+            i -= 1;
+            j -= 1;
+        }
+    }
+    edits.reverse();
+    Alignment { edits }
+}
+
+/// Lowercase the text, drop punctuation (apostrophes inside words are kept) and split on
+/// whitespace so that formatting differences do not count as errors.
+pub fn normalize_words(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .map(|w| {
+            w.chars()
+                .filter(|c| c.is_alphanumeric() || *c == '\'')
+                .flat_map(char::to_lowercase)
+                .collect::<String>()
+        })
+        .map(|w| w.trim_matches('\'').to_string())
+        .filter(|w| !w.is_empty())
+        .collect()
+}
+
+pub fn word_alignment(reference: &str, hypothesis: &str) -> Alignment<String> {
+    align(&normalize_words(reference), &normalize_words(hypothesis))
+}
+
+/// Character alignment on the normalized texts, words are joined by a single space.
+pub fn char_alignment(reference: &str, hypothesis: &str) -> Alignment<char> {
+    let chars = |s: &str| normalize_words(s).join(" ").chars().collect::<Vec<_>>();
+    align(&chars(reference), &chars(hypothesis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identical_transcripts_have_no_errors() {
+        let a = word_alignment("Hello, world!", "hello world");
+        assert_eq!(a.counts().errors(), 0);
+        assert_eq!(a.counts().reference_len, 2);
+        assert_eq!(a.counts().rate(), 0.0);
+    }
+
+    #[test]
+    fn counts_each_edit_kind() {
+        let a = word_alignment("the cat sat on the mat", "the bat sat on mat today");
+        let c = a.counts();
+        assert_eq!(c.reference_len, 6);
+        assert_eq!(c.substitutions, 1);
+        assert_eq!(c.deletions, 1);
+        assert_eq!(c.insertions, 1);
+        assert!((c.rate() - 0.5).abs() < 1e-9);
+        assert_eq!(
+            a.edits[1],
+            Edit::Substitute {
+                reference: "cat".to_string(),
+                hypothesis: "bat".to_string()
+            }
+        );
+        assert_eq!(a.edits.last(), Some(&Edit::Insert("today".to_string())));
+    }
+
+    #[test]
+    fn char_error_rate() {
+        let c = char_alignment("kitten", "sitting").counts();
+        assert_eq!(c.reference_len, 6);
+        assert_eq!(c.errors(), 3);
+    }
+
+    #[test]
+    fn empty_sides() {
+        assert_eq!(word_alignment("", "").counts().rate(), 0.0);
+        assert_eq!(word_alignment("", "hi there").counts().insertions, 2);
+        assert_eq!(word_alignment("a b", "").counts().deletions, 2);
+    }
+
+    #[test]
+    fn normalization_keeps_contractions() {
+        assert_eq!(
+            normalize_words("Don't STOP -- 'now'."),
+            vec!["don't", "stop", "now"]
+        );
+    }
+}
@@ -1,71 +1,103 @@
-use crate::stt::error::{Result, SttError};
-use crate::stt::protocol::{InMsg, OutMsg, decode_out_msg, encode_in_msg, encode_in_msg_into};
-use crate::stt::transcript::TranscriptAssembler;
-use crate::stt::types::{SttEvent, Utterance};
+use crate::stt::error::{Result, Retry, ServerClose, SttError};
+use crate::stt::events::EventAssembler;
+use crate::stt::failover::{AudioJournal, SAMPLE_RATE_HZ, connect_first};
+use crate::stt::latency::{LatencyMeter, unix_ms};
+use crate::stt::protocol::{InMsg, OutMsg, decode_out_msgs, encode_in_msg, encode_in_msg_into};
+use crate::stt::spill::{Spill, SpillConfig};
+use crate::stt::types::SttEvent;
 
-use futures_util::{SinkExt, StreamExt};
 use futures_util::stream::SplitStream;
-use std::collections::VecDeque;
+use futures_util::{SinkExt, StreamExt};
+use kyutai_client_core::auth::BetterAuthClient;
+use kyutai_client_core::capabilities;
+use kyutai_client_core::ws::{WsStream, build_ws_url, connect_ws, redact_ws_url};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 use tokio::time::{Instant, sleep, sleep_until, timeout};
 use tokio_tungstenite::tungstenite::Message;
-use kyutai_client_core::ws::{WsStream, connect_ws, build_ws_url};
+use url::Url;
 type WsRead = SplitStream<WsStream>;
 
 const SHUTDOWN_FLUSH_MARKER_ID: i64 = i64::MIN + 1;
 const SHUTDOWN_FLUSH_CHUNK_SAMPLES: usize = 1920;
 const SHUTDOWN_FLUSH_CHUNK_DELAY: Duration = Duration::from_millis(80);
 const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+const REPLAY_CHUNK_SAMPLES: usize = 1920;
+/// Query parameters that older servers may not support, with their `/api/capabilities` feature.
+const FEATURES: &[(&str, &str)] = &[
+    ("segment_s", "segmentation"),
+    ("stats_interval_s", "audio_stats"),
+    ("speakers_interval_s", "speaker_stats"),
+    ("spot", "phrase_spotting"),
+    ("itn", "itn"),
+    ("coalesce_ms", "coalesce"),
+];
 
 #[derive(Debug)]
-enum SendCmd {
+pub(crate) enum SendCmd {
     Msg(InMsg),
     Raw(Vec<u8>),
     Close,
 }
 
+#[cfg(feature = "local")]
+type LocalFallback = crate::stt::local::LocalAsrConfig;
+#[cfg(not(feature = "local"))]
+type LocalFallback = std::convert::Infallible;
+
+#[cfg(feature = "local-mimi")]
+type LocalMimi = crate::stt::mimi::LocalMimiConfig;
+#[cfg(not(feature = "local-mimi"))]
+type LocalMimi = std::convert::Infallible;
+#[cfg(feature = "local-mimi")]
+type MimiEncoder = crate::stt::mimi::MimiEncoder;
+#[cfg(not(feature = "local-mimi"))]
+type MimiEncoder = std::convert::Infallible;
+
 enum RecvOutcome {
     Closed { code: u16, reason: String },
     Error(String),
     Eof,
 }
 
-fn is_retryable_close_code(code: u16) -> bool {
-    matches!(code, 4000 | 4004 | 4005 | 4006 | 1012 | 1013)
-}
-
-fn close_code_message(code: u16, reason: &str) -> String {
-    let reason = reason.trim();
-    let reason_suffix = if reason.is_empty() {
-        String::new()
+/// Replace `token` with one from `source` for the next connection, a new one when the server
+/// turned the previous one down.
+async fn fresh_token(
+    source: Option<&BetterAuthClient>,
+    rejected: bool,
+    token: &mut Option<String>,
+) {
+    let Some(source) = source else {
+        return;
+    };
+    let fetched = if rejected {
+        source.refresh().await
     } else {
-        format!(" (reason: {reason})")
+        source.token().await
     };
-
-    match code {
-        4000 => format!("server at capacity (close code 4000){reason_suffix}"),
-        4001 => format!("authentication failed (close code 4001){reason_suffix}"),
-        4002 => format!("session timeout (close code 4002){reason_suffix}"),
-        4003 => format!("invalid message (close code 4003){reason_suffix}"),
-        4004 => format!("rate limited (close code 4004){reason_suffix}"),
-        4005 => format!("resource unavailable (close code 4005){reason_suffix}"),
-        4006 => format!("client timeout (close code 4006){reason_suffix}"),
-        other => format!("websocket closed (code {other}){reason_suffix}"),
+    match fetched {
+        Ok(fetched) => *token = Some(fetched),
+        Err(err) => tracing::warn!(?err, "cannot refresh the auth token"),
     }
 }
 
 // Replaced by kyutai_client_core::ws::connect_ws
 
+/// `offset` is the session time at which the connection audio starts, added to the word
+/// timestamps, and `confirmed` tracks the end of the last finalized word in samples.
 fn spawn_recv_task(
     mut ws_read: WsRead,
     out_tx: mpsc::Sender<OutMsg>,
+    offset: f64,
+    confirmed: Arc<AtomicU64>,
 ) -> mpsc::Receiver<RecvOutcome> {
     let (done_tx, done_rx) = mpsc::channel(1);
 
     tokio::spawn(async move {
-        let outcome = loop {
+        let outcome = 'recv: loop {
             let Some(item) = ws_read.next().await else {
                 break RecvOutcome::Eof;
             };
@@ -77,13 +109,25 @@ fn spawn_recv_task(
 
             match msg {
                 Message::Binary(bytes) => {
-                    let out = match decode_out_msg(bytes.as_ref()) {
-                        Ok(out) => out,
+                    let msgs = match decode_out_msgs(bytes.as_ref()) {
+                        Ok(msgs) => msgs,
                         Err(e) => break RecvOutcome::Error(format!("protocol decode error: {e}")),
                     };
+                    for mut out in msgs {
+                        match &mut out {
+                            OutMsg::Word { start_time, .. } => *start_time += offset,
+                            OutMsg::SegmentBoundary { start_s, .. } => *start_s += offset,
+                            OutMsg::EndWord { stop_time } => {
+                                *stop_time += offset;
+                                let samples = (*stop_time * SAMPLE_RATE_HZ as f64) as u64;
+                                confirmed.fetch_max(samples, Ordering::Relaxed);
+                            }
+                            _ => {}
+                        }
 
-                    if out_tx.send(out).await.is_err() {
-                        break RecvOutcome::Error("recv consumer dropped".to_string());
+                        if out_tx.send(out).await.is_err() {
+                            break 'recv RecvOutcome::Error("recv consumer dropped".to_string());
+                        }
                     }
                 }
                 Message::Close(frame) => {
@@ -104,9 +148,105 @@ fn spawn_recv_task(
     done_rx
 }
 
+/// Transcribe the rest of the session with the local model, starting with `replay`, the
+/// audio at session time `offset` that no server finalized.
+#[cfg(feature = "local")]
+async fn continue_locally(
+    cfg: LocalFallback,
+    reason: String,
+    mut rx: mpsc::Receiver<SendCmd>,
+    out_tx: mpsc::Sender<OutMsg>,
+    (replay, offset): (Vec<f32>, f64),
+) -> Result<()> {
+    tracing::warn!(reason, "continuing with the local model");
+    let message = format!("{reason}; continuing with the local model");
+    let _ = out_tx.send(OutMsg::Error { message }).await;
+    let id = crate::stt::events::LOCAL_ENGINE_MARKER_ID;
+    let _ = out_tx
+        .send(OutMsg::Marker {
+            id,
+            sample_idx: 0,
+            step_idx: 0,
+        })
+        .await;
+    let local_tx = crate::stt::local::spawn(cfg, offset, out_tx);
+    if !replay.is_empty() {
+        let _ = local_tx.send(InMsg::Audio { pcm: replay });
+    }
+    while let Some(cmd) = rx.recv().await {
+        match cmd {
+            SendCmd::Msg(msg) => {
+                if local_tx.send(msg).is_err() {
+                    break;
+                }
+            }
+            SendCmd::Raw(_) => {}
+            SendCmd::Close => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "local-mimi")]
+async fn load_mimi(cfg: LocalMimi) -> Result<MimiEncoder> {
+    tokio::task::spawn_blocking(move || MimiEncoder::load(&cfg))
+        .await
+        .map_err(|e| SttError::Message(e.to_string()))?
+        .map_err(|e| SttError::Message(format!("cannot load the Mimi encoder: {e:#}")))
+}
+
+#[cfg(not(feature = "local-mimi"))]
+async fn load_mimi(cfg: LocalMimi) -> Result<MimiEncoder> {
+    match cfg {}
+}
+
+/// The message carrying `pcm`, as the codes of the frames completed so far when encoding
+/// locally, `None` until a frame is complete.
+#[cfg(feature = "local-mimi")]
+fn audio_msg(mimi: Option<&mut MimiEncoder>, pcm: Vec<f32>) -> Result<Option<InMsg>> {
+    let Some(mimi) = mimi else {
+        return Ok(Some(InMsg::Audio { pcm }));
+    };
+    let codes = mimi
+        .encode(&pcm)
+        .map_err(|e| SttError::Message(format!("mimi encoding: {e:#}")))?;
+    Ok((!codes.is_empty()).then_some(InMsg::AudioCodes { codes }))
+}
+
+#[cfg(not(feature = "local-mimi"))]
+fn audio_msg(mimi: Option<&mut MimiEncoder>, pcm: Vec<f32>) -> Result<Option<InMsg>> {
+    match mimi {
+        Some(mimi) => match *mimi {},
+        None => Ok(Some(InMsg::Audio { pcm })),
+    }
+}
+
+#[cfg(feature = "local-mimi")]
+fn reset_mimi(mimi: &mut MimiEncoder) {
+    mimi.reset()
+}
+
+#[cfg(not(feature = "local-mimi"))]
+fn reset_mimi(mimi: &mut MimiEncoder) {
+    match *mimi {}
+}
+
+#[cfg(not(feature = "local"))]
+async fn continue_locally(
+    cfg: LocalFallback,
+    _: String,
+    _: mpsc::Receiver<SendCmd>,
+    _: mpsc::Sender<OutMsg>,
+    _: (Vec<f32>, f64),
+) -> Result<()> {
+    match cfg {}
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::stt::events::LOCAL_ENGINE_MARKER_ID;
+    use crate::stt::types::Engine;
 
     fn dummy_session(out_rx: mpsc::Receiver<OutMsg>) -> SttSession {
         let (tx, _rx) = mpsc::channel::<SendCmd>(1);
@@ -116,11 +256,12 @@ mod tests {
         let keepalive_loop: JoinHandle<Result<()>> = tokio::spawn(async move { Ok(()) });
 
         SttSession {
-            sender: SttSender { tx },
+            sender: SttSender { tx, latency: None },
             send_loop,
             recv_loop,
             keepalive_loop,
             out_rx,
+            closed: Arc::default(),
         }
     }
 
@@ -137,14 +278,16 @@ mod tests {
             .send(OutMsg::Word {
                 text: "hello".to_string(),
                 start_time: 0.0,
+                lang: None,
             })
             .await
             .unwrap();
 
         match stream.recv().await.unwrap() {
-            SttEvent::WordReceived { text, start_ms } => {
+            SttEvent::WordReceived { text, start_ms, engine } => {
                 assert_eq!(text, "hello");
                 assert_eq!(start_ms, 0);
+                assert_eq!(engine, Engine::Server);
             }
             other => panic!("unexpected event: {other:?}"),
         }
@@ -179,16 +322,96 @@ mod tests {
             other => panic!("unexpected event: {other:?}"),
         }
     }
+
+    #[tokio::test]
+    async fn words_after_local_switch_are_tagged() {
+        let (out_tx, out_rx) = mpsc::channel::<OutMsg>(16);
+        let mut stream = dummy_session(out_rx).into_event_stream();
+
+        let marker = OutMsg::Marker {
+            id: LOCAL_ENGINE_MARKER_ID,
+            sample_idx: 0,
+            step_idx: 0,
+        };
+        out_tx.send(marker).await.unwrap();
+        out_tx
+            .send(OutMsg::Word {
+                text: "bonjour".to_string(),
+                start_time: 1.0,
+                lang: None,
+            })
+            .await
+            .unwrap();
+        out_tx
+            .send(OutMsg::EndWord { stop_time: 1.5 })
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            stream.recv().await.unwrap(),
+            SttEvent::EngineChanged {
+                engine: Engine::Local
+            }
+        ));
+        match stream.recv().await.unwrap() {
+            SttEvent::WordReceived {
+                engine, start_ms, ..
+            } => {
+                assert_eq!((engine, start_ms), (Engine::Local, 1000));
+            }
+            other => panic!("unexpected event: {other:?}"),
+        }
+        match stream.recv().await.unwrap() {
+            SttEvent::WordFinalized(word) => assert_eq!(word.engine, Engine::Local),
+            other => panic!("unexpected event: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn server_close_ends_the_stream_with_its_code() {
+        let (out_tx, out_rx) = mpsc::channel::<OutMsg>(16);
+        let session = dummy_session(out_rx);
+        *session.closed.lock().unwrap() = Some(ServerClose::new(4000, "no free channel"));
+        let mut stream = session.into_event_stream();
+
+        let message = "server at capacity (close code 4000) (reason: no free channel)".to_string();
+        out_tx.send(OutMsg::Error { message }).await.unwrap();
+        drop(out_tx);
+
+        assert!(matches!(
+            stream.recv().await.unwrap(),
+            SttEvent::Error { .. }
+        ));
+        let err = stream.recv().await.unwrap_err();
+        let close = err.server_close().unwrap();
+        assert_eq!(close.code, crate::stt::CloseCode::ServerAtCapacity);
+        assert_eq!(close.retry(), Retry::AfterDelay);
+    }
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct SttClientBuilder {
     url: Option<String>,
+    urls: Vec<Url>,
     auth_token: Option<String>,
+    token_source: Option<Arc<BetterAuthClient>>,
     query_token: Option<String>,
+    stream_id: Option<String>,
+    segment_s: Option<f64>,
+    stats_interval: Option<Duration>,
+    speakers_interval: Option<Duration>,
+    spot: Vec<String>,
+    itn: bool,
+    coalesce: Option<Duration>,
+    latency: bool,
     auto_reconnect: bool,
     max_reconnect_attempts: usize,
     reconnect_delay: Duration,
+    health_timeout: Duration,
+    failover_buffer: Duration,
+    spill: Option<SpillConfig>,
+    local_fallback: Option<LocalFallback>,
+    local_mimi: Option<LocalMimi>,
 }
 
 impl SttClientBuilder {
@@ -197,6 +420,8 @@ impl SttClientBuilder {
             auto_reconnect: false,
             max_reconnect_attempts: 3,
             reconnect_delay: Duration::from_secs(1),
+            health_timeout: Duration::from_secs(3),
+            failover_buffer: Duration::from_secs(10),
             ..Self::default()
         }
     }
@@ -206,16 +431,134 @@ impl SttClientBuilder {
         self
     }
 
+    /// Servers to fail over to, tried in order after `url`. The session connects to the first
+    /// server that answers and, when that server fails mid-session, moves to the next one and
+    /// replays the audio that no finalized word covers yet. Word timestamps stay relative to
+    /// the start of the session.
+    pub fn urls(mut self, urls: Vec<Url>) -> Self {
+        self.urls = urls;
+        self
+    }
+
+    /// How long a server has to accept the connection before the next one is tried.
+    pub fn health_timeout(mut self, timeout: Duration) -> Self {
+        self.health_timeout = timeout;
+        self
+    }
+
+    /// Maximum audio kept for replay on failover, the oldest audio is dropped beyond it.
+    pub fn failover_buffer(mut self, duration: Duration) -> Self {
+        self.failover_buffer = duration;
+        self
+    }
+
+    /// Write the audio to a temporary file while the connection is stalled, e.g. during a
+    /// network outage, and replay it faster than real time once the connection drains, so
+    /// that a session rides out outages of up to `cfg.max_lag` without losing speech.
+    pub fn spill(mut self, cfg: SpillConfig) -> Self {
+        self.spill = Some(cfg);
+        self
+    }
+
+    /// Continue the session with a local model when no server can be reached, at connection
+    /// time or once reconnection and failover are exhausted. The audio not covered by a
+    /// finalized word is replayed to the local model, and its words are tagged
+    /// [`Engine::Local`].
+    #[cfg(feature = "local")]
+    pub fn local_fallback(mut self, cfg: crate::stt::local::LocalAsrConfig) -> Self {
+        self.local_fallback = Some(cfg);
+        self
+    }
+
+    /// Encode the audio with the Mimi of the server model and send its codes, about 100 bytes
+    /// per 80ms frame instead of 7.7KB of f32 pcm, for links short on bandwidth (batched asr
+    /// only). The audio has to be 24kHz, the encoder is loaded by `connect`.
+    #[cfg(feature = "local-mimi")]
+    pub fn local_mimi(mut self, cfg: crate::stt::mimi::LocalMimiConfig) -> Self {
+        self.local_mimi = Some(cfg);
+        self
+    }
+
     pub fn auth_token(mut self, token: impl Into<String>) -> Self {
         self.auth_token = Some(token.into());
         self
     }
 
+    /// Fetch the bearer token from a Better Auth server instead of `auth_token`. The token is
+    /// renewed before each reconnection and failover, and a connection closed with
+    /// `authentication failed` or `token expired` is retried with a new one when auto
+    /// reconnection is on, so that a session outlives its tokens.
+    pub fn token_source(mut self, source: Arc<BetterAuthClient>) -> Self {
+        self.token_source = Some(source);
+        self
+    }
+
     pub fn query_token(mut self, token: impl Into<String>) -> Self {
         self.query_token = Some(token.into());
         self
     }
 
+    /// Name the logical stream so that a reconnection within the server grace period resumes
+    /// the same model state (batched asr only).
+    pub fn stream_id(mut self, stream_id: impl Into<String>) -> Self {
+        self.stream_id = Some(stream_id.into());
+        self
+    }
+
+    /// Ask the server to cut the session into segments of `seconds`, each one announced by
+    /// [`SttEvent::SegmentBoundary`]. Zero disables the server default (batched asr only).
+    pub fn segment_s(mut self, seconds: f64) -> Self {
+        self.segment_s = Some(seconds);
+        self
+    }
+
+    /// Ask the server for [`SttEvent::AudioStats`] and [`SttEvent::SpeechRate`] every
+    /// `interval` of audio (one second at least), to diagnose a quiet or clipping input.
+    pub fn stats(mut self, interval: Duration) -> Self {
+        self.stats_interval = Some(interval);
+        self
+    }
+
+    /// Ask the server for [`SttEvent::Overlap`] every `interval` of audio and for a
+    /// [`SttEvent::SpeakerCount`] before each `StreamMarker`, to tell whether a recording
+    /// holds several voices. Both are rough estimates from the voice timbre.
+    pub fn speakers(mut self, interval: Duration) -> Self {
+        self.speakers_interval = Some(interval);
+        self
+    }
+
+    /// Ask the server for [`SttEvent::PhraseSpotted`] as soon as one of `phrases` is heard,
+    /// e.g. voice commands such as `next slide`. The words are matched without casing and
+    /// allow for a misspelled letter or two in longer words.
+    pub fn spot<S: Into<String>>(mut self, phrases: impl IntoIterator<Item = S>) -> Self {
+        self.spot = phrases.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Ask the server to write the numbers with digits, `twenty three dollars` becomes `$23`.
+    /// The words of a number then arrive together once it is complete, as a single
+    /// [`SttEvent::WordReceived`]. Servers without it send the words as spoken.
+    pub fn itn(mut self, enabled: bool) -> Self {
+        self.itn = enabled;
+        self
+    }
+
+    /// Ask the server to send the messages produced within `interval` of each other, 100ms at
+    /// most, in a single frame, which saves frames when words come in bursts. Servers that do
+    /// not coalesce ignore it.
+    pub fn coalesce(mut self, interval: Duration) -> Self {
+        self.coalesce = Some(interval);
+        self
+    }
+
+    /// Probe the server every few seconds and emit [`SttEvent::LatencyReport`] with the round
+    /// trip, the clock offset and the delay between capturing the audio of the words and
+    /// receiving them. The audio is taken to be sent as soon as it is captured.
+    pub fn latency(mut self, enabled: bool) -> Self {
+        self.latency = enabled;
+        self
+    }
+
     pub fn auto_reconnect(mut self, max_attempts: usize) -> Self {
         self.auto_reconnect = true;
         self.max_reconnect_attempts = max_attempts;
@@ -228,64 +571,194 @@ impl SttClientBuilder {
     }
 
     pub async fn connect(self) -> Result<SttSession> {
-        let url = self
-            .url
-            .ok_or_else(|| SttError::Message("missing websocket url".to_string()))?;
+        let mut bases: Vec<String> = self.url.into_iter().collect();
+        bases.extend(self.urls.iter().map(|u| u.to_string()));
+        if bases.is_empty() {
+            return Err(SttError::Message("missing websocket url".to_string()));
+        }
 
-        let auth_token = self.auth_token;
+        let token_source = self.token_source;
+        let auth_token = match token_source.as_deref() {
+            Some(source) => Some(
+                source
+                    .token()
+                    .await
+                    .map_err(|e| SttError::Message(format!("cannot fetch the auth token: {e}")))?,
+            ),
+            None => self.auth_token,
+        };
         let query_token = self.query_token;
+        let stream_id = self.stream_id;
         let auto_reconnect = self.auto_reconnect;
         let max_reconnect_attempts = self.max_reconnect_attempts;
         let reconnect_delay = self.reconnect_delay;
-
-        let ws_url = build_ws_url(&url, "", &[], query_token.as_deref())
-            .map_err(|e| SttError::Message(e.to_string()))?;
-        let ws_stream = connect_ws(&ws_url, auth_token.as_deref())
-            .await
+        let health_timeout = self.health_timeout;
+
+        let segment_s = self.segment_s.map(|s| s.to_string());
+        let mut query: Vec<(&str, &str)> = stream_id
+            .as_deref()
+            .map(|id| ("stream_id", id))
+            .into_iter()
+            .collect();
+        if let Some(segment_s) = segment_s.as_deref() {
+            query.push(("segment_s", segment_s));
+        }
+        let stats_interval_s = self.stats_interval.map(|d| d.as_secs_f64().to_string());
+        if let Some(interval) = stats_interval_s.as_deref() {
+            query.push(("stats_interval_s", interval));
+        }
+        let speakers_interval_s = self.speakers_interval.map(|d| d.as_secs_f64().to_string());
+        if let Some(interval) = speakers_interval_s.as_deref() {
+            query.push(("speakers_interval_s", interval));
+        }
+        let spot = (!self.spot.is_empty())
+            .then(|| serde_json::to_string(&self.spot))
+            .transpose()
             .map_err(|e| SttError::Message(e.to_string()))?;
-        let (ws_write, ws_read) = ws_stream.split();
+        if let Some(spot) = spot.as_deref() {
+            query.push(("spot", spot));
+        }
+        if self.itn {
+            query.push(("itn", "true"));
+        }
+        let coalesce_ms = self.coalesce.map(|d| d.as_millis().to_string());
+        if let Some(coalesce_ms) = coalesce_ms.as_deref() {
+            query.push(("coalesce_ms", coalesce_ms));
+        }
+        let servers = futures_util::future::join_all(bases.iter().map(|base| {
+            let mut query = query.clone();
+            let query_token = query_token.as_deref();
+            async move {
+                capabilities::negotiate(base, &mut query, FEATURES, health_timeout).await;
+                build_ws_url(base, "", &query, query_token)
+            }
+        }))
+        .await
+        .into_iter()
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(|e| SttError::Message(e.to_string()))?;
+        let mut local_fallback = self.local_fallback;
+        let mut mimi = match self.local_mimi {
+            Some(cfg) => Some(load_mimi(cfg).await?),
+            None => None,
+        };
         let (tx, mut rx) = mpsc::channel::<SendCmd>(128);
         let (out_tx, out_rx) = mpsc::channel::<OutMsg>(128);
-
         let keepalive_tx = tx.clone();
         let ping_bytes = encode_in_msg(&InMsg::Ping)?;
+        let latency = self
+            .latency
+            .then(|| Arc::new(Mutex::new(LatencyMeter::new())));
+        let probe = latency.is_some();
+
+        let connected = connect_first(&servers, 0, auth_token.as_deref(), health_timeout).await;
+        let (mut current, ws_stream) = match (connected, local_fallback.take()) {
+            (Ok(v), cfg) => {
+                local_fallback = cfg;
+                v
+            }
+            (Err(e), None) => return Err(e),
+            (Err(e), Some(cfg)) => {
+                let send_loop = tokio::spawn(continue_locally(
+                    cfg,
+                    e.to_string(),
+                    rx,
+                    out_tx,
+                    (vec![], 0.0),
+                ));
+                return Ok(SttSession {
+                    sender: SttSender { tx, latency },
+                    send_loop,
+                    recv_loop: tokio::spawn(async move { Ok(()) }),
+                    keepalive_loop: tokio::spawn(async move { Ok(()) }),
+                    out_rx,
+                    closed: Arc::default(),
+                });
+            }
+        };
+        let confirmed = Arc::new(AtomicU64::new(0));
+        let mut journal = (servers.len() > 1 || local_fallback.is_some())
+            .then(|| AudioJournal::new(self.failover_buffer, confirmed.clone()));
+        // Audio messages written to the socket, for the spill to tell a stalled connection.
+        let sent = Arc::new(AtomicU64::new(0));
+        let sender_tx = match self.spill {
+            Some(cfg) => {
+                let (spill_tx, spill_rx) = mpsc::channel::<SendCmd>(128);
+                let spill = Spill::new(cfg, sent.clone());
+                tokio::spawn(crate::stt::spill::run(
+                    spill,
+                    spill_rx,
+                    tx.clone(),
+                    out_tx.clone(),
+                ));
+                spill_tx
+            }
+            None => tx,
+        };
+        let (ws_write, ws_read) = ws_stream.split();
+        let session_closed = Arc::new(Mutex::new(None));
+        let closed = session_closed.clone();
 
         let send_loop: JoinHandle<Result<()>> = tokio::spawn(async move {
-            let url = url;
-            let auth_token = auth_token;
-            let query_token = query_token;
+            let mut auth_token = auth_token;
             let reconnect_delay = reconnect_delay;
 
             let mut ws_write = ws_write;
             let mut reconnect_attempts = 0usize;
-            let mut recv_done_rx = spawn_recv_task(ws_read, out_tx.clone());
+            let mut failovers = 0usize;
+            let mut offset = 0.0;
+            // Close frame behind the failure that ends the session.
+            let mut last_close = None;
+            // Sent again to the server taking over, before the replayed audio.
+            let mut enrollment = None;
+            let mut recv_done_rx = spawn_recv_task(ws_read, out_tx.clone(), offset, confirmed.clone());
 
             loop {
-                tokio::select! {
+                let failure = tokio::select! {
                     cmd = rx.recv() => {
                         let Some(cmd) = cmd else {
                             break;
                         };
 
-                        match cmd {
+                        let mut audio = false;
+                        let bytes = match cmd {
                             SendCmd::Msg(msg) => {
+                                audio = matches!(msg, InMsg::Audio { .. });
+                                if let (Some(journal), InMsg::Audio { pcm }) = (journal.as_mut(), &msg) {
+                                    journal.push(pcm);
+                                }
+                                if journal.is_some() && matches!(msg, InMsg::Enroll { .. }) {
+                                    enrollment = Some(msg.clone());
+                                }
+                                let msg = match msg {
+                                    InMsg::Audio { pcm } => match audio_msg(mimi.as_mut(), pcm)? {
+                                        Some(msg) => msg,
+                                        None => {
+                                            sent.fetch_add(1, Ordering::Relaxed);
+                                            continue;
+                                        }
+                                    },
+                                    msg => msg,
+                                };
                                 let mut buf = Vec::new();
                                 encode_in_msg_into(&mut buf, &msg)?;
-                                ws_write
-                                    .send(Message::Binary(buf.into()))
-                                    .await
-                                    .map_err(|e| SttError::Message(e.to_string()))?;
-                            }
-                            SendCmd::Raw(bytes) => {
-                                ws_write
-                                    .send(Message::Binary(bytes.into()))
-                                    .await
-                                    .map_err(|e| SttError::Message(e.to_string()))?;
+                                buf
                             }
+                            SendCmd::Raw(bytes) => bytes,
                             SendCmd::Close => {
                                 let _ = ws_write.send(Message::Close(None)).await;
                                 break;
                             }
+                        };
+                        match ws_write.send(Message::Binary(bytes.into())).await {
+                            Ok(()) => {
+                                if audio {
+                                    sent.fetch_add(1, Ordering::Relaxed);
+                                }
+                                continue;
+                            }
+                            Err(e) if journal.is_none() => return Err(SttError::Message(e.to_string())),
+                            Err(e) => format!("websocket send error: {e}"),
                         }
                     }
                     outcome = recv_done_rx.recv() => {
@@ -295,64 +768,113 @@ impl SttClientBuilder {
 
                         match outcome {
                             RecvOutcome::Closed { code, reason } => {
-                                if code != 1000 {
-                                    let message = close_code_message(code, &reason);
-                                    if auto_reconnect
-                                        && is_retryable_close_code(code)
-                                        && reconnect_attempts < max_reconnect_attempts
-                                    {
-                                        reconnect_attempts += 1;
-                                        let _ = out_tx
-                                            .send(OutMsg::Error {
-                                                message: format!("{message}; reconnecting..."),
-                                            })
-                                            .await;
-
-                                        sleep(reconnect_delay).await;
-
-                                        let ws_url = build_ws_url(&url, "", &[], query_token.as_deref())
-                                            .map_err(|e| SttError::Message(e.to_string()))?;
-                                        let ws_stream = match connect_ws(
-                                            &ws_url,
-                                            auth_token.as_deref(),
-                                        )
-                                        .await
-                                        {
-                                            Ok(ws_stream) => ws_stream,
-                                            Err(e) => {
-                                                let _ = out_tx
-                                                    .send(OutMsg::Error {
-                                                        message: format!("reconnect failed: {e}"),
-                                                    })
-                                                    .await;
-                                                break;
-                                            }
-                                        };
-
-                                        let (new_write, new_read) = ws_stream.split();
-                                        ws_write = new_write;
-                                        recv_done_rx = spawn_recv_task(new_read, out_tx.clone());
-                                        continue;
-                                    }
-
+                                if code == 1000 {
+                                    break;
+                                }
+                                let close = ServerClose::new(code, &reason);
+                                let message = close.to_string();
+                                // Retried with a new token from the token source.
+                                let rejected =
+                                    token_source.is_some() && close.retry() == Retry::WithNewToken;
+                                if auto_reconnect
+                                    && (close.retry() == Retry::AfterDelay || rejected)
+                                    && reconnect_attempts < max_reconnect_attempts
+                                {
+                                    reconnect_attempts += 1;
                                     let _ = out_tx
-                                        .send(OutMsg::Error { message })
+                                        .send(OutMsg::Error {
+                                            message: format!("{message}; reconnecting..."),
+                                        })
                                         .await;
-                                }
 
-                                break;
-                            }
-                            RecvOutcome::Error(message) => {
-                                let _ = out_tx
-                                    .send(OutMsg::Error { message })
-                                    .await;
-                                break;
+                                    sleep(reconnect_delay).await;
+                                    fresh_token(token_source.as_deref(), rejected, &mut auth_token)
+                                        .await;
+
+                                    // The same URL is reused so that a sticky stream
+                                    // resumes on the server side.
+                                    match connect_ws(&servers[current], auth_token.as_deref()).await {
+                                        Ok(ws_stream) => {
+                                            let (new_write, new_read) = ws_stream.split();
+                                            ws_write = new_write;
+                                            recv_done_rx = spawn_recv_task(
+                                                new_read,
+                                                out_tx.clone(),
+                                                offset,
+                                                confirmed.clone(),
+                                            );
+                                            continue;
+                                        }
+                                        Err(e) => format!("reconnect failed: {e}"),
+                                    }
+                                } else {
+                                    last_close = Some(close);
+                                    message
+                                }
                             }
+                            RecvOutcome::Error(message) => message,
                             RecvOutcome::Eof => {
                                 break;
                             }
                         }
                     }
+                };
+
+                let journal = match journal.as_mut() {
+                    Some(journal) if failovers < max_reconnect_attempts.max(1) * servers.len() => journal,
+                    journal => {
+                        if let (Some(journal), Some(cfg)) = (journal, local_fallback.take()) {
+                            return continue_locally(cfg, failure, rx, out_tx, journal.pending()).await;
+                        }
+                        let _ = out_tx.send(OutMsg::Error { message: failure }).await;
+                        *closed.lock().unwrap() = last_close;
+                        break;
+                    }
+                };
+                failovers += 1;
+                let _ = out_tx
+                    .send(OutMsg::Error { message: format!("{failure}; failing over...") })
+                    .await;
+                fresh_token(token_source.as_deref(), false, &mut auth_token).await;
+                let (new_current, ws_stream) = match connect_first(
+                    &servers,
+                    current + 1,
+                    auth_token.as_deref(),
+                    health_timeout,
+                )
+                .await
+                {
+                    Ok(v) => v,
+                    Err(e) => {
+                        if let Some(cfg) = local_fallback.take() {
+                            return continue_locally(cfg, e.to_string(), rx, out_tx, journal.pending()).await;
+                        }
+                        let _ = out_tx.send(OutMsg::Error { message: e.to_string() }).await;
+                        break;
+                    }
+                };
+                current = new_current;
+                last_close = None;
+                tracing::info!(server = redact_ws_url(&servers[current]), "failed over");
+                let (new_write, new_read) = ws_stream.split();
+                ws_write = new_write;
+                let (pcm, pending_offset) = journal.pending();
+                offset = pending_offset;
+                recv_done_rx = spawn_recv_task(new_read, out_tx.clone(), offset, confirmed.clone());
+                if let Some(msg) = enrollment.as_ref() {
+                    let bytes = encode_in_msg(msg)?;
+                    let _ = ws_write.send(Message::Binary(bytes.into())).await;
+                }
+                // The new server gets the codes of the replayed audio from a fresh encoder.
+                if let Some(mimi) = mimi.as_mut() {
+                    reset_mimi(mimi);
+                }
+                for chunk in pcm.chunks(REPLAY_CHUNK_SAMPLES) {
+                    let Some(msg) = audio_msg(mimi.as_mut(), chunk.to_vec())? else { continue };
+                    let bytes = encode_in_msg(&msg)?;
+                    if ws_write.send(Message::Binary(bytes.into())).await.is_err() {
+                        break;
+                    }
                 }
             }
 
@@ -370,6 +892,12 @@ impl SttClientBuilder {
                 {
                     break;
                 }
+                if probe {
+                    let echo = encode_in_msg(&InMsg::Echo { client_ts_ms: unix_ms() })?;
+                    if keepalive_tx.send(SendCmd::Raw(echo)).await.is_err() {
+                        break;
+                    }
+                }
             }
             Ok(())
         });
@@ -377,11 +905,12 @@ impl SttClientBuilder {
         let recv_loop: JoinHandle<Result<()>> = tokio::spawn(async move { Ok(()) });
 
         Ok(SttSession {
-            sender: SttSender { tx },
+            sender: SttSender { tx: sender_tx, latency },
             send_loop,
             recv_loop,
             keepalive_loop,
             out_rx,
+            closed: session_closed,
         })
     }
 }
@@ -392,6 +921,8 @@ pub struct SttSession {
     recv_loop: JoinHandle<Result<()>>,
     keepalive_loop: JoinHandle<Result<()>>,
     out_rx: mpsc::Receiver<OutMsg>,
+    /// Set when the server closed the session for good.
+    closed: Arc<Mutex<Option<ServerClose>>>,
 }
 
 impl SttSession {
@@ -400,10 +931,17 @@ impl SttSession {
     }
 
     pub async fn recv(&mut self) -> Result<OutMsg> {
-        self.out_rx
-            .recv()
-            .await
-            .ok_or_else(|| SttError::Message("recv loop ended".to_string()))
+        let msg = self.out_rx.recv().await;
+        msg.ok_or_else(|| self.ended())
+    }
+
+    /// Error for a session whose messages are over, [`SttError::Closed`] when the server
+    /// closed it.
+    fn ended(&self) -> SttError {
+        match self.closed.lock().unwrap().clone() {
+            Some(close) => SttError::Closed(close),
+            None => SttError::Message("recv loop ended".to_string()),
+        }
     }
 
     pub async fn shutdown(self) -> Result<()> {
@@ -413,6 +951,7 @@ impl SttSession {
             recv_loop,
             keepalive_loop,
             mut out_rx,
+            closed: _,
         } = self;
 
         if sender
@@ -432,7 +971,7 @@ impl SttSession {
                                 break;
                             };
 
-                            if let OutMsg::Marker { id } = msg
+                            if let OutMsg::Marker { id, .. } = msg
                                 && id == SHUTDOWN_FLUSH_MARKER_ID
                             {
                                 break;
@@ -503,36 +1042,26 @@ impl SttSession {
 
 pub struct SttEventStream {
     session: SttSession,
-    transcript: TranscriptAssembler,
-    pending: VecDeque<SttEvent>,
-    utterance_text: String,
-    utterance_finalize_delay: Duration,
-    utterance_deadline: Option<Instant>,
-    utterance_partial_min_interval: Duration,
-    last_partial_emit: Option<Instant>,
+    events: EventAssembler,
+    start: Instant,
 }
 
 impl SttEventStream {
     fn new(session: SttSession) -> Self {
         Self {
             session,
-            transcript: TranscriptAssembler::new(),
-            pending: VecDeque::new(),
-            utterance_text: String::new(),
-            utterance_finalize_delay: Duration::from_millis(1500),
-            utterance_deadline: None,
-            utterance_partial_min_interval: Duration::from_millis(100),
-            last_partial_emit: None,
+            events: EventAssembler::new(),
+            start: Instant::now(),
         }
     }
 
     pub fn utterance_finalize_delay(mut self, delay: Duration) -> Self {
-        self.utterance_finalize_delay = delay;
+        self.events.utterance_finalize_delay = delay;
         self
     }
 
     pub fn utterance_partial_interval(mut self, interval: Duration) -> Self {
-        self.utterance_partial_min_interval = interval;
+        self.events.utterance_partial_min_interval = interval;
         self
     }
 
@@ -542,29 +1071,25 @@ impl SttEventStream {
 
     pub async fn recv(&mut self) -> Result<SttEvent> {
         loop {
-            if let Some(ev) = self.pending.pop_front() {
+            if let Some(ev) = self.events.pop() {
                 return Ok(ev);
             }
 
-            if let Some(deadline) = self.utterance_deadline {
+            if let Some(deadline) = self.events.deadline() {
                 tokio::select! {
-                    _ = sleep_until(deadline) => {
-                        if let Some(ev) = self.finalize_utterance() {
+                    _ = sleep_until(self.start + deadline) => {
+                        if let Some(ev) = self.events.finalize_utterance() {
                             return Ok(ev);
                         }
                     }
                     msg = self.session.out_rx.recv() => {
-                        let msg = msg.ok_or_else(|| SttError::Message("recv loop ended".to_string()))?;
+                        let msg = msg.ok_or_else(|| self.session.ended())?;
                         self.handle_out_msg(msg);
                     }
                 }
             } else {
-                let msg = self
-                    .session
-                    .out_rx
-                    .recv()
-                    .await
-                    .ok_or_else(|| SttError::Message("recv loop ended".to_string()))?;
+                let msg = self.session.out_rx.recv().await;
+                let msg = msg.ok_or_else(|| self.session.ended())?;
                 self.handle_out_msg(msg);
             }
         }
@@ -575,96 +1100,49 @@ impl SttEventStream {
     }
 
     fn handle_out_msg(&mut self, msg: OutMsg) {
-        match msg {
-            OutMsg::Ready => {
-                self.pending.push_back(SttEvent::Ready);
-            }
-            OutMsg::Word { text, start_time } => {
-                self.pending.push_back(SttEvent::WordReceived {
-                    text: text.clone(),
-                    start_ms: sec_to_ms(start_time),
-                });
-
-                if let Some(word) = self.transcript.push_word(text, start_time) {
-                    self.push_word_finalized(word);
+        if let Some(latency) = self.session.sender.latency.as_ref() {
+            match &msg {
+                OutMsg::Word { start_time, .. } => {
+                    latency
+                        .lock()
+                        .unwrap()
+                        .word(*start_time, std::time::Instant::now());
                 }
-            }
-            OutMsg::EndWord { stop_time } => {
-                if let Some(word) = self.transcript.push_end_word(stop_time) {
-                    self.push_word_finalized(word);
+                OutMsg::Echo {
+                    client_ts_ms,
+                    server_recv_ms,
+                    server_send_ms,
+                } => {
+                    let mut latency = latency.lock().unwrap();
+                    let report =
+                        latency.report(*client_ts_ms, *server_recv_ms, *server_send_ms, unix_ms());
+                    self.events.push(report);
                 }
+                _ => {}
             }
-            OutMsg::Step {
-                step_idx,
-                prs,
-                buffered_pcm,
-            } => {
-                self.pending.push_back(SttEvent::VadStep {
-                    step_idx,
-                    prs,
-                    buffered_pcm,
-                });
-            }
-            OutMsg::Marker { id } => {
-                self.pending.push_back(SttEvent::StreamMarker { id });
-            }
-            OutMsg::Error { message } => {
-                self.pending.push_back(SttEvent::Error { message });
-            }
-        }
-    }
-
-    fn push_word_finalized(&mut self, word: crate::stt::types::WordTiming) {
-        let word_text = word.word.clone();
-        self.pending.push_back(SttEvent::WordFinalized(word));
-
-        if !self.utterance_text.is_empty() {
-            self.utterance_text.push(' ');
-        }
-        self.utterance_text.push_str(&word_text);
-        let now = Instant::now();
-        let should_emit = self.utterance_partial_min_interval.is_zero()
-            || self
-                .last_partial_emit
-                .is_none_or(|last| now.duration_since(last) >= self.utterance_partial_min_interval);
-        if should_emit {
-            self.pending
-                .push_back(SttEvent::UtterancePartial(Utterance {
-                    text: self.utterance_text.clone(),
-                }));
-            self.last_partial_emit = Some(now);
         }
-
-        self.utterance_deadline = Some(Instant::now() + self.utterance_finalize_delay);
-    }
-
-    fn finalize_utterance(&mut self) -> Option<SttEvent> {
-        self.utterance_deadline = None;
-        if self.utterance_text.is_empty() {
-            return None;
-        }
-
-        let text = std::mem::take(&mut self.utterance_text);
-        self.last_partial_emit = None;
-        Some(SttEvent::UtteranceFinal(Utterance { text }))
-    }
-}
-
-fn sec_to_ms(s: f64) -> u64 {
-    if !s.is_finite() || s.is_sign_negative() {
-        return 0;
+        self.events.handle(msg, self.start.elapsed());
     }
-
-    (s * 1000.0).round() as u64
 }
 
 #[derive(Clone, Debug)]
 pub struct SttSender {
     tx: mpsc::Sender<SendCmd>,
+    latency: Option<Arc<Mutex<LatencyMeter>>>,
 }
 
 impl SttSender {
     pub async fn send(&self, msg: InMsg) -> Result<()> {
+        if let Some(latency) = self.latency.as_ref() {
+            match &msg {
+                InMsg::Audio { pcm } => latency
+                    .lock()
+                    .unwrap()
+                    .audio_sent(pcm.len(), std::time::Instant::now()),
+                InMsg::SetSampleRate { hz } => latency.lock().unwrap().set_rate(*hz),
+                _ => {}
+            }
+        }
         self.tx
             .send(SendCmd::Msg(msg))
             .await
@@ -1,7 +1,9 @@
+use crate::tts::error::{Result, TtsError};
 use serde::{Deserialize, Serialize};
 
 /// Incoming message types (received from server)
 #[derive(Debug, Deserialize)]
+#[cfg_attr(test, derive(Serialize, PartialEq, Clone))]
 #[serde(tag = "type")]
 pub enum InMsg {
     Audio {
@@ -19,6 +21,17 @@ pub enum InMsg {
         message: String,
     },
     Ready,
+    /// A sentence of the input text and its translation, for translated sessions.
+    Translation {
+        original: String,
+        translated: String,
+    },
+    /// RMS of the upcoming audio, one value per `hop_ms` window, when requested with
+    /// [`crate::tts::TtsClientBuilder::envelope`].
+    Envelope {
+        rms: Vec<f32>,
+        hop_ms: u32,
+    },
 }
 
 /// Outgoing message types (not explicitly used in tts-rs yet, but good for symmetry)
@@ -27,3 +40,55 @@ pub enum InMsg {
 pub enum OutMsg {
     Text { text: String },
 }
+
+pub fn decode_in_msg(bytes: &[u8]) -> Result<InMsg> {
+    let mut de = rmp_serde::Deserializer::from_read_ref(bytes);
+    de.set_max_depth(crate::stt::protocol::MAX_MSG_DEPTH);
+    InMsg::deserialize(&mut de).map_err(|e| TtsError::Serialization(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn in_msg() -> impl Strategy<Value = InMsg> {
+        prop_oneof![
+            Just(InMsg::Ready),
+            prop::collection::vec(-1.0f32..1.0, 0..2000).prop_map(|pcm| InMsg::Audio { pcm }),
+            prop::collection::vec(any::<u8>(), 0..512).prop_map(|data| InMsg::OggOpus { data }),
+            (".*", 0.0f64..100.0, 0.0f64..100.0).prop_map(|(text, start_s, stop_s)| InMsg::Text {
+                text,
+                start_s,
+                stop_s
+            }),
+            ".*".prop_map(|message| InMsg::Error { message }),
+            (".*", ".*").prop_map(|(original, translated)| InMsg::Translation {
+                original,
+                translated
+            }),
+            (prop::collection::vec(0.0f32..1.0, 0..64), 1u32..1000)
+                .prop_map(|(rms, hop_ms)| InMsg::Envelope { rms, hop_ms }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn server_messages_round_trip(msg in in_msg()) {
+            let bytes = rmp_serde::to_vec_named(&msg).unwrap();
+            prop_assert_eq!(decode_in_msg(&bytes).unwrap(), msg);
+        }
+
+        #[test]
+        fn malformed_messages_are_errors(msg in in_msg(), cut in any::<prop::sample::Index>()) {
+            let mut bytes = rmp_serde::to_vec_named(&msg).unwrap();
+            bytes.truncate(cut.index(bytes.len()));
+            prop_assert!(matches!(decode_in_msg(&bytes), Err(TtsError::Serialization(_))));
+        }
+
+        #[test]
+        fn arbitrary_bytes_do_not_panic(bytes in prop::collection::vec(any::<u8>(), 0..256)) {
+            let _ = decode_in_msg(&bytes);
+        }
+    }
+}
@@ -2,13 +2,32 @@ use crate::tts::error::Result;
 use crate::tts::protocol::InMsg;
 use anyhow::Context;
 use futures_util::{SinkExt, StreamExt};
-use kyutai_client_core::ws::{WsStream, connect_ws, build_ws_url};
+use kyutai_client_core::capabilities;
+use kyutai_client_core::ws::{WsStream, build_ws_url, connect_ws};
+use std::time::Duration;
 use tokio_tungstenite::tungstenite::Message;
 use url::Url;
 
+/// Query parameters that older servers may not support, with their `/api/capabilities` feature.
+const FEATURES: &[(&str, &str)] = &[
+    ("voices", "tts_voice_mix"),
+    ("voice_weights", "tts_voice_mix"),
+    ("input_language", "tts_translation"),
+    ("speak_language", "tts_translation"),
+    ("envelope_hop_ms", "tts_envelope"),
+    ("speed", "tts_speed"),
+];
+const CAPABILITIES_TIMEOUT: Duration = Duration::from_secs(3);
+
 pub struct TtsClientBuilder {
     url: String,
     auth_token: Option<String>,
+    voice: Option<String>,
+    voices: Vec<String>,
+    voice_weights: Vec<f32>,
+    languages: Option<(String, String)>,
+    envelope_hop_ms: Option<u32>,
+    speed: Option<f32>,
 }
 
 impl TtsClientBuilder {
@@ -16,6 +35,12 @@ impl TtsClientBuilder {
         Self {
             url: url.into(),
             auth_token: None,
+            voice: None,
+            voices: Vec::new(),
+            voice_weights: Vec::new(),
+            languages: None,
+            envelope_hop_ms: None,
+            speed: None,
         }
     }
 
@@ -24,12 +49,89 @@ impl TtsClientBuilder {
         self
     }
 
+    /// Voice file to condition the generation on, relative to the server voice directory.
+    pub fn voice(mut self, voice: impl Into<String>) -> Self {
+        self.voice = Some(voice.into());
+        self
+    }
+
+    /// Blend several voices, the server interpolates their speaker embeddings with `weights`
+    /// (normalized server side). Takes precedence over [`Self::voice`].
+    pub fn voice_mix(mut self, voices: Vec<String>, weights: Vec<f32>) -> Self {
+        self.voices = voices;
+        self.voice_weights = weights;
+        self
+    }
+
+    /// Translate the text from `input_language` to `speak_language` before synthesis, using
+    /// the translation backend configured on the server.
+    pub fn translate(
+        mut self,
+        input_language: impl Into<String>,
+        speak_language: impl Into<String>,
+    ) -> Self {
+        self.languages = Some((input_language.into(), speak_language.into()));
+        self
+    }
+
+    /// Ask for [`InMsg::Envelope`] messages with the RMS of the audio over `hop_ms` windows,
+    /// e.g. to duck background music under the speech.
+    pub fn envelope(mut self, hop_ms: u32) -> Self {
+        self.envelope_hop_ms = Some(hop_ms);
+        self
+    }
+
+    /// Playback speed from 0.5 to 2, the server time-stretches the audio without changing the
+    /// pitch and scales the word timestamps.
+    pub fn speed(mut self, speed: f32) -> Self {
+        self.speed = Some(speed);
+        self
+    }
+
     pub async fn connect(self) -> Result<TtsSession> {
         let url = Url::parse(&self.url).map_err(|e| crate::tts::error::TtsError::Message(e.to_string()))?;
+        let voices = self.voices.join(",");
+        let voice_weights = self
+            .voice_weights
+            .iter()
+            .map(|w| w.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let mut query: Vec<(&str, &str)> = if self.voices.is_empty() {
+            self.voice
+                .as_deref()
+                .map(|v| ("voice", v))
+                .into_iter()
+                .collect()
+        } else {
+            vec![
+                ("voices", voices.as_str()),
+                ("voice_weights", voice_weights.as_str()),
+            ]
+        };
+        if let Some((input, speak)) = &self.languages {
+            query.push(("input_language", input));
+            query.push(("speak_language", speak));
+        }
+        let envelope_hop_ms = self.envelope_hop_ms.map(|v| v.to_string());
+        if let Some(hop_ms) = &envelope_hop_ms {
+            // Envelopes are only sent in the messagepack formats.
+            query.push(("format", "PcmMessagePack"));
+            query.push(("envelope_hop_ms", hop_ms));
+        }
+        let speed = self.speed.map(|v| v.to_string());
+        if let Some(speed) = &speed {
+            query.push(("speed", speed));
+        }
+        capabilities::negotiate(url.as_str(), &mut query, FEATURES, CAPABILITIES_TIMEOUT).await;
+        if !self.voices.is_empty() && !query.iter().any(|(name, _)| *name == "voices") {
+            // Servers without voice mixing get the first voice.
+            query.push(("voice", &self.voices[0]));
+        }
         let ws_url = build_ws_url(
             url.as_str(),
             "",
-            &[],
+            &query,
             self.auth_token.as_deref()
         ).context("Failed to build WS URL").map_err(|e| crate::tts::error::TtsError::Message(e.to_string()))?;
 
@@ -55,9 +157,7 @@ impl TtsSession {
         while let Some(msg) = self.stream.next().await {
             match msg {
                 Ok(Message::Binary(data)) => {
-                    let in_msg = rmp_serde::from_slice::<InMsg>(&data)
-                        .map_err(|e| crate::tts::error::TtsError::Serialization(e.to_string()))?;
-                    return Ok(Some(in_msg));
+                    return crate::tts::protocol::decode_in_msg(&data).map(Some);
                 }
                 Ok(Message::Text(text)) => {
                     // Sometimes JSON is sent?
@@ -0,0 +1,82 @@
+//! Local Mimi encoding and decoding, so that a publisher can send codes directly.
+
+use crate::NUM_CODEBOOKS;
+use crate::error::{MimiError, Result};
+use candle::{Device, IndexOp, Tensor};
+
+fn codec_err(e: impl std::fmt::Display) -> MimiError {
+    MimiError::Codec(e.to_string())
+}
+
+pub struct MimiCodec {
+    model: moshi::mimi::Mimi,
+    device: Device,
+}
+
+impl MimiCodec {
+    /// Load the Mimi weights from a local file or a `hf://<org>/<repo>/<file>` path.
+    pub fn load(model_file: &str, device: &Device) -> Result<Self> {
+        let path = match model_file.strip_prefix("hf://") {
+            Some(path) => {
+                let s: Vec<&str> = path.split('/').collect();
+                if s.len() < 3 {
+                    return Err(MimiError::Message(format!(
+                        "unexpected format for hf path {model_file}"
+                    )));
+                }
+                let api = hf_hub::api::sync::ApiBuilder::from_env()
+                    .build()
+                    .map_err(codec_err)?;
+                let repo = api.model(format!("{}/{}", s[0], s[1]));
+                repo.get(&s[2..].join("/")).map_err(codec_err)?
+            }
+            None => model_file.into(),
+        };
+        let path = path.to_string_lossy();
+        let model = moshi::mimi::load(&path, Some(NUM_CODEBOOKS), device).map_err(codec_err)?;
+        Ok(Self {
+            model,
+            device: device.clone(),
+        })
+    }
+
+    /// Encode 24kHz mono audio, returning the codes of each frame completed so far. Samples
+    /// left over are kept for the next call.
+    pub fn encode(&mut self, pcm: &[f32]) -> Result<Vec<Vec<u32>>> {
+        let pcm = Tensor::from_slice(pcm, (1, 1, pcm.len()), &self.device).map_err(codec_err)?;
+        let codes = self
+            .model
+            .encode_step(&pcm.into(), &().into())
+            .map_err(codec_err)?;
+        let Some(codes) = codes.as_option() else {
+            return Ok(vec![]);
+        };
+        // (1, codebooks, steps) -> one vec of codes per step.
+        let codes = codes
+            .i(0)
+            .and_then(|c| c.t())
+            .and_then(|c| c.to_vec2::<u32>());
+        codes.map_err(codec_err)
+    }
+
+    /// Decode the codes of one frame, [`NUM_CODEBOOKS`] of them.
+    pub fn decode(&mut self, codes: &[u32]) -> Result<Vec<f32>> {
+        let codes =
+            Tensor::from_slice(codes, (1, codes.len(), 1), &self.device).map_err(codec_err)?;
+        let pcm = self
+            .model
+            .decode_step(&codes.into(), &().into())
+            .map_err(codec_err)?;
+        match pcm.as_option() {
+            Some(pcm) => pcm
+                .flatten_all()
+                .and_then(|p| p.to_vec1::<f32>())
+                .map_err(codec_err),
+            None => Ok(vec![]),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.model.reset_state()
+    }
+}
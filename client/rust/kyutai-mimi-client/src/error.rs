@@ -0,0 +1,13 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MimiError {
+    #[error("WebSocket error: {0}")]
+    Ws(String),
+    #[error("Codec error: {0}")]
+    Codec(String),
+    #[error("{0}")]
+    Message(String),
+}
+
+pub type Result<T> = std::result::Result<T, MimiError>;
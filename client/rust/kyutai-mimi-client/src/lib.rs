@@ -0,0 +1,26 @@
+//! Client of the Mimi rooms of moshi-server.
+//!
+//! A [`PublisherSession`] sends audio to a room on the send path (`/api/mimi/send`), either as
+//! Mimi codes encoded locally with [`MimiCodec`] or as Ogg/Opus that the server transcodes.
+//! A [`SubscriberSession`] receives the mix of the room on the recv path (`/api/mimi/recv`)
+//! as Ogg/Opus and decodes it to 24kHz PCM, along with the text messages of the publishers.
+
+mod error;
+pub mod protocol;
+pub mod publisher;
+pub mod subscriber;
+
+#[cfg(feature = "codec")]
+pub mod codec;
+
+#[cfg(feature = "codec")]
+pub use codec::MimiCodec;
+pub use error::{MimiError, Result};
+pub use publisher::{PublisherBuilder, PublisherSession};
+pub use subscriber::{RoomEvent, SubscriberBuilder, SubscriberSession};
+
+pub const SAMPLE_RATE: u32 = 24_000;
+/// Samples of a Mimi frame, 80ms.
+pub const FRAME_SIZE: usize = 1920;
+/// Codebooks of the codes accepted by the server.
+pub const NUM_CODEBOOKS: usize = 8;
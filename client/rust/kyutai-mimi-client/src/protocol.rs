@@ -0,0 +1,78 @@
+//! Binary messages of the Mimi endpoints, a type byte followed by the payload.
+
+use crate::error::{MimiError, Result};
+
+pub const HANDSHAKE: u8 = 0;
+pub const AUDIO: u8 = 1;
+pub const TEXT: u8 = 2;
+pub const PING: u8 = 6;
+pub const CODES: u8 = 9;
+
+/// Message of the room broadcast, in the default `moshi` format of the recv path.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RoomMsg {
+    Handshake,
+    /// An Ogg page of the room audio, the first one holds the Opus headers.
+    Audio(Vec<u8>),
+    Text(String),
+    Ping,
+}
+
+pub fn decode_room_msg(data: &[u8]) -> Result<RoomMsg> {
+    let (kind, payload) = data
+        .split_first()
+        .ok_or_else(|| MimiError::Message("empty message".to_string()))?;
+    let msg = match *kind {
+        HANDSHAKE => RoomMsg::Handshake,
+        AUDIO => RoomMsg::Audio(payload.to_vec()),
+        TEXT => RoomMsg::Text(String::from_utf8_lossy(payload).into_owned()),
+        PING => RoomMsg::Ping,
+        other => {
+            return Err(MimiError::Message(format!(
+                "unexpected message type {other}"
+            )));
+        }
+    };
+    Ok(msg)
+}
+
+/// The codes of one frame, one per codebook, as little-endian u32s.
+pub fn encode_codes(codes: &[u32]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(1 + 4 * codes.len());
+    msg.push(CODES);
+    for code in codes {
+        msg.extend_from_slice(&code.to_le_bytes());
+    }
+    msg
+}
+
+pub fn encode_audio(page: &[u8]) -> Vec<u8> {
+    [&[AUDIO], page].concat()
+}
+
+pub fn encode_text(text: &str) -> Vec<u8> {
+    [&[TEXT], text.as_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages() {
+        assert_eq!(encode_codes(&[1, 258]), [CODES, 1, 0, 0, 0, 2, 1, 0, 0]);
+        assert_eq!(
+            decode_room_msg(&encode_audio(&[7, 8])).unwrap(),
+            RoomMsg::Audio(vec![7, 8])
+        );
+        let text = decode_room_msg(&encode_text("hello")).unwrap();
+        assert_eq!(text, RoomMsg::Text("hello".to_string()));
+        assert_eq!(
+            decode_room_msg(&[HANDSHAKE, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap(),
+            RoomMsg::Handshake
+        );
+        assert_eq!(decode_room_msg(&[PING]).unwrap(), RoomMsg::Ping);
+        assert!(decode_room_msg(&[]).is_err());
+        assert!(decode_room_msg(&[42]).is_err());
+    }
+}
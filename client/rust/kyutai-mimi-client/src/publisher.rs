@@ -0,0 +1,141 @@
+use crate::error::{MimiError, Result};
+use crate::protocol;
+use futures_util::SinkExt;
+use kyutai_client_core::ws::{WsStream, build_ws_url, connect_ws};
+use tokio_tungstenite::tungstenite::Message;
+
+#[cfg(feature = "codec")]
+use crate::codec::MimiCodec;
+
+pub struct PublisherBuilder {
+    url: String,
+    auth_token: Option<String>,
+    room_id: Option<String>,
+    name: Option<String>,
+    #[cfg(feature = "codec")]
+    codec: Option<MimiCodec>,
+}
+
+impl PublisherBuilder {
+    /// `url` is the send path of the Mimi module, e.g. `ws://localhost:8080/api/mimi/send`.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            auth_token: None,
+            room_id: None,
+            name: None,
+            #[cfg(feature = "codec")]
+            codec: None,
+        }
+    }
+
+    pub fn auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth_token = Some(token.into());
+        self
+    }
+
+    /// Room to publish to, the default room of the server when unset.
+    pub fn room(mut self, room_id: impl Into<String>) -> Self {
+        self.room_id = Some(room_id.into());
+        self
+    }
+
+    /// Name of the publisher in the room mix, unique within the room. The server assigns
+    /// `publisher-N` when unset.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Encode the audio to Mimi codes locally instead of sending Ogg/Opus, which saves the
+    /// server the encoding.
+    #[cfg(feature = "codec")]
+    pub fn codec(mut self, codec: MimiCodec) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+
+    pub async fn connect(self) -> Result<PublisherSession> {
+        let mut query = vec![];
+        if let Some(room_id) = self.room_id.as_deref() {
+            query.push(("room_id", room_id));
+        }
+        if let Some(name) = self.name.as_deref() {
+            query.push(("publisher", name));
+        }
+        let url = build_ws_url(&self.url, "", &query, None)
+            .map_err(|e| MimiError::Message(e.to_string()))?;
+        let stream = connect_ws(&url, self.auth_token.as_deref())
+            .await
+            .map_err(|e| MimiError::Ws(e.to_string()))?;
+        Ok(PublisherSession {
+            stream,
+            encoder: None,
+            #[cfg(feature = "codec")]
+            codec: self.codec,
+        })
+    }
+}
+
+pub struct PublisherSession {
+    stream: WsStream,
+    /// Created on the first audio sent as Ogg/Opus, whose headers go first.
+    encoder: Option<kaudio::ogg_opus::Encoder>,
+    #[cfg(feature = "codec")]
+    codec: Option<MimiCodec>,
+}
+
+impl PublisherSession {
+    /// Send 24kHz mono audio, as Mimi codes when the session has a codec and as Ogg/Opus
+    /// otherwise.
+    pub async fn send_pcm(&mut self, pcm: &[f32]) -> Result<()> {
+        #[cfg(feature = "codec")]
+        if let Some(codec) = self.codec.as_mut() {
+            for codes in codec.encode(pcm)? {
+                self.send_codes(&codes).await?;
+            }
+            return Ok(());
+        }
+        let encoder = match self.encoder.as_mut() {
+            Some(encoder) => encoder,
+            None => {
+                let encoder = kaudio::ogg_opus::Encoder::new(crate::SAMPLE_RATE as usize)
+                    .map_err(|e| MimiError::Codec(e.to_string()))?;
+                let header = protocol::encode_audio(encoder.header_data());
+                self.send(header).await?;
+                self.encoder.insert(encoder)
+            }
+        };
+        let page = encoder
+            .encode_page(pcm)
+            .map_err(|e| MimiError::Codec(e.to_string()))?;
+        if !page.is_empty() {
+            self.send(protocol::encode_audio(&page)).await?;
+        }
+        Ok(())
+    }
+
+    /// Send the codes of one frame, [`crate::NUM_CODEBOOKS`] of them.
+    pub async fn send_codes(&mut self, codes: &[u32]) -> Result<()> {
+        self.send(protocol::encode_codes(codes)).await
+    }
+
+    /// Send a text message, forwarded as is to the listeners of the room.
+    pub async fn send_text(&mut self, text: &str) -> Result<()> {
+        self.send(protocol::encode_text(text)).await
+    }
+
+    pub async fn close(mut self) -> Result<()> {
+        self.stream
+            .close(None)
+            .await
+            .map_err(|e| MimiError::Ws(e.to_string()))
+    }
+
+    async fn send(&mut self, msg: Vec<u8>) -> Result<()> {
+        self.stream
+            .send(Message::Binary(msg.into()))
+            .await
+            .map_err(|e| MimiError::Ws(e.to_string()))
+    }
+}
@@ -0,0 +1,91 @@
+use crate::error::{MimiError, Result};
+use crate::protocol::{self, RoomMsg};
+use futures_util::StreamExt;
+use kyutai_client_core::ws::{WsStream, build_ws_url, connect_ws};
+use tokio_tungstenite::tungstenite::Message;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RoomEvent {
+    /// 24kHz mono audio of the room mix.
+    Audio { pcm: Vec<f32> },
+    /// Text message of a publisher.
+    Text(String),
+}
+
+pub struct SubscriberBuilder {
+    url: String,
+    auth_token: Option<String>,
+    room_id: Option<String>,
+}
+
+impl SubscriberBuilder {
+    /// `url` is the recv path of the Mimi module, e.g. `ws://localhost:8080/api/mimi/recv`.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            auth_token: None,
+            room_id: None,
+        }
+    }
+
+    pub fn auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth_token = Some(token.into());
+        self
+    }
+
+    /// Room to listen to, the default room of the server when unset.
+    pub fn room(mut self, room_id: impl Into<String>) -> Self {
+        self.room_id = Some(room_id.into());
+        self
+    }
+
+    pub async fn connect(self) -> Result<SubscriberSession> {
+        let query: Vec<_> = self
+            .room_id
+            .as_deref()
+            .map(|id| ("room_id", id))
+            .into_iter()
+            .collect();
+        let url = build_ws_url(&self.url, "", &query, None)
+            .map_err(|e| MimiError::Message(e.to_string()))?;
+        let stream = connect_ws(&url, self.auth_token.as_deref())
+            .await
+            .map_err(|e| MimiError::Ws(e.to_string()))?;
+        let decoder =
+            kaudio::ogg_opus::Decoder::new(crate::SAMPLE_RATE as usize, crate::FRAME_SIZE)
+                .map_err(|e| MimiError::Codec(e.to_string()))?;
+        Ok(SubscriberSession { stream, decoder })
+    }
+}
+
+pub struct SubscriberSession {
+    stream: WsStream,
+    decoder: kaudio::ogg_opus::Decoder,
+}
+
+impl SubscriberSession {
+    /// Next event of the room, `None` once the server closed the connection.
+    pub async fn recv(&mut self) -> Result<Option<RoomEvent>> {
+        while let Some(msg) = self.stream.next().await {
+            let data = match msg.map_err(|e| MimiError::Ws(e.to_string()))? {
+                Message::Binary(data) => data,
+                Message::Close(_) => return Ok(None),
+                _ => continue,
+            };
+            match protocol::decode_room_msg(&data)? {
+                RoomMsg::Audio(page) => {
+                    let pcm = self
+                        .decoder
+                        .decode(&page)
+                        .map_err(|e| MimiError::Codec(e.to_string()))?;
+                    if let Some(pcm) = pcm {
+                        return Ok(Some(RoomEvent::Audio { pcm: pcm.to_vec() }));
+                    }
+                }
+                RoomMsg::Text(text) => return Ok(Some(RoomEvent::Text(text))),
+                RoomMsg::Handshake | RoomMsg::Ping => {}
+            }
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,86 @@
+//! Publisher and subscriber sessions against a local server standing in for a Mimi room.
+
+use futures_util::{SinkExt, StreamExt};
+use kyutai_mimi_client::protocol;
+use kyutai_mimi_client::{PublisherBuilder, RoomEvent, SubscriberBuilder};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+/// Accept one connection and report its path and query on the channel. The connection then
+/// sends `replies` and closes when there are some, and reports its binary messages otherwise.
+async fn room_server(replies: Vec<Vec<u8>>) -> (String, mpsc::UnboundedReceiver<Vec<u8>>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        // The error type is the one of the tungstenite callbacks.
+        #[allow(clippy::result_large_err)]
+        let callback = |req: &Request, resp: Response| {
+            tx.send(req.uri().to_string().into_bytes()).unwrap();
+            Ok(resp)
+        };
+        let mut ws = tokio_tungstenite::accept_hdr_async(stream, callback)
+            .await
+            .unwrap();
+        if !replies.is_empty() {
+            for reply in replies {
+                ws.send(Message::Binary(reply.into())).await.unwrap();
+            }
+            ws.close(None).await.unwrap();
+            return;
+        }
+        while let Some(Ok(msg)) = ws.next().await {
+            match msg {
+                Message::Binary(data) => tx.send(data.to_vec()).unwrap(),
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+    });
+    (format!("ws://{addr}"), rx)
+}
+
+#[tokio::test]
+async fn publisher_round_trip() {
+    let (url, mut rx) = room_server(vec![]).await;
+    let mut session = PublisherBuilder::new(format!("{url}/api/mimi/send"))
+        .room("lobby")
+        .name("alice")
+        .connect()
+        .await
+        .unwrap();
+    session.send_codes(&[1, 2, 3, 4, 5, 6, 7, 8]).await.unwrap();
+    session.send_text("hello").await.unwrap();
+    session.close().await.unwrap();
+
+    let uri = String::from_utf8(rx.recv().await.unwrap()).unwrap();
+    assert_eq!(uri, "/api/mimi/send?room_id=lobby&publisher=alice");
+    let codes = rx.recv().await.unwrap();
+    assert_eq!(codes, protocol::encode_codes(&[1, 2, 3, 4, 5, 6, 7, 8]));
+    assert_eq!(rx.recv().await.unwrap(), protocol::encode_text("hello"));
+    assert!(rx.recv().await.is_none());
+}
+
+#[tokio::test]
+async fn subscriber_round_trip() {
+    let replies = vec![
+        vec![protocol::HANDSHAKE, 0, 0, 0, 0, 0, 0, 0, 0],
+        vec![protocol::PING],
+        protocol::encode_text("hello"),
+    ];
+    let (url, mut rx) = room_server(replies).await;
+    let mut session = SubscriberBuilder::new(format!("{url}/api/mimi/recv"))
+        .room("lobby")
+        .connect()
+        .await
+        .unwrap();
+    let uri = String::from_utf8(rx.recv().await.unwrap()).unwrap();
+    assert_eq!(uri, "/api/mimi/recv?room_id=lobby");
+
+    // The handshake and the pings are not events.
+    let event = session.recv().await.unwrap();
+    assert_eq!(event, Some(RoomEvent::Text("hello".to_string())));
+    assert_eq!(session.recv().await.unwrap(), None);
+}
@@ -7,9 +7,29 @@ use candle::{IndexOp, Result, Tensor};
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum AsrMsg {
-    Step { step_idx: usize, prs: Vec<Vec<f32>> },
-    Word { tokens: Vec<u32>, start_time: f64, batch_idx: usize },
-    EndWord { stop_time: f64, batch_idx: usize },
+    Step {
+        step_idx: usize,
+        prs: Vec<Vec<f32>>,
+    },
+    /// `token_times` holds the time at which each of the `tokens` was emitted.
+    Word {
+        tokens: Vec<u32>,
+        token_times: Vec<f64>,
+        start_time: f64,
+        batch_idx: usize,
+    },
+    EndWord {
+        stop_time: f64,
+        batch_idx: usize,
+    },
+    /// The text logits of the slot were not finite, e.g. an overflow with a half precision
+    /// dtype. When the logits retried in f32 are not finite either, the transformer state of
+    /// the slot is reset and the step yields no text.
+    NonFinite {
+        step_idx: usize,
+        batch_idx: usize,
+        recovered: bool,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -17,6 +37,7 @@ pub struct ItemState {
     step_idx: usize,
     text_token: u32,
     word_tokens: Vec<u32>,
+    word_token_times: Vec<f64>,
     unended_word: bool,
     last_stop_time: f64,
 }
@@ -27,6 +48,7 @@ impl ItemState {
             step_idx: 0,
             text_token,
             word_tokens: vec![],
+            word_token_times: vec![],
             last_stop_time: 0.0,
             unended_word: false,
         }
@@ -36,6 +58,7 @@ impl ItemState {
         self.step_idx = 0;
         self.text_token = text_start_token;
         self.word_tokens.clear();
+        self.word_token_times.clear();
         self.unended_word = false;
         self.last_stop_time = 0.;
     }
@@ -47,7 +70,6 @@ impl ItemState {
     pub fn is_first_step(&self) -> bool {
         self.step_idx == 0
     }
-
 }
 
 pub struct State {
@@ -72,7 +94,8 @@ impl State {
         let text_token = lm.text_start_token();
         let device = lm.device().clone();
         let item_state = ItemState::new(text_token);
-        let next_codebooks = Tensor::full(lm.audio_pad_token(), (batch_size, lm.in_audio_codebooks()), &device)?;
+        let next_codebooks =
+            Tensor::full(lm.audio_pad_token(), (batch_size, lm.in_audio_codebooks()), &device)?;
         let mut s = Self {
             asr_delay_in_tokens,
             lm,
@@ -108,7 +131,8 @@ impl State {
         self.lm.reset_state();
         self.audio_tokenizer.reset_state();
         self.batch.iter_mut().for_each(|s| s.reset(text_start_token));
-        self.next_codebooks = Tensor::full(self.lm.audio_pad_token(), self.next_codebooks.shape(), &self.device)?;
+        self.next_codebooks =
+            Tensor::full(self.lm.audio_pad_token(), self.next_codebooks.shape(), &self.device)?;
         Ok(())
     }
 
@@ -169,8 +193,9 @@ impl State {
             let audio_step_tokens =
                 audio_tokens.narrow(2, step, 1)?.reshape((batch_size, codebooks))?;
 
-            let is_first_step_t = Tensor::from_vec(is_first_step_vec.clone(), (batch_size, 1), &dev)?
-                .to_dtype(candle::DType::U8)?;
+            let is_first_step_t =
+                Tensor::from_vec(is_first_step_vec.clone(), (batch_size, 1), &dev)?
+                    .to_dtype(candle::DType::U8)?;
             let pad_tokens = Tensor::full(audio_pad_token, (batch_size, codebooks), &dev)?;
             let next_tokens_t = is_first_step_t.where_cond(&pad_tokens, &self.next_codebooks)?;
 
@@ -205,16 +230,29 @@ impl State {
                 words.push(AsrMsg::Step { step_idx: self.model_step_idx(), prs });
             }
 
-            let text_tokens = if self.temperature <= 0.0 {
-                text_logits.i((.., 0))?.argmax(candle::D::Minus1)?
-            } else {
-                candle_nn::sampling::gumbel_softmax(
-                    &text_logits.i((.., 0))?.to_dtype(candle::DType::F32)?,
-                    self.temperature,
-                    candle::D::Minus1,
-                )?
-            };
-            let text_tokens = text_tokens.to_vec1::<u32>()?;
+            let text_logits = text_logits.i((.., 0))?;
+            let mut text_tokens = self.sample_text(&text_logits)?.to_vec1::<u32>()?;
+            // The f32 path is left as is, overflows are a half precision issue.
+            if text_logits.dtype() != candle::DType::F32 {
+                let step_idx = self.model_step_idx();
+                for batch_idx in nonfinite_rows(&text_logits)? {
+                    if !mask.is_active(batch_idx) {
+                        continue;
+                    }
+                    let ys = transformer_out.narrow(0, batch_idx, 1)?;
+                    let logits = self.lm.text_logits_f32(&ys)?.i((.., 0))?;
+                    let recovered = nonfinite_rows(&logits)?.is_empty();
+                    if recovered {
+                        text_tokens[batch_idx] = self.sample_text(&logits)?.to_vec1::<u32>()?[0];
+                    } else {
+                        // The nans are in the kv cache of the slot, its next steps would get
+                        // them too. The slot keeps its timeline, only the context is lost.
+                        self.lm.reset_batch_idx(batch_idx, batch_size)?;
+                        text_tokens[batch_idx] = 3;
+                    }
+                    words.push(AsrMsg::NonFinite { step_idx, batch_idx, recovered });
+                }
+            }
             for (batch_idx, (text_token, item)) in
                 text_tokens.into_iter().zip(self.batch.iter_mut()).enumerate()
             {
@@ -226,22 +264,25 @@ impl State {
                 is_first_step_vec[batch_idx] = 0; // No longer first step
 
                 if item.step_idx >= self.asr_delay_in_tokens {
+                    let time = (item.step_idx - self.asr_delay_in_tokens) as f64 / 12.5;
                     if text_token == 3 || text_token == 0 {
                         if !item.word_tokens.is_empty() {
-                            let mut tokens = vec![];
-                            std::mem::swap(&mut item.word_tokens, &mut tokens);
+                            let tokens = std::mem::take(&mut item.word_tokens);
+                            let token_times = std::mem::take(&mut item.word_token_times);
                             words.push(AsrMsg::Word {
                                 tokens,
+                                token_times,
                                 start_time: item.last_stop_time,
                                 batch_idx,
                             });
                             item.unended_word = true;
                         }
                     } else {
-                        item.word_tokens.push(item.text_token)
+                        item.word_tokens.push(item.text_token);
+                        item.word_token_times.push(time);
                     }
                     if item.text_token == 0 {
-                        let stop_time = (item.step_idx - self.asr_delay_in_tokens) as f64 / 12.5;
+                        let stop_time = time;
                         if item.unended_word {
                             item.unended_word = false;
                             words.push(AsrMsg::EndWord { stop_time, batch_idx });
@@ -254,6 +295,18 @@ impl State {
         Ok(words)
     }
 
+    fn sample_text(&self, logits: &Tensor) -> Result<Tensor> {
+        if self.temperature <= 0.0 {
+            logits.argmax(candle::D::Minus1)
+        } else {
+            candle_nn::sampling::gumbel_softmax(
+                &logits.to_dtype(candle::DType::F32)?,
+                self.temperature,
+                candle::D::Minus1,
+            )
+        }
+    }
+
     pub fn reset_batch_idx(&mut self, batch_idx: usize) -> Result<()> {
         if batch_idx >= self.batch_size() {
             candle::bail!("batch index out of range: {batch_idx} >= {}", self.batch_size());
@@ -282,3 +335,28 @@ impl State {
         self.step_tokens(&audio_tokens, conditions, mask, f)
     }
 }
+
+/// Rows of the `(batch, vocab)` logits holding a nan or an infinity.
+fn nonfinite_rows(logits: &Tensor) -> Result<Vec<usize>> {
+    let logits = logits.to_dtype(candle::DType::F32)?;
+    // Zero for finite values, nan otherwise.
+    let rows = logits.affine(0.0, 0.0)?.sum(candle::D::Minus1)?.to_vec1::<f32>()?;
+    Ok(rows.iter().enumerate().filter(|(_, v)| !v.is_finite()).map(|(i, _)| i).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_nonfinite_rows() -> Result<()> {
+        let logits = [1.0f32, 2.0, 0.5, f32::NAN, -3.0, f32::NEG_INFINITY, 0.0, 1e4];
+        let logits = Tensor::new(&logits, &candle::Device::Cpu)?.reshape((4, 2))?;
+        assert_eq!(nonfinite_rows(&logits)?, [1, 2]);
+        // 1e5 overflows in f16.
+        let logits = Tensor::new(&[[1.0f32, 1e5], [1.0, 1e4]], &candle::Device::Cpu)?;
+        assert_eq!(nonfinite_rows(&logits)?, Vec::<usize>::new());
+        assert_eq!(nonfinite_rows(&logits.to_dtype(candle::DType::F16)?)?, [0]);
+        Ok(())
+    }
+}
@@ -600,6 +600,7 @@ fn linear_from_vb(
 #[derive(Debug, Clone)]
 pub struct DepFormer {
     slices: Vec<DepFormerSlice>,
+    span: tracing::Span,
 }
 
 impl DepFormer {
@@ -632,7 +633,7 @@ impl DepFormer {
 
             slices.push(slice)
         }
-        Ok(Self { slices })
+        Ok(Self { slices, span: tracing::span!(tracing::Level::TRACE, "depformer") })
     }
 
     /// Run a transformer sampling step, getting a token id per codebook.
@@ -645,6 +646,7 @@ impl DepFormer {
         lp: &mut candle_transformers::generation::LogitsProcessor,
     ) -> Result<Vec<u32>> {
         use crate::streaming::StreamingModule;
+        let _enter = self.span.clone().entered();
         let dev = xs.device();
         let mut tokens = Vec::with_capacity(self.slices.len());
         let mut last_token = text_token;
@@ -693,6 +695,7 @@ impl DepFormer {
         lp: &mut candle_transformers::generation::LogitsProcessor,
     ) -> Result<Vec<u32>> {
         use crate::streaming::StreamingModule;
+        let _enter = self.span.clone().entered();
         let dev = xs.device();
         let mut tokens = Vec::with_capacity(self.slices.len());
         let mut last_token = text_token;
@@ -806,6 +809,7 @@ pub struct LmModel {
     condition_provider: Option<crate::conditioner::ConditionProvider>,
     extra_heads: Vec<MaybeQuantizedLinear>,
     dtype: DType,
+    span: tracing::Span,
 }
 
 impl LmModel {
@@ -893,6 +897,7 @@ impl LmModel {
             condition_provider,
             extra_heads,
             dtype,
+            span: tracing::span!(tracing::Level::TRACE, "lm-forward"),
         })
     }
 
@@ -961,6 +966,7 @@ impl LmModel {
         conditions: Option<&crate::conditioner::Condition>,
         mask: &StreamMask,
     ) -> candle::Result<(Tensor, Tensor)> {
+        let _enter = self.span.clone().entered();
         if VERBOSE.with(|v| *v) {
             print!("text_ids ");
             if let Some(text_ids) = text_ids.as_ref() {
@@ -1023,6 +1029,7 @@ impl LmModel {
         conditions: Option<&crate::conditioner::Condition>,
         mask: &StreamMask,
     ) -> candle::Result<(Tensor, Tensor)> {
+        let _enter = self.span.clone().entered();
         if VERBOSE.with(|v| *v) {
             print!("text_ids ");
             if let Some(text_ids) = text_ids.as_ref() {
@@ -1108,6 +1115,21 @@ impl LmModel {
     pub fn reset_batch_idx(&mut self, batch_idx: usize, batch_size: usize) -> Result<()> {
         self.transformer.reset_batch_idx(batch_idx, batch_size)
     }
+
+    /// Text logits in f32 for the transformer output `ys` returned by `forward_cond`, to retry
+    /// the head of a step whose half precision logits overflowed.
+    pub fn text_logits_f32(&self, ys: &Tensor) -> Result<Tensor> {
+        let ys = ys.to_dtype(DType::F32)?;
+        match &self.text_linear {
+            MaybeQuantizedLinear::Real(l) => {
+                let weight = l.weight().to_dtype(DType::F32)?;
+                let bias = l.bias().map(|b| b.to_dtype(DType::F32)).transpose()?;
+                ys.apply(&candle_nn::Linear::new(weight, bias))
+            }
+            // Quantized matmuls already run in f32.
+            MaybeQuantizedLinear::Quantized(_) => ys.apply(&self.text_linear),
+        }
+    }
 }
 
 pub fn load_lm_model<P: AsRef<std::path::Path>>(
@@ -103,6 +103,8 @@ pub struct Mimi {
     upsample: conv::ConvTrUpsample1d,
     quantizer: quantization::SplitResidualVectorQuantizer,
     config: Config,
+    span_encode: tracing::Span,
+    span_decode: tracing::Span,
 }
 
 impl Mimi {
@@ -167,6 +169,8 @@ impl Mimi {
             downsample,
             upsample,
             config: cfg,
+            span_encode: tracing::span!(tracing::Level::TRACE, "mimi-encode-step"),
+            span_decode: tracing::span!(tracing::Level::TRACE, "mimi-decode-step"),
         })
     }
 
@@ -193,6 +197,7 @@ impl Mimi {
     }
 
     pub fn encode_step(&mut self, xs: &StreamTensor, m: &StreamMask) -> Result<StreamTensor> {
+        let _enter = self.span_encode.clone().entered();
         let xs = self.encoder.step(xs, m)?;
         let xs = self.encoder_transformer.step(&xs, m)?;
         let xs = self.downsample.step(&xs, m)?;
@@ -215,6 +220,7 @@ impl Mimi {
     }
 
     pub fn decode_step(&mut self, codes: &StreamTensor, m: &StreamMask) -> Result<StreamTensor> {
+        let _enter = self.span_decode.clone().entered();
         let emb = match codes.as_option() {
             Some(codes) => StreamTensor::from_tensor(self.quantizer.decode(codes)?),
             None => StreamTensor::empty(),
@@ -31,7 +31,17 @@ pub fn write_wav_header<W: Write>(
     chunk_size: u32,
     data_size: u32,
 ) -> std::io::Result<()> {
-    let n_channels = 1u16;
+    write_wav_header_with_channels(w, sample_rate, 1, chunk_size, data_size)
+}
+
+/// Header of a 16-bit PCM file with `n_channels` interleaved channels.
+pub fn write_wav_header_with_channels<W: Write>(
+    w: &mut W,
+    sample_rate: u32,
+    n_channels: u16,
+    chunk_size: u32,
+    data_size: u32,
+) -> std::io::Result<()> {
     let bits_per_sample = 16u16;
     let byte_rate = sample_rate * n_channels as u32 * (bits_per_sample / 8) as u32;
     let block_align = n_channels * (bits_per_sample / 8);
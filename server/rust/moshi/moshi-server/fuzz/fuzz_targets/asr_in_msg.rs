@@ -0,0 +1,29 @@
+//! Decoding of the frames sent by asr, batched asr and vad clients.
+//!
+//! Run with `cargo +nightly fuzz run asr_in_msg`. Besides panics, the target fails when decoding
+//! a frame allocates much more than the frame size.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use moshi_server::protocol::{decode_in_msg, InMsg};
+
+#[global_allocator]
+static GLOBAL: fuzz_alloc::Tracking = fuzz_alloc::Tracking;
+
+fuzz_target!(|data: &[u8]| {
+    let msg = fuzz_alloc::check_alloc(data, decode_in_msg);
+    match msg {
+        Ok(InMsg::Audio { pcm }) => assert!(pcm.iter().all(|v| v.is_finite())),
+        Ok(InMsg::OggOpus { data }) => {
+            // The payload goes straight to the opus decoder of the session.
+            let mut decoder = kaudio::ogg_opus::Decoder::new(24000, 1920).unwrap();
+            let _ = decoder.decode(&data);
+        }
+        Ok(msg) => {
+            // Whatever is accepted must survive a round trip.
+            let data = rmp_serde::to_vec_named(&msg).unwrap();
+            assert_eq!(decode_in_msg(&data).as_ref(), Ok(&msg));
+        }
+        Err(_) => {}
+    }
+});
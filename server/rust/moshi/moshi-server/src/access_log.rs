@@ -0,0 +1,204 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Access log of the REST endpoints, one JSON line per request.
+//!
+//! The lines are written by their own thread to their own file rather than going through
+//! `tracing`, so that their format does not depend on the log settings and they can be shipped
+//! to a SIEM as they are. Websocket upgrades are left out, their sessions are logged by the
+//! modules. The query string is not logged as it may hold a token.
+
+use anyhow::{Context, Result};
+use axum::body::HttpBody;
+use axum::extract::{ConnectInfo, Query, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// File the lines are appended to, `<log_dir>/access.log` by default, `-` for stdout.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Share of the successful requests that are logged.
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    /// Log all the requests answered with a 4xx or 5xx status, whatever the sample rate.
+    #[serde(default = "default_log_errors")]
+    pub log_errors: bool,
+    /// Paths that are never logged, e.g. `/api/health` for the probes of a load balancer.
+    #[serde(default)]
+    pub skip_paths: Vec<String>,
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+fn default_log_errors() -> bool {
+    true
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: None,
+            sample_rate: default_sample_rate(),
+            log_errors: default_log_errors(),
+            skip_paths: vec![],
+        }
+    }
+}
+
+impl AccessLogConfig {
+    fn skipped(&self, path: &str) -> bool {
+        self.skip_paths.iter().any(|p| p == path)
+    }
+
+    /// Whether a request is logged, `draw` being uniform in `[0, 1)`.
+    fn sampled(&self, status: StatusCode, draw: f64) -> bool {
+        let error = status.is_client_error() || status.is_server_error();
+        (self.log_errors && error) || draw < self.sample_rate
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Entry<'a> {
+    schema_version: u64,
+    ts: String,
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    duration_ms: f64,
+    user_id: Option<String>,
+    request_bytes: Option<u64>,
+    /// Unknown for streamed bodies.
+    response_bytes: Option<u64>,
+    request_id: Option<&'a str>,
+    remote_addr: Option<SocketAddr>,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+pub struct AccessLog {
+    config: AccessLogConfig,
+    tx: std::sync::mpsc::Sender<String>,
+}
+
+impl AccessLog {
+    /// Open the log file and start the thread writing to it.
+    pub fn open(config: &AccessLogConfig, log_dir: &str) -> Result<Self> {
+        let mut out: Box<dyn Write + Send> = match config.path.as_deref() {
+            Some(path) if path == Path::new("-") => Box::new(std::io::stdout()),
+            path => {
+                let path =
+                    path.map_or_else(|| Path::new(log_dir).join("access.log"), PathBuf::from);
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir).with_context(|| dir.display().to_string())?;
+                }
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .with_context(|| path.display().to_string())?;
+                tracing::info!(path = %path.display(), "writing the access log");
+                Box::new(std::io::LineWriter::new(file))
+            }
+        };
+        let (tx, rx) = std::sync::mpsc::channel::<String>();
+        std::thread::Builder::new().name("access_log".to_string()).spawn(move || {
+            for line in rx {
+                if let Err(err) = writeln!(out, "{line}") {
+                    tracing::error!(?err, "cannot write the access log");
+                }
+            }
+        })?;
+        Ok(Self { config: config.clone(), tx })
+    }
+}
+
+/// Middleware logging the requests that are not websocket upgrades.
+pub async fn log_request(
+    State(log): State<Arc<AccessLog>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let upgrade = request.headers().get(header::UPGRADE);
+    if upgrade.is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"websocket"))
+        || log.config.skipped(request.uri().path())
+    {
+        return next.run(request).await;
+    }
+    let start = Instant::now();
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let headers = request.headers().clone();
+    let query_token = Query::<TokenQuery>::try_from_uri(request.uri()).ok().and_then(|q| q.0.token);
+    let request_bytes = request.body().size_hint().exact();
+    let remote_addr = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|c| c.0);
+
+    let response = next.run(request).await;
+    if !log.config.sampled(response.status(), rand::random()) {
+        return response;
+    }
+    let request_id = headers
+        .get(REQUEST_ID_HEADER)
+        .or_else(|| response.headers().get(REQUEST_ID_HEADER))
+        .and_then(|v| v.to_str().ok());
+    let entry = Entry {
+        schema_version: crate::schema::Artifact::AccessLog.version(),
+        ts: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        method: method.as_str(),
+        path: &path,
+        status: response.status().as_u16(),
+        duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+        user_id: crate::auth::user_id(&headers, query_token.as_deref()),
+        request_bytes,
+        response_bytes: response.body().size_hint().exact(),
+        request_id,
+        remote_addr,
+    };
+    match serde_json::to_string(&entry) {
+        Ok(line) => {
+            let _ = log.tx.send(line);
+        }
+        Err(err) => tracing::error!(?err, "cannot serialize an access log entry"),
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampling_keeps_errors_and_skips_paths() {
+        let cfg: AccessLogConfig =
+            toml::from_str("enabled = true\nsample_rate = 0.1\nskip_paths = [\"/api/health\"]")
+                .unwrap();
+        assert!(cfg.log_errors);
+        assert!(cfg.skipped("/api/health"));
+        assert!(!cfg.skipped("/api/asr-streaming/batch"));
+        assert!(cfg.sampled(StatusCode::OK, 0.05));
+        assert!(!cfg.sampled(StatusCode::OK, 0.5));
+        assert!(cfg.sampled(StatusCode::UNAUTHORIZED, 0.5));
+        assert!(cfg.sampled(StatusCode::SERVICE_UNAVAILABLE, 0.99));
+        let cfg = AccessLogConfig { log_errors: false, sample_rate: 0.0, ..cfg };
+        assert!(!cfg.sampled(StatusCode::INTERNAL_SERVER_ERROR, 0.0));
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Documents that admins manage per tenant through the `/api/admin/{tenant}/{kind}` endpoints
+//! instead of editing the config and restarting:
+//!
+//! - `voices`: the language, tags and description of a voice, overriding the voice catalog in
+//!   the `voices` listing of the TTS modules for the users of the tenant,
+//! - `lexicons`: the pronunciation of words, for a language,
+//! - `boost_words`: lists of words that recognition should favor, with their boost.
+//!
+//! Users with the `admin` role manage all the tenants, or only theirs when their token carries
+//! a `tenant`.
+
+use crate::errors::ApiError;
+use crate::storage::Storage;
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+/// Tenant of the users whose token does not carry one.
+pub const DEFAULT_TENANT: &str = "default";
+const MAX_NAME_LEN: usize = crate::storage::MAX_NAME_BYTES;
+/// Entries of a lexicon or words of a boost list.
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Directory holding the documents, the admin endpoints are disabled when unset.
+    #[serde(default)]
+    pub storage_dir: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Voices,
+    Lexicons,
+    BoostWords,
+}
+
+impl Kind {
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "voices" => Some(Self::Voices),
+            "lexicons" => Some(Self::Lexicons),
+            "boost_words" => Some(Self::BoostWords),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Voices => "voices",
+            Self::Lexicons => "lexicons",
+            Self::BoostWords => "boost_words",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Lexicon {
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Pronunciation of each word, as IPA or as a respelling.
+    pub entries: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BoostWords {
+    pub words: Vec<String>,
+    #[serde(default)]
+    pub boost: Option<f32>,
+}
+
+fn invalid<T>(msg: String) -> Result<T> {
+    Err(ApiError::InvalidRequest(msg).into())
+}
+
+fn check_tenant(tenant: &str) -> Result<()> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if tenant.is_empty() || tenant.len() > 64 || !tenant.chars().all(valid) {
+        return invalid(format!("invalid tenant '{tenant}', use up to 64 of [A-Za-z0-9_-]"));
+    }
+    Ok(())
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.chars().any(char::is_control) {
+        return invalid(format!("invalid name, use 1 to {MAX_NAME_LEN} bytes of printable text"));
+    }
+    Ok(())
+}
+
+/// Check a document against the schema of its kind, returns it with the defaults filled in.
+fn validate(kind: Kind, doc: serde_json::Value) -> Result<serde_json::Value> {
+    fn parse<T: serde::de::DeserializeOwned>(doc: serde_json::Value) -> Result<T> {
+        serde_json::from_value(doc).or_else(|err| invalid(err.to_string()))
+    }
+    let doc = match kind {
+        Kind::Voices => serde_json::to_value(parse::<crate::voices::Meta>(doc)?)?,
+        Kind::Lexicons => {
+            let lexicon: Lexicon = parse(doc)?;
+            if lexicon.entries.len() > MAX_ENTRIES {
+                return invalid(format!("a lexicon holds at most {MAX_ENTRIES} entries"));
+            }
+            if lexicon.entries.iter().any(|(w, p)| w.trim().is_empty() || p.trim().is_empty()) {
+                return invalid("lexicon entries cannot be empty".to_string());
+            }
+            serde_json::to_value(lexicon)?
+        }
+        Kind::BoostWords => {
+            let boost: BoostWords = parse(doc)?;
+            if boost.words.len() > MAX_ENTRIES {
+                return invalid(format!("a boost list holds at most {MAX_ENTRIES} words"));
+            }
+            if boost.words.iter().any(|w| w.trim().is_empty()) {
+                return invalid("boost words cannot be empty".to_string());
+            }
+            if boost.boost.is_some_and(|b| !b.is_finite()) {
+                return invalid("boost has to be a finite number".to_string());
+            }
+            serde_json::to_value(boost)?
+        }
+    };
+    Ok(doc)
+}
+
+/// 403 when the token of an admin is bound to another tenant.
+pub fn authorize(claims: Option<&crate::auth::BetterAuthClaims>, tenant: &str) -> Result<()> {
+    match claims.and_then(|c| c.user.tenant.as_deref()) {
+        Some(own) if own != tenant => {
+            Err(ApiError::Forbidden(format!("not authorized for tenant {tenant}")).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+pub struct AdminStore {
+    storage: Box<dyn Storage>,
+}
+
+impl AdminStore {
+    pub fn new(storage: Box<dyn Storage>) -> Self {
+        Self { storage }
+    }
+
+    fn prefix(tenant: &str, kind: Kind) -> Result<String> {
+        check_tenant(tenant)?;
+        Ok(format!("{tenant}/{}", kind.as_str()))
+    }
+
+    pub fn list(&self, tenant: &str, kind: Kind) -> Result<BTreeMap<String, serde_json::Value>> {
+        let prefix = Self::prefix(tenant, kind)?;
+        let mut docs = BTreeMap::new();
+        for name in self.storage.list(&prefix)? {
+            if let Some(doc) = self.storage.get(&prefix, &name)? {
+                docs.insert(name, serde_json::from_slice(&doc)?);
+            }
+        }
+        Ok(docs)
+    }
+
+    pub fn get(&self, tenant: &str, kind: Kind, name: &str) -> Result<Option<serde_json::Value>> {
+        let prefix = Self::prefix(tenant, kind)?;
+        check_name(name)?;
+        match self.storage.get(&prefix, name)? {
+            None => Ok(None),
+            Some(doc) => Ok(Some(serde_json::from_slice(&doc)?)),
+        }
+    }
+
+    /// Create or replace a document, returns it as stored.
+    pub fn put(
+        &self,
+        tenant: &str,
+        kind: Kind,
+        name: &str,
+        doc: serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        let prefix = Self::prefix(tenant, kind)?;
+        check_name(name)?;
+        let doc = validate(kind, doc)?;
+        self.storage.put(&prefix, name, &serde_json::to_vec(&doc)?)?;
+        Ok(doc)
+    }
+
+    pub fn delete(&self, tenant: &str, kind: Kind, name: &str) -> Result<bool> {
+        let prefix = Self::prefix(tenant, kind)?;
+        check_name(name)?;
+        self.storage.delete(&prefix, name)
+    }
+
+    /// Voice metadata of a tenant, for the `voices` listing.
+    pub fn voices(&self, tenant: &str) -> Result<BTreeMap<String, crate::voices::Meta>> {
+        let mut voices = BTreeMap::new();
+        for (name, doc) in self.list(tenant, Kind::Voices)? {
+            voices.insert(name, serde_json::from_value(doc)?);
+        }
+        Ok(voices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct MemoryStorage(std::sync::Mutex<BTreeMap<(String, String), Vec<u8>>>);
+
+    impl Storage for MemoryStorage {
+        fn get(&self, prefix: &str, name: &str) -> Result<Option<Vec<u8>>> {
+            Ok(self.0.lock().unwrap().get(&(prefix.to_string(), name.to_string())).cloned())
+        }
+
+        fn put(&self, prefix: &str, name: &str, value: &[u8]) -> Result<()> {
+            let key = (prefix.to_string(), name.to_string());
+            self.0.lock().unwrap().insert(key, value.to_vec());
+            Ok(())
+        }
+
+        fn delete(&self, prefix: &str, name: &str) -> Result<bool> {
+            let key = (prefix.to_string(), name.to_string());
+            Ok(self.0.lock().unwrap().remove(&key).is_some())
+        }
+
+        fn list(&self, prefix: &str) -> Result<Vec<String>> {
+            let docs = self.0.lock().unwrap();
+            Ok(docs.keys().filter(|(p, _)| p == prefix).map(|(_, n)| n.clone()).collect())
+        }
+    }
+
+    fn status(err: anyhow::Error) -> axum::http::StatusCode {
+        ApiError::from(err).status()
+    }
+
+    #[test]
+    fn documents_are_validated_and_scoped_by_tenant() {
+        let store = AdminStore::new(Box::new(MemoryStorage::default()));
+        let voice = serde_json::json!({ "language": "en", "tags": ["calm"] });
+        let stored = store.put("acme", Kind::Voices, "vctk/p225_023.wav", voice).unwrap();
+        assert_eq!(stored["description"], serde_json::Value::Null);
+        let lexicon = serde_json::json!({ "entries": { "Kyutai": "kyoo-tie" } });
+        store.put("acme", Kind::Lexicons, "brands", lexicon).unwrap();
+
+        let voices = store.voices("acme").unwrap();
+        assert_eq!(voices["vctk/p225_023.wav"].tags, ["calm"]);
+        assert!(store.voices("other").unwrap().is_empty());
+        let lexicon = store.get("acme", Kind::Lexicons, "brands").unwrap().unwrap();
+        assert_eq!(lexicon["entries"]["Kyutai"], "kyoo-tie");
+        assert!(store.delete("acme", Kind::Lexicons, "brands").unwrap());
+        assert!(store.list("acme", Kind::Lexicons).unwrap().is_empty());
+
+        let bad = serde_json::json!({ "words": ["kyutai"], "weight": 2.0 });
+        let err = store.put("acme", Kind::BoostWords, "names", bad).unwrap_err();
+        assert_eq!(status(err), axum::http::StatusCode::BAD_REQUEST);
+        let err = store.list("../acme", Kind::Voices).unwrap_err();
+        assert_eq!(status(err), axum::http::StatusCode::BAD_REQUEST);
+        let long = "a".repeat(MAX_NAME_LEN + 1);
+        let err = store.put("acme", Kind::Voices, &long, serde_json::json!({})).unwrap_err();
+        assert_eq!(status(err), axum::http::StatusCode::BAD_REQUEST);
+
+        let claims: crate::auth::BetterAuthClaims = serde_json::from_value(serde_json::json!({
+            "session": {
+                "id": "s", "userId": "u", "createdAt": "", "updatedAt": "", "expiresAt": "",
+            },
+            "user": { "id": "u", "role": "admin", "tenant": "acme" },
+        }))
+        .unwrap();
+        assert!(authorize(Some(&claims), "acme").is_ok());
+        let err = authorize(Some(&claims), "other").unwrap_err();
+        assert_eq!(status(err), axum::http::StatusCode::FORBIDDEN);
+        assert!(authorize(None, "other").is_ok());
+    }
+}
@@ -2,54 +2,182 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
+use crate::retune::{RetuneQuery, Tuning};
 use crate::AsrStreamingQuery as Query;
 use anyhow::{Context, Result};
 use axum::extract::ws;
 use candle::{DType, Device, IndexOp, Tensor};
-use candle_nn::VarBuilder;
 use std::collections::VecDeque;
 use tokio::time::{timeout, Duration};
 
 const FRAME_SIZE: usize = 1920;
 
-#[derive(serde::Serialize, serde::Deserialize)]
-#[serde(tag = "type")]
-pub enum InMsg {
-    Init,
-    Marker { id: i64 },
-    Audio { pcm: Vec<f32> },
-    OggOpus { data: Vec<u8> },
-    Ping,
-}
+pub use crate::protocol::InMsg;
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(tag = "type")]
 pub enum OutMsg {
-    Word { text: String, start_time: f64 },
-    EndWord { stop_time: f64 },
-    Marker { id: i64 },
-    Step { step_idx: usize, prs: Vec<f32>, buffered_pcm: usize },
-    Error { message: String },
-    Ready,
+    Word {
+        text: String,
+        start_time: f64,
+        /// Language of the word, when `word_lang` is enabled.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lang: Option<String>,
+        /// Sub-word pieces of the word, with `detail=tokens`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tokens: Option<Vec<WordToken>>,
+    },
+    EndWord {
+        stop_time: f64,
+    },
+    /// Answers a `Marker` once the audio sent before it has been processed, with the session
+    /// time of the marker in 24kHz samples and the model step that consumed it.
+    Marker {
+        id: i64,
+        #[serde(default)]
+        sample_idx: u64,
+        #[serde(default)]
+        step_idx: usize,
+    },
+    Step {
+        step_idx: usize,
+        prs: Vec<f32>,
+        buffered_pcm: usize,
+        /// Correction added to the word timestamps so that they follow the source media,
+        /// see [`crate::drift`].
+        #[serde(default)]
+        time_correction_ms: f64,
+    },
+    Error {
+        message: String,
+    },
+    /// The session is set up, with the ids of the upstream request when the gateway sent
+    /// them, see [`crate::otel::TraceIds`].
+    Ready {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        trace_id: Option<String>,
+    },
+    /// Opaque session state, answers a `Checkpoint` request.
+    Checkpoint {
+        data: Vec<u8>,
+    },
+    /// Start of a new segment of a long session, the words of the previous one are final.
+    SegmentBoundary {
+        index: usize,
+        start_s: f64,
+    },
+    /// Level of the received audio over the last stats interval, in dBFS, with the fraction
+    /// of clipped samples and of 80ms frames loud enough to hold speech.
+    AudioStats {
+        rms_db: f32,
+        clipping_ratio: f32,
+        speech_ratio: f32,
+    },
+    /// Words per minute over the last minute of the session.
+    SpeechRate {
+        wpm: f32,
+    },
+    /// Fraction of the speech since the last report in which two voices overlap.
+    Overlap {
+        ratio: f32,
+    },
+    /// Number of voices heard in the session, sent before answering a `Marker`.
+    SpeakerCountEstimate {
+        n: usize,
+    },
+    /// Answers an `Enroll` message, with the duration of the speech found in the clip.
+    Enrolled {
+        voiced_s: f64,
+    },
+    /// Answers an `Echo` message, with the wall clock of the server in milliseconds since the
+    /// unix epoch when the message was received and when the answer was sent.
+    Echo {
+        client_ts_ms: u64,
+        server_recv_ms: u64,
+        server_send_ms: u64,
+    },
+    /// A phrase requested with `spot` was heard, sent right after its last word, `time_s` is
+    /// the start of its first word.
+    PhraseSpotted {
+        phrase: String,
+        time_s: f64,
+    },
+    /// An admin retuned the module, the values apply from the next model step.
+    ConfigUpdate {
+        asr_delay: usize,
+        temperature: f64,
+    },
+}
+
+/// Extra information sent with the words, the `detail` query parameter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Detail {
+    #[default]
+    Words,
+    /// The pieces of each word with their times, for clients splitting or merging words.
+    Tokens,
+}
+
+/// A piece of a word as produced by the text tokenizer, before any `formatting`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct WordToken {
+    pub piece: String,
+    pub start_s: f64,
+}
+
+/// The pieces of a word, each one starting when the model emitted it except for the first
+/// one that starts with the word.
+pub fn word_tokens(
+    text_tokenizer: &sentencepiece::SentencePieceProcessor,
+    tokens: &[u32],
+    token_times: &[f64],
+    start_time: f64,
+) -> Result<Vec<WordToken>> {
+    let mut pieces = Vec::with_capacity(tokens.len());
+    for (i, (&token, &time)) in tokens.iter().zip(token_times).enumerate() {
+        let piece = text_tokenizer.decode_piece_ids(&[token])?;
+        pieces.push(WordToken { piece, start_s: if i == 0 { start_time } else { time } });
+    }
+    Ok(pieces)
+}
+
+impl OutMsg {
+    pub fn ready(trace: &crate::otel::TraceIds) -> Self {
+        Self::Ready { request_id: trace.request_id.clone(), trace_id: trace.trace_id.clone() }
+    }
+
+    pub fn echo(client_ts_ms: u64, server_recv_ms: u64) -> Self {
+        Self::Echo { client_ts_ms, server_recv_ms, server_send_ms: crate::utils::unix_ms() }
+    }
 }
 
 #[derive(Debug)]
 pub struct Asr {
-    asr_delay_in_tokens: usize,
-    temperature: f64,
+    tuning: tokio::sync::watch::Sender<Tuning>,
     lm: moshi::lm::LmModel,
     audio_tokenizer: moshi::mimi::Mimi,
     text_tokenizer: std::sync::Arc<sentencepiece::SentencePieceProcessor>,
     instance_name: String,
     log_dir: std::path::PathBuf,
     conditions: Option<moshi::conditioner::Condition>,
+    word_lang: bool,
+    speaker_max_distance: f32,
+    steps: crate::compute::StepPool,
+    shadow: Option<std::sync::Arc<crate::shadow::Shadow>>,
 }
 
 impl Asr {
     pub fn new(asr: &crate::AsrConfig, config: &crate::Config, dev: &Device) -> Result<Self> {
         let dtype = crate::utils::model_dtype(asr.dtype_override.as_deref(), dev)?;
-        let vb_lm =
-            unsafe { VarBuilder::from_mmaped_safetensors(&[&asr.lm_model_file], dtype, dev)? };
+        let vb_lm = crate::snapshot::var_builder(
+            &asr.lm_model_file,
+            dtype,
+            dev,
+            config.snapshots.as_ref(),
+        )?;
         let lm =
             moshi::lm::LmModel::new(&asr.model, moshi::nn::MaybeQuantizedVarBuilder::Real(vb_lm))?;
         let conditions = match lm.condition_provider() {
@@ -78,24 +206,31 @@ impl Asr {
         let text_tokenizer = sentencepiece::SentencePieceProcessor::open(&asr.text_tokenizer_file)
             .with_context(|| asr.text_tokenizer_file.clone())?;
         Ok(Self {
-            asr_delay_in_tokens: asr.asr_delay_in_tokens,
+            tuning: tokio::sync::watch::Sender::new(Tuning {
+                asr_delay: asr.asr_delay_in_tokens,
+                temperature: asr.temperature.unwrap_or(0.0),
+            }),
             lm,
-            temperature: asr.temperature.unwrap_or(0.0),
             audio_tokenizer,
             text_tokenizer: text_tokenizer.into(),
             log_dir: config.log_dir.clone().into(),
             instance_name: config.instance_name.clone(),
             conditions,
+            word_lang: asr.word_lang,
+            speaker_max_distance: asr.speaker_max_distance,
+            steps: crate::compute::StepPool::new(&asr.compute, "asr")?,
+            shadow: crate::shadow::Shadow::new(asr, config, dev)?,
         })
     }
 
     pub fn warmup(&self) -> Result<()> {
         let lm = self.lm.clone();
         let audio_tokenizer = self.audio_tokenizer.clone();
+        let tuning = *self.tuning.borrow();
         let mut state = moshi::asr::State::new(
             1,
-            self.asr_delay_in_tokens,
-            self.temperature,
+            tuning.asr_delay,
+            tuning.temperature,
             audio_tokenizer,
             lm,
         )?;
@@ -109,12 +244,52 @@ impl Asr {
         Ok(())
     }
 
-    pub async fn handle_socket(&self, socket: ws::WebSocket, query: Query) -> Result<()> {
+    /// Fresh single stream state sharing the model weights.
+    pub(crate) fn new_state(&self) -> Result<moshi::asr::State> {
+        let tuning = *self.tuning.borrow();
+        let state = moshi::asr::State::new(
+            1,
+            tuning.asr_delay,
+            tuning.temperature,
+            self.audio_tokenizer.clone(),
+            self.lm.clone(),
+        )?;
+        Ok(state)
+    }
+
+    pub(crate) fn conditions(&self) -> Option<&moshi::conditioner::Condition> {
+        self.conditions.as_ref()
+    }
+
+    pub(crate) fn steps(&self) -> &crate::compute::StepPool {
+        &self.steps
+    }
+
+    pub(crate) fn text_tokenizer(&self) -> &sentencepiece::SentencePieceProcessor {
+        &self.text_tokenizer
+    }
+
+    /// Change the settings of the module, live sessions included.
+    pub(crate) fn retune(&self, query: &RetuneQuery) -> Result<Tuning, String> {
+        let tuning = self.tuning.borrow().retune(query, false)?;
+        self.tuning.send_replace(tuning);
+        tracing::info!(?tuning, "retuned asr");
+        Ok(tuning)
+    }
+
+    pub async fn handle_socket(
+        &self,
+        socket: crate::limits::LimitedSocket,
+        query: Query,
+    ) -> Result<()> {
         use futures_util::{SinkExt, StreamExt};
-        use serde::Serialize;
 
+        let limits = socket.limits().clone();
+        let closer = socket.closer();
+        let auth = socket.auth();
         let (mut sender, mut receiver) = socket.split();
         let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<OutMsg>();
+        let coalesce = crate::coalesce::interval(query.coalesce_ms);
         let (log_tx, log_rx) = std::sync::mpsc::channel::<(Tensor, Vec<Tensor>)>();
         let log_tx_inference = log_tx.clone();
         let (log_done_tx, log_done_rx) = tokio::sync::oneshot::channel::<()>();
@@ -122,12 +297,17 @@ impl Asr {
         let instance_name = self.instance_name.clone();
         let log_dir = self.log_dir.clone();
         let query_clone = query.clone();
+        let dump_tokens = crate::privacy::log_text() == crate::privacy::TextLogging::Plain;
 
         let logger_handle = crate::utils::spawn_blocking("logger_loop", move || {
             let mut all_text_tokens = vec![];
             let mut all_audio_tokens_vec = vec![];
 
             for (text_tokens_tensor, audio_tokens_tensors) in log_rx {
+                // The text tokens are the transcript, see the privacy module.
+                if !dump_tokens {
+                    continue;
+                }
                 let text_tokens_vec = text_tokens_tensor.to_vec1::<u32>().unwrap_or_default();
                 let audio_tokens_vecs = audio_tokens_tensors
                     .iter()
@@ -161,7 +341,8 @@ impl Asr {
             let base_path = log_dir.join(format!("{instance_name}-asr-{secs}-{us}"));
 
             let json_filename = base_path.with_extension("json");
-            let json_content = serde_json::to_string_pretty(&query_clone)?;
+            let query = crate::schema::stamp(crate::schema::Artifact::SessionLog, &query_clone)?;
+            let json_content = serde_json::to_string_pretty(&query)?;
             std::fs::write(json_filename, json_content)?;
 
             let st_filename = base_path.with_extension("safetensors");
@@ -176,22 +357,49 @@ impl Asr {
 
         let lm = self.lm.clone();
         let audio_tokenizer = self.audio_tokenizer.clone();
+        let tuning = *self.tuning.borrow();
         let mut state = moshi::asr::State::new(
             1,
-            self.asr_delay_in_tokens,
-            self.temperature,
+            tuning.asr_delay,
+            tuning.temperature,
             audio_tokenizer,
             lm,
         )?;
         let text_tokenizer = self.text_tokenizer.clone();
+        let mut lang_tagger = self.word_lang.then(crate::lang::LangTagger::default);
+        let mut formatter =
+            crate::formatting::FormatterChain::new(query.formatting.unwrap_or_default());
+        let mut spotter = query.spot.as_deref().map(crate::spotting::PhraseSpotter::new);
+        let mut itn = query.itn.unwrap_or_default().then(crate::itn::Itn::default);
+        let detail = query.detail.unwrap_or_default();
 
-        let _asr_delay_in_tokens = self.asr_delay_in_tokens;
+        let mut tuning_rx = self.tuning.subscribe();
         let conditions = self.conditions.clone();
         let mut ogg_opus_decoder = kaudio::ogg_opus::Decoder::new(24000, 1920)?;
         let (pcm_tx, pcm_rx) = std::sync::mpsc::sync_channel::<Vec<f32>>(100);
-        let recv_loop = crate::utils::spawn("recv_loop", async move {
+        let mut resampler = crate::resample::InputResampler::default();
+        let stats = query.stats_interval_s.filter(|&s| s > 0.0).map(|s| {
+            std::sync::Arc::new(std::sync::Mutex::new(crate::audio_stats::SessionStats::new(s)))
+        });
+        let (stats_recv, stats_tx) = (stats.clone(), tx.clone());
+        let mut speakers = query
+            .speakers_interval_s
+            .filter(|&s| s > 0.0)
+            .map(crate::speaker_count::SpeakerStats::new);
+        let speaker = std::sync::Arc::new(std::sync::Mutex::new(None));
+        let (speaker_recv, max_distance) = (speaker.clone(), self.speaker_max_distance);
+        let drift =
+            std::sync::Arc::new(std::sync::Mutex::new(crate::drift::TimeCorrection::default()));
+        let drift_recv = drift.clone();
+        let mut received = 0usize;
+        let shadow = self.shadow.as_ref().and_then(|s| s.session(itn.is_some()));
+        let shadow_words = shadow.clone();
+        let mut scope = crate::task_scope::TaskScope::new();
+        scope.spawn("recv_loop", async move {
             let mut _markers: VecDeque<(usize, i64)> = VecDeque::new();
+            let mut applied_s = 0.0;
             while let Some(msg) = receiver.next().await {
+                let recv_ms = crate::utils::unix_ms();
                 let msg = match msg? {
                     ws::Message::Binary(x) => {
                         if crate::metrics::stream::enabled() {
@@ -203,7 +411,7 @@ impl Asr {
                     ws::Message::Ping(_) | ws::Message::Pong(_) | ws::Message::Text(_) => continue,
                     ws::Message::Close(_) => break,
                 };
-                let msg: InMsg = match rmp_serde::from_slice(&msg) {
+                let msg = match crate::protocol::decode_in_msg(&msg) {
                     Ok(m) => m,
                     Err(e) => {
                         tracing::warn!(error = %e, msg_len = msg.len(), "failed to deserialize InMsg, skipping message");
@@ -214,6 +422,9 @@ impl Asr {
                     InMsg::Init => None,
                     InMsg::Marker { id } => {
                         tracing::info!("received marker {id}");
+                        if let Some(speakers) = speakers.as_ref() {
+                            stats_tx.send(speakers.estimate())?;
+                        }
                         // Markers need to be handled carefully with pipelining.
                         // We'll send them through the pcm_tx as a special message if needed,
                         // or just rely on the step_idx.
@@ -221,12 +432,77 @@ impl Asr {
                         None
                     }
                     InMsg::OggOpus { data } => ogg_opus_decoder.decode(&data)?.map(|v| v.to_vec()),
-                    InMsg::Audio { pcm } => Some(pcm),
-                    InMsg::Ping => None,
+                    InMsg::Audio { pcm } => match limits.check_pcm(pcm.len()) {
+                        Ok(()) => Some(resampler.process(pcm)?),
+                        Err(err) => {
+                            closer.close(err);
+                            None
+                        }
+                    },
+                    InMsg::SetSampleRate { hz } => match resampler.set_rate(hz) {
+                        Ok(tail) => (!tail.is_empty()).then_some(tail),
+                        Err(err) => {
+                            tracing::warn!(?err, rate = resampler.rate(), "ignoring SetSampleRate");
+                            None
+                        }
+                    },
+                    InMsg::Ping | InMsg::TimeCorrection { .. } => None,
+                    InMsg::Echo { client_ts_ms } => {
+                        stats_tx.send(OutMsg::echo(client_ts_ms, recv_ms))?;
+                        None
+                    }
+                    InMsg::RefreshToken { jwt } => {
+                        crate::auth::SessionAuth::handle_refresh(auth.as_ref(), &jwt);
+                        None
+                    }
+                    InMsg::Checkpoint | InMsg::Restore { .. } => {
+                        tracing::warn!("checkpoints are only supported by batched asr");
+                        None
+                    }
+                    InMsg::AudioCodes { .. } => {
+                        tracing::warn!("mimi codes are only supported by batched asr");
+                        None
+                    }
+                    InMsg::Enroll { pcm, other } => {
+                        let now_s = received as f64 / 24000.0;
+                        let filter =
+                            crate::speaker::SpeakerFilter::enroll(&pcm, other, max_distance, now_s);
+                        let msg = match filter {
+                            Ok(filter) => {
+                                let voiced_s = filter.voiced_s();
+                                *speaker_recv.lock().unwrap() = Some(filter);
+                                OutMsg::Enrolled { voiced_s }
+                            }
+                            Err(err) => OutMsg::Error { message: format!("enroll failed: {err}") },
+                        };
+                        stats_tx.send(msg)?;
+                        None
+                    }
                 };
                 if let Some(pcm) = pcm {
+                    received += pcm.len();
+                    if let Some(speaker) = speaker_recv.lock().unwrap().as_mut() {
+                        speaker.push(&pcm)
+                    }
+                    if let Some(stats) = stats_recv.as_ref() {
+                        for msg in stats.lock().unwrap().push(&pcm) {
+                            stats_tx.send(msg)?
+                        }
+                    }
+                    if let Some(msg) = speakers.as_mut().and_then(|s| s.push(&pcm)) {
+                        stats_tx.send(msg)?
+                    }
+                    if let Some(shadow) = shadow.as_ref() {
+                        shadow.pcm(&pcm)
+                    }
                     pcm_tx.send(pcm)?;
                 }
+                // The audio of the previous rate has been sent, the correction applies after.
+                if resampler.correction_s() != applied_s {
+                    let model_s = received as f64 / 24000.0;
+                    drift_recv.lock().unwrap().shift(model_s, resampler.correction_s() - applied_s);
+                    applied_s = resampler.correction_s();
+                }
             }
             Ok::<(), anyhow::Error>(())
         });
@@ -235,7 +511,7 @@ impl Asr {
         let mimi_dev = state.device().clone();
         let mimi_batch_size = state.batch_size();
         let mut mimi_tokenizer = state.audio_tokenizer.clone();
-        let mimi_handle = crate::utils::spawn_blocking("mimi_encode_loop", move || {
+        scope.spawn_blocking("mimi_encode_loop", move |_| {
             for pcm in pcm_rx {
                 let pcm_len = pcm.len();
                 let pcm = Tensor::from_vec(pcm, (1, 1, pcm_len), &mimi_dev)?.broadcast_as((
@@ -257,44 +533,94 @@ impl Asr {
             Ok::<(), anyhow::Error>(())
         });
 
-        let inference_handle = crate::utils::spawn_blocking("inference_loop", move || {
+        let steps = self.steps.clone();
+        scope.spawn_blocking("inference_loop", move |token| {
             for steps_tokens in mimi_rx {
+                if token.is_cancelled() {
+                    break;
+                }
                 for codes in steps_tokens {
-                    let asr_msgs = state.step_tokens_vec(
-                        codes,
-                        conditions.as_ref(),
-                        &().into(),
-                        |_, text_tokens, audio_tokens| {
-                            if let Err(err) =
-                                log_tx_inference.send((text_tokens.clone(), audio_tokens.to_vec()))
-                            {
-                                tracing::error!(?err, "failed to send log");
-                            }
-                        },
-                    )?;
+                    if tuning_rx.has_changed().unwrap_or(false) {
+                        let tuning = *tuning_rx.borrow_and_update();
+                        tuning.apply(&mut state);
+                        let Tuning { asr_delay, temperature } = tuning;
+                        tx.send(OutMsg::ConfigUpdate { asr_delay, temperature })?;
+                    }
+                    let asr_msgs = steps.install(|| {
+                        state.step_tokens_vec(
+                            codes,
+                            conditions.as_ref(),
+                            &().into(),
+                            |_, text_tokens, audio_tokens| {
+                                let log = (text_tokens.clone(), audio_tokens.to_vec());
+                                if let Err(err) = log_tx_inference.send(log) {
+                                    tracing::error!(?err, "failed to send log");
+                                }
+                            },
+                        )
+                    })?;
+                    crate::otel::record_steps(state.model_step_idx());
                     for asr_msg in asr_msgs {
                         let msg = match asr_msg {
-                            moshi::asr::AsrMsg::Word { tokens, start_time, .. } => OutMsg::Word {
-                                text: text_tokenizer.decode_piece_ids(&tokens)?,
-                                start_time,
-                            },
+                            moshi::asr::AsrMsg::Word { tokens, token_times, start_time, .. } => {
+                                let drift = drift.lock().unwrap();
+                                let start_time = drift.correct(start_time);
+                                let pieces = match detail {
+                                    Detail::Words => None,
+                                    Detail::Tokens => {
+                                        let times: Vec<_> =
+                                            token_times.iter().map(|&t| drift.correct(t)).collect();
+                                        Some(word_tokens(&text_tokenizer, &tokens, &times, start_time)?)
+                                    }
+                                };
+                                drop(drift);
+                                let text = text_tokenizer.decode_piece_ids(&tokens)?;
+                                let lang = lang_tagger.as_mut().map(|t| t.tag(&text).to_string());
+                                if let Some(stats) = stats.as_ref() {
+                                    stats.lock().unwrap().word(start_time)
+                                }
+                                let text = formatter.format(text);
+                                OutMsg::Word { text, start_time, lang, tokens: pieces }
+                            }
                             moshi::asr::AsrMsg::Step { step_idx, prs } => {
                                 let prs = prs.iter().map(|p| p[0]).collect::<Vec<_>>();
-                                OutMsg::Step { step_idx, prs, buffered_pcm: 0 }
+                                let time_correction_ms = drift.lock().unwrap().current_ms();
+                                OutMsg::Step { step_idx, prs, buffered_pcm: 0, time_correction_ms }
                             }
                             moshi::asr::AsrMsg::EndWord { stop_time, .. } => {
-                                OutMsg::EndWord { stop_time }
+                                OutMsg::EndWord { stop_time: drift.lock().unwrap().correct(stop_time) }
                             }
+                            moshi::asr::AsrMsg::NonFinite { step_idx, batch_idx, recovered } => {
+                                crate::batched_asr::nonfinite_step(step_idx, batch_idx, recovered);
+                                continue;
+                            }
+                        };
+                        let msg = match speaker.lock().unwrap().as_mut() {
+                            Some(speaker) => speaker.filter(msg),
+                            None => Some(msg),
                         };
-                        tx.send(msg)?
+                        let msgs = match (msg, itn.as_mut()) {
+                            (Some(msg), Some(itn)) => itn.push(msg),
+                            (msg, _) => msg.into_iter().collect(),
+                        };
+                        for msg in msgs {
+                            let spotted = match (&msg, spotter.as_mut()) {
+                                (OutMsg::Word { text, start_time, .. }, Some(spotter)) => {
+                                    spotter.push(text, *start_time)
+                                }
+                                _ => None,
+                            };
+                            tx.send(msg)?;
+                            if let Some(msg) = spotted {
+                                tx.send(msg)?
+                            }
+                        }
                     }
                 }
             }
             Ok::<(), anyhow::Error>(())
         });
-        let send_loop = crate::utils::spawn("send_loop", async move {
-            use bytes::BufMut;
-
+        scope.spawn("send_loop", async move {
             let mut chunk_buf = bytes::BytesMut::with_capacity(8 * 1024);
             let mut chunk_buf_spare = bytes::BytesMut::with_capacity(8 * 1024);
             loop {
@@ -304,19 +630,30 @@ impl Asr {
                     Ok(None) => break,
                     Err(_) => ws::Message::Ping(vec![].into()),
                     Ok(Some(msg)) => {
-                        chunk_buf.clear();
-                        {
-                            let mut w = (&mut chunk_buf).writer();
-                            msg.serialize(
-                                &mut rmp_serde::Serializer::new(&mut w)
-                                    .with_human_readable()
-                                    .with_struct_map(),
-                            )?;
+                        let mut msgs = vec![msg];
+                        if let Some(interval) = coalesce {
+                            let deadline = tokio::time::Instant::now() + interval;
+                            while msgs.len() < crate::coalesce::MAX_MESSAGES {
+                                match tokio::time::timeout_at(deadline, rx.recv()).await {
+                                    Ok(Some(msg)) => msgs.push(msg),
+                                    // A closed channel ends the loop on the next recv.
+                                    Ok(None) | Err(_) => break,
+                                }
+                            }
+                        }
+                        if let Some(shadow) = shadow_words.as_ref() {
+                            for msg in msgs.iter() {
+                                if let OutMsg::Word { text, .. } = msg {
+                                    shadow.word(text)
+                                }
+                            }
                         }
+                        chunk_buf.clear();
+                        crate::coalesce::serialize(&msgs, &mut chunk_buf)?;
                         std::mem::swap(&mut chunk_buf, &mut chunk_buf_spare);
                         let bytes = chunk_buf_spare.split().freeze();
                         if crate::metrics::stream::enabled() {
-                            crate::metrics::stream::ASR_WS_OUT_MESSAGES.inc();
+                            crate::metrics::stream::ASR_WS_OUT_MESSAGES.inc_by(msgs.len() as u64);
                             crate::metrics::stream::ASR_WS_OUT_BYTES.inc_by(bytes.len() as u64);
                         }
                         ws::Message::Binary(bytes)
@@ -327,35 +664,11 @@ impl Asr {
             tracing::info!("send loop exited");
             Ok::<(), anyhow::Error>(())
         });
-        // recv_loop and send_loop are already JoinHandle<()> from crate::utils::spawn
-        let mut recv_handle = recv_loop;
-        let mut send_handle = send_loop;
-
-        let sleep = tokio::time::sleep(std::time::Duration::from_secs(360));
-        tokio::pin!(sleep);
-
-        // Use tokio::select! with proper abort handling for spawned tasks.
-        // When one branch completes or times out, we explicitly abort the other tasks.
-        tokio::select! {
-            _ = &mut sleep => {
-                tracing::error!("reached timeout, aborting background tasks");
-            }
-            _ = &mut recv_handle => {}
-            _ = &mut send_handle => {}
-        }
-
-        // Explicitly abort tasks if they are still running
-        recv_handle.abort();
-        send_handle.abort();
-        mimi_handle.abort();
-        inference_handle.abort();
+        // The session ends with the first of the recv and send loops, the model loops stop
+        // once their channels are closed.
+        scope.run(Some(Duration::from_secs(360)), Duration::from_millis(500)).await;
 
-        // Wait briefly for aborted tasks to clean up
         let _ = tokio::time::timeout(std::time::Duration::from_millis(500), async {
-            let _ = recv_handle.await;
-            let _ = send_handle.await;
-            let _ = mimi_handle.await;
-            let _ = inference_handle.await;
             drop(log_tx); // Close the log channel to trigger logger completion
             let _ = logger_handle.await;
             let _ = log_done_rx.await;
@@ -0,0 +1,183 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Per-session audio statistics and speaking rate, reported to clients that ask for them.
+//!
+//! These help diagnosing poor transcripts without access to the server logs: a microphone
+//! that is too quiet shows as a low level, an overdriven one as clipping, and a low speech
+//! ratio points at long silences or a wrong input device.
+
+use crate::asr::OutMsg;
+use std::collections::VecDeque;
+
+const SAMPLE_RATE: usize = 24000;
+/// Frames of 80ms are classified as speech or silence on their level.
+const FRAME_SIZE: usize = 1920;
+const SPEECH_DB: f32 = -45.0;
+pub const CLIP_LEVEL: f32 = 0.99;
+/// Reported level of digital silence.
+const MIN_DB: f32 = -100.0;
+const RATE_WINDOW_S: f64 = 60.0;
+/// Shortest interval between two reports.
+pub const MIN_INTERVAL_S: f64 = 1.0;
+
+pub fn to_db(mean_sq: f64) -> f32 {
+    if mean_sq <= 0.0 {
+        return MIN_DB;
+    }
+    (10.0 * mean_sq.log10() as f32).max(MIN_DB)
+}
+
+#[derive(Debug)]
+pub struct SessionStats {
+    interval: usize,
+    /// Session time at which the audio pushed so far starts, and its length in samples.
+    start_s: f64,
+    samples: usize,
+    /// Accumulators for the current report.
+    len: usize,
+    sum_sq: f64,
+    clipped: usize,
+    frames: usize,
+    speech_frames: usize,
+    frame_len: usize,
+    frame_sum_sq: f64,
+    /// Start times of the words of the last minute.
+    words: VecDeque<f64>,
+}
+
+impl SessionStats {
+    pub fn new(interval_s: f64) -> Self {
+        let interval_s = interval_s.max(MIN_INTERVAL_S);
+        Self {
+            interval: (interval_s * SAMPLE_RATE as f64).round() as usize,
+            start_s: 0.0,
+            samples: 0,
+            len: 0,
+            sum_sq: 0.0,
+            clipped: 0,
+            frames: 0,
+            speech_frames: 0,
+            frame_len: 0,
+            frame_sum_sq: 0.0,
+            words: VecDeque::new(),
+        }
+    }
+
+    /// Continue from session time `start_s`, for restored sessions.
+    pub fn restart_at(&mut self, start_s: f64) {
+        self.start_s = start_s;
+        self.samples = 0;
+    }
+
+    fn now_s(&self) -> f64 {
+        self.start_s + self.samples as f64 / SAMPLE_RATE as f64
+    }
+
+    pub fn word(&mut self, start_s: f64) {
+        self.words.push_back(start_s);
+    }
+
+    /// Words per minute over the last minute of audio, or since the start of the session.
+    pub fn wpm(&mut self) -> f32 {
+        let now = self.now_s();
+        while self.words.front().is_some_and(|&t| t < now - RATE_WINDOW_S) {
+            self.words.pop_front();
+        }
+        let window = (now - self.start_s).min(RATE_WINDOW_S);
+        if window < MIN_INTERVAL_S {
+            return 0.0;
+        }
+        (self.words.len() as f64 * 60.0 / window) as f32
+    }
+
+    /// Account for the received audio, returns the `AudioStats` and `SpeechRate` messages
+    /// when a report is due.
+    pub fn push(&mut self, pcm: &[f32]) -> Vec<OutMsg> {
+        let mut msgs = vec![];
+        for &v in pcm {
+            let sq = v as f64 * v as f64;
+            self.sum_sq += sq;
+            self.frame_sum_sq += sq;
+            self.clipped += (v.abs() >= CLIP_LEVEL) as usize;
+            self.len += 1;
+            self.samples += 1;
+            self.frame_len += 1;
+            if self.frame_len == FRAME_SIZE {
+                self.frames += 1;
+                if to_db(self.frame_sum_sq / FRAME_SIZE as f64) >= SPEECH_DB {
+                    self.speech_frames += 1;
+                }
+                self.frame_len = 0;
+                self.frame_sum_sq = 0.0;
+            }
+            if self.len == self.interval {
+                msgs.clear();
+                msgs.push(self.report());
+                msgs.push(OutMsg::SpeechRate { wpm: self.wpm() });
+            }
+        }
+        msgs
+    }
+
+    fn report(&mut self) -> OutMsg {
+        let msg = OutMsg::AudioStats {
+            rms_db: to_db(self.sum_sq / self.len.max(1) as f64),
+            clipping_ratio: self.clipped as f32 / self.len.max(1) as f32,
+            speech_ratio: self.speech_frames as f32 / self.frames.max(1) as f32,
+        };
+        self.len = 0;
+        self.sum_sq = 0.0;
+        self.clipped = 0;
+        self.frames = 0;
+        self.speech_frames = 0;
+        msg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_level_clipping_and_rate() {
+        let mut stats = SessionStats::new(2.0);
+        // One second of silence and one of a clipped square wave, pushed in 80ms chunks.
+        let mut pcm = vec![0.0f32; SAMPLE_RATE];
+        pcm.extend((0..SAMPLE_RATE).map(|i| if i % 2 == 0 { 1.0 } else { -0.5 }));
+        for t in 0..5 {
+            stats.word(t as f64 * 0.4);
+        }
+        let mut msgs = vec![];
+        for chunk in pcm.chunks(FRAME_SIZE) {
+            msgs.extend(stats.push(chunk));
+        }
+        let [OutMsg::AudioStats { rms_db, clipping_ratio, speech_ratio }, OutMsg::SpeechRate { wpm }] =
+            msgs.as_slice()
+        else {
+            panic!("unexpected messages {msgs:?}")
+        };
+        // Mean square of 0.625 over half of the audio.
+        assert!((rms_db - to_db(0.3125)).abs() < 1e-3, "{rms_db}");
+        assert!((clipping_ratio - 0.25).abs() < 1e-6);
+        assert!((speech_ratio - 0.5).abs() < 0.05, "{speech_ratio}");
+        assert!((wpm - 150.0).abs() < 1e-3, "{wpm}");
+        assert!(stats.push(&[0.0; 100]).is_empty());
+    }
+
+    #[test]
+    fn rate_covers_the_last_minute() {
+        let mut stats = SessionStats::new(10.0);
+        stats.restart_at(100.0);
+        stats.word(101.0);
+        stats.push(&vec![0.0; 90 * SAMPLE_RATE]);
+        // Only the words of the last 60s count once the session is longer than that.
+        assert_eq!(stats.wpm(), 0.0);
+        for t in 0..30 {
+            stats.word(160.0 + t as f64);
+        }
+        assert!((stats.wpm() - 30.0).abs() < 1e-3);
+        assert_eq!(to_db(0.0), MIN_DB);
+    }
+}
@@ -17,9 +17,15 @@ pub const AUTHORIZATION_HEADER: &str = "authorization";
 /// Cookie name for Better Auth session (when using cookie cache with JWT strategy)
 pub const SESSION_COOKIE: &str = "better-auth.session_token";
 
+/// Header for static API key authentication
+pub const API_KEY_HEADER: &str = "x-api-key";
+
 /// Global JWT secret loaded from environment
 static JWT_SECRET: OnceLock<Option<String>> = OnceLock::new();
 
+/// Global API keys loaded from environment (MOSHI_API_KEY, comma-separated)
+static API_KEYS: OnceLock<Vec<String>> = OnceLock::new();
+
 // ============================================================================
 // AuthError - Structured authentication error type
 // ============================================================================
@@ -35,6 +41,10 @@ pub enum AuthErrorCode {
     PendingApproval,
     /// Account has been rejected by admin
     AccountRejected,
+    /// API key missing from the configured set
+    InvalidApiKey,
+    /// Authenticated user lacks the role required by the module policy
+    InsufficientRole,
 }
 
 impl std::fmt::Display for AuthErrorCode {
@@ -45,6 +55,8 @@ impl std::fmt::Display for AuthErrorCode {
             Self::JwtValidationFailed => write!(f, "jwt_validation_failed"),
             Self::PendingApproval => write!(f, "pending_approval"),
             Self::AccountRejected => write!(f, "account_rejected"),
+            Self::InvalidApiKey => write!(f, "invalid_api_key"),
+            Self::InsufficientRole => write!(f, "insufficient_role"),
         }
     }
 }
@@ -117,6 +129,34 @@ impl AuthError {
         }
     }
 
+    /// API key is missing or does not match any configured key
+    pub fn invalid_api_key() -> Self {
+        Self {
+            error: "unauthorized",
+            code: AuthErrorCode::InvalidApiKey,
+            message: "Invalid or missing API key".to_string(),
+            hint: "Provide a key from MOSHI_API_KEY via X-API-Key header, Bearer token or ?token",
+        }
+    }
+
+    /// User is authenticated but does not have the required role
+    pub fn insufficient_role(required: &str) -> Self {
+        Self {
+            error: "forbidden",
+            code: AuthErrorCode::InsufficientRole,
+            message: format!("This endpoint requires the '{required}' role"),
+            hint: "Ask an administrator to grant the required role",
+        }
+    }
+
+    /// HTTP status for this error
+    pub fn status_code(&self) -> StatusCode {
+        match self.code {
+            AuthErrorCode::InsufficientRole => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
     /// Get the error code as a string for metrics labels
     pub fn error_type(&self) -> &'static str {
         match self.code {
@@ -125,6 +165,8 @@ impl AuthError {
             AuthErrorCode::JwtValidationFailed => "jwt_validation_failed",
             AuthErrorCode::PendingApproval => "pending_approval",
             AuthErrorCode::AccountRejected => "account_rejected",
+            AuthErrorCode::InvalidApiKey => "invalid_api_key",
+            AuthErrorCode::InsufficientRole => "insufficient_role",
         }
     }
 }
@@ -134,7 +176,7 @@ impl IntoResponse for AuthError {
         // Increment Prometheus counter with error type label
         error_metrics::record_auth_error(self.error_type());
 
-        (StatusCode::UNAUTHORIZED, Json(self)).into_response()
+        (self.status_code(), Json(self)).into_response()
     }
 }
 
@@ -189,6 +231,12 @@ pub struct UserData {
     /// User approval status (e.g., "pending", "approved", "rejected")
     #[serde(default)]
     pub status: Option<String>,
+    /// Mimi rooms the user may join, `*` grants all of them
+    #[serde(default)]
+    pub rooms: Option<Vec<String>>,
+    /// Tenant of the user, admins without one manage all the tenants
+    #[serde(default)]
+    pub tenant: Option<String>,
 }
 
 /// Better Auth session claims structure
@@ -206,6 +254,20 @@ pub struct BetterAuthClaims {
     pub exp: Option<i64>,
 }
 
+impl BetterAuthClaims {
+    /// Unix time at which the credentials stop being valid, the earliest of the JWT `exp` and
+    /// the session `expiresAt`.
+    pub fn expires_at(&self) -> Option<i64> {
+        let session = chrono::DateTime::parse_from_rfc3339(&self.session.expires_at)
+            .ok()
+            .map(|dt| dt.timestamp());
+        match (self.exp, session) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
 /// Check if a user's approval status allows access.
 /// Returns Ok(()) if status is "approved" or not set (backwards compatibility).
 /// Returns Err with appropriate AuthError for "pending" or "rejected" status.
@@ -258,6 +320,8 @@ pub fn check_approval_status(claims: &BetterAuthClaims) -> Result<(), AuthError>
 pub struct AuthConfig {
     /// JWT secret for Better Auth validation (from BETTER_AUTH_SECRET env var)
     pub jwt_secret: Option<String>,
+    /// Whether static API keys are configured (MOSHI_API_KEY env var)
+    pub api_keys_configured: bool,
 }
 
 impl AuthConfig {
@@ -265,7 +329,8 @@ impl AuthConfig {
     pub fn from_env() -> Self {
         // Load JWT secret for Better Auth
         let jwt_secret = std::env::var("BETTER_AUTH_SECRET").ok();
-        Self { jwt_secret }
+        let api_keys_configured = !get_api_keys().is_empty();
+        Self { jwt_secret, api_keys_configured }
     }
 
     /// Log authentication configuration (call after tracing is initialized)
@@ -275,6 +340,25 @@ impl AuthConfig {
         } else {
             tracing::warn!("No authentication configured (BETTER_AUTH_SECRET not set)");
         }
+        if self.api_keys_configured {
+            tracing::info!("API key authentication available (MOSHI_API_KEY is set)");
+        }
+    }
+
+    /// Refuse the `api_key` policy of `module` when no key is configured, as it would reject
+    /// every request.
+    pub fn check_policies(
+        &self,
+        module: &str,
+        policies: impl IntoIterator<Item = AuthPolicy>,
+    ) -> anyhow::Result<()> {
+        let api_key = policies.into_iter().any(|p| p == AuthPolicy::ApiKey);
+        if api_key && !self.api_keys_configured {
+            anyhow::bail!(
+                "module {module} uses the api_key auth policy but MOSHI_API_KEY is not set"
+            )
+        }
+        Ok(())
     }
 }
 
@@ -283,6 +367,22 @@ fn get_jwt_secret() -> Option<&'static str> {
     JWT_SECRET.get_or_init(|| std::env::var("BETTER_AUTH_SECRET").ok()).as_deref()
 }
 
+/// Get the configured API keys from environment (cached)
+fn get_api_keys() -> &'static [String] {
+    API_KEYS.get_or_init(|| {
+        std::env::var("MOSHI_API_KEY").map(|v| parse_api_keys(&v)).unwrap_or_default()
+    })
+}
+
+fn parse_api_keys(value: &str) -> Vec<String> {
+    value.split(',').map(|k| k.trim().to_string()).filter(|k| !k.is_empty()).collect()
+}
+
+/// Compare two keys without short-circuiting on the first differing byte
+fn keys_match(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 /// Extract Bearer token from Authorization header
 fn extract_bearer_token(headers: &HeaderMap) -> Option<&str> {
     headers
@@ -362,18 +462,18 @@ fn validate_jwt(token: &str) -> Result<BetterAuthClaims, AuthError> {
 /// 2. JWT token via query parameter (?token=...)
 /// 3. Session cookie (better-auth.session_token)
 ///
-/// Returns Ok(()) if any method succeeds, Err(AuthError) with structured JSON otherwise.
-pub fn check(
+/// Returns the user claims if any method succeeds, Err(AuthError) with structured JSON otherwise.
+pub fn check_with_user(
     headers: &HeaderMap,
     query_token: Option<&str>,
-) -> Result<(), AuthError> {
+) -> Result<BetterAuthClaims, AuthError> {
     // Method 1: Bearer token (JWT)
     if let Some(token) = extract_bearer_token(headers) {
         match validate_jwt(token) {
             Ok(claims) => {
-                // Validate approval status
+                // Validate approval status before returning claims
                 check_approval_status(&claims)?;
-                return Ok(());
+                return Ok(claims);
             }
             Err(e) => {
                 // Demote expired token to debug - it's expected behavior, not a security issue
@@ -391,10 +491,10 @@ pub fn check(
     if let Some(token) = query_token {
         match validate_jwt(token) {
             Ok(claims) => {
-                // Validate approval status
+                // Validate approval status before returning claims
                 check_approval_status(&claims)?;
                 tracing::debug!("Authenticated via query token parameter");
-                return Ok(());
+                return Ok(claims);
             }
             Err(e) => {
                 // Demote expired token to debug - it's expected behavior, not a security issue
@@ -412,9 +512,9 @@ pub fn check(
     if let Some(token) = extract_session_cookie(headers) {
         match validate_jwt(token) {
             Ok(claims) => {
-                // Validate approval status
+                // Validate approval status before returning claims
                 check_approval_status(&claims)?;
-                return Ok(());
+                return Ok(claims);
             }
             Err(e) => {
                 // Demote expired token to debug - it's expected behavior, not a security issue
@@ -433,75 +533,168 @@ pub fn check(
     Err(AuthError::missing_credentials())
 }
 
-/// Extended check that returns user information if authenticated via JWT
-pub fn check_with_user(
+// ============================================================================
+// Per-module authentication policies
+// ============================================================================
+
+/// Authentication policy declared per module with `auth = "..."` in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthPolicy {
+    /// Public endpoint, no credentials required
+    None,
+    /// Static API key from MOSHI_API_KEY
+    ApiKey,
+    /// Better Auth JWT (header, query token or session cookie)
+    Jwt,
+    /// Better Auth JWT whose user has the `admin` role
+    Admin,
+}
+
+impl AuthPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::ApiKey => "api_key",
+            Self::Jwt => "jwt",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+impl std::fmt::Display for AuthPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+fn check_api_key_against(
+    keys: &[String],
     headers: &HeaderMap,
     query_token: Option<&str>,
-) -> Result<BetterAuthClaims, AuthError> {
-    // Method 1: Bearer token (JWT)
-    if let Some(token) = extract_bearer_token(headers) {
-        match validate_jwt(token) {
-            Ok(claims) => {
-                // Validate approval status before returning claims
-                check_approval_status(&claims)?;
-                return Ok(claims);
-            }
-            Err(e) => {
-                // Demote expired token to debug - it's expected behavior, not a security issue
-                if matches!(e.code, AuthErrorCode::ExpiredToken) {
-                    tracing::debug!(error_type = %e.code, "Authentication failed: JWT expired");
-                } else {
-                    tracing::warn!(error_type = %e.code, "Authentication failed: JWT validation error");
-                }
-                return Err(e);
-            }
+) -> Result<(), AuthError> {
+    let provided = headers
+        .get(API_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| extract_bearer_token(headers))
+        .or(query_token);
+    match provided {
+        None => {
+            tracing::warn!("Authentication failed: no API key provided");
+            Err(AuthError::missing_credentials())
+        }
+        Some(key) if keys.iter().any(|k| keys_match(k, key)) => Ok(()),
+        Some(_) => {
+            tracing::warn!("Authentication failed: invalid API key");
+            Err(AuthError::invalid_api_key())
         }
     }
+}
 
-    // Method 2: JWT token via query parameter (?token=...)
-    if let Some(token) = query_token {
-        match validate_jwt(token) {
-            Ok(claims) => {
-                // Validate approval status before returning claims
-                check_approval_status(&claims)?;
-                tracing::debug!("Authenticated via query token parameter");
-                return Ok(claims);
-            }
-            Err(e) => {
-                // Demote expired token to debug - it's expected behavior, not a security issue
-                if matches!(e.code, AuthErrorCode::ExpiredToken) {
-                    tracing::debug!(error_type = %e.code, "Authentication failed: query token expired");
-                } else {
-                    tracing::warn!(error_type = %e.code, "Authentication failed: query token validation error");
-                }
-                return Err(e);
-            }
+/// Check a static API key from the `X-API-Key` header, Bearer token or `?token=` parameter.
+pub fn check_api_key(headers: &HeaderMap, query_token: Option<&str>) -> Result<(), AuthError> {
+    check_api_key_against(get_api_keys(), headers, query_token)
+}
+
+fn check_role(claims: BetterAuthClaims, role: &str) -> Result<BetterAuthClaims, AuthError> {
+    if claims.user.role.as_deref() == Some(role) {
+        Ok(claims)
+    } else {
+        tracing::warn!(user_id = %claims.user.id, role = ?claims.user.role, "Authorization failed: role {role} required");
+        Err(AuthError::insufficient_role(role))
+    }
+}
+
+/// Id of the user whose valid JWT comes with a request, for the access log. Neither the
+/// approval status nor the policy of the module are checked.
+pub fn user_id(headers: &HeaderMap, query_token: Option<&str>) -> Option<String> {
+    get_jwt_secret()?;
+    let token = extract_bearer_token(headers)
+        .or(query_token)
+        .or_else(|| extract_session_cookie(headers))?;
+    validate_jwt(token).ok().map(|claims| claims.user.id)
+}
+
+/// Apply a module auth policy, returns the user claims when the policy is JWT based.
+pub fn check_policy(
+    policy: AuthPolicy,
+    headers: &HeaderMap,
+    query_token: Option<&str>,
+) -> Result<Option<BetterAuthClaims>, AuthError> {
+    match policy {
+        AuthPolicy::None => Ok(None),
+        AuthPolicy::ApiKey => check_api_key(headers, query_token).map(|()| None),
+        AuthPolicy::Jwt => check_with_user(headers, query_token).map(Some),
+        AuthPolicy::Admin => {
+            check_with_user(headers, query_token).and_then(|c| check_role(c, "admin")).map(Some)
         }
     }
+}
 
-    // Method 3: Session cookie
-    if let Some(token) = extract_session_cookie(headers) {
-        match validate_jwt(token) {
-            Ok(claims) => {
-                // Validate approval status before returning claims
-                check_approval_status(&claims)?;
-                return Ok(claims);
-            }
-            Err(e) => {
-                // Demote expired token to debug - it's expected behavior, not a security issue
-                if matches!(e.code, AuthErrorCode::ExpiredToken) {
-                    tracing::debug!(error_type = %e.code, "Authentication failed: session cookie expired");
-                } else {
-                    tracing::warn!(error_type = %e.code, "Authentication failed: session cookie validation error");
+/// Credentials of a websocket session. With a JWT policy, the socket is closed once they have
+/// expired, unless the client presents a new token with a `RefreshToken` message.
+#[derive(Debug, Clone)]
+pub struct SessionAuth(std::sync::Arc<std::sync::Mutex<SessionAuthInner>>);
+
+#[derive(Debug)]
+struct SessionAuthInner {
+    policy: AuthPolicy,
+    user_id: String,
+    expires_at: Option<i64>,
+}
+
+impl SessionAuth {
+    /// `None` when the policy does not rely on expiring tokens.
+    pub fn new(policy: AuthPolicy, claims: Option<&BetterAuthClaims>) -> Option<Self> {
+        let claims = claims?;
+        let inner = SessionAuthInner {
+            policy,
+            user_id: claims.user.id.clone(),
+            expires_at: claims.expires_at(),
+        };
+        Some(Self(std::sync::Arc::new(std::sync::Mutex::new(inner))))
+    }
+
+    pub fn user_id(&self) -> String {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).user_id.clone()
+    }
+
+    pub fn expires_at(&self) -> Option<i64> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).expires_at
+    }
+
+    /// Validate `jwt` against the module policy and extend the session with it.
+    pub fn refresh(&self, jwt: &str) -> Result<(), AuthError> {
+        let policy = self.0.lock().unwrap_or_else(|e| e.into_inner()).policy;
+        let claims = check_policy(policy, &HeaderMap::new(), Some(jwt))?;
+        match claims {
+            Some(claims) => self.apply(&claims),
+            None => Ok(()),
+        }
+    }
+
+    /// Handle a `RefreshToken` message, a rejected token leaves the current expiry unchanged.
+    pub fn handle_refresh(auth: Option<&Self>, jwt: &str) {
+        match auth {
+            None => tracing::warn!("ignoring RefreshToken, the session token does not expire"),
+            Some(auth) => {
+                if let Err(err) = auth.refresh(jwt) {
+                    tracing::warn!(error_type = %err.code, "refresh token rejected");
                 }
-                return Err(e);
             }
         }
     }
 
-    // No credentials provided at all
-    tracing::warn!("Authentication failed: no credentials provided");
-    Err(AuthError::missing_credentials())
+    fn apply(&self, claims: &BetterAuthClaims) -> Result<(), AuthError> {
+        let mut inner = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if claims.user.id != inner.user_id {
+            tracing::warn!(user_id = %inner.user_id, new_user_id = %claims.user.id, "refresh token for another user");
+            return Err(AuthError::jwt_validation_failed("token issued for another user"));
+        }
+        inner.expires_at = claims.expires_at();
+        tracing::info!(user_id = %inner.user_id, expires_at = ?inner.expires_at, "session token refreshed");
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -535,7 +728,15 @@ mod tests {
     fn test_missing_credentials_error() {
         let headers = HeaderMap::new();
 
-        let err = check(&headers, None).unwrap_err();
+        let err = check_policy(AuthPolicy::Jwt, &headers, None).unwrap_err();
+        assert!(matches!(err.code, AuthErrorCode::MissingCredentials));
+    }
+
+    #[test]
+    fn test_missing_credentials_with_user() {
+        let headers = HeaderMap::new();
+
+        let err = check_with_user(&headers, None).unwrap_err();
         assert!(matches!(err.code, AuthErrorCode::MissingCredentials));
     }
 
@@ -560,12 +761,35 @@ mod tests {
                 image: None,
                 role: Some("user".to_string()),
                 status: status.map(String::from),
+                rooms: None,
+                tenant: None,
             },
             iat: Some(1704067200),
             exp: Some(4102444800),
         }
     }
 
+    #[test]
+    fn session_auth_refresh() {
+        let mut claims = make_test_claims(None);
+        claims.exp = Some(1_800_000_000);
+        assert_eq!(claims.expires_at(), Some(1_800_000_000));
+        claims.exp = None;
+        assert_eq!(claims.expires_at(), Some(4_070_908_800));
+
+        assert!(SessionAuth::new(AuthPolicy::ApiKey, None).is_none());
+        claims.exp = Some(1_800_000_000);
+        let auth = SessionAuth::new(AuthPolicy::Jwt, Some(&claims)).unwrap();
+        claims.exp = Some(1_800_003_600);
+        auth.apply(&claims).unwrap();
+        assert_eq!(auth.clone().expires_at(), Some(1_800_003_600));
+
+        claims.user.id = "someone-else".to_string();
+        claims.exp = Some(1_900_000_000);
+        assert!(auth.apply(&claims).is_err());
+        assert_eq!(auth.expires_at(), Some(1_800_003_600));
+    }
+
     #[test]
     fn test_check_approval_status_approved() {
         let claims = make_test_claims(Some("approved"));
@@ -629,4 +853,73 @@ mod tests {
         let rejected = AuthError::account_rejected(None);
         assert_eq!(rejected.error_type(), "account_rejected");
     }
+
+    #[test]
+    fn test_policy_none_allows_anonymous() {
+        let headers = HeaderMap::new();
+        assert!(check_policy(AuthPolicy::None, &headers, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_policy_jwt_requires_credentials() {
+        let headers = HeaderMap::new();
+        let err = check_policy(AuthPolicy::Jwt, &headers, None).unwrap_err();
+        assert!(matches!(err.code, AuthErrorCode::MissingCredentials));
+    }
+
+    #[test]
+    fn test_policy_deserialize() {
+        #[derive(Deserialize)]
+        struct M {
+            auth: AuthPolicy,
+        }
+        let m: M = toml::from_str("auth = \"api_key\"").unwrap();
+        assert_eq!(m.auth, AuthPolicy::ApiKey);
+        let m: M = toml::from_str("auth = \"none\"").unwrap();
+        assert_eq!(m.auth, AuthPolicy::None);
+        assert!(toml::from_str::<M>("auth = \"open\"").is_err());
+    }
+
+    #[test]
+    fn test_api_key_policy_requires_keys() {
+        let config = AuthConfig { jwt_secret: None, api_keys_configured: false };
+        assert!(config.check_policies("asr", [AuthPolicy::Jwt, AuthPolicy::None]).is_ok());
+        let err = config.check_policies("asr", [AuthPolicy::Jwt, AuthPolicy::ApiKey]).unwrap_err();
+        assert!(err.to_string().contains("MOSHI_API_KEY"));
+        let config = AuthConfig { api_keys_configured: true, ..config };
+        assert!(config.check_policies("asr", [AuthPolicy::ApiKey]).is_ok());
+    }
+
+    #[test]
+    fn test_api_key_sources() {
+        let keys = parse_api_keys("key-one, key-two,,");
+        assert_eq!(keys, vec!["key-one".to_string(), "key-two".to_string()]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(API_KEY_HEADER, "key-two".parse().unwrap());
+        assert!(check_api_key_against(&keys, &headers, None).is_ok());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION_HEADER, "Bearer key-one".parse().unwrap());
+        assert!(check_api_key_against(&keys, &headers, None).is_ok());
+
+        let headers = HeaderMap::new();
+        assert!(check_api_key_against(&keys, &headers, Some("key-one")).is_ok());
+        let err = check_api_key_against(&keys, &headers, Some("key-three")).unwrap_err();
+        assert!(matches!(err.code, AuthErrorCode::InvalidApiKey));
+        let err = check_api_key_against(&keys, &headers, None).unwrap_err();
+        assert!(matches!(err.code, AuthErrorCode::MissingCredentials));
+    }
+
+    #[test]
+    fn test_admin_role() {
+        let claims = make_test_claims(Some("approved"));
+        let err = check_role(claims.clone(), "admin").unwrap_err();
+        assert!(matches!(err.code, AuthErrorCode::InsufficientRole));
+        assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+
+        let mut admin = claims;
+        admin.user.role = Some("admin".to_string());
+        assert!(check_role(admin, "admin").is_ok());
+    }
 }
@@ -0,0 +1,325 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Persisted autotune results, enabled with `[autotune] enabled = true`.
+//!
+//! The first start on a GPU records, for each model, the dtype picked for the GPU and, for the
+//! batched asr modules, the latency of a model step measured with the self-benchmark and the
+//! largest batch that keeps up with the audio. The next starts read these values back instead
+//! of detecting them again, so that a server behaves the same across restarts. The first start
+//! already caps the sessions of a batched asr module to the calibrated batch, the model itself
+//! gets the smaller batch from the next start. Entries are keyed by the GPU, the sha3 of the
+//! checkpoint identity (path, size and modification time), the dtype override and the
+//! configured batch size: changing any of them calibrates again. Remove the cache file to force
+//! a new calibration.
+
+use crate::schema::{self, Artifact};
+use crate::{utils, Config, Module, ModuleConfig};
+use anyhow::{Context, Result};
+use sha3::Digest;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Seconds of audio streamed through a batched asr module to time its steps.
+const CALIBRATION_S: f64 = 3.0;
+/// Share of the 80ms frame that a model step may take, the rest is left for the other stages
+/// of the pipeline and for load spikes.
+const STEP_BUDGET_MS: f64 = 80.0 * 0.8;
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct AutotuneConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Directory of `autotune.json`, the user cache directory by default.
+    #[serde(default)]
+    pub dir: Option<PathBuf>,
+}
+
+impl AutotuneConfig {
+    pub fn path(&self) -> PathBuf {
+        let dir = self.dir.clone().unwrap_or_else(|| {
+            dirs::cache_dir().unwrap_or_else(std::env::temp_dir).join("moshi-server")
+        });
+        dir.join("autotune.json")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Tuning {
+    pub dtype: String,
+    /// p90 latency of a model step with every slot in use, in milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub step_ms: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_batch_size: Option<usize>,
+}
+
+/// Content of `autotune.json`.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct CacheFile {
+    schema_version: u64,
+    entries: BTreeMap<String, Tuning>,
+}
+
+impl CacheFile {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let value = schema::migrate(Artifact::Autotune, serde_json::from_slice(bytes)?)?;
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+#[derive(Debug)]
+pub struct Cache {
+    path: PathBuf,
+    entries: BTreeMap<String, Tuning>,
+}
+
+impl Cache {
+    /// Read the cache at `path`, a missing or unreadable file gives an empty cache.
+    pub fn load(path: PathBuf) -> Self {
+        let entries = match std::fs::read(&path) {
+            Ok(bytes) => CacheFile::parse(&bytes).map(|f| f.entries).unwrap_or_else(|err| {
+                tracing::warn!(?err, path = %path.display(), "ignoring invalid autotune cache");
+                BTreeMap::new()
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                tracing::warn!(?err, path = %path.display(), "cannot read autotune cache");
+                BTreeMap::new()
+            }
+        };
+        Self { path, entries }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Tuning> {
+        self.entries.get(key)
+    }
+
+    pub fn insert(&mut self, key: String, tuning: Tuning) {
+        self.entries.insert(key, tuning);
+    }
+
+    /// Write the cache, through a rename so that a concurrent start never reads a partial file.
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| dir.display().to_string())?;
+        }
+        let tmp = self.path.with_extension(format!("tmp{}", std::process::id()));
+        let file = CacheFile {
+            schema_version: Artifact::Autotune.version(),
+            entries: self.entries.clone(),
+        };
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&file)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Checkpoint, dtype override and configured batch size of the modules that run a model.
+fn model_of(module: &ModuleConfig) -> Option<(&str, Option<&str>, Option<usize>)> {
+    match module {
+        ModuleConfig::BatchedAsr { config: c, batch_size, .. } => {
+            Some((&c.lm_model_file, c.dtype_override.as_deref(), Some(*batch_size)))
+        }
+        ModuleConfig::Asr { config: c, .. } | ModuleConfig::Vad { config: c, .. } => {
+            Some((&c.lm_model_file, c.dtype_override.as_deref(), None))
+        }
+        ModuleConfig::Tts { config: c, .. } => {
+            Some((&c.lm_model_file, c.dtype_override.as_deref(), None))
+        }
+        ModuleConfig::Lm { config: c, .. } => {
+            Some((&c.lm_model_file, c.dtype_override.as_deref(), None))
+        }
+        ModuleConfig::Mimi { .. } => None,
+    }
+}
+
+/// Cache key of a model on `gpu`. The checkpoint is identified by the sha3 of its canonical
+/// path, size and modification time, stable across builds unlike the std hasher, hashing the
+/// content of a multi-GB checkpoint at every start would be too slow.
+pub fn key(
+    gpu: &utils::GpuInfo,
+    model_file: &str,
+    dtype_override: Option<&str>,
+    batch_size: Option<usize>,
+) -> String {
+    let file = Path::new(model_file);
+    let mut hasher = sha3::Sha3_256::new();
+    let path = file.canonicalize().unwrap_or_else(|_| file.to_path_buf());
+    hasher.update(path.as_os_str().as_encoded_bytes());
+    if let Ok(meta) = std::fs::metadata(file) {
+        hasher.update(meta.len().to_le_bytes());
+        let modified =
+            meta.modified().ok().and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok());
+        if let Some(modified) = modified {
+            hasher.update(modified.as_secs().to_le_bytes());
+            hasher.update(modified.subsec_nanos().to_le_bytes());
+        }
+    }
+    let digest: String = hasher.finalize()[..8].iter().map(|b| format!("{b:02x}")).collect();
+    let stem = file.file_stem().and_then(|s| s.to_str()).unwrap_or("model");
+    let mut key = format!(
+        "{} sm{} {}MB/{stem}.{digest}/{}",
+        gpu.name,
+        gpu.sm_version(),
+        gpu.total_vram_mb(),
+        dtype_override.unwrap_or("auto"),
+    );
+    if let Some(batch_size) = batch_size {
+        key.push_str(&format!("/batch{batch_size}"));
+    }
+    key
+}
+
+/// Largest batch whose steps fit in [`STEP_BUDGET_MS`], assuming that the step time grows
+/// with the batch size from the time measured with `batch_size` slots.
+pub fn max_batch_size(batch_size: usize, step_ms: f64) -> usize {
+    if step_ms <= STEP_BUDGET_MS {
+        return batch_size;
+    }
+    ((batch_size as f64 * STEP_BUDGET_MS / step_ms) as usize).max(1)
+}
+
+/// Set the cached dtype and batch size of the modules, before the memory plan. Returns the
+/// names and keys of the modules without an entry, to calibrate once they are loaded.
+pub fn apply(
+    cache: &Cache,
+    modules: &mut HashMap<String, ModuleConfig>,
+    gpu: &utils::GpuInfo,
+) -> Vec<(String, String)> {
+    let mut pending = vec![];
+    for (name, module) in modules.iter_mut() {
+        let Some((model_file, dtype_override, batch_size)) = model_of(module) else { continue };
+        let key = key(gpu, model_file, dtype_override, batch_size);
+        let Some(tuning) = cache.get(&key) else {
+            pending.push((name.clone(), key));
+            continue;
+        };
+        tracing::info!(module = name, ?tuning, "using autotune cache");
+        match module {
+            ModuleConfig::BatchedAsr { config: c, batch_size, .. } => {
+                c.dtype_override.get_or_insert_with(|| tuning.dtype.clone());
+                if let Some(max) = tuning.max_batch_size {
+                    *batch_size = (*batch_size).min(max);
+                }
+            }
+            ModuleConfig::Asr { config: c, .. } | ModuleConfig::Vad { config: c, .. } => {
+                c.dtype_override.get_or_insert_with(|| tuning.dtype.clone());
+            }
+            ModuleConfig::Tts { config: c, .. } => {
+                c.dtype_override.get_or_insert_with(|| tuning.dtype.clone());
+            }
+            ModuleConfig::Lm { config: c, .. } => {
+                c.dtype_override.get_or_insert_with(|| tuning.dtype.clone());
+            }
+            ModuleConfig::Mimi { .. } => {}
+        }
+    }
+    pending.sort();
+    pending
+}
+
+/// Record the dtype of the `pending` modules and time the steps of the batched asr ones, whose
+/// sessions are capped to the calibrated batch right away, then write the cache. Runs before
+/// the server accepts connections so that every slot is idle.
+pub async fn calibrate(
+    mut cache: Cache,
+    pending: &[(String, String)],
+    config: &Config,
+    modules: &[Module],
+) {
+    for (name, key) in pending {
+        let Some(module) = config.modules.get(name) else { continue };
+        let Some((_, Some(dtype), _)) = model_of(module) else { continue };
+        let mut tuning = Tuning { dtype: dtype.to_string(), step_ms: None, max_batch_size: None };
+        if let ModuleConfig::BatchedAsr { path, batch_size, .. } = module {
+            let m = modules.iter().find_map(|m| match m {
+                Module::BatchedAsr { path: p, m, .. } if p == path => Some(m),
+                _ => None,
+            });
+            let Some(m) = m else { continue };
+            tracing::info!(module = name, batch_size, "calibrating batched asr");
+            let report = match m.bench_latency(path, CALIBRATION_S).await {
+                Ok(Some(report)) => report,
+                Ok(None) => {
+                    tracing::warn!(module = name, "no idle slot, skipping calibration");
+                    continue;
+                }
+                Err(err) => {
+                    tracing::warn!(module = name, ?err, "calibration failed");
+                    continue;
+                }
+            };
+            let max = max_batch_size(*batch_size, report.p90_ms);
+            tracing::info!(
+                module = name,
+                step_ms = report.p90_ms,
+                max_batch_size = max,
+                "calibrated"
+            );
+            if max < *batch_size {
+                m.limit_slots(max);
+            }
+            tuning.step_ms = Some(report.p90_ms);
+            tuning.max_batch_size = Some(max);
+        }
+        cache.insert(key.clone(), tuning);
+    }
+    if let Err(err) = cache.save() {
+        tracing::warn!(?err, path = %cache.path.display(), "cannot write autotune cache");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gpu() -> utils::GpuInfo {
+        utils::GpuInfo {
+            free_vram: 6 << 30,
+            total_vram: 8 << 30,
+            name: "NVIDIA GeForce RTX 2070".to_string(),
+            compute_major: 7,
+            compute_minor: 5,
+            utilization: 0,
+        }
+    }
+
+    #[test]
+    fn cache_roundtrip() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("moshi-autotune-test-{}", std::process::id()));
+        let model = dir.join("model.safetensors");
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(&model, b"weights")?;
+        let model = model.to_str().unwrap();
+        let path = dir.join("cache").join("autotune.json");
+
+        let key = key(&gpu(), model, None, Some(32));
+        assert!(key.starts_with("NVIDIA GeForce RTX 2070 sm75 8192MB/model."), "{key}");
+        assert_ne!(key, super::key(&gpu(), model, None, Some(16)));
+        assert_ne!(key, super::key(&gpu(), model, Some("f32"), Some(32)));
+        let mut cache = Cache::load(path.clone());
+        assert!(cache.get(&key).is_none());
+        let tuning =
+            Tuning { dtype: "f16".to_string(), step_ms: Some(41.5), max_batch_size: Some(24) };
+        cache.insert(key.clone(), tuning.clone());
+        cache.save()?;
+        assert_eq!(Cache::load(path.clone()).get(&key), Some(&tuning));
+
+        // A new checkpoint gets a new entry.
+        std::fs::write(model, b"new weights")?;
+        assert_ne!(key, super::key(&gpu(), model, None, Some(32)));
+        std::fs::write(&path, b"{")?;
+        assert!(Cache::load(path).get(&key).is_none());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn batch_size_from_step_latency() {
+        assert_eq!(max_batch_size(32, 40.0), 32);
+        assert_eq!(max_batch_size(32, 128.0), 16);
+        assert_eq!(max_batch_size(4, 1000.0), 1);
+    }
+}
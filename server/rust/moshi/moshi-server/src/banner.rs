@@ -120,7 +120,7 @@ impl ServerBanner {
 
     /// Print a boxed header with a title
     pub fn print_box_header(&self, title: &str) {
-        let padding = (self.box_width - title.len() - 4).max(0);
+        let padding = self.box_width.saturating_sub(title.len() + 4);
         let left_pad = padding / 2;
         let right_pad = padding - left_pad;
 
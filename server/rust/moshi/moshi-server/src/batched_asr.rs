@@ -2,17 +2,30 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
-use crate::asr::{InMsg, OutMsg};
+use crate::asr::{word_tokens, Detail, InMsg, OutMsg};
+use crate::audio_stats::SessionStats;
+use crate::breaker::{BreakerConfig, Quarantine, SlotErrors};
+use crate::checkpoint::{Checkpoint, ContextRecorder};
+use crate::drift::TimeCorrection;
+use crate::formatting::{FormatterChain, Formatting};
+use crate::itn::Itn;
+use crate::lang::LangTagger;
 use crate::metrics::asr as metrics;
 use crate::metrics::errors as error_metrics;
 use crate::metrics::warmup as warmup_metrics;
 use crate::protocol::CloseCode;
+use crate::retune::{RetuneQuery, Tuning};
+use crate::schema::Artifact;
+use crate::session_summary::{self, SessionSummary};
+use crate::speaker::SpeakerFilter;
+use crate::speaker_count::SpeakerStats;
+use crate::spotting::PhraseSpotter;
 use crate::AsrStreamingQuery as Query;
 use anyhow::{Context, Result};
 use axum::extract::ws;
 use candle::{DType, Device, Tensor};
-use candle_nn::VarBuilder;
 use std::collections::{BinaryHeap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use tokio::task;
@@ -24,16 +37,97 @@ enum PipelineEvent {
 }
 
 const FRAME_SIZE: usize = 1920;
+/// Duration of a frame in seconds.
+const FRAME_S: f64 = FRAME_SIZE as f64 / 24000.0;
+/// Shortest segment, so that the flushes remain a small part of the audio.
+const MIN_SEGMENT_S: f64 = 10.0;
 const SEND_PING_EVERY: Duration = Duration::from_secs(10);
 const POST_RETRY_DELAY: Duration = Duration::from_millis(100);
 const POST_MAX_RETRIES: usize = 1000;
+const MAX_DETACHED_BACKLOG: usize = 4096;
+/// How many times a batch query resumes after losing its slot before giving up.
+const BATCH_QUERY_MAX_RESUMES: usize = 2;
+/// How long the self-benchmark waits for the last steps once all its audio has been sent.
+const BENCH_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// When the model steps, the `step_scheduling` of the asr config.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StepScheduling {
+    /// Step as soon as any slot has a full frame, the slots whose frame arrives a bit later
+    /// wait for the next step.
+    #[default]
+    Latency,
+    /// Hold the step until every attached slot has a frame, or `step_max_wait_ms` after the
+    /// first one, so that each step carries more slots.
+    Throughput,
+}
+
+impl StepScheduling {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Latency => "latency",
+            Self::Throughput => "throughput",
+        }
+    }
+}
+
+/// Decides when the frames gathered by the encoder loop make a step.
+struct StepGate {
+    scheduling: StepScheduling,
+    max_wait: Duration,
+    /// When the first frame of the held step was ready.
+    first_frame: Option<Instant>,
+}
+
+impl StepGate {
+    fn new(scheduling: StepScheduling, max_wait: Duration) -> Self {
+        Self { scheduling, max_wait, first_frame: None }
+    }
+
+    /// Whether a partial step is held, its slots that have a frame are not read again.
+    fn holding(&self) -> bool {
+        self.first_frame.is_some()
+    }
+
+    /// Whether to step now, with `filled` slots having a frame and `pending` attached slots
+    /// still waiting for theirs. Steps without any frame are left to the caller.
+    fn ready(&mut self, filled: usize, pending: usize, now: Instant) -> bool {
+        if filled == 0 {
+            return true;
+        }
+        let first_frame = *self.first_frame.get_or_insert(now);
+        let waited = now.saturating_duration_since(first_frame);
+        let ready = match self.scheduling {
+            StepScheduling::Latency => true,
+            StepScheduling::Throughput => pending == 0 || waited >= self.max_wait,
+        };
+        if ready {
+            self.first_frame = None;
+            let label = [self.scheduling.label()];
+            metrics::BATCH_WAIT.with_label_values(&label).observe(waited.as_secs_f64());
+            let fill = filled as f64 / (filled + pending) as f64;
+            metrics::BATCH_FILL.with_label_values(&label).observe(fill);
+        }
+        ready
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum MarkerKind {
+    /// Sent by the client, echoed back once the audio before it has been processed.
+    /// `sample_idx` is the session time of the marker, in samples.
+    Client { id: i64, sample_idx: u64 },
+    /// End of the flush of a segment, the words that follow belong to the next segment.
+    Segment,
+}
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 struct Marker {
     channel_id: ChannelId,
     batch_idx: usize,
     step_idx: usize,
-    marker_id: i64,
+    kind: MarkerKind,
 }
 
 impl std::cmp::PartialOrd for Marker {
@@ -72,24 +166,525 @@ struct Channel {
     in_rx: InRecv,
     out_tx: OutSend,
     data: VecDeque<f32>,
+    /// Frames of mimi codes sent by the client, fed in place of the encoded audio, with the
+    /// samples of `data` that arrived before them and go through the model first.
+    codes: VecDeque<(usize, Vec<u32>)>,
+    steps: usize,
+    /// Logical stream requested by the client, the slot survives a disconnection for the
+    /// grace period so that a reconnecting client can resume with the same model state.
+    stream_id: Option<String>,
+    /// User that opened the stream, the only one allowed to resume it.
+    owner: Option<String>,
+    detached_at: Option<Instant>,
+    /// Messages produced while the client was away, replayed when it reattaches.
+    backlog: VecDeque<OutMsg>,
+    /// Recent audio, exported on `Checkpoint`.
+    context: ContextRecorder,
+    /// Session time at which the slot started, non-zero for restored sessions.
+    time_offset: f64,
+    /// Shift of the timestamps to the time of the source media.
+    drift: TimeCorrection,
+    last_word_s: Option<f64>,
+    replay: Option<Replay>,
+    /// Language of the words, when `word_lang` is enabled.
+    lang: Option<LangTagger>,
+    segments: Option<Segments>,
+    /// Audio statistics and speaking rate, when requested by the client.
+    stats: Option<SessionStats>,
+    /// Speaker-count and overlap diagnostics, when requested by the client.
+    speakers: Option<SpeakerStats>,
+    /// Voice activity, words and levels per second, when `summary_path` is set.
+    summary: Option<SessionSummary>,
+    /// Batch query whose slot can be taken by an interactive session.
+    preemptible: bool,
+    /// Voice enrolled by the client, the words of other speakers are tagged or dropped.
+    speaker: Option<SpeakerFilter>,
+    formatter: FormatterChain,
+    /// Numbers written with digits, with `itn`.
+    itn: Option<Itn>,
+    /// Phrases requested with `spot`, announced after their last word.
+    spotter: Option<PhraseSpotter>,
+    detail: Detail,
+    /// Inference errors, the session is terminated once they trip the circuit breaker.
+    errors: SlotErrors,
+    /// Upstream ids of the attached connection, echoed in `Ready`.
+    trace: crate::otel::TraceIds,
+}
+
+/// Time-based segmentation of long sessions. At the end of each segment the words in flight
+/// are flushed by feeding silence, then the model state of the slot is reset and the
+/// timestamps of the next segment continue from the audio time at which it starts.
+///
+/// The pre-process side feeds the flush and requests the reset, the post-process side
+/// announces the new segment once the words of the previous one have been sent.
+#[derive(Debug)]
+struct Segments {
+    frames: usize,
+    flush: usize,
+    /// Value of `Channel::steps` when the segment being fed started, and its audio time.
+    begin_steps: usize,
+    begin_s: f64,
+    /// Step at which the model is reset and start of the next segment, during a flush.
+    flushing: Option<(usize, f64)>,
+    /// Starts of the segments whose boundary has not been announced yet.
+    pending: VecDeque<f64>,
+    index: usize,
+    start_s: f64,
+    store: Option<SegmentStore>,
+}
+
+/// Words of the current segment, written to `{prefix}-{index}.json` when it ends.
+#[derive(Debug)]
+struct SegmentStore {
+    prefix: std::path::PathBuf,
+    words: Vec<SegmentWord>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct SegmentWord {
+    text: String,
+    start_s: f64,
+    stop_s: Option<f64>,
+}
+
+impl Segments {
+    fn new(segment_s: f64, flush: usize, store: Option<std::path::PathBuf>) -> Self {
+        let frames = (segment_s.max(MIN_SEGMENT_S) / FRAME_S).round() as usize;
+        Self {
+            frames,
+            flush,
+            begin_steps: 0,
+            begin_s: 0.0,
+            flushing: None,
+            pending: VecDeque::new(),
+            index: 0,
+            start_s: 0.0,
+            store: store.map(|prefix| SegmentStore { prefix, words: vec![] }),
+        }
+    }
+
+    /// Called before feeding the frames of a step, returns true when the model state of the
+    /// slot has to be reset.
+    fn pre_step(&mut self, steps: usize, data: &mut VecDeque<f32>) -> bool {
+        match self.flushing {
+            Some((reset_at, next_s)) if steps >= reset_at => {
+                self.flushing = None;
+                self.begin_steps = steps;
+                self.begin_s = next_s;
+                self.pending.push_back(next_s);
+                true
+            }
+            Some(_) => false,
+            None if steps >= self.begin_steps + self.frames => {
+                // The silence goes ahead of the audio buffered in the meantime.
+                let next_s = self.begin_s + (steps - self.begin_steps) as f64 * FRAME_S;
+                for _ in 0..self.flush * FRAME_SIZE {
+                    data.push_front(0.0);
+                }
+                self.flushing = Some((steps + self.flush, next_s));
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Move to the next segment, returns its index and start time.
+    fn next(&mut self) -> Option<(usize, f64)> {
+        let start_s = self.pending.pop_front()?;
+        self.save();
+        self.index += 1;
+        self.start_s = start_s;
+        Some((self.index, start_s))
+    }
+
+    fn push(&mut self, msg: &OutMsg) {
+        let Some(store) = self.store.as_mut() else { return };
+        match msg {
+            OutMsg::Word { text, start_time, .. } => store.words.push(SegmentWord {
+                text: text.clone(),
+                start_s: *start_time,
+                stop_s: None,
+            }),
+            OutMsg::EndWord { stop_time } => {
+                if let Some(w) = store.words.last_mut() {
+                    w.stop_s = Some(*stop_time)
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Write the words of the current segment, off the calling thread.
+    fn save(&mut self) {
+        let Some(store) = self.store.as_mut() else { return };
+        let words = std::mem::take(&mut store.words);
+        let path = format!("{}-{:05}.json", store.prefix.display(), self.index);
+        let json = serde_json::json!({
+            "schema_version": Artifact::Segment.version(),
+            "index": self.index,
+            "start_s": self.start_s,
+            "words": words,
+        });
+        std::thread::spawn(move || {
+            if let Err(err) = std::fs::write(&path, json.to_string()) {
+                tracing::error!(?err, path, "cannot write asr segment")
+            }
+        });
+    }
+}
+
+/// Output filter while the audio of a restored checkpoint is replayed.
+#[derive(Debug)]
+struct Replay {
+    /// Steps of replayed audio left.
     steps: usize,
+    /// Words starting before this time were already sent by the previous session.
+    words_until: Option<f64>,
+    dropping_word: bool,
+}
+
+/// Per-session settings of a new slot.
+#[derive(Debug, Default)]
+struct SlotOptions<'a> {
+    stream_id: Option<&'a str>,
+    /// Authenticated user, for the path of the session summary.
+    user: Option<&'a str>,
+    segment_s: Option<f64>,
+    stats_interval_s: Option<f64>,
+    speakers_interval_s: Option<f64>,
+    formatting: Formatting,
+    itn: bool,
+    spot: &'a [String],
+    preemptible: bool,
+    detail: Detail,
+    trace: crate::otel::TraceIds,
+}
+
+/// Where a preempted batch query resumes, after the last word whose end was received. The
+/// words from there on are removed from `msgs` to be recognized again, the checkpoint
+/// replays the audio before the resume point so that the model has its context back. Also
+/// returns the sample at which the rest of the audio starts, `None` restarts from scratch.
+fn resume_point(
+    msgs: &mut Vec<OutMsg>,
+    pcm: &[f32],
+    model: &str,
+    context_s: f64,
+) -> Option<(Checkpoint, usize)> {
+    if matches!(msgs.last(), Some(OutMsg::Word { .. })) {
+        msgs.pop();
+    }
+    let mut last_word_s = None;
+    let mut end_s = 0.0;
+    for msg in msgs.iter() {
+        match msg {
+            OutMsg::Word { start_time, .. } => last_word_s = Some(*start_time),
+            OutMsg::EndWord { stop_time } => end_s = *stop_time,
+            _ => {}
+        }
+    }
+    let last_word_s = last_word_s?;
+    let end = ((end_s.max(last_word_s) / FRAME_S).ceil() as usize * FRAME_SIZE).min(pcm.len());
+    let context = (context_s.min(crate::checkpoint::MAX_CONTEXT_S) / FRAME_S) as usize * FRAME_SIZE;
+    let start = end.saturating_sub(context) / FRAME_SIZE * FRAME_SIZE;
+    let ckpt = Checkpoint {
+        model: model.to_string(),
+        start_s: start as f64 * FRAME_S / FRAME_SIZE as f64,
+        last_word_s: Some(last_word_s),
+        pcm: pcm[start..end].to_vec(),
+        codes: vec![],
+    };
+    Some((ckpt, end))
 }
 
 impl Channel {
-    fn new(in_rx: InRecv, out_tx: OutSend) -> Result<Self> {
+    fn new(
+        in_rx: InRecv,
+        out_tx: OutSend,
+        stream_id: Option<String>,
+        checkpoint_context_s: f64,
+    ) -> Result<Self> {
         metrics::OPEN_CHANNELS.inc();
         Ok(Self {
             id: ChannelId::new(),
             in_rx,
             out_tx,
             data: VecDeque::new(),
+            codes: VecDeque::new(),
             steps: 0,
+            stream_id,
+            owner: None,
+            detached_at: None,
+            backlog: VecDeque::new(),
+            context: ContextRecorder::new(checkpoint_context_s),
+            time_offset: 0.0,
+            drift: TimeCorrection::default(),
+            last_word_s: None,
+            replay: None,
+            lang: None,
+            segments: None,
+            stats: None,
+            speakers: None,
+            summary: None,
+            preemptible: false,
+            speaker: None,
+            formatter: FormatterChain::default(),
+            itn: None,
+            spotter: None,
+            detail: Detail::default(),
+            errors: SlotErrors::default(),
+            trace: Default::default(),
         })
     }
 
+    fn push_audio(&mut self, pcm: &[f32], out_pcm: &mut [f32]) -> bool {
+        self.context.push(pcm);
+        if let Some(summary) = self.summary.as_mut() {
+            summary.push(pcm);
+        }
+        if !self.codes.is_empty() {
+            // Queued behind the codes, the frames are taken in arrival order.
+            self.data.extend(pcm);
+            return false;
+        }
+        self.extend_data(pcm, out_pcm)
+    }
+
+    /// Queue frames of mimi codes after the audio already buffered, whose last partial frame
+    /// is completed with silence. The audio the codes stand for is recorded as silence in the
+    /// summary, so that the session time keeps advancing, and as codes in the checkpoints.
+    fn push_codes(&mut self, codes: Vec<Vec<u32>>) {
+        let padding = vec![0.0; self.data.len().next_multiple_of(FRAME_SIZE) - self.data.len()];
+        self.context.push(&padding);
+        self.data.extend(&padding);
+        let silence = vec![0.0; padding.len() + codes.len() * FRAME_SIZE];
+        if let Some(summary) = self.summary.as_mut() {
+            summary.push(&silence);
+        }
+        for frame in codes {
+            self.context.push_codes(frame.clone());
+            self.codes.push_back((self.data.len(), frame));
+        }
+    }
+
+    /// The next frame of codes when all the audio sent before it went through the model.
+    fn pop_due_codes(&mut self) -> Option<Vec<u32>> {
+        match self.codes.front() {
+            Some((0, _)) => self.codes.pop_front().map(|(_, frame)| frame),
+            _ => None,
+        }
+    }
+
+    /// Samples of `data` that can be fed to the model before the next frame of codes.
+    fn pcm_before_codes(&self) -> usize {
+        self.codes.front().map_or(self.data.len(), |(ahead, _)| *ahead)
+    }
+
+    /// Audio received but not fed to the model yet, in samples.
+    fn buffered_pcm(&self) -> usize {
+        self.data.len() + self.codes.len() * FRAME_SIZE
+    }
+
+    fn checkpoint(&self, model: &str) -> OutMsg {
+        match self.context.checkpoint(model, self.last_word_s).encode() {
+            Ok(data) => OutMsg::Checkpoint { data },
+            Err(err) => OutMsg::Error { message: format!("checkpoint failed: {err}") },
+        }
+    }
+
+    /// Start from a checkpoint: its audio is queued for replay and the timestamps continue
+    /// from the checkpoint.
+    fn restore(&mut self, data: &[u8], model: &str, n_q: usize, bins: u32) -> Result<()> {
+        if self.steps > 0 || !self.data.is_empty() || !self.context.is_empty() {
+            anyhow::bail!("restore must be sent before any audio")
+        }
+        let ckpt = Checkpoint::decode(data)?;
+        if ckpt.model != model {
+            anyhow::bail!("checkpoint of model {}, this server runs {model}", ckpt.model)
+        }
+        let codes: Vec<_> = ckpt.codes.iter().map(|(_, frame)| frame.clone()).collect();
+        check_codes(&codes, n_q, bins).map_err(anyhow::Error::msg)?;
+        self.time_offset = ckpt.start_s;
+        if let Some(s) = self.segments.as_mut() {
+            s.begin_s = ckpt.start_s;
+            s.start_s = ckpt.start_s;
+        }
+        self.context.restart_at(ckpt.start_s);
+        if let Some(stats) = self.stats.as_mut() {
+            stats.restart_at(ckpt.start_s);
+        }
+        if let Some(summary) = self.summary.as_mut() {
+            // The replayed audio and its steps are not part of this session.
+            summary.restart_at(ckpt.start_s + ckpt.pcm.len() as f64 / 24000.0);
+        }
+        if let Some(speaker) = self.speaker.as_mut() {
+            speaker.restart_at(ckpt.start_s);
+            speaker.push(&ckpt.pcm);
+        }
+        // The frames sent as codes are replayed as codes, in place of their silence.
+        let mut pcm_start = 0;
+        for (offset, frame) in ckpt.codes {
+            // Checked by the decoding, a bad offset must not panic in the model loop.
+            let Some(pcm) = ckpt.pcm.get(pcm_start..offset) else {
+                anyhow::bail!("invalid codes frame at {offset} in the checkpoint")
+            };
+            self.context.push(pcm);
+            self.context.push_codes(frame.clone());
+            self.data.extend(pcm);
+            self.codes.push_back((self.data.len(), frame));
+            pcm_start = offset + FRAME_SIZE;
+        }
+        let tail = ckpt.pcm.get(pcm_start..).unwrap_or_default();
+        self.context.push(tail);
+        self.replay = Some(Replay {
+            steps: ckpt.pcm.len() / FRAME_SIZE,
+            words_until: ckpt.last_word_s,
+            dropping_word: false,
+        });
+        self.last_word_s = ckpt.last_word_s;
+        self.data.extend(tail);
+        Ok(())
+    }
+
+    /// Shift the timestamps of restored sessions and drop the output of the replayed audio.
+    fn filter(&mut self, msg: OutMsg) -> Option<OutMsg> {
+        let msg = match msg {
+            OutMsg::Word { text, start_time, lang, mut tokens } => {
+                for token in tokens.iter_mut().flatten() {
+                    token.start_s = self.drift.correct(token.start_s + self.time_offset);
+                }
+                let start_time = self.drift.correct(start_time + self.time_offset);
+                OutMsg::Word { text, start_time, lang, tokens }
+            }
+            OutMsg::EndWord { stop_time } => {
+                OutMsg::EndWord { stop_time: self.drift.correct(stop_time + self.time_offset) }
+            }
+            OutMsg::Step { step_idx, prs, buffered_pcm, .. } => {
+                let time_correction_ms = self.drift.current_ms();
+                OutMsg::Step { step_idx, prs, buffered_pcm, time_correction_ms }
+            }
+            msg => msg,
+        };
+        if let Some(replay) = self.replay.as_mut() {
+            match &msg {
+                OutMsg::Step { .. } if replay.steps > 0 => {
+                    replay.steps -= 1;
+                    return None;
+                }
+                OutMsg::Word { start_time, .. } => {
+                    // Timestamps have a one frame resolution, allow for half a frame of drift.
+                    replay.dropping_word =
+                        replay.words_until.is_some_and(|t| *start_time < t + 0.04);
+                    if replay.dropping_word {
+                        return None;
+                    }
+                    if replay.steps == 0 {
+                        self.replay = None;
+                    }
+                }
+                OutMsg::EndWord { .. } if replay.dropping_word => {
+                    replay.dropping_word = false;
+                    return None;
+                }
+                _ => {}
+            }
+        }
+        let msg = match msg {
+            OutMsg::Word { text, start_time, lang, tokens } => {
+                OutMsg::Word { text: self.formatter.format(text), start_time, lang, tokens }
+            }
+            msg => msg,
+        };
+        match (&msg, self.summary.as_mut()) {
+            (OutMsg::Step { prs, buffered_pcm, .. }, Some(summary)) => {
+                summary.step(prs, *buffered_pcm)
+            }
+            (OutMsg::Word { start_time, .. }, Some(summary)) => summary.word(*start_time),
+            _ => {}
+        }
+        if let OutMsg::Word { start_time, .. } = &msg {
+            self.last_word_s = Some(*start_time);
+            if let Some(stats) = self.stats.as_mut() {
+                stats.word(*start_time);
+            }
+        }
+        if let Some(s) = self.segments.as_mut() {
+            s.push(&msg);
+        }
+        match self.speaker.as_mut() {
+            Some(speaker) => speaker.filter(msg),
+            None => Some(msg),
+        }
+    }
+
+    /// Announce the next segment, the timestamps of the words that follow start from it.
+    fn start_segment(&mut self) -> Option<OutMsg> {
+        let (index, start_s) = self.segments.as_mut()?.next()?;
+        self.time_offset = start_s;
+        if let Some(summary) = self.summary.as_mut() {
+            summary.segment(start_s);
+        }
+        metrics::SEGMENTS.inc();
+        Some(OutMsg::SegmentBoundary { index, start_s })
+    }
+
+    fn is_detached(&self) -> bool {
+        self.stream_id.is_some() && self.out_tx.is_closed()
+    }
+
+    /// Whether the slot can be released: the client is gone and cannot come back anymore.
+    fn is_expired(&self, grace: Duration) -> bool {
+        if !self.out_tx.is_closed() {
+            return false;
+        }
+        self.stream_id.is_none() || self.detached_at.is_some_and(|t| t.elapsed() >= grace)
+    }
+
+    /// Attach the connection of `user` to this slot, refused when another user opened the
+    /// stream so that a known stream id is not enough to take over a transcript.
+    fn resume(
+        &mut self,
+        user: Option<&str>,
+        in_rx: InRecv,
+        out_tx: OutSend,
+        trace: crate::otel::TraceIds,
+    ) -> Result<()> {
+        if self.owner.as_deref() != user {
+            anyhow::bail!("the stream belongs to another user")
+        }
+        self.reattach(in_rx, out_tx, trace);
+        Ok(())
+    }
+
+    /// Attach a new connection to this slot, the pending audio and model state are kept.
+    fn reattach(&mut self, in_rx: InRecv, out_tx: OutSend, trace: crate::otel::TraceIds) {
+        while let Ok(msg) = self.in_rx.try_recv() {
+            match msg {
+                InMsg::Audio { pcm } => {
+                    self.context.push(&pcm);
+                    if let Some(summary) = self.summary.as_mut() {
+                        summary.push(&pcm);
+                    }
+                    self.data.extend(pcm);
+                }
+                InMsg::TimeCorrection { delta_s } => {
+                    self.drift.shift(self.context.end_s(), delta_s)
+                }
+                _ => {}
+            }
+        }
+        self.trace = trace;
+        let _ = out_tx.send(OutMsg::ready(&self.trace));
+        for msg in self.backlog.drain(..) {
+            let _ = out_tx.send(msg);
+        }
+        self.in_rx = in_rx;
+        self.out_tx = out_tx;
+        self.detached_at = None;
+    }
+
     fn extend_data(&mut self, pcm: &[f32], out_pcm: &mut [f32]) -> bool {
         debug_assert_eq!(out_pcm.len(), FRAME_SIZE);
-        if pcm.is_empty() && self.data.len() < FRAME_SIZE {
+        if pcm.is_empty() && self.pcm_before_codes() < FRAME_SIZE {
             return false;
         }
         if self.data.is_empty() && pcm.len() >= FRAME_SIZE {
@@ -105,6 +700,9 @@ impl Channel {
                 let cont = self.data.make_contiguous();
                 out_pcm.copy_from_slice(&cont[..FRAME_SIZE]);
                 self.data.drain(..FRAME_SIZE);
+                for (ahead, _) in self.codes.iter_mut() {
+                    *ahead -= FRAME_SIZE;
+                }
                 true
             } else {
                 false
@@ -112,10 +710,69 @@ impl Channel {
         }
     }
 
-    fn send(&self, msg: OutMsg, ref_channel_id: Option<ChannelId>) -> Result<()> {
+    fn send(&mut self, msg: OutMsg, ref_channel_id: Option<ChannelId>) -> Result<()> {
         // If the channel id has changed compared to the reference. Return Ok(())
         // so as not to disconnect the new user.
-        if Some(self.id) != ref_channel_id {
+        if Some(self.id) != ref_channel_id || self.errors.tripped().is_some() {
+            return Ok(());
+        }
+        let Some(msg) = self.filter(msg) else { return Ok(()) };
+        let msgs = match self.itn.as_mut() {
+            Some(itn) => itn.push(msg),
+            None => vec![msg],
+        };
+        for msg in msgs {
+            let spotted = match (&msg, self.spotter.as_mut()) {
+                (OutMsg::Word { text, start_time, .. }, Some(spotter)) => {
+                    spotter.push(text, *start_time)
+                }
+                _ => None,
+            };
+            self.deliver(msg)?;
+            if let Some(msg) = spotted {
+                self.deliver(msg)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Count an inference error of the session. Once the breaker trips the session gets a
+    /// last error and no other message, the slot is released to the quarantine on the next
+    /// clean up.
+    fn inference_error(
+        &mut self,
+        breaker: &BreakerConfig,
+        bid: usize,
+        err: &dyn std::fmt::Display,
+    ) {
+        metrics::SLOT_ERRORS.inc();
+        if !self.errors.record(Instant::now(), breaker) {
+            // Only the first error is logged, the breaker takes care of the rest.
+            if self.errors.total() == 1 {
+                tracing::warn!(bid, %err, "asr inference error");
+            }
+            return;
+        }
+        tracing::error!(
+            bid,
+            %err,
+            errors = self.errors.total(),
+            cooldown_s = breaker.cooldown_s,
+            "asr session tripped the circuit breaker"
+        );
+        metrics::SLOT_TRIPPED.inc();
+        let (module, error) = ("batched_asr".to_string(), "circuit_breaker".to_string());
+        crate::events::emit(crate::events::Event::Error { module, error });
+        let message = crate::breaker::TRIPPED_MESSAGE.to_string();
+        let _ = self.out_tx.send(OutMsg::Error { message });
+    }
+
+    /// Send a message to the client, or keep it for the reattach while it is detached.
+    fn deliver(&mut self, msg: OutMsg) -> Result<()> {
+        if self.is_detached() {
+            if !matches!(msg, OutMsg::Step { .. }) && self.backlog.len() < MAX_DETACHED_BACKLOG {
+                self.backlog.push_back(msg);
+            }
             return Ok(());
         }
         self.out_tx.send(msg)?;
@@ -125,6 +782,12 @@ impl Channel {
 
 impl Drop for Channel {
     fn drop(&mut self) {
+        if let Some(s) = self.segments.as_mut() {
+            s.save();
+        }
+        if let Some(s) = self.summary.as_mut() {
+            s.save();
+        }
         metrics::OPEN_CHANNELS.dec();
         metrics::CONNECTION_NUM_STEPS.observe(self.steps as f64);
     }
@@ -214,14 +877,38 @@ impl Logger {
 }
 
 struct BatchedAsrInner {
+    /// Path of the module, for the server events.
+    path: String,
     channels: Channels,
     active_indices: Arc<Mutex<VecDeque<usize>>>,
     free_indices: Arc<Mutex<VecDeque<usize>>>,
+    /// Slots that sessions can take, see [`BatchedAsr::limit_slots`].
+    slots: Arc<AtomicUsize>,
     asr_delay_in_tokens: usize,
-    temperature: f64,
+    stream_grace: Duration,
+    /// Model file name, checkpoints can only be restored on the same model.
+    model_id: String,
+    speaker_max_distance: f32,
+    tuning: tokio::sync::watch::Receiver<Tuning>,
+    step_scheduling: StepScheduling,
+    step_max_wait: Duration,
     lm: moshi::lm::LmModel,
     audio_tokenizer: moshi::mimi::Mimi,
     text_tokenizer: std::sync::Arc<sentencepiece::SentencePieceProcessor>,
+    breaker: BreakerConfig,
+    /// Slots of the sessions terminated by the breaker, only used by the clean up.
+    quarantine: Mutex<Quarantine>,
+}
+
+/// Log and count the steps of a slot whose text logits were not finite.
+pub(crate) fn nonfinite_step(step_idx: usize, batch_idx: usize, recovered: bool) {
+    if recovered {
+        tracing::warn!(step_idx, bid = batch_idx, "non-finite text logits, retried in f32");
+        metrics::NONFINITE_RECOVERED.inc();
+    } else {
+        tracing::error!(step_idx, bid = batch_idx, "non-finite text logits, slot context reset");
+        metrics::NONFINITE_RESET.inc();
+    }
 }
 
 fn warmup(
@@ -269,10 +956,11 @@ impl BatchedAsrInner {
                 }
             },
         };
+        let mut tuning_rx = self.tuning.clone();
         let mut state = moshi::asr::State::new(
             batch_size,
             self.asr_delay_in_tokens,
-            self.temperature,
+            tuning_rx.borrow_and_update().temperature,
             self.audio_tokenizer.clone(),
             self.lm.clone(),
         )?;
@@ -310,15 +998,22 @@ impl BatchedAsrInner {
 
         let mut new_markers = Vec::new();
         let mut resets = Vec::new();
+        let mut gate = StepGate::new(asr_inner.step_scheduling, asr_inner.step_max_wait);
 
         let _encoder_handle = crate::utils::spawn_blocking("encoder_loop", move || {
             let mut step_idx = 0;
             let mut batch_pcm_vec = vec![0f32; FRAME_SIZE * batch_size];
+            let mut batch_codes = vec![None; batch_size];
             let mut channel_ids = vec![None; batch_size];
             let mut mask = vec![false; batch_size];
             loop {
-                new_markers.clear();
-                resets.clear();
+                if !gate.holding() {
+                    new_markers.clear();
+                    resets.clear();
+                    mask.fill(false);
+                    batch_codes.fill(None);
+                    channel_ids.fill(None);
+                }
 
                 #[cfg(feature = "cuda")]
                 let batch_pcm: &mut [f32] = if let Some(p) = pinned_batch_pcm.as_mut() {
@@ -336,19 +1031,30 @@ impl BatchedAsrInner {
                     &mut batch_pcm_vec
                 };
 
-                asr_inner_encoder.pre_process_pipelined(
+                let pre_process = tracing::span!(tracing::Level::TRACE, "pre-process").entered();
+                let pending = asr_inner_encoder.pre_process_pipelined(
                     asr_delay_in_tokens,
                     step_idx,
                     &mut new_markers,
                     &mut resets,
                     batch_pcm,
+                    &mut batch_codes,
                     &mut mask,
                     &mut channel_ids,
                 );
+                pre_process.exit();
 
-                let with_data = mask.iter().any(|&v| v);
+                let filled = mask.iter().filter(|&&v| v).count();
+                if !gate.ready(filled, pending, Instant::now()) {
+                    std::thread::sleep(std::time::Duration::from_millis(2));
+                    continue;
+                }
+                let with_data = filled > 0;
                 if with_data || !resets.is_empty() || !new_markers.is_empty() {
-                    let mask_obj = moshi::StreamMask::new(mask.clone(), &dev_encoder)?;
+                    // Slots sent mimi codes skip the encoder, its state only moves with the pcm.
+                    let pcm_mask: Vec<bool> =
+                        mask.iter().zip(batch_codes.iter()).map(|(&m, c)| m && c.is_none()).collect();
+                    let mask_obj = moshi::StreamMask::new(pcm_mask.clone(), &dev_encoder)?;
                     let pcm = {
                         #[cfg(feature = "cuda")]
                         {
@@ -360,11 +1066,18 @@ impl BatchedAsrInner {
                         }
                     }?;
                     let audio_tokens = mimi_tokenizer.encode_step(&pcm.into(), &mask_obj)?;
-                    if let Some(audio_tokens) = audio_tokens.into_option() {
+                    let audio_tokens = with_codes(
+                        audio_tokens.into_option(),
+                        &pcm_mask,
+                        &batch_codes,
+                        mimi_tokenizer.config().quantizer_n_q,
+                        &dev_encoder,
+                    )?;
+                    if let Some((audio_tokens, mask)) = audio_tokens {
                         if pipeline_tx
                             .send(PipelineMsg {
                                 audio_tokens,
-                                mask: mask_obj,
+                                mask: moshi::StreamMask::new(mask, &dev_encoder)?,
                                 channel_ids: channel_ids.clone(),
                                 new_markers: new_markers.clone(),
                                 resets: resets.clone(),
@@ -415,6 +1128,7 @@ impl BatchedAsrInner {
             let mut markers = BinaryHeap::new();
             for msg in post_rx {
                 let PostProcessMsg { asr_msgs, step_idx, new_markers, mask, channel_ids } = msg;
+                let _span = tracing::span!(tracing::Level::TRACE, "post-process").entered();
                 for m in new_markers {
                     markers.push(m);
                 }
@@ -429,6 +1143,7 @@ impl BatchedAsrInner {
             Ok(())
         });
 
+        let path = asr_inner.path.clone();
         crate::utils::spawn_blocking("model_loop", move || {
             if warmup_enabled {
                 let start = Instant::now();
@@ -446,6 +1161,13 @@ impl BatchedAsrInner {
                         tracing::error!(duration_ms = (elapsed * 1000.0), ?err, "warmup failed");
                     }
                 }
+                let (module, duration_s, ok) = ("batched_asr", elapsed, res.is_ok());
+                crate::events::emit(crate::events::Event::WarmupDone {
+                    module,
+                    path,
+                    duration_s,
+                    ok,
+                });
                 res?;
             } else {
                 tracing::info!("skipping warmup (disabled)");
@@ -470,8 +1192,16 @@ impl BatchedAsrInner {
                     }
                 }
 
+                if tuning_rx.has_changed().unwrap_or(false) {
+                    tuning_rx.borrow_and_update().apply(&mut state);
+                }
                 if has_data {
                     let mask_obj = mask;
+                    let slots = channel_ids.iter().flatten().count();
+                    let _step_span =
+                        tracing::info_span!("batched_asr_step", step_idx, slots).entered();
+                    let step =
+                        tracing::span!(tracing::Level::TRACE, crate::profiler::STEP).entered();
                     let start_time = std::time::Instant::now();
                     let asr_msgs = state.step_tokens(
                         &audio_tokens,
@@ -488,6 +1218,7 @@ impl BatchedAsrInner {
                         },
                     )?;
                     let elapsed = start_time.elapsed().as_secs_f64();
+                    step.exit();
                     metrics::MODEL_STEP_DURATION.observe(elapsed);
                     tracing::info!(step_idx, "{:.2}ms", elapsed * 1000.);
                     step_idx += 1;
@@ -523,6 +1254,9 @@ impl BatchedAsrInner {
         Ok(())
     }
 
+    /// Read the audio of the slots that do not have a frame in `mask` yet, returning how
+    /// many attached slots are still without one. The frames of the slots fed with mimi codes
+    /// go to `batch_codes` instead of `batch_pcm`.
     #[allow(clippy::too_many_arguments)]
     fn pre_process_pipelined(
         &self,
@@ -531,50 +1265,84 @@ impl BatchedAsrInner {
         new_markers: &mut Vec<Marker>,
         resets: &mut Vec<usize>,
         batch_pcm: &mut [f32],
+        batch_codes: &mut [Option<Vec<u32>>],
         mask: &mut [bool],
         channel_ids: &mut [Option<ChannelId>],
-    ) {
+    ) -> usize {
         use rayon::prelude::*;
 
-        mask.fill(false);
-        channel_ids.fill(None);
-
         let active_indices: Vec<usize> = {
             let guard = self.active_indices.lock().unwrap();
             guard.iter().copied().collect()
         };
 
         if active_indices.is_empty() {
-            return;
+            return 0;
         }
 
-
         let active_set: std::collections::HashSet<usize> = active_indices.iter().copied().collect();
 
-        let todo: Vec<(usize, bool, Option<ChannelId>, Vec<PipelineEvent>)> = batch_pcm
+        let filled: &[bool] = mask;
+        let (n_q, bins) = {
+            let cfg = self.audio_tokenizer.config();
+            (cfg.quantizer_n_q, cfg.quantizer_bins as u32)
+        };
+        // Slot, whether it has a frame, whether it waits for one, channel, events, codes frame.
+        type Todo = (usize, bool, bool, Option<ChannelId>, Vec<PipelineEvent>, Option<Vec<u32>>);
+        let todo: Vec<Todo> = batch_pcm
             .par_chunks_mut(FRAME_SIZE)
             .enumerate()
-            .filter(|(bid, _)| active_set.contains(bid))
+            .filter(|(bid, _)| active_set.contains(bid) && !filled[*bid])
             .map(|(bid, out_pcm)| {
                 out_pcm.fill(0.0);
                 let mut guard = self.channels[bid].lock().unwrap();
                 let channel = &mut *guard;
                 let c = match channel.as_mut() {
                     Some(c) => c,
-                    None => return (bid, false, None, vec![]),
+                    None => return (bid, false, false, None, vec![], None),
                 };
 
-                if c.out_tx.is_closed() {
-                    return (bid, false, Some(c.id), vec![PipelineEvent::Reset(usize::MAX)]);
+                if c.errors.tripped().is_some() {
+                    return (bid, false, false, Some(c.id), vec![], None);
+                }
+                if c.is_detached() {
+                    // Keep processing the audio already received, the words end up in the
+                    // backlog until the client reattaches or the grace period expires.
+                    if c.detached_at.is_none() {
+                        tracing::info!(bid, stream_id = ?c.stream_id, "stream detached, keeping slot");
+                        c.detached_at = Some(Instant::now());
+                    }
+                } else if c.out_tx.is_closed() {
+                    let events = vec![PipelineEvent::Reset(usize::MAX)];
+                    return (bid, false, false, Some(c.id), events, None);
                 }
 
                 let mut events = Vec::new();
                 let mut mask_val = false;
+                let mut codes = None;
+                if let Some(s) = c.segments.as_mut() {
+                    let buffered = c.data.len();
+                    let boundary = s.pre_step(c.steps, &mut c.data);
+                    // The flush silence goes ahead of the codes too.
+                    for (ahead, _) in c.codes.iter_mut() {
+                        *ahead += c.data.len() - buffered;
+                    }
+                    if boundary {
+                        tracing::info!(bid, steps = c.steps, "asr segment boundary");
+                        events.push(PipelineEvent::Reset(bid));
+                        events.push(PipelineEvent::Marker(Marker {
+                            channel_id: c.id,
+                            batch_idx: bid,
+                            step_idx,
+                            kind: MarkerKind::Segment,
+                        }));
+                    }
+                }
                 use std::sync::mpsc::TryRecvError;
                 loop {
                     match c.in_rx.try_recv() {
                         Ok(InMsg::Init) => {
-                            if c.out_tx.send(OutMsg::Ready).is_err() {
+                            if c.out_tx.send(OutMsg::ready(&c.trace)).is_err() {
                                 events.push(PipelineEvent::Reset(usize::MAX));
                                 break;
                             }
@@ -582,29 +1350,101 @@ impl BatchedAsrInner {
                         }
                         Ok(InMsg::Marker { id }) => {
                             tracing::info!(bid, id, "received marker");
-                            let current_data = c.data.len() / FRAME_SIZE;
+                            let current_data = c.buffered_pcm() / FRAME_SIZE;
                             let marker_step_idx = step_idx + asr_delay_in_tokens + current_data;
                             events.push(PipelineEvent::Marker(Marker {
                                 channel_id: c.id,
                                 batch_idx: bid,
                                 step_idx: marker_step_idx,
-                                marker_id: id,
+                                kind: MarkerKind::Client { id, sample_idx: c.context.end_sample() },
                             }));
                         }
                         Ok(InMsg::OggOpus { .. }) => {
                             tracing::warn!("OggOpus message received in pre-process, should have been decoded in handle_socket");
                         }
+                        Ok(InMsg::Checkpoint) => {
+                            let msg = c.checkpoint(&self.model_id);
+                            if matches!(msg, OutMsg::Checkpoint { .. }) {
+                                metrics::CHECKPOINT_EXPORTED.inc();
+                            }
+                            let id = c.id;
+                            let _ = c.send(msg, Some(id));
+                        }
+                        Ok(InMsg::Restore { data }) => {
+                            let id = c.id;
+                            match c.restore(&data, &self.model_id, n_q, bins) {
+                                Ok(()) => {
+                                    tracing::info!(bid, offset = c.time_offset, "restored asr checkpoint");
+                                    metrics::CHECKPOINT_RESTORED.inc();
+                                }
+                                Err(err) => {
+                                    let message = format!("restore failed: {err}");
+                                    let _ = c.send(OutMsg::Error { message }, Some(id));
+                                }
+                            }
+                        }
+                        Ok(InMsg::Enroll { pcm, other }) => {
+                            let now_s = c.context.end_s();
+                            let max_distance = self.speaker_max_distance;
+                            let msg = match SpeakerFilter::enroll(&pcm, other, max_distance, now_s) {
+                                Ok(speaker) => {
+                                    let voiced_s = speaker.voiced_s();
+                                    tracing::info!(bid, voiced_s, "enrolled speaker");
+                                    c.speaker = Some(speaker);
+                                    OutMsg::Enrolled { voiced_s }
+                                }
+                                Err(err) => {
+                                    let message = format!("enroll failed: {err}");
+                                    OutMsg::Error { message }
+                                }
+                            };
+                            let id = c.id;
+                            let _ = c.send(msg, Some(id));
+                        }
                         Ok(InMsg::Audio { pcm }) => {
-                            if c.extend_data(&pcm, out_pcm) {
+                            if let Some(speaker) = c.speaker.as_mut() {
+                                speaker.push(&pcm);
+                            }
+                            let mut stats =
+                                c.stats.as_mut().map(|s| s.push(&pcm)).unwrap_or_default();
+                            stats.extend(c.speakers.as_mut().and_then(|s| s.push(&pcm)));
+                            let id = c.id;
+                            for msg in stats {
+                                let _ = c.send(msg, Some(id));
+                            }
+                            if c.push_audio(&pcm, out_pcm) {
                                 c.steps += 1;
                                 mask_val = true;
                             }
                         }
+                        Ok(InMsg::AudioCodes { codes }) => match check_codes(&codes, n_q, bins) {
+                            Ok(()) => c.push_codes(codes),
+                            Err(message) => {
+                                let id = c.id;
+                                let _ = c.send(OutMsg::Error { message }, Some(id));
+                            }
+                        },
+                        Ok(InMsg::TimeCorrection { delta_s }) => {
+                            c.drift.shift(c.context.end_s(), delta_s);
+                        }
                         Ok(InMsg::Ping) => {}
+                        Ok(
+                            InMsg::SetSampleRate { .. }
+                            | InMsg::RefreshToken { .. }
+                            | InMsg::Echo { .. },
+                        ) => {
+                            tracing::warn!("control message received in pre-process, should have been handled in handle_socket");
+                        }
                         Err(TryRecvError::Empty) => {
                             if c.extend_data(&[], out_pcm) {
                                 c.steps += 1;
                                 mask_val = true;
+                            } else if !mask_val {
+                                codes = c.pop_due_codes();
+                                if codes.is_some() {
+                                    c.steps += 1;
+                                    mask_val = true;
+                                }
                             }
                             break;
                         }
@@ -614,13 +1454,17 @@ impl BatchedAsrInner {
                         }
                     }
                 }
-                (bid, mask_val, Some(c.id), events)
+                let waiting = !mask_val && !c.is_detached();
+                (bid, mask_val, waiting, Some(c.id), events, codes)
             })
             .collect();
 
-        for (bid, mask_val, cid, events) in todo {
+        let mut pending = 0;
+        for (bid, mask_val, waiting, cid, events, codes) in todo {
+            pending += waiting as usize;
             channel_ids[bid] = cid;
             mask[bid] = mask_val;
+            batch_codes[bid] = codes;
             for event in events {
                 match event {
                     PipelineEvent::Reset(usize::MAX) => {}
@@ -633,23 +1477,47 @@ impl BatchedAsrInner {
         // Clean up closed channels
         let mut active_guard = self.active_indices.lock().unwrap();
         let mut free_guard = self.free_indices.lock().unwrap();
+        let free_before = free_guard.len();
+        let mut quarantine = self.quarantine.lock().unwrap();
+        for bid in quarantine.release(Instant::now()) {
+            tracing::info!(bid, "asr slot back from quarantine");
+            free_guard.push_back(bid);
+        }
 
         let mut i = 0;
         while i < active_guard.len() {
             let bid = active_guard[i];
             let mut guard = self.channels[bid].lock().unwrap();
             let should_remove = match guard.as_ref() {
-                Some(c) => c.out_tx.is_closed(),
+                Some(c) if c.errors.tripped().is_some() => {
+                    quarantine.add(bid, c.errors.tripped().unwrap_or_else(Instant::now));
+                    true
+                }
+                Some(c) => {
+                    let expired = c.is_expired(self.stream_grace);
+                    if expired && c.stream_id.is_some() {
+                        tracing::info!(bid, stream_id = ?c.stream_id, "stream grace period expired");
+                        metrics::STREAM_EXPIRED.inc();
+                    }
+                    expired
+                }
                 None => true,
             };
             if should_remove {
                 *guard = None;
                 active_guard.remove(i);
-                free_guard.push_back(bid);
+                if !quarantine.contains(bid) {
+                    free_guard.push_back(bid);
+                }
             } else {
                 i += 1;
             }
         }
+        metrics::SLOTS_QUARANTINED.set(quarantine.len() as f64);
+        if free_guard.len() != free_before {
+            capacity_event(&self.path, self.slots.load(Ordering::Relaxed), free_guard.len());
+        }
+        pending
     }
 
     fn post_process(
@@ -662,13 +1530,31 @@ impl BatchedAsrInner {
     ) -> Result<()> {
         for asr_msg in asr_msgs.into_iter() {
             match asr_msg {
-                moshi::asr::AsrMsg::Word { tokens, start_time, batch_idx } => {
-                    let msg = OutMsg::Word {
-                        text: self.text_tokenizer.decode_piece_ids(&tokens)?,
-                        start_time,
-                    };
+                moshi::asr::AsrMsg::Word { tokens, token_times, start_time, batch_idx } => {
                     let mut channel = self.channels[batch_idx].lock().unwrap();
-                    if let Some(c) = channel.as_ref() {
+                    if let Some(c) = channel.as_mut() {
+                        let text = match self.text_tokenizer.decode_piece_ids(&tokens) {
+                            Ok(text) => text,
+                            Err(err) => {
+                                c.inference_error(&self.breaker, batch_idx, &err);
+                                continue;
+                            }
+                        };
+                        let lang = c.lang.as_mut().map(|t| t.tag(&text).to_string());
+                        let tokens = match c.detail {
+                            Detail::Words => None,
+                            Detail::Tokens => {
+                                let tokenizer = &self.text_tokenizer;
+                                match word_tokens(tokenizer, &tokens, &token_times, start_time) {
+                                    Ok(tokens) => Some(tokens),
+                                    Err(err) => {
+                                        c.inference_error(&self.breaker, batch_idx, &err);
+                                        continue;
+                                    }
+                                }
+                            }
+                        };
+                        let msg = OutMsg::Word { text, start_time, lang, tokens };
                         if c.send(msg, ref_channel_ids[batch_idx]).is_err() {
                             *channel = None;
                         }
@@ -677,12 +1563,15 @@ impl BatchedAsrInner {
                 moshi::asr::AsrMsg::EndWord { stop_time, batch_idx } => {
                     let msg = OutMsg::EndWord { stop_time };
                     let mut channel = self.channels[batch_idx].lock().unwrap();
-                    if let Some(c) = channel.as_ref() {
+                    if let Some(c) = channel.as_mut() {
                         if c.send(msg, ref_channel_ids[batch_idx]).is_err() {
                             *channel = None;
                         }
                     }
                 }
+                moshi::asr::AsrMsg::NonFinite { step_idx, batch_idx, recovered } => {
+                    nonfinite_step(step_idx, batch_idx, recovered)
+                }
                 moshi::asr::AsrMsg::Step { step_idx, prs } => {
                     for (batch_idx, channel_mutex) in self.channels.iter().enumerate() {
                         if !mask.is_active(batch_idx) {
@@ -690,8 +1579,18 @@ impl BatchedAsrInner {
                         }
                         let mut channel = channel_mutex.lock().unwrap();
                         if let Some(ch) = channel.as_mut() {
-                            let prs = prs.iter().map(|p| p[batch_idx]).collect();
-                            let msg = OutMsg::Step { step_idx, prs, buffered_pcm: ch.data.len() };
+                            let prs: Vec<f32> = prs.iter().map(|p| p[batch_idx]).collect();
+                            if prs.iter().any(|p| !p.is_finite()) {
+                                let err = format!("non-finite probabilities at step {step_idx}");
+                                ch.inference_error(&self.breaker, batch_idx, &err);
+                                continue;
+                            }
+                            let msg = OutMsg::Step {
+                                step_idx,
+                                prs,
+                                buffered_pcm: ch.buffered_pcm(),
+                                time_correction_ms: 0.0,
+                            };
                             if ch.send(msg, ref_channel_ids[batch_idx]).is_err() {
                                 *channel = None;
                             }
@@ -703,9 +1602,22 @@ impl BatchedAsrInner {
         while let Some(m) = markers.peek() {
             if m.step_idx <= step_idx {
                 let mut channel = self.channels[m.batch_idx].lock().unwrap();
-                if let Some(c) = channel.as_ref() {
-                    if c.send(OutMsg::Marker { id: m.marker_id }, Some(m.channel_id)).is_err() {
-                        *channel = None;
+                if let Some(c) = channel.as_mut() {
+                    let msg = match m.kind {
+                        MarkerKind::Client { id, sample_idx } => {
+                            // A failed send also fails for the marker, which drops the slot.
+                            if let Some(estimate) = c.speakers.as_ref().map(|s| s.estimate()) {
+                                let _ = c.send(estimate, Some(m.channel_id));
+                            }
+                            Some(OutMsg::Marker { id, sample_idx, step_idx })
+                        }
+                        MarkerKind::Segment if c.id == m.channel_id => c.start_segment(),
+                        MarkerKind::Segment => None,
+                    };
+                    if let Some(msg) = msg {
+                        if c.send(msg, Some(m.channel_id)).is_err() {
+                            *channel = None;
+                        }
                     }
                 }
                 markers.pop();
@@ -719,16 +1631,90 @@ impl BatchedAsrInner {
 
 type Channels = Arc<Vec<Mutex<Option<Channel>>>>;
 
+/// Client provided names restricted to characters that are safe in a file name.
+fn file_name(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+fn model_id(lm_model_file: &str) -> String {
+    std::path::Path::new(lm_model_file)
+        .file_name()
+        .map_or_else(|| lm_model_file.to_string(), |f| f.to_string_lossy().into_owned())
+}
+
+/// Codes sent by a client have to come from the same mimi as the model, an index out of the
+/// codebooks would fail the step of the whole batch.
+fn check_codes(codes: &[Vec<u32>], n_q: usize, bins: u32) -> std::result::Result<(), String> {
+    for (idx, frame) in codes.iter().enumerate() {
+        if frame.len() != n_q {
+            return Err(format!("codes frame {idx} has {} codebooks, expected {n_q}", frame.len()));
+        }
+        if let Some(code) = frame.iter().find(|&&c| c >= bins) {
+            return Err(format!("code {code} of frame {idx} is out of the {bins} bins"));
+        }
+    }
+    Ok(())
+}
+
+/// Audio tokens of a step and the slots they hold, the tokens encoded by mimi from the pcm of
+/// `pcm_mask` merged with the frames of codes sent by the clients.
+fn with_codes(
+    encoded: Option<Tensor>,
+    pcm_mask: &[bool],
+    codes: &[Option<Vec<u32>>],
+    n_q: usize,
+    dev: &Device,
+) -> Result<Option<(Tensor, Vec<bool>)>> {
+    if codes.iter().all(Option::is_none) {
+        return Ok(encoded.map(|tokens| (tokens, pcm_mask.to_vec())));
+    }
+    let batch_size = codes.len();
+    let mut flat = vec![0u32; batch_size * n_q];
+    for (dst, frame) in flat.chunks_mut(n_q).zip(codes.iter()) {
+        if let Some(frame) = frame {
+            dst.copy_from_slice(frame);
+        }
+    }
+    let code_tokens = Tensor::from_vec(flat, (batch_size, n_q, 1), dev)?;
+    let code_mask: Vec<bool> = codes.iter().map(Option::is_some).collect();
+    match encoded {
+        None => Ok(Some((code_tokens, code_mask))),
+        Some(tokens) => {
+            let select = code_mask.iter().map(|&m| m as u8).collect::<Vec<_>>();
+            let select = Tensor::from_vec(select, (batch_size, 1, 1), dev)?;
+            let select = select.broadcast_as(tokens.shape())?;
+            let tokens = select.where_cond(&code_tokens, &tokens)?;
+            let mask = pcm_mask.iter().zip(code_mask.iter()).map(|(&p, &c)| p || c).collect();
+            Ok(Some((tokens, mask)))
+        }
+    }
+}
+
 pub struct BatchedAsr {
     channels: Channels,
     active_indices: Arc<Mutex<VecDeque<usize>>>,
     free_indices: Arc<Mutex<VecDeque<usize>>>,
     config: crate::AsrConfig,
     batch_size: usize,
+    slots: Arc<AtomicUsize>,
+    stream_grace: Duration,
+    asr_delay_in_tokens: usize,
+    tuning: tokio::sync::watch::Sender<Tuning>,
+    poll: Arc<crate::long_poll::PollSessions>,
+    shadow: Option<Arc<crate::shadow::Shadow>>,
+    path: String,
+}
+
+fn capacity_event(path: &str, total_slots: usize, free_slots: usize) {
+    let path = path.to_string();
+    crate::events::emit(crate::events::Event::Capacity { path, total_slots, free_slots });
 }
 
 impl BatchedAsr {
     pub fn new(
+        path: &str,
         batch_size: usize,
         asr: &crate::AsrConfig,
         config: &crate::Config,
@@ -736,8 +1722,12 @@ impl BatchedAsr {
         warmup_enabled: bool,
     ) -> Result<Self> {
         let dtype = crate::utils::model_dtype(asr.dtype_override.as_deref(), dev)?;
-        let vb_lm =
-            unsafe { VarBuilder::from_mmaped_safetensors(&[&asr.lm_model_file], dtype, dev)? };
+        let vb_lm = crate::snapshot::var_builder(
+            &asr.lm_model_file,
+            dtype,
+            dev,
+            config.snapshots.as_ref(),
+        )?;
         let lm = moshi::lm::LmModel::batched(
             batch_size,
             &asr.model,
@@ -762,22 +1752,42 @@ impl BatchedAsr {
         let channels = Arc::new(channels);
         let free_indices = Arc::new(Mutex::new((0..batch_size).collect::<VecDeque<_>>()));
         let active_indices = Arc::new(Mutex::new(VecDeque::with_capacity(batch_size)));
+        let slots = Arc::new(AtomicUsize::new(batch_size));
 
         let asr_delay_in_tokens =
             asr.conditioning_delay.map_or(asr.asr_delay_in_tokens, |v| (v * 12.5) as usize + 1);
+        let stream_grace = Duration::from_secs_f64(asr.stream_grace_period_s.max(0.0));
+        if let Some(dir) = asr.segment_dir.as_ref() {
+            std::fs::create_dir_all(dir).with_context(|| dir.clone())?;
+        }
+        let tuning = tokio::sync::watch::Sender::new(Tuning {
+            asr_delay: asr_delay_in_tokens,
+            temperature: asr.temperature.unwrap_or(0.0),
+        });
         let batched_asr = BatchedAsrInner {
+            path: path.to_string(),
             asr_delay_in_tokens,
-            temperature: asr.temperature.unwrap_or(0.0),
+            stream_grace,
+            model_id: model_id(&asr.lm_model_file),
+            speaker_max_distance: asr.speaker_max_distance,
+            tuning: tuning.subscribe(),
+            step_scheduling: asr.step_scheduling,
+            step_max_wait: Duration::from_millis(asr.step_max_wait_ms),
             lm,
             audio_tokenizer,
             text_tokenizer: text_tokenizer.into(),
             channels: channels.clone(),
             active_indices: active_indices.clone(),
             free_indices: free_indices.clone(),
+            slots: slots.clone(),
+            breaker: asr.circuit_breaker.clone(),
+            quarantine: Mutex::new(Quarantine::default()),
         };
         let logger = match asr.log_frequency_s {
-            Some(s) => Some(Logger::new(&config.instance_name, &config.log_dir, s)?),
-            None => None,
+            Some(s) if crate::privacy::allow_token_dumps("log_frequency_s") => {
+                Some(Logger::new(&config.instance_name, &config.log_dir, s)?)
+            }
+            _ => None,
         };
         batched_asr.start_model_loop(
             asr.conditioning_delay,
@@ -789,79 +1799,384 @@ impl BatchedAsr {
         if let Some(logger) = logger {
             logger.log_loop()
         }
-        Ok(Self { channels, active_indices, free_indices, config: asr.clone(), batch_size })
+        Ok(Self {
+            channels,
+            active_indices,
+            free_indices,
+            config: asr.clone(),
+            batch_size,
+            slots,
+            stream_grace,
+            asr_delay_in_tokens,
+            tuning,
+            poll: Arc::new(crate::long_poll::PollSessions::new(
+                Duration::from_secs_f64(asr.poll_session_ttl_s.max(0.0)),
+                FRAME_SIZE,
+            )),
+            shadow: crate::shadow::Shadow::new(asr, config, dev)?,
+            path: path.to_string(),
+        })
+    }
+
+    /// Change the settings of the module and tell the sessions of all the slots, detached
+    /// ones get the update with their backlog.
+    pub(crate) fn retune(&self, query: &RetuneQuery) -> Result<Tuning, String> {
+        let tuning = self.tuning.borrow().retune(query, true)?;
+        self.tuning.send_replace(tuning);
+        tracing::info!(?tuning, "retuned batched asr");
+        let Tuning { asr_delay, temperature } = tuning;
+        for channel in self.channels.iter() {
+            let mut guard = channel.lock().unwrap();
+            if let Some(c) = guard.as_mut() {
+                let id = c.id;
+                let _ = c.send(OutMsg::ConfigUpdate { asr_delay, temperature }, Some(id));
+            }
+        }
+        Ok(tuning)
     }
 
-    fn channels(&self) -> Result<Option<(usize, InSend, OutRecv)>> {
+    /// Reattach `user` to the slot holding `stream_id`, taking over any previous connection.
+    /// Fails when the stream was opened by another user.
+    fn resume_stream(
+        &self,
+        stream_id: &str,
+        user: Option<&str>,
+        trace: &crate::otel::TraceIds,
+    ) -> Result<Option<(usize, InSend, OutRecv)>> {
+        for (batch_idx, channel) in self.channels.iter().enumerate() {
+            let mut guard = channel.lock().unwrap();
+            let Some(c) = guard.as_mut() else { continue };
+            if c.stream_id.as_deref() != Some(stream_id) {
+                continue;
+            }
+            let (in_tx, in_rx) = std::sync::mpsc::channel::<InMsg>();
+            let (out_tx, out_rx) = tokio::sync::mpsc::unbounded_channel::<OutMsg>();
+            c.resume(user, in_rx, out_tx, trace.clone())?;
+            return Ok(Some((batch_idx, in_tx, out_rx)));
+        }
+        Ok(None)
+    }
+
+    fn channels(&self, opts: &SlotOptions) -> Result<Option<(usize, InSend, OutRecv)>> {
         let mut free_guard = self.free_indices.lock().unwrap();
         if let Some(batch_idx) = free_guard.pop_front() {
             let mut guard = self.channels[batch_idx].lock().unwrap();
             let (in_tx, in_rx) = std::sync::mpsc::channel::<InMsg>();
             let (out_tx, out_rx) = tokio::sync::mpsc::unbounded_channel::<OutMsg>();
-            let c = Channel::new(in_rx, out_tx)?;
+            let stream_id = opts.stream_id.map(|s| s.to_string());
+            let mut c = Channel::new(in_rx, out_tx, stream_id, self.config.checkpoint_context_s)?;
+            c.owner = opts.user.map(|u| u.to_string());
+            c.lang = self.config.word_lang.then(LangTagger::default);
+            c.stats = opts.stats_interval_s.filter(|&s| s > 0.0).map(SessionStats::new);
+            c.speakers = opts.speakers_interval_s.filter(|&s| s > 0.0).map(SpeakerStats::new);
+            c.formatter = FormatterChain::new(opts.formatting);
+            c.itn = opts.itn.then(Itn::default);
+            c.spotter = (!opts.spot.is_empty()).then(|| PhraseSpotter::new(opts.spot));
+            c.detail = opts.detail;
+            c.preemptible = opts.preemptible;
+            c.trace = opts.trace.clone();
+            let since_epoch = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+            let name = c.stream_id.as_deref().map_or("asr".to_string(), file_name);
+            let session = format!("{name}-{}-{}", since_epoch.as_secs(), c.id.0);
+            c.summary = self.config.summary_path.as_ref().map(|template| {
+                let user = opts.user.map(file_name);
+                SessionSummary::new(session_summary::path(template, user.as_deref(), &session))
+            });
+            if let Some(segment_s) = opts.segment_s.filter(|&s| s > 0.0) {
+                let store = self.config.segment_dir.as_ref().map(|dir| {
+                    std::path::Path::new(dir).join(&session)
+                });
+                // Silence long enough for the words of the last frames to come out.
+                c.segments = Some(Segments::new(segment_s, self.asr_delay_in_tokens + 2, store));
+            }
             *guard = Some(c);
             let mut active_guard = self.active_indices.lock().unwrap();
             active_guard.push_back(batch_idx);
+            capacity_event(&self.path, self.total_slots(), free_guard.len());
             return Ok(Some((batch_idx, in_tx, out_rx)));
         }
         Ok(None)
     }
 
-    pub async fn handle_query(&self, query: axum::body::Bytes) -> Result<Vec<OutMsg>> {
-        tracing::info!("batched-asr post query");
-        let (batch_idx, in_tx, mut out_rx) = {
-            let mut num_tries = 0;
-            loop {
-                match self.channels() {
-                    Ok(Some(x)) => break x,
-                    Ok(None) => {
-                        num_tries += 1;
-                        if num_tries > POST_MAX_RETRIES {
-                            tracing::error!("no free channels after 1000 tries");
-                            anyhow::bail!("no free channels");
-                        }
-                        tokio::time::sleep(POST_RETRY_DELAY).await;
-                    }
-                    Err(err) => {
-                        tracing::error!(?err, "no free channels");
-                        Err(err)?
-                    }
+    /// A slot for an interactive session, taken from a batch query when the server is full.
+    fn interactive_channels(&self, opts: &SlotOptions) -> Result<Option<(usize, InSend, OutRecv)>> {
+        match self.channels(opts)? {
+            None if self.preempt() => self.channels(opts),
+            slot => Ok(slot),
+        }
+    }
+
+    /// Release the slot of a batch query, returns false when no slot can be preempted.
+    /// Dropping the channel closes its output, the query then waits for another slot and
+    /// resumes from its last word.
+    fn preempt(&self) -> bool {
+        if !self.config.preempt_batch_jobs {
+            return false;
+        }
+        // Same lock order as the clean up of the model loop.
+        let mut active_guard = self.active_indices.lock().unwrap();
+        let mut free_guard = self.free_indices.lock().unwrap();
+        let preempted = active_guard.iter().enumerate().find_map(|(i, &bid)| {
+            let mut guard = self.channels[bid].lock().unwrap();
+            guard.as_ref().is_some_and(|c| c.preemptible).then(|| (i, bid, guard.take()))
+        });
+        let Some((i, bid, channel)) = preempted else { return false };
+        active_guard.remove(i);
+        free_guard.push_front(bid);
+        let free_slots = free_guard.len();
+        drop((active_guard, free_guard, channel));
+        capacity_event(&self.path, self.total_slots(), free_slots);
+        tracing::info!(bid, "batch query preempted");
+        metrics::BATCH_PREEMPTED.inc();
+        true
+    }
+
+    /// Allocate a slot for a long-poll session of `owner`, `None` when the server is at
+    /// capacity.
+    pub fn poll_open(&self, owner: crate::long_poll::Owner) -> Result<Option<String>> {
+        let opts = SlotOptions { segment_s: self.config.segment_s, ..Default::default() };
+        let Some((batch_idx, in_tx, out_rx)) = self.interactive_channels(&opts)? else {
+            error_metrics::record_connection_error("capacity", "batched_asr");
+            return Ok(None);
+        };
+        metrics::CONNECT.inc();
+        in_tx.send(InMsg::Init)?;
+        let session_id = self.poll.insert(in_tx, out_rx, owner)?;
+        tracing::info!(batch_idx, "batched-asr long-poll session");
+        Ok(Some(session_id))
+    }
+
+    pub fn poll_sessions(&self) -> &Arc<crate::long_poll::PollSessions> {
+        &self.poll
+    }
+
+    /// Transcribe a whole file in the batch lane: the query only uses idle slots and, when
+    /// preempted by an interactive session, waits for another slot and continues from its
+    /// last word. A query preempted more than [`BATCH_QUERY_MAX_RESUMES`] times fails as busy.
+    pub async fn handle_query(
+        &self,
+        query: axum::body::Bytes,
+        format: crate::remote_audio::AudioFormat,
+    ) -> Result<Vec<OutMsg>> {
+        tracing::info!(?format, "batched-asr post query");
+        let decoded = crate::utils::pcm_decode(query.clone(), format.extension()).and_then(
+            |(pcm, sample_rate)| {
+                anyhow::ensure!(!pcm.is_empty(), "no audio decoded");
+                anyhow::ensure!(sample_rate > 0, "unknown sample rate");
+                Ok((pcm, sample_rate))
+            },
+        );
+        let (pcm, sample_rate) = match decoded {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                let detected = crate::sniff::sniff(&query);
+                tracing::info!(%detected, ?err, "cannot decode the posted audio");
+                if detected.is_supported() {
+                    anyhow::bail!(crate::errors::ApiError::BadAudio(format!("{err:#}")))
                 }
+                anyhow::bail!(crate::errors::ApiError::UnsupportedAudio(detected))
             }
         };
-        tracing::info!(batch_idx, "batched-asr channel");
-        in_tx.send(InMsg::Init)?;
-        let (pcm, sample_rate) = crate::utils::pcm_decode(query)?;
         let pcm = if sample_rate == 24000 {
             pcm
         } else {
             kaudio::resample(&pcm, sample_rate as usize, 24000)?
         };
-        in_tx.send(InMsg::Audio { pcm })?;
-        in_tx.send(InMsg::Marker { id: 0 })?;
-        in_tx.send(InMsg::Audio { pcm: vec![0f32; 240000] })?;
+        let model = model_id(&self.config.lm_model_file);
         let mut msgs = vec![];
-        while let Some(msg) = out_rx.recv().await {
-            match msg {
-                OutMsg::Marker { .. } => break,
-                OutMsg::Error { .. } | OutMsg::Word { .. } | OutMsg::EndWord { .. } => {
-                    msgs.push(msg)
+        let mut resumes = 0;
+        loop {
+            let (batch_idx, in_tx, mut out_rx) = self.batch_channels().await?;
+            tracing::info!(batch_idx, "batched-asr channel");
+            in_tx.send(InMsg::Init)?;
+            let context_s = self.config.checkpoint_context_s;
+            let start = match resume_point(&mut msgs, &pcm, &model, context_s) {
+                None => {
+                    msgs.clear();
+                    0
+                }
+                Some((ckpt, start)) => {
+                    tracing::info!(batch_idx, start_s = ckpt.start_s, "resuming batch query");
+                    in_tx.send(InMsg::Restore { data: ckpt.encode()? })?;
+                    start
+                }
+            };
+            in_tx.send(InMsg::Audio { pcm: pcm[start..].to_vec() })?;
+            in_tx.send(InMsg::Marker { id: 0 })?;
+            in_tx.send(InMsg::Audio { pcm: vec![0f32; 240000] })?;
+            while let Some(msg) = out_rx.recv().await {
+                match msg {
+                    OutMsg::Marker { .. } => return Ok(msgs),
+                    OutMsg::Error { message } if message == crate::breaker::TRIPPED_MESSAGE => {
+                        anyhow::bail!(message)
+                    }
+                    OutMsg::Error { .. } | OutMsg::Word { .. } | OutMsg::EndWord { .. } => {
+                        msgs.push(msg)
+                    }
+                    OutMsg::Ready { .. }
+                    | OutMsg::Step { .. }
+                    | OutMsg::Checkpoint { .. }
+                    | OutMsg::SegmentBoundary { .. }
+                    | OutMsg::AudioStats { .. }
+                    | OutMsg::SpeechRate { .. }
+                    | OutMsg::Overlap { .. }
+                    | OutMsg::SpeakerCountEstimate { .. }
+                    | OutMsg::Enrolled { .. }
+                    | OutMsg::Echo { .. }
+                    | OutMsg::PhraseSpotted { .. }
+                    | OutMsg::ConfigUpdate { .. } => {}
+                }
+            }
+            // The slot was released before the end of the audio, most likely preempted.
+            tracing::info!(batch_idx, resumes, "batch query lost its slot");
+            if resumes >= BATCH_QUERY_MAX_RESUMES {
+                anyhow::bail!(crate::errors::ApiError::ModelBusy(
+                    "the query was preempted by interactive sessions, retry later".to_string()
+                ))
+            }
+            resumes += 1;
+        }
+    }
+
+    /// Wait for a free slot for a batch query.
+    async fn batch_channels(&self) -> Result<(usize, InSend, OutRecv)> {
+        let opts = SlotOptions {
+            segment_s: self.config.segment_s,
+            preemptible: true,
+            ..Default::default()
+        };
+        let mut num_tries = 0;
+        loop {
+            match self.channels(&opts) {
+                Ok(Some(x)) => return Ok(x),
+                Ok(None) => {
+                    num_tries += 1;
+                    if num_tries > POST_MAX_RETRIES {
+                        tracing::error!("no free channels after 1000 tries");
+                        let msg = "no free channels".to_string();
+                        anyhow::bail!(crate::errors::ApiError::ModelBusy(msg));
+                    }
+                    tokio::time::sleep(POST_RETRY_DELAY).await;
+                }
+                Err(err) => {
+                    tracing::error!(?err, "no free channels");
+                    Err(err)?
                 }
-                OutMsg::Ready | OutMsg::Step { .. } => {}
             }
         }
-        Ok(msgs)
     }
 
-    pub async fn handle_socket(&self, socket: ws::WebSocket, query: Query) -> Result<()> {
+    /// Stream `seconds` of synthetic audio in real time through an idle slot and time every
+    /// model step, from sending its frame to receiving the step. Returns `None` when all the
+    /// slots are busy so that the benchmark never delays user traffic.
+    pub async fn bench_latency(
+        &self,
+        module: &str,
+        seconds: f64,
+    ) -> Result<Option<crate::bench::SelfBenchReport>> {
+        let start = Instant::now();
+        let Some((audio_s, samples)) = self.bench_steps(seconds).await? else { return Ok(None) };
+        Ok(Some(crate::bench::SelfBenchReport::new(module, audio_s, start.elapsed(), samples)))
+    }
+
+    /// The seconds of audio sent and the latency of each step of a self-benchmark run, see
+    /// [`Self::bench_latency`].
+    pub async fn bench_steps(&self, seconds: f64) -> Result<Option<(f64, Vec<Duration>)>> {
+        let (batch_idx, in_tx, mut out_rx) = match self.channels(&SlotOptions::default())? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        tracing::info!(batch_idx, seconds, "batched-asr self-benchmark");
+        in_tx.send(InMsg::Init)?;
+        let num_frames = ((seconds * 24000.0) as usize).div_ceil(FRAME_SIZE).max(1);
+        let pcm = crate::bench::synthetic_pcm(num_frames * FRAME_SIZE, 24000);
+        let frame_duration = Duration::from_secs_f64(FRAME_SIZE as f64 / 24000.0);
+        let mut ticker = tokio::time::interval(frame_duration);
+        let mut sent_at = Vec::with_capacity(num_frames);
+        let mut samples = Vec::with_capacity(num_frames);
+        let deadline =
+            tokio::time::Instant::now() + frame_duration * num_frames as u32 + BENCH_DRAIN_TIMEOUT;
+        while samples.len() < num_frames {
+            tokio::select! {
+                _ = ticker.tick(), if sent_at.len() < num_frames => {
+                    let frame = &pcm[sent_at.len() * FRAME_SIZE..(sent_at.len() + 1) * FRAME_SIZE];
+                    sent_at.push(Instant::now());
+                    in_tx.send(InMsg::Audio { pcm: frame.to_vec() })?;
+                }
+                msg = out_rx.recv() => match msg {
+                    Some(OutMsg::Step { .. }) => {
+                        if let Some(t) = sent_at.get(samples.len()) {
+                            samples.push(t.elapsed())
+                        }
+                    }
+                    Some(OutMsg::Error { message }) => anyhow::bail!("self-benchmark failed: {message}"),
+                    Some(_) => {}
+                    None => anyhow::bail!("self-benchmark slot closed"),
+                },
+                _ = tokio::time::sleep_until(deadline) => {
+                    anyhow::bail!("self-benchmark timed out after {}/{num_frames} steps", samples.len())
+                }
+            }
+        }
+        let audio_s = (num_frames * FRAME_SIZE) as f64 / 24000.0;
+        Ok(Some((audio_s, samples)))
+    }
+
+    pub async fn handle_socket(
+        &self,
+        socket: crate::limits::LimitedSocket,
+        query: Query,
+    ) -> Result<()> {
         use futures_util::{SinkExt, StreamExt};
         use serde::Serialize;
 
         tracing::info!(?query, "batched-asr ws query");
         metrics::CONNECT.inc();
 
+        let limits = socket.limits().clone();
+        let closer = socket.closer();
+        let auth = socket.auth();
+        let trace = socket.trace().clone();
+        let user = auth.as_ref().map(|a| a.user_id());
         let (mut sender, receiver) = socket.split();
-        let (batch_idx, in_tx, mut out_rx) = match self.channels()? {
+        // Sticky sessions are disabled with a zero grace period.
+        let stream_id = query.stream_id.as_deref().filter(|_| !self.stream_grace.is_zero());
+        let resumed = match stream_id.map(|id| self.resume_stream(id, user.as_deref(), &trace)) {
+            Some(Err(err)) => {
+                tracing::warn!(stream_id, "refused to resume the stream of another user");
+                let mut msg = vec![];
+                OutMsg::Error { message: err.to_string() }.serialize(
+                    &mut rmp_serde::Serializer::new(&mut msg)
+                        .with_human_readable()
+                        .with_struct_map(),
+                )?;
+                sender.send(ws::Message::binary(msg)).await?;
+                crate::utils::close_with_reason(&mut sender, CloseCode::Forbidden, None).await?;
+                return Err(err);
+            }
+            Some(Ok(resumed)) => resumed,
+            None => None,
+        };
+        let is_resumed = resumed.is_some();
+        let slot = match resumed {
+            Some(v) => Some(v),
+            None => self.interactive_channels(&SlotOptions {
+                stream_id,
+                user: user.as_deref(),
+                segment_s: query.segment_s.or(self.config.segment_s),
+                stats_interval_s: query.stats_interval_s,
+                speakers_interval_s: query.speakers_interval_s,
+                formatting: query.formatting.unwrap_or_default(),
+                itn: query.itn.unwrap_or_default(),
+                spot: query.spot.as_deref().unwrap_or_default(),
+                preemptible: false,
+                detail: query.detail.unwrap_or_default(),
+                trace,
+            })?,
+        };
+        let (batch_idx, in_tx, mut out_rx) = match slot {
             Some(v) => v,
             None => {
                 tracing::error!(
@@ -889,11 +2204,27 @@ impl BatchedAsr {
                 anyhow::bail!("no free channels")
             }
         };
-        tracing::info!(batch_idx, "batched-asr channel");
-        in_tx.send(InMsg::Init)?;
+        if is_resumed {
+            tracing::info!(batch_idx, stream_id, "batched-asr stream resumed");
+            metrics::STREAM_RESUMED.inc();
+        } else {
+            tracing::info!(batch_idx, "batched-asr channel");
+            in_tx.send(InMsg::Init)?;
+        }
         let mut decoder = kaudio::ogg_opus::Decoder::new(24000, FRAME_SIZE)?;
-
-        crate::utils::spawn("recv_loop", async move {
+        let mut resampler = crate::resample::InputResampler::default();
+        let mut applied_s = 0.0;
+        // Echo answers skip the channel so that they are not delayed by the batch steps.
+        let (echo_tx, mut echo_rx) = tokio::sync::mpsc::unbounded_channel::<OutMsg>();
+        // A resumed stream already sent audio that the shadow would miss.
+        let itn = query.itn.unwrap_or_default();
+        let shadow = self.shadow.as_ref().filter(|_| !is_resumed).and_then(|s| s.session(itn));
+        let shadow_words = shadow.clone();
+
+        // The slot is released once the send loop drops `out_rx`, so both loops stop as soon
+        // as one of them ends rather than when the next ping fails.
+        let mut scope = crate::task_scope::TaskScope::new();
+        scope.spawn("recv_loop", async move {
             let mut receiver = receiver;
             // There are two timeouts here:
             // - The short timeout handles the case where the client does not answer the regular pings.
@@ -924,7 +2255,8 @@ impl BatchedAsr {
                     Message::Close(_) => break,
                 };
                 last_message_received = std::time::Instant::now();
-                let msg: InMsg = match rmp_serde::from_slice(&msg) {
+                let recv_ms = crate::utils::unix_ms();
+                let msg = match crate::protocol::decode_in_msg(&msg) {
                     Ok(m) => m,
                     Err(e) => {
                         tracing::warn!(
@@ -941,60 +2273,492 @@ impl BatchedAsr {
                     InMsg::OggOpus { data } => {
                         match decoder.decode(&data) {
                             Ok(Some(pcm)) => {
+                                if let Some(shadow) = shadow.as_ref() {
+                                    shadow.pcm(pcm)
+                                }
                                 in_tx.send(InMsg::Audio { pcm: pcm.to_vec() })?;
                             }
                             Ok(None) => {}
                             Err(err) => tracing::error!(?err, "oggopus decoding error"),
                         }
                     }
+                    InMsg::Audio { pcm } => match limits.check_pcm(pcm.len()) {
+                        Ok(()) => {
+                            let pcm = resampler.process(pcm)?;
+                            if let Some(shadow) = shadow.as_ref() {
+                                shadow.pcm(&pcm)
+                            }
+                            in_tx.send(InMsg::Audio { pcm })?
+                        }
+                        Err(err) => closer.close(err),
+                    },
+                    // The codes are 24kHz mimi frames, the sample rate of the pcm does not apply.
+                    InMsg::AudioCodes { codes } => match limits.check_pcm(codes.len() * FRAME_SIZE) {
+                        Ok(()) => {
+                            if let Some(shadow) = shadow.as_ref() {
+                                shadow.lose()
+                            }
+                            in_tx.send(InMsg::AudioCodes { codes })?
+                        }
+                        Err(err) => closer.close(err),
+                    },
+                    InMsg::SetSampleRate { hz } => match resampler.set_rate(hz) {
+                        Ok(tail) => {
+                            tracing::info!(?batch_idx, hz, "client sample rate changed");
+                            if !tail.is_empty() {
+                                if let Some(shadow) = shadow.as_ref() {
+                                    shadow.pcm(&tail)
+                                }
+                                in_tx.send(InMsg::Audio { pcm: tail })?
+                            }
+                        }
+                        Err(err) => tracing::warn!(?batch_idx, ?err, rate = resampler.rate(), "ignoring SetSampleRate"),
+                    },
+                    InMsg::RefreshToken { jwt } => {
+                        crate::auth::SessionAuth::handle_refresh(auth.as_ref(), &jwt)
+                    }
+                    InMsg::Echo { client_ts_ms } => {
+                        let _ = echo_tx.send(OutMsg::echo(client_ts_ms, recv_ms));
+                    }
+                    // The restored state comes from audio that the shadow never got.
+                    m @ InMsg::Restore { .. } => {
+                        if let Some(shadow) = shadow.as_ref() {
+                            shadow.lose()
+                        }
+                        in_tx.send(m)?
+                    }
                     m => in_tx.send(m)?,
                 }
+                // Queued after the audio of the previous rate, the correction applies after it.
+                if resampler.correction_s() != applied_s {
+                    let delta_s = resampler.correction_s() - applied_s;
+                    in_tx.send(InMsg::TimeCorrection { delta_s })?;
+                    applied_s = resampler.correction_s();
+                }
             }
             Ok::<_, anyhow::Error>(())
         });
-        crate::utils::spawn("send_loop", async move {
-            use bytes::BufMut;
-
+        let coalesce = crate::coalesce::interval(query.coalesce_ms);
+        scope.spawn("send_loop", async move {
             let mut chunk_buf = bytes::BytesMut::with_capacity(8 * 1024);
             let mut chunk_buf_spare = bytes::BytesMut::with_capacity(8 * 1024);
             let mut sender = sender;
+            let mut steps = 0;
             loop {
-                // The recv method is cancel-safe so can be wrapped in a timeout.
-                let msg = timeout(SEND_PING_EVERY, out_rx.recv()).await;
+                // The recv methods are cancel-safe so can be wrapped in a timeout.
+                let next = async {
+                    tokio::select! {
+                        msg = out_rx.recv() => msg,
+                        Some(msg) = echo_rx.recv() => Some(msg),
+                    }
+                };
+                let msg = timeout(SEND_PING_EVERY, next).await;
+                let mut tripped = false;
                 let msg = match msg {
                     Ok(None) => break,
                     Err(_) => ws::Message::Ping(vec![].into()),
                     Ok(Some(msg)) => {
-                        chunk_buf.clear();
-                        {
-                            let mut w = (&mut chunk_buf).writer();
-                            msg.serialize(
-                                &mut rmp_serde::Serializer::new(&mut w)
-                                    .with_human_readable()
-                                    .with_struct_map(),
-                            )?;
+                        let mut msgs = vec![msg];
+                        if let Some(interval) = coalesce {
+                            let deadline = tokio::time::Instant::now() + interval;
+                            while msgs.len() < crate::coalesce::MAX_MESSAGES {
+                                let next = async {
+                                    tokio::select! {
+                                        msg = out_rx.recv() => msg,
+                                        Some(msg) = echo_rx.recv() => Some(msg),
+                                    }
+                                };
+                                match tokio::time::timeout_at(deadline, next).await {
+                                    Ok(Some(msg)) => msgs.push(msg),
+                                    // A closed channel ends the loop on the next recv.
+                                    Ok(None) | Err(_) => break,
+                                }
+                            }
                         }
+                        if let Some(shadow) = shadow_words.as_ref() {
+                            for msg in msgs.iter() {
+                                if let OutMsg::Word { text, .. } = msg {
+                                    shadow.word(text)
+                                }
+                            }
+                        }
+                        let new_steps =
+                            msgs.iter().filter(|msg| matches!(msg, OutMsg::Step { .. })).count();
+                        if new_steps > 0 {
+                            steps += new_steps;
+                            crate::otel::record_steps(steps);
+                        }
+                        tripped = msgs.iter().any(|msg| {
+                            matches!(msg, OutMsg::Error { message }
+                                if message == crate::breaker::TRIPPED_MESSAGE)
+                        });
+                        chunk_buf.clear();
+                        crate::coalesce::serialize(&msgs, &mut chunk_buf)?;
                         std::mem::swap(&mut chunk_buf, &mut chunk_buf_spare);
                         let bytes = chunk_buf_spare.split().freeze();
                         ws::Message::Binary(bytes)
                     }
                 };
                 sender.send(msg).await?;
+                if tripped {
+                    let reason = Some(crate::breaker::TRIPPED_MESSAGE);
+                    crate::utils::close_with_reason(&mut sender, CloseCode::InternalError, reason)
+                        .await?;
+                    break;
+                }
             }
             Ok::<(), anyhow::Error>(())
         });
+        scope.run(None, Duration::from_millis(500)).await;
+        tracing::info!(batch_idx, "batched-asr session ended");
         Ok(())
     }
 
-    pub fn config(&self) -> &crate::AsrConfig {
-        &self.config
+    pub fn total_slots(&self) -> usize {
+        self.slots.load(Ordering::Relaxed)
     }
 
-    pub fn total_slots(&self) -> usize {
-        self.batch_size
+    /// Only hand out the first `max` slots to the sessions, for the batch size found by the
+    /// autotune calibration. The model keeps stepping `batch_size` slots until the next start
+    /// loads it with the smaller batch. Call before serving, the slots above `max` must be free.
+    pub fn limit_slots(&self, max: usize) {
+        let max = max.clamp(1, self.batch_size);
+        self.free_indices.lock().unwrap().retain(|&bid| bid < max);
+        self.slots.store(max, Ordering::Relaxed);
+        capacity_event(&self.path, max, self.free_slots());
+    }
+
+    /// File name of the model, checkpoints can only be restored on the same one.
+    pub fn model_id(&self) -> String {
+        model_id(&self.config.lm_model_file)
     }
 
     pub fn used_slots(&self) -> usize {
         self.channels.iter().filter(|v| v.lock().unwrap().is_some()).count()
     }
+
+    /// Slots a new session can take, quarantined slots are neither free nor used.
+    pub fn free_slots(&self) -> usize {
+        self.free_indices.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(stream_id: Option<&str>) -> (Channel, InSend, OutRecv) {
+        let (in_tx, in_rx) = std::sync::mpsc::channel::<InMsg>();
+        let (out_tx, out_rx) = tokio::sync::mpsc::unbounded_channel::<OutMsg>();
+        let c = Channel::new(in_rx, out_tx, stream_id.map(|s| s.to_string()), 10.0).unwrap();
+        (c, in_tx, out_rx)
+    }
+
+    fn word(text: &str) -> OutMsg {
+        OutMsg::Word { text: text.to_string(), start_time: 0.0, lang: None, tokens: None }
+    }
+
+    #[test]
+    fn throughput_gate_waits_for_the_other_slots() {
+        let max_wait = Duration::from_millis(80);
+        let start = Instant::now();
+        let mut latency = StepGate::new(StepScheduling::Latency, max_wait);
+        assert!(latency.ready(1, 3, start));
+        assert!(!latency.holding());
+
+        let mut gate = StepGate::new(StepScheduling::Throughput, max_wait);
+        assert!(gate.ready(0, 4, start));
+        assert!(!gate.ready(1, 3, start));
+        assert!(gate.holding());
+        assert!(!gate.ready(3, 1, start + Duration::from_millis(40)));
+        assert!(gate.ready(4, 0, start + Duration::from_millis(50)));
+        assert!(!gate.holding());
+        assert!(!gate.ready(2, 2, start + Duration::from_millis(60)));
+        assert!(gate.ready(2, 2, start + Duration::from_millis(140)));
+    }
+
+    #[test]
+    fn client_codes_replace_the_encoded_tokens() {
+        assert!(check_codes(&[vec![1, 2], vec![3, 4]], 2, 8).is_ok());
+        assert!(check_codes(&[vec![1, 2], vec![3]], 2, 8).is_err());
+        assert!(check_codes(&[vec![1, 8]], 2, 8).is_err());
+
+        let dev = Device::Cpu;
+        let encoded = Tensor::from_vec(vec![10u32, 11, 20, 21, 30, 31], (3, 2, 1), &dev).unwrap();
+        let codes = vec![None, Some(vec![1, 2]), None];
+        let pcm_mask = [true, false, false];
+        let (tokens, mask) =
+            with_codes(Some(encoded.clone()), &pcm_mask, &codes, 2, &dev).unwrap().unwrap();
+        assert_eq!(tokens.flatten_all().unwrap().to_vec1::<u32>().unwrap(), [10, 11, 1, 2, 30, 31]);
+        assert_eq!(mask, [true, true, false]);
+
+        // Mimi has no tokens yet for the pcm slots, the codes still make a step.
+        let (tokens, mask) = with_codes(None, &pcm_mask, &codes, 2, &dev).unwrap().unwrap();
+        assert_eq!(tokens.dims(), [3, 2, 1]);
+        assert_eq!(mask, [false, true, false]);
+
+        let (tokens, mask) =
+            with_codes(Some(encoded), &pcm_mask, &[None, None, None], 2, &dev).unwrap().unwrap();
+        assert_eq!(tokens.dims(), [3, 2, 1]);
+        assert_eq!(mask, pcm_mask);
+        assert!(with_codes(None, &pcm_mask, &[None, None, None], 2, &dev).unwrap().is_none());
+    }
+
+    #[test]
+    fn plain_channel_expires_on_disconnect() {
+        let (c, _in_tx, out_rx) = channel(None);
+        assert!(!c.is_expired(Duration::from_secs(30)));
+        drop(out_rx);
+        assert!(!c.is_detached());
+        assert!(c.is_expired(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn sticky_channel_keeps_slot_during_grace_period() {
+        let (mut c, _in_tx, out_rx) = channel(Some("call-42"));
+        drop(out_rx);
+        assert!(c.is_detached());
+        c.detached_at = Some(Instant::now());
+        assert!(!c.is_expired(Duration::from_secs(30)));
+        assert!(c.is_expired(Duration::ZERO));
+    }
+
+    #[test]
+    fn stream_is_resumed_by_its_owner_only() {
+        let (mut c, _in_tx, out_rx) = channel(Some("call-42"));
+        c.owner = Some("alice".to_string());
+        drop(out_rx);
+        c.detached_at = Some(Instant::now());
+
+        let trace = crate::otel::TraceIds::default();
+        for user in [Some("bob"), None] {
+            let (_in_tx, in_rx) = std::sync::mpsc::channel::<InMsg>();
+            let (out_tx, mut out_rx) = tokio::sync::mpsc::unbounded_channel::<OutMsg>();
+            assert!(c.resume(user, in_rx, out_tx, trace.clone()).is_err());
+            assert!(c.is_detached());
+            assert!(out_rx.try_recv().is_err());
+        }
+
+        let (_in_tx, in_rx) = std::sync::mpsc::channel::<InMsg>();
+        let (out_tx, mut out_rx) = tokio::sync::mpsc::unbounded_channel::<OutMsg>();
+        c.resume(Some("alice"), in_rx, out_tx, trace).unwrap();
+        assert!(!c.is_detached());
+        assert!(matches!(out_rx.try_recv(), Ok(OutMsg::Ready { .. })));
+    }
+
+    #[test]
+    fn detached_words_are_replayed_on_reattach() {
+        let (mut c, old_in_tx, out_rx) = channel(Some("call-42"));
+        let id = Some(c.id);
+        drop(out_rx);
+        c.send(word("hello"), id).unwrap();
+        c.send(
+            OutMsg::Step { step_idx: 0, prs: vec![], buffered_pcm: 0, time_correction_ms: 0.0 },
+            id,
+        )
+        .unwrap();
+        old_in_tx.send(InMsg::Audio { pcm: vec![0.5; 10] }).unwrap();
+
+        let (_in_tx, in_rx) = std::sync::mpsc::channel::<InMsg>();
+        let (out_tx, mut out_rx) = tokio::sync::mpsc::unbounded_channel::<OutMsg>();
+        let trace = crate::otel::TraceIds { request_id: Some("req-2".to_string()), trace_id: None };
+        c.reattach(in_rx, out_tx, trace);
+        assert!(!c.is_detached());
+        assert_eq!(c.data.len(), 10);
+        assert!(matches!(out_rx.try_recv(),
+            Ok(OutMsg::Ready { request_id: Some(id), trace_id: None }) if id == "req-2"));
+        assert!(matches!(out_rx.try_recv(), Ok(OutMsg::Word { text, .. }) if text == "hello"));
+        assert!(out_rx.try_recv().is_err());
+        c.send(word("again"), id).unwrap();
+        assert!(matches!(out_rx.try_recv(), Ok(OutMsg::Word { .. })));
+    }
+
+    #[test]
+    fn preempted_query_resumes_after_last_complete_word() {
+        let pcm = vec![0.1; 5 * 24000];
+        let mut msgs = vec![OutMsg::Error { message: "x".into() }];
+        assert!(resume_point(&mut msgs, &pcm, "stt", 1.0).is_none());
+
+        let mut msgs = vec![
+            OutMsg::Word { text: "a".into(), start_time: 1.0, lang: None, tokens: None },
+            OutMsg::EndWord { stop_time: 1.4 },
+            OutMsg::Word { text: "b".into(), start_time: 2.0, lang: None, tokens: None },
+        ];
+        let (ckpt, end) = resume_point(&mut msgs, &pcm, "stt", 1.0).unwrap();
+        // The unfinished word is recognized again.
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(end, 18 * FRAME_SIZE);
+        assert_eq!(ckpt.pcm.len(), 12 * FRAME_SIZE);
+        assert!((ckpt.start_s - 6.0 * FRAME_S).abs() < 1e-9);
+        assert_eq!(ckpt.last_word_s, Some(1.0));
+
+        // The checkpoint restores on a fresh slot, which drops the word already sent.
+        let (mut c, _in_tx, mut out_rx) = channel(None);
+        c.restore(&ckpt.encode().unwrap(), "stt", 2, 2048).unwrap();
+        let id = Some(c.id);
+        let word = |text: &str, start_time: f64| {
+            let piece = crate::asr::WordToken { piece: text.to_string(), start_s: start_time };
+            OutMsg::Word { text: text.into(), start_time, lang: None, tokens: Some(vec![piece]) }
+        };
+        c.send(word("a", 0.52), id).unwrap();
+        c.send(OutMsg::EndWord { stop_time: 0.9 }, id).unwrap();
+        c.send(word("b", 1.52), id).unwrap();
+        let Ok(OutMsg::Word { text, start_time, tokens, .. }) = out_rx.try_recv() else { panic!() };
+        assert_eq!(text, "b");
+        assert!((start_time - 2.0).abs() < 1e-9);
+        // The pieces are shifted with their word.
+        assert!((tokens.unwrap()[0].start_s - 2.0).abs() < 1e-9);
+        assert!(out_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn segments_flush_before_reset() {
+        let mut s = Segments::new(10.0, 3, None);
+        let mut data = VecDeque::from(vec![0.5; 100]);
+        assert!((0..125).all(|steps| !s.pre_step(steps, &mut data)));
+        assert_eq!(data.len(), 100);
+        // The flush silence is queued ahead of the pending audio.
+        assert!(!s.pre_step(125, &mut data));
+        assert_eq!(data.len(), 3 * FRAME_SIZE + 100);
+        assert_eq!(data[0], 0.0);
+        assert_eq!(data[3 * FRAME_SIZE], 0.5);
+        assert!(!s.pre_step(127, &mut data));
+        assert!(s.pre_step(128, &mut data));
+        assert_eq!(s.pending, [10.0]);
+        // The next segment counts its audio from the reset.
+        assert!(!s.pre_step(252, &mut data));
+        assert!(!s.pre_step(253, &mut data));
+        assert!(s.pre_step(256, &mut data));
+        assert_eq!(s.pending, [10.0, 20.0]);
+    }
+
+    #[test]
+    fn segment_boundaries_shift_timestamps_and_store_words() {
+        let dir = std::env::temp_dir().join(format!("asr-segments-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (mut c, _in_tx, mut out_rx) = channel(None);
+        let id = Some(c.id);
+        c.segments = Some(Segments::new(10.0, 3, Some(dir.join("s"))));
+        c.send(OutMsg::Word { text: "one".into(), start_time: 9.5, lang: None, tokens: None }, id)
+            .unwrap();
+        c.send(OutMsg::EndWord { stop_time: 9.9 }, id).unwrap();
+        // Nothing to announce before the pre-process side resets the model.
+        assert!(c.start_segment().is_none());
+        c.segments.as_mut().unwrap().pending.push_back(10.0);
+        let msg = c.start_segment().unwrap();
+        assert!(matches!(msg, OutMsg::SegmentBoundary { index: 1, start_s } if start_s == 10.0));
+        c.send(msg, id).unwrap();
+        c.send(OutMsg::Word { text: "two".into(), start_time: 0.5, lang: None, tokens: None }, id)
+            .unwrap();
+        let _ = out_rx.try_recv();
+        let _ = out_rx.try_recv();
+        assert!(matches!(out_rx.try_recv(), Ok(OutMsg::SegmentBoundary { index: 1, .. })));
+        assert!(
+            matches!(out_rx.try_recv(), Ok(OutMsg::Word { start_time, .. }) if start_time == 10.5)
+        );
+        drop(c);
+
+        let read = |index: usize| -> serde_json::Value {
+            let path = dir.join(format!("s-{index:05}.json"));
+            let start = Instant::now();
+            while !path.exists() && start.elapsed().as_secs() < 5 {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            std::thread::sleep(Duration::from_millis(10));
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+        };
+        let first = read(0);
+        assert_eq!(first["start_s"], 0.0);
+        assert_eq!(first["words"][0]["text"], "one");
+        assert_eq!(first["words"][0]["stop_s"], 9.9);
+        let second = read(1);
+        assert_eq!(second["start_s"], 10.0);
+        assert_eq!(second["words"][0]["start_s"], 10.5);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn mixed_pcm_and_codes_keep_their_order() {
+        let (mut c, _in_tx, _out_rx) = channel(None);
+        let mut out_pcm = vec![0f32; FRAME_SIZE];
+        // A frame and a half of pcm, two frames of codes, then another frame of pcm.
+        assert!(c.push_audio(&[0.5; FRAME_SIZE + 960], &mut out_pcm));
+        c.push_codes(vec![vec![1, 2], vec![3, 4]]);
+        assert!(!c.push_audio(&[-0.5; FRAME_SIZE], &mut out_pcm));
+        assert_eq!(c.buffered_pcm(), 4 * FRAME_SIZE);
+        let next = |c: &mut Channel, out_pcm: &mut [f32]| {
+            if c.extend_data(&[], out_pcm) {
+                return Some(out_pcm[0]);
+            }
+            c.pop_due_codes().map(|frame| -(frame[0] as f32))
+        };
+        // The half frame is completed with silence and goes before the codes.
+        assert_eq!(next(&mut c, &mut out_pcm), Some(0.5));
+        assert_eq!(out_pcm[FRAME_SIZE - 1], 0.0);
+        assert_eq!(next(&mut c, &mut out_pcm), Some(-1.0));
+        assert_eq!(next(&mut c, &mut out_pcm), Some(-3.0));
+        assert_eq!(next(&mut c, &mut out_pcm), Some(-0.5));
+        assert_eq!(next(&mut c, &mut out_pcm), None);
+
+        // The checkpoint holds the codes and replays the frames in the same order.
+        let OutMsg::Checkpoint { data } = c.checkpoint("stt") else { panic!() };
+        let (mut restored, _in_tx, _out_rx) = channel(None);
+        assert!(restored.restore(&data, "stt", 3, 2048).is_err());
+        assert!(restored.restore(&data, "stt", 2, 3).is_err());
+        restored.restore(&data, "stt", 2, 2048).unwrap();
+        let frames: Vec<_> = std::iter::from_fn(|| next(&mut restored, &mut out_pcm)).collect();
+        assert_eq!(frames.len(), 5);
+        assert!((frames[0] - 0.5).abs() < 1e-4 && (frames[4] + 0.5).abs() < 1e-4, "{frames:?}");
+        assert_eq!(frames[2..4], [-1.0, -3.0]);
+        assert_eq!(restored.context.end_sample(), 5 * FRAME_SIZE as u64);
+    }
+
+    #[test]
+    fn restored_session_skips_sent_words() {
+        let (mut old, _in_tx, _out_rx) = channel(None);
+        let mut out_pcm = vec![0f32; FRAME_SIZE];
+        for _ in 0..200 {
+            old.push_audio(&[0.1; FRAME_SIZE], &mut out_pcm);
+        }
+        old.send(
+            OutMsg::Word { text: "hi".into(), start_time: 14.0, lang: None, tokens: None },
+            Some(old.id),
+        )
+        .unwrap();
+        let OutMsg::Checkpoint { data } = old.checkpoint("stt.safetensors") else { panic!() };
+
+        let (mut c, _in_tx, mut out_rx) = channel(None);
+        assert!(c.restore(&data, "other.safetensors", 2, 2048).is_err());
+        c.restore(&data, "stt.safetensors", 2, 2048).unwrap();
+        assert!(c.restore(&data, "stt.safetensors", 2, 2048).is_err());
+        // The last 10s of the 16s session are replayed from 6s.
+        assert_eq!(c.time_offset, 6.0);
+        assert_eq!(c.data.len(), 240_000);
+
+        let id = Some(c.id);
+        let replay_steps = 240_000 / FRAME_SIZE;
+        for step_idx in 0..replay_steps + 1 {
+            c.send(
+                OutMsg::Step { step_idx, prs: vec![], buffered_pcm: 0, time_correction_ms: 0.0 },
+                id,
+            )
+            .unwrap();
+        }
+        c.send(OutMsg::Word { text: "hi".into(), start_time: 8.0, lang: None, tokens: None }, id)
+            .unwrap();
+        c.send(OutMsg::EndWord { stop_time: 8.3 }, id).unwrap();
+        c.send(
+            OutMsg::Word { text: "there".into(), start_time: 8.5, lang: None, tokens: None },
+            id,
+        )
+        .unwrap();
+        assert!(
+            matches!(out_rx.try_recv(), Ok(OutMsg::Step { step_idx, .. }) if step_idx == replay_steps)
+        );
+        assert!(
+            matches!(out_rx.try_recv(), Ok(OutMsg::Word { start_time, .. }) if start_time == 14.5)
+        );
+        assert!(out_rx.try_recv().is_err());
+        assert!(c.replay.is_none());
+    }
 }
@@ -154,7 +154,7 @@ impl LatencyRecorder {
         let min_ns = self.min_ns.load(Ordering::Relaxed);
         let max_ns = self.max_ns.load(Ordering::Relaxed);
 
-        let mean_ns = if count > 0 { total_ns / count } else { 0 };
+        let mean_ns = total_ns.checked_div(count).unwrap_or(0);
 
         // Calculate percentiles from samples
         let (p50, p95, p99) = if let Ok(mut samples) = self.samples.lock() {
@@ -422,6 +422,415 @@ pub struct BenchmarkResult {
     pub stats: LatencyStats,
 }
 
+// ============================================================================
+// Self-Benchmark
+// ============================================================================
+
+/// Synthetic input for the self-benchmark: a quiet 220Hz tone with a slow amplitude
+/// modulation, so that the model sees neither silence nor clipped audio.
+pub fn synthetic_pcm(num_samples: usize, sample_rate: usize) -> Vec<f32> {
+    let sr = sample_rate as f32;
+    (0..num_samples)
+        .map(|i| {
+            let t = i as f32 / sr;
+            let envelope = 0.5 + 0.5 * (2.0 * std::f32::consts::PI * 0.5 * t).sin();
+            0.1 * envelope * (2.0 * std::f32::consts::PI * 220.0 * t).sin()
+        })
+        .collect()
+}
+
+/// Result of a self-benchmark run on an idle slot, returned by `/api/bench/latency`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SelfBenchReport {
+    /// Path of the benchmarked module
+    pub module: String,
+    /// Seconds of synthetic audio sent
+    pub audio_s: f64,
+    /// Number of model steps measured
+    pub steps: usize,
+    /// Latency between sending a frame and receiving its step, in milliseconds
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+    /// Wall-clock duration of the run divided by the audio duration
+    pub real_time_factor: f64,
+}
+
+impl SelfBenchReport {
+    pub fn new(module: &str, audio_s: f64, wall: Duration, mut samples: Vec<Duration>) -> Self {
+        samples.sort();
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let pct = |p: usize| match samples.len() {
+            0 => 0.0,
+            len => ms(samples[(len * p / 100).min(len - 1)]),
+        };
+        let mean_ms = match samples.len() {
+            0 => 0.0,
+            len => samples.iter().map(|&d| ms(d)).sum::<f64>() / len as f64,
+        };
+        let real_time_factor = if audio_s > 0.0 { wall.as_secs_f64() / audio_s } else { 0.0 };
+        Self {
+            module: module.to_string(),
+            audio_s,
+            steps: samples.len(),
+            mean_ms,
+            p50_ms: pct(50),
+            p90_ms: pct(90),
+            p99_ms: pct(99),
+            max_ms: samples.last().map_or(0.0, |&d| ms(d)),
+            real_time_factor,
+        }
+    }
+}
+
+// ============================================================================
+// Soak Test
+// ============================================================================
+
+/// Parse a duration such as `24h`, `90m`, `1h30m`, `2d` or a number of seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration '{s}', e.g. 24h, 90m, 1h30m or 45s");
+    let s = s.trim();
+    if let Ok(secs) = s.parse::<f64>() {
+        return Duration::try_from_secs_f64(secs).map_err(|_| invalid());
+    }
+    let (mut total, mut num) = (0.0, String::new());
+    for c in s.chars() {
+        if c.is_ascii_digit() || c == '.' {
+            num.push(c);
+            continue;
+        }
+        let unit = match c {
+            'd' => 86400.0,
+            'h' => 3600.0,
+            'm' => 60.0,
+            's' => 1.0,
+            _ => return Err(invalid()),
+        };
+        let value: f64 = num.parse().map_err(|_| invalid())?;
+        total += value * unit;
+        num.clear();
+    }
+    if s.is_empty() || !num.is_empty() {
+        return Err(invalid());
+    }
+    Duration::try_from_secs_f64(total).map_err(|_| invalid())
+}
+
+/// Number of concurrent sessions `elapsed` into a ramp cycle: up from 0 to `max_sessions` over
+/// the first half of the period and back down to 0 over the second half.
+pub fn ramp_target(elapsed: Duration, period: Duration, max_sessions: usize) -> usize {
+    let phase = elapsed.as_secs_f64() / period.as_secs_f64().max(1e-3);
+    if !(0.0..1.0).contains(&phase) {
+        return 0;
+    }
+    let level = 1.0 - (2.0 * phase - 1.0).abs();
+    (level * max_sessions as f64).round() as usize
+}
+
+#[derive(Debug, Clone)]
+pub struct SoakConfig {
+    /// Total duration of the test, the last cycle completes past it.
+    pub duration: Duration,
+    /// Duration of a ramp up and down cycle.
+    pub ramp_period: Duration,
+    /// Seconds of synthetic audio streamed by each session.
+    pub session_s: f64,
+    /// Sessions at the top of the ramp, all the slots of the module when unset.
+    pub max_sessions: Option<usize>,
+    /// Time given to the slots to be released once the sessions of a cycle are over.
+    pub slot_grace: Duration,
+    /// Growth of the RSS or of the used VRAM since the first cycle, in MB.
+    pub max_memory_growth_mb: f64,
+    /// Ratio between the p90 step latency of the last cycle and of the first one.
+    pub max_latency_drift: f64,
+}
+
+/// Measures of a ramp cycle, taken once all its sessions are over.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct SoakCycle {
+    pub index: usize,
+    /// Seconds since the start of the test.
+    pub end_s: f64,
+    pub sessions: usize,
+    /// Sessions that found no idle slot, or that failed.
+    pub busy: usize,
+    pub failed: usize,
+    /// Slots still in use after the grace period, sessions that were never released.
+    pub leaked_slots: usize,
+    pub steps: usize,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub rss_mb: Option<f64>,
+    pub vram_used_mb: Option<f64>,
+}
+
+impl SoakCycle {
+    pub fn set_latencies(&mut self, samples: &mut [Duration]) {
+        samples.sort();
+        let pct = |p: usize| match samples.len() {
+            0 => 0.0,
+            len => samples[(len * p / 100).min(len - 1)].as_secs_f64() * 1000.0,
+        };
+        self.steps = samples.len();
+        self.p50_ms = pct(50);
+        self.p90_ms = pct(90);
+    }
+}
+
+/// Written as JSON at the end of `server bench --soak`, which exits with an error when
+/// `failures` is not empty.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SoakReport {
+    pub module: String,
+    /// File name of the model of the module.
+    pub model: String,
+    pub duration_s: f64,
+    pub max_sessions: usize,
+    /// Last cycle against the first one, `None` with a single cycle.
+    pub rss_growth_mb: Option<f64>,
+    pub vram_growth_mb: Option<f64>,
+    pub latency_drift: Option<f64>,
+    pub failures: Vec<String>,
+    pub cycles: Vec<SoakCycle>,
+}
+
+impl SoakReport {
+    /// Check the cycles against the thresholds of `cfg`. The first cycle is the baseline, it
+    /// already includes the allocations made when the slots are first used.
+    pub fn new(
+        module: &str,
+        model: &str,
+        cfg: &SoakConfig,
+        max_sessions: usize,
+        cycles: Vec<SoakCycle>,
+    ) -> Self {
+        let mut failures = vec![];
+        for c in cycles.iter() {
+            if c.leaked_slots > 0 {
+                failures.push(format!(
+                    "cycle {}: {} slots still in use after the sessions ended",
+                    c.index, c.leaked_slots
+                ))
+            }
+            if c.failed > 0 {
+                failures.push(format!("cycle {}: {} sessions failed", c.index, c.failed))
+            }
+        }
+        let (first, last) = match cycles.as_slice() {
+            [first, .., last] => (Some(first), Some(last)),
+            _ => (None, None),
+        };
+        let growth = |f: fn(&SoakCycle) -> Option<f64>| Some(f(last?)? - f(first?)?);
+        let rss_growth_mb = growth(|c| c.rss_mb);
+        let vram_growth_mb = growth(|c| c.vram_used_mb);
+        for (name, growth) in [("RSS", rss_growth_mb), ("VRAM", vram_growth_mb)] {
+            if let Some(growth) = growth.filter(|&g| g > cfg.max_memory_growth_mb) {
+                failures.push(format!(
+                    "{name} grew by {growth:.1}MB, above {:.1}MB",
+                    cfg.max_memory_growth_mb
+                ))
+            }
+        }
+        let latency_drift = match (first, last) {
+            (Some(first), Some(last)) if first.p90_ms > 0.0 => Some(last.p90_ms / first.p90_ms),
+            _ => None,
+        };
+        if let Some(drift) = latency_drift.filter(|&d| d > cfg.max_latency_drift) {
+            failures.push(format!(
+                "p90 step latency drifted by x{drift:.2}, above x{:.2}",
+                cfg.max_latency_drift
+            ))
+        }
+        Self {
+            module: module.to_string(),
+            model: model.to_string(),
+            duration_s: cycles.last().map_or(0.0, |c| c.end_s),
+            max_sessions,
+            rss_growth_mb,
+            vram_growth_mb,
+            latency_drift,
+            failures,
+            cycles,
+        }
+    }
+}
+
+/// Resident memory of the process in MB, from `/proc/self/status`.
+fn rss_mb() -> Option<f64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: f64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb / 1024.0)
+}
+
+fn vram_used_mb() -> Option<f64> {
+    let info = crate::utils::get_gpu_info().ok()?;
+    Some(info.total_vram.saturating_sub(info.free_vram) as f64 / (1024.0 * 1024.0))
+}
+
+/// Ramp synthetic sessions up and down on a batched asr module for `cfg.duration`, checking
+/// after each cycle that all the slots were released and measuring the memory and latency.
+pub(crate) async fn soak(
+    module: &str,
+    asr: std::sync::Arc<crate::batched_asr::BatchedAsr>,
+    cfg: &SoakConfig,
+) -> anyhow::Result<SoakReport> {
+    type Session = anyhow::Result<Option<(f64, Vec<Duration>)>>;
+
+    let max_sessions = cfg.max_sessions.unwrap_or(asr.total_slots()).max(1);
+    let start = Instant::now();
+    let mut ticker = tokio::time::interval(Duration::from_millis(250));
+    let mut cycles = vec![];
+    while start.elapsed() < cfg.duration {
+        let mut cycle = SoakCycle { index: cycles.len(), ..SoakCycle::default() };
+        let mut samples = vec![];
+        let mut sessions = tokio::task::JoinSet::<Session>::new();
+        let mut record =
+            |cycle: &mut SoakCycle, res: Result<Session, tokio::task::JoinError>| match res {
+                Ok(Ok(Some((_, s)))) => samples.extend(s),
+                Ok(Ok(None)) => cycle.busy += 1,
+                Ok(Err(err)) => {
+                    tracing::warn!(?err, "soak session failed");
+                    cycle.failed += 1
+                }
+                Err(err) => {
+                    tracing::warn!(?err, "soak session panicked");
+                    cycle.failed += 1
+                }
+            };
+        let cycle_start = Instant::now();
+        while cycle_start.elapsed() < cfg.ramp_period {
+            ticker.tick().await;
+            while let Some(res) = sessions.try_join_next() {
+                record(&mut cycle, res)
+            }
+            let target = ramp_target(cycle_start.elapsed(), cfg.ramp_period, max_sessions);
+            while sessions.len() < target {
+                let asr = asr.clone();
+                let session_s = cfg.session_s;
+                sessions.spawn(async move { asr.bench_steps(session_s).await });
+                cycle.sessions += 1;
+            }
+        }
+        while let Some(res) = sessions.join_next().await {
+            record(&mut cycle, res)
+        }
+        let deadline = Instant::now() + cfg.slot_grace;
+        while asr.used_slots() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        cycle.leaked_slots = asr.used_slots();
+        cycle.set_latencies(&mut samples);
+        cycle.rss_mb = rss_mb();
+        cycle.vram_used_mb = vram_used_mb();
+        cycle.end_s = start.elapsed().as_secs_f64();
+        tracing::info!(
+            cycle = cycle.index,
+            sessions = cycle.sessions,
+            busy = cycle.busy,
+            failed = cycle.failed,
+            leaked_slots = cycle.leaked_slots,
+            p90_ms = cycle.p90_ms,
+            rss_mb = cycle.rss_mb,
+            vram_used_mb = cycle.vram_used_mb,
+            "soak cycle done"
+        );
+        cycles.push(cycle);
+    }
+    Ok(SoakReport::new(module, &asr.model_id(), cfg, max_sessions, cycles))
+}
+
+// ============================================================================
+// Baselines
+// ============================================================================
+
+/// Measures of a bench run on a GPU and model, written by `server bench --baseline-dir` to be
+/// compared with later runs by `server bench compare`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Baseline {
+    /// `<gpu>-<model>`, the name of the file without its extension.
+    pub name: String,
+    pub gpu: String,
+    pub model: String,
+    /// Lower is better for all of them.
+    pub metrics: std::collections::BTreeMap<String, f64>,
+}
+
+/// Lower case letters and digits, with single dashes in between.
+fn slug(s: &str) -> String {
+    let s = s.to_lowercase().replace(|c: char| !c.is_ascii_alphanumeric(), " ");
+    s.split_whitespace().collect::<Vec<_>>().join("-")
+}
+
+impl Baseline {
+    pub fn new(report: &SoakReport, gpu: &str) -> Self {
+        let model = report.model.strip_suffix(".safetensors").unwrap_or(&report.model);
+        let cycles = report.cycles.iter().filter(|c| c.steps > 0).collect::<Vec<_>>();
+        let mean = |f: fn(&SoakCycle) -> f64| {
+            (!cycles.is_empty())
+                .then(|| cycles.iter().map(|&c| f(c)).sum::<f64>() / cycles.len() as f64)
+        };
+        let sum = |f: fn(&SoakCycle) -> usize| report.cycles.iter().map(f).sum::<usize>() as f64;
+        let metrics = [
+            ("p50_ms", mean(|c| c.p50_ms)),
+            ("p90_ms", mean(|c| c.p90_ms)),
+            ("latency_drift", report.latency_drift),
+            ("rss_growth_mb", report.rss_growth_mb),
+            ("vram_growth_mb", report.vram_growth_mb),
+            ("failed_sessions", Some(sum(|c| c.failed))),
+            ("leaked_slots", Some(sum(|c| c.leaked_slots))),
+        ];
+        Self {
+            name: format!("{}-{}", slug(gpu), slug(model)),
+            gpu: gpu.to_string(),
+            model: report.model.clone(),
+            metrics: metrics.into_iter().filter_map(|(k, v)| Some((k.to_string(), v?))).collect(),
+        }
+    }
+
+    pub fn file_name(&self) -> String {
+        format!("{}.json", self.name)
+    }
+
+    /// Compare the metrics of `run` with these ones. A metric regresses when it is above
+    /// `(1 + tolerance)` times its baseline value plus a small absolute slack, so that metrics
+    /// close to zero do not fail on noise.
+    pub fn compare(&self, run: &Baseline, tolerance: f64) -> Vec<MetricDiff> {
+        let mut diffs = vec![];
+        for (metric, &baseline) in self.metrics.iter() {
+            let Some(&current) = run.metrics.get(metric) else { continue };
+            let slack = match metric.rsplit('_').next() {
+                Some("ms") => 1.0,
+                Some("mb") => 64.0,
+                Some("drift") => 0.05,
+                _ => 0.0,
+            };
+            let allowed = baseline * (1.0 + tolerance) + slack;
+            diffs.push(MetricDiff {
+                metric: metric.clone(),
+                baseline,
+                current,
+                allowed,
+                regressed: current > allowed,
+            })
+        }
+        diffs
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct MetricDiff {
+    pub metric: String,
+    pub baseline: f64,
+    pub current: f64,
+    /// Highest value that is not a regression.
+    pub allowed: f64,
+    pub regressed: bool,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -440,6 +849,114 @@ mod tests {
         assert_eq!(stats.max.as_millis(), 30);
     }
 
+    #[test]
+    fn test_self_bench_report() {
+        let samples = (1..=10).rev().map(Duration::from_millis).collect();
+        let report =
+            SelfBenchReport::new("/api/asr-streaming", 0.8, Duration::from_millis(400), samples);
+        assert_eq!(report.steps, 10);
+        assert_eq!(report.p50_ms, 6.0);
+        assert_eq!(report.p99_ms, 10.0);
+        assert_eq!(report.max_ms, 10.0);
+        assert!((report.mean_ms - 5.5).abs() < 1e-9);
+        assert!((report.real_time_factor - 0.5).abs() < 1e-9);
+
+        let empty = SelfBenchReport::new("x", 0.0, Duration::ZERO, vec![]);
+        assert_eq!((empty.steps, empty.max_ms, empty.real_time_factor), (0, 0.0, 0.0));
+        assert_eq!(synthetic_pcm(1920, 24000).len(), 1920);
+    }
+
+    #[test]
+    fn test_soak_helpers() {
+        assert_eq!(parse_duration("24h"), Ok(Duration::from_secs(86400)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert!(parse_duration("10ms").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("").is_err());
+
+        let period = Duration::from_secs(100);
+        let ramp: Vec<_> =
+            [0, 25, 50, 75, 99, 100].map(|s| ramp_target(Duration::from_secs(s), period, 8)).into();
+        assert_eq!(ramp, [0, 4, 8, 4, 0, 0]);
+
+        let cfg = SoakConfig {
+            duration: Duration::from_secs(300),
+            ramp_period: period,
+            session_s: 10.0,
+            max_sessions: None,
+            slot_grace: Duration::from_secs(5),
+            max_memory_growth_mb: 100.0,
+            max_latency_drift: 1.5,
+        };
+        let cycle = |index, rss_mb, p90_ms| SoakCycle {
+            index,
+            rss_mb: Some(rss_mb),
+            p90_ms,
+            ..SoakCycle::default()
+        };
+        let report = SoakReport::new("/asr", "m", &cfg, 8, vec![cycle(0, 1000.0, 20.0)]);
+        assert_eq!((report.rss_growth_mb, report.latency_drift), (None, None));
+        assert!(report.failures.is_empty());
+
+        let cycles = vec![cycle(0, 1000.0, 20.0), cycle(1, 1050.0, 22.0), cycle(2, 1080.0, 24.0)];
+        let report = SoakReport::new("/asr", "m", &cfg, 8, cycles);
+        assert_eq!(report.rss_growth_mb, Some(80.0));
+        assert_eq!(report.vram_growth_mb, None);
+        assert!(report.failures.is_empty());
+
+        let mut leaking = cycle(2, 1200.0, 40.0);
+        leaking.leaked_slots = 1;
+        let cycles = vec![cycle(0, 1000.0, 20.0), leaking];
+        let report = SoakReport::new("/asr", "m", &cfg, 8, cycles);
+        assert_eq!(report.failures.len(), 3, "{:?}", report.failures);
+        assert!(report.failures[0].contains("1 slots still in use"));
+    }
+
+    #[test]
+    fn test_baseline_compare() {
+        let cycle = |index, p90_ms, rss_mb| SoakCycle {
+            index,
+            steps: 100,
+            p50_ms: p90_ms / 2.0,
+            p90_ms,
+            rss_mb: Some(rss_mb),
+            ..SoakCycle::default()
+        };
+        let cfg = SoakConfig {
+            duration: Duration::from_secs(60),
+            ramp_period: Duration::from_secs(30),
+            session_s: 10.0,
+            max_sessions: None,
+            slot_grace: Duration::from_secs(5),
+            max_memory_growth_mb: 100.0,
+            max_latency_drift: 1.5,
+        };
+        let model = "stt-1b-en_fr.safetensors";
+        let cycles = vec![cycle(0, 20.0, 1000.0), cycle(1, 22.0, 1010.0)];
+        let report = SoakReport::new("/asr", model, &cfg, 8, cycles);
+        let baseline = Baseline::new(&report, "NVIDIA GeForce RTX 4090");
+        assert_eq!(baseline.name, "nvidia-geforce-rtx-4090-stt-1b-en-fr");
+        assert_eq!(baseline.metrics["p90_ms"], 21.0);
+        assert_eq!(baseline.metrics["rss_growth_mb"], 10.0);
+        assert_eq!(baseline.metrics["failed_sessions"], 0.0);
+        assert!(!baseline.metrics.contains_key("vram_growth_mb"));
+        assert!(baseline.compare(&baseline, 0.0).iter().all(|d| !d.regressed));
+
+        // 10% slower is within a 15% tolerance, a failed session never is.
+        let mut slower = cycle(1, 24.2, 1010.0);
+        slower.failed = 1;
+        let cycles = vec![cycle(0, 22.0, 1000.0), slower];
+        let report = SoakReport::new("/asr", model, &cfg, 8, cycles);
+        let run = Baseline::new(&report, "NVIDIA GeForce RTX 4090");
+        let regressed = baseline.compare(&run, 0.15).into_iter().filter(|d| d.regressed);
+        let regressed = regressed.map(|d| d.metric).collect::<Vec<_>>();
+        assert_eq!(regressed, ["failed_sessions"]);
+        let regressed = baseline.compare(&run, 0.0).into_iter().filter(|d| d.regressed);
+        let regressed = regressed.map(|d| d.metric).collect::<Vec<_>>();
+        assert_eq!(regressed, ["failed_sessions", "p50_ms", "p90_ms"]);
+    }
+
     #[test]
     fn test_scoped_timer() {
         let recorder = LatencyRecorder::new("test_scoped");
@@ -0,0 +1,161 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Circuit breaker of the batched asr slots. A session whose steps keep failing, with NaN
+//! probabilities or text tokens that cannot be decoded, is terminated with an error once it
+//! reaches `max_errors` within `window_s` rather than logging an error at every step. Its slot
+//! is then kept out of use for `cooldown_s` in case the slot itself is at fault, the next
+//! session of the slot starting from a reset model state.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Message of the error sent to a session terminated by the breaker.
+pub const TRIPPED_MESSAGE: &str = "session terminated after repeated inference errors";
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_max_errors() -> usize {
+    5
+}
+
+fn default_window_s() -> f64 {
+    10.0
+}
+
+fn default_cooldown_s() -> f64 {
+    30.0
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BreakerConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Errors of a session within `window_s` that terminate it.
+    #[serde(default = "default_max_errors")]
+    pub max_errors: usize,
+    #[serde(default = "default_window_s")]
+    pub window_s: f64,
+    /// How long the slot of a terminated session stays out of use.
+    #[serde(default = "default_cooldown_s")]
+    pub cooldown_s: f64,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            max_errors: default_max_errors(),
+            window_s: default_window_s(),
+            cooldown_s: default_cooldown_s(),
+        }
+    }
+}
+
+/// Inference errors of a session.
+#[derive(Debug, Default)]
+pub struct SlotErrors {
+    /// Times of the errors within the window.
+    recent: VecDeque<Instant>,
+    total: usize,
+    /// End of the cooldown of the slot, once the breaker tripped.
+    tripped: Option<Instant>,
+}
+
+impl SlotErrors {
+    /// Record an error, returns true when it trips the breaker.
+    pub fn record(&mut self, now: Instant, config: &BreakerConfig) -> bool {
+        self.total += 1;
+        if !config.enabled || self.tripped.is_some() {
+            return false;
+        }
+        let window = Duration::from_secs_f64(config.window_s.max(0.0));
+        while self.recent.front().is_some_and(|&t| now.duration_since(t) > window) {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(now);
+        if self.recent.len() < config.max_errors.max(1) {
+            return false;
+        }
+        self.tripped = Some(now + Duration::from_secs_f64(config.cooldown_s.max(0.0)));
+        true
+    }
+
+    /// Errors since the session started.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// End of the cooldown of the slot when the breaker tripped.
+    pub fn tripped(&self) -> Option<Instant> {
+        self.tripped
+    }
+}
+
+/// Slots of terminated sessions, until the end of their cooldown.
+#[derive(Debug, Default)]
+pub struct Quarantine {
+    slots: Vec<(usize, Instant)>,
+}
+
+impl Quarantine {
+    pub fn add(&mut self, slot: usize, until: Instant) {
+        self.slots.push((slot, until))
+    }
+
+    pub fn contains(&self, slot: usize) -> bool {
+        self.slots.iter().any(|&(s, _)| s == slot)
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Remove the slots whose cooldown is over and return them.
+    pub fn release(&mut self, now: Instant) -> Vec<usize> {
+        let mut released = vec![];
+        self.slots.retain(|&(slot, until)| {
+            let over = until <= now;
+            if over {
+                released.push(slot)
+            }
+            !over
+        });
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trips_on_errors_within_the_window() {
+        let config = BreakerConfig { max_errors: 3, window_s: 1.0, ..Default::default() };
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut errors = SlotErrors::default();
+        assert!(!errors.record(at(0), &config));
+        // The first error is out of the window.
+        assert!(!errors.record(at(1500), &config));
+        assert!(!errors.record(at(1600), &config));
+        assert!(errors.record(at(1700), &config));
+        assert_eq!(errors.tripped(), Some(at(31700)));
+        assert!(!errors.record(at(1800), &config));
+        assert_eq!(errors.total(), 5);
+
+        let mut quarantine = Quarantine::default();
+        quarantine.add(2, at(31700));
+        assert!(quarantine.contains(2));
+        assert!(quarantine.release(at(2000)).is_empty());
+        assert_eq!(quarantine.release(at(31700)), vec![2]);
+        assert_eq!(quarantine.len(), 0);
+
+        let disabled = BreakerConfig { enabled: false, max_errors: 1, ..Default::default() };
+        assert!(!SlotErrors::default().record(at(0), &disabled));
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Registry of the protocol features, served on `/api/capabilities`.
+//!
+//! The server ignores the query parameters it does not know, so an option sent to an older
+//! server is silently dropped. Clients check that a feature is listed for the endpoint they
+//! connect to before relying on it, which handles fleets mixing server versions without
+//! comparing version numbers. A feature added to the protocol gets an entry in [`FEATURES`],
+//! with the modules that support it.
+
+use crate::ModuleConfig;
+use std::collections::BTreeMap;
+
+pub struct Feature {
+    pub name: &'static str,
+    /// Whether a module supports the feature, given its config.
+    supported: fn(&ModuleConfig) -> bool,
+}
+
+const fn feature(name: &'static str, supported: fn(&ModuleConfig) -> bool) -> Feature {
+    Feature { name, supported }
+}
+
+fn asr(m: &ModuleConfig) -> bool {
+    matches!(m, ModuleConfig::Asr { .. } | ModuleConfig::BatchedAsr { .. })
+}
+
+fn asr_or_vad(m: &ModuleConfig) -> bool {
+    asr(m) || matches!(m, ModuleConfig::Vad { .. })
+}
+
+fn batched_asr(m: &ModuleConfig) -> bool {
+    matches!(m, ModuleConfig::BatchedAsr { .. })
+}
+
+fn tts(m: &ModuleConfig) -> bool {
+    matches!(m, ModuleConfig::Tts { .. })
+}
+
+fn tts_translation(m: &ModuleConfig) -> bool {
+    matches!(m, ModuleConfig::Tts { config, .. } if config.translation.is_some())
+}
+
+fn tts_voice_previews(m: &ModuleConfig) -> bool {
+    matches!(m, ModuleConfig::Tts { config, .. } if config.voice_previews)
+}
+
+fn mimi(m: &ModuleConfig) -> bool {
+    matches!(m, ModuleConfig::Mimi { .. })
+}
+
+pub const FEATURES: &[Feature] = &[
+    // Input messages of the asr and vad websockets.
+    feature("ogg_opus_input", asr_or_vad),
+    feature("set_sample_rate", asr_or_vad),
+    feature("refresh_token", asr_or_vad),
+    feature("echo", asr),
+    feature("enroll", asr),
+    feature("audio_codes_input", batched_asr),
+    feature("checkpoint", batched_asr),
+    // Query parameters of the asr websockets.
+    feature("formatting", asr),
+    feature("itn", asr),
+    feature("phrase_spotting", asr),
+    feature("word_tokens", asr),
+    feature("coalesce", asr),
+    feature("audio_stats", asr),
+    feature("speaker_stats", asr),
+    feature("stream_resume", batched_asr),
+    feature("segmentation", batched_asr),
+    feature("long_poll", batched_asr),
+    // Options of the tts endpoints.
+    feature("tts_voice_mix", tts),
+    feature("tts_envelope", tts),
+    feature("tts_channels", tts),
+    feature("tts_speed", tts),
+    feature("tts_translation", tts_translation),
+    feature("tts_voice_previews", tts_voice_previews),
+    // Mimi rooms.
+    feature("mimi_ogg_opus_recv", mimi),
+    feature("mimi_publishers", mimi),
+];
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Capabilities {
+    pub server_version: &'static str,
+    /// The features of the configured modules, with the endpoints supporting them.
+    pub features: BTreeMap<&'static str, Vec<String>>,
+}
+
+impl Capabilities {
+    pub fn new(modules: &std::collections::HashMap<String, ModuleConfig>) -> Self {
+        let mut features = BTreeMap::new();
+        for m in modules.values() {
+            let paths = match m {
+                ModuleConfig::Tts { path, .. } => vec![path.clone(), format!("{path}_streaming")],
+                ModuleConfig::Asr { path, .. }
+                | ModuleConfig::BatchedAsr { path, .. }
+                | ModuleConfig::Vad { path, .. }
+                | ModuleConfig::Lm { path, .. } => vec![path.clone()],
+                ModuleConfig::Mimi { send_path, recv_path, .. } => {
+                    vec![send_path.clone(), recv_path.clone()]
+                }
+            };
+            for f in FEATURES.iter().filter(|f| (f.supported)(m)) {
+                features.entry(f.name).or_insert_with(Vec::new).extend(paths.iter().cloned());
+            }
+        }
+        for paths in features.values_mut() {
+            paths.sort();
+        }
+        Self { server_version: env!("CARGO_PKG_VERSION"), features }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry() {
+        let names: std::collections::HashSet<_> = FEATURES.iter().map(|f| f.name).collect();
+        assert_eq!(names.len(), FEATURES.len());
+
+        let stt = include_str!("../../../../../configs/stt/config-stt-en_fr-hf.toml");
+        let tts = include_str!("../../../../../configs/tts/config-tts.toml");
+        let mut modules = toml::from_str::<crate::Config>(stt).unwrap().modules;
+        modules.extend(toml::from_str::<crate::Config>(tts).unwrap().modules);
+        let caps = Capabilities::new(&modules);
+        let asr = vec!["/api/asr-streaming".to_string()];
+        assert_eq!(caps.features["segmentation"], asr);
+        assert_eq!(caps.features["ogg_opus_input"], asr);
+        let tts = vec!["/api/tts".to_string(), "/api/tts_streaming".to_string()];
+        assert_eq!(caps.features["tts_speed"], tts);
+        assert!(!caps.features.contains_key("tts_translation"));
+        assert!(!caps.features.contains_key("mimi_publishers"));
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Export and import of batched ASR sessions.
+//!
+//! A checkpoint carries the last seconds of audio received by a slot along with the session
+//! time at which they start. Restoring it on a new slot replays this audio to rebuild the
+//! model and Mimi context, the words that the previous session already sent are dropped and
+//! the timestamps continue from where the previous session was. The frames the client sent as
+//! mimi codes are kept as codes, the pcm holds silence in their place.
+
+use anyhow::Result;
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::VecDeque;
+
+const MAGIC: &[u8; 4] = b"KASR";
+const VERSION: u8 = 1;
+const SAMPLE_RATE: usize = 24000;
+const FRAME_SIZE: usize = 1920;
+/// Longest context accepted on restore.
+pub const MAX_CONTEXT_S: f64 = 60.0;
+/// Latest session time accepted on restore, far from overflowing the times in samples.
+const MAX_START_S: f64 = 1e9;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+struct Meta {
+    model: String,
+    start_s: f64,
+    last_word_s: Option<f64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    codes: Vec<(usize, Vec<u32>)>,
+}
+
+/// The state of an ASR session, serialized as an opaque blob for the client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    /// Model file the session was running, a checkpoint cannot move to another model.
+    pub model: String,
+    /// Session time of the first context sample, in seconds.
+    pub start_s: f64,
+    /// Start time of the last word sent to the client.
+    pub last_word_s: Option<f64>,
+    pub pcm: Vec<f32>,
+    /// Frames sent as mimi codes, with the offset in `pcm` of the frame they stand for.
+    pub codes: Vec<(usize, Vec<u32>)>,
+}
+
+impl Checkpoint {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let meta = Meta {
+            model: self.model.clone(),
+            start_s: self.start_s,
+            last_word_s: self.last_word_s,
+            codes: self.codes.clone(),
+        };
+        let meta = rmp_serde::to_vec_named(&meta)?;
+        let mut buf = Vec::with_capacity(9 + meta.len() + 2 * self.pcm.len());
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        buf.extend_from_slice(&(meta.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&meta);
+        let pcm: Vec<i16> =
+            self.pcm.iter().map(|&v| (v.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).collect();
+        let start = buf.len();
+        buf.resize(start + 2 * pcm.len(), 0);
+        LittleEndian::write_i16_into(&pcm, &mut buf[start..]);
+        Ok(buf)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < 9 || &data[..4] != MAGIC {
+            anyhow::bail!("not an asr checkpoint")
+        }
+        if data[4] != VERSION {
+            anyhow::bail!("unsupported checkpoint version {}", data[4])
+        }
+        let meta_len = LittleEndian::read_u32(&data[5..9]) as usize;
+        let Some(meta) = data.get(9..9 + meta_len) else { anyhow::bail!("truncated checkpoint") };
+        let meta: Meta = rmp_serde::from_slice(meta)?;
+        if !(0.0..=MAX_START_S).contains(&meta.start_s) {
+            anyhow::bail!("invalid checkpoint start {}", meta.start_s)
+        }
+        if meta.last_word_s.is_some_and(|s| !s.is_finite()) {
+            anyhow::bail!("invalid checkpoint last word time")
+        }
+        let pcm = &data[9 + meta_len..];
+        if !pcm.len().is_multiple_of(2) {
+            anyhow::bail!("truncated checkpoint")
+        }
+        if pcm.len() / 2 > (MAX_CONTEXT_S * SAMPLE_RATE as f64) as usize {
+            anyhow::bail!("checkpoint context longer than {MAX_CONTEXT_S}s")
+        }
+        let mut samples = vec![0i16; pcm.len() / 2];
+        LittleEndian::read_i16_into(pcm, &mut samples);
+        let pcm: Vec<f32> = samples.into_iter().map(|v| v as f32 / i16::MAX as f32).collect();
+        let mut end = 0;
+        for (offset, _) in meta.codes.iter() {
+            match offset.checked_add(FRAME_SIZE) {
+                Some(frame_end) if *offset >= end && frame_end <= pcm.len() => end = frame_end,
+                _ => anyhow::bail!("invalid codes frame at {offset} in the checkpoint"),
+            }
+        }
+        Ok(Self {
+            model: meta.model,
+            start_s: meta.start_s,
+            last_word_s: meta.last_word_s,
+            pcm,
+            codes: meta.codes,
+        })
+    }
+}
+
+/// The most recent audio received by a slot.
+#[derive(Debug)]
+pub struct ContextRecorder {
+    pcm: VecDeque<f32>,
+    /// Frames sent as mimi codes, with the session time of their silence in `pcm`.
+    codes: VecDeque<(u64, Vec<u32>)>,
+    /// Session time of the first buffered sample, in samples.
+    start: u64,
+    max_samples: usize,
+}
+
+impl ContextRecorder {
+    pub fn new(max_s: f64) -> Self {
+        let max_samples = (max_s.clamp(0.0, MAX_CONTEXT_S) * SAMPLE_RATE as f64) as usize;
+        Self { pcm: VecDeque::new(), codes: VecDeque::new(), start: 0, max_samples }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == 0 && self.pcm.is_empty()
+    }
+
+    /// Continue a restored session: the context starts at `start_s`.
+    pub fn restart_at(&mut self, start_s: f64) {
+        self.pcm.clear();
+        self.codes.clear();
+        self.start = (start_s * SAMPLE_RATE as f64).round() as u64;
+    }
+
+    /// Session time of the end of the received audio, in samples.
+    pub fn end_sample(&self) -> u64 {
+        self.start + self.pcm.len() as u64
+    }
+
+    /// Session time of the end of the received audio.
+    pub fn end_s(&self) -> f64 {
+        self.end_sample() as f64 / SAMPLE_RATE as f64
+    }
+
+    pub fn push(&mut self, pcm: &[f32]) {
+        self.pcm.extend(pcm);
+        let excess = self.pcm.len().saturating_sub(self.max_samples);
+        self.pcm.drain(..excess);
+        self.start += excess as u64;
+        while self.codes.front().is_some_and(|(sample, _)| *sample < self.start) {
+            self.codes.pop_front();
+        }
+    }
+
+    /// Record a frame sent as mimi codes, the session time advances by a frame of silence.
+    pub fn push_codes(&mut self, codes: Vec<u32>) {
+        self.codes.push_back((self.end_sample(), codes));
+        self.push(&[0.0; FRAME_SIZE]);
+    }
+
+    pub fn checkpoint(&self, model: &str, last_word_s: Option<f64>) -> Checkpoint {
+        Checkpoint {
+            model: model.to_string(),
+            start_s: self.start as f64 / SAMPLE_RATE as f64,
+            last_word_s,
+            pcm: self.pcm.iter().copied().collect(),
+            codes: self.codes.iter().map(|(s, c)| ((s - self.start) as usize, c.clone())).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorder_and_round_trip() {
+        let mut rec = ContextRecorder::new(1.0);
+        assert!(rec.is_empty());
+        rec.push(&[0.5; 12_000]);
+        rec.push(&[-0.25; 24_000]);
+        let ckpt = rec.checkpoint("stt-1b.safetensors", Some(1.2));
+        assert_eq!((ckpt.start_s, ckpt.pcm.len()), (0.5, 24_000));
+
+        let decoded = Checkpoint::decode(&ckpt.encode().unwrap()).unwrap();
+        assert_eq!(decoded.model, ckpt.model);
+        assert_eq!((decoded.start_s, decoded.last_word_s), (0.5, Some(1.2)));
+        assert!(decoded.pcm.iter().all(|v| (v + 0.25).abs() < 1e-4));
+
+        rec.restart_at(decoded.start_s);
+        rec.push(&decoded.pcm);
+        assert_eq!(rec.checkpoint("m", None).start_s, 0.5);
+        assert_eq!((rec.end_sample(), rec.end_s()), (36_000, 1.5));
+
+        assert!(Checkpoint::decode(b"KASR").is_err());
+        let mut data = ckpt.encode().unwrap();
+        data.pop();
+        assert!(Checkpoint::decode(&data).is_err());
+    }
+
+    #[test]
+    fn codes_frames_round_trip() {
+        let mut rec = ContextRecorder::new(0.2);
+        rec.push_codes(vec![1, 2]);
+        rec.push(&[0.5; 960]);
+        rec.push_codes(vec![3, 4]);
+        rec.push(&[0.5; 960]);
+        // 0.2s is 4800 samples, the first codes frame is partly out of the context.
+        let ckpt = rec.checkpoint("m", None);
+        assert_eq!(ckpt.pcm.len(), 4800);
+        assert_eq!(ckpt.codes, [(1920, vec![3, 4])]);
+        let decoded = Checkpoint::decode(&ckpt.encode().unwrap()).unwrap();
+        assert_eq!(decoded.codes, ckpt.codes);
+
+        let overlapping = Checkpoint { codes: vec![(0, vec![1]), (1000, vec![2])], ..ckpt.clone() };
+        assert!(Checkpoint::decode(&overlapping.encode().unwrap()).is_err());
+        let past_the_end = Checkpoint { codes: vec![(4000, vec![1])], ..ckpt.clone() };
+        assert!(Checkpoint::decode(&past_the_end.encode().unwrap()).is_err());
+        let overflowing = Checkpoint { codes: vec![(usize::MAX - 10, vec![1])], ..ckpt.clone() };
+        assert!(Checkpoint::decode(&overflowing.encode().unwrap()).is_err());
+        for start_s in [f64::NAN, f64::INFINITY, -1.0, 1e12] {
+            let invalid = Checkpoint { start_s, codes: vec![], ..ckpt.clone() };
+            assert!(Checkpoint::decode(&invalid.encode().unwrap()).is_err(), "{start_s}");
+        }
+        let invalid = Checkpoint { last_word_s: Some(f64::NAN), ..ckpt };
+        assert!(Checkpoint::decode(&invalid.encode().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,72 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Coalescing of the messages sent on the asr websockets. A client connecting with the
+//! `coalesce_ms` query parameter receives the messages produced within that interval of the
+//! first one in a single binary frame, a msgpack array of the messages, rather than one frame
+//! each. This cuts the per-frame overhead when words come in bursts, e.g. when a file is
+//! transcribed faster than real time. A lone message is still sent on its own, so clients that
+//! ask for coalescing have to decode both forms.
+
+use serde::Serialize;
+use std::time::Duration;
+
+/// Longest flush interval a client can ask for, longer ones would hold the words back.
+pub const MAX_INTERVAL_MS: u64 = 100;
+
+/// Messages in a frame, a full frame is sent without waiting for the end of the interval.
+pub const MAX_MESSAGES: usize = 64;
+
+/// Flush interval asked for by the client, `None` when messages are sent one frame each.
+pub fn interval(coalesce_ms: Option<u64>) -> Option<Duration> {
+    match coalesce_ms {
+        None | Some(0) => None,
+        Some(ms) => Some(Duration::from_millis(ms.min(MAX_INTERVAL_MS))),
+    }
+}
+
+/// Serialize the messages of a frame into `buf`, a lone message as a map and several ones as
+/// an array of maps.
+pub fn serialize<T: Serialize>(msgs: &[T], buf: &mut bytes::BytesMut) -> anyhow::Result<()> {
+    use bytes::BufMut;
+
+    let mut w = buf.writer();
+    let mut ser = rmp_serde::Serializer::new(&mut w).with_human_readable().with_struct_map();
+    match msgs {
+        [msg] => msg.serialize(&mut ser)?,
+        msgs => msgs.serialize(&mut ser)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, serde::Deserialize, Debug, PartialEq)]
+    #[serde(tag = "type")]
+    enum Msg {
+        Word { text: String },
+        EndWord { stop_time: f64 },
+    }
+
+    #[test]
+    fn lone_messages_are_not_wrapped() {
+        let word = Msg::Word { text: "hello".to_string() };
+        let end = Msg::EndWord { stop_time: 1.5 };
+        let mut buf = bytes::BytesMut::new();
+        serialize(std::slice::from_ref(&word), &mut buf).unwrap();
+        assert_eq!(buf[0], 0x82);
+        assert_eq!(rmp_serde::from_slice::<Msg>(&buf).unwrap(), word);
+
+        let mut buf = bytes::BytesMut::new();
+        let msgs = vec![word, end];
+        serialize(&msgs, &mut buf).unwrap();
+        assert_eq!(buf[0], 0x92);
+        assert_eq!(rmp_serde::from_slice::<Vec<Msg>>(&buf).unwrap(), msgs);
+
+        assert_eq!(interval(Some(0)), None);
+        assert_eq!(interval(Some(1000)), Some(Duration::from_millis(MAX_INTERVAL_MS)));
+    }
+}
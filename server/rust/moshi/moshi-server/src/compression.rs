@@ -0,0 +1,128 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Compression of the REST responses, negotiated with the `Accept-Encoding` header of the
+//! request. Only the content types listed in the config are compressed, by default JSON, which
+//! includes the base64 WAV of the TTS, text and WAV files. Websocket upgrades are left alone, as
+//! are event streams since compressing them would hold their events back.
+
+use axum::http::{header, HeaderMap, StatusCode, Version};
+use std::sync::Arc;
+use tower_http::compression::{CompressionLayer, CompressionLevel};
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_encoding() -> bool {
+    true
+}
+
+fn default_min_size_bytes() -> u16 {
+    1024
+}
+
+fn default_content_types() -> Vec<String> {
+    ["application/json", "text/", "audio/wav"].map(String::from).to_vec()
+}
+
+/// Effort spent compressing, the responses are compressed as they are sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    #[default]
+    Fastest,
+    Default,
+    Best,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompressionConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_encoding")]
+    pub gzip: bool,
+    #[serde(default = "default_encoding")]
+    pub br: bool,
+    #[serde(default)]
+    pub level: Level,
+    /// Responses of a known size below this are sent as they are.
+    #[serde(default = "default_min_size_bytes")]
+    pub min_size_bytes: u16,
+    /// Prefixes of the content types that are compressed.
+    #[serde(default = "default_content_types")]
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            gzip: default_encoding(),
+            br: default_encoding(),
+            level: Level::default(),
+            min_size_bytes: default_min_size_bytes(),
+            content_types: default_content_types(),
+        }
+    }
+}
+
+impl CompressionConfig {
+    fn compressed(&self, status: StatusCode, headers: &HeaderMap) -> bool {
+        if status == StatusCode::SWITCHING_PROTOCOLS || status == StatusCode::NO_CONTENT {
+            return false;
+        }
+        let content_type = match headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+            Some(content_type) => content_type.to_ascii_lowercase(),
+            None => return false,
+        };
+        !content_type.starts_with("text/event-stream")
+            && self.content_types.iter().any(|t| content_type.starts_with(t.as_str()))
+    }
+
+    /// Layer compressing the responses, `None` when disabled.
+    pub fn layer(&self) -> Option<CompressionLayer<impl tower_http::compression::Predicate>> {
+        if !self.enabled || !(self.gzip || self.br) {
+            return None;
+        }
+        let config = Arc::new(self.clone());
+        let content_type = move |status, _: Version, headers: &HeaderMap, _: &_| {
+            config.compressed(status, headers)
+        };
+        let quality = match self.level {
+            Level::Fastest => CompressionLevel::Fastest,
+            Level::Default => CompressionLevel::Default,
+            Level::Best => CompressionLevel::Best,
+        };
+        let predicate = tower_http::compression::predicate::SizeAbove::new(self.min_size_bytes);
+        let layer = CompressionLayer::new()
+            .gzip(self.gzip)
+            .br(self.br)
+            .deflate(false)
+            .zstd(false)
+            .quality(quality)
+            .compress_when(tower_http::compression::Predicate::and(predicate, content_type));
+        Some(layer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compresses_listed_content_types() {
+        let config = CompressionConfig::default();
+        let headers = |content_type: &str| {
+            HeaderMap::from_iter([(header::CONTENT_TYPE, content_type.parse().unwrap())])
+        };
+        assert!(config.compressed(StatusCode::OK, &headers("application/json")));
+        assert!(config.compressed(StatusCode::OK, &headers("text/plain; charset=utf-8")));
+        assert!(config.compressed(StatusCode::OK, &headers("audio/wav")));
+        assert!(!config.compressed(StatusCode::OK, &headers("text/event-stream")));
+        assert!(!config.compressed(StatusCode::OK, &headers("audio/ogg")));
+        assert!(!config.compressed(StatusCode::OK, &HeaderMap::new()));
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Isolation of the modules sharing a GPU. Each module gets its own CUDA stream, or Metal
+//! command queue, so that the kernels of a long tts generation do not serialize against the
+//! batched asr steps on the default stream. The model steps of a module can also run on a
+//! dedicated thread pool, which bounds how many sessions of the module step at once.
+
+use anyhow::Result;
+use candle::{Device, DeviceLocation};
+use std::sync::Arc;
+
+fn default_own_stream() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ComputeConfig {
+    /// Run the module on its own stream rather than on the one of the server device, shared
+    /// with the other modules that turn this off.
+    #[serde(default = "default_own_stream")]
+    pub own_stream: bool,
+    /// Threads running the model steps of the sessions (tts, asr, vad and lm), the sessions
+    /// waiting for a free thread. Unset, each session steps on its own thread.
+    #[serde(default)]
+    pub threads: Option<usize>,
+}
+
+impl Default for ComputeConfig {
+    fn default() -> Self {
+        Self { own_stream: default_own_stream(), threads: None }
+    }
+}
+
+/// The device of a module, a new stream on the GPU of `base` unless `own_stream` is off.
+pub fn module_device(cfg: &ComputeConfig, base: &Device) -> Result<Device> {
+    if !cfg.own_stream {
+        return Ok(base.clone());
+    }
+    let device = match base.location() {
+        DeviceLocation::Cuda { gpu_id } => Device::new_cuda_with_stream(gpu_id)?,
+        DeviceLocation::Metal { gpu_id } => Device::new_metal(gpu_id)?,
+        DeviceLocation::Cpu => base.clone(),
+    };
+    Ok(device)
+}
+
+/// Threads stepping the models of a module, see [`ComputeConfig::threads`].
+#[derive(Debug, Clone)]
+pub struct StepPool(Option<Arc<rayon::ThreadPool>>);
+
+impl StepPool {
+    pub fn new(cfg: &ComputeConfig, module: &str) -> Result<Self> {
+        let Some(threads) = cfg.threads else { return Ok(Self(None)) };
+        let module = module.to_string();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.max(1))
+            .thread_name(move |idx| format!("{module}-step-{idx}"))
+            .build()?;
+        Ok(Self(Some(Arc::new(pool))))
+    }
+
+    /// Run `f` on a thread of the pool once one is free, or on the calling thread without a
+    /// pool.
+    pub fn install<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        match self.0.as_ref() {
+            Some(pool) => pool.install(f),
+            None => f(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_pool_bounds_concurrent_steps() {
+        let cfg: ComputeConfig = toml::from_str("threads = 2").unwrap();
+        assert!(cfg.own_stream);
+        let device = module_device(&cfg, &Device::Cpu).unwrap();
+        assert!(device.is_cpu());
+
+        let pool = StepPool::new(&cfg, "tts").unwrap();
+        let running = std::sync::atomic::AtomicUsize::new(0);
+        let max = std::sync::atomic::AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..6 {
+                s.spawn(|| {
+                    let name = pool.install(|| {
+                        use std::sync::atomic::Ordering::SeqCst;
+                        let n = running.fetch_add(1, SeqCst) + 1;
+                        max.fetch_max(n, SeqCst);
+                        std::thread::sleep(std::time::Duration::from_millis(20));
+                        running.fetch_sub(1, SeqCst);
+                        std::thread::current().name().map(str::to_string)
+                    });
+                    assert!(name.unwrap().starts_with("tts-step-"));
+                });
+            }
+        });
+        assert!(max.into_inner() <= 2);
+        assert_eq!(StepPool::new(&ComputeConfig::default(), "tts").unwrap().install(|| 1), 1);
+    }
+}
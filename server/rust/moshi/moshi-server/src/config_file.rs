@@ -0,0 +1,181 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Reading of the TOML config files.
+//!
+//! `${VAR}` is replaced with the value of the environment variable `VAR`, `${VAR:-default}`
+//! falls back on `default` when it is unset and `$${` is a literal `${`. A file can list other
+//! files with `include = ["common.toml"]`, relative to its own directory. The included files
+//! are merged in order and the including file comes last: tables are merged key by key, other
+//! values are replaced.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Replace the `${VAR}` references of a config file, `origin` is used in error messages.
+pub fn interpolate(text: &str, origin: &str) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    for (line_idx, line) in text.split_inclusive('\n').enumerate() {
+        if line.trim_start().starts_with('#') {
+            out.push_str(line);
+            continue;
+        }
+        let mut rest = line;
+        while let Some(pos) = rest.find('$') {
+            out.push_str(&rest[..pos]);
+            rest = &rest[pos..];
+            if let Some(r) = rest.strip_prefix("$${") {
+                out.push_str("${");
+                rest = r;
+                continue;
+            }
+            let Some(r) = rest.strip_prefix("${") else {
+                out.push('$');
+                rest = &rest[1..];
+                continue;
+            };
+            let Some(end) = r.find('}') else {
+                anyhow::bail!("{origin}:{}: unterminated '${{'", line_idx + 1)
+            };
+            let (name, default) = match r[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&r[..end], None),
+            };
+            let valid = !name.is_empty()
+                && !name.starts_with(|c: char| c.is_ascii_digit())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                anyhow::bail!("{origin}:{}: invalid variable name '{name}'", line_idx + 1)
+            }
+            match (std::env::var(name), default) {
+                (Ok(v), _) => out.push_str(&v),
+                (Err(_), Some(default)) => out.push_str(default),
+                (Err(_), None) => anyhow::bail!(
+                    "{origin}:{}: environment variable {name} is not set, use ${{{name}:-default}} for optional values",
+                    line_idx + 1
+                ),
+            }
+            rest = &r[end + 1..];
+        }
+        out.push_str(rest);
+    }
+    Ok(out)
+}
+
+/// Merge `over` into `base`, recursing into tables.
+pub fn merge(base: &mut toml::Table, over: toml::Table) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(b)), toml::Value::Table(o)) => merge(b, o),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Read a config file along with its includes.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<toml::Table> {
+    load_rec(path.as_ref(), &mut vec![])
+}
+
+fn load_rec(path: &Path, stack: &mut Vec<PathBuf>) -> Result<toml::Table> {
+    let canonical =
+        path.canonicalize().with_context(|| format!("cannot open config {}", path.display()))?;
+    if let Some(pos) = stack.iter().position(|p| p == &canonical) {
+        let cycle: Vec<_> = stack[pos..]
+            .iter()
+            .chain(std::iter::once(&canonical))
+            .map(|p| p.display().to_string())
+            .collect();
+        anyhow::bail!("config include cycle: {}", cycle.join(" -> "))
+    }
+    let origin = path.display().to_string();
+    let text = std::fs::read_to_string(path).with_context(|| format!("cannot read {origin}"))?;
+    let text = interpolate(&text, &origin)?;
+    let mut table: toml::Table =
+        toml::from_str(&text).with_context(|| format!("invalid config {origin}"))?;
+    let includes = match table.remove("include") {
+        None => vec![],
+        Some(toml::Value::Array(a)) => a
+            .into_iter()
+            .map(|v| match v {
+                toml::Value::String(s) => Ok(s),
+                v => anyhow::bail!("{origin}: include entries must be strings, got {v}"),
+            })
+            .collect::<Result<Vec<_>>>()?,
+        Some(v) => anyhow::bail!("{origin}: include must be an array of paths, got {v}"),
+    };
+    if includes.is_empty() {
+        return Ok(table);
+    }
+    stack.push(canonical);
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut merged = toml::Table::new();
+    for include in includes {
+        let included = load_rec(&dir.join(&include), stack)
+            .with_context(|| format!("included from {origin}"))?;
+        merge(&mut merged, included);
+    }
+    stack.pop();
+    merge(&mut merged, table);
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_dir(files: &[(&str, &str)]) -> PathBuf {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("moshi-config-{}-{n}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            std::fs::write(dir.join(name), content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn interpolation() {
+        std::env::set_var("MOSHI_CONFIG_TEST_DIR", "/data");
+        let text = "a = \"${MOSHI_CONFIG_TEST_DIR}/m\"\nb = ${MOSHI_CONFIG_TEST_UNSET:-8}\n# ${X}\nc = \"$${HOME} $HOME\"\n";
+        let out = interpolate(text, "t.toml").unwrap();
+        assert_eq!(out, "a = \"/data/m\"\nb = 8\n# ${X}\nc = \"${HOME} $HOME\"\n");
+
+        let err = interpolate("a = 1\nb = \"${MOSHI_CONFIG_TEST_UNSET}\"", "t.toml").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("t.toml:2: environment variable MOSHI_CONFIG_TEST_UNSET"));
+        assert!(interpolate("a = \"${A", "t.toml").is_err());
+        assert!(interpolate("a = \"${1A}\"", "t.toml").is_err());
+    }
+
+    #[test]
+    fn includes_are_merged() {
+        let dir = write_dir(&[
+            ("common.toml", "static_dir = \"./static/\"\n[modules.asr]\ntype = \"BatchedAsr\"\nbatch_size = 64\n"),
+            ("prod.toml", "include = [\"common.toml\"]\ninstance_name = \"prod\"\n[modules.asr]\nbatch_size = 16\n"),
+        ]);
+        let t = load(dir.join("prod.toml")).unwrap();
+        assert!(!t.contains_key("include"));
+        assert_eq!(t["static_dir"].as_str(), Some("./static/"));
+        assert_eq!(t["instance_name"].as_str(), Some("prod"));
+        assert_eq!(t["modules"]["asr"]["type"].as_str(), Some("BatchedAsr"));
+        assert_eq!(t["modules"]["asr"]["batch_size"].as_integer(), Some(16));
+    }
+
+    #[test]
+    fn include_cycles_are_reported() {
+        let dir = write_dir(&[
+            ("a.toml", "include = [\"b.toml\"]\n"),
+            ("b.toml", "include = [\"a.toml\"]\n"),
+        ]);
+        let err = format!("{:#}", load(dir.join("a.toml")).unwrap_err());
+        assert!(err.contains("config include cycle"), "{err}");
+        assert!(load(dir.join("missing.toml")).is_err());
+    }
+}
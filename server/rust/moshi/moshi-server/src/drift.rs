@@ -0,0 +1,134 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Correction of the word timestamps for the drift between the source media and the model.
+//!
+//! The timestamps of the model count the 24kHz samples it was fed, which strays from the time
+//! of the audio sent by the client once it is resampled: the resampler delays its output and
+//! pads the audio it still buffers when the sample rate changes. Over a multi-hour stream with
+//! a few rate changes this adds up to hundreds of milliseconds.
+//!
+//! The resampler knows how far its output is from the source, see
+//! [`crate::resample::InputResampler::correction_s`]. The sessions record each change of this
+//! correction along with the model time of the first sample it applies to, the timestamps of
+//! the words are then corrected with the value that was in effect when their audio was fed.
+//! The corrected timestamps stay within 40ms, half a frame, of the source media. The correction
+//! in effect is reported in the `time_correction_ms` field of the `Step` messages.
+
+use std::collections::VecDeque;
+
+/// Changes older than this are dropped, the words come out a few seconds after their audio.
+const KEEP_S: f64 = 60.0;
+
+#[derive(Debug, Clone, Default)]
+pub struct TimeCorrection {
+    /// Model time from which each correction applies, in increasing order.
+    changes: VecDeque<(f64, f64)>,
+    /// Correction of the audio fed before the first kept change.
+    before_s: f64,
+}
+
+impl TimeCorrection {
+    /// Shift the source time of the audio fed from `model_s` on by `delta_s`.
+    pub fn shift(&mut self, model_s: f64, delta_s: f64) {
+        let correction_s = self.current_s() + delta_s;
+        while self.changes.back().is_some_and(|&(t, _)| t >= model_s) {
+            self.changes.pop_back();
+        }
+        self.changes.push_back((model_s, correction_s));
+        while self.changes.len() > 1 && self.changes[1].0 < model_s - KEEP_S {
+            if let Some((_, c)) = self.changes.pop_front() {
+                self.before_s = c;
+            }
+        }
+    }
+
+    /// Correction of the audio fed last.
+    pub fn current_s(&self) -> f64 {
+        self.changes.back().map_or(self.before_s, |&(_, c)| c)
+    }
+
+    pub fn current_ms(&self) -> f64 {
+        self.current_s() * 1000.0
+    }
+
+    /// Source time of the model timestamp `model_s`.
+    pub fn correct(&self, model_s: f64) -> f64 {
+        let correction_s = self.changes.iter().rev().find(|&&(t, _)| t <= model_s);
+        model_s + correction_s.map_or(self.before_s, |&(_, c)| c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resample::InputResampler;
+
+    /// Bound on the difference between a corrected timestamp and the time of the source media.
+    const MAX_ERROR_S: f64 = 0.04;
+
+    #[test]
+    fn corrections_apply_from_their_model_time() {
+        let mut c = TimeCorrection::default();
+        assert_eq!(c.correct(3.0), 3.0);
+        c.shift(10.0, -0.02);
+        c.shift(20.0, 0.005);
+        assert_eq!(c.correct(5.0), 5.0);
+        assert!((c.correct(12.0) - 11.98).abs() < 1e-9);
+        assert!((c.correct(25.0) - 24.985).abs() < 1e-9);
+        assert!((c.current_ms() + 15.0).abs() < 1e-9);
+        c.shift(200.0, 0.0);
+        assert_eq!(c.changes.len(), 2);
+        assert!((c.correct(250.0) - 249.985).abs() < 1e-9);
+    }
+
+    /// Two hours of audio with a click in the middle of each minute, the first minute of every
+    /// ten is sent at another rate: once corrected, the time at which the model hears each
+    /// click matches the time at which it is in the source.
+    #[test]
+    fn two_hours_stay_aligned() {
+        const RATES: [u32; 4] = [44_100, 8_000, 16_000, 11_025];
+        let mut resampler = InputResampler::default();
+        let mut correction = TimeCorrection::default();
+        let (mut applied_s, mut fed, mut source_s) = (0.0, 0usize, 0.0);
+        let mut model_pcm = vec![];
+        let mut max_drift_s = 0f64;
+        for minute in 0..120 {
+            let start = fed;
+            model_pcm.clear();
+            let rate = match minute % 10 {
+                0 => Some(RATES[minute / 10 % RATES.len()]),
+                1 => Some(24_000),
+                _ => None,
+            };
+            if let Some(rate) = rate {
+                let tail = resampler.set_rate(rate).unwrap();
+                fed += tail.len();
+                model_pcm.extend(tail);
+            }
+            if resampler.correction_s() != applied_s {
+                correction.shift(fed as f64 / 24_000.0, resampler.correction_s() - applied_s);
+                applied_s = resampler.correction_s();
+            }
+            // The extra 12.5ms leave audio buffered in the resampler at the rate changes.
+            let rate = resampler.rate() as usize;
+            let mut pcm = vec![0f32; rate * 60 + rate / 80];
+            pcm[rate * 30] = 1.0;
+            let click_s = source_s + 30.0;
+            source_s += pcm.len() as f64 / rate as f64;
+            for chunk in pcm.chunks(rate / 25 + 7) {
+                let out = resampler.process(chunk.to_vec()).unwrap();
+                fed += out.len();
+                model_pcm.extend(out);
+            }
+            let peak = (0..model_pcm.len()).max_by(|&a, &b| model_pcm[a].total_cmp(&model_pcm[b]));
+            let model_s = (start + peak.unwrap()) as f64 / 24_000.0;
+            let error_s = correction.correct(model_s) - click_s;
+            assert!(error_s.abs() < MAX_ERROR_S, "minute {minute}: error of {error_s}s");
+            max_drift_s = max_drift_s.max((model_s - click_s).abs());
+        }
+        // Without the correction, some clicks are off by more than the bound.
+        assert!(max_drift_s > MAX_ERROR_S, "{max_drift_s}");
+    }
+}
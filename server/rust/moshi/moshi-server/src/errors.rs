@@ -0,0 +1,268 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Errors of the REST endpoints.
+//!
+//! Every failure answers with a specific status and a JSON body with a stable `code` that
+//! clients can match on, the `message` is for humans and may change:
+//!
+//! ```json
+//! {"error": "service_unavailable", "code": "model_busy", "message": "no free channels"}
+//! ```
+//!
+//! `error` is the status reason, as in the bodies of [`crate::auth::AuthError`]. Handlers
+//! return an [`ApiError`] directly or raise it from deeper code with `anyhow::bail!`, the
+//! conversion to a response finds it in the error chain. Errors without one are a 500 with
+//! the `internal_error` code.
+
+use crate::auth::AuthError;
+use crate::long_poll::PollError;
+use crate::memory::BudgetExceeded;
+use crate::mimi::RoomError;
+use crate::remote_audio::FetchError;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+
+#[derive(Debug)]
+pub enum ApiError {
+    Auth(AuthError),
+    Fetch(FetchError),
+    Poll(PollError),
+    Room(RoomError),
+    /// The body or the query parameters cannot be used.
+    InvalidRequest(String),
+    /// Unknown voice file or invalid combination of `voice`, `voices` and `voice_weights`.
+    InvalidVoice(String),
+    UnsupportedLanguage(String),
+    /// The audio of a batch query cannot be decoded.
+    BadAudio(String),
+    /// The audio of a batch query is in a format the decoder does not read.
+    UnsupportedAudio(crate::sniff::Detected),
+    NotFound(String),
+    /// The credentials are valid but do not grant access to the resource.
+    Forbidden(String),
+    PayloadTooLarge {
+        max_bytes: usize,
+    },
+    /// All the slots of the module are in use, the client should retry later.
+    ModelBusy(String),
+    /// The memory budget cannot fit another stream on the module.
+    AtCapacity(BudgetExceeded),
+    Internal(anyhow::Error),
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auth(err) => write!(f, "{}", err.message),
+            Self::Fetch(err) => write!(f, "{err}"),
+            Self::Poll(err) => write!(f, "{err}"),
+            Self::Room(err) => write!(f, "{err}"),
+            Self::InvalidRequest(msg)
+            | Self::InvalidVoice(msg)
+            | Self::UnsupportedLanguage(msg)
+            | Self::BadAudio(msg)
+            | Self::NotFound(msg)
+            | Self::Forbidden(msg)
+            | Self::ModelBusy(msg) => write!(f, "{msg}"),
+            Self::PayloadTooLarge { max_bytes } => {
+                write!(f, "request body exceeds {max_bytes} bytes")
+            }
+            Self::UnsupportedAudio(detected) => {
+                write!(f, "unsupported audio format ({detected}), send one of ")?;
+                write!(f, "{} encoded with ", crate::sniff::CONTAINERS.join(", "))?;
+                write!(f, "{}", crate::sniff::CODECS.join(", "))
+            }
+            Self::AtCapacity(err) => write!(f, "server at capacity, {err}"),
+            // The chain can hold paths or driver details, it is only logged.
+            Self::Internal(_) => write!(f, "internal error"),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Auth(err) => err.status_code(),
+            Self::Fetch(err) => err.status(),
+            Self::Poll(err) => err.status(),
+            Self::Room(err) => err.status(),
+            Self::InvalidRequest(_)
+            | Self::InvalidVoice(_)
+            | Self::UnsupportedLanguage(_)
+            | Self::BadAudio(_) => StatusCode::BAD_REQUEST,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::UnsupportedAudio(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::ModelBusy(_) | Self::AtCapacity(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable machine-readable code, also the label of the `api_error_total` counter.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Auth(err) => err.error_type(),
+            Self::Fetch(err) => err.code(),
+            Self::Poll(err) => err.code(),
+            Self::Room(err) => err.code(),
+            Self::InvalidRequest(_) => "invalid_request",
+            Self::InvalidVoice(_) => "invalid_voice",
+            Self::UnsupportedLanguage(_) => "unsupported_language",
+            Self::BadAudio(_) => "bad_audio",
+            Self::UnsupportedAudio(_) => "unsupported_audio",
+            Self::NotFound(_) => "not_found",
+            Self::Forbidden(_) => "forbidden",
+            Self::PayloadTooLarge { .. } => "payload_too_large",
+            Self::ModelBusy(_) => "model_busy",
+            Self::AtCapacity(_) => "at_capacity",
+            Self::Internal(_) => "internal_error",
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct ErrorBody {
+    error: String,
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detected: Option<crate::sniff::Detected>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    supported: Option<crate::sniff::Supported>,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> axum::response::Response {
+        // Auth errors keep their own body, with a hint, and counter.
+        let err = match self {
+            Self::Auth(err) => return err.into_response(),
+            err => err,
+        };
+        let status = err.status();
+        let code = err.code();
+        let max_bytes = match &err {
+            Self::Auth(_) => None,
+            Self::Internal(err) => {
+                tracing::error!(?err, "returning internal server error 500");
+                None
+            }
+            Self::PayloadTooLarge { max_bytes } => Some(*max_bytes),
+            _ => {
+                tracing::debug!(%status, code, message = %err, "returning api error");
+                None
+            }
+        };
+        crate::metrics::errors::record_api_error(code);
+        let error = status.canonical_reason().unwrap_or("error").to_lowercase().replace(' ', "_");
+        let (detected, supported) = match &err {
+            Self::UnsupportedAudio(detected) => {
+                (Some(detected.clone()), Some(crate::sniff::SUPPORTED))
+            }
+            _ => (None, None),
+        };
+        let message = err.to_string();
+        let body = ErrorBody { error, code, message, max_bytes, detected, supported };
+        (status, axum::Json(body)).into_response()
+    }
+}
+
+/// Finds the [`ApiError`] raised in the chain of `err`, if any.
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<ApiError>() {
+            Ok(err) => err,
+            Err(err) => Self::Internal(err),
+        }
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(err: AuthError) -> Self {
+        Self::Auth(err)
+    }
+}
+
+impl From<FetchError> for ApiError {
+    fn from(err: FetchError) -> Self {
+        Self::Fetch(err)
+    }
+}
+
+impl From<PollError> for ApiError {
+    fn from(err: PollError) -> Self {
+        Self::Poll(err)
+    }
+}
+
+impl From<RoomError> for ApiError {
+    fn from(err: RoomError) -> Self {
+        Self::Room(err)
+    }
+}
+
+impl From<BudgetExceeded> for ApiError {
+    fn from(err: BudgetExceeded) -> Self {
+        Self::AtCapacity(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn body(err: ApiError) -> (StatusCode, serde_json::Value) {
+        let response = err.into_response();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn status_and_code() {
+        let (status, v) = body(ApiError::ModelBusy("no free channels".to_string())).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(v["error"], "service_unavailable");
+        assert_eq!(v["code"], "model_busy");
+        assert_eq!(v["message"], "no free channels");
+        assert!(v.get("max_bytes").is_none());
+
+        let (status, v) = body(ApiError::PayloadTooLarge { max_bytes: 1024 }).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(v["error"], "payload_too_large");
+        assert_eq!(v["max_bytes"], 1024);
+
+        let (status, v) = body(AuthError::invalid_api_key().into()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(v["code"], "invalid_api_key");
+
+        let detected = crate::sniff::Detected { container: Some("webm"), codec: Some("opus") };
+        let (status, v) = body(ApiError::UnsupportedAudio(detected)).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(v["code"], "unsupported_audio");
+        assert_eq!(v["detected"]["codec"], "opus");
+        assert_eq!(v["supported"]["containers"][0], "wav");
+    }
+
+    #[tokio::test]
+    async fn found_in_the_error_chain() {
+        let err = anyhow::Error::new(ApiError::InvalidVoice("unknown voice 'x'".into()));
+        let err = ApiError::from(err.context("running tts"));
+        assert_eq!(err.code(), "invalid_voice");
+        let (status, v) = body(err).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(v["message"], "unknown voice 'x'");
+
+        let err = anyhow::anyhow!("cuda error").context("loading /models/x.safetensors");
+        let (status, v) = body(err.into()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(v["code"], "internal_error");
+        assert_eq!(v["message"], "internal error");
+    }
+}
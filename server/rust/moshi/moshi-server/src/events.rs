@@ -0,0 +1,190 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Server events for orchestration: modules being loaded and warmed up, websocket sessions
+//! starting and ending, batched asr slots being taken and released, and errors. They are
+//! streamed as server-sent events on `/api/events` so that auto-scalers and dashboards can react
+//! to state changes rather than polling `/api/status`.
+//!
+//! The startup events, `module_loaded` and `warmup_done`, are kept and replayed to every new
+//! subscriber as they are usually emitted before anyone can connect. The other events are not
+//! stored, a subscriber only gets the ones emitted after it connected, and one that falls behind
+//! gets a `lagged` event with the number of events it missed.
+
+use axum::response::sse;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use tokio::sync::broadcast;
+
+/// Events kept for the slowest subscriber before it lags.
+const CAPACITY: usize = 1024;
+/// Startup events kept for the new subscribers, the oldest are dropped beyond.
+const STARTUP_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    ModuleLoaded {
+        module: &'static str,
+        path: String,
+    },
+    WarmupDone {
+        module: &'static str,
+        path: String,
+        duration_s: f64,
+        ok: bool,
+    },
+    SessionStarted {
+        session: u64,
+        module: &'static str,
+        request_id: Option<String>,
+    },
+    SessionEnded {
+        session: u64,
+        module: &'static str,
+        duration_s: f64,
+    },
+    /// Slots of a batched asr module, quarantined slots are neither free nor in use.
+    Capacity {
+        path: String,
+        total_slots: usize,
+        free_slots: usize,
+    },
+    Error {
+        module: String,
+        error: String,
+    },
+    /// Only sent to a subscriber that fell behind, it should resync from `/api/status`.
+    Lagged {
+        skipped: u64,
+    },
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Stamped {
+    /// RFC 3339 UTC timestamp.
+    pub time: String,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+fn bus() -> &'static broadcast::Sender<Stamped> {
+    static BUS: OnceLock<broadcast::Sender<Stamped>> = OnceLock::new();
+    BUS.get_or_init(|| broadcast::channel(CAPACITY).0)
+}
+
+/// The startup events emitted so far. The lock is held while sending them, so that a new
+/// subscriber gets each of them either from the log or from the bus.
+fn startup() -> &'static Mutex<std::collections::VecDeque<Stamped>> {
+    static STARTUP: OnceLock<Mutex<std::collections::VecDeque<Stamped>>> = OnceLock::new();
+    STARTUP.get_or_init(Default::default)
+}
+
+/// Send an event to the current subscribers. Startup events are also kept for the later ones,
+/// the others are dropped when there are no subscribers.
+pub fn emit(event: Event) {
+    let bus = bus();
+    let time = || chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    if matches!(event, Event::ModuleLoaded { .. } | Event::WarmupDone { .. }) {
+        let mut startup = startup().lock().unwrap();
+        let stamped = Stamped { time: time(), event };
+        if startup.len() >= STARTUP_CAPACITY {
+            startup.pop_front();
+        }
+        startup.push_back(stamped.clone());
+        let _ = bus.send(stamped);
+        return;
+    }
+    if bus.receiver_count() == 0 {
+        return;
+    }
+    let _ = bus.send(Stamped { time: time(), event });
+}
+
+/// Subscribe before taking a snapshot of the state, so that no change is missed in between.
+/// Returns the startup events emitted before, to be sent first.
+pub fn subscribe() -> (broadcast::Receiver<Stamped>, Vec<Stamped>) {
+    let startup = startup().lock().unwrap();
+    (bus().subscribe(), startup.iter().cloned().collect())
+}
+
+/// Emit `SessionStarted` with a new session id, to be given back to `SessionEnded`.
+pub fn session_started(module: &'static str, request_id: Option<String>) -> u64 {
+    static NEXT_SESSION: AtomicU64 = AtomicU64::new(1);
+    let session = NEXT_SESSION.fetch_add(1, Ordering::Relaxed);
+    emit(Event::SessionStarted { session, module, request_id });
+    session
+}
+
+/// The events of `rx` as server-sent events, preceded by the `startup` ones and the `snapshot`
+/// ones.
+pub fn sse(
+    (rx, startup): (broadcast::Receiver<Stamped>, Vec<Stamped>),
+    snapshot: Vec<Event>,
+) -> sse::Sse<impl futures_util::Stream<Item = Result<sse::Event, axum::Error>>> {
+    use futures_util::StreamExt;
+
+    let time = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    let snapshot = snapshot.into_iter().map(move |event| Stamped { time: time.clone(), event });
+    let snapshot = startup.into_iter().chain(snapshot);
+    let live = futures_util::stream::unfold(rx, |mut rx| async move {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                let time = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+                Stamped { time, event: Event::Lagged { skipped } }
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        };
+        Some((event, rx))
+    });
+    let stream = futures_util::stream::iter(snapshot)
+        .chain(live)
+        .map(|event| sse::Event::default().json_data(event));
+    sse::Sse::new(stream).keep_alive(sse::KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribers_get_emitted_events() {
+        let event =
+            Event::Capacity { path: "/api/events-test".to_string(), total_slots: 4, free_slots: 3 };
+        emit(event.clone());
+        let (mut rx, _) = subscribe();
+        emit(event.clone());
+        // Other tests may emit events concurrently.
+        let received =
+            std::iter::from_fn(|| rx.try_recv().ok()).find(|e| e.event == event).unwrap();
+        let json = serde_json::to_value(&received).unwrap();
+        assert_eq!(json["type"], "capacity");
+        assert_eq!(json["free_slots"], 3);
+        assert!(json["time"].as_str().unwrap().ends_with('Z'));
+        assert!(std::iter::from_fn(|| rx.try_recv().ok()).all(|e| e.event != event));
+    }
+
+    #[test]
+    fn startup_events_are_replayed() {
+        let path = "/api/events-startup-test".to_string();
+        let loaded = Event::ModuleLoaded { module: "asr", path: path.clone() };
+        let warmup =
+            Event::WarmupDone { module: "asr", path: path.clone(), duration_s: 1.5, ok: true };
+        // Emitted before anyone subscribed.
+        emit(loaded.clone());
+        emit(warmup.clone());
+        let (mut rx, startup) = subscribe();
+        let ours = |e: &&Stamped| e.event == loaded || e.event == warmup;
+        let replayed: Vec<_> = startup.iter().filter(ours).map(|e| e.event.clone()).collect();
+        assert_eq!(replayed, [loaded.clone(), warmup.clone()]);
+        let live: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        assert!(!live.iter().any(|e| ours(&e)));
+
+        // Later startup events, e.g. a module reloaded, reach the current subscribers too.
+        emit(loaded.clone());
+        let live: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        assert!(live.iter().any(|e| e.event == loaded));
+    }
+}
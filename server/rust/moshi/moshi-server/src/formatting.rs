@@ -0,0 +1,123 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Casing of the asr words, chosen per session with the `formatting` query parameter.
+//!
+//! The words go through a chain of formatters in the order they are emitted, so that the
+//! formatters can carry state across words, the end of the previous sentence for instance.
+//! Language tags are computed before formatting, on the text of the model.
+
+/// Casing profile of a session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Formatting {
+    /// The words as the model writes them.
+    #[default]
+    Raw,
+    Lower,
+    /// The first word of each sentence is capitalized, the other words are left as they are.
+    Sentences,
+}
+
+/// A step of the formatting, called on each word in order.
+pub trait TextFormatter: Send {
+    fn format(&mut self, word: String) -> String;
+}
+
+#[derive(Debug, Default)]
+pub struct Lowercase;
+
+impl TextFormatter for Lowercase {
+    fn format(&mut self, word: String) -> String {
+        word.to_lowercase()
+    }
+}
+
+/// Capitalizes the words that follow a `.`, `!`, `?` or `…`, and the first word.
+#[derive(Debug)]
+pub struct SentenceCase {
+    sentence_start: bool,
+}
+
+impl Default for SentenceCase {
+    fn default() -> Self {
+        Self { sentence_start: true }
+    }
+}
+
+impl TextFormatter for SentenceCase {
+    fn format(&mut self, word: String) -> String {
+        let word = if self.sentence_start { capitalize(word) } else { word };
+        let end = word.trim_end_matches(['"', '\'', ')', ']', '»', '”', '’']);
+        if end.ends_with(['.', '!', '?', '…']) {
+            self.sentence_start = true
+        } else if word.chars().any(char::is_alphanumeric) {
+            self.sentence_start = false
+        }
+        word
+    }
+}
+
+fn capitalize(word: String) -> String {
+    match word.char_indices().find(|(_, c)| c.is_alphabetic()) {
+        Some((idx, c)) if c.is_lowercase() => {
+            let rest = &word[idx + c.len_utf8()..];
+            format!("{}{}{rest}", &word[..idx], c.to_uppercase())
+        }
+        _ => word,
+    }
+}
+
+/// The formatters of a session, applied in order.
+#[derive(Default)]
+pub struct FormatterChain {
+    formatters: Vec<Box<dyn TextFormatter>>,
+}
+
+impl FormatterChain {
+    pub fn new(formatting: Formatting) -> Self {
+        let chain = Self::default();
+        match formatting {
+            Formatting::Raw => chain,
+            Formatting::Lower => chain.with(Lowercase),
+            Formatting::Sentences => chain.with(SentenceCase::default()),
+        }
+    }
+
+    pub fn with(mut self, formatter: impl TextFormatter + 'static) -> Self {
+        self.formatters.push(Box::new(formatter));
+        self
+    }
+
+    pub fn format(&mut self, word: String) -> String {
+        self.formatters.iter_mut().fold(word, |word, f| f.format(word))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(chain: &mut FormatterChain, text: &str) -> String {
+        text.split(' ').map(|w| chain.format(w.to_string())).collect::<Vec<_>>().join(" ")
+    }
+
+    #[test]
+    fn profiles() {
+        let text = "hello Paris. this is \"great!\" 42 and... é then ? yes";
+        assert_eq!(format(&mut FormatterChain::new(Formatting::Raw), text), text);
+        assert_eq!(
+            format(&mut FormatterChain::new(Formatting::Lower), text),
+            "hello paris. this is \"great!\" 42 and... é then ? yes"
+        );
+        assert_eq!(
+            format(&mut FormatterChain::new(Formatting::Sentences), text),
+            "Hello Paris. This is \"great!\" 42 and... É then ? Yes"
+        );
+        let mut chain = FormatterChain::new(Formatting::Lower).with(SentenceCase::default());
+        assert_eq!(format(&mut chain, "WHAT? NO"), "What? No");
+        let q: Formatting = serde_json::from_str("\"sentences\"").unwrap();
+        assert_eq!(q, Formatting::Sentences);
+    }
+}
@@ -0,0 +1,596 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Inverse text normalization of the asr words, enabled per session with the `itn` query
+//! parameter: the numbers spelled out by the model are written with digits, along with the
+//! sign of the currency or percentage that follows them, e.g. `twenty three dollars` becomes
+//! `$23` and `vingt-trois pour cent` becomes `23 %`.
+//!
+//! The words of a number are held until a word that cannot be part of it, a pause of
+//! [`MAX_IDLE_STEPS`] model steps or a message ordered with the words, e.g. a marker answer.
+//! They are then sent as a single word starting with the first one and ending with the last
+//! one. The English and French rules are both tried, the ones reading the most words win.
+//! Single digits on their own stay words, as in `one of them` or `un chat`.
+
+use crate::asr::{OutMsg, WordToken};
+
+/// Model steps without a new word after which a held number is sent, 0.8s.
+const MAX_IDLE_STEPS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lang {
+    En,
+    Fr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Unit {
+    Dollar,
+    Euro,
+    Pound,
+    Percent,
+}
+
+impl Unit {
+    fn sign(self) -> &'static str {
+        match self {
+            Self::Dollar => "$",
+            Self::Euro => "€",
+            Self::Pound => "£",
+            Self::Percent => "%",
+        }
+    }
+}
+
+/// Values below a hundred written as a single word.
+fn small(word: &str, lang: Lang) -> Option<u64> {
+    let v = match (lang, word) {
+        (Lang::En, "zero") | (Lang::Fr, "zéro") => 0,
+        (Lang::En, "one") | (Lang::Fr, "un" | "une") => 1,
+        (Lang::En, "two") | (Lang::Fr, "deux") => 2,
+        (Lang::En, "three") | (Lang::Fr, "trois") => 3,
+        (Lang::En, "four") | (Lang::Fr, "quatre") => 4,
+        (Lang::En, "five") | (Lang::Fr, "cinq") => 5,
+        (Lang::En | Lang::Fr, "six") => 6,
+        (Lang::En, "seven") | (Lang::Fr, "sept") => 7,
+        (Lang::En, "eight") | (Lang::Fr, "huit") => 8,
+        (Lang::En, "nine") | (Lang::Fr, "neuf") => 9,
+        (Lang::En, "ten") | (Lang::Fr, "dix") => 10,
+        (Lang::En, "eleven") | (Lang::Fr, "onze") => 11,
+        (Lang::En, "twelve") | (Lang::Fr, "douze") => 12,
+        (Lang::En, "thirteen") | (Lang::Fr, "treize") => 13,
+        (Lang::En, "fourteen") | (Lang::Fr, "quatorze") => 14,
+        (Lang::En, "fifteen") | (Lang::Fr, "quinze") => 15,
+        (Lang::En, "sixteen") | (Lang::Fr, "seize") => 16,
+        (Lang::En, "seventeen") => 17,
+        (Lang::En, "eighteen") => 18,
+        (Lang::En, "nineteen") => 19,
+        (Lang::En, "twenty") | (Lang::Fr, "vingt" | "vingts") => 20,
+        (Lang::En, "thirty") | (Lang::Fr, "trente") => 30,
+        (Lang::En, "forty") | (Lang::Fr, "quarante") => 40,
+        (Lang::En, "fifty") | (Lang::Fr, "cinquante") => 50,
+        (Lang::En, "sixty") | (Lang::Fr, "soixante") => 60,
+        (Lang::En, "seventy") => 70,
+        (Lang::En, "eighty") => 80,
+        (Lang::En, "ninety") => 90,
+        _ => return None,
+    };
+    Some(v)
+}
+
+fn scale(word: &str, lang: Lang) -> Option<u64> {
+    let v = match (lang, word) {
+        (Lang::En, "hundred") | (Lang::Fr, "cent" | "cents") => 100,
+        (Lang::En, "thousand") | (Lang::Fr, "mille") => 1_000,
+        (Lang::En, "million") | (Lang::Fr, "million" | "millions") => 1_000_000,
+        (Lang::En, "billion") | (Lang::Fr, "milliard" | "milliards") => 1_000_000_000,
+        _ => return None,
+    };
+    Some(v)
+}
+
+fn unit(word: &str, lang: Lang) -> Option<Unit> {
+    let unit = match (lang, word) {
+        (_, "dollar" | "dollars") => Unit::Dollar,
+        (_, "euro" | "euros") => Unit::Euro,
+        (Lang::En, "pound" | "pounds") => Unit::Pound,
+        (Lang::En, "percent") | (Lang::Fr, "pourcent") => Unit::Percent,
+        _ => return None,
+    };
+    Some(unit)
+}
+
+/// Whether a word that only belongs to a number when followed by the right word fits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fits {
+    Yes,
+    No,
+    /// It is the last word received so far.
+    Wait,
+}
+
+impl Fits {
+    fn new(fits: bool, next: Option<bool>) -> Self {
+        match (fits, next) {
+            (false, _) | (true, Some(false)) => Self::No,
+            (true, Some(true)) => Self::Yes,
+            (true, None) => Self::Wait,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Number {
+    total: u64,
+    group: u64,
+    /// Last value added to the group, 0 after a scale.
+    last_small: u64,
+    /// Last scale of a thousand or more, each one has to be smaller than the previous one.
+    last_scale: Option<u64>,
+    hundred: bool,
+    /// Read as a year, `nineteen eighty four`.
+    year: bool,
+    /// Words of the number read, the connectors excluded.
+    words: usize,
+    decimals: Option<String>,
+    unit: Option<Unit>,
+}
+
+impl Number {
+    fn value(&self) -> u64 {
+        self.total + self.group
+    }
+
+    /// A lone `zero`, nothing follows it but the unit.
+    fn is_zero(&self) -> bool {
+        self.words > 0 && self.value() == 0
+    }
+
+    fn is_integer(&self) -> bool {
+        self.words > 0 && self.decimals.is_none() && self.unit.is_none()
+    }
+
+    fn small(&mut self, v: u64, lang: Lang) -> bool {
+        if let Some(decimals) = self.decimals.as_mut() {
+            if v >= 10 || self.unit.is_some() {
+                return false;
+            }
+            decimals.push_str(&v.to_string());
+            self.words += 1;
+            return true;
+        }
+        if self.unit.is_some() || self.is_zero() || (v == 0 && self.words > 0) {
+            return false;
+        }
+        if lang == Lang::Fr && v == 20 && self.last_small == 4 && self.group % 100 == 4 {
+            // quatre-vingt
+            self.group += 76;
+            self.last_small = 80;
+            self.words += 1;
+            return true;
+        }
+        let fits = match lang {
+            Lang::En => {
+                self.last_small == 0
+                    || (self.last_small >= 20 && self.last_small.is_multiple_of(10) && v < 10)
+            }
+            Lang::Fr => {
+                self.last_small == 0 || (self.last_small.is_multiple_of(10) && v < self.last_small)
+            }
+        };
+        if !fits {
+            let year = lang == Lang::En
+                && !self.year
+                && !self.hundred
+                && self.total == 0
+                && self.last_scale.is_none()
+                && (10..100).contains(&self.group)
+                && v >= 10;
+            if !year {
+                return false;
+            }
+            self.group *= 100;
+            self.year = true;
+        }
+        self.group += v;
+        self.last_small = v;
+        self.words += 1;
+        true
+    }
+
+    fn scale(&mut self, s: u64, lang: Lang) -> bool {
+        if !self.is_integer() && self.words > 0 || self.year || self.is_zero() {
+            return false;
+        }
+        if s == 100 {
+            let max_group = match lang {
+                Lang::En => 100,
+                Lang::Fr => 10,
+            };
+            if self.hundred || self.group >= max_group {
+                return false;
+            }
+            self.group = self.group.max(1) * 100;
+            self.hundred = true;
+        } else {
+            if self.last_scale.is_some_and(|last| s >= last) {
+                return false;
+            }
+            self.total += self.group.max(1) * s;
+            self.group = 0;
+            self.hundred = false;
+            self.last_scale = Some(s);
+        }
+        self.last_small = 0;
+        self.words += 1;
+        true
+    }
+
+    /// `one hundred and five`, `vingt et un`.
+    fn and_fits(&self, next: Option<&str>, lang: Lang) -> Fits {
+        match lang {
+            Lang::En => {
+                let fits = self.is_integer() && self.last_small == 0;
+                let next = next
+                    .map(|n| small(n, lang).is_some_and(|v| v > 0 && self.clone().small(v, lang)));
+                Fits::new(fits, next)
+            }
+            Lang::Fr => {
+                let fits = self.is_integer() && matches!(self.last_small, 20 | 30 | 40 | 50 | 60);
+                Fits::new(fits, next.map(|n| matches!(n, "un" | "une" | "onze")))
+            }
+        }
+    }
+
+    /// `three point five`, `trois virgule cinq`.
+    fn point_fits(&self, next: Option<&str>, lang: Lang) -> Fits {
+        let next = next.map(|n| small(n, lang).is_some_and(|v| v < 10));
+        Fits::new(self.is_integer(), next)
+    }
+
+    /// `twenty per cent`, `vingt pour cent`.
+    fn percent_fits(&self, next: Option<&str>) -> Fits {
+        Fits::new(self.words > 0 && self.unit.is_none(), next.map(|n| n == "cent"))
+    }
+
+    /// The number is worth writing with digits.
+    fn is_significant(&self) -> bool {
+        self.words >= 2 || self.value() >= 10 || self.decimals.is_some() || self.unit.is_some()
+    }
+
+    fn render(&self, lang: Lang) -> String {
+        let mut text = self.value().to_string();
+        if self.value() >= 10_000 {
+            let sep = match lang {
+                Lang::En => ",",
+                Lang::Fr => "\u{202f}",
+            };
+            let first = text.len() % 3;
+            let mut grouped = text[..first].to_string();
+            for (i, chunk) in text.as_bytes()[first..].chunks(3).enumerate() {
+                if first > 0 || i > 0 {
+                    grouped.push_str(sep);
+                }
+                grouped.push_str(std::str::from_utf8(chunk).unwrap_or_default());
+            }
+            text = grouped;
+        }
+        if let Some(decimals) = self.decimals.as_ref() {
+            text.push(if lang == Lang::En { '.' } else { ',' });
+            text.push_str(decimals);
+        }
+        match (self.unit, lang) {
+            (None, _) => text,
+            (Some(Unit::Percent), Lang::En) => format!("{text}%"),
+            (Some(unit), Lang::En) => format!("{}{text}", unit.sign()),
+            (Some(unit), Lang::Fr) => format!("{text}\u{a0}{}", unit.sign()),
+        }
+    }
+}
+
+/// A word, or a piece of a hyphenated word, lowercased without its punctuation.
+#[derive(Debug)]
+struct Part {
+    text: String,
+    /// Index of the word in the held words.
+    word: usize,
+    /// Punctuation before the part, a number cannot go on through it.
+    opens: bool,
+    /// Punctuation after the part.
+    closes: bool,
+}
+
+fn parts(words: &[HeldWord]) -> Vec<Part> {
+    let mut parts = vec![];
+    for (idx, word) in words.iter().enumerate() {
+        let pieces = word.text.trim().split('-').collect::<Vec<_>>();
+        for (i, piece) in pieces.iter().enumerate() {
+            let punct = |c: char| !c.is_alphanumeric();
+            parts.push(Part {
+                text: piece.trim_matches(punct).to_lowercase(),
+                word: idx,
+                opens: i == 0 && piece.starts_with(punct),
+                closes: i + 1 == pieces.len() && piece.ends_with(punct),
+            })
+        }
+    }
+    parts
+}
+
+#[derive(Debug)]
+struct Parse {
+    number: Number,
+    /// Parts read.
+    parts: usize,
+    /// More words could still be part of the number.
+    open: bool,
+}
+
+fn parse(parts: &[Part], lang: Lang) -> Parse {
+    let mut number = Number::default();
+    let mut i = 0;
+    let mut open = true;
+    while i < parts.len() {
+        let part = &parts[i];
+        // No word follows through punctuation.
+        let next_text = match parts.get(i + 1) {
+            _ if part.closes => Some(""),
+            Some(next) if next.opens => Some(""),
+            next => next.map(|n| n.text.as_str()),
+        };
+        if i > 0 && part.opens {
+            open = false;
+            break;
+        }
+        let (fits, len) = if let Some(v) = small(&part.text, lang) {
+            (Fits::new(number.small(v, lang), Some(true)), 1)
+        } else if let Some(s) = scale(&part.text, lang) {
+            (Fits::new(number.scale(s, lang), Some(true)), 1)
+        } else if let Some(unit) = unit(&part.text, lang) {
+            let fits = number.words > 0 && number.unit.is_none();
+            if fits {
+                number.unit = Some(unit);
+            }
+            (Fits::new(fits, Some(true)), 1)
+        } else if part.text == if lang == Lang::En { "and" } else { "et" } {
+            (number.and_fits(next_text, lang), 1)
+        } else if part.text == if lang == Lang::En { "point" } else { "virgule" } {
+            let fits = number.point_fits(next_text, lang);
+            if fits == Fits::Yes {
+                number.decimals = Some(String::new());
+            }
+            (fits, 1)
+        } else if part.text == if lang == Lang::En { "per" } else { "pour" } {
+            let fits = number.percent_fits(next_text);
+            if fits == Fits::Yes {
+                number.unit = Some(Unit::Percent);
+            }
+            (fits, 2)
+        } else {
+            (Fits::No, 0)
+        };
+        match fits {
+            Fits::Yes => i += len,
+            Fits::No => {
+                open = false;
+                break;
+            }
+            Fits::Wait => break,
+        }
+        if number.unit.is_some() || parts[i - 1].closes {
+            open = false;
+            break;
+        }
+    }
+    Parse { number, parts: i, open }
+}
+
+/// The number at the start of `words` read with the rules of `lang`, with the number of
+/// words it takes.
+fn read(words: &[HeldWord], lang: Lang) -> (Parse, usize) {
+    let mut parts = parts(words);
+    let mut truncated = false;
+    loop {
+        let mut p = parse(&parts, lang);
+        p.open &= !truncated;
+        if p.parts == 0 {
+            return (p, 0);
+        }
+        let last = parts[p.parts - 1].word;
+        match parts.get(p.parts) {
+            // The number ends within a word, it ends before that word instead.
+            Some(next) if next.word == last => {
+                parts.retain(|part| part.word < last);
+                truncated = true;
+            }
+            _ => return (p, last + 1),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HeldWord {
+    text: String,
+    start_time: f64,
+    lang: Option<String>,
+    tokens: Option<Vec<WordToken>>,
+    stop_time: Option<f64>,
+}
+
+impl HeldWord {
+    fn send(self, out: &mut Vec<OutMsg>) {
+        let Self { text, start_time, lang, tokens, stop_time } = self;
+        out.push(OutMsg::Word { text, start_time, lang, tokens });
+        if let Some(stop_time) = stop_time {
+            out.push(OutMsg::EndWord { stop_time })
+        }
+    }
+}
+
+/// The inverse text normalization of a session, the words go through it in order.
+#[derive(Debug, Default)]
+pub struct Itn {
+    held: Vec<HeldWord>,
+    idle_steps: usize,
+}
+
+impl Itn {
+    /// The messages to send in place of `msg`, none while the words of a number are held.
+    pub fn push(&mut self, msg: OutMsg) -> Vec<OutMsg> {
+        let mut out = vec![];
+        match msg {
+            OutMsg::Word { text, start_time, lang, tokens } => {
+                self.idle_steps = 0;
+                self.held.push(HeldWord { text, start_time, lang, tokens, stop_time: None });
+                self.settle(false, &mut out);
+            }
+            OutMsg::EndWord { stop_time }
+                if self.held.last().is_some_and(|w| w.stop_time.is_none()) =>
+            {
+                if let Some(word) = self.held.last_mut() {
+                    word.stop_time = Some(stop_time)
+                }
+            }
+            msg @ OutMsg::Step { .. } => {
+                if !self.held.is_empty() {
+                    self.idle_steps += 1;
+                    if self.idle_steps >= MAX_IDLE_STEPS {
+                        self.settle(true, &mut out)
+                    }
+                }
+                out.push(msg)
+            }
+            msg @ (OutMsg::Marker { .. }
+            | OutMsg::Checkpoint { .. }
+            | OutMsg::SegmentBoundary { .. }
+            | OutMsg::Error { .. }) => {
+                self.settle(true, &mut out);
+                out.push(msg)
+            }
+            msg => out.push(msg),
+        }
+        out
+    }
+
+    /// The held words once no more words come.
+    pub fn flush(&mut self) -> Vec<OutMsg> {
+        let mut out = vec![];
+        self.settle(true, &mut out);
+        out
+    }
+
+    /// Send the held words, the ones that cannot be part of a number anymore unless `all`.
+    fn settle(&mut self, all: bool, out: &mut Vec<OutMsg>) {
+        while !self.held.is_empty() {
+            let (en, fr) = (read(&self.held, Lang::En), read(&self.held, Lang::Fr));
+            if !all && (en.0.open || fr.0.open) {
+                return;
+            }
+            let ((parse, len), lang) = if fr.1 > en.1 { (fr, Lang::Fr) } else { (en, Lang::En) };
+            if len == 0 || !parse.number.is_significant() {
+                self.held.remove(0).send(out);
+                continue;
+            }
+            let mut words = self.held.drain(..len).collect::<Vec<_>>();
+            let first = words.remove(0);
+            let last = words.pop();
+            let end = last.as_ref().unwrap_or(&first);
+            let punct = |c: char| !c.is_alphanumeric();
+            let (head, tail) = (first.text.trim(), end.text.trim());
+            let lead = &head[..head.len() - head.trim_start_matches(punct).len()];
+            let trail = &tail[tail.trim_end_matches(punct).len()..];
+            let tokens = std::iter::once(&first)
+                .chain(words.iter())
+                .chain(last.iter())
+                .filter_map(|w| w.tokens.clone())
+                .reduce(|mut a, b| {
+                    a.extend(b);
+                    a
+                });
+            HeldWord {
+                text: format!("{lead}{}{trail}", parse.number.render(lang)),
+                start_time: first.start_time,
+                lang: first.lang.clone(),
+                tokens,
+                stop_time: end.stop_time,
+            }
+            .send(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(itn: &mut Itn, text: &str) -> Vec<OutMsg> {
+        let mut out = vec![];
+        for (i, word) in text.split(' ').enumerate() {
+            let word = OutMsg::Word {
+                text: word.to_string(),
+                start_time: i as f64,
+                lang: None,
+                tokens: None,
+            };
+            out.extend(itn.push(word));
+            out.extend(itn.push(OutMsg::EndWord { stop_time: i as f64 + 0.5 }));
+        }
+        out
+    }
+
+    fn normalize(text: &str) -> String {
+        let mut itn = Itn::default();
+        let mut out = run(&mut itn, text);
+        out.extend(itn.push(OutMsg::Marker { id: 0, sample_idx: 0, step_idx: 0 }));
+        let words = out.iter().filter_map(|m| match m {
+            OutMsg::Word { text, .. } => Some(text.as_str()),
+            _ => None,
+        });
+        words.collect::<Vec<_>>().join(" ")
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(normalize("it costs twenty three dollars."), "it costs $23.");
+        assert_eq!(normalize("one of them said one two three"), "one of them said one two three");
+        assert_eq!(normalize("two hundred and five people"), "205 people");
+        assert_eq!(normalize("rates rose three point five percent"), "rates rose 3.5%");
+        assert_eq!(normalize("in nineteen eighty four and"), "in 1984 and");
+        assert_eq!(normalize("Twenty five thousand, six"), "25,000, six");
+        assert_eq!(normalize("ten and a half"), "10 and a half");
+        assert_eq!(normalize("vingt-trois pour cent"), "23\u{a0}%");
+        assert_eq!(normalize("un chat et quatre-vingt-dix-sept euros"), "un chat et 97\u{a0}€");
+        assert_eq!(normalize("soixante et onze mille"), "71\u{202f}000");
+        assert_eq!(normalize("deux mille vingt-quatre"), "2024");
+    }
+
+    #[test]
+    fn holds_number_words() {
+        let mut itn = Itn::default();
+        // The words of the number are held until the next word.
+        let out = run(&mut itn, "pay twenty three");
+        let words = out.iter().filter(|m| matches!(m, OutMsg::Word { .. })).count();
+        assert_eq!(words, 1);
+        let out = itn.push(OutMsg::Word {
+            text: "dollars".to_string(),
+            start_time: 3.0,
+            lang: None,
+            tokens: None,
+        });
+        assert!(matches!(&out[..], [OutMsg::Word { text, start_time, .. }]
+            if text == "$23" && *start_time == 1.0));
+        // A pause sends the number.
+        run(&mut itn, "forty");
+        let step =
+            OutMsg::Step { step_idx: 0, prs: vec![], buffered_pcm: 0, time_correction_ms: 0. };
+        for _ in 0..MAX_IDLE_STEPS - 1 {
+            assert_eq!(itn.push(step.clone()).len(), 1);
+        }
+        let out = itn.push(step);
+        assert!(
+            matches!(&out[..], [OutMsg::Word { text, .. }, OutMsg::EndWord { stop_time }, OutMsg::Step { .. }]
+            if text == "40" && *stop_time == 0.5)
+        );
+    }
+}
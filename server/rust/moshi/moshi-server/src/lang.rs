@@ -0,0 +1,100 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Per-word language tags for the English/French models.
+//!
+//! The models do not expose a language posterior, so words are scored from their spelling:
+//! accents, elisions, contractions and the most frequent function words of each language.
+//! Words carrying no evidence, numbers or names for instance, inherit the language of the
+//! previous words through an exponentially decaying score.
+
+/// Weight of the previous words in the running score.
+const DECAY: f32 = 0.6;
+
+const FRENCH_WORDS: &[&str] = &[
+    "le", "la", "les", "un", "une", "des", "du", "de", "et", "est", "sont", "je", "tu", "il",
+    "elle", "nous", "vous", "ils", "elles", "ce", "cette", "ces", "que", "qui", "quoi", "pas",
+    "ne", "mais", "ou", "où", "avec", "pour", "dans", "sur", "par", "mon", "ma", "mes", "ton",
+    "ta", "tes", "son", "sa", "ses", "notre", "votre", "leur", "au", "aux", "oui", "non", "très",
+    "bien", "aussi", "alors", "donc", "comme", "tout", "tous", "toute", "fait", "faire", "avoir",
+    "être", "suis", "sommes", "êtes", "ai", "as", "avons", "avez", "ont", "était", "c'est",
+    "merci", "bonjour", "parce", "quand", "peut", "veux", "chose", "encore", "toujours", "rien",
+];
+
+const ENGLISH_WORDS: &[&str] = &[
+    "the", "a", "an", "and", "is", "are", "was", "were", "i", "you", "he", "she", "it", "we",
+    "they", "this", "that", "these", "those", "what", "who", "which", "not", "but", "or", "with",
+    "for", "in", "on", "at", "by", "of", "to", "my", "your", "his", "her", "our", "their", "yes",
+    "no", "very", "well", "also", "so", "then", "like", "all", "do", "does", "did", "have", "has",
+    "had", "be", "been", "am", "will", "would", "can", "could", "should", "there", "here", "thank",
+    "thanks", "hello", "because", "when", "want", "thing", "still", "always", "nothing", "just",
+    "know", "think", "about", "from", "if", "how", "why",
+];
+
+/// Evidence for French (positive) or English (negative) carried by a single word.
+pub fn word_score(word: &str) -> f32 {
+    let word = word.trim().trim_matches(|c: char| c.is_ascii_punctuation() && c != '\'');
+    let lower = word.to_lowercase().replace('’', "'");
+    if lower.is_empty() {
+        return 0.0;
+    }
+    let mut score = 0.0;
+    if FRENCH_WORDS.contains(&lower.as_str()) {
+        score += 1.5;
+    }
+    if ENGLISH_WORDS.contains(&lower.as_str()) {
+        score -= 1.5;
+    }
+    if lower.chars().any(|c| "éèêëàâçîïôûùüœæ".contains(c)) {
+        score += 2.0;
+    }
+    if ["l'", "d'", "j'", "qu'", "c'", "n'", "s'", "m'", "t'"].iter().any(|p| lower.starts_with(p))
+    {
+        score += 2.0;
+    }
+    if ["'s", "n't", "'re", "'ll", "'ve", "'m", "'d"].iter().any(|s| lower.ends_with(s)) {
+        score -= 2.0;
+    }
+    if lower.ends_with("ing") || lower.contains("th") || lower.contains('w') {
+        score -= 0.5;
+    }
+    if lower.ends_with("eux") || lower.ends_with("ment") || lower.ends_with("oi") {
+        score += 0.5;
+    }
+    score
+}
+
+/// Running language state of a stream.
+#[derive(Debug, Default, Clone)]
+pub struct LangTagger {
+    score: f32,
+}
+
+impl LangTagger {
+    /// Language of `word`, `"en"` or `"fr"`, given the words seen before it.
+    pub fn tag(&mut self, word: &str) -> &'static str {
+        let s = word_score(word);
+        self.score = if s.abs() >= 2.0 { s } else { DECAY * self.score + s };
+        if self.score > 0.0 {
+            "fr"
+        } else {
+            "en"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_switched_sentence() {
+        let mut tagger = LangTagger::default();
+        let text = "I think that c'est vraiment très bien Paris but we should go";
+        let tags: Vec<_> = text.split(' ').map(|w| tagger.tag(w)).collect();
+        assert_eq!(tags, ["en", "en", "en", "fr", "fr", "fr", "fr", "fr", "en", "en", "en", "en"]);
+        assert_eq!(LangTagger::default().tag("42"), "en");
+        assert_eq!(LangTagger::default().tag("L’été"), "fr");
+    }
+}
@@ -1,3 +1,2715 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! The moshi server as a library: [`ServerBuilder`] loads the modules of a [`Config`] and
+//! returns an axum router serving them, so that they can be mounted inside another service.
+//! The `moshi-server` binary is a thin wrapper around it.
+
+use anyhow::{Context, Result};
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use candle::Device;
+use std::sync::Arc;
+use std::time::Instant;
+
+mod access_log;
+mod admin;
+mod asr;
+mod audio_stats;
+mod auth;
+mod autotune;
+mod batched_asr;
 pub mod bench;
+mod breaker;
+mod capabilities;
+mod checkpoint;
+mod coalesce;
+mod compression;
+mod compute;
+mod config_file;
+mod drift;
+mod errors;
+mod events;
+mod formatting;
+mod itn;
+mod lang;
+mod limits;
+mod lm;
+mod long_poll;
+pub mod mdns;
+mod memory;
 pub mod metrics;
+mod mimi;
+mod mimi_bridge;
+mod modules_info;
+mod ogg_opus;
+pub mod otel;
+mod privacy;
+pub mod profiler;
 pub mod protocol;
+mod remote_audio;
+mod resample;
+mod retune;
+pub mod schema;
+mod server;
+mod session_summary;
+mod shadow;
+pub mod snapshot;
+mod sniff;
+mod speaker;
+mod speaker_count;
+mod spill;
+mod spotting;
+mod storage;
+mod task_scope;
+mod translation;
+mod tts;
+mod tts_cache;
+mod tts_channels;
+mod tts_encode;
+mod tts_jobs;
+mod tts_multipart;
+mod tts_preprocess;
+mod tts_speed;
+pub mod utils;
+mod vad;
+mod voices;
+
+pub use server::{Server, ServerBuilder};
+
+const ROOM_ID_HEADER: &str = "room_id";
+/// Length of the TTS voice preview clips.
+const VOICE_PREVIEW_S: f64 = 2.0;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct TtsConfig {
+    pub lm_model_file: String,
+    pub text_tokenizer_file: String,
+    pub speaker_tokenizer_file: String,
+    pub audio_tokenizer_file: String,
+    pub voices: std::collections::HashMap<String, String>,
+    pub voice_dir: String,
+    pub model: moshi::lm::Config,
+    pub generation: moshi::tts_streaming::Config,
+    #[serde(default)]
+    pub log_tokens: bool,
+    #[serde(default)]
+    pub dtype_override: Option<String>,
+    /// Machine translation backend for requests whose `input_language` and `speak_language`
+    /// differ.
+    #[serde(default)]
+    pub translation: Option<translation::TranslationConfig>,
+    /// Sidecar TOML file with the language, tags and description of the voices.
+    #[serde(default)]
+    pub voice_catalog: Option<String>,
+    /// Generate a short preview clip of each catalog voice at warmup.
+    #[serde(default)]
+    pub voice_previews: bool,
+    #[serde(default = "default_voice_preview_text")]
+    pub voice_preview_text: String,
+    /// Outputs above this size are written to a temporary file and streamed back from it.
+    #[serde(default = "default_spill_threshold_mb")]
+    pub spill_threshold_mb: usize,
+    /// Directory of the spilled outputs, the system temporary directory by default.
+    #[serde(default)]
+    pub spill_dir: Option<String>,
+    /// Workers encoding the streamed audio and the queue of each stream.
+    #[serde(default)]
+    pub encode_pool: tts_encode::EncodePoolConfig,
+    /// Chunking of the long texts of `/api/tts`.
+    #[serde(default)]
+    pub chunking: tts_preprocess::ChunkingConfig,
+    /// Cache of the `/api/tts` outputs, disabled when unset.
+    #[serde(default)]
+    pub cache: Option<tts_cache::TtsCacheConfig>,
+    /// Background jobs of `/api/tts`, disabled when unset.
+    #[serde(default)]
+    pub jobs: Option<tts_jobs::TtsJobsConfig>,
+    #[serde(default)]
+    pub compute: compute::ComputeConfig,
+}
+
+fn default_voice_preview_text() -> String {
+    "Hello, this is what my voice sounds like.".to_string()
+}
+
+fn default_spill_threshold_mb() -> usize {
+    64
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct AsrConfig {
+    pub lm_model_file: String,
+    pub text_tokenizer_file: String,
+    pub audio_tokenizer_file: String,
+    pub model: moshi::lm::Config,
+    pub asr_delay_in_tokens: usize,
+    #[serde(default)]
+    pub log_frequency_s: Option<f64>,
+    #[serde(default)]
+    pub conditioning_delay: Option<f32>,
+    // The default for bools in rust is false.
+    #[serde(default)]
+    pub conditioning_learnt_padding: bool,
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    #[serde(default)]
+    pub dtype_override: Option<String>,
+    /// How long a batched asr slot requested with a `stream_id` is kept after the client
+    /// disconnects, 0 disables sticky sessions.
+    #[serde(default = "default_stream_grace_period_s")]
+    pub stream_grace_period_s: f64,
+    /// Seconds of audio kept in the checkpoints of batched asr sessions.
+    #[serde(default = "default_checkpoint_context_s")]
+    pub checkpoint_context_s: f64,
+    /// Tag the words with their language, `en` or `fr`, for the English/French models.
+    #[serde(default)]
+    pub word_lang: bool,
+    /// How long a batched asr long-poll session is kept without being posted to or polled.
+    #[serde(default = "default_poll_session_ttl_s")]
+    pub poll_session_ttl_s: f64,
+    /// Split batched asr sessions into segments of this many seconds, the model state of the
+    /// slot is reset at each boundary so that 24/7 streams do not grow it without bound.
+    #[serde(default)]
+    pub segment_s: Option<f64>,
+    /// Write the words of each segment to a json file in this directory.
+    #[serde(default)]
+    pub segment_dir: Option<String>,
+    /// Write a json summary of each batched asr session to this path, with `{user}`,
+    /// `{session}` and `{segment}` replaced. With `{segment}`, one summary is written per
+    /// segment, at its boundary.
+    #[serde(default)]
+    pub summary_path: Option<String>,
+    /// Let websocket and long-poll sessions take the slot of a batch REST query when the
+    /// server is full, the query resumes from its last word once a slot frees up.
+    #[serde(default = "default_preempt_batch_jobs")]
+    pub preempt_batch_jobs: bool,
+    /// Words of sessions with an enrolled voice count as another speaker when the audio
+    /// around them is further than this from the voice, in standard deviations of its mel
+    /// cepstrum. Lower values drop more words of the enrolled speaker too.
+    #[serde(default = "default_speaker_max_distance")]
+    pub speaker_max_distance: f32,
+    /// When a batched asr steps: as soon as a slot has a frame (`latency`), or once every
+    /// slot has one (`throughput`).
+    #[serde(default)]
+    pub step_scheduling: batched_asr::StepScheduling,
+    /// Longest wait for the other slots in `throughput` scheduling, from the first frame of
+    /// the step.
+    #[serde(default = "default_step_max_wait_ms")]
+    pub step_max_wait_ms: u64,
+    /// Terminate the batched asr sessions whose steps keep failing and keep their slot out
+    /// of use for a while.
+    #[serde(default)]
+    pub circuit_breaker: breaker::BreakerConfig,
+    #[serde(default)]
+    pub compute: compute::ComputeConfig,
+    /// Second model transcribing a sample of the websocket sessions, its words are compared
+    /// with the production ones in the logs and metrics but never sent to the clients.
+    #[serde(default)]
+    pub shadow: Option<shadow::ShadowConfig>,
+}
+
+fn default_step_max_wait_ms() -> u64 {
+    80
+}
+
+fn default_preempt_batch_jobs() -> bool {
+    true
+}
+
+fn default_speaker_max_distance() -> f32 {
+    1.5
+}
+
+fn default_stream_grace_period_s() -> f64 {
+    30.0
+}
+
+fn default_checkpoint_context_s() -> f64 {
+    10.0
+}
+
+fn default_poll_session_ttl_s() -> f64 {
+    60.0
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct MimiConfig {
+    pub audio_tokenizer_file: String,
+    /// Legacy switch for authenticating listeners, superseded by the module `auth` policy.
+    #[serde(default)]
+    pub auth_recv: bool,
+    pub rooms: Vec<String>,
+    pub default_room: Option<String>,
+    /// Initial gain of the named publishers, others start at 1.0.
+    #[serde(default)]
+    pub publisher_gains: std::collections::HashMap<String, f32>,
+    /// Maximum number of publishers sending to the same room simultaneously.
+    #[serde(default = "default_max_publishers")]
+    pub max_publishers: usize,
+    /// Rooms granted to the JWT users of each role, in addition to the `rooms` claim of their
+    /// token. Once set, users whose token grants no room cannot join any.
+    #[serde(default)]
+    pub room_roles: std::collections::HashMap<String, Vec<String>>,
+    /// Messages buffered for each listener, 80ms audio frames and the occasional text or ping,
+    /// before it is considered lagging.
+    #[serde(default = "default_recv_buffer")]
+    pub recv_buffer: usize,
+    #[serde(default)]
+    pub recv_lag_policy: mimi::LagPolicy,
+    /// Republish the audio of rooms to Icecast or as HLS playlists.
+    #[serde(default)]
+    pub bridges: Vec<mimi_bridge::BridgeConfig>,
+    #[serde(default)]
+    pub compute: compute::ComputeConfig,
+}
+
+fn default_max_publishers() -> usize {
+    8
+}
+
+fn default_recv_buffer() -> usize {
+    32
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct LmConfig {
+    pub lm_model_file: String,
+    pub text_tokenizer_file: String,
+    pub audio_tokenizer_file: String,
+    pub model: moshi::lm::Config,
+    pub gen: moshi::lm_generate_multistream::Config,
+    #[serde(default)]
+    pub dtype_override: Option<String>,
+    #[serde(default)]
+    pub compute: compute::ComputeConfig,
+}
+
+fn default_warmup_enabled() -> bool {
+    true
+}
+
+fn default_bench_auth() -> auth::AuthPolicy {
+    auth::AuthPolicy::Admin
+}
+
+fn default_bench_max_seconds() -> f64 {
+    30.0
+}
+
+/// Settings of the `/api/bench/latency` self-benchmark endpoint.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct BenchConfig {
+    #[serde(default = "default_bench_auth")]
+    pub auth: auth::AuthPolicy,
+    /// Longest run a caller can ask for, in seconds of audio.
+    #[serde(default = "default_bench_max_seconds")]
+    pub max_seconds: f64,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self { auth: default_bench_auth(), max_seconds: default_bench_max_seconds() }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct WarmupConfig {
+    /// Enable or disable eager warmup for supported modules.
+    #[serde(default = "default_warmup_enabled")]
+    pub enabled: bool,
+}
+
+impl Default for WarmupConfig {
+    fn default() -> Self {
+        Self { enabled: default_warmup_enabled() }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(tag = "type")]
+pub enum ModuleConfig {
+    Tts {
+        path: String,
+        #[serde(default)]
+        auth: Option<auth::AuthPolicy>,
+        #[serde(flatten)]
+        config: TtsConfig,
+    },
+    Asr {
+        path: String,
+        #[serde(default)]
+        auth: Option<auth::AuthPolicy>,
+        #[serde(flatten)]
+        config: AsrConfig,
+    },
+    BatchedAsr {
+        path: String,
+        #[serde(default)]
+        auth: Option<auth::AuthPolicy>,
+        #[serde(flatten)]
+        config: AsrConfig,
+        batch_size: usize,
+    },
+    Vad {
+        path: String,
+        #[serde(default)]
+        auth: Option<auth::AuthPolicy>,
+        #[serde(flatten)]
+        config: AsrConfig,
+        #[serde(flatten)]
+        vad: vad::VadConfig,
+    },
+    Mimi {
+        send_path: String,
+        recv_path: String,
+        #[serde(default)]
+        auth: Option<auth::AuthPolicy>,
+        #[serde(flatten)]
+        config: MimiConfig,
+    },
+    Lm {
+        path: String,
+        #[serde(default)]
+        auth: Option<auth::AuthPolicy>,
+        #[serde(flatten)]
+        config: LmConfig,
+    },
+}
+
+impl ModuleConfig {
+    /// Auth policy for the module endpoints, the defaults preserve the historical behavior:
+    /// JWT everywhere except for the LM endpoint which has never been authenticated.
+    pub fn auth_policy(&self) -> auth::AuthPolicy {
+        match self {
+            Self::Tts { auth, .. }
+            | Self::Asr { auth, .. }
+            | Self::BatchedAsr { auth, .. }
+            | Self::Vad { auth, .. }
+            | Self::Mimi { auth, .. } => auth.unwrap_or(auth::AuthPolicy::Jwt),
+            Self::Lm { auth, .. } => auth.unwrap_or(auth::AuthPolicy::None),
+        }
+    }
+
+    /// Stream and step threads of the module.
+    pub fn compute(&self) -> &compute::ComputeConfig {
+        match self {
+            Self::Tts { config, .. } => &config.compute,
+            Self::Asr { config, .. }
+            | Self::BatchedAsr { config, .. }
+            | Self::Vad { config, .. } => &config.compute,
+            Self::Mimi { config, .. } => &config.compute,
+            Self::Lm { config, .. } => &config.compute,
+        }
+    }
+
+    /// Auth policy for Mimi listeners, falls back on the legacy `auth_recv` flag.
+    pub fn recv_auth_policy(&self) -> auth::AuthPolicy {
+        match self {
+            Self::Mimi { auth: None, config, .. } if !config.auth_recv => auth::AuthPolicy::None,
+            _ => self.auth_policy(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Config {
+    pub static_dir: String,
+    pub log_dir: String,
+    pub instance_name: String,
+    #[serde(default)]
+    pub warmup: WarmupConfig,
+    #[serde(default)]
+    pub modules: std::collections::HashMap<String, ModuleConfig>,
+    #[serde(default)]
+    pub mdns: mdns::MdnsConfig,
+    #[serde(default)]
+    pub otel: otel::OtelConfig,
+    #[serde(default)]
+    pub limits: limits::LimitsConfig,
+    #[serde(default)]
+    pub memory: memory::MemoryConfig,
+    #[serde(default)]
+    pub bench: BenchConfig,
+    #[serde(default)]
+    pub privacy: privacy::PrivacyConfig,
+    #[serde(default)]
+    pub autotune: autotune::AutotuneConfig,
+    #[serde(default)]
+    pub retune: retune::RetuneConfig,
+    #[serde(default)]
+    pub access_log: access_log::AccessLogConfig,
+    #[serde(default)]
+    pub compression: compression::CompressionConfig,
+    #[serde(default)]
+    pub admin: admin::AdminConfig,
+    /// Authentication configuration derived from environment.
+    #[serde(skip)]
+    #[serde(default)]
+    pub auth: auth::AuthConfig,
+    /// Weight snapshots, set by `--fast-restart`.
+    #[serde(skip)]
+    #[serde(default)]
+    pub snapshots: Option<snapshot::Snapshots>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<std::path::Path>>(p: P) -> Result<Self> {
+        use utils::resolve_or_download as rod;
+        let table = config_file::load(p.as_ref())?;
+        let mut config: Self = toml::Value::Table(table)
+            .try_into()
+            .with_context(|| format!("invalid config {}", p.as_ref().display()))?;
+
+        // Derive auth config from environment.
+        config.auth = auth::AuthConfig::from_env();
+        for (name, c) in config.modules.iter() {
+            config.auth.check_policies(name, [c.auth_policy(), c.recv_auth_policy()])?;
+        }
+        config.auth.check_policies("retune", [config.retune.auth])?;
+
+        // Collect all paths that need to be resolved.
+        let mut paths = Vec::new();
+
+        // Helper to add a path to our collection list.
+        fn add_path(paths: &mut Vec<String>, path: &str) {
+            paths.push(path.to_string());
+        }
+
+        for (_, c) in config.modules.iter() {
+            match c {
+                ModuleConfig::Mimi { config: c, .. } => {
+                    add_path(&mut paths, &c.audio_tokenizer_file);
+                }
+                ModuleConfig::Tts { config: c, .. } => {
+                    add_path(&mut paths, &c.lm_model_file);
+                    add_path(&mut paths, &c.text_tokenizer_file);
+                    add_path(&mut paths, &c.speaker_tokenizer_file);
+                    add_path(&mut paths, &c.audio_tokenizer_file);
+                    for (_, v) in c.voices.iter() {
+                        add_path(&mut paths, v);
+                    }
+                    add_path(&mut paths, &c.voice_dir);
+                    if let Some(v) = c.voice_catalog.as_ref() {
+                        add_path(&mut paths, v);
+                    }
+                }
+                ModuleConfig::BatchedAsr { config: c, .. } => {
+                    add_path(&mut paths, &c.lm_model_file);
+                    add_path(&mut paths, &c.text_tokenizer_file);
+                    add_path(&mut paths, &c.audio_tokenizer_file);
+                }
+                ModuleConfig::Asr { config: c, .. } | ModuleConfig::Vad { config: c, .. } => {
+                    add_path(&mut paths, &c.lm_model_file);
+                    add_path(&mut paths, &c.text_tokenizer_file);
+                    add_path(&mut paths, &c.audio_tokenizer_file);
+                }
+                ModuleConfig::Lm { config: c, .. } => {
+                    add_path(&mut paths, &c.audio_tokenizer_file);
+                    add_path(&mut paths, &c.text_tokenizer_file);
+                    add_path(&mut paths, &c.lm_model_file);
+                }
+            }
+        }
+        add_path(&mut paths, &config.static_dir);
+        add_path(&mut paths, &config.log_dir);
+        add_path(&mut paths, &config.instance_name);
+
+        // Resolve all paths in parallel.
+        use rayon::prelude::*;
+        let resolved_paths: Result<std::collections::HashMap<String, String>> = paths
+            .into_par_iter()
+            .map(|p| {
+                let resolved = rod(&p)?;
+                Ok((p, resolved))
+            })
+            .collect();
+        let resolved_paths = resolved_paths?;
+
+        // Update the config with resolved paths.
+        for (_, c) in config.modules.iter_mut() {
+            match c {
+                ModuleConfig::Mimi { config: c, .. } => {
+                    c.audio_tokenizer_file = resolved_paths[&c.audio_tokenizer_file].clone();
+                }
+                ModuleConfig::Tts { config: c, .. } => {
+                    c.lm_model_file = resolved_paths[&c.lm_model_file].clone();
+                    c.text_tokenizer_file = resolved_paths[&c.text_tokenizer_file].clone();
+                    c.speaker_tokenizer_file = resolved_paths[&c.speaker_tokenizer_file].clone();
+                    c.audio_tokenizer_file = resolved_paths[&c.audio_tokenizer_file].clone();
+                    for (_, v) in c.voices.iter_mut() {
+                        *v = resolved_paths[v].clone();
+                    }
+                    c.voice_dir = resolved_paths[&c.voice_dir].clone();
+                    if let Some(v) = c.voice_catalog.as_mut() {
+                        *v = resolved_paths[v].clone();
+                    }
+                }
+                ModuleConfig::BatchedAsr { config: c, .. } => {
+                    c.lm_model_file = resolved_paths[&c.lm_model_file].clone();
+                    c.text_tokenizer_file = resolved_paths[&c.text_tokenizer_file].clone();
+                    c.audio_tokenizer_file = resolved_paths[&c.audio_tokenizer_file].clone();
+                }
+                ModuleConfig::Asr { config: c, .. } | ModuleConfig::Vad { config: c, .. } => {
+                    c.lm_model_file = resolved_paths[&c.lm_model_file].clone();
+                    c.text_tokenizer_file = resolved_paths[&c.text_tokenizer_file].clone();
+                    c.audio_tokenizer_file = resolved_paths[&c.audio_tokenizer_file].clone();
+                }
+                ModuleConfig::Lm { config: c, .. } => {
+                    c.audio_tokenizer_file = resolved_paths[&c.audio_tokenizer_file].clone();
+                    c.text_tokenizer_file = resolved_paths[&c.text_tokenizer_file].clone();
+                    c.lm_model_file = resolved_paths[&c.lm_model_file].clone();
+                }
+            }
+        }
+        config.static_dir = resolved_paths[&config.static_dir].clone();
+        config.log_dir = resolved_paths[&config.log_dir].clone();
+        config.instance_name = resolved_paths[&config.instance_name].clone();
+        Ok(config)
+    }
+}
+
+#[allow(unused)]
+enum Module {
+    Tts {
+        path: String,
+        auth: auth::AuthPolicy,
+        m: Arc<tts::Model>,
+    },
+    Asr {
+        path: String,
+        auth: auth::AuthPolicy,
+        m: Arc<asr::Asr>,
+    },
+    BatchedAsr {
+        path: String,
+        auth: auth::AuthPolicy,
+        m: Arc<batched_asr::BatchedAsr>,
+    },
+    Mimi {
+        send_path: String,
+        recv_path: String,
+        send_auth: auth::AuthPolicy,
+        recv_auth: auth::AuthPolicy,
+        m: Arc<mimi::Mimi>,
+    },
+    Lm {
+        path: String,
+        auth: auth::AuthPolicy,
+        m: Arc<lm::Lm>,
+    },
+    Vad {
+        path: String,
+        auth: auth::AuthPolicy,
+        m: Arc<vad::Vad>,
+    },
+}
+
+struct SharedStateInner {
+    config: Config,
+    memory: Arc<memory::MemoryBudget>,
+    /// Documents of the admin endpoints, `None` when they are disabled.
+    admin: Option<Arc<admin::AdminStore>>,
+}
+
+type SharedState = Arc<SharedStateInner>;
+
+fn lm_router(
+    s: Arc<lm::Lm>,
+    path: &str,
+    auth: auth::AuthPolicy,
+    ss: &SharedState,
+) -> axum::Router<()> {
+    async fn lm_websocket(
+        socket: limits::LimitedSocket,
+        state: Arc<lm::Lm>,
+        _addr: Option<String>,
+    ) {
+        if let Err(err) = state.handle_socket(socket).await {
+            tracing::error!(?err, "lm")
+        }
+    }
+
+    #[tracing::instrument(skip(ws, headers, state), fields(client_ip))]
+    async fn lm_streaming(
+        ws: axum::extract::ws::WebSocketUpgrade,
+        headers: axum::http::HeaderMap,
+        state: axum::extract::State<(Arc<lm::Lm>, SharedState, auth::AuthPolicy)>,
+        axum::Extension(module): axum::Extension<memory::ModuleKey>,
+        req: axum::extract::Query<LmStreamingQuery>,
+    ) -> utils::AxumResult<axum::response::Response> {
+        let addr = headers.get("X-Real-IP").and_then(|v| v.to_str().ok().map(|v| v.to_string()));
+        if let Some(ip) = &addr {
+            tracing::Span::current().record("client_ip", ip);
+        }
+        tracing::info!("handling lm-streaming query");
+        let auth_result = auth::check_policy(state.0 .2, &headers, req.token.as_deref());
+        let session = otel::Session::new("lm", &headers, &auth_result);
+        let limits = state.0 .1.config.limits.clone();
+        let memory = state.0 .1.memory.clone();
+        let state = state.0 .0.clone();
+        let upg = limits
+            .ws_upgrade(ws)
+            .write_buffer_size(0)
+            .protocols(["permessage-deflate"])
+            .on_upgrade(move |mut socket| {
+                session.run(async move {
+                    if let Err(err) = auth_result {
+                        tracing::warn!(?err, "WebSocket auth failed, closing with 4001");
+                        let _ = crate::utils::close_with_reason(
+                            &mut socket,
+                            crate::protocol::CloseCode::AuthenticationFailed,
+                            Some("Authentication failed"),
+                        )
+                        .await;
+                        return;
+                    }
+                    let Some(_admission) = memory.admit_socket(&module.0, &mut socket).await else {
+                        return;
+                    };
+                    lm_websocket(limits::LimitedSocket::new(socket, &limits), state, addr).await
+                })
+            });
+        Ok(upg)
+    }
+
+    axum::Router::new()
+        .route(path, axum::routing::get(lm_streaming))
+        .layer(axum::Extension(memory::ModuleKey(path.to_string())))
+        .with_state((s, ss.clone(), auth))
+}
+
+impl Module {
+    fn run_warmup<F>(
+        module: &'static str,
+        path: &str,
+        warmup_cfg: &WarmupConfig,
+        warmup_fn: F,
+    ) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        use crate::metrics::warmup as warmup_metrics;
+
+        if !warmup_cfg.enabled {
+            tracing::info!(module, path, "skipping warmup (disabled)");
+            warmup_metrics::SKIPPED.inc();
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        tracing::info!(module, path, "starting warmup");
+        let res = warmup_fn();
+        let elapsed = start.elapsed().as_secs_f64();
+
+        match &res {
+            Ok(_) => {
+                warmup_metrics::DURATION.observe(elapsed);
+                warmup_metrics::SUCCESS.inc();
+                tracing::info!(module, path, duration_ms = (elapsed * 1000.0), "warmup completed");
+            }
+            Err(err) => {
+                warmup_metrics::DURATION.observe(elapsed);
+                warmup_metrics::FAILURE.inc();
+                tracing::error!(
+                    module,
+                    path,
+                    duration_ms = (elapsed * 1000.0),
+                    ?err,
+                    "warmup failed"
+                );
+            }
+        }
+        let (path, duration_s, ok) = (path.to_string(), elapsed, res.is_ok());
+        events::emit(events::Event::WarmupDone { module, path, duration_s, ok });
+        res
+    }
+
+    fn new(
+        module_cfg: &ModuleConfig,
+        full_cfg: &Config,
+        dev: &Device,
+        warmup_cfg: &WarmupConfig,
+    ) -> Result<Self> {
+        let auth = module_cfg.auth_policy();
+        let m = match module_cfg {
+            ModuleConfig::Lm { path, config, .. } => {
+                let m = lm::Lm::new(config, full_cfg, dev)?;
+                let m = Arc::new(m);
+                Self::Lm { m, path: path.to_string(), auth }
+            }
+            ModuleConfig::Asr { path, config, .. } => {
+                let m = asr::Asr::new(config, full_cfg, dev)?;
+                let m = Arc::new(m);
+                Self::run_warmup("asr", path, warmup_cfg, || m.warmup())?;
+                Self::Asr { m, path: path.to_string(), auth }
+            }
+            ModuleConfig::Vad { path, config, vad, .. } => {
+                let m = vad::Vad::new(config, vad, full_cfg, dev)?;
+                let m = Arc::new(m);
+                Self::run_warmup("vad", path, warmup_cfg, || m.warmup())?;
+                Self::Vad { m, path: path.to_string(), auth }
+            }
+            ModuleConfig::BatchedAsr { path, config, batch_size, .. } => {
+                let m = batched_asr::BatchedAsr::new(
+                    path,
+                    *batch_size,
+                    config,
+                    full_cfg,
+                    dev,
+                    warmup_cfg.enabled,
+                )?;
+                let m = Arc::new(m);
+                Self::BatchedAsr { m, path: path.to_string(), auth }
+            }
+            ModuleConfig::Tts { path, config, .. } => {
+                let voice = config.voices.keys().next();
+                let m = tts::Model::new(config, full_cfg, dev)?;
+                let m = Arc::new(m);
+                if let Some(voice) = voice {
+                    let voice = voice.clone();
+                    Self::run_warmup("tts", path, warmup_cfg, || {
+                        m.run(&TtsQuery {
+                            text: privacy::Sensitive::new(vec!["hello".to_string()]),
+                            seed: 42,
+                            temperature: 0.8,
+                            top_k: 250,
+                            voice: Some(voice.clone()),
+                            voices: None,
+                            voice_weights: None,
+                            max_seq_len: None,
+                            chunk_max_tokens: None,
+                            return_timestamps: None,
+                            cfg_alpha: None,
+                            input_language: None,
+                            speak_language: None,
+                            channels: None,
+                            pan: None,
+                            speed: None,
+                        })
+                        .and_then(|(wav, _, _)| wav.into_vec())
+                        .map(|_| ())
+                    })?;
+                } else {
+                    tracing::info!(path, "skipping tts warmup (no voices configured)");
+                }
+                if config.voice_previews && warmup_cfg.enabled {
+                    let start = Instant::now();
+                    m.generate_previews(&config.voice_preview_text, VOICE_PREVIEW_S);
+                    tracing::info!(path, elapsed = ?start.elapsed(), "generated voice previews");
+                }
+                Self::Tts { m, path: path.to_string(), auth }
+            }
+            ModuleConfig::Mimi { send_path, recv_path, config, .. } => {
+                let m = mimi::Mimi::new(config, full_cfg, dev)?;
+                let m = Arc::new(m);
+                Self::Mimi {
+                    m,
+                    send_path: send_path.to_string(),
+                    recv_path: recv_path.to_string(),
+                    send_auth: auth,
+                    recv_auth: module_cfg.recv_auth_policy(),
+                }
+            }
+        };
+        let loaded = match &m {
+            Self::Lm { path, .. } => vec![("lm", path)],
+            Self::Asr { path, .. } => vec![("asr", path)],
+            Self::Vad { path, .. } => vec![("vad", path)],
+            Self::BatchedAsr { path, .. } => vec![("batched_asr", path)],
+            Self::Tts { path, .. } => vec![("tts", path)],
+            Self::Mimi { send_path, recv_path, .. } => {
+                vec![("mimi_send", send_path), ("mimi_recv", recv_path)]
+            }
+        };
+        for (module, path) in loaded {
+            events::emit(events::Event::ModuleLoaded { module, path: path.clone() });
+        }
+        Ok(m)
+    }
+
+    fn router(&self, shared_state: &SharedState) -> Result<axum::Router<()>> {
+        let router = match self {
+            Self::Lm { path, auth, m } => lm_router(m.clone(), path, *auth, shared_state),
+            Self::Asr { path, auth, m } => asr_router(m.clone(), path, *auth, shared_state),
+            Self::Vad { path, auth, m } => vad_router(m.clone(), path, *auth, shared_state),
+            Self::BatchedAsr { path, auth, m } => {
+                batched_asr_router(m.clone(), path, *auth, shared_state)
+            }
+            Self::Tts { path, auth, m } => tts_router(m.clone(), path, *auth, shared_state),
+            Self::Mimi { send_path, recv_path, send_auth, recv_auth, m } => {
+                mimi_router(m.clone(), send_path, recv_path, (*send_auth, *recv_auth), shared_state)
+            }
+        };
+        Ok(router)
+    }
+}
+
+struct AppStateInner {
+    modules: Vec<Module>,
+    /// Description of each module of `modules`, in the same order.
+    module_infos: Vec<modules_info::ModuleInfo>,
+    capabilities: capabilities::Capabilities,
+    memory: Arc<memory::MemoryBudget>,
+    bench: BenchConfig,
+}
+
+type AppState = Arc<AppStateInner>;
+
+impl AppStateInner {
+    async fn new(
+        config: Config,
+        memory: Arc<memory::MemoryBudget>,
+        devices: std::collections::HashMap<String, Device>,
+    ) -> Result<Self> {
+        let capabilities = capabilities::Capabilities::new(&config.modules);
+        let mut modules_f = Vec::with_capacity(config.modules.len());
+        let mut module_infos = Vec::with_capacity(config.modules.len());
+        for (name, module_cfg) in config.modules.iter() {
+            module_infos.push(modules_info::ModuleInfo::new(name, module_cfg));
+            let config = config.clone();
+            let device = devices[name].clone();
+            let module_cfg = module_cfg.clone();
+            modules_f.push(tokio::task::spawn_blocking(move || {
+                Module::new(&module_cfg, &config, &device, &config.warmup)
+            }));
+        }
+        let mut modules = Vec::with_capacity(modules_f.len());
+        for m in modules_f {
+            modules.push(m.await??);
+        }
+        Ok(Self { modules, module_infos, capabilities, memory, bench: config.bench })
+    }
+}
+
+async fn metrics(
+    axum::extract::ConnectInfo(_addr): axum::extract::ConnectInfo<std::net::SocketAddr>,
+    _state: axum::extract::State<AppState>,
+    _req: axum::extract::Query<()>,
+) -> impl IntoResponse {
+    use prometheus::Encoder;
+
+    let encoder = prometheus::TextEncoder::new();
+    let metric_families = prometheus::gather();
+    let mut buffer = vec![];
+    if let Err(err) = encoder.encode(&metric_families, &mut buffer) {
+        return (axum::http::StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+    };
+    axum::response::Response::builder()
+        .status(200)
+        .header(axum::http::header::CONTENT_TYPE, encoder.format_type())
+        .body(axum::body::Body::from(buffer))
+        .unwrap()
+}
+
+/// Modules of `config` as announced over mDNS.
+pub fn advertised_modules(config: &Config) -> Vec<mdns::AdvertisedModule> {
+    let mut modules = Vec::new();
+    for (name, cfg) in config.modules.iter() {
+        let mut push = |name: String, kind: &'static str, path: &str| {
+            modules.push(mdns::AdvertisedModule { name, kind, path: path.to_string() })
+        };
+        match cfg {
+            ModuleConfig::Tts { path, .. } => push(name.clone(), "tts", path),
+            ModuleConfig::Asr { path, .. } => push(name.clone(), "asr", path),
+            ModuleConfig::Vad { path, .. } => push(name.clone(), "vad", path),
+            ModuleConfig::BatchedAsr { path, .. } => push(name.clone(), "batched_asr", path),
+            ModuleConfig::Mimi { send_path, recv_path, .. } => {
+                push(format!("{name}.send"), "mimi_send", send_path);
+                push(format!("{name}.recv"), "mimi_recv", recv_path);
+            }
+            ModuleConfig::Lm { path, .. } => push(name.clone(), "lm", path),
+        }
+    }
+    modules.sort_by(|a, b| a.name.cmp(&b.name));
+    modules
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamingOutput {
+    Pcm,
+    PcmMessagePack,
+    OggOpus,
+    OggOpusMessagePack,
+}
+fn default_seed() -> u64 {
+    42
+}
+fn default_temperature() -> f64 {
+    0.8
+}
+fn default_top_k() -> usize {
+    250
+}
+fn default_format() -> StreamingOutput {
+    StreamingOutput::OggOpus
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+struct TtsStreamingQuery {
+    #[serde(default = "default_seed")]
+    seed: u64,
+    #[serde(default = "default_temperature")]
+    temperature: f64,
+    #[serde(default = "default_top_k")]
+    top_k: usize,
+    #[serde(default = "default_format")]
+    format: StreamingOutput,
+    voice: Option<String>,
+    /// Comma-separated list of voices, e.g. `voices=a.wav,b.wav`.
+    #[serde(default, deserialize_with = "utils::comma_separated")]
+    voices: Option<Vec<String>>,
+    /// Comma-separated weights used to interpolate the speaker embeddings of `voices`.
+    #[serde(default, deserialize_with = "utils::comma_separated")]
+    voice_weights: Option<Vec<f32>>,
+    max_seq_len: Option<usize>,
+    cfg_alpha: Option<f64>,
+    /// Language of the text sent by the client, e.g. `de`.
+    input_language: Option<String>,
+    /// Language to speak, the text is translated when it differs from `input_language`.
+    speak_language: Option<String>,
+    /// Send `Envelope` messages with the RMS of the audio over windows of this many
+    /// milliseconds, only for the messagepack formats.
+    envelope_hop_ms: Option<u32>,
+    /// 1 or 2, the stereo samples of the pcm formats are interleaved.
+    channels: Option<u16>,
+    /// From -1 (left) to 1 (right), for stereo outputs. Unset, both channels are the same.
+    pan: Option<f32>,
+    /// Playback speed from 0.5 to 2, the pitch is kept, see [`tts_speed`].
+    speed: Option<f32>,
+    /// JWT token for authentication (alternative to Authorization header)
+    token: Option<String>,
+}
+
+impl TtsStreamingQuery {
+    fn layout(&self) -> Result<tts_channels::ChannelLayout, errors::ApiError> {
+        tts_channels::ChannelLayout::new(self.channels, self.pan)
+    }
+
+    fn speed(&self) -> Result<f32, errors::ApiError> {
+        tts_speed::speed(self.speed)
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+struct TtsQuery {
+    text: privacy::Sensitive<Vec<String>>,
+    seed: u64,
+    temperature: f64,
+    top_k: usize,
+    voice: Option<String>,
+    voices: Option<Vec<String>>,
+    /// Weights used to interpolate the speaker embeddings of `voices`.
+    #[serde(default)]
+    voice_weights: Option<Vec<f32>>,
+    /// Steps of each chunk of the text.
+    max_seq_len: Option<usize>,
+    /// Overrides the `max_tokens` of the chunking config, 0 disables the chunking.
+    #[serde(default)]
+    chunk_max_tokens: Option<usize>,
+    return_timestamps: Option<bool>,
+    cfg_alpha: Option<f64>,
+    input_language: Option<String>,
+    speak_language: Option<String>,
+    /// 1 or 2 channels in the WAV file.
+    #[serde(default)]
+    channels: Option<u16>,
+    /// From -1 (left) to 1 (right), for stereo outputs.
+    #[serde(default)]
+    pan: Option<f32>,
+    /// Playback speed from 0.5 to 2, the pitch is kept.
+    #[serde(default)]
+    speed: Option<f32>,
+}
+
+impl TtsQuery {
+    fn layout(&self) -> Result<tts_channels::ChannelLayout, errors::ApiError> {
+        tts_channels::ChannelLayout::new(self.channels, self.pan)
+    }
+
+    fn speed(&self) -> Result<f32, errors::ApiError> {
+        tts_speed::speed(self.speed)
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+struct TtsResponse {
+    wav: String,
+    transcript: Vec<crate::tts::WordWithTimestamps>,
+    /// Parts of the text generated on their own.
+    #[serde(default)]
+    chunks: Vec<crate::tts::TextChunk>,
+    /// Original and translated text for translated requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    translation: Option<translation::TranslatedText>,
+}
+
+/// Transcript of the multipart and `X-Transcript-Json` TTS responses, see [`tts_multipart`].
+#[derive(serde::Serialize, Debug)]
+struct TtsTranscript {
+    transcript: Vec<crate::tts::WordWithTimestamps>,
+    chunks: Vec<crate::tts::TextChunk>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    translation: Option<translation::TranslatedText>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::warmup as warmup_metrics;
+    use std::sync::{Mutex, OnceLock};
+
+    fn metric_lock() -> std::sync::MutexGuard<'static, ()> {
+        static LOCK: OnceLock<Mutex<()>> = OnceLock::new();
+        LOCK.get_or_init(|| Mutex::new(())).lock().unwrap()
+    }
+
+    #[test]
+    fn warmup_success_increments_success_counter() {
+        let _guard = metric_lock();
+        let before = warmup_metrics::SUCCESS.get();
+        Module::run_warmup(
+            "asr",
+            "/asr",
+            &WarmupConfig { enabled: true },
+            || -> anyhow::Result<()> { Ok(()) },
+        )
+        .unwrap();
+        let after = warmup_metrics::SUCCESS.get();
+        assert!(
+            (after - before - 1.0).abs() < f64::EPSILON,
+            "expected success counter to increment by 1 (before {before}, after {after})"
+        );
+    }
+
+    #[test]
+    fn warmup_failure_increments_failure_counter() {
+        let _guard = metric_lock();
+        let before = warmup_metrics::FAILURE.get();
+        let res = Module::run_warmup(
+            "asr",
+            "/asr",
+            &WarmupConfig { enabled: true },
+            || -> anyhow::Result<()> { anyhow::bail!("boom") },
+        );
+        assert!(res.is_err(), "expected warmup to fail");
+        let after = warmup_metrics::FAILURE.get();
+        assert!(
+            (after - before - 1.0).abs() < f64::EPSILON,
+            "expected failure counter to increment by 1 (before {before}, after {after})"
+        );
+    }
+
+    #[test]
+    fn warmup_skipped_increments_skipped_counter() {
+        let _guard = metric_lock();
+        let before = warmup_metrics::SKIPPED.get();
+        Module::run_warmup(
+            "tts",
+            "/tts",
+            &WarmupConfig { enabled: false },
+            || -> anyhow::Result<()> { Ok(()) },
+        )
+        .unwrap();
+        let after = warmup_metrics::SKIPPED.get();
+        assert!(
+            (after - before - 1.0).abs() < f64::EPSILON,
+            "expected skipped counter to increment by 1 (before {before}, after {after})"
+        );
+    }
+
+    fn mimi_module(extra: &str) -> ModuleConfig {
+        let cfg = format!(
+            "type = \"Mimi\"\nsend_path = \"/send\"\nrecv_path = \"/recv\"\n\
+             audio_tokenizer_file = \"mimi.safetensors\"\nrooms = []\n{extra}"
+        );
+        toml::from_str(&cfg).unwrap()
+    }
+
+    #[test]
+    fn module_auth_policy_defaults() {
+        let m = mimi_module("");
+        assert_eq!(m.auth_policy(), auth::AuthPolicy::Jwt);
+        assert_eq!(m.recv_auth_policy(), auth::AuthPolicy::None);
+        let m = mimi_module("auth_recv = true");
+        assert_eq!(m.recv_auth_policy(), auth::AuthPolicy::Jwt);
+    }
+
+    #[test]
+    fn module_auth_policy_override() {
+        let m = mimi_module("auth = \"api_key\"");
+        assert_eq!(m.auth_policy(), auth::AuthPolicy::ApiKey);
+        assert_eq!(m.recv_auth_policy(), auth::AuthPolicy::ApiKey);
+        let m = mimi_module("auth = \"none\"\nauth_recv = true");
+        assert_eq!(m.recv_auth_policy(), auth::AuthPolicy::None);
+    }
+
+    #[test]
+    fn vad_module_config() {
+        let cfg = include_str!("../../../../../configs/stt/config-stt-en_fr-hf.toml")
+            .replace("type = \"BatchedAsr\"", "type = \"Vad\"\nspeech_threshold = 0.6");
+        let cfg: Config = toml::from_str(&cfg).unwrap();
+        match &cfg.modules["asr"] {
+            ModuleConfig::Vad { path, config, vad, .. } => {
+                assert_eq!(path, "/api/asr-streaming");
+                assert_eq!(config.asr_delay_in_tokens, 6);
+                assert_eq!(vad.vad_head, 2);
+                assert_eq!(vad.speech_threshold, 0.6);
+                assert_eq!(vad.min_silence_s, 0.5);
+            }
+            m => panic!("unexpected module {m:?}"),
+        }
+    }
+
+    #[test]
+    fn tts_streaming_query_voice_mix() {
+        let parse = |q: &str| {
+            let uri: axum::http::Uri = format!("/api/tts_streaming?{q}").parse().unwrap();
+            axum::extract::Query::<TtsStreamingQuery>::try_from_uri(&uri).map(|q| q.0)
+        };
+        let q = parse("voices=a.wav,b.wav%2B1.5&voice_weights=0.7,0.3").unwrap();
+        assert_eq!(q.voices, Some(vec!["a.wav".to_string(), "b.wav+1.5".to_string()]));
+        assert_eq!(q.voice_weights, Some(vec![0.7, 0.3]));
+        let q = parse("voice=a.wav").unwrap();
+        assert!(q.voices.is_none() && q.voice_weights.is_none());
+        assert!(parse("voices=a,b&voice_weights=0.5,x").is_err());
+    }
+
+    #[test]
+    fn tts_streaming_query_channels() {
+        let parse = |q: &str| {
+            let uri: axum::http::Uri = format!("/api/tts_streaming?{q}").parse().unwrap();
+            axum::extract::Query::<TtsStreamingQuery>::try_from_uri(&uri).unwrap().0.layout()
+        };
+        assert_eq!(parse("voice=a.wav").unwrap(), tts_channels::ChannelLayout::Mono);
+        let stereo = tts_channels::ChannelLayout::Stereo { left: 1.0, right: 0.75 };
+        assert_eq!(parse("channels=2&pan=-0.25").unwrap(), stereo);
+        assert!(parse("channels=3").is_err());
+    }
+}
+
+fn tts_router(
+    s: Arc<tts::Model>,
+    path: &str,
+    auth: auth::AuthPolicy,
+    ss: &SharedState,
+) -> axum::Router<()> {
+    use base64::Engine;
+
+    async fn t(
+        state: axum::extract::State<(Arc<tts::Model>, SharedState, auth::AuthPolicy)>,
+        headers: axum::http::HeaderMap,
+        axum::Extension(module): axum::Extension<memory::ModuleKey>,
+        axum::Json(mut req): axum::Json<TtsQuery>,
+    ) -> utils::AxumResult<Response> {
+        tracing::debug!("handling tts query {req:?}");
+        match auth::check_policy(state.0 .2, &headers, None) {
+            Ok(Some(claims)) => {
+                tracing::debug!(user_id = %claims.user.id, session_id = %claims.session.id, "authenticated via JWT");
+            }
+            Ok(None) => {}
+            Err(err) => return Ok(err.into_response()),
+        }
+        if let Err(err) = req.layout().and_then(|_| req.speed()) {
+            return Ok(err.into_response());
+        }
+        let tts = &state.0 .0;
+        let cache = match tts.cache.as_ref() {
+            None => None,
+            Some(cache) => {
+                let key = tts_cache::key(&TtsQuery { return_timestamps: None, ..req.clone() })?;
+                if let Some(hit) = cache.get(&key) {
+                    let wav = spill::Wav::Memory(hit.wav.to_vec());
+                    let transcript = TtsTranscript {
+                        transcript: hit.transcript,
+                        chunks: hit.chunks,
+                        translation: hit.translation,
+                    };
+                    let response = response(&headers, &req, wav, transcript)?;
+                    return Ok(with_cache_status(response, "hit"));
+                }
+                Some((cache, key))
+            }
+        };
+        let languages = (req.input_language.as_deref(), req.speak_language.as_deref());
+        let translation = match tts.translation(languages.0, languages.1) {
+            Ok(t) => t,
+            Err(err) => {
+                let err = errors::ApiError::UnsupportedLanguage(err.to_string());
+                return Ok(err.into_response());
+            }
+        };
+        let translation = match translation {
+            None => None,
+            Some(tr) => {
+                let translated = tr.translate(&req.text).await?;
+                let translated_text = privacy::Sensitive::new(translated.clone());
+                let original = std::mem::replace(&mut req.text, translated_text).into_inner();
+                Some(translation::TranslatedText {
+                    input_language: tr.source,
+                    speak_language: tr.target,
+                    original,
+                    translated,
+                })
+            }
+        };
+        let _admission = match state.0 .1.memory.admit(&module.0) {
+            Ok(admission) => admission,
+            Err(err) => return Ok(errors::ApiError::from(err).into_response()),
+        };
+        let (wav, transcript, chunks) = {
+            let _guard = state.0 .0.mutex.lock().await;
+            state.0 .0.run(&req)?
+        };
+        tracing::debug!("ok {}", wav.len());
+        let transcript = TtsTranscript { transcript, chunks, translation };
+        let Some((cache, key)) = cache else {
+            return Ok(response(&headers, &req, wav, transcript)?);
+        };
+        // Spilled outputs are too large to be kept in memory.
+        if let spill::Wav::Memory(wav) = &wav {
+            let cached = tts_cache::Cached {
+                wav: wav.clone().into(),
+                transcript: transcript.transcript.clone(),
+                chunks: transcript.chunks.clone(),
+                translation: transcript.translation.clone(),
+            };
+            cache.insert(key, cached);
+        }
+        Ok(with_cache_status(response(&headers, &req, wav, transcript)?, "miss"))
+    }
+
+    fn with_cache_status(mut response: Response, status: &'static str) -> Response {
+        let value = axum::http::HeaderValue::from_static(status);
+        response.headers_mut().insert("x-cache", value);
+        response
+    }
+
+    /// Answer with the audio alone, with its transcript, or with a multipart body, depending
+    /// on the request and on its `Accept` header.
+    fn response(
+        headers: &axum::http::HeaderMap,
+        req: &TtsQuery,
+        wav: spill::Wav,
+        transcript: TtsTranscript,
+    ) -> Result<Response> {
+        let multipart = tts_multipart::wants_multipart(headers);
+        let timestamps = req.return_timestamps.unwrap_or(false);
+        if multipart || (timestamps && tts_multipart::wants_wav(headers)) {
+            let json = serde_json::to_vec(&transcript)?;
+            return Ok(if multipart {
+                tts_multipart::multipart(json, wav)
+            } else {
+                tts_multipart::wav_with_transcript(&json, wav)?
+            });
+        }
+        let wav = match wav {
+            spill::Wav::Memory(wav) => wav,
+            wav => {
+                let json = req.return_timestamps.unwrap_or(false);
+                return spilled_response(wav, json.then_some(transcript));
+            }
+        };
+        if req.return_timestamps.unwrap_or(false) {
+            let data = TtsResponse {
+                wav: base64::prelude::BASE64_STANDARD.encode(wav),
+                transcript: transcript.transcript,
+                chunks: transcript.chunks,
+                translation: transcript.translation,
+            };
+            Ok((
+                StatusCode::OK,
+                [(axum::http::header::CONTENT_TYPE, "application/json")],
+                axum::Json(data),
+            )
+                .into_response())
+        } else {
+            Ok((StatusCode::OK, [(axum::http::header::CONTENT_TYPE, "audio/wav")], wav)
+                .into_response())
+        }
+    }
+
+    /// Stream a spilled output from its file, the base64 of the json response is encoded
+    /// chunk by chunk.
+    fn spilled_response(wav: spill::Wav, json: Option<TtsTranscript>) -> Result<Response> {
+        use futures_util::StreamExt;
+
+        let Some(json) = json else {
+            let body = axum::body::Body::from_stream(wav.into_stream(bytes::Bytes::from));
+            return Ok((StatusCode::OK, [(axum::http::header::CONTENT_TYPE, "audio/wav")], body)
+                .into_response());
+        };
+        const HEAD: &str = r#"{"wav":""#;
+        let tail = TtsResponse {
+            wav: String::new(),
+            transcript: json.transcript,
+            chunks: json.chunks,
+            translation: json.translation,
+        };
+        let tail = serde_json::to_string(&tail)?;
+        let tail = match tail.strip_prefix(HEAD) {
+            Some(tail) => bytes::Bytes::from(tail.to_string()),
+            None => anyhow::bail!("unexpected tts response layout {tail}"),
+        };
+        let wav = wav.into_stream(|chunk| base64::prelude::BASE64_STANDARD.encode(chunk).into());
+        let head = bytes::Bytes::from_static(HEAD.as_bytes());
+        let body = futures_util::stream::once(async { Ok(head) })
+            .chain(wav)
+            .chain(futures_util::stream::once(async { Ok(tail) }));
+        Ok((
+            StatusCode::OK,
+            [(axum::http::header::CONTENT_TYPE, "application/json")],
+            axum::body::Body::from_stream(body),
+        )
+            .into_response())
+    }
+
+    #[tracing::instrument(skip(ws, headers, state), fields(client_ip))]
+    async fn streaming_t(
+        ws: axum::extract::ws::WebSocketUpgrade,
+        headers: axum::http::HeaderMap,
+        state: axum::extract::State<(Arc<tts::Model>, SharedState, auth::AuthPolicy)>,
+        axum::Extension(module): axum::Extension<memory::ModuleKey>,
+        req: axum::extract::Query<TtsStreamingQuery>,
+    ) -> utils::AxumResult<Response> {
+        tracing::debug!("handling tts streaming query {req:?}");
+        let addr = headers.get("X-Real-IP").and_then(|v| v.to_str().ok().map(|v| v.to_string()));
+        if let Some(ip) = &addr {
+            tracing::Span::current().record("client_ip", ip);
+        }
+        let auth_result = auth::check_policy(state.0 .2, &headers, req.token.as_deref());
+        if let Err(err) = req.layout().and_then(|_| req.speed()) {
+            return Ok(err.into_response());
+        }
+        let session = otel::Session::new("tts", &headers, &auth_result);
+        let limits = state.0 .1.config.limits.clone();
+        let memory = state.0 .1.memory.clone();
+
+        let tts_query = req.0.clone();
+        let tts = state.0 .0.clone();
+        let upg =
+            limits.ws_upgrade(ws).write_buffer_size(0).protocols(["permessage-deflate"]).on_upgrade(move |mut socket| session.run(async move {
+                match &auth_result {
+                    Err(err) => {
+                        tracing::warn!(?err, "WebSocket auth failed, closing with 4001");
+                        let _ = crate::utils::close_with_reason(
+                            &mut socket,
+                            crate::protocol::CloseCode::AuthenticationFailed,
+                            Some("Authentication failed"),
+                        ).await;
+                        return;
+                    }
+                    Ok(Some(claims)) => {
+                        tracing::debug!(user_id = %claims.user.id, session_id = %claims.session.id, "authenticated via JWT");
+                    }
+                    Ok(None) => {}
+                }
+                let Some(_admission) = memory.admit_socket(&module.0, &mut socket).await else { return };
+                if let Err(err) = tts.handle_socket(limits::LimitedSocket::new(socket, &limits), tts_query).await {
+                    tracing::error!(?err, "tts socket handler failed");
+                }
+            }));
+        Ok(upg)
+    }
+
+    async fn voices(
+        state: axum::extract::State<(Arc<tts::Model>, SharedState, auth::AuthPolicy)>,
+        headers: axum::http::HeaderMap,
+    ) -> utils::AxumResult<Response> {
+        let claims = match auth::check_policy(state.0 .2, &headers, None) {
+            Ok(claims) => claims,
+            Err(err) => return Ok(err.into_response()),
+        };
+        let voices = state.0 .0.voices();
+        let voices = match state.0 .1.admin.as_ref() {
+            None => voices,
+            Some(store) => {
+                let tenant = claims.as_ref().and_then(|c| c.user.tenant.as_deref());
+                match store.voices(tenant.unwrap_or(admin::DEFAULT_TENANT)) {
+                    Ok(stored) => crate::voices::overlay(voices, stored),
+                    Err(err) => {
+                        tracing::error!(?err, "cannot read the stored voices");
+                        voices
+                    }
+                }
+            }
+        };
+        Ok(axum::Json(voices).into_response())
+    }
+
+    #[derive(serde::Deserialize)]
+    struct PreviewQuery {
+        voice: String,
+        /// JWT token for authentication (alternative to Authorization header)
+        token: Option<String>,
+    }
+
+    async fn voice_preview(
+        state: axum::extract::State<(Arc<tts::Model>, SharedState, auth::AuthPolicy)>,
+        headers: axum::http::HeaderMap,
+        req: axum::extract::Query<PreviewQuery>,
+    ) -> utils::AxumResult<Response> {
+        if let Err(err) = auth::check_policy(state.0 .2, &headers, req.token.as_deref()) {
+            return Ok(err.into_response());
+        }
+        match state.0 .0.preview(&req.voice) {
+            Some(wav) => Ok((
+                StatusCode::OK,
+                [
+                    (axum::http::header::CONTENT_TYPE, "audio/wav"),
+                    (axum::http::header::CACHE_CONTROL, "private, max-age=3600"),
+                ],
+                wav,
+            )
+                .into_response()),
+            None => {
+                let msg = format!("no preview for voice '{}'", req.voice);
+                Ok(errors::ApiError::NotFound(msg).into_response())
+            }
+        }
+    }
+
+    /// The job store and job `id`, the caller has to be its submitter.
+    fn job<'a>(
+        state: &'a (Arc<tts::Model>, SharedState, auth::AuthPolicy),
+        headers: &axum::http::HeaderMap,
+        id: &str,
+    ) -> std::result::Result<(&'a tts_jobs::JobStore, tts_jobs::Job), errors::ApiError> {
+        let claims = auth::check_policy(state.2, headers, None)?;
+        let Some(store) = state.0.jobs.as_ref() else {
+            return Err(errors::ApiError::NotFound("tts jobs are disabled".to_string()));
+        };
+        let Some(job) = store.get(id)? else {
+            return Err(errors::ApiError::NotFound(format!("unknown job {id}")));
+        };
+        if job.owner() != claims.as_ref().map(|c| c.user.id.as_str()) {
+            return Err(errors::ApiError::Forbidden(format!("job {id} belongs to another user")));
+        }
+        Ok((store, job))
+    }
+
+    async fn submit_job(
+        state: axum::extract::State<(Arc<tts::Model>, SharedState, auth::AuthPolicy)>,
+        headers: axum::http::HeaderMap,
+        axum::Json(req): axum::Json<TtsQuery>,
+    ) -> utils::AxumResult<Response> {
+        let claims = match auth::check_policy(state.0 .2, &headers, None) {
+            Ok(claims) => claims,
+            Err(err) => return Ok(err.into_response()),
+        };
+        let Some(store) = state.0 .0.jobs.as_ref() else {
+            let err = errors::ApiError::NotFound("tts jobs are disabled".to_string());
+            return Ok(err.into_response());
+        };
+        if req.text.iter().all(|t| t.trim().is_empty()) {
+            let err = errors::ApiError::InvalidRequest("empty text".to_string());
+            return Ok(err.into_response());
+        }
+        let job = store.submit(&req, claims.map(|c| c.user.id))?;
+        Ok((StatusCode::ACCEPTED, axum::Json(job.info())).into_response())
+    }
+
+    async fn job_status(
+        state: axum::extract::State<(Arc<tts::Model>, SharedState, auth::AuthPolicy)>,
+        headers: axum::http::HeaderMap,
+        axum::extract::Path(id): axum::extract::Path<String>,
+    ) -> utils::AxumResult<Response> {
+        match job(&state.0, &headers, &id) {
+            Ok((_, job)) => Ok(axum::Json(job.info()).into_response()),
+            Err(err) => Ok(err.into_response()),
+        }
+    }
+
+    async fn job_chunk(
+        state: axum::extract::State<(Arc<tts::Model>, SharedState, auth::AuthPolicy)>,
+        headers: axum::http::HeaderMap,
+        axum::extract::Path((id, index)): axum::extract::Path<(String, usize)>,
+    ) -> utils::AxumResult<Response> {
+        let (store, job) = match job(&state.0, &headers, &id) {
+            Ok(job) => job,
+            Err(err) => return Ok(err.into_response()),
+        };
+        match store.chunk(&job, index)? {
+            Some(wav) => {
+                Ok((StatusCode::OK, [(axum::http::header::CONTENT_TYPE, "audio/wav")], wav)
+                    .into_response())
+            }
+            None => {
+                let msg = format!("chunk {index} of job {id} is not synthesized");
+                Ok(errors::ApiError::NotFound(msg).into_response())
+            }
+        }
+    }
+
+    let mut router = axum::Router::new()
+        .route(path, axum::routing::post(t))
+        .route(&format!("{path}_streaming"), axum::routing::get(streaming_t))
+        .route(&format!("{path}/voices"), axum::routing::get(voices))
+        .route(&format!("{path}/voices/preview"), axum::routing::get(voice_preview));
+    if s.jobs.is_some() {
+        router = router
+            .route(&format!("{path}/jobs"), axum::routing::post(submit_job))
+            .route(&format!("{path}/jobs/{{id}}"), axum::routing::get(job_status))
+            .route(&format!("{path}/jobs/{{id}}/chunks/{{index}}"), axum::routing::get(job_chunk));
+        tokio::spawn(tts_jobs::run(s.clone()));
+    }
+    let module = memory::ModuleKey(path.to_string());
+    router.layer(axum::Extension(module)).with_state((s, ss.clone(), auth))
+}
+
+/// CRUD endpoints of the documents of [`admin`], `/api/admin/{tenant}/{kind}` lists the
+/// documents of a kind and `/api/admin/{tenant}/{kind}/{name}` reads, writes or deletes one.
+fn admin_router(store: Arc<admin::AdminStore>) -> axum::Router<()> {
+    type AdminState = axum::extract::State<Arc<admin::AdminStore>>;
+    type DocPath = axum::extract::Path<(String, String, String)>;
+
+    /// The kind of document and the id of the admin.
+    fn authorize(
+        headers: &axum::http::HeaderMap,
+        tenant: &str,
+        kind: &str,
+    ) -> std::result::Result<(admin::Kind, Option<String>), errors::ApiError> {
+        let claims = auth::check_policy(auth::AuthPolicy::Admin, headers, None)?;
+        admin::authorize(claims.as_ref(), tenant)?;
+        match admin::Kind::parse(kind) {
+            Some(kind) => Ok((kind, claims.map(|c| c.user.id))),
+            None => Err(errors::ApiError::NotFound(format!("unknown document kind {kind}"))),
+        }
+    }
+
+    fn api_error(err: anyhow::Error) -> utils::AxumResult<Response> {
+        Ok(errors::ApiError::from(err).into_response())
+    }
+
+    async fn list(
+        headers: axum::http::HeaderMap,
+        state: AdminState,
+        axum::extract::Path((tenant, kind)): axum::extract::Path<(String, String)>,
+    ) -> utils::AxumResult<Response> {
+        let (kind, _) = match authorize(&headers, &tenant, &kind) {
+            Ok(auth) => auth,
+            Err(err) => return Ok(err.into_response()),
+        };
+        match state.0.list(&tenant, kind) {
+            Ok(docs) => Ok(axum::Json(docs).into_response()),
+            Err(err) => api_error(err),
+        }
+    }
+
+    async fn get_doc(
+        headers: axum::http::HeaderMap,
+        state: AdminState,
+        axum::extract::Path((tenant, kind, name)): DocPath,
+    ) -> utils::AxumResult<Response> {
+        let (kind, _) = match authorize(&headers, &tenant, &kind) {
+            Ok(auth) => auth,
+            Err(err) => return Ok(err.into_response()),
+        };
+        match state.0.get(&tenant, kind, &name) {
+            Ok(Some(doc)) => Ok(axum::Json(doc).into_response()),
+            Ok(None) => Ok(errors::ApiError::NotFound(format!("unknown {} {name}", kind.as_str()))
+                .into_response()),
+            Err(err) => api_error(err),
+        }
+    }
+
+    async fn put_doc(
+        headers: axum::http::HeaderMap,
+        state: AdminState,
+        axum::extract::Path((tenant, kind, name)): DocPath,
+        axum::Json(doc): axum::Json<serde_json::Value>,
+    ) -> utils::AxumResult<Response> {
+        let (kind, user_id) = match authorize(&headers, &tenant, &kind) {
+            Ok(auth) => auth,
+            Err(err) => return Ok(err.into_response()),
+        };
+        match state.0.put(&tenant, kind, &name, doc) {
+            Ok(doc) => {
+                tracing::info!(
+                    tenant,
+                    kind = kind.as_str(),
+                    name,
+                    ?user_id,
+                    "admin document saved"
+                );
+                Ok(axum::Json(doc).into_response())
+            }
+            Err(err) => api_error(err),
+        }
+    }
+
+    async fn delete_doc(
+        headers: axum::http::HeaderMap,
+        state: AdminState,
+        axum::extract::Path((tenant, kind, name)): DocPath,
+    ) -> utils::AxumResult<Response> {
+        let (kind, user_id) = match authorize(&headers, &tenant, &kind) {
+            Ok(auth) => auth,
+            Err(err) => return Ok(err.into_response()),
+        };
+        match state.0.delete(&tenant, kind, &name) {
+            Ok(true) => {
+                tracing::info!(
+                    tenant,
+                    kind = kind.as_str(),
+                    name,
+                    ?user_id,
+                    "admin document deleted"
+                );
+                Ok(StatusCode::NO_CONTENT.into_response())
+            }
+            Ok(false) => {
+                Ok(errors::ApiError::NotFound(format!("unknown {} {name}", kind.as_str()))
+                    .into_response())
+            }
+            Err(err) => api_error(err),
+        }
+    }
+
+    axum::Router::new()
+        .route("/api/admin/{tenant}/{kind}", axum::routing::get(list))
+        .route(
+            "/api/admin/{tenant}/{kind}/{*name}",
+            axum::routing::get(get_doc).put(put_doc).delete(delete_doc),
+        )
+        .with_state(store)
+}
+
+async fn build_info(
+    axum::extract::ConnectInfo(_addr): axum::extract::ConnectInfo<std::net::SocketAddr>,
+    _state: axum::extract::State<AppState>,
+    _req: axum::extract::Query<()>,
+) -> impl IntoResponse {
+    let build_info = utils::BuildInfo::new();
+    utils::WrapJson(Ok(build_info)).into_response()
+}
+
+// ============================================================================
+// Server Status Endpoint
+// ============================================================================
+
+/// Response structure for /api/status endpoint
+#[derive(serde::Serialize, Debug)]
+struct StatusResponse {
+    /// Server status: "healthy", "degraded", or "unhealthy"
+    status: &'static str,
+    /// Server uptime in seconds
+    uptime_seconds: u64,
+    /// ISO 8601 timestamp when server started
+    started_at: String,
+    /// Build information
+    build: utils::BuildInfo,
+    /// Module capacity information
+    capacity: CapacityInfo,
+    /// VRAM budget committed by the modules and their streams, admins only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memory: Option<memory::MemoryReport>,
+    /// Authentication configuration (without secrets), admins only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth: Option<AuthInfo>,
+}
+
+/// Capacity information for all modules
+#[derive(serde::Serialize, Debug)]
+struct CapacityInfo {
+    /// Total slots across all batched modules
+    total_slots: usize,
+    /// Used slots across all batched modules
+    used_slots: usize,
+    /// Available slots (total - used)
+    available_slots: usize,
+    /// Per-module breakdown
+    modules: Vec<ModuleCapacity>,
+}
+
+/// Capacity information for a single module
+#[derive(serde::Serialize, Debug)]
+struct ModuleCapacity {
+    /// Module name/path
+    name: String,
+    /// Module type (batched_asr, py_batched_asr, py)
+    module_type: &'static str,
+    /// Total slots for this module
+    total_slots: usize,
+    /// Used slots for this module
+    used_slots: usize,
+    /// Available slots for this module
+    available_slots: usize,
+}
+
+/// Authentication configuration (without secrets)
+#[derive(serde::Serialize, Debug)]
+struct AuthInfo {
+    /// Whether API key auth is configured
+    api_key_configured: bool,
+    /// Whether Better Auth JWT validation is enabled
+    better_auth_enabled: bool,
+}
+
+/// Global server start time (set once at startup)
+static SERVER_START_TIME: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+static SERVER_START_TIMESTAMP: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+
+/// Initialize server start time (call once at startup)
+fn init_server_start_time() {
+    SERVER_START_TIME.get_or_init(std::time::Instant::now);
+    SERVER_START_TIMESTAMP
+        .get_or_init(|| chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+}
+
+/// Get server uptime in seconds
+fn get_uptime_seconds() -> u64 {
+    SERVER_START_TIME.get().map(|start| start.elapsed().as_secs()).unwrap_or(0)
+}
+
+fn spawn_metrics_updater(memory: Arc<memory::MemoryBudget>) {
+    utils::spawn("metrics_updater", async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+
+            if let Ok(info) = utils::get_gpu_info() {
+                use crate::metrics::system;
+                system::FREE_VRAM.set(info.free_vram as f64);
+                system::TOTAL_VRAM.set(info.total_vram as f64);
+                system::USED_VRAM.set((info.total_vram.saturating_sub(info.free_vram)) as f64);
+                system::GPU_UTILIZATION.set(info.utilization as f64);
+                let used = info.total_vram.saturating_sub(info.free_vram) as f64;
+                let unattributed = used - memory.committed_bytes() as f64;
+                crate::metrics::memory::UNATTRIBUTED_VRAM_BYTES.set(unattributed);
+            }
+        }
+    });
+}
+
+async fn server_status(
+    axum::extract::ConnectInfo(_addr): axum::extract::ConnectInfo<std::net::SocketAddr>,
+    state: axum::extract::State<AppState>,
+    headers: axum::http::HeaderMap,
+    req: axum::extract::Query<InfoQuery>,
+) -> impl IntoResponse {
+    let full = modules_info::Detail::of_request(&headers, req.token.as_deref())
+        == modules_info::Detail::Full;
+    // Collect capacity info from all modules
+    let mut total_slots = 0usize;
+    let mut used_slots = 0usize;
+    let mut modules = Vec::new();
+
+    for module in state.modules.iter() {
+        if let Module::BatchedAsr { path, m, .. } = module {
+            let t = m.total_slots();
+            let u = m.used_slots();
+            total_slots += t;
+            used_slots += u;
+            modules.push(ModuleCapacity {
+                name: path.clone(),
+                module_type: "batched_asr",
+                total_slots: t,
+                used_slots: u,
+                available_slots: t.saturating_sub(u),
+            });
+        }
+    }
+
+    let available_slots = total_slots.saturating_sub(used_slots);
+
+    // Determine overall status
+    let status = if available_slots == 0 && total_slots > 0 {
+        "degraded" // At capacity
+    } else {
+        "healthy"
+    };
+
+    let response = StatusResponse {
+        status,
+        uptime_seconds: get_uptime_seconds(),
+        started_at: SERVER_START_TIMESTAMP.get().cloned().unwrap_or_else(|| "unknown".to_string()),
+        build: utils::BuildInfo::new(),
+        capacity: CapacityInfo { total_slots, used_slots, available_slots, modules },
+        memory: full.then(|| state.memory.report()),
+        auth: full.then(|| AuthInfo {
+            api_key_configured: std::env::var("MOSHI_API_KEY").is_ok(),
+            better_auth_enabled: std::env::var("BETTER_AUTH_SECRET").is_ok(),
+        }),
+    };
+
+    utils::WrapJson(Ok(response)).into_response()
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct BenchLatencyQuery {
+    /// Seconds of synthetic audio to stream, 5 by default.
+    seconds: Option<f64>,
+    /// Path of the module to benchmark, the first batched ASR module by default.
+    module: Option<String>,
+    token: Option<String>,
+}
+
+/// `POST <path>/config` of the ASR modules, answers with the settings now in use.
+fn retune_response(
+    ss: &SharedState,
+    headers: &axum::http::HeaderMap,
+    body: &[u8],
+    retune: impl FnOnce(&retune::RetuneQuery) -> Result<retune::Tuning, String>,
+) -> Response {
+    if let Err(err) = auth::check_policy(ss.config.retune.auth, headers, None) {
+        return err.into_response();
+    }
+    let query = match serde_json::from_slice(body) {
+        Ok(query) => query,
+        Err(err) => return errors::ApiError::InvalidRequest(err.to_string()).into_response(),
+    };
+    match retune(&query) {
+        Ok(tuning) => axum::Json(tuning).into_response(),
+        Err(err) => errors::ApiError::InvalidRequest(err).into_response(),
+    }
+}
+
+/// Run a short self-benchmark on an idle batched ASR slot so that monitoring can track the
+/// inference latency without relying on user traffic.
+async fn bench_latency(
+    state: axum::extract::State<AppState>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Query(req): axum::extract::Query<BenchLatencyQuery>,
+) -> utils::AxumResult<Response> {
+    if let Err(err) = auth::check_policy(state.bench.auth, &headers, req.token.as_deref()) {
+        return Ok(err.into_response());
+    }
+    let seconds = req.seconds.unwrap_or(5.0);
+    if !seconds.is_finite() || seconds <= 0.0 || seconds > state.bench.max_seconds {
+        let msg = format!("seconds must be in (0, {}]", state.bench.max_seconds);
+        return Ok(errors::ApiError::InvalidRequest(msg).into_response());
+    }
+    let target = state.modules.iter().find_map(|m| match m {
+        Module::BatchedAsr { path, m, .. } if req.module.as_ref().is_none_or(|p| p == path) => {
+            Some((path, m))
+        }
+        _ => None,
+    });
+    let Some((path, m)) = target else {
+        let msg = "no batched asr module to benchmark".to_string();
+        return Ok(errors::ApiError::NotFound(msg).into_response());
+    };
+    match m.bench_latency(path, seconds).await? {
+        Some(report) => Ok(utils::WrapJson(Ok(report)).into_response()),
+        None => Ok(errors::ApiError::ModelBusy("no idle slot".to_string()).into_response()),
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct EventsQuery {
+    token: Option<String>,
+}
+
+/// Server events as server-sent events, starting with the startup events and the capacity of
+/// the batched asr modules.
+async fn server_events(
+    state: axum::extract::State<AppState>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Query(req): axum::extract::Query<EventsQuery>,
+) -> Response {
+    if let Err(err) = auth::check_policy(auth::AuthPolicy::Admin, &headers, req.token.as_deref()) {
+        return err.into_response();
+    }
+    let rx = events::subscribe();
+    let snapshot = state
+        .modules
+        .iter()
+        .filter_map(|m| match m {
+            Module::BatchedAsr { path, m, .. } => Some(events::Event::Capacity {
+                path: path.clone(),
+                total_slots: m.total_slots(),
+                free_slots: m.free_slots(),
+            }),
+            _ => None,
+        })
+        .collect();
+    events::sse(rx, snapshot).into_response()
+}
+
+/// Simple health check endpoint returning JSON
+async fn health_check() -> impl IntoResponse {
+    #[derive(serde::Serialize)]
+    struct HealthResponse {
+        status: &'static str,
+        uptime_seconds: u64,
+    }
+
+    axum::Json(HealthResponse { status: "ok", uptime_seconds: get_uptime_seconds() })
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct InfoQuery {
+    /// Admin JWT for the full detail (alternative to Authorization header)
+    token: Option<String>,
+}
+
+/// Modules of the server, with their config for the admins only.
+async fn modules_info(
+    axum::extract::ConnectInfo(_addr): axum::extract::ConnectInfo<std::net::SocketAddr>,
+    state: axum::extract::State<AppState>,
+    headers: axum::http::HeaderMap,
+    req: axum::extract::Query<InfoQuery>,
+) -> impl IntoResponse {
+    let detail = modules_info::Detail::of_request(&headers, req.token.as_deref());
+    let modules: Vec<_> = state
+        .modules
+        .iter()
+        .zip(state.module_infos.iter())
+        .map(|(m, info)| {
+            let mut info = info.redact(detail);
+            if let Module::BatchedAsr { m, .. } = m {
+                info.slots =
+                    Some(modules_info::Slots { total: m.total_slots(), used: m.used_slots() });
+            }
+            info
+        })
+        .collect();
+    utils::WrapJson(Ok(modules)).into_response()
+}
+
+/// Protocol features of the modules, for the clients to check before using them.
+async fn capabilities(state: axum::extract::State<AppState>) -> impl IntoResponse {
+    utils::WrapJson(Ok(state.capabilities.clone())).into_response()
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+struct AsrStreamingQuery {
+    /// JWT token for authentication (alternative to Authorization header)
+    token: Option<String>,
+    /// Logical stream name, reconnecting with the same name within the grace period resumes
+    /// the previous model state (batched asr only).
+    stream_id: Option<String>,
+    /// Segment length in seconds, overrides the `segment_s` of the config, 0 disables
+    /// segmentation (batched asr only).
+    segment_s: Option<f64>,
+    /// Send `AudioStats` and `SpeechRate` messages every this many seconds of audio.
+    stats_interval_s: Option<f64>,
+    /// Send `Overlap` messages every this many seconds of audio, and a `SpeakerCountEstimate`
+    /// before answering each `Marker`.
+    speakers_interval_s: Option<f64>,
+    /// Casing of the words, `raw` (default), `lower` or `sentences`.
+    formatting: Option<formatting::Formatting>,
+    /// Write the spelled out numbers with digits, `twenty three dollars` becomes `$23`, see
+    /// [`itn`].
+    itn: Option<bool>,
+    /// Phrases to spot in the words, a JSON list or comma separated, see [`spotting`].
+    #[serde(default, deserialize_with = "spotting::deserialize_phrases")]
+    spot: Option<Vec<String>>,
+    /// `tokens` adds the sub-word pieces of each word with their times.
+    detail: Option<asr::Detail>,
+    /// Send the messages produced within this many milliseconds, up to 100, in a single
+    /// frame holding a msgpack array of them, see [`coalesce`].
+    coalesce_ms: Option<u64>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+struct VadStreamingQuery {
+    /// JWT token for authentication (alternative to Authorization header)
+    token: Option<String>,
+}
+
+fn asr_router(
+    s: Arc<asr::Asr>,
+    path: &str,
+    auth: auth::AuthPolicy,
+    ss: &SharedState,
+) -> axum::Router<()> {
+    async fn asr_websocket(
+        socket: limits::LimitedSocket,
+        state: Arc<asr::Asr>,
+        query: AsrStreamingQuery,
+        _addr: Option<String>,
+    ) {
+        if let Err(err) = state.handle_socket(socket, query).await {
+            tracing::error!(?err, "asr")
+        }
+    }
+
+    async fn health() -> impl IntoResponse {
+        StatusCode::OK
+    }
+
+    async fn config(
+        state: axum::extract::State<(Arc<asr::Asr>, SharedState, auth::AuthPolicy)>,
+        headers: axum::http::HeaderMap,
+        body: axum::body::Bytes,
+    ) -> Response {
+        retune_response(&state.0 .1, &headers, &body, |q| state.0 .0.retune(q))
+    }
+
+    #[tracing::instrument(skip(ws, headers, state), fields(client_ip))]
+    async fn t(
+        ws: axum::extract::ws::WebSocketUpgrade,
+        headers: axum::http::HeaderMap,
+        state: axum::extract::State<(Arc<asr::Asr>, SharedState, auth::AuthPolicy)>,
+        axum::Extension(module): axum::Extension<memory::ModuleKey>,
+        req: axum::extract::Query<AsrStreamingQuery>,
+    ) -> utils::AxumResult<axum::response::Response> {
+        let addr = headers.get("X-Real-IP").and_then(|v| v.to_str().ok().map(|v| v.to_string()));
+        if let Some(ip) = &addr {
+            tracing::Span::current().record("client_ip", ip);
+        }
+        tracing::info!("handling asr-streaming query");
+        let auth_result = auth::check_policy(state.0 .2, &headers, req.token.as_deref());
+        let session = otel::Session::new("asr", &headers, &auth_result);
+        let trace = session.trace_ids().clone();
+        let session_auth = auth_result
+            .as_ref()
+            .ok()
+            .and_then(|claims| auth::SessionAuth::new(state.0 .2, claims.as_ref()));
+        let limits = state.0 .1.config.limits.clone();
+        let memory = state.0 .1.memory.clone();
+
+        let asr_query = req.0.clone();
+        let asr = state.0 .0.clone();
+        let upg = limits
+            .ws_upgrade(ws)
+            .write_buffer_size(0)
+            .protocols(["permessage-deflate"])
+            .on_upgrade(move |mut socket| {
+                session.run(async move {
+                    if let Err(err) = auth_result {
+                        tracing::warn!(?err, "WebSocket auth failed, closing with 4001");
+                        let _ = crate::utils::close_with_reason(
+                            &mut socket,
+                            crate::protocol::CloseCode::AuthenticationFailed,
+                            Some("Authentication failed"),
+                        )
+                        .await;
+                        return;
+                    }
+                    let Some(_admission) = memory.admit_socket(&module.0, &mut socket).await else {
+                        return;
+                    };
+                    let socket = limits::LimitedSocket::new(socket, &limits)
+                        .with_auth(session_auth)
+                        .with_trace(trace);
+                    asr_websocket(socket, asr, asr_query, addr).await
+                })
+            });
+        Ok(upg)
+    }
+    axum::Router::new()
+        .route(path, axum::routing::get(t))
+        .route(&format!("{path}/health"), axum::routing::get(health))
+        .route(&format!("{path}/config"), axum::routing::post(config))
+        .layer(axum::Extension(memory::ModuleKey(path.to_string())))
+        .with_state((s, ss.clone(), auth))
+}
+
+fn vad_router(
+    s: Arc<vad::Vad>,
+    path: &str,
+    auth: auth::AuthPolicy,
+    ss: &SharedState,
+) -> axum::Router<()> {
+    #[tracing::instrument(skip(ws, headers, state), fields(client_ip))]
+    async fn t(
+        ws: axum::extract::ws::WebSocketUpgrade,
+        headers: axum::http::HeaderMap,
+        state: axum::extract::State<(Arc<vad::Vad>, SharedState, auth::AuthPolicy)>,
+        axum::Extension(module): axum::Extension<memory::ModuleKey>,
+        req: axum::extract::Query<VadStreamingQuery>,
+    ) -> utils::AxumResult<axum::response::Response> {
+        let addr = headers.get("X-Real-IP").and_then(|v| v.to_str().ok().map(|v| v.to_string()));
+        if let Some(ip) = &addr {
+            tracing::Span::current().record("client_ip", ip);
+        }
+        tracing::info!("handling vad query");
+        let auth_result = auth::check_policy(state.0 .2, &headers, req.token.as_deref());
+        let session = otel::Session::new("vad", &headers, &auth_result);
+        let trace = session.trace_ids().clone();
+        let session_auth = auth_result
+            .as_ref()
+            .ok()
+            .and_then(|claims| auth::SessionAuth::new(state.0 .2, claims.as_ref()));
+        let limits = state.0 .1.config.limits.clone();
+        let memory = state.0 .1.memory.clone();
+        let vad = state.0 .0.clone();
+        let upg = limits
+            .ws_upgrade(ws)
+            .write_buffer_size(0)
+            .protocols(["permessage-deflate"])
+            .on_upgrade(move |mut socket| {
+                session.run(async move {
+                    if let Err(err) = auth_result {
+                        tracing::warn!(?err, "WebSocket auth failed, closing with 4001");
+                        let _ = crate::utils::close_with_reason(
+                            &mut socket,
+                            crate::protocol::CloseCode::AuthenticationFailed,
+                            Some("Authentication failed"),
+                        )
+                        .await;
+                        return;
+                    }
+                    let Some(_admission) = memory.admit_socket(&module.0, &mut socket).await else {
+                        return;
+                    };
+                    let socket = limits::LimitedSocket::new(socket, &limits)
+                        .with_auth(session_auth)
+                        .with_trace(trace);
+                    if let Err(err) = vad.handle_socket(socket).await {
+                        tracing::error!(?err, "vad")
+                    }
+                })
+            });
+        Ok(upg)
+    }
+    axum::Router::new()
+        .route(path, axum::routing::get(t))
+        .layer(axum::Extension(memory::ModuleKey(path.to_string())))
+        .with_state((s, ss.clone(), auth))
+}
+
+fn batched_asr_router(
+    s: Arc<batched_asr::BatchedAsr>,
+    path: &str,
+    auth: auth::AuthPolicy,
+    ss: &SharedState,
+) -> axum::Router<()> {
+    async fn asr_websocket(
+        socket: limits::LimitedSocket,
+        state: Arc<batched_asr::BatchedAsr>,
+        query: AsrStreamingQuery,
+        _addr: Option<String>,
+    ) {
+        if let Err(err) = state.handle_socket(socket, query).await {
+            tracing::error!(?err, "asr")
+        }
+    }
+
+    async fn health() -> impl IntoResponse {
+        StatusCode::OK
+    }
+
+    // TODO: add a batch mode.
+    /// The body is either the audio file or a JSON `{"url": ..., "format": ...}` pointing
+    /// at it.
+    async fn t(
+        state: axum::extract::State<(Arc<batched_asr::BatchedAsr>, SharedState, auth::AuthPolicy)>,
+        headers: axum::http::HeaderMap,
+        req: axum::body::Bytes,
+    ) -> utils::AxumResult<Response> {
+        tracing::info!(len = req.len(), "handling asr post query");
+        if let Err(err) = auth::check_policy(state.0 .2, &headers, None) {
+            return Ok(err.into_response());
+        }
+        let is_json = headers
+            .get(axum::http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("application/json"));
+        let (audio, format) = if is_json {
+            let query: remote_audio::UrlQuery = match serde_json::from_slice(&req) {
+                Ok(query) => query,
+                Err(err) => {
+                    return Ok(errors::ApiError::InvalidRequest(err.to_string()).into_response())
+                }
+            };
+            let limits = &state.0 .1.config.limits;
+            let timeout = std::time::Duration::from_secs_f64(limits.fetch_timeout_s);
+            tracing::info!(url = query.url, "fetching remote audio");
+            let allow_private = limits.fetch_private_hosts;
+            match remote_audio::fetch(&query.url, limits.max_fetch_bytes, timeout, allow_private)
+                .await
+            {
+                Ok(audio) => (audio, query.format),
+                Err(err) => {
+                    tracing::warn!(%err, url = query.url, "cannot fetch remote audio");
+                    return Ok(errors::ApiError::from(err).into_response());
+                }
+            }
+        } else {
+            (req, remote_audio::AudioFormat::Auto)
+        };
+        let transcript = state.0 .0.handle_query(audio, format).await?;
+        Ok((
+            StatusCode::OK,
+            [(axum::http::header::CONTENT_TYPE, "application/json")],
+            axum::Json(transcript),
+        )
+            .into_response())
+    }
+
+    #[tracing::instrument(skip(ws, headers, state), fields(client_ip))]
+    async fn streaming_t(
+        ws: axum::extract::ws::WebSocketUpgrade,
+        headers: axum::http::HeaderMap,
+        state: axum::extract::State<(Arc<batched_asr::BatchedAsr>, SharedState, auth::AuthPolicy)>,
+        req: axum::extract::Query<AsrStreamingQuery>,
+    ) -> utils::AxumResult<axum::response::Response> {
+        let addr = headers.get("X-Real-IP").and_then(|v| v.to_str().ok().map(|v| v.to_string()));
+        if let Some(ip) = &addr {
+            tracing::Span::current().record("client_ip", ip);
+        }
+        tracing::info!("handling batched asr-streaming query");
+        let auth_result = auth::check_policy(state.0 .2, &headers, req.token.as_deref());
+        let session = otel::Session::new("batched_asr", &headers, &auth_result);
+        let trace = session.trace_ids().clone();
+        let session_auth = auth_result
+            .as_ref()
+            .ok()
+            .and_then(|claims| auth::SessionAuth::new(state.0 .2, claims.as_ref()));
+        let limits = state.0 .1.config.limits.clone();
+
+        let asr_query = req.0.clone();
+        let asr = state.0 .0.clone();
+        let upg = limits
+            .ws_upgrade(ws)
+            .write_buffer_size(0)
+            .protocols(["permessage-deflate"])
+            .on_upgrade(move |mut socket| {
+                session.run(async move {
+                    if let Err(err) = auth_result {
+                        tracing::warn!(?err, "WebSocket auth failed, closing with 4001");
+                        let _ = crate::utils::close_with_reason(
+                            &mut socket,
+                            crate::protocol::CloseCode::AuthenticationFailed,
+                            Some("Authentication failed"),
+                        )
+                        .await;
+                        return;
+                    }
+                    let socket = limits::LimitedSocket::new(socket, &limits)
+                        .with_auth(session_auth)
+                        .with_trace(trace);
+                    asr_websocket(socket, asr, asr_query, addr).await
+                })
+            });
+        Ok(upg)
+    }
+
+    type PollState =
+        axum::extract::State<(Arc<batched_asr::BatchedAsr>, SharedState, auth::AuthPolicy)>;
+
+    async fn config(
+        state: PollState,
+        headers: axum::http::HeaderMap,
+        body: axum::body::Bytes,
+    ) -> Response {
+        retune_response(&state.0 .1, &headers, &body, |q| state.0 .0.retune(q))
+    }
+
+    /// Identity of the caller, a session can only be used by the client that opened it.
+    fn poll_owner(
+        state: &PollState,
+        headers: &axum::http::HeaderMap,
+        token: Option<&str>,
+    ) -> std::result::Result<long_poll::Owner, auth::AuthError> {
+        let claims = auth::check_policy(state.0 .2, headers, token)?;
+        Ok(match (claims, state.0 .2) {
+            (Some(claims), _) => long_poll::Owner::User(claims.user.id),
+            (None, auth::AuthPolicy::None) => long_poll::Owner::Anonymous,
+            (None, _) => long_poll::Owner::ApiKey,
+        })
+    }
+
+    async fn poll_open(
+        state: PollState,
+        headers: axum::http::HeaderMap,
+        req: axum::extract::Query<PollQuery>,
+    ) -> utils::AxumResult<Response> {
+        let owner = match poll_owner(&state, &headers, req.token.as_deref()) {
+            Ok(owner) => owner,
+            Err(err) => return Ok(err.into_response()),
+        };
+        match state.0 .0.poll_open(owner)? {
+            Some(session_id) => {
+                Ok(axum::Json(serde_json::json!({ "session_id": session_id })).into_response())
+            }
+            None => {
+                let err = errors::ApiError::ModelBusy("no free channels".to_string());
+                Ok(err.into_response())
+            }
+        }
+    }
+
+    async fn poll_push(
+        state: PollState,
+        headers: axum::http::HeaderMap,
+        axum::extract::Path(session_id): axum::extract::Path<String>,
+        req: axum::extract::Query<PollQuery>,
+        body: axum::body::Bytes,
+    ) -> utils::AxumResult<Response> {
+        let owner = match poll_owner(&state, &headers, req.token.as_deref()) {
+            Ok(owner) => owner,
+            Err(err) => return Ok(err.into_response()),
+        };
+        let limits = &state.0 .1.config.limits;
+        match state.0 .0.poll_sessions().push(&session_id, &owner, &body, limits) {
+            Ok(()) => Ok(StatusCode::NO_CONTENT.into_response()),
+            Err(err) => Ok(errors::ApiError::from(err).into_response()),
+        }
+    }
+
+    async fn poll_recv(
+        state: PollState,
+        headers: axum::http::HeaderMap,
+        axum::extract::Path(session_id): axum::extract::Path<String>,
+        req: axum::extract::Query<PollQuery>,
+    ) -> utils::AxumResult<Response> {
+        let owner = match poll_owner(&state, &headers, req.token.as_deref()) {
+            Ok(owner) => owner,
+            Err(err) => return Ok(err.into_response()),
+        };
+        let wait = std::time::Duration::from_millis(req.wait_ms.unwrap_or(20_000));
+        match state.0 .0.poll_sessions().poll(&session_id, &owner, wait).await {
+            Ok(msgs) => Ok(axum::Json(msgs).into_response()),
+            Err(err) => Ok(errors::ApiError::from(err).into_response()),
+        }
+    }
+
+    async fn poll_close(
+        state: PollState,
+        headers: axum::http::HeaderMap,
+        axum::extract::Path(session_id): axum::extract::Path<String>,
+        req: axum::extract::Query<PollQuery>,
+    ) -> utils::AxumResult<Response> {
+        let owner = match poll_owner(&state, &headers, req.token.as_deref()) {
+            Ok(owner) => owner,
+            Err(err) => return Ok(err.into_response()),
+        };
+        match state.0 .0.poll_sessions().close(&session_id, &owner) {
+            Ok(()) => Ok(StatusCode::NO_CONTENT.into_response()),
+            Err(err) => Ok(errors::ApiError::from(err).into_response()),
+        }
+    }
+
+    let sessions = s.poll_sessions().clone();
+    tokio::spawn(async move {
+        let (min, max) = (std::time::Duration::from_secs(1), std::time::Duration::from_secs(10));
+        let period = sessions.ttl().clamp(min, max);
+        let mut ticker = tokio::time::interval(period);
+        loop {
+            ticker.tick().await;
+            let expired = sessions.expire();
+            if expired > 0 {
+                tracing::info!(expired, "long-poll sessions expired");
+            }
+        }
+    });
+    axum::Router::new()
+        .route(path, axum::routing::post(t))
+        .route(path, axum::routing::get(streaming_t))
+        .route(&format!("{path}/health"), axum::routing::get(health))
+        .route(&format!("{path}/config"), axum::routing::post(config))
+        .route(&format!("{path}/poll"), axum::routing::post(poll_open))
+        .route(
+            &format!("{path}/poll/{{session_id}}"),
+            axum::routing::get(poll_recv).post(poll_push).delete(poll_close),
+        )
+        .with_state((s, ss.clone(), auth))
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+struct PollQuery {
+    /// JWT token for authentication (alternative to Authorization header)
+    token: Option<String>,
+    /// How long a poll waits for output messages, capped at 60s.
+    wait_ms: Option<u64>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+struct LmStreamingQuery {
+    token: Option<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+struct MimiStreamingQuery {
+    /// JWT token for authentication (alternative to Authorization header)
+    token: Option<String>,
+    room_id: Option<String>,
+    /// Format of the audio streamed to listeners, ignored for producers.
+    #[serde(default)]
+    format: mimi::RecvFormat,
+    /// Name of the producer in the room mix, ignored for listeners.
+    publisher: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+struct MimiPublishersQuery {
+    room_id: Option<String>,
+}
+
+fn mimi_router(
+    s: Arc<mimi::Mimi>,
+    send_path: &str,
+    recv_path: &str,
+    auth: (auth::AuthPolicy, auth::AuthPolicy),
+    ss: &SharedState,
+) -> axum::Router<()> {
+    async fn mimi_recv_websocket(
+        socket: limits::LimitedSocket,
+        state: Arc<mimi::Mimi>,
+        room_id: String,
+        format: mimi::RecvFormat,
+        _addr: Option<String>,
+    ) {
+        if let Err(err) = state.recv_socket(socket, room_id, format).await {
+            tracing::error!(?err, "mimi")
+        }
+    }
+
+    async fn recv(
+        ws: axum::extract::ws::WebSocketUpgrade,
+        headers: axum::http::HeaderMap,
+        state: axum::extract::State<(
+            Arc<mimi::Mimi>,
+            SharedState,
+            (auth::AuthPolicy, auth::AuthPolicy),
+        )>,
+        req: axum::extract::Query<MimiStreamingQuery>,
+    ) -> utils::AxumResult<axum::response::Response> {
+        let addr = headers.get("X-Real-IP").and_then(|v| v.to_str().ok().map(|v| v.to_string()));
+        tracing::info!(addr, "handling mimi-streaming query");
+        // It's tricky to set the headers of a websocket in javascript so we pass the token via the
+        // query too.
+        let (_, recv_auth) = state.0 .2;
+        let auth_result = auth::check_policy(recv_auth, &headers, req.token.as_deref());
+        let session = otel::Session::new("mimi_recv", &headers, &auth_result);
+        let limits = state.0 .1.config.limits.clone();
+
+        let room_id = match headers.get(ROOM_ID_HEADER) {
+            Some(v) => v.to_str().ok().map(|v| v.to_string()),
+            None => req.room_id.clone(),
+        };
+        let format = req.format;
+        let state = state.0 .0.clone();
+        let upg = limits
+            .ws_upgrade(ws)
+            .write_buffer_size(0)
+            .protocols(["permessage-deflate"])
+            .on_upgrade(move |mut socket| {
+                session.run(async move {
+                    let claims = match auth_result {
+                        Ok(claims) => claims,
+                        Err(err) => {
+                            tracing::warn!(?err, "WebSocket auth failed, closing with 4001");
+                            let _ = crate::utils::close_with_reason(
+                                &mut socket,
+                                crate::protocol::CloseCode::AuthenticationFailed,
+                                Some("Authentication failed"),
+                            )
+                            .await;
+                            return;
+                        }
+                    };
+                    let room_id = match state.authorize(room_id.as_deref(), claims.as_ref()) {
+                        Ok(room_id) => room_id,
+                        Err(err) => {
+                            tracing::warn!(%err, "mimi room refused, closing socket");
+                            let reason = err.to_string();
+                            let _ = crate::utils::close_with_reason(
+                                &mut socket,
+                                err.close_code(),
+                                Some(&reason),
+                            )
+                            .await;
+                            return;
+                        }
+                    };
+                    mimi_recv_websocket(
+                        limits::LimitedSocket::new(socket, &limits),
+                        state,
+                        room_id,
+                        format,
+                        addr,
+                    )
+                    .await
+                })
+            });
+        Ok(upg)
+    }
+
+    async fn mimi_send_websocket(
+        socket: limits::LimitedSocket,
+        state: Arc<mimi::Mimi>,
+        room_id: String,
+        publisher: Option<String>,
+        _addr: Option<String>,
+    ) {
+        if let Err(err) = state.send_socket(socket, room_id, publisher).await {
+            tracing::error!(?err, "mimi")
+        }
+    }
+
+    async fn send(
+        ws: axum::extract::ws::WebSocketUpgrade,
+        headers: axum::http::HeaderMap,
+        state: axum::extract::State<(
+            Arc<mimi::Mimi>,
+            SharedState,
+            (auth::AuthPolicy, auth::AuthPolicy),
+        )>,
+        req: axum::extract::Query<MimiStreamingQuery>,
+    ) -> utils::AxumResult<axum::response::Response> {
+        let addr = headers.get("X-Real-IP").and_then(|v| v.to_str().ok().map(|v| v.to_string()));
+        tracing::info!(addr, "handling mimi-streaming send query");
+        let auth_result = auth::check_policy(state.0 .2 .0, &headers, req.token.as_deref());
+        let session = otel::Session::new("mimi_send", &headers, &auth_result);
+        let limits = state.0 .1.config.limits.clone();
+
+        let room_id = match headers.get(ROOM_ID_HEADER) {
+            Some(v) => v.to_str().ok().map(|v| v.to_string()),
+            None => req.room_id.clone(),
+        };
+        let publisher = req.publisher.clone();
+
+        let state = state.0 .0;
+        let upg = limits
+            .ws_upgrade(ws)
+            .write_buffer_size(0)
+            .protocols(["permessage-deflate"])
+            .on_upgrade(move |mut socket| {
+                session.run(async move {
+                    let claims = match auth_result {
+                        Ok(claims) => claims,
+                        Err(err) => {
+                            tracing::warn!(?err, "WebSocket auth failed, closing with 4001");
+                            let _ = crate::utils::close_with_reason(
+                                &mut socket,
+                                crate::protocol::CloseCode::AuthenticationFailed,
+                                Some("Authentication failed"),
+                            )
+                            .await;
+                            return;
+                        }
+                    };
+
+                    // Publishers name their room, the default room is for listeners only.
+                    let room_id = match room_id {
+                        None => Err(mimi::RoomError::Missing),
+                        Some(room_id) => state.authorize(Some(&room_id), claims.as_ref()),
+                    };
+                    let room_id = match room_id {
+                        Ok(id) => id,
+                        Err(err) => {
+                            tracing::warn!(%err, "mimi room refused, closing socket");
+                            let reason = err.to_string();
+                            let _ = crate::utils::close_with_reason(
+                                &mut socket,
+                                err.close_code(),
+                                Some(&reason),
+                            )
+                            .await;
+                            return;
+                        }
+                    };
+
+                    let socket = limits::LimitedSocket::new(socket, &limits);
+                    mimi_send_websocket(socket, state, room_id, publisher, addr).await
+                })
+            });
+        Ok(upg)
+    }
+
+    async fn publishers(
+        headers: axum::http::HeaderMap,
+        state: axum::extract::State<(
+            Arc<mimi::Mimi>,
+            SharedState,
+            (auth::AuthPolicy, auth::AuthPolicy),
+        )>,
+        req: axum::extract::Query<MimiPublishersQuery>,
+    ) -> utils::AxumResult<Response> {
+        let claims = match auth::check_policy(state.0 .2 .0, &headers, None) {
+            Ok(claims) => claims,
+            Err(err) => return Ok(err.into_response()),
+        };
+        if let Err(err) = state.0 .0.authorize(req.room_id.as_deref(), claims.as_ref()) {
+            return Ok(errors::ApiError::from(err).into_response());
+        }
+        match state.0 .0.publishers(req.room_id.as_deref()) {
+            Ok(publishers) => Ok(axum::Json(publishers).into_response()),
+            Err(err) => Ok(errors::ApiError::NotFound(err.to_string()).into_response()),
+        }
+    }
+
+    async fn update_publisher(
+        headers: axum::http::HeaderMap,
+        state: axum::extract::State<(
+            Arc<mimi::Mimi>,
+            SharedState,
+            (auth::AuthPolicy, auth::AuthPolicy),
+        )>,
+        axum::extract::Path(name): axum::extract::Path<String>,
+        req: axum::extract::Query<MimiPublishersQuery>,
+        axum::Json(update): axum::Json<mimi::PublisherUpdate>,
+    ) -> utils::AxumResult<Response> {
+        let claims = match auth::check_policy(state.0 .2 .0, &headers, None) {
+            Ok(claims) => claims,
+            Err(err) => return Ok(err.into_response()),
+        };
+        let mimi = &state.0 .0;
+        if let Err(err) = mimi.authorize(req.room_id.as_deref(), claims.as_ref()) {
+            return Ok(errors::ApiError::from(err).into_response());
+        }
+        match mimi.update_publisher(req.room_id.as_deref(), &name, &update) {
+            Ok(Some(publisher)) => Ok(axum::Json(publisher).into_response()),
+            Ok(None) => {
+                Ok(errors::ApiError::NotFound(format!("unknown publisher {name}")).into_response())
+            }
+            Err(err) => Ok(errors::ApiError::InvalidRequest(err.to_string()).into_response()),
+        }
+    }
+
+    /// Files of the HLS bridge of a room, with the authentication and the room checks of the
+    /// listeners. The playlist carries the `token` of the request on to the segments.
+    async fn hls(
+        headers: axum::http::HeaderMap,
+        state: axum::extract::State<(
+            Arc<mimi::Mimi>,
+            SharedState,
+            (auth::AuthPolicy, auth::AuthPolicy),
+        )>,
+        axum::extract::Path((room_id, file)): axum::extract::Path<(String, String)>,
+        req: axum::extract::Query<MimiStreamingQuery>,
+    ) -> utils::AxumResult<Response> {
+        let (_, recv_auth) = state.0 .2;
+        let claims = match auth::check_policy(recv_auth, &headers, req.token.as_deref()) {
+            Ok(claims) => claims,
+            Err(err) => return Ok(err.into_response()),
+        };
+        let mimi = &state.0 .0;
+        if let Err(err) = mimi.authorize(Some(&room_id), claims.as_ref()) {
+            return Ok(errors::ApiError::from(err).into_response());
+        }
+        let content_type = mimi_bridge::hls_content_type(&file);
+        let (Some(dir), Some(content_type)) = (mimi.hls_dir(&room_id), content_type) else {
+            let msg = format!("no hls file {file} for room {room_id}");
+            return Ok(errors::ApiError::NotFound(msg).into_response());
+        };
+        // Segments leave the directory once they are out of the playlist.
+        let data = match tokio::fs::read(dir.join(&file)).await {
+            Ok(data) => data,
+            Err(_) => {
+                let msg = format!("no hls file {file} for room {room_id}");
+                return Ok(errors::ApiError::NotFound(msg).into_response());
+            }
+        };
+        use axum::http::header;
+        if file != "index.m3u8" {
+            return Ok(([(header::CONTENT_TYPE, content_type)], data).into_response());
+        }
+        let m3u8 = String::from_utf8_lossy(&data);
+        let m3u8 = match req.token.as_deref() {
+            Some(token) => mimi_bridge::playlist_with_token(&m3u8, token),
+            None => m3u8.into_owned(),
+        };
+        let headers = [(header::CONTENT_TYPE, content_type), (header::CACHE_CONTROL, "no-cache")];
+        Ok((headers, m3u8).into_response())
+    }
+
+    let publishers_path = format!("{}/publishers", send_path.trim_end_matches('/'));
+    let hls_path = format!("{}/hls/{{room_id}}/{{file}}", recv_path.trim_end_matches('/'));
+    axum::Router::new()
+        .route(send_path, axum::routing::get(send))
+        .route(recv_path, axum::routing::get(recv))
+        .route(&hls_path, axum::routing::get(hls))
+        .route(&publishers_path, axum::routing::get(publishers))
+        .route(&format!("{publishers_path}/{{name}}"), axum::routing::post(update_publisher))
+        .with_state((s, ss.clone(), auth))
+}
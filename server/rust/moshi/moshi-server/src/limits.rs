@@ -0,0 +1,351 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Size limits on client input.
+//!
+//! Websocket messages and PCM chunks above the limits close the connection with the standard
+//! 1009 (message too big) code, HTTP bodies above the limit are rejected with a JSON 413
+//! response. Both happen before any msgpack or audio decoding.
+//!
+//! Sockets authenticated with a JWT are also closed with `TokenExpired` (4007) once the token
+//! has been expired for `token_expiry_grace_s`, unless a `RefreshToken` message extended it.
+
+use crate::protocol::CloseCode;
+use axum::extract::ws;
+use futures_util::{Sink, Stream};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+fn default_max_ws_message_bytes() -> usize {
+    4 << 20
+}
+fn default_max_pcm_samples() -> usize {
+    // 10s at 24kHz.
+    240_000
+}
+fn default_max_http_body_bytes() -> usize {
+    32 << 20
+}
+fn default_token_expiry_grace_s() -> f64 {
+    60.0
+}
+fn default_max_fetch_bytes() -> usize {
+    256 << 20
+}
+fn default_fetch_timeout_s() -> f64 {
+    60.0
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct LimitsConfig {
+    /// Maximum size of a single websocket message, after reassembling the frames.
+    #[serde(default = "default_max_ws_message_bytes")]
+    pub max_ws_message_bytes: usize,
+    /// Maximum number of samples in a single `Audio` message.
+    #[serde(default = "default_max_pcm_samples")]
+    pub max_pcm_samples: usize,
+    /// Maximum size of an HTTP request body.
+    #[serde(default = "default_max_http_body_bytes")]
+    pub max_http_body_bytes: usize,
+    /// How long a websocket session stays open after its JWT expired, waiting for a
+    /// `RefreshToken` message.
+    #[serde(default = "default_token_expiry_grace_s")]
+    pub token_expiry_grace_s: f64,
+    /// Maximum size of a file fetched by the server for a batch query.
+    #[serde(default = "default_max_fetch_bytes")]
+    pub max_fetch_bytes: usize,
+    /// Time allowed to download a file for a batch query.
+    #[serde(default = "default_fetch_timeout_s")]
+    pub fetch_timeout_s: f64,
+    /// Let batch queries fetch from loopback, private and link-local addresses, for
+    /// deployments whose audio is on an internal network.
+    #[serde(default)]
+    pub fetch_private_hosts: bool,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_ws_message_bytes: default_max_ws_message_bytes(),
+            max_pcm_samples: default_max_pcm_samples(),
+            max_http_body_bytes: default_max_http_body_bytes(),
+            token_expiry_grace_s: default_token_expiry_grace_s(),
+            max_fetch_bytes: default_max_fetch_bytes(),
+            fetch_timeout_s: default_fetch_timeout_s(),
+            fetch_private_hosts: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LimitError {
+    WsMessage { len: usize, max: usize },
+    PcmChunk { len: usize, max: usize },
+    TokenExpired { expires_at: i64 },
+}
+
+impl std::fmt::Display for LimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::WsMessage { len, max } => write!(f, "message of {len} bytes exceeds {max}"),
+            Self::PcmChunk { len, max } => write!(f, "pcm chunk of {len} samples exceeds {max}"),
+            Self::TokenExpired { expires_at } => {
+                write!(f, "token expired at {expires_at} and was not refreshed")
+            }
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+impl LimitError {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::WsMessage { .. } => "ws_message",
+            Self::PcmChunk { .. } => "pcm_chunk",
+            Self::TokenExpired { .. } => "token_expired",
+        }
+    }
+
+    fn close_code(&self) -> CloseCode {
+        match self {
+            Self::WsMessage { .. } | Self::PcmChunk { .. } => CloseCode::MessageTooBig,
+            Self::TokenExpired { .. } => CloseCode::TokenExpired,
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn check_ws_message(&self, len: usize) -> Result<(), LimitError> {
+        if len > self.max_ws_message_bytes {
+            return Err(LimitError::WsMessage { len, max: self.max_ws_message_bytes });
+        }
+        Ok(())
+    }
+
+    pub fn check_pcm(&self, len: usize) -> Result<(), LimitError> {
+        if len > self.max_pcm_samples {
+            return Err(LimitError::PcmChunk { len, max: self.max_pcm_samples });
+        }
+        Ok(())
+    }
+
+    /// Apply the message limit to a websocket upgrade so that tungstenite stops reading
+    /// oversize messages instead of buffering them.
+    pub fn ws_upgrade(&self, ws: ws::WebSocketUpgrade) -> ws::WebSocketUpgrade {
+        ws.max_message_size(self.max_ws_message_bytes).max_frame_size(self.max_ws_message_bytes)
+    }
+}
+
+/// Asks the socket to close, can be used from the receiving half of a split socket.
+#[derive(Clone)]
+pub struct Closer(Arc<Mutex<Option<LimitError>>>);
+
+impl Closer {
+    pub fn close(&self, err: LimitError) {
+        let mut pending = self.0.lock().unwrap();
+        if pending.is_none() {
+            *pending = Some(err)
+        }
+    }
+}
+
+/// Websocket enforcing the size limits and the token expiry.
+///
+/// When a limit is exceeded, either on a message or through the [`Closer`], the close frame
+/// is sent on the next read and the stream then ends.
+pub struct LimitedSocket {
+    inner: ws::WebSocket,
+    limits: LimitsConfig,
+    pending: Arc<Mutex<Option<LimitError>>>,
+    auth: Option<crate::auth::SessionAuth>,
+    trace: crate::otel::TraceIds,
+    /// Expiry the timer was armed for, and the timer.
+    expiry: Option<(i64, Pin<Box<tokio::time::Sleep>>)>,
+    flushing: bool,
+    closed: bool,
+}
+
+impl LimitedSocket {
+    pub fn new(inner: ws::WebSocket, limits: &LimitsConfig) -> Self {
+        Self {
+            inner,
+            limits: limits.clone(),
+            pending: Arc::new(Mutex::new(None)),
+            auth: None,
+            trace: Default::default(),
+            expiry: None,
+            flushing: false,
+            closed: false,
+        }
+    }
+
+    /// Close the socket when the session credentials expire.
+    pub fn with_auth(mut self, auth: Option<crate::auth::SessionAuth>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Upstream ids of the session, echoed in the `Ready` message.
+    pub fn with_trace(mut self, trace: crate::otel::TraceIds) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    pub fn trace(&self) -> &crate::otel::TraceIds {
+        &self.trace
+    }
+
+    /// The session credentials, to be extended on `RefreshToken`.
+    pub fn auth(&self) -> Option<crate::auth::SessionAuth> {
+        self.auth.clone()
+    }
+
+    pub fn limits(&self) -> &LimitsConfig {
+        &self.limits
+    }
+
+    pub fn closer(&self) -> Closer {
+        Closer(self.pending.clone())
+    }
+
+    fn poll_close_frame(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.flushing {
+            let err = match self.pending.lock().unwrap().clone() {
+                None => return Poll::Ready(()),
+                Some(err) => err,
+            };
+            if ready!(Pin::new(&mut self.inner).poll_ready(cx)).is_ok() {
+                let code = err.close_code();
+                tracing::warn!(%err, code = code.code(), "closing the socket");
+                crate::metrics::errors::record_ws_close(code.code(), err.kind());
+                let frame = code.with_reason(err.to_string());
+                let _ = Pin::new(&mut self.inner).start_send(ws::Message::Close(Some(frame)));
+            }
+            self.flushing = true;
+        }
+        let _ = ready!(Pin::new(&mut self.inner).poll_flush(cx));
+        self.closed = true;
+        Poll::Ready(())
+    }
+
+    /// Arm the timer for the current expiry, a refresh re-arms it.
+    fn poll_expiry(&mut self, cx: &mut Context<'_>) {
+        use std::future::Future;
+        let Some(expires_at) = self.auth.as_ref().and_then(|a| a.expires_at()) else { return };
+        if self.expiry.as_ref().map(|(e, _)| *e) != Some(expires_at) {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0.0, |d| d.as_secs_f64());
+            let wait = (expires_at as f64 + self.limits.token_expiry_grace_s - now).max(0.0);
+            let sleep = tokio::time::sleep(Duration::from_secs_f64(wait));
+            self.expiry = Some((expires_at, Box::pin(sleep)));
+        }
+        if let Some((_, sleep)) = self.expiry.as_mut() {
+            if sleep.as_mut().poll(cx).is_ready() {
+                self.closer().close(LimitError::TokenExpired { expires_at });
+            }
+        }
+    }
+}
+
+fn message_too_long(err: &axum::Error) -> Option<LimitError> {
+    use tokio_tungstenite::tungstenite::error::{CapacityError, Error};
+    let err = std::error::Error::source(err)?.downcast_ref::<Error>()?;
+    match err {
+        Error::Capacity(CapacityError::MessageTooLong { size, max_size }) => {
+            Some(LimitError::WsMessage { len: *size, max: *max_size })
+        }
+        _ => None,
+    }
+}
+
+impl Stream for LimitedSocket {
+    type Item = Result<ws::Message, axum::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        this.poll_expiry(cx);
+        loop {
+            if !this.closed {
+                ready!(this.poll_close_frame(cx));
+            }
+            if this.closed {
+                return Poll::Ready(None);
+            }
+            let msg = match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(msg)) => msg,
+                Some(Err(err)) => match message_too_long(&err) {
+                    Some(err) => {
+                        this.closer().close(err);
+                        continue;
+                    }
+                    None => return Poll::Ready(Some(Err(err))),
+                },
+                None => return Poll::Ready(None),
+            };
+            let len = match &msg {
+                ws::Message::Binary(b) => b.len(),
+                ws::Message::Text(t) => t.len(),
+                _ => 0,
+            };
+            match this.limits.check_ws_message(len) {
+                Ok(()) => return Poll::Ready(Some(Ok(msg))),
+                Err(err) => this.closer().close(err),
+            }
+        }
+    }
+}
+
+impl Sink<ws::Message> for LimitedSocket {
+    type Error = axum::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: ws::Message) -> Result<(), Self::Error> {
+        Pin::new(&mut self.get_mut().inner).start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+/// Turn the plain-text 413 produced by the body extractors into a JSON error.
+pub async fn payload_too_large_json(
+    axum::extract::State(max_bytes): axum::extract::State<usize>,
+    response: axum::response::Response,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+    if response.status() != axum::http::StatusCode::PAYLOAD_TOO_LARGE {
+        return response;
+    }
+    crate::errors::ApiError::PayloadTooLarge { max_bytes }.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_defaults_and_checks() {
+        let cfg: LimitsConfig = toml::from_str("max_pcm_samples = 1920").unwrap();
+        assert_eq!(cfg.max_ws_message_bytes, 4 << 20);
+        assert_eq!(cfg.max_pcm_samples, 1920);
+        assert!(cfg.check_pcm(1920).is_ok());
+        assert_eq!(cfg.check_pcm(1921), Err(LimitError::PcmChunk { len: 1921, max: 1920 }));
+        assert!(cfg.check_ws_message(5 << 20).is_err());
+        let reason = LimitError::WsMessage { len: 5 << 20, max: 4 << 20 }.to_string();
+        // Close frame reasons are limited to 123 bytes.
+        assert!(reason.len() <= 123);
+    }
+}
@@ -5,7 +5,6 @@ use crate::protocol::MsgType;
 use anyhow::{Context, Result};
 use axum::extract::ws;
 use candle::{Device, IndexOp, Tensor};
-use candle_nn::VarBuilder;
 use candle_transformers::generation::LogitsProcessor;
 
 use kaudio::ogg_opus;
@@ -37,6 +36,7 @@ pub struct Lm {
     text_tokenizer: std::sync::Arc<sentencepiece::SentencePieceProcessor>,
     instance_name: String,
     log_dir: std::path::PathBuf,
+    steps: crate::compute::StepPool,
 }
 
 enum WsEvent {
@@ -58,7 +58,8 @@ impl Lm {
         let text_tokenizer = sentencepiece::SentencePieceProcessor::open(&lm.text_tokenizer_file)
             .with_context(|| lm.text_tokenizer_file.clone())?;
         let vb_lm =
-            unsafe { VarBuilder::from_mmaped_safetensors(&[&lm.lm_model_file], dtype, dev)? };
+            crate::snapshot::var_builder(&lm.lm_model_file, dtype, dev, config.snapshots.as_ref())?;
+        let steps = crate::compute::StepPool::new(&lm.compute, "lm")?;
         let lm = moshi::lm::LmModel::new(
             model_config,
             moshi::nn::MaybeQuantizedVarBuilder::Real(vb_lm),
@@ -71,10 +72,11 @@ impl Lm {
             log_dir: config.log_dir.clone().into(),
             instance_name: config.instance_name.clone(),
             text_tokenizer: text_tokenizer.into(),
+            steps,
         })
     }
 
-    pub async fn handle_socket(&self, socket: ws::WebSocket) -> Result<()> {
+    pub async fn handle_socket(&self, socket: crate::limits::LimitedSocket) -> Result<()> {
         use futures_util::StreamExt;
 
         tracing::info!("connected");
@@ -197,17 +199,15 @@ impl Lm {
             Ok::<(), anyhow::Error>(())
         });
 
+        let steps = self.steps.clone();
         let inference_handle = crate::utils::spawn_blocking("inference_loop", move || {
             let mut prev_text_token = state.config().text_start_token;
             for steps_tokens in mimi_rx {
                 for codes in steps_tokens {
-                    let text_token = state.step_(
-                        Some(prev_text_token),
-                        &codes,
-                        None,
-                        None,
-                        conditions.as_ref(),
-                    )?;
+                    let text_token = steps.install(|| {
+                        let prev = Some(prev_text_token);
+                        state.step_(prev, &codes, None, None, conditions.as_ref())
+                    })?;
 
                     if let Some(text) = text_decoder.text(prev_text_token, text_token) {
                         out_tx.send(WsEvent::Text(text))?
@@ -329,6 +329,7 @@ fn save_logs(
     let (secs, us) = (since_epoch.as_secs(), since_epoch.subsec_micros());
     let base_path = log_dir.join(format!("{}-lm-{secs}-{us}", instance_name));
     let json_filename = base_path.with_extension("json");
+    let query = crate::schema::stamp(crate::schema::Artifact::SessionLog, &query)?;
     let json_content = serde_json::to_string_pretty(&query)?;
     std::fs::write(json_filename, json_content)?;
     let st_filename = base_path.with_extension("safetensors");
@@ -0,0 +1,302 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Long-poll transport for batched asr sessions, for clients whose network blocks websockets.
+//!
+//! A session holds a batched asr slot like a websocket connection does. Clients post the
+//! same MessagePack `InMsg` frames they would send on the websocket and poll for the output
+//! messages, which are buffered in the meantime. Sessions that are neither posted to nor
+//! polled for the TTL are closed, which frees their slot. A session can only be used with the
+//! credentials it was opened with.
+
+use crate::asr::{InMsg, OutMsg};
+use crate::limits::{LimitError, LimitsConfig};
+use crate::protocol::DecodeError;
+use axum::http::StatusCode;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+type InSend = std::sync::mpsc::Sender<InMsg>;
+type OutRecv = tokio::sync::mpsc::UnboundedReceiver<OutMsg>;
+
+/// Upper bound for the `wait_ms` parameter of a poll.
+pub const MAX_POLL_WAIT: Duration = Duration::from_secs(60);
+
+/// Identity of the client that opened a session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Owner {
+    /// The module does not authenticate its clients.
+    Anonymous,
+    /// The clients share the API key of the server.
+    ApiKey,
+    /// User id of the JWT claims.
+    User(String),
+}
+
+#[derive(Debug)]
+pub enum PollError {
+    UnknownSession,
+    /// The session was opened by another client.
+    Forbidden,
+    /// The slot of the session was released, e.g. after a model error.
+    Closed,
+    Decode(DecodeError),
+    Invalid(String),
+    Limit(LimitError),
+}
+
+impl std::fmt::Display for PollError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownSession => write!(f, "unknown or expired session"),
+            Self::Forbidden => write!(f, "session opened by another client"),
+            Self::Closed => write!(f, "session closed"),
+            Self::Decode(err) => write!(f, "{err}"),
+            Self::Invalid(msg) => write!(f, "{msg}"),
+            Self::Limit(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for PollError {}
+
+impl PollError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::UnknownSession => StatusCode::NOT_FOUND,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::Closed => StatusCode::GONE,
+            Self::Decode(_) | Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::Limit(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::UnknownSession => "unknown_session",
+            Self::Forbidden => "forbidden",
+            Self::Closed => "session_closed",
+            Self::Decode(_) | Self::Invalid(_) => "invalid_message",
+            Self::Limit(_) => "payload_too_large",
+        }
+    }
+}
+
+struct Session {
+    owner: Owner,
+    in_tx: Mutex<InSend>,
+    out_rx: tokio::sync::Mutex<OutRecv>,
+    decoder: Mutex<kaudio::ogg_opus::Decoder>,
+    resampler: Mutex<crate::resample::InputResampler>,
+    last_seen: Mutex<Instant>,
+}
+
+impl Session {
+    fn touch(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now()
+    }
+
+    fn send(&self, msg: InMsg) -> Result<(), PollError> {
+        self.in_tx.lock().unwrap().send(msg).map_err(|_| PollError::Closed)
+    }
+}
+
+pub struct PollSessions {
+    sessions: Mutex<HashMap<String, Arc<Session>>>,
+    ttl: Duration,
+    frame_size: usize,
+}
+
+impl PollSessions {
+    pub fn new(ttl: Duration, frame_size: usize) -> Self {
+        Self { sessions: Mutex::new(HashMap::new()), ttl, frame_size }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Register the channels of a freshly allocated slot and return the new session id.
+    pub fn insert(&self, in_tx: InSend, out_rx: OutRecv, owner: Owner) -> anyhow::Result<String> {
+        let session = Session {
+            owner,
+            in_tx: Mutex::new(in_tx),
+            out_rx: tokio::sync::Mutex::new(out_rx),
+            decoder: Mutex::new(kaudio::ogg_opus::Decoder::new(24000, self.frame_size)?),
+            resampler: Mutex::new(crate::resample::InputResampler::default()),
+            last_seen: Mutex::new(Instant::now()),
+        };
+        let id = format!("{:032x}", rand::random::<u128>());
+        self.sessions.lock().unwrap().insert(id.clone(), Arc::new(session));
+        Ok(id)
+    }
+
+    fn get(&self, id: &str, owner: &Owner) -> Result<Arc<Session>, PollError> {
+        let session = self.sessions.lock().unwrap().get(id).cloned();
+        let session = session.ok_or(PollError::UnknownSession)?;
+        if session.owner != *owner {
+            return Err(PollError::Forbidden);
+        }
+        session.touch();
+        Ok(session)
+    }
+
+    /// Forward a MessagePack `InMsg` frame to the slot of the session.
+    pub fn push(
+        &self,
+        id: &str,
+        owner: &Owner,
+        data: &[u8],
+        limits: &LimitsConfig,
+    ) -> Result<(), PollError> {
+        let session = self.get(id, owner)?;
+        let res = match crate::protocol::decode_in_msg(data).map_err(PollError::Decode)? {
+            InMsg::OggOpus { data } => match session.decoder.lock().unwrap().decode(&data) {
+                Ok(Some(pcm)) => session.send(InMsg::Audio { pcm: pcm.to_vec() }),
+                Ok(None) => Ok(()),
+                Err(err) => Err(PollError::Invalid(format!("oggopus decoding error: {err}"))),
+            },
+            InMsg::Audio { pcm } => {
+                limits.check_pcm(pcm.len()).map_err(PollError::Limit)?;
+                let pcm = session.resampler.lock().unwrap().process(pcm);
+                let pcm = pcm.map_err(|err| PollError::Invalid(err.to_string()))?;
+                session.send(InMsg::Audio { pcm })
+            }
+            InMsg::AudioCodes { codes } => {
+                limits.check_pcm(codes.len() * self.frame_size).map_err(PollError::Limit)?;
+                session.send(InMsg::AudioCodes { codes })
+            }
+            InMsg::SetSampleRate { hz } => {
+                let (tail, delta_s) = {
+                    let mut resampler = session.resampler.lock().unwrap();
+                    let correction_s = resampler.correction_s();
+                    let tail = resampler.set_rate(hz);
+                    (tail, resampler.correction_s() - correction_s)
+                };
+                match tail.map_err(|err| PollError::Invalid(err.to_string()))? {
+                    tail if tail.is_empty() => Ok(()),
+                    tail => session.send(InMsg::Audio { pcm: tail }),
+                }
+                // Queued after the audio of the previous rate, the correction applies after it.
+                .and_then(|()| {
+                    if delta_s == 0.0 {
+                        return Ok(());
+                    }
+                    session.send(InMsg::TimeCorrection { delta_s })
+                })
+            }
+            // Every request is authenticated on its own.
+            InMsg::RefreshToken { .. } => Ok(()),
+            m => session.send(m),
+        };
+        if let Err(PollError::Closed) = res {
+            self.remove(id);
+        }
+        res
+    }
+
+    /// Wait up to `wait` for output messages and return all the buffered ones.
+    pub async fn poll(
+        &self,
+        id: &str,
+        owner: &Owner,
+        wait: Duration,
+    ) -> Result<Vec<OutMsg>, PollError> {
+        let session = self.get(id, owner)?;
+        let mut msgs = vec![];
+        {
+            let mut out_rx = session.out_rx.lock().await;
+            match tokio::time::timeout(wait.min(MAX_POLL_WAIT), out_rx.recv()).await {
+                Ok(Some(msg)) => msgs.push(msg),
+                Ok(None) => {
+                    self.remove(id);
+                    return Err(PollError::Closed);
+                }
+                Err(_) => {}
+            }
+            while let Ok(msg) = out_rx.try_recv() {
+                msgs.push(msg)
+            }
+        }
+        session.touch();
+        Ok(msgs)
+    }
+
+    /// Close a session on the request of its owner.
+    pub fn close(&self, id: &str, owner: &Owner) -> Result<(), PollError> {
+        self.get(id, owner)?;
+        if !self.remove(id) {
+            return Err(PollError::UnknownSession);
+        }
+        Ok(())
+    }
+
+    /// Close a session, dropping its input channel releases the slot.
+    fn remove(&self, id: &str) -> bool {
+        self.sessions.lock().unwrap().remove(id).is_some()
+    }
+
+    /// Close the sessions that have been idle for longer than the TTL, returns their number.
+    pub fn expire(&self) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, s| s.last_seen.lock().unwrap().elapsed() <= self.ttl);
+        before - sessions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(msg: &InMsg) -> Vec<u8> {
+        rmp_serde::to_vec_named(msg).unwrap()
+    }
+
+    #[tokio::test]
+    async fn push_poll_and_expire() {
+        let sessions = PollSessions::new(Duration::from_millis(50), 1920);
+        let limits = LimitsConfig::default();
+        let (in_tx, in_rx) = std::sync::mpsc::channel();
+        let (out_tx, out_rx) = tokio::sync::mpsc::unbounded_channel();
+        let owner = Owner::User("alice".to_string());
+        let id = sessions.insert(in_tx, out_rx, owner.clone()).unwrap();
+
+        let audio = encode(&InMsg::Audio { pcm: vec![0.5; 1920] });
+        sessions.push(&id, &owner, &audio, &limits).unwrap();
+        assert!(matches!(in_rx.try_recv().unwrap(), InMsg::Audio { pcm } if pcm.len() == 1920));
+        let too_long = encode(&InMsg::Audio { pcm: vec![0.0; limits.max_pcm_samples + 1] });
+        let err = sessions.push(&id, &owner, &too_long, &limits).unwrap_err();
+        assert_eq!(err.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let err = sessions.push(&id, &owner, b"\xc1", &limits).unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+
+        // Nothing buffered, the poll times out with an empty list.
+        assert!(sessions.poll(&id, &owner, Duration::from_millis(10)).await.unwrap().is_empty());
+        out_tx.send(OutMsg::ready(&Default::default())).unwrap();
+        out_tx.send(OutMsg::Marker { id: 1, sample_idx: 0, step_idx: 0 }).unwrap();
+        let msgs = sessions.poll(&id, &owner, Duration::from_secs(1)).await.unwrap();
+        assert!(matches!(msgs.as_slice(), [OutMsg::Ready { .. }, OutMsg::Marker { id: 1, .. }]));
+
+        // Another user, or the api key, cannot use or close the session.
+        for other in [Owner::User("bob".to_string()), Owner::ApiKey, Owner::Anonymous] {
+            let err = sessions.push(&id, &other, &audio, &limits).unwrap_err();
+            assert_eq!(err.status(), StatusCode::FORBIDDEN);
+            let err = sessions.poll(&id, &other, Duration::from_millis(1)).await.unwrap_err();
+            assert_eq!(err.status(), StatusCode::FORBIDDEN);
+            let err = sessions.close(&id, &other).unwrap_err();
+            assert_eq!(err.status(), StatusCode::FORBIDDEN);
+        }
+        assert!(in_rx.try_recv().is_err());
+
+        assert_eq!(sessions.expire(), 0);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(sessions.expire(), 1);
+        // The slot sees the input channel disconnect.
+        assert!(in_rx.recv().is_err());
+        let err = sessions.poll(&id, &owner, Duration::from_millis(1)).await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Zeroconf/mDNS advertisement of the running server.
+//!
+//! When enabled, the server registers a `_moshi._tcp.local.` service whose TXT
+//! record carries the server version, the instance name and the path of every
+//! configured module so that clients on the LAN can discover it without
+//! knowing its IP address in advance.
+
+use anyhow::Result;
+
+/// DNS-SD service type used for moshi-server advertisements.
+pub const SERVICE_TYPE: &str = "_moshi._tcp.local.";
+
+/// TXT record key prefix used for module entries, e.g. `m.asr=batched_asr:/api/asr-streaming`.
+pub const MODULE_KEY_PREFIX: &str = "m.";
+
+fn default_mdns_enabled() -> bool {
+    false
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct MdnsConfig {
+    /// Advertise the server over mDNS (disabled by default).
+    #[serde(default = "default_mdns_enabled")]
+    pub enabled: bool,
+    /// Service instance name, defaults to the config `instance_name`.
+    #[serde(default)]
+    pub service_name: Option<String>,
+    /// Host name announced in the SRV record, defaults to the machine hostname.
+    #[serde(default)]
+    pub host_name: Option<String>,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        Self { enabled: default_mdns_enabled(), service_name: None, host_name: None }
+    }
+}
+
+/// A module entry published in the TXT record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdvertisedModule {
+    pub name: String,
+    pub kind: &'static str,
+    pub path: String,
+}
+
+/// Keeps the mDNS registration alive, the service is withdrawn on drop.
+pub struct Advertisement {
+    daemon: mdns_sd::ServiceDaemon,
+    fullname: String,
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        if let Err(err) = self.daemon.unregister(&self.fullname) {
+            tracing::warn!(?err, "failed to unregister mDNS service");
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+fn local_host_name() -> String {
+    let host = std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "moshi-server".to_string());
+    if host.ends_with(".local.") {
+        host
+    } else {
+        format!("{}.local.", host.trim_end_matches('.'))
+    }
+}
+
+/// Build the TXT properties advertised alongside the service.
+pub fn txt_properties(
+    version: &str,
+    instance_name: &str,
+    modules: &[AdvertisedModule],
+) -> Vec<(String, String)> {
+    let mut props = vec![
+        ("version".to_string(), version.to_string()),
+        ("instance".to_string(), instance_name.to_string()),
+    ];
+    for m in modules.iter() {
+        props.push((format!("{MODULE_KEY_PREFIX}{}", m.name), format!("{}:{}", m.kind, m.path)));
+    }
+    props
+}
+
+/// Register the service on the local network, returns `None` when disabled.
+pub fn advertise(
+    config: &MdnsConfig,
+    instance_name: &str,
+    addr: &str,
+    port: u16,
+    modules: &[AdvertisedModule],
+) -> Result<Option<Advertisement>> {
+    if !config.enabled {
+        return Ok(None);
+    }
+    let service_name = config.service_name.clone().unwrap_or_else(|| instance_name.to_string());
+    let host_name = config.host_name.clone().unwrap_or_else(local_host_name);
+    let version = crate::utils::BuildInfo::new().git_describe();
+    let props = txt_properties(&version, instance_name, modules);
+
+    // Only pin the advertised address when the server is bound to a specific interface.
+    let ip = match addr.parse::<std::net::IpAddr>() {
+        Ok(ip) if !ip.is_unspecified() => ip.to_string(),
+        _ => String::new(),
+    };
+    let info = mdns_sd::ServiceInfo::new(
+        SERVICE_TYPE,
+        &service_name,
+        &host_name,
+        ip.as_str(),
+        port,
+        props.as_slice(),
+    )?;
+    let info = if ip.is_empty() { info.enable_addr_auto() } else { info };
+    let fullname = info.get_fullname().to_string();
+
+    let daemon = mdns_sd::ServiceDaemon::new()?;
+    daemon.register(info)?;
+    tracing::info!(service = fullname, host = host_name, port, "advertising server over mDNS");
+    Ok(Some(Advertisement { daemon, fullname }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_txt_properties() {
+        let modules = vec![
+            AdvertisedModule {
+                name: "asr".to_string(),
+                kind: "batched_asr",
+                path: "/api/asr-streaming".to_string(),
+            },
+            AdvertisedModule { name: "tts".to_string(), kind: "tts", path: "/api/tts".to_string() },
+        ];
+        let props = txt_properties("v0.6.4", "lab", &modules);
+        assert_eq!(props[0], ("version".to_string(), "v0.6.4".to_string()));
+        assert_eq!(props[1], ("instance".to_string(), "lab".to_string()));
+        assert_eq!(props[2], ("m.asr".to_string(), "batched_asr:/api/asr-streaming".to_string()));
+        assert_eq!(props[3], ("m.tts".to_string(), "tts:/api/tts".to_string()));
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        let config: MdnsConfig = toml::from_str("").unwrap();
+        assert!(!config.enabled);
+        let adv = advertise(&config, "lab", "0.0.0.0", 8080, &[]).unwrap();
+        assert!(adv.is_none());
+    }
+}
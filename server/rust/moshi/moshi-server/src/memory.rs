@@ -0,0 +1,435 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! VRAM budget shared by the modules.
+//!
+//! Every module registers the memory taken by its weights and the cost of one stream (KV cache
+//! and activations). Batched modules reserve their slots once at startup, the other modules go
+//! through admission for each connection and give the memory back when it ends, so modules
+//! sharing a GPU cannot together go over the budget.
+
+use crate::{utils, ModuleConfig};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+const MB: u64 = 1024 * 1024;
+
+fn default_reserved_mb() -> u64 {
+    utils::DEFAULT_VRAM_RESERVED_MB
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct MemoryConfig {
+    /// VRAM the modules may use in total, defaults to the free VRAM at startup minus
+    /// `reserved_mb`. Without a GPU and without this setting, the budget is unlimited.
+    #[serde(default)]
+    pub budget_mb: Option<u64>,
+    /// Kept aside for the CUDA context, the driver and allocator fragmentation.
+    #[serde(default = "default_reserved_mb")]
+    pub reserved_mb: u64,
+    /// Cost overrides by module name, replacing the estimates from the model config.
+    #[serde(default)]
+    pub modules: HashMap<String, ModuleCostConfig>,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self { budget_mb: None, reserved_mb: default_reserved_mb(), modules: HashMap::new() }
+    }
+}
+
+/// The shadow model of an asr module, with one slot for each of its sessions, is added to the
+/// weights of the module on top of these.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct ModuleCostConfig {
+    pub weights_mb: Option<u64>,
+    pub slot_mb: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ModuleUsage {
+    pub name: String,
+    pub weights_mb: u64,
+    pub slot_mb: u64,
+    /// Slots allocated at startup by batched modules.
+    pub static_slots: usize,
+    /// Streams currently admitted.
+    pub active_slots: usize,
+    pub rejected: u64,
+}
+
+impl ModuleUsage {
+    fn committed_mb(&self) -> u64 {
+        self.weights_mb + self.slot_mb * (self.static_slots + self.active_slots) as u64
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MemoryReport {
+    /// `None` when the budget is unlimited.
+    pub budget_mb: Option<u64>,
+    pub reserved_mb: u64,
+    pub committed_mb: u64,
+    pub available_mb: Option<u64>,
+    pub modules: Vec<ModuleUsage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BudgetExceeded {
+    pub module: String,
+    pub needed_mb: u64,
+    pub available_mb: u64,
+}
+
+impl std::fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} needs {}MB of VRAM, {}MB available",
+            self.module, self.needed_mb, self.available_mb
+        )
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+/// Path of the module serving a request, its key in the budget.
+#[derive(Debug, Clone)]
+pub struct ModuleKey(pub String);
+
+pub struct MemoryBudget {
+    budget_mb: Option<u64>,
+    reserved_mb: u64,
+    modules: Mutex<BTreeMap<String, ModuleUsage>>,
+}
+
+impl MemoryBudget {
+    pub fn new(budget_mb: Option<u64>, reserved_mb: u64) -> Self {
+        Self { budget_mb, reserved_mb, modules: Mutex::new(BTreeMap::new()) }
+    }
+
+    fn available(&self, modules: &BTreeMap<String, ModuleUsage>) -> Option<u64> {
+        let committed: u64 = modules.values().map(|m| m.committed_mb()).sum();
+        self.budget_mb.map(|b| b.saturating_sub(committed))
+    }
+
+    /// Add a module to the ledger, its weights are committed right away.
+    pub fn register(&self, name: &str, weights_mb: u64, slot_mb: u64) {
+        let mut modules = self.modules.lock().unwrap();
+        if let Some(available) = self.available(&modules) {
+            if weights_mb > available {
+                tracing::error!(
+                    module = name,
+                    weights_mb,
+                    available,
+                    "model weights do not fit in the memory budget, OOM is likely"
+                );
+            }
+        }
+        let usage = ModuleUsage {
+            name: name.to_string(),
+            weights_mb,
+            slot_mb,
+            static_slots: 0,
+            active_slots: 0,
+            rejected: 0,
+        };
+        modules.insert(name.to_string(), usage);
+        self.update_metrics(&modules);
+    }
+
+    /// Reserve up to `wanted` slots for a batched module, returns the number of slots that fit
+    /// in the budget, at least one.
+    pub fn reserve_slots(&self, name: &str, wanted: usize) -> usize {
+        let mut modules = self.modules.lock().unwrap();
+        let available = self.available(&modules);
+        let Some(m) = modules.get_mut(name) else { return wanted };
+        let slots = match (available, m.slot_mb) {
+            (Some(available), slot_mb) if slot_mb > 0 => {
+                wanted.min((available / slot_mb) as usize).max(1)
+            }
+            _ => wanted,
+        };
+        m.static_slots = slots;
+        self.update_metrics(&modules);
+        slots
+    }
+
+    /// Admit a new stream on a module, the memory is released when the admission is dropped.
+    pub fn admit(self: &Arc<Self>, name: &str) -> Result<Admission, BudgetExceeded> {
+        let mut modules = self.modules.lock().unwrap();
+        let available = self.available(&modules);
+        let Some(m) = modules.get_mut(name) else {
+            return Ok(Admission { budget: self.clone(), name: None });
+        };
+        if let Some(available) = available {
+            if m.slot_mb > available {
+                m.rejected += 1;
+                crate::metrics::memory::ADMISSION_REJECTED.with_label_values(&[name]).inc();
+                return Err(BudgetExceeded {
+                    module: name.to_string(),
+                    needed_mb: m.slot_mb,
+                    available_mb: available,
+                });
+            }
+        }
+        m.active_slots += 1;
+        self.update_metrics(&modules);
+        Ok(Admission { budget: self.clone(), name: Some(name.to_string()) })
+    }
+
+    /// Admit a websocket stream, the socket is closed with 4000 (server at capacity) when the
+    /// stream does not fit in the budget.
+    pub async fn admit_socket(
+        self: &Arc<Self>,
+        name: &str,
+        socket: &mut axum::extract::ws::WebSocket,
+    ) -> Option<Admission> {
+        match self.admit(name) {
+            Ok(admission) => Some(admission),
+            Err(err) => {
+                tracing::warn!(%err, "memory budget exhausted, refusing the stream");
+                crate::metrics::errors::record_connection_error("memory", name);
+                let code = crate::protocol::CloseCode::ServerAtCapacity;
+                let _ = utils::close_with_reason(socket, code, Some(&err.to_string())).await;
+                None
+            }
+        }
+    }
+
+    pub fn report(&self) -> MemoryReport {
+        let modules = self.modules.lock().unwrap();
+        MemoryReport {
+            budget_mb: self.budget_mb,
+            reserved_mb: self.reserved_mb,
+            committed_mb: modules.values().map(|m| m.committed_mb()).sum(),
+            available_mb: self.available(&modules),
+            modules: modules.values().cloned().collect(),
+        }
+    }
+
+    /// VRAM committed by all the modules, in bytes.
+    pub fn committed_bytes(&self) -> u64 {
+        let modules = self.modules.lock().unwrap();
+        modules.values().map(|m| m.committed_mb()).sum::<u64>() * MB
+    }
+
+    fn update_metrics(&self, modules: &BTreeMap<String, ModuleUsage>) {
+        use crate::metrics::memory as m;
+
+        let committed: u64 = modules.values().map(|m| m.committed_mb()).sum();
+        m::BUDGET_COMMITTED_MB.set(committed as f64);
+        for (name, usage) in modules.iter() {
+            let slots = usage.static_slots + usage.active_slots;
+            let weights = (usage.weights_mb * MB) as f64;
+            let slot = (usage.slot_mb * MB) as f64;
+            m::MODULE_COMMITTED_BYTES.with_label_values(&[name, "weights"]).set(weights);
+            m::MODULE_COMMITTED_BYTES.with_label_values(&[name, "slots"]).set(slot * slots as f64);
+            m::MODULE_SLOT_BYTES.with_label_values(&[name]).set(slot);
+            m::MODULE_SLOTS.with_label_values(&[name, "static"]).set(usage.static_slots as i64);
+            m::MODULE_SLOTS.with_label_values(&[name, "active"]).set(usage.active_slots as i64);
+        }
+    }
+
+    pub fn log_summary(&self) {
+        let report = self.report();
+        let line = "─".repeat(60);
+        tracing::info!("\n{}", line);
+        tracing::info!("  MEMORY BUDGET");
+        tracing::info!("{}", line);
+        match report.budget_mb {
+            Some(b) => tracing::info!("  Budget:            {:>6} MB", b),
+            None => tracing::info!("  Budget:         unlimited"),
+        }
+        tracing::info!("  Reserved:          {:>6} MB  (CUDA runtime)", report.reserved_mb);
+        for m in report.modules.iter() {
+            tracing::info!(
+                "  {:<18} {:>6} MB  + {} MB/slot × {}",
+                m.name,
+                m.weights_mb,
+                m.slot_mb,
+                m.static_slots
+            );
+        }
+        tracing::info!("  Committed:         {:>6} MB", report.committed_mb);
+        tracing::info!("{}", line);
+    }
+}
+
+/// Memory held by an admitted stream.
+pub struct Admission {
+    budget: Arc<MemoryBudget>,
+    name: Option<String>,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        let Some(name) = self.name.as_ref() else { return };
+        let mut modules = self.budget.modules.lock().unwrap();
+        if let Some(m) = modules.get_mut(name) {
+            m.active_slots = m.active_slots.saturating_sub(1);
+        }
+        self.budget.update_metrics(&modules);
+    }
+}
+
+fn dtype_bytes(dtype_override: Option<&str>, gpu: Option<&utils::GpuInfo>) -> u64 {
+    match dtype_override.or(gpu.map(|g| g.recommended_dtype())) {
+        Some("bf16") | Some("f16") => 2,
+        _ => 4,
+    }
+}
+
+/// Weights of a model and of its Mimi.
+fn weights_estimate_mb(info: &utils::ModelInfo, dtype_bytes: u64) -> u64 {
+    let lm_mb = info.estimated_params_billions() * 1e9 * dtype_bytes as f64 / 1048576.;
+    lm_mb.ceil() as u64 + utils::DEFAULT_MIMI_ESTIMATE_MB
+}
+
+/// Build the budget for the configured modules and cap the batch sizes so that the batched
+/// modules fit. Modules are registered by path, the key used for admission.
+pub fn plan(
+    config: &MemoryConfig,
+    modules: &mut HashMap<String, ModuleConfig>,
+    gpu: Option<&utils::GpuInfo>,
+) -> MemoryBudget {
+    let budget_mb = match (config.budget_mb, gpu) {
+        (Some(b), _) => Some(b),
+        (None, Some(gpu)) => Some(gpu.free_vram_mb().saturating_sub(config.reserved_mb)),
+        (None, None) => None,
+    };
+    let budget = MemoryBudget::new(budget_mb, config.reserved_mb);
+    let mut names: Vec<_> = modules.keys().cloned().collect();
+    names.sort();
+    // Register all the weights first so that the batched modules only get what is left.
+    for name in names.iter() {
+        let mut shadow = None;
+        let (path, model, info, dtype) = match &modules[name] {
+            ModuleConfig::Asr { path, config: c, .. }
+            | ModuleConfig::BatchedAsr { path, config: c, .. }
+            | ModuleConfig::Vad { path, config: c, .. } => {
+                shadow = c.shadow.as_ref().map(|s| (s, s.model.as_ref().unwrap_or(&c.model)));
+                (path, &c.model, utils::ModelInfo::from_asr_config(c), c.dtype_override.as_deref())
+            }
+            ModuleConfig::Tts { path, config: c, .. } => {
+                (path, &c.model, utils::ModelInfo::from_tts_config(c), c.dtype_override.as_deref())
+            }
+            ModuleConfig::Lm { path, config: c, .. } => {
+                (path, &c.model, utils::ModelInfo::from_lm_config(c), c.dtype_override.as_deref())
+            }
+            ModuleConfig::Mimi { send_path, .. } => {
+                let weights_mb = config.modules.get(name).and_then(|c| c.weights_mb);
+                let weights_mb = weights_mb.unwrap_or(utils::DEFAULT_MIMI_ESTIMATE_MB);
+                budget.register(send_path, weights_mb, 0);
+                continue;
+            }
+        };
+        let dtype_bytes = dtype_bytes(dtype, gpu);
+        let overrides = config.modules.get(name).cloned().unwrap_or_default();
+        let mut weights_mb =
+            overrides.weights_mb.unwrap_or_else(|| weights_estimate_mb(&info, dtype_bytes));
+        let slot_mb = overrides.slot_mb.unwrap_or_else(|| {
+            let estimate = utils::estimate_per_batch_item_mb(model, dtype_bytes, gpu);
+            tracing::debug!(
+                module = name,
+                raw_kv_mb = estimate.raw_kv_mb,
+                overhead_multiplier = estimate.overhead_multiplier,
+                slot_mb = estimate.estimated_mb,
+                "Estimated per-slot memory"
+            );
+            estimate.estimated_mb
+        });
+        // The shadow loads its own model and runs its sessions next to the slots of the module.
+        if let Some((shadow, model)) = shadow {
+            let info = utils::ModelInfo::from_model(&shadow.lm_model_file, model);
+            let shadow_slot_mb = utils::estimate_per_batch_item_mb(model, dtype_bytes, gpu);
+            let shadow_mb = weights_estimate_mb(&info, dtype_bytes)
+                + shadow_slot_mb.estimated_mb * shadow.max_sessions as u64;
+            tracing::debug!(module = name, shadow_mb, "Estimated shadow memory");
+            weights_mb += shadow_mb;
+        }
+        budget.register(path, weights_mb, slot_mb);
+    }
+    for name in names.iter() {
+        if let Some(ModuleConfig::BatchedAsr { path, batch_size, .. }) = modules.get_mut(name) {
+            let slots = budget.reserve_slots(path, *batch_size);
+            if slots < *batch_size {
+                tracing::warn!(
+                    module = name,
+                    configured = *batch_size,
+                    adjusted = slots,
+                    "Reducing batch size to fit in the memory budget"
+                );
+                *batch_size = slots;
+            }
+        }
+    }
+    budget
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admission_and_reservation() {
+        let budget = Arc::new(MemoryBudget::new(Some(10_000), 2_000));
+        budget.register("/api/asr-streaming", 4_000, 1_000);
+        budget.register("/api/tts_streaming", 2_000, 1_500);
+        assert_eq!(budget.reserve_slots("/api/asr-streaming", 8), 4);
+        assert_eq!(budget.report().available_mb, Some(0));
+        assert!(budget.admit("/api/tts_streaming").is_err());
+
+        let budget = Arc::new(MemoryBudget::new(Some(10_000), 2_000));
+        budget.register("/api/tts_streaming", 6_000, 1_500);
+        let a1 = budget.admit("/api/tts_streaming").unwrap();
+        let _a2 = budget.admit("/api/tts_streaming").unwrap();
+        let err = budget.admit("/api/tts_streaming").err().unwrap();
+        assert_eq!(err.available_mb, 1_000);
+        drop(a1);
+        assert!(budget.admit("/api/tts_streaming").is_ok());
+        let report = budget.report();
+        assert_eq!(report.modules[0].active_slots, 1);
+        assert_eq!(report.modules[0].rejected, 1);
+        let slots = crate::metrics::memory::MODULE_COMMITTED_BYTES
+            .with_label_values(&["/api/tts_streaming", "slots"])
+            .get();
+        assert_eq!(slots, (1_500 * MB) as f64);
+        assert_eq!(budget.committed_bytes(), 7_500 * MB);
+        // Modules without a cost are always admitted.
+        assert!(budget.admit("/api/unknown").is_ok());
+    }
+
+    #[test]
+    fn plan_counts_the_shadow() {
+        let stt = include_str!("../../../../../configs/stt/config-stt-en_fr-hf.toml");
+        let plan_stt = |cfg: &str| {
+            let mut cfg: crate::Config = toml::from_str(cfg).unwrap();
+            let cost = ModuleCostConfig { weights_mb: Some(4_000), slot_mb: Some(500) };
+            let memory = MemoryConfig {
+                budget_mb: Some(4_000 + 64 * 500),
+                modules: HashMap::from([("asr".to_string(), cost)]),
+                ..MemoryConfig::default()
+            };
+            let budget = plan(&memory, &mut cfg.modules, None);
+            let Some(ModuleConfig::BatchedAsr { batch_size, .. }) = cfg.modules.get("asr") else {
+                panic!("no batched asr module")
+            };
+            (budget.report().modules[0].weights_mb, *batch_size, cfg)
+        };
+        assert_eq!(plan_stt(stt).0, 4_000);
+        assert_eq!(plan_stt(stt).1, 64);
+
+        let shadow = "[modules.asr.shadow]\nlm_model_file = \"new.safetensors\"\nmax_sessions = 2";
+        let shadowed = format!("{stt}\n{shadow}\n");
+        let (weights_mb, batch_size, cfg) = plan_stt(&shadowed);
+        let Some(ModuleConfig::BatchedAsr { config, .. }) = cfg.modules.get("asr") else {
+            panic!("no batched asr module")
+        };
+        let info = utils::ModelInfo::from_asr_config(config);
+        let slot_mb = utils::estimate_per_batch_item_mb(&config.model, 4, None).estimated_mb;
+        assert_eq!(weights_mb, 4_000 + weights_estimate_mb(&info, 4) + 2 * slot_mb);
+        assert_eq!(batch_size, 64 - (weights_mb - 4_000).div_ceil(500) as usize);
+    }
+}
@@ -36,6 +36,133 @@ pub mod asr {
             labels! {"handler" => "all",}
         ))
         .unwrap();
+        pub static ref STREAM_RESUMED: Counter = register_counter!(opts!(
+            "asr_stream_resumed",
+            "Number of sticky streams reattached to their slot after a reconnection.",
+            labels! {"handler" => "all",}
+        ))
+        .unwrap();
+        pub static ref STREAM_EXPIRED: Counter = register_counter!(opts!(
+            "asr_stream_expired",
+            "Number of sticky streams released after their grace period expired.",
+            labels! {"handler" => "all",}
+        ))
+        .unwrap();
+        pub static ref CHECKPOINT_EXPORTED: Counter = register_counter!(opts!(
+            "asr_checkpoint_exported",
+            "Number of session checkpoints sent to clients.",
+            labels! {"handler" => "all",}
+        ))
+        .unwrap();
+        pub static ref CHECKPOINT_RESTORED: Counter = register_counter!(opts!(
+            "asr_checkpoint_restored",
+            "Number of sessions started from a checkpoint.",
+            labels! {"handler" => "all",}
+        ))
+        .unwrap();
+        pub static ref SEGMENTS: Counter = register_counter!(opts!(
+            "asr_segments",
+            "Number of segment boundaries of long sessions, each resets the model state of the slot.",
+            labels! {"handler" => "all",}
+        ))
+        .unwrap();
+        pub static ref BATCH_PREEMPTED: Counter = register_counter!(opts!(
+            "asr_batch_preempted",
+            "Number of batch queries that gave their slot up to an interactive session.",
+            labels! {"handler" => "all",}
+        ))
+        .unwrap();
+        pub static ref SLOT_ERRORS: Counter = register_counter!(opts!(
+            "asr_slot_errors",
+            "Number of inference errors of batched asr sessions, NaN steps or undecodable words.",
+            labels! {"handler" => "all",}
+        ))
+        .unwrap();
+        pub static ref NONFINITE_RECOVERED: Counter = register_counter!(opts!(
+            "asr_nonfinite_recovered",
+            "Number of slot steps with non-finite text logits, recovered by a retry in f32.",
+            labels! {"handler" => "all",}
+        ))
+        .unwrap();
+        pub static ref NONFINITE_RESET: Counter = register_counter!(opts!(
+            "asr_nonfinite_reset",
+            "Number of slot steps with non-finite text logits in f32 too, the slot context was reset.",
+            labels! {"handler" => "all",}
+        ))
+        .unwrap();
+        pub static ref SLOT_TRIPPED: Counter = register_counter!(opts!(
+            "asr_slot_tripped",
+            "Number of batched asr sessions terminated by the circuit breaker.",
+            labels! {"handler" => "all",}
+        ))
+        .unwrap();
+        pub static ref SLOTS_QUARANTINED: Gauge = register_gauge!(opts!(
+            "asr_slots_quarantined",
+            "Number of batched asr slots out of use after their session tripped the breaker.",
+            labels! {"handler" => "all",}
+        ))
+        .unwrap();
+        pub static ref SHADOW_SESSIONS: Counter = register_counter!(opts!(
+            "asr_shadow_sessions",
+            "Number of sessions whose words were compared with the ones of the shadow model.",
+            labels! {"handler" => "all",}
+        ))
+        .unwrap();
+        pub static ref SHADOW_LOST: Counter = register_counter!(opts!(
+            "asr_shadow_lost",
+            "Number of shadowed sessions left out of the comparison, the shadow missed some audio.",
+            labels! {"handler" => "all",}
+        ))
+        .unwrap();
+        pub static ref SHADOW_WER: Histogram = register_histogram!(histogram_opts!(
+            "asr_shadow_wer",
+            "Word error rate of the shadow model, with the production words as the reference.",
+            vec![0.01, 0.02, 0.05, 0.1, 0.2, 0.3, 0.5, 1.0],
+        ))
+        .unwrap();
+        pub static ref OTHER_SPEAKER_WORDS: Counter = register_counter!(opts!(
+            "asr_other_speaker_words",
+            "Number of words not matching the voice enrolled by the session.",
+            labels! {"handler" => "all",}
+        ))
+        .unwrap();
+        /// Time between the first frame of a batched step and the step, with the frames of
+        /// the other slots gathered meanwhile.
+        /// Labels: scheduling (latency, throughput)
+        pub static ref BATCH_WAIT: prometheus::HistogramVec = prometheus::register_histogram_vec!(
+            "asr_batch_wait_seconds",
+            "Time a batched asr step waited for the frames of its slots.",
+            &["scheduling"],
+            vec![1e-3, 5e-3, 10e-3, 20e-3, 40e-3, 60e-3, 80e-3, 120e-3]
+        )
+        .unwrap();
+        /// Share of the active slots with a frame in a batched step.
+        /// Labels: scheduling (latency, throughput)
+        pub static ref BATCH_FILL: prometheus::HistogramVec = prometheus::register_histogram_vec!(
+            "asr_batch_fill_ratio",
+            "Share of the active slots with a frame in a batched asr step.",
+            &["scheduling"],
+            vec![0.1, 0.25, 0.5, 0.75, 0.9, 1.0]
+        )
+        .unwrap();
+    }
+}
+
+pub mod vad {
+    use super::*;
+    lazy_static! {
+        pub static ref CONNECT: Counter = register_counter!(opts!(
+            "vad_connect",
+            "Number of connections to the vad.",
+            labels! {"handler" => "all",}
+        ))
+        .unwrap();
+        pub static ref UTTERANCE_BOUNDARIES: Counter = register_counter!(opts!(
+            "vad_utterance_boundaries",
+            "Number of speech start and end events sent by the vad.",
+            labels! {"handler" => "all",}
+        ))
+        .unwrap();
     }
 }
 
@@ -94,6 +221,18 @@ pub mod stream {
     }
 }
 
+pub mod tasks {
+    use super::*;
+    use prometheus::{register_int_gauge, IntGauge};
+    lazy_static! {
+        pub static ref SESSION_TASKS: IntGauge = register_int_gauge!(
+            "session_tasks",
+            "Number of running tasks of the websocket sessions."
+        )
+        .unwrap();
+    }
+}
+
 pub mod warmup {
     use super::*;
     lazy_static! {
@@ -165,6 +304,15 @@ pub mod errors {
             &["error_type"]
         )
         .unwrap();
+
+        /// REST errors by stable error code, auth errors are counted in auth_error_total.
+        /// Labels: code (invalid_voice, bad_audio, model_busy, internal_error, ...)
+        pub static ref API_ERROR_TOTAL: IntCounterVec = register_int_counter_vec!(
+            "api_error_total",
+            "Total REST error responses by error code.",
+            &["code"]
+        )
+        .unwrap();
     }
 
     /// Record a WebSocket close event.
@@ -177,12 +325,19 @@ pub mod errors {
     /// Record a connection error.
     pub fn record_connection_error(error_type: &str, module: &str) {
         CONNECTION_ERROR_TOTAL.with_label_values(&[error_type, module]).inc();
+        let (module, error) = (module.to_string(), error_type.to_string());
+        crate::events::emit(crate::events::Event::Error { module, error });
     }
 
     /// Record an authentication error.
     pub fn record_auth_error(error_type: &str) {
         AUTH_ERROR_TOTAL.with_label_values(&[error_type]).inc();
     }
+
+    /// Record a REST error response.
+    pub fn record_api_error(code: &str) {
+        API_ERROR_TOTAL.with_label_values(&[code]).inc();
+    }
 }
 
 /// LM inference performance metrics.
@@ -285,6 +440,23 @@ pub mod mimi {
             vec![0.005, 0.010, 0.020, 0.030, 0.050, 0.075, 0.100, 0.150],
         ))
         .unwrap();
+
+        /// Room messages dropped for lagging listeners.
+        pub static ref RECV_DROPPED: prometheus::IntCounterVec =
+            prometheus::register_int_counter_vec!(
+                "mimi_recv_dropped_messages_total",
+                "Room messages dropped for listeners that could not keep up, by room.",
+                &["room"]
+            )
+            .unwrap();
+
+        /// Messages dropped over the connection of each listener.
+        pub static ref RECV_DROPPED_PER_LISTENER: Histogram = register_histogram!(histogram_opts!(
+            "mimi_recv_dropped_messages_per_listener",
+            "Room messages dropped over the connection of a listener.",
+            vec![0.0, 1.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0],
+        ))
+        .unwrap();
     }
 
     /// Record an encode operation with its duration.
@@ -347,6 +519,56 @@ pub mod tts {
             vec![0.01, 0.02, 0.05, 0.1, 0.2, 0.3, 0.5, 0.75, 1.0],
         ))
         .unwrap();
+
+        /// Latency of the machine translation backend for translated requests.
+        pub static ref TRANSLATION_DURATION: Histogram = register_histogram!(histogram_opts!(
+            "tts_translation_duration_seconds",
+            "TTS input translation latency distribution.",
+            vec![0.05, 0.1, 0.2, 0.3, 0.5, 0.75, 1.0, 2.0, 5.0],
+        ))
+        .unwrap();
+
+        /// Outputs that went over `spill_threshold_mb` and were written to disk.
+        pub static ref SPILLED: IntCounter = register_int_counter!(
+            "tts_spilled_outputs_total",
+            "TTS outputs spilled to a temporary file."
+        )
+        .unwrap();
+
+        /// Encoding time of a chunk of streamed audio on the encoding pool.
+        pub static ref ENCODE_DURATION: Histogram = register_histogram!(histogram_opts!(
+            "tts_encode_duration_seconds",
+            "TTS streamed audio chunk encoding latency distribution.",
+            vec![0.0005, 0.001, 0.002, 0.005, 0.010, 0.020, 0.050],
+        ))
+        .unwrap();
+
+        /// Streamed audio chunks dropped because the encoding queue of their stream was full.
+        pub static ref ENCODE_DROPPED: IntCounter = register_int_counter!(
+            "tts_encode_dropped_chunks_total",
+            "TTS streamed audio chunks dropped by a lagging encoder."
+        )
+        .unwrap();
+
+        /// Requests answered from the output cache, and the ones that were synthesized.
+        pub static ref CACHE_HITS: IntCounter =
+            register_int_counter!("tts_cache_hits_total", "TTS requests served from the cache.")
+                .unwrap();
+        pub static ref CACHE_MISSES: IntCounter = register_int_counter!(
+            "tts_cache_misses_total",
+            "TTS requests not found in the cache."
+        )
+        .unwrap();
+        pub static ref CACHE_EVICTIONS: IntCounter = register_int_counter!(
+            "tts_cache_evictions_total",
+            "TTS cache entries evicted to make room."
+        )
+        .unwrap();
+        pub static ref CACHE_ENTRIES: Gauge =
+            register_gauge!(opts!("tts_cache_entries", "Outputs held by the TTS cache.")).unwrap();
+        pub static ref CACHE_BYTES: Gauge =
+            register_gauge!(opts!("tts_cache_bytes", "Size of the outputs held by the TTS cache."))
+                .unwrap();
     }
 
     /// Record a TTS synthesis with its duration and audio length.
@@ -397,6 +619,61 @@ pub mod memory {
             "Total bytes deallocated on GPU."
         ))
         .unwrap();
+
+        /// VRAM committed in the memory budget, weights and slots, in MB.
+        pub static ref BUDGET_COMMITTED_MB: Gauge = register_gauge!(opts!(
+            "memory_budget_committed_mb",
+            "VRAM committed in the memory budget in MB."
+        ))
+        .unwrap();
+
+        /// Streams refused because the memory budget was exhausted.
+        /// Labels: module (path)
+        pub static ref ADMISSION_REJECTED: prometheus::IntCounterVec =
+            prometheus::register_int_counter_vec!(
+                "memory_admission_rejected_total",
+                "Streams refused because the memory budget was exhausted.",
+                &["module"]
+            )
+            .unwrap();
+
+        /// VRAM committed by a module in the memory budget, in bytes.
+        /// Labels: module (path), kind (weights, slots)
+        pub static ref MODULE_COMMITTED_BYTES: prometheus::GaugeVec =
+            prometheus::register_gauge_vec!(
+                "memory_module_committed_bytes",
+                "VRAM committed by a module in the memory budget in bytes.",
+                &["module", "kind"]
+            )
+            .unwrap();
+
+        /// Cost of one stream of a module, KV cache and activations, in bytes.
+        /// Labels: module (path)
+        pub static ref MODULE_SLOT_BYTES: prometheus::GaugeVec =
+            prometheus::register_gauge_vec!(
+                "memory_module_slot_bytes",
+                "Cost of one stream of a module in bytes.",
+                &["module"]
+            )
+            .unwrap();
+
+        /// Slots of a module holding memory.
+        /// Labels: module (path), state (static, active)
+        pub static ref MODULE_SLOTS: prometheus::IntGaugeVec =
+            prometheus::register_int_gauge_vec!(
+                "memory_module_slots",
+                "Slots of a module holding memory.",
+                &["module", "state"]
+            )
+            .unwrap();
+
+        /// Used VRAM not accounted for in the memory budget, negative when the modules use
+        /// less than committed.
+        pub static ref UNATTRIBUTED_VRAM_BYTES: Gauge = register_gauge!(opts!(
+            "memory_unattributed_vram_bytes",
+            "Used VRAM not accounted for in the memory budget in bytes."
+        ))
+        .unwrap();
     }
 
     /// Update VRAM usage metrics.
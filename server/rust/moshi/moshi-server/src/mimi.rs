@@ -2,7 +2,8 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
-use crate::protocol::MsgType;
+use crate::auth::BetterAuthClaims;
+use crate::protocol::{CloseCode, MsgType};
 use anyhow::Result;
 use axum::extract::ws;
 use candle::{Device, IndexOp, Tensor};
@@ -10,6 +11,35 @@ use std::sync::Arc;
 
 use kaudio::ogg_opus;
 
+pub(crate) const FRAME_SIZE: usize = 1920;
+pub(crate) const FRAME_DURATION: std::time::Duration = std::time::Duration::from_millis(80);
+/// Audio buffered per publisher before the oldest samples get dropped, 1s.
+const MAX_QUEUED_SAMPLES: usize = 24_000;
+const LIMITER_THRESHOLD: f32 = 0.95;
+
+/// What a listener that fell more than `recv_buffer` messages behind the room gets next.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LagPolicy {
+    /// Drop all the buffered messages and continue with the live audio.
+    #[default]
+    SkipToLive,
+    /// Drop the messages that were overwritten only, the listener stays up to `recv_buffer`
+    /// messages behind.
+    DropOldest,
+}
+
+/// Wire format used to stream the room audio to listeners.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RecvFormat {
+    /// Moshi binary protocol: handshake then type-prefixed Ogg/Opus, text and ping messages.
+    #[default]
+    Moshi,
+    /// Bare Ogg/Opus pages, playable by listeners without any Moshi or Mimi decoding.
+    OggOpus,
+}
+
 struct Sender {
     tx: tokio::sync::broadcast::Sender<ws::Message>,
     encoder: kaudio::ogg_opus::Encoder,
@@ -38,20 +68,256 @@ impl Sender {
     }
 }
 
+/// Peak limiter applied to the mixed frames, the gain drops instantly and recovers over a
+/// few frames.
+struct Limiter {
+    gain: f32,
+}
+
+impl Limiter {
+    fn process(&mut self, pcm: &mut [f32]) {
+        let peak = pcm.iter().fold(0f32, |m, v| m.max(v.abs()));
+        let target = if peak > LIMITER_THRESHOLD { LIMITER_THRESHOLD / peak } else { 1.0 };
+        let prev = self.gain;
+        self.gain = if target < prev { target } else { prev + (target - prev) * 0.2 };
+        let len = pcm.len().max(1) as f32;
+        for (i, v) in pcm.iter_mut().enumerate() {
+            let gain = prev + (self.gain - prev) * (i + 1) as f32 / len;
+            *v = (*v * gain).clamp(-1.0, 1.0)
+        }
+    }
+}
+
+struct Publisher {
+    queue: std::collections::VecDeque<f32>,
+    gain: f32,
+    muted: bool,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PublisherInfo {
+    pub name: String,
+    pub gain: f32,
+    pub muted: bool,
+    pub queued_ms: u64,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct PublisherUpdate {
+    pub gain: Option<f32>,
+    pub muted: Option<bool>,
+}
+
+/// Sums the audio of the room publishers.
+///
+/// A frame is mixed as soon as every unmuted publisher has one queued, or once a frame
+/// duration has elapsed since the previous one, the publishers lagging behind are then padded
+/// with silence. A single publisher is therefore forwarded at its own pace.
+struct Mixer {
+    publishers: std::collections::BTreeMap<String, Publisher>,
+    default_gains: std::collections::HashMap<String, f32>,
+    max_publishers: usize,
+    next_id: usize,
+    last_frame: std::time::Instant,
+    limiter: Limiter,
+}
+
+impl Mixer {
+    fn new(default_gains: std::collections::HashMap<String, f32>, max_publishers: usize) -> Self {
+        Self {
+            publishers: Default::default(),
+            default_gains,
+            max_publishers,
+            next_id: 0,
+            last_frame: std::time::Instant::now(),
+            limiter: Limiter { gain: 1.0 },
+        }
+    }
+
+    fn add(&mut self, name: Option<String>) -> Result<String> {
+        if self.publishers.len() >= self.max_publishers {
+            anyhow::bail!("room already has {} publishers", self.max_publishers)
+        }
+        let name = match name {
+            Some(name) => name,
+            None => loop {
+                self.next_id += 1;
+                let name = format!("publisher-{}", self.next_id);
+                if !self.publishers.contains_key(&name) {
+                    break name;
+                }
+            },
+        };
+        if self.publishers.contains_key(&name) {
+            anyhow::bail!("publisher {name} is already connected")
+        }
+        let gain = self.default_gains.get(&name).copied().unwrap_or(1.0);
+        let publisher = Publisher { queue: Default::default(), gain, muted: false };
+        self.publishers.insert(name.clone(), publisher);
+        Ok(name)
+    }
+
+    fn push(&mut self, name: &str, pcm: &[f32]) {
+        let Some(p) = self.publishers.get_mut(name) else { return };
+        if p.muted {
+            return;
+        }
+        p.queue.extend(pcm);
+        let excess = p.queue.len().saturating_sub(MAX_QUEUED_SAMPLES);
+        p.queue.drain(..excess);
+    }
+
+    fn pop_frame(&mut self, now: std::time::Instant) -> Option<Vec<f32>> {
+        let live = || self.publishers.values().filter(|p| !p.muted);
+        if live().all(|p| p.queue.is_empty()) {
+            return None;
+        }
+        let all_ready = live().all(|p| p.queue.len() >= FRAME_SIZE);
+        if !all_ready && now < self.last_frame + FRAME_DURATION {
+            return None;
+        }
+        let mut frame = vec![0f32; FRAME_SIZE];
+        for p in self.publishers.values_mut() {
+            let n = p.queue.len().min(FRAME_SIZE);
+            for (dst, src) in frame.iter_mut().zip(p.queue.drain(..n)) {
+                *dst += src * p.gain
+            }
+        }
+        self.limiter.process(&mut frame);
+        self.last_frame = now;
+        Some(frame)
+    }
+
+    fn info(&self) -> Vec<PublisherInfo> {
+        self.publishers
+            .iter()
+            .map(|(name, p)| PublisherInfo {
+                name: name.clone(),
+                gain: p.gain,
+                muted: p.muted,
+                queued_ms: (p.queue.len() * 1000 / 24_000) as u64,
+            })
+            .collect()
+    }
+
+    fn update(&mut self, name: &str, update: &PublisherUpdate) -> Result<Option<PublisherInfo>> {
+        let Some(p) = self.publishers.get_mut(name) else { return Ok(None) };
+        if let Some(gain) = update.gain {
+            if !gain.is_finite() || gain < 0.0 {
+                anyhow::bail!("invalid gain {gain}")
+            }
+            p.gain = gain
+        }
+        if let Some(muted) = update.muted {
+            p.muted = muted;
+            if muted {
+                p.queue.clear()
+            }
+        }
+        Ok(self.info().into_iter().find(|p| p.name == name))
+    }
+}
+
+/// Removes the publisher from the room mixer when the connection ends.
+struct PublisherGuard {
+    mixer: Arc<std::sync::Mutex<Mixer>>,
+    name: String,
+}
+
+impl Drop for PublisherGuard {
+    fn drop(&mut self) {
+        self.mixer.lock().unwrap().publishers.remove(&self.name);
+    }
+}
+
+/// Room messages of a listener. Each listener has its own position in the room broadcast, a
+/// slow one drops messages instead of holding the room back or buffering without bounds.
+struct Subscriber {
+    rx: tokio::sync::broadcast::Receiver<ws::Message>,
+    policy: LagPolicy,
+    room_id: String,
+    dropped: u64,
+}
+
+impl Subscriber {
+    async fn recv(&mut self) -> Option<ws::Message> {
+        use tokio::sync::broadcast::error::RecvError;
+        loop {
+            let dropped = match self.rx.recv().await {
+                Ok(msg) => return Some(msg),
+                Err(RecvError::Closed) => return None,
+                Err(RecvError::Lagged(n)) => match self.policy {
+                    LagPolicy::DropOldest => n,
+                    LagPolicy::SkipToLive => {
+                        let buffered = self.rx.len() as u64;
+                        self.rx = self.rx.resubscribe();
+                        n + buffered
+                    }
+                },
+            };
+            if self.dropped == 0 {
+                tracing::warn!(room_id = self.room_id, dropped, "listener lagging behind the room");
+            }
+            self.dropped += dropped;
+            crate::metrics::mimi::RECV_DROPPED.with_label_values(&[&self.room_id]).inc_by(dropped);
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        crate::metrics::mimi::RECV_DROPPED_PER_LISTENER.observe(self.dropped as f64);
+        if self.dropped > 0 {
+            tracing::info!(room_id = self.room_id, dropped = self.dropped, "lagging listener left");
+        }
+    }
+}
+
 struct Room {
     sender: Arc<tokio::sync::Mutex<Sender>>,
+    mixer: Arc<std::sync::Mutex<Mixer>>,
+    mixer_notify: Arc<tokio::sync::Notify>,
     header_message: ws::Message,
-    rx: tokio::sync::broadcast::Receiver<ws::Message>,
+    tx: tokio::sync::broadcast::Sender<ws::Message>,
+    /// Mixed audio of the room, for the bridges.
+    pcm_tx: tokio::sync::broadcast::Sender<crate::mimi_bridge::PcmFrame>,
+    lag_policy: LagPolicy,
 }
 
 impl Room {
-    fn new() -> Result<Self> {
-        let (tx, rx) = tokio::sync::broadcast::channel(10);
+    fn new(mimi: &crate::MimiConfig) -> Result<Self> {
+        let (tx, _) = tokio::sync::broadcast::channel(mimi.recv_buffer.max(1));
+        let (pcm_tx, _) = tokio::sync::broadcast::channel(mimi.recv_buffer.max(1));
         let encoder = ogg_opus::Encoder::new(24_000)?;
         let header_message: Vec<u8> = [&[MsgType::Audio.to_u8()], encoder.header_data()].concat();
         let header_message = ws::Message::Binary(header_message.into());
-        let sender = Sender { tx, encoder };
+        let sender = Sender { tx: tx.clone(), encoder };
         let sender = Arc::new(tokio::sync::Mutex::new(sender));
+        let mixer = Mixer::new(mimi.publisher_gains.clone(), mimi.max_publishers);
+        let mixer = Arc::new(std::sync::Mutex::new(mixer));
+        let mixer_notify = Arc::new(tokio::sync::Notify::new());
+        tokio::spawn({
+            let sender = sender.clone();
+            let mixer = mixer.clone();
+            let notify = mixer_notify.clone();
+            let pcm_tx = pcm_tx.clone();
+            async move {
+                loop {
+                    tokio::select! {
+                        _ = notify.notified() => {}
+                        _ = tokio::time::sleep(FRAME_DURATION) => {}
+                    }
+                    loop {
+                        let frame = mixer.lock().unwrap().pop_frame(std::time::Instant::now());
+                        let Some(frame) = frame else { break };
+                        if let Err(err) = sender.lock().await.send_pcm(&frame) {
+                            tracing::error!(?err, "failed to encode the room audio");
+                        }
+                        let _ = pcm_tx.send(Arc::new(frame));
+                    }
+                }
+            }
+        });
         tokio::spawn({
             let sender = sender.clone();
             async move {
@@ -62,7 +328,98 @@ impl Room {
                 }
             }
         });
-        Ok(Self { sender, header_message, rx })
+        let lag_policy = mimi.recv_lag_policy;
+        Ok(Self { sender, mixer, mixer_notify, header_message, tx, pcm_tx, lag_policy })
+    }
+
+    fn subscribe(&self, room_id: &str) -> Subscriber {
+        let rx = self.tx.subscribe();
+        Subscriber { rx, policy: self.lag_policy, room_id: room_id.to_string(), dropped: 0 }
+    }
+}
+
+/// Why a connection cannot join a room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomError {
+    Missing,
+    NotFound(String),
+    Forbidden(String),
+}
+
+impl std::fmt::Display for RoomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing => write!(f, "no room_id provided"),
+            Self::NotFound(room_id) => write!(f, "unknown room {room_id}"),
+            Self::Forbidden(room_id) => write!(f, "not authorized for room {room_id}"),
+        }
+    }
+}
+
+impl std::error::Error for RoomError {}
+
+impl RoomError {
+    pub fn close_code(&self) -> CloseCode {
+        match self {
+            Self::Missing => CloseCode::InvalidMessage,
+            Self::NotFound(_) => CloseCode::ResourceUnavailable,
+            Self::Forbidden(_) => CloseCode::Forbidden,
+        }
+    }
+
+    pub fn status(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+        match self {
+            Self::Missing => StatusCode::BAD_REQUEST,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Missing => "missing_room",
+            Self::NotFound(_) => "unknown_room",
+            Self::Forbidden(_) => "room_forbidden",
+        }
+    }
+}
+
+/// Which rooms a connection may join. JWT users get the rooms of the `rooms` claim of their
+/// token and those that `room_roles` maps their role to, `*` standing for all the rooms.
+#[derive(Debug)]
+struct RoomAccess {
+    rooms: std::collections::HashSet<String>,
+    default_room: Option<String>,
+    room_roles: std::collections::HashMap<String, Vec<String>>,
+}
+
+impl RoomAccess {
+    /// The room to join, the default room when `room_id` is not given.
+    fn authorize(
+        &self,
+        room_id: Option<&str>,
+        claims: Option<&BetterAuthClaims>,
+    ) -> std::result::Result<String, RoomError> {
+        let room_id = room_id.or(self.default_room.as_deref()).ok_or(RoomError::Missing)?;
+        if !self.rooms.contains(room_id) {
+            return Err(RoomError::NotFound(room_id.to_string()));
+        }
+        if !self.allows(room_id, claims) {
+            return Err(RoomError::Forbidden(room_id.to_string()));
+        }
+        Ok(room_id.to_string())
+    }
+
+    fn allows(&self, room_id: &str, claims: Option<&BetterAuthClaims>) -> bool {
+        // Public modules and API keys carry no identity, all the rooms are open to them.
+        let Some(user) = claims.map(|c| &c.user) else { return true };
+        let by_role = user.role.as_deref().and_then(|role| self.room_roles.get(role));
+        if user.rooms.is_none() && by_role.is_none() {
+            // Tokens without room grants keep access to all the rooms until roles are set up.
+            return self.room_roles.is_empty();
+        }
+        user.rooms.iter().chain(by_role).flatten().any(|r| r == "*" || r == room_id)
     }
 }
 
@@ -71,11 +428,12 @@ pub struct Mimi {
     device: Device,
     #[allow(unused)]
     instance_name: String,
-    auth_recv: bool,
     #[allow(unused)]
     log_dir: std::path::PathBuf,
     rooms: std::collections::HashMap<String, Room>,
-    default_room: Option<String>,
+    access: RoomAccess,
+    /// Directories of the HLS bridges, by room.
+    hls_dirs: std::collections::HashMap<String, std::path::PathBuf>,
 }
 
 impl Mimi {
@@ -83,60 +441,96 @@ impl Mimi {
         let audio_tokenizer = moshi::mimi::load(&mimi.audio_tokenizer_file, Some(8), dev)?;
         let mut rooms = std::collections::HashMap::new();
         for room in mimi.rooms.iter() {
-            rooms.insert(room.to_string(), Room::new()?);
+            rooms.insert(room.to_string(), Room::new(mimi)?);
+        }
+        let mut hls_dirs = std::collections::HashMap::new();
+        for bridge in mimi.bridges.iter() {
+            let Some(room) = rooms.get(bridge.room()) else {
+                anyhow::bail!("bridge for unknown room {}", bridge.room())
+            };
+            if let Some(dir) = crate::mimi_bridge::spawn(bridge, &room.pcm_tx, &config.static_dir)?
+            {
+                if hls_dirs.insert(bridge.room().to_string(), dir).is_some() {
+                    anyhow::bail!("more than one hls bridge for room {}", bridge.room())
+                }
+            }
         }
 
+        let access = RoomAccess {
+            rooms: rooms.keys().cloned().collect(),
+            default_room: mimi.default_room.clone(),
+            room_roles: mimi.room_roles.clone(),
+        };
         Ok(Self {
             audio_tokenizer,
             device: dev.clone(),
             log_dir: config.log_dir.clone().into(),
             instance_name: config.instance_name.clone(),
-            auth_recv: mimi.auth_recv,
-            default_room: mimi.default_room.clone(),
             rooms,
+            access,
+            hls_dirs,
         })
     }
 
-    pub fn auth_recv(&self) -> bool {
-        self.auth_recv
+    /// The directory of the HLS bridge of a room, if it has one.
+    pub fn hls_dir(&self, room_id: &str) -> Option<&std::path::Path> {
+        self.hls_dirs.get(room_id).map(|d| d.as_path())
+    }
+
+    /// The room a connection joins, checked against the room grants of its JWT claims.
+    pub fn authorize(
+        &self,
+        room_id: Option<&str>,
+        claims: Option<&BetterAuthClaims>,
+    ) -> std::result::Result<String, RoomError> {
+        self.access.authorize(room_id, claims)
     }
 
-    pub async fn recv_socket(&self, socket: ws::WebSocket, room_id: Option<String>) -> Result<()> {
+    pub async fn recv_socket(
+        &self,
+        socket: crate::limits::LimitedSocket,
+        room_id: String,
+        format: RecvFormat,
+    ) -> Result<()> {
         use futures_util::{SinkExt, StreamExt};
 
-        let room_id = match (room_id, self.default_room.as_ref()) {
-            (Some(r), _) => r,
-            (None, Some(d)) => d.to_string(),
-            (None, None) => anyhow::bail!("no room_id provided"),
-        };
         let room = match self.rooms.get(&room_id) {
             None => anyhow::bail!("unknown room"),
             Some(room) => room,
         };
 
-        // Re-subscribe early to have more chances to have a message immediately available.
-        let mut rx = room.rx.resubscribe();
+        // Subscribe early to have more chances to have a message immediately available.
+        let mut subscriber = room.subscribe(&room_id);
         let (mut ws_sender, mut ws_receiver) = socket.split();
         let recv_loop = async move { while ws_receiver.next().await.is_some() {} };
-        let mut handshake = vec![MsgType::Handshake.to_u8()];
-        handshake.resize(9, 0u8);
-        if let Err(err) = ws_sender.send(ws::Message::binary(handshake)).await {
-            tracing::error!("error sending header {err:?}");
-            return Ok(());
-        }
-        if let Err(err) = ws_sender.send(room.header_message.clone()).await {
+        let header_message = match format {
+            RecvFormat::Moshi => {
+                let mut handshake = vec![MsgType::Handshake.to_u8()];
+                handshake.resize(9, 0u8);
+                if let Err(err) = ws_sender.send(ws::Message::binary(handshake)).await {
+                    tracing::error!("error sending header {err:?}");
+                    return Ok(());
+                }
+                room.header_message.clone()
+            }
+            RecvFormat::OggOpus => match ogg_opus_message(room.header_message.clone()) {
+                Some(msg) => msg,
+                None => anyhow::bail!("unexpected room header"),
+            },
+        };
+        if let Err(err) = ws_sender.send(header_message).await {
             tracing::error!("error sending header {err:?}");
             return Ok(());
         }
         let send_loop = async move {
-            loop {
-                let msg = match rx.recv().await {
-                    Ok(msg) => msg,
-                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
-                    Err(tokio::sync::broadcast::error::RecvError::Closed) => {
-                        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
-                        continue;
-                    }
+            // The room tasks hold the sender forever, the channel only closes with the server.
+            while let Some(msg) = subscriber.recv().await {
+                let msg = match format {
+                    RecvFormat::Moshi => msg,
+                    RecvFormat::OggOpus => match ogg_opus_message(msg) {
+                        Some(msg) => msg,
+                        None => continue,
+                    },
                 };
                 if let Err(err) = ws_sender.send(msg).await {
                     tracing::error!("exiting recv loop, error in send: {err:?}");
@@ -151,7 +545,38 @@ impl Mimi {
         Ok(())
     }
 
-    pub async fn send_socket(&self, socket: ws::WebSocket, room_id: String) -> Result<()> {
+    fn room(&self, room_id: Option<&str>) -> Result<&Room> {
+        let room_id = match (room_id, self.access.default_room.as_deref()) {
+            (Some(r), _) | (None, Some(r)) => r,
+            (None, None) => anyhow::bail!("no room_id provided"),
+        };
+        match self.rooms.get(room_id) {
+            None => anyhow::bail!("unknown room {room_id}"),
+            Some(room) => Ok(room),
+        }
+    }
+
+    /// The publishers currently connected to a room.
+    pub fn publishers(&self, room_id: Option<&str>) -> Result<Vec<PublisherInfo>> {
+        Ok(self.room(room_id)?.mixer.lock().unwrap().info())
+    }
+
+    /// Change the gain or mute state of a connected publisher, `None` if it is not connected.
+    pub fn update_publisher(
+        &self,
+        room_id: Option<&str>,
+        name: &str,
+        update: &PublisherUpdate,
+    ) -> Result<Option<PublisherInfo>> {
+        self.room(room_id)?.mixer.lock().unwrap().update(name, update)
+    }
+
+    pub async fn send_socket(
+        &self,
+        socket: crate::limits::LimitedSocket,
+        room_id: String,
+        publisher: Option<String>,
+    ) -> Result<()> {
         use futures_util::StreamExt;
 
         tracing::info!("connected to sender for {room_id}");
@@ -159,14 +584,20 @@ impl Mimi {
             None => anyhow::bail!("unknown room"),
             Some(room) => room,
         };
-        let mut sender = match room.sender.try_lock() {
-            Ok(s) => s,
-            Err(_) => anyhow::bail!("already a producer"),
+        let name = room.mixer.lock().unwrap().add(publisher)?;
+        tracing::info!(room_id, publisher = name, "publisher joined");
+        let publisher = PublisherGuard { mixer: room.mixer.clone(), name };
+        let push_pcm = |pcm: Option<Vec<f32>>| {
+            if let Some(pcm) = pcm {
+                room.mixer.lock().unwrap().push(&publisher.name, &pcm);
+                room.mixer_notify.notify_one();
+            }
         };
         let (_ws_sender, mut ws_receiver) = socket.split();
         let mut audio_tokenizer = self.audio_tokenizer.clone();
+        // Only created when the producer pushes Ogg/Opus audio rather than Mimi codes.
+        let mut ogg_opus_decoder = None;
 
-        let mut pcm_all = vec![];
         while let Some(msg) = ws_receiver.next().await {
             let msg = match msg? {
                 ws::Message::Binary(b) => b.to_vec(),
@@ -178,7 +609,7 @@ impl Mimi {
             match MsgType::from_u8(msg[0]) {
                 Ok(MsgType::Text) => {
                     // Forward directly the text messages.
-                    sender.send_raw(&msg)?;
+                    room.sender.lock().await.send_raw(&msg)?;
                 }
                 Ok(MsgType::Codes) => {
                     let codes: Vec<u32> = msg[1..]
@@ -188,19 +619,29 @@ impl Mimi {
                     let ncodes = codes.len();
                     // Using Tensor::from_vec is faster.
                     let codes = Tensor::from_vec(codes, (1, ncodes, 1), &self.device)?;
-                    let pcm = audio_tokenizer.decode_step(&codes.into(), &().into())?;
-                    if let Some(pcm) = pcm.as_option() {
-                        let pcm = pcm.i((0, 0))?.to_vec1::<f32>()?;
-                        for v in pcm.into_iter() {
-                            pcm_all.push(v);
-                            if pcm_all.len() == 1920 {
-                                sender.send_pcm(&pcm_all)?;
-                                pcm_all.clear();
-                            }
-                        }
-                        // Sleep to avoid starving the scheduler.
-                        tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+                    push_pcm(decode_codes(&mut audio_tokenizer, &codes)?);
+                    // Sleep to avoid starving the scheduler.
+                    tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+                }
+                Ok(MsgType::Audio) => {
+                    // Ogg/Opus from lightweight producers, transcoded to Mimi codes so that the
+                    // room audio goes through the same codec path as for code producers.
+                    let decoder = match ogg_opus_decoder.as_mut() {
+                        Some(decoder) => decoder,
+                        None => ogg_opus_decoder
+                            .insert(kaudio::ogg_opus::Decoder::new(24_000, FRAME_SIZE)?),
+                    };
+                    let pcm = match decoder.decode(&msg[1..])? {
+                        None => continue,
+                        Some(pcm) => pcm.to_vec(),
+                    };
+                    let pcm_len = pcm.len();
+                    let pcm = Tensor::from_vec(pcm, (1, 1, pcm_len), &self.device)?;
+                    let codes = audio_tokenizer.encode_step(&pcm.into(), &().into())?;
+                    if let Some(codes) = codes.as_option() {
+                        push_pcm(decode_codes(&mut audio_tokenizer, codes)?);
                     }
+                    tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
                 }
                 t => {
                     tracing::warn!("unexpected msg type {t:?}");
@@ -213,3 +654,163 @@ impl Mimi {
         Ok(())
     }
 }
+
+fn decode_codes(
+    audio_tokenizer: &mut moshi::mimi::Mimi,
+    codes: &Tensor,
+) -> Result<Option<Vec<f32>>> {
+    let pcm = audio_tokenizer.decode_step(&codes.clone().into(), &().into())?;
+    match pcm.as_option() {
+        Some(pcm) => Ok(Some(pcm.i((0, 0))?.to_vec1::<f32>()?)),
+        None => Ok(None),
+    }
+}
+
+/// Convert a room broadcast message to its bare Ogg/Opus form, `None` for non-audio messages.
+fn ogg_opus_message(msg: ws::Message) -> Option<ws::Message> {
+    match msg {
+        ws::Message::Binary(b) => match b.first().map(|t| MsgType::from_u8(*t)) {
+            Some(Ok(MsgType::Audio)) => Some(ws::Message::Binary(b.slice(1..))),
+            // Keep proxies from timing out idle listeners.
+            Some(Ok(MsgType::Ping)) => Some(ws::Message::Ping(Default::default())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ogg_opus_message() {
+        let audio = ws::Message::Binary(vec![MsgType::Audio.to_u8(), 1, 2, 3].into());
+        match ogg_opus_message(audio) {
+            Some(ws::Message::Binary(b)) => assert_eq!(b.as_ref(), &[1, 2, 3]),
+            other => panic!("unexpected {other:?}"),
+        }
+        let ping = ws::Message::Binary(vec![MsgType::Ping.to_u8()].into());
+        assert!(matches!(ogg_opus_message(ping), Some(ws::Message::Ping(_))));
+        let text = ws::Message::Binary(vec![MsgType::Text.to_u8(), b'h', b'i'].into());
+        assert!(ogg_opus_message(text).is_none());
+    }
+
+    #[test]
+    fn test_mixer() {
+        let gains = std::collections::HashMap::from([("b".to_string(), 0.5)]);
+        let mut mixer = Mixer::new(gains, 2);
+        let t0 = mixer.last_frame;
+        assert_eq!(mixer.add(Some("a".into())).unwrap(), "a");
+        assert_eq!(mixer.add(Some("b".into())).unwrap(), "b");
+        assert!(mixer.add(None).is_err());
+        mixer.push("a", &[0.2; FRAME_SIZE]);
+        // Waits for b until a frame duration has elapsed.
+        assert!(mixer.pop_frame(t0).is_none());
+        mixer.push("b", &[0.2; FRAME_SIZE]);
+        let frame = mixer.pop_frame(t0).unwrap();
+        assert!((frame[FRAME_SIZE - 1] - 0.3).abs() < 1e-6);
+
+        mixer.push("a", &[0.2; FRAME_SIZE]);
+        let frame = mixer.pop_frame(t0 + FRAME_DURATION).unwrap();
+        assert!((frame[0] - 0.2).abs() < 1e-6);
+
+        let update = PublisherUpdate { gain: None, muted: Some(true) };
+        assert!(mixer.update("b", &update).unwrap().unwrap().muted);
+        mixer.push("a", &[0.2; FRAME_SIZE]);
+        assert!(mixer.pop_frame(t0 + FRAME_DURATION).is_some());
+        assert!(mixer.update("c", &update).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lagging_subscriber() {
+        let msg = |i: u8| ws::Message::Binary(vec![i].into());
+        for (policy, expected) in [(LagPolicy::SkipToLive, 10), (LagPolicy::DropOldest, 6)] {
+            let (tx, rx) = tokio::sync::broadcast::channel(4);
+            let room_id = "test".to_string();
+            let mut subscriber = Subscriber { rx, policy, room_id, dropped: 0 };
+            for i in 0..10 {
+                tx.send(msg(i)).unwrap();
+            }
+            // Only 6..10 are still buffered, and are dropped too when skipping to live.
+            let (received, _) = tokio::join!(subscriber.recv(), async {
+                tokio::task::yield_now().await;
+                tx.send(msg(10))
+            });
+            let next = if policy == LagPolicy::SkipToLive { 10 } else { 6 };
+            assert_eq!(received, Some(msg(next)));
+            assert_eq!(subscriber.dropped, expected);
+        }
+    }
+
+    #[test]
+    fn test_limiter() {
+        let mut limiter = Limiter { gain: 1.0 };
+        let mut loud = vec![1.9f32; FRAME_SIZE];
+        limiter.process(&mut loud);
+        assert!(loud.iter().all(|v| v.abs() <= 1.0));
+        assert!((loud[FRAME_SIZE - 1] - LIMITER_THRESHOLD).abs() < 1e-4);
+        let mut quiet = vec![0.1f32; FRAME_SIZE];
+        limiter.process(&mut quiet);
+        assert!(quiet[FRAME_SIZE - 1] < 0.1 && limiter.gain < 1.0);
+    }
+
+    #[test]
+    fn test_recv_format_query() {
+        let f: RecvFormat = serde_json::from_str("\"ogg_opus\"").unwrap();
+        assert_eq!(f, RecvFormat::OggOpus);
+        assert_eq!(RecvFormat::default(), RecvFormat::Moshi);
+    }
+
+    fn claims(role: &str, rooms: Option<&[&str]>) -> BetterAuthClaims {
+        serde_json::from_value(serde_json::json!({
+            "session": {
+                "id": "s", "userId": "u", "createdAt": "", "updatedAt": "", "expiresAt": "",
+            },
+            "user": { "id": "u", "role": role, "rooms": rooms },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_room_access() {
+        let mut access = RoomAccess {
+            rooms: ["lobby", "stage"].map(String::from).into(),
+            default_room: Some("lobby".to_string()),
+            room_roles: Default::default(),
+        };
+        let user = claims("user", None);
+        assert_eq!(access.authorize(None, None).unwrap(), "lobby");
+        assert_eq!(access.authorize(Some("stage"), Some(&user)).unwrap(), "stage");
+        let err = access.authorize(Some("attic"), None).unwrap_err();
+        assert_eq!(err, RoomError::NotFound("attic".to_string()));
+        assert_eq!(err.close_code(), CloseCode::ResourceUnavailable);
+
+        // Room claims restrict the user even without role mapping.
+        let guest = claims("user", Some(&["lobby"]));
+        assert!(access.authorize(Some("lobby"), Some(&guest)).is_ok());
+        let err = access.authorize(Some("stage"), Some(&guest)).unwrap_err();
+        assert_eq!(err, RoomError::Forbidden("stage".to_string()));
+        assert_eq!(err.close_code(), CloseCode::Forbidden);
+        // Unknown rooms are reported as such whatever the grants.
+        let err = access.authorize(Some("attic"), Some(&guest)).unwrap_err();
+        assert_eq!(err, RoomError::NotFound("attic".to_string()));
+
+        access.room_roles.insert("speaker".to_string(), vec!["stage".to_string()]);
+        access.room_roles.insert("admin".to_string(), vec!["*".to_string()]);
+        // Once roles are configured, tokens without any grant are refused.
+        assert!(access.authorize(Some("lobby"), Some(&user)).is_err());
+        assert!(access.authorize(Some("stage"), Some(&claims("speaker", None))).is_ok());
+        assert!(access.authorize(Some("lobby"), Some(&claims("speaker", None))).is_err());
+        // Claim and role grants add up.
+        let speaker = claims("speaker", Some(&["lobby"]));
+        assert!(access.authorize(None, Some(&speaker)).is_ok());
+        assert!(access.authorize(Some("stage"), Some(&speaker)).is_ok());
+        assert!(access.authorize(Some("lobby"), Some(&claims("admin", None))).is_ok());
+        // API keys and public access carry no claims.
+        assert!(access.authorize(Some("stage"), None).is_ok());
+
+        access.default_room = None;
+        assert_eq!(access.authorize(None, None).unwrap_err(), RoomError::Missing);
+    }
+}
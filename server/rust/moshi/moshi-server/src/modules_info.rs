@@ -0,0 +1,170 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Description of the modules served on `/api/modules_info`.
+//!
+//! Everyone gets a summary of each module: its type, endpoints, auth policy and slots. The
+//! model files, voice directory and rooms of the config are only given to admins, the file
+//! paths being resolved to the local cache once the config is loaded.
+
+use crate::auth::{self, AuthPolicy};
+use crate::ModuleConfig;
+use std::collections::BTreeMap;
+
+/// How much of the modules and server state a caller gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Detail {
+    Summary,
+    Full,
+}
+
+impl Detail {
+    /// `Full` for the callers with an admin JWT, `Summary` for the others.
+    pub fn of_request(headers: &axum::http::HeaderMap, token: Option<&str>) -> Self {
+        match auth::check_policy(AuthPolicy::Admin, headers, token) {
+            Ok(_) => Self::Full,
+            Err(_) => Self::Summary,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Slots {
+    pub total: usize,
+    pub used: usize,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ModuleInfo {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// Endpoints of the module, the send then recv paths of a mimi module.
+    pub paths: Vec<String>,
+    pub auth: AuthPolicy,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slots: Option<Slots>,
+    /// Settings of the config, admins only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config: Option<ModuleSettings>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(untagged)]
+pub enum ModuleSettings {
+    Tts {
+        lm_model_file: String,
+        text_tokenizer_file: String,
+        audio_tokenizer_file: String,
+        voice_dir: String,
+        voices: BTreeMap<String, String>,
+        dtype_override: Option<String>,
+    },
+    Asr {
+        lm_model_file: String,
+        text_tokenizer_file: String,
+        audio_tokenizer_file: String,
+        asr_delay_in_tokens: usize,
+        dtype_override: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        batch_size: Option<usize>,
+    },
+    Mimi {
+        audio_tokenizer_file: String,
+        rooms: Vec<String>,
+        default_room: Option<String>,
+        max_publishers: usize,
+    },
+    Lm {
+        lm_model_file: String,
+        text_tokenizer_file: String,
+        audio_tokenizer_file: String,
+        dtype_override: Option<String>,
+    },
+}
+
+impl ModuleInfo {
+    pub fn new(name: &str, config: &ModuleConfig) -> Self {
+        let asr = |c: &crate::AsrConfig, batch_size| ModuleSettings::Asr {
+            lm_model_file: c.lm_model_file.clone(),
+            text_tokenizer_file: c.text_tokenizer_file.clone(),
+            audio_tokenizer_file: c.audio_tokenizer_file.clone(),
+            asr_delay_in_tokens: c.asr_delay_in_tokens,
+            dtype_override: c.dtype_override.clone(),
+            batch_size,
+        };
+        let (kind, paths, settings) = match config {
+            ModuleConfig::Tts { path, config: c, .. } => {
+                let settings = ModuleSettings::Tts {
+                    lm_model_file: c.lm_model_file.clone(),
+                    text_tokenizer_file: c.text_tokenizer_file.clone(),
+                    audio_tokenizer_file: c.audio_tokenizer_file.clone(),
+                    voice_dir: c.voice_dir.clone(),
+                    voices: c.voices.clone().into_iter().collect(),
+                    dtype_override: c.dtype_override.clone(),
+                };
+                ("tts", vec![path.clone()], settings)
+            }
+            ModuleConfig::Asr { path, config: c, .. } => ("asr", vec![path.clone()], asr(c, None)),
+            ModuleConfig::BatchedAsr { path, config: c, batch_size, .. } => {
+                ("batched_asr", vec![path.clone()], asr(c, Some(*batch_size)))
+            }
+            ModuleConfig::Vad { path, config: c, .. } => ("vad", vec![path.clone()], asr(c, None)),
+            ModuleConfig::Mimi { send_path, recv_path, config: c, .. } => {
+                let settings = ModuleSettings::Mimi {
+                    audio_tokenizer_file: c.audio_tokenizer_file.clone(),
+                    rooms: c.rooms.clone(),
+                    default_room: c.default_room.clone(),
+                    max_publishers: c.max_publishers,
+                };
+                ("mimi", vec![send_path.clone(), recv_path.clone()], settings)
+            }
+            ModuleConfig::Lm { path, config: c, .. } => {
+                let settings = ModuleSettings::Lm {
+                    lm_model_file: c.lm_model_file.clone(),
+                    text_tokenizer_file: c.text_tokenizer_file.clone(),
+                    audio_tokenizer_file: c.audio_tokenizer_file.clone(),
+                    dtype_override: c.dtype_override.clone(),
+                };
+                ("lm", vec![path.clone()], settings)
+            }
+        };
+        Self {
+            name: name.to_string(),
+            kind,
+            paths,
+            auth: config.auth_policy(),
+            slots: None,
+            config: Some(settings),
+        }
+    }
+
+    /// The info given to a caller with `detail`, every response goes through this.
+    pub fn redact(&self, detail: Detail) -> Self {
+        let mut info = self.clone();
+        if detail == Detail::Summary {
+            info.config = None;
+        }
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_has_no_files() {
+        let cfg = include_str!("../../../../../configs/tts/config-tts.toml");
+        let cfg: crate::Config = toml::from_str(cfg).unwrap();
+        let info = ModuleInfo::new("tts", &cfg.modules["tts"]);
+        let full = serde_json::to_value(info.redact(Detail::Full)).unwrap();
+        assert_eq!(full["type"], "tts");
+        assert_eq!(full["paths"], serde_json::json!(["/api/tts"]));
+        assert!(full["config"]["voice_dir"].as_str().unwrap().contains("tts-voices"));
+
+        let summary = serde_json::to_string(&info.redact(Detail::Summary)).unwrap();
+        assert_eq!(summary, r#"{"name":"tts","type":"tts","paths":["/api/tts"],"auth":"jwt"}"#);
+    }
+}
@@ -0,0 +1,288 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Ogg/Opus encapsulation of the TTS output, following RFC 7845.
+//!
+//! Granule positions count 48kHz samples from the start of the logical stream, the encoder
+//! lookahead included, and the `OpusHead` pre-skip is the actual encoder lookahead so that
+//! players drop exactly the priming samples. Each utterance ends its logical stream with an
+//! end-of-stream page whose granule position trims the final padding, the next utterance is
+//! chained as a new logical stream with its own serial number and headers.
+
+use anyhow::Result;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Granule positions always use a 48kHz clock, whatever the encoder sample rate.
+const GRANULE_RATE: u64 = 48_000;
+
+static NEXT_SERIAL: AtomicU32 = AtomicU32::new(0x4b79_7400);
+
+fn opus_head(pre_skip: u16, input_sample_rate: u32, channels: u8) -> Vec<u8> {
+    // https://www.rfc-editor.org/rfc/rfc7845#section-5.1
+    let mut head = Vec::with_capacity(19);
+    head.extend_from_slice(b"OpusHead");
+    head.push(1); // version
+    head.push(channels);
+    head.extend_from_slice(&pre_skip.to_le_bytes());
+    head.extend_from_slice(&input_sample_rate.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+    head.push(0); // channel mapping family, mono or left and right
+    head
+}
+
+fn opus_tags() -> Vec<u8> {
+    // https://www.rfc-editor.org/rfc/rfc7845#section-5.2
+    let vendor = "KyutaiMoshi";
+    let mut tags = Vec::with_capacity(16 + vendor.len());
+    tags.extend_from_slice(b"OpusTags");
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor.as_bytes());
+    tags.extend_from_slice(&0u32.to_le_bytes()); // no user comments
+    tags
+}
+
+pub struct Encoder {
+    encoder: opus::Encoder,
+    pw: ogg::PacketWriter<'static, Vec<u8>>,
+    sample_rate: usize,
+    channels: usize,
+    /// 20ms at the encoder sample rate, per channel.
+    frame_size: usize,
+    /// Encoder lookahead at the encoder sample rate.
+    lookahead: usize,
+    serial: u32,
+    /// Whether the headers of the current logical stream have been written.
+    in_stream: bool,
+    header_data: Vec<u8>,
+    /// Interleaved samples waiting for a full frame.
+    pcm: Vec<f32>,
+    /// Samples per channel received in the current logical stream.
+    samples_in: u64,
+    /// Samples encoded in the current logical stream, padding included.
+    samples_encoded: u64,
+    opus_buf: Vec<u8>,
+}
+
+impl Encoder {
+    /// Encoder of `channels` interleaved channels, 1 or 2.
+    pub fn new(sample_rate: usize, channels: usize) -> Result<Self> {
+        let opus_channels = match channels {
+            1 => opus::Channels::Mono,
+            2 => opus::Channels::Stereo,
+            _ => anyhow::bail!("unsupported number of channels {channels}"),
+        };
+        let mut encoder =
+            opus::Encoder::new(sample_rate as u32, opus_channels, opus::Application::Voip)?;
+        let lookahead = encoder.get_lookahead()? as usize;
+        let mut s = Self {
+            encoder,
+            pw: ogg::PacketWriter::new(Vec::new()),
+            sample_rate,
+            channels,
+            frame_size: sample_rate / 50,
+            lookahead,
+            serial: 0,
+            in_stream: false,
+            header_data: vec![],
+            pcm: vec![],
+            samples_in: 0,
+            samples_encoded: 0,
+            opus_buf: vec![0u8; 50_000],
+        };
+        s.header_data = s.start_stream()?;
+        Ok(s)
+    }
+
+    /// Headers of the first logical stream, chained streams carry their own headers.
+    pub fn header_data(&self) -> &[u8] {
+        &self.header_data
+    }
+
+    /// Pre-skip written in the `OpusHead` header, in 48kHz samples.
+    pub fn pre_skip(&self) -> u16 {
+        self.to_granule(self.lookahead as u64) as u16
+    }
+
+    fn to_granule(&self, samples: u64) -> u64 {
+        samples * GRANULE_RATE / self.sample_rate as u64
+    }
+
+    fn take_written(&mut self) -> Vec<u8> {
+        std::mem::take(self.pw.inner_mut())
+    }
+
+    fn start_stream(&mut self) -> Result<Vec<u8>> {
+        self.serial = NEXT_SERIAL.fetch_add(1, Ordering::Relaxed);
+        self.encoder.reset_state()?;
+        self.samples_in = 0;
+        self.samples_encoded = 0;
+        self.in_stream = true;
+        let head = opus_head(self.pre_skip(), self.sample_rate as u32, self.channels as u8);
+        self.pw.write_packet(head, self.serial, ogg::PacketWriteEndInfo::EndPage, 0)?;
+        self.pw.write_packet(opus_tags(), self.serial, ogg::PacketWriteEndInfo::EndPage, 0)?;
+        Ok(self.take_written())
+    }
+
+    fn write_frame(&mut self, end: Option<u64>) -> Result<()> {
+        let frame: Vec<f32> = self.pcm.drain(..self.frame_size * self.channels).collect();
+        let size = self.encoder.encode_float(&frame, &mut self.opus_buf)?;
+        self.samples_encoded += self.frame_size as u64;
+        let (end_info, absgp) = match end {
+            None => (ogg::PacketWriteEndInfo::EndPage, self.to_granule(self.samples_encoded)),
+            Some(absgp) => (ogg::PacketWriteEndInfo::EndStream, absgp),
+        };
+        self.pw.write_packet(self.opus_buf[..size].to_vec(), self.serial, end_info, absgp)?;
+        Ok(())
+    }
+
+    /// Encode `pcm`, interleaved when stereo, and return the completed pages, one 20ms packet
+    /// per page. After [`Self::finish`], this starts a new chained logical stream.
+    pub fn encode_page(&mut self, pcm: &[f32]) -> Result<Vec<u8>> {
+        let mut out = if self.in_stream { vec![] } else { self.start_stream()? };
+        self.pcm.extend_from_slice(pcm);
+        self.samples_in += (pcm.len() / self.channels) as u64;
+        while self.pcm.len() >= self.frame_size * self.channels {
+            self.write_frame(None)?;
+        }
+        out.extend(self.take_written());
+        Ok(out)
+    }
+
+    /// End the current logical stream. The buffered audio is padded with silence until the
+    /// decoder has output every input sample, and the last page is flagged end-of-stream with
+    /// a granule position that trims the padding.
+    pub fn finish(&mut self) -> Result<Vec<u8>> {
+        if !self.in_stream {
+            return Ok(vec![]);
+        }
+        let needed = self.samples_in + self.lookahead as u64;
+        let end = self.to_granule(needed);
+        loop {
+            // Less than a frame is buffered here, complete it with silence.
+            self.pcm.resize(self.frame_size * self.channels, 0.0);
+            if self.samples_encoded + self.frame_size as u64 >= needed {
+                // The end trimming cannot go before the previous page.
+                let end = end.max(self.to_granule(self.samples_encoded));
+                self.write_frame(Some(end))?;
+                break;
+            }
+            self.write_frame(None)?;
+        }
+        self.in_stream = false;
+        Ok(self.take_written())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Page {
+        serial: u32,
+        absgp: u64,
+        first: bool,
+        last: bool,
+        data: Vec<u8>,
+    }
+
+    fn parse(data: Vec<u8>) -> Vec<Page> {
+        let mut reader = ogg::PacketReader::new(std::io::Cursor::new(data));
+        let mut pages = vec![];
+        while let Some(p) = reader.read_packet().unwrap() {
+            pages.push(Page {
+                serial: p.stream_serial(),
+                absgp: p.absgp_page(),
+                first: p.first_in_stream(),
+                last: p.last_in_stream(),
+                data: p.data,
+            });
+        }
+        pages
+    }
+
+    fn check_stream(pages: &[Page], samples_in: u64) -> u16 {
+        assert!(pages[0].first && pages[0].data.starts_with(b"OpusHead"));
+        assert!(pages[1].data.starts_with(b"OpusTags"));
+        assert_eq!((pages[0].absgp, pages[1].absgp), (0, 0));
+        let pre_skip = u16::from_le_bytes([pages[0].data[10], pages[0].data[11]]);
+        let audio = &pages[2..];
+        assert!(audio.iter().all(|p| p.serial == pages[0].serial));
+        assert!(audio.windows(2).all(|w| w[0].absgp < w[1].absgp));
+        let (last, rest) = audio.split_last().unwrap();
+        assert!(last.last && rest.iter().all(|p| !p.last && !p.first));
+        // 24kHz input, the granule position counts at 48kHz after the pre-skip.
+        assert_eq!(last.absgp, pre_skip as u64 + 2 * samples_in);
+        pre_skip
+    }
+
+    #[test]
+    fn granule_positions_and_end_of_stream() {
+        let mut enc = Encoder::new(24000, 1).unwrap();
+        let mut data = enc.header_data().to_vec();
+        let pcm: Vec<f32> = (0..10_000).map(|i| (i as f32 * 0.05).sin() * 0.2).collect();
+        for chunk in pcm.chunks(1920) {
+            data.extend(enc.encode_page(chunk).unwrap());
+        }
+        data.extend(enc.finish().unwrap());
+        assert!(enc.finish().unwrap().is_empty());
+
+        let pages = parse(data);
+        let pre_skip = check_stream(&pages, 10_000);
+        assert_eq!(pre_skip, enc.pre_skip());
+        assert!(pre_skip > 0);
+
+        // The decoded audio covers every input sample once the pre-skip is dropped.
+        let mut decoder = opus::Decoder::new(48000, opus::Channels::Mono).unwrap();
+        let mut out = vec![0f32; 5760];
+        let decoded: usize = pages[2..]
+            .iter()
+            .map(|p| decoder.decode_float(&p.data, &mut out, false).unwrap())
+            .sum();
+        assert!(decoded as u64 >= pages.last().unwrap().absgp);
+    }
+
+    #[test]
+    fn chained_streams() {
+        let mut enc = Encoder::new(24000, 1).unwrap();
+        let mut data = enc.header_data().to_vec();
+        data.extend(enc.encode_page(&[0.1; 4800]).unwrap());
+        data.extend(enc.finish().unwrap());
+        data.extend(enc.encode_page(&[0.1; 1000]).unwrap());
+        data.extend(enc.finish().unwrap());
+
+        let pages = parse(data);
+        let split = pages.iter().position(|p| p.last).unwrap() + 1;
+        let (first, second) = pages.split_at(split);
+        check_stream(first, 4800);
+        check_stream(second, 1000);
+        assert_ne!(first[0].serial, second[0].serial);
+    }
+
+    #[test]
+    fn stereo_stream() {
+        assert!(Encoder::new(24000, 3).is_err());
+        let mut enc = Encoder::new(24000, 2).unwrap();
+        let mut data = enc.header_data().to_vec();
+        // A tone on the left channel only.
+        let pcm: Vec<f32> = (0..4800).flat_map(|i| [(i as f32 * 0.05).sin() * 0.5, 0.0]).collect();
+        for chunk in pcm.chunks(3840) {
+            data.extend(enc.encode_page(chunk).unwrap());
+        }
+        data.extend(enc.finish().unwrap());
+
+        let pages = parse(data);
+        assert_eq!(pages[0].data[9], 2);
+        check_stream(&pages, 4800);
+        let mut decoder = opus::Decoder::new(48000, opus::Channels::Stereo).unwrap();
+        let mut out = vec![0f32; 2 * 5760];
+        let (mut left, mut right) = (0f32, 0f32);
+        for page in pages[2..].iter() {
+            let n = decoder.decode_float(&page.data, &mut out, false).unwrap();
+            for frame in out[..2 * n].chunks(2) {
+                (left, right) = (left + frame[0].abs(), right + frame[1].abs());
+            }
+        }
+        assert!(left > 10.0 * right, "left {left} right {right}");
+    }
+}
@@ -0,0 +1,374 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! OpenTelemetry export of tracing spans and session metrics over OTLP/HTTP.
+//!
+//! The exporter is only compiled with the `otel` feature, without it the `[otel]` section is
+//! still parsed so that the same config can be used with both builds. Every websocket
+//! connection gets a `ws_session` span covering the upgrade, the auth check, the model steps
+//! and the close. When the reverse proxy forwards a W3C `traceparent` header, the session
+//! span is attached to the proxy trace.
+//!
+//! Whatever the feature, the `X-Request-Id` and the trace id of the `traceparent` of the
+//! upgrade request are recorded on the session span, so that they show in every log line of
+//! the session, and echoed in the `Ready` message, see [`TraceIds`].
+
+use anyhow::Result;
+
+/// Layer added to the tracing registry when the export is enabled.
+pub type BoxedLayer =
+    Box<dyn tracing_subscriber::Layer<tracing_subscriber::Registry> + Send + Sync + 'static>;
+
+fn default_endpoint() -> String {
+    "http://localhost:4318".to_string()
+}
+fn default_sample_ratio() -> f64 {
+    1.0
+}
+fn default_metrics_interval_s() -> f64 {
+    30.0
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct OtelConfig {
+    /// Export traces and metrics (disabled by default).
+    #[serde(default)]
+    pub enabled: bool,
+    /// Base URL of the OTLP/HTTP collector, `/v1/traces` and `/v1/metrics` are appended.
+    #[serde(default = "default_endpoint")]
+    pub endpoint: String,
+    /// Extra headers sent with every export request, e.g. an authorization header.
+    #[serde(default)]
+    pub headers: std::collections::HashMap<String, String>,
+    /// Service name reported in the resource, defaults to the config `instance_name`.
+    #[serde(default)]
+    pub service_name: Option<String>,
+    /// Fraction of root traces that are sampled, traces started by the proxy follow its choice.
+    #[serde(default = "default_sample_ratio")]
+    pub sample_ratio: f64,
+    #[serde(default = "default_metrics_interval_s")]
+    pub metrics_interval_s: f64,
+}
+
+impl Default for OtelConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: default_endpoint(),
+            headers: Default::default(),
+            service_name: None,
+            sample_ratio: default_sample_ratio(),
+            metrics_interval_s: default_metrics_interval_s(),
+        }
+    }
+}
+
+impl OtelConfig {
+    #[cfg_attr(not(feature = "otel"), allow(dead_code))]
+    fn signal_endpoint(&self, signal: &str) -> String {
+        format!("{}/v1/{signal}", self.endpoint.trim_end_matches('/'))
+    }
+}
+
+/// Keeps the exporters alive, pending spans and metrics are flushed on drop.
+pub struct OtelGuard {
+    #[cfg(feature = "otel")]
+    tracer_provider: opentelemetry_sdk::trace::SdkTracerProvider,
+    #[cfg(feature = "otel")]
+    meter_provider: opentelemetry_sdk::metrics::SdkMeterProvider,
+}
+
+impl Drop for OtelGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        {
+            if let Err(err) = self.tracer_provider.shutdown() {
+                eprintln!("failed to flush OpenTelemetry traces: {err}");
+            }
+            if let Err(err) = self.meter_provider.shutdown() {
+                eprintln!("failed to flush OpenTelemetry metrics: {err}");
+            }
+        }
+    }
+}
+
+/// Set up the OTLP exporters, this has to run before the tracing subscriber is installed.
+#[cfg(feature = "otel")]
+pub fn init(config: &OtelConfig, instance_name: &str) -> Result<Option<(BoxedLayer, OtelGuard)>> {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::{WithExportConfig, WithHttpConfig};
+    use opentelemetry_sdk::{metrics, trace, Resource};
+
+    if !config.enabled {
+        return Ok(None);
+    }
+    let service_name = config.service_name.clone().unwrap_or_else(|| instance_name.to_string());
+    let resource = Resource::builder().with_service_name(service_name).build();
+
+    let span_exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(config.signal_endpoint("traces"))
+        .with_headers(config.headers.clone())
+        .build()?;
+    let sampler = trace::Sampler::ParentBased(Box::new(trace::Sampler::TraceIdRatioBased(
+        config.sample_ratio,
+    )));
+    let tracer_provider = trace::SdkTracerProvider::builder()
+        .with_batch_exporter(span_exporter)
+        .with_sampler(sampler)
+        .with_resource(resource.clone())
+        .build();
+
+    let metric_exporter = opentelemetry_otlp::MetricExporter::builder()
+        .with_http()
+        .with_endpoint(config.signal_endpoint("metrics"))
+        .with_headers(config.headers.clone())
+        .build()?;
+    let reader = metrics::PeriodicReader::builder(metric_exporter)
+        .with_interval(std::time::Duration::from_secs_f64(config.metrics_interval_s.max(1.0)))
+        .build();
+    let meter_provider =
+        metrics::SdkMeterProvider::builder().with_reader(reader).with_resource(resource).build();
+
+    opentelemetry::global::set_text_map_propagator(
+        opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+    );
+    opentelemetry::global::set_meter_provider(meter_provider.clone());
+    let tracer = tracer_provider.tracer("moshi-server");
+    let layer: BoxedLayer = Box::new(tracing_opentelemetry::layer().with_tracer(tracer));
+    Ok(Some((layer, OtelGuard { tracer_provider, meter_provider })))
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn init(config: &OtelConfig, _instance_name: &str) -> Result<Option<(BoxedLayer, OtelGuard)>> {
+    if config.enabled {
+        // The tracing subscriber is not installed yet.
+        eprintln!(
+            "Warning: otel.enabled is set but moshi-server was built without the otel feature"
+        );
+    }
+    Ok(None)
+}
+
+#[cfg(feature = "otel")]
+struct HeaderExtractor<'a>(&'a axum::http::HeaderMap);
+
+#[cfg(feature = "otel")]
+impl opentelemetry::propagation::Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+#[cfg(feature = "otel")]
+fn record_session(module: &'static str, auth_ok: bool, duration_s: f64) {
+    use opentelemetry::KeyValue;
+    use std::sync::OnceLock;
+
+    static INSTRUMENTS: OnceLock<(
+        opentelemetry::metrics::Counter<u64>,
+        opentelemetry::metrics::Histogram<f64>,
+    )> = OnceLock::new();
+    let (sessions, duration) = INSTRUMENTS.get_or_init(|| {
+        let meter = opentelemetry::global::meter("moshi-server");
+        let sessions = meter
+            .u64_counter("ws_sessions")
+            .with_description("Number of websocket sessions.")
+            .build();
+        let duration = meter
+            .f64_histogram("ws_session_duration")
+            .with_unit("s")
+            .with_description("Duration of the websocket sessions.")
+            .build();
+        (sessions, duration)
+    });
+    let attrs = [KeyValue::new("module", module), KeyValue::new("auth_ok", auth_ok)];
+    sessions.add(1, &attrs);
+    duration.record(duration_s, &attrs);
+}
+
+/// Longest request id that is kept, longer ones are dropped rather than truncated.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Ids of the upstream request a session belongs to, echoed to the client so that its
+/// transcripts and errors can be matched with the gateway traces.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceIds {
+    /// `X-Request-Id` set by the gateway.
+    pub request_id: Option<String>,
+    /// Trace id of the W3C `traceparent`, 32 lowercase hex digits.
+    pub trace_id: Option<String>,
+}
+
+impl TraceIds {
+    pub fn from_headers(headers: &axum::http::HeaderMap) -> Self {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim);
+        let request_id = header("x-request-id")
+            .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+            .filter(|id| id.bytes().all(|b| b.is_ascii_graphic()))
+            .map(str::to_string);
+        let trace_id = header("traceparent").and_then(traceparent_trace_id);
+        Self { request_id, trace_id }
+    }
+}
+
+/// Trace id of a `version-traceid-parentid-flags` header, `None` when it is malformed or
+/// all zeros as the W3C Trace Context requires.
+fn traceparent_trace_id(traceparent: &str) -> Option<String> {
+    let mut parts = traceparent.split('-');
+    let (version, trace_id, parent_id, flags) =
+        (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    let hex = |s: &str, len: usize| {
+        s.len() == len && s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    };
+    // Later versions may append fields, version 00 has exactly four.
+    let valid = hex(version, 2)
+        && version != "ff"
+        && (version != "00" || parts.next().is_none())
+        && hex(trace_id, 32)
+        && hex(parent_id, 16)
+        && hex(flags, 2)
+        && trace_id.bytes().any(|b| b != b'0')
+        && parent_id.bytes().any(|b| b != b'0');
+    valid.then(|| trace_id.to_string())
+}
+
+/// Lifetime of a websocket session, the span is closed when this is dropped.
+pub struct Session {
+    span: tracing::Span,
+    module: &'static str,
+    auth_ok: bool,
+    start: std::time::Instant,
+    trace: TraceIds,
+    /// Id of the authorized sessions in the server events.
+    event_id: Option<u64>,
+}
+
+impl Session {
+    /// Open the `ws_session` span for an upgrade request, `auth` is the result of the auth
+    /// check for the connection.
+    pub fn new<T, E: std::fmt::Debug>(
+        module: &'static str,
+        headers: &axum::http::HeaderMap,
+        auth: &std::result::Result<T, E>,
+    ) -> Self {
+        let client_ip = headers.get("X-Real-IP").and_then(|v| v.to_str().ok());
+        let auth_ok = auth.is_ok();
+        let trace = TraceIds::from_headers(headers);
+        let span = tracing::info_span!(
+            "ws_session",
+            module,
+            client_ip,
+            auth_ok,
+            request_id = trace.request_id.as_deref(),
+            trace_id = trace.trace_id.as_deref(),
+            steps = tracing::field::Empty,
+        );
+        #[cfg(feature = "otel")]
+        {
+            use tracing_opentelemetry::OpenTelemetrySpanExt;
+            let cx = opentelemetry::global::get_text_map_propagator(|p| {
+                p.extract(&HeaderExtractor(headers))
+            });
+            let _ = span.set_parent(cx);
+        }
+        span.in_scope(|| match auth {
+            Ok(_) => tracing::debug!("session authorized"),
+            Err(err) => tracing::debug!(?err, "session rejected"),
+        });
+        let start = std::time::Instant::now();
+        Self { span, module, auth_ok, start, trace, event_id: None }
+    }
+
+    /// Upstream ids of the session, to be echoed in the `Ready` message.
+    pub fn trace_ids(&self) -> &TraceIds {
+        &self.trace
+    }
+
+    /// Run the session future within the span, the session ends when the future completes.
+    pub fn run<F: std::future::Future>(
+        mut self,
+        f: F,
+    ) -> impl std::future::Future<Output = F::Output> {
+        use tracing::Instrument;
+        if self.auth_ok {
+            let request_id = self.trace.request_id.clone();
+            self.event_id = Some(crate::events::session_started(self.module, request_id));
+        }
+        let span = self.span.clone();
+        async move {
+            let _session = self;
+            f.await
+        }
+        .instrument(span)
+    }
+}
+
+/// Record the number of model steps processed for the session running on this thread.
+pub fn record_steps(steps: usize) {
+    tracing::Span::current().record("steps", steps);
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let duration_s = self.start.elapsed().as_secs_f64();
+        self.span.in_scope(|| {
+            tracing::debug!(
+                module = self.module,
+                auth_ok = self.auth_ok,
+                duration_s,
+                "session closed"
+            )
+        });
+        if let Some(session) = self.event_id {
+            let module = self.module;
+            crate::events::emit(crate::events::Event::SessionEnded { session, module, duration_s });
+        }
+        #[cfg(feature = "otel")]
+        record_session(self.module, self.auth_ok, duration_s);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_defaults_and_endpoints() {
+        let cfg: OtelConfig =
+            toml::from_str("enabled = true\nendpoint = \"http://tempo:4318/\"").unwrap();
+        assert!(cfg.enabled);
+        assert_eq!(cfg.sample_ratio, 1.0);
+        assert_eq!(cfg.signal_endpoint("traces"), "http://tempo:4318/v1/traces");
+        assert!(!OtelConfig::default().enabled);
+    }
+
+    #[test]
+    fn trace_ids_from_headers() {
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert("x-request-id", " req-42 ".parse().unwrap());
+        headers.insert(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".parse().unwrap(),
+        );
+        let ids = TraceIds::from_headers(&headers);
+        assert_eq!(ids.request_id.as_deref(), Some("req-42"));
+        assert_eq!(ids.trace_id.as_deref(), Some("4bf92f3577b34da6a3ce929d0e0e4736"));
+
+        let parsed = |v: &str| traceparent_trace_id(v);
+        assert!(parsed("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+        assert!(parsed("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01").is_none());
+        assert!(parsed("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-x").is_none());
+        assert!(parsed("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-x").is_some());
+        assert!(parsed("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none());
+
+        headers.insert("x-request-id", "a b".parse().unwrap());
+        assert_eq!(TraceIds::from_headers(&headers).request_id, None);
+        assert_eq!(TraceIds::from_headers(&Default::default()), TraceIds::default());
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! What the logs may contain of the text of the users, ASR words and TTS inputs.
+//!
+//! Such text is held in a [`Sensitive`] wrapper wherever it can reach a log line, e.g. the
+//! fields of a query printed with `?query`. Its `Display` and `Debug` follow `log_text` of the
+//! `[privacy]` section: `redact` (the default) only shows the length, `hash` a short digest so
+//! that repeated inputs can be told apart, `plain` the text itself. The raw value is only
+//! reached through `Deref` or [`Sensitive::into_inner`], never by formatting.
+//!
+//! The token dumps written to `log_dir` (`log_tokens` of the TTS modules, `log_frequency_s` of
+//! the batched ASR modules and the per-session dumps of the ASR modules) hold the text too,
+//! they are only written with `log_text = "plain"`.
+
+use sha3::Digest;
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextLogging {
+    #[default]
+    Redact,
+    Hash,
+    Plain,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrivacyConfig {
+    /// How user text shows in the logs.
+    #[serde(default)]
+    pub log_text: TextLogging,
+}
+
+static LOG_TEXT: OnceLock<TextLogging> = OnceLock::new();
+
+/// Sets the policy of the process, before the modules are created.
+pub fn init(config: &PrivacyConfig) {
+    let _ = LOG_TEXT.set(config.log_text);
+}
+
+pub fn log_text() -> TextLogging {
+    LOG_TEXT.get().copied().unwrap_or_default()
+}
+
+/// Whether the token dumps enabled by `setting` can be written, warns when they cannot.
+pub fn allow_token_dumps(setting: &str) -> bool {
+    let allowed = log_text() == TextLogging::Plain;
+    if !allowed {
+        tracing::warn!(setting, "token dumps hold user text, ignored unless log_text = \"plain\"");
+    }
+    allowed
+}
+
+fn render(text: &str, mode: TextLogging, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let chars = text.chars().count();
+    match mode {
+        TextLogging::Redact => write!(f, "<redacted {chars} chars>"),
+        TextLogging::Hash => {
+            let digest = sha3::Sha3_256::digest(text.as_bytes());
+            let hex: String = digest[..6].iter().map(|b| format!("{b:02x}")).collect();
+            write!(f, "<sha3:{hex} {chars} chars>")
+        }
+        TextLogging::Plain => f.write_str(text),
+    }
+}
+
+/// User text that is redacted or hashed when formatted, as set by the `[privacy]` section.
+#[derive(Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Sensitive<T>(T);
+
+impl<T> Sensitive<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> std::ops::Deref for Sensitive<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> std::ops::DerefMut for Sensitive<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for Sensitive<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        render(&format!("{:?}", self.0), log_text(), f)
+    }
+}
+
+impl<T: std::fmt::Display> std::fmt::Display for Sensitive<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        render(&self.0.to_string(), log_text(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Shown<'a>(&'a str, TextLogging);
+
+    impl std::fmt::Display for Shown<'_> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            render(self.0, self.1, f)
+        }
+    }
+
+    #[test]
+    fn modes() {
+        let text = "call me at 555-0100";
+        assert_eq!(Shown(text, TextLogging::Redact).to_string(), "<redacted 19 chars>");
+        let hashed = Shown(text, TextLogging::Hash).to_string();
+        assert!(hashed.starts_with("<sha3:") && !hashed.contains("555"), "{hashed}");
+        assert_eq!(hashed, Shown(text, TextLogging::Hash).to_string());
+        assert_ne!(hashed, Shown("call me later", TextLogging::Hash).to_string());
+        assert_eq!(Shown(text, TextLogging::Plain).to_string(), text);
+    }
+
+    #[test]
+    fn never_formats_the_raw_text() {
+        #[derive(Debug, serde::Deserialize)]
+        #[allow(dead_code)]
+        struct Query {
+            text: Sensitive<Vec<String>>,
+            seed: u64,
+        }
+        let query: Query =
+            serde_json::from_str(r#"{"text": ["my password is hunter2"], "seed": 4}"#).unwrap();
+        // The policy is not initialized in the tests, so it is the default one.
+        let shown = format!("{query:?}");
+        assert!(!shown.contains("hunter2"), "{shown}");
+        assert!(shown.contains("seed: 4"), "{shown}");
+        assert_eq!(query.text[0], "my password is hunter2");
+    }
+}
@@ -0,0 +1,217 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Step-level profiling of the model loops, enabled with `worker --profile <dir>`.
+//!
+//! The model loops and the moshi crate mark their stages with `TRACE` spans (`step`,
+//! `mimi-encode-step`, `lm-forward`, `depformer`, ...). In profile mode these spans are
+//! recorded and a Chrome trace, viewable in Perfetto or `chrome://tracing`, is written every
+//! `--profile-steps` model steps. When built with the `nvtx` feature, the spans are also
+//! emitted as NVTX ranges so that Nsight Systems shows them next to the CUDA kernels.
+//!
+//! Durations are measured on the host. CUDA kernels run asynchronously, so set
+//! `CUDA_LAUNCH_BLOCKING=1` to attribute the GPU time to the stage that launched it.
+
+use anyhow::Result;
+use std::cell::Cell;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::span;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Name of the span wrapping one step of a model loop, a trace is written every
+/// `--profile-steps` such spans.
+pub const STEP: &str = "step";
+
+static NEXT_TID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static TID: u64 = NEXT_TID.fetch_add(1, Ordering::Relaxed);
+    static THREAD_NAME: Cell<&'static str> = const { Cell::new("") };
+}
+
+/// Label the current thread in the traces, blocking pool threads get reused so this is
+/// called each time a model loop starts on one.
+pub fn name_thread(name: &'static str) {
+    THREAD_NAME.with(|n| n.set(name));
+    #[cfg(feature = "nvtx")]
+    nvtx::name_thread!("{name}");
+}
+
+struct Entered(Instant);
+
+struct Complete {
+    name: &'static str,
+    tid: u64,
+    thread: &'static str,
+    start: Instant,
+    end: Instant,
+}
+
+#[derive(Default)]
+struct Buffer {
+    events: Vec<Complete>,
+    steps: usize,
+    traces: usize,
+}
+
+struct Inner {
+    dir: PathBuf,
+    steps_per_trace: usize,
+    origin: Instant,
+    buffer: Mutex<Buffer>,
+}
+
+pub struct ProfileLayer(Arc<Inner>);
+
+impl ProfileLayer {
+    pub fn new(dir: PathBuf, steps_per_trace: usize) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let inner = Inner {
+            dir,
+            steps_per_trace: steps_per_trace.max(1),
+            origin: Instant::now(),
+            buffer: Mutex::new(Buffer::default()),
+        };
+        Ok(Self(Arc::new(inner)))
+    }
+
+    /// The layer with its filter, the profiled spans are only enabled in profile mode.
+    pub fn boxed(self) -> crate::otel::BoxedLayer {
+        let targets = tracing_subscriber::filter::Targets::new()
+            .with_target("moshi", tracing::Level::TRACE)
+            .with_target("moshi_server", tracing::Level::TRACE);
+        Box::new(self.with_filter(targets))
+    }
+}
+
+impl Inner {
+    fn record(&self, event: Complete) {
+        let is_step = event.name == STEP;
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.events.push(event);
+        if !is_step {
+            return;
+        }
+        buffer.steps += 1;
+        if buffer.steps < self.steps_per_trace {
+            return;
+        }
+        buffer.steps = 0;
+        buffer.traces += 1;
+        let events = std::mem::take(&mut buffer.events);
+        let path = self.dir.join(format!("trace-{:05}.json", buffer.traces));
+        drop(buffer);
+        let origin = self.origin;
+        // Serialize off the model thread so that writing does not show up in the next trace.
+        std::thread::spawn(move || {
+            let trace = chrome_trace(origin, &events);
+            let tmp = path.with_extension("json.tmp");
+            let res =
+                std::fs::write(&tmp, trace.to_string()).and_then(|()| std::fs::rename(&tmp, &path));
+            if let Err(err) = res {
+                tracing::error!(?err, ?path, "cannot write profile trace")
+            }
+        });
+    }
+}
+
+fn chrome_trace(origin: Instant, events: &[Complete]) -> serde_json::Value {
+    let us = |t: Instant| t.saturating_duration_since(origin).as_secs_f64() * 1e6;
+    let mut threads = std::collections::BTreeMap::new();
+    let mut trace_events: Vec<_> = events
+        .iter()
+        .map(|e| {
+            if !e.thread.is_empty() {
+                threads.insert(e.tid, e.thread);
+            }
+            serde_json::json!({
+                "name": e.name,
+                "cat": "moshi",
+                "ph": "X",
+                "ts": us(e.start),
+                "dur": us(e.end) - us(e.start),
+                "pid": 1,
+                "tid": e.tid,
+            })
+        })
+        .collect();
+    trace_events.extend(threads.into_iter().map(|(tid, name)| {
+        serde_json::json!({
+            "name": "thread_name",
+            "ph": "M",
+            "pid": 1,
+            "tid": tid,
+            "args": { "name": name },
+        })
+    }));
+    serde_json::json!({ "traceEvents": trace_events, "displayTimeUnit": "ms" })
+}
+
+impl<S> Layer<S> for ProfileLayer
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            #[cfg(feature = "nvtx")]
+            nvtx::range_push!("{}", span.name());
+            span.extensions_mut().replace(Entered(Instant::now()));
+        }
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        let end = Instant::now();
+        let Some(span) = ctx.span(id) else { return };
+        #[cfg(feature = "nvtx")]
+        nvtx::range_pop!();
+        let Some(Entered(start)) = span.extensions_mut().remove::<Entered>() else { return };
+        self.0.record(Complete {
+            name: span.name(),
+            tid: TID.with(|t| *t),
+            thread: THREAD_NAME.with(|n| n.get()),
+            start,
+            end,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn writes_a_trace_every_n_steps() {
+        let dir = std::env::temp_dir().join(format!("moshi-profile-{}", std::process::id()));
+        let layer = ProfileLayer::new(dir.clone(), 2).unwrap();
+        let inner = layer.0.clone();
+        let subscriber = tracing_subscriber::registry().with(layer.boxed());
+        tracing::subscriber::with_default(subscriber, || {
+            name_thread("model_loop");
+            for _ in 0..3 {
+                let _step = tracing::span!(tracing::Level::TRACE, STEP).entered();
+                let _lm = tracing::span!(tracing::Level::TRACE, "lm-forward").entered();
+            }
+        });
+        // The third step is still buffered.
+        assert_eq!(inner.buffer.lock().unwrap().events.len(), 2);
+        let path = dir.join("trace-00001.json");
+        let start = Instant::now();
+        while !path.exists() && start.elapsed().as_secs() < 5 {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let trace: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        let names: Vec<_> = events.iter().map(|e| e["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["lm-forward", "step", "lm-forward", "step", "thread_name"]);
+        assert_eq!(events[4]["args"]["name"], "model_loop");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -25,6 +25,8 @@ pub enum CloseCode {
     GoingAway = 1001,
     /// Protocol error (RFC 6455)
     ProtocolError = 1002,
+    /// Message too big - input exceeds the configured size limits (RFC 6455)
+    MessageTooBig = 1009,
     /// Internal server error (RFC 6455)
     InternalError = 1011,
 
@@ -43,6 +45,10 @@ pub enum CloseCode {
     ResourceUnavailable = 4005,
     /// Client timeout - no data received within expected timeframe
     ClientTimeout = 4006,
+    /// Token expired - the session credentials expired and were not refreshed in time
+    TokenExpired = 4007,
+    /// Forbidden - valid credentials that do not grant access to the requested resource
+    Forbidden = 4008,
 }
 
 impl CloseCode {
@@ -57,6 +63,7 @@ impl CloseCode {
             CloseCode::Normal => "Normal closure",
             CloseCode::GoingAway => "Server going away",
             CloseCode::ProtocolError => "Protocol error",
+            CloseCode::MessageTooBig => "Message too big",
             CloseCode::InternalError => "Internal server error",
             CloseCode::ServerAtCapacity => "Server at capacity",
             CloseCode::AuthenticationFailed => "Authentication failed",
@@ -65,6 +72,8 @@ impl CloseCode {
             CloseCode::RateLimited => "Rate limited",
             CloseCode::ResourceUnavailable => "Resource unavailable",
             CloseCode::ClientTimeout => "Client timeout",
+            CloseCode::TokenExpired => "Token expired",
+            CloseCode::Forbidden => "Forbidden",
         }
     }
 
@@ -147,3 +156,216 @@ impl MsgType {
         }
     }
 }
+
+// ============================================================================
+// Streaming Input Messages
+// ============================================================================
+
+/// MessagePack messages sent by the clients of the asr, batched asr and vad endpoints.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type")]
+pub enum InMsg {
+    Init,
+    Marker {
+        id: i64,
+    },
+    Audio {
+        pcm: Vec<f32>,
+    },
+    OggOpus {
+        data: Vec<u8>,
+    },
+    /// Audio already encoded by mimi on the client, one array of `audio_codebooks` codes per
+    /// 80ms frame (batched asr only).
+    AudioCodes {
+        codes: Vec<Vec<u32>>,
+    },
+    Ping,
+    /// Ask for a checkpoint of the session (batched asr only).
+    Checkpoint,
+    /// Continue the session exported in a checkpoint, sent before any audio (batched asr only).
+    Restore {
+        data: Vec<u8>,
+    },
+    /// Sample rate of the following `Audio` messages, 24kHz until set.
+    SetSampleRate {
+        hz: u32,
+    },
+    /// New JWT for a session whose token is about to expire.
+    RefreshToken {
+        jwt: String,
+    },
+    /// A clip of the voice to transcribe, 24kHz mono, the speech of other voices is then
+    /// tagged or dropped.
+    Enroll {
+        pcm: Vec<f32>,
+        #[serde(default)]
+        other: OtherSpeech,
+    },
+    /// Latency probe, answered as soon as it is received with the server clock.
+    Echo {
+        client_ts_ms: u64,
+    },
+    /// Shift of the source time of the audio that follows, queued by the server when the
+    /// resampling of the client audio changes, never sent by the clients.
+    #[serde(skip)]
+    TimeCorrection {
+        delta_s: f64,
+    },
+}
+
+/// What becomes of the words of other speakers once a voice is enrolled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OtherSpeech {
+    /// A single `[other]` word stands for each stretch of other speech.
+    #[default]
+    Tag,
+    Suppress,
+}
+
+/// Why a client frame was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    Empty,
+    Malformed(String),
+    /// The frame holds more than one message.
+    TrailingBytes {
+        len: usize,
+    },
+    /// NaN or infinite samples, which would poison the model state of the session.
+    NonFinitePcm {
+        index: usize,
+    },
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty message"),
+            Self::Malformed(err) => write!(f, "malformed message: {err}"),
+            Self::TrailingBytes { len } => write!(f, "{len} trailing bytes after the message"),
+            Self::NonFinitePcm { index } => write!(f, "non-finite pcm sample at index {index}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Nesting allowed in client frames, messages only use a map holding arrays. Each nested
+/// sequence can preallocate up to 1MiB while it is buffered, so the MessagePack default of
+/// 1024 levels would let a few kilobytes allocate a gigabyte.
+const MAX_DEPTH: usize = 4;
+
+/// Decode a client frame. The reader never allocates more than the frame size for byte
+/// strings and serde caps the preallocation of sequences, so a frame announcing a huge array
+/// cannot exhaust the memory.
+pub fn decode_in_msg(data: &[u8]) -> std::result::Result<InMsg, DecodeError> {
+    use serde::Deserialize;
+
+    if data.is_empty() {
+        return Err(DecodeError::Empty);
+    }
+    let mut de = rmp_serde::Deserializer::new(std::io::Cursor::new(data));
+    de.set_max_depth(MAX_DEPTH);
+    let msg = InMsg::deserialize(&mut de).map_err(|e| DecodeError::Malformed(e.to_string()))?;
+    let len = data.len() - de.position() as usize;
+    if len > 0 {
+        return Err(DecodeError::TrailingBytes { len });
+    }
+    if let InMsg::Audio { pcm } | InMsg::Enroll { pcm, .. } = &msg {
+        if let Some(index) = pcm.iter().position(|v| !v.is_finite()) {
+            return Err(DecodeError::NonFinitePcm { index });
+        }
+    }
+    Ok(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn in_msg() -> impl Strategy<Value = InMsg> {
+        prop_oneof![
+            Just(InMsg::Init),
+            Just(InMsg::Ping),
+            Just(InMsg::Checkpoint),
+            any::<i64>().prop_map(|id| InMsg::Marker { id }),
+            prop::collection::vec(-1.0f32..1.0, 0..2000).prop_map(|pcm| InMsg::Audio { pcm }),
+            prop::collection::vec(any::<u8>(), 0..512).prop_map(|data| InMsg::OggOpus { data }),
+            prop::collection::vec(prop::collection::vec(0u32..2048, 32), 0..8)
+                .prop_map(|codes| InMsg::AudioCodes { codes }),
+            prop::collection::vec(any::<u8>(), 0..512).prop_map(|data| InMsg::Restore { data }),
+            any::<u32>().prop_map(|hz| InMsg::SetSampleRate { hz }),
+            ".*".prop_map(|jwt| InMsg::RefreshToken { jwt }),
+            any::<u64>().prop_map(|client_ts_ms| InMsg::Echo { client_ts_ms }),
+            (prop::collection::vec(-1.0f32..1.0, 0..2000), any::<bool>()).prop_map(|(pcm, tag)| {
+                let other = if tag { OtherSpeech::Tag } else { OtherSpeech::Suppress };
+                InMsg::Enroll { pcm, other }
+            }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn valid_messages_round_trip(msg in in_msg(), named in any::<bool>()) {
+            let data = if named {
+                rmp_serde::to_vec_named(&msg).unwrap()
+            } else {
+                rmp_serde::to_vec(&msg).unwrap()
+            };
+            prop_assert_eq!(decode_in_msg(&data), Ok(msg));
+        }
+
+        #[test]
+        fn arbitrary_bytes_do_not_panic(data in prop::collection::vec(any::<u8>(), 0..256)) {
+            let _ = decode_in_msg(&data);
+        }
+
+        #[test]
+        fn corrupted_messages_are_rejected(msg in in_msg(), pos in any::<prop::sample::Index>(), byte in any::<u8>()) {
+            let mut data = rmp_serde::to_vec_named(&msg).unwrap();
+            let pos = pos.index(data.len());
+            data[pos] = byte;
+            let _ = decode_in_msg(&data);
+            data.truncate(pos);
+            prop_assert!(decode_in_msg(&data).is_err());
+        }
+    }
+
+    #[test]
+    fn structured_errors() {
+        assert_eq!(decode_in_msg(&[]), Err(DecodeError::Empty));
+        let mut data = rmp_serde::to_vec_named(&InMsg::Ping).unwrap();
+        data.extend(rmp_serde::to_vec_named(&InMsg::Init).unwrap());
+        assert!(matches!(decode_in_msg(&data), Err(DecodeError::TrailingBytes { .. })));
+        let data = rmp_serde::to_vec_named(&InMsg::Audio { pcm: vec![0.0, f32::NAN] }).unwrap();
+        assert_eq!(decode_in_msg(&data), Err(DecodeError::NonFinitePcm { index: 1 }));
+
+        // An array announcing 2^32-1 samples followed by a handful of bytes.
+        let mut data = vec![0x82, 0xa4];
+        data.extend(b"type");
+        data.push(0xa5);
+        data.extend(b"Audio");
+        data.extend([0xa3, b'p', b'c', b'm', 0xdd, 0xff, 0xff, 0xff, 0xff, 0xca, 0, 0, 0, 0]);
+        assert!(matches!(decode_in_msg(&data), Err(DecodeError::Malformed(_))));
+        // Same with a byte string.
+        let mut data = vec![0x82, 0xa4];
+        data.extend(b"type");
+        data.push(0xa7);
+        data.extend(b"OggOpus");
+        data.extend([0xa4, b'd', b'a', b't', b'a', 0xc6, 0xff, 0xff, 0xff, 0xff, 1, 2]);
+        assert!(matches!(decode_in_msg(&data), Err(DecodeError::Malformed(_))));
+
+        // Deeply nested arrays in an unknown field.
+        let mut data = vec![0x82, 0xa4];
+        data.extend(b"type");
+        data.push(0xa4);
+        data.extend(b"Ping");
+        data.extend([0xa1, b'x']);
+        data.extend([0xdd, 0xff, 0xff, 0xff, 0xff].repeat(100));
+        let err = decode_in_msg(&data).unwrap_err();
+        assert!(err.to_string().contains("depth limit exceeded"), "{err}");
+    }
+}
@@ -0,0 +1,334 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Audio files fetched by the server for the batch ASR endpoint.
+//!
+//! A JSON body `{"url": "https://...", "format": "auto"}` replaces the raw audio bytes. The
+//! download is bounded by `max_fetch_bytes` and `fetch_timeout_s` of the `[limits]` section,
+//! the file is then decoded like an uploaded one.
+//!
+//! The server only connects to public addresses unless `fetch_private_hosts` is set: the host
+//! is resolved before connecting, the connection is pinned to the checked addresses, and
+//! redirects are followed by hand so that each hop is checked the same way. Without this, a
+//! client could make the server reach loopback services or the cloud metadata endpoint.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// Redirects followed before giving up.
+const MAX_REDIRECTS: usize = 5;
+
+/// Body of a batch query that points at a remote file.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct UrlQuery {
+    pub url: String,
+    #[serde(default)]
+    pub format: AudioFormat,
+}
+
+/// Container of the remote file, `auto` probes the content.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioFormat {
+    #[default]
+    Auto,
+    Wav,
+    Mp3,
+    Ogg,
+    Flac,
+    M4a,
+}
+
+impl AudioFormat {
+    /// File extension passed to the probe as a hint.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Self::Auto => None,
+            Self::Wav => Some("wav"),
+            Self::Mp3 => Some("mp3"),
+            Self::Ogg => Some("ogg"),
+            Self::Flac => Some("flac"),
+            Self::M4a => Some("m4a"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    InvalidUrl(String),
+    /// The host resolves to a loopback, private or otherwise non-public address.
+    Blocked(IpAddr),
+    TooLarge {
+        max_bytes: usize,
+    },
+    Status(u16),
+    Http(reqwest::Error),
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidUrl(msg) => write!(f, "invalid url: {msg}"),
+            Self::Blocked(ip) => write!(f, "fetching from {ip} is not allowed"),
+            Self::TooLarge { max_bytes } => write!(f, "remote file exceeds {max_bytes} bytes"),
+            Self::Status(status) => write!(f, "remote server answered {status}"),
+            Self::Http(err) => write!(f, "cannot fetch the remote file: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+impl FetchError {
+    pub fn status(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+        match self {
+            Self::InvalidUrl(_) => StatusCode::BAD_REQUEST,
+            Self::Blocked(_) => StatusCode::FORBIDDEN,
+            Self::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Status(_) | Self::Http(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidUrl(_) => "invalid_url",
+            Self::Blocked(_) => "blocked_url",
+            Self::TooLarge { .. } => "payload_too_large",
+            Self::Status(_) | Self::Http(_) => "fetch_failed",
+        }
+    }
+}
+
+/// Whether `ip` is a public unicast address, i.e. not loopback, private, link-local (such
+/// as the `169.254.169.254` metadata endpoint), shared, documentation or reserved.
+pub fn is_public(ip: IpAddr) -> bool {
+    fn is_public_v4(ip: Ipv4Addr) -> bool {
+        let [a, b, c, _] = ip.octets();
+        !(ip.is_unspecified()
+            || ip.is_loopback()
+            || ip.is_private()
+            || ip.is_link_local()
+            || ip.is_broadcast()
+            || ip.is_documentation()
+            || ip.is_multicast()
+            || a == 0
+            || a >= 240
+            || (a == 100 && (64..128).contains(&b))
+            || (a == 192 && b == 0 && c == 0)
+            || (a == 198 && (18..20).contains(&b)))
+    }
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_public_v4(v4);
+            }
+            let seg = ip.segments();
+            // NAT64 addresses embed the IPv4 address they reach.
+            if seg[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [a, b] = seg[6].to_be_bytes();
+                let [c, d] = seg[7].to_be_bytes();
+                return is_public_v4(Ipv4Addr::new(a, b, c, d));
+            }
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || (seg[0] & 0xfe00) == 0xfc00
+                || (seg[0] & 0xffc0) == 0xfe80
+                || (seg[0] == 0x2001 && seg[1] == 0xdb8))
+        }
+    }
+}
+
+/// Downloads `url`, giving up as soon as the file is known to exceed `max_bytes`. Hosts
+/// resolving to non-public addresses are refused unless `allow_private` is set.
+pub async fn fetch(
+    url: &str,
+    max_bytes: usize,
+    timeout: std::time::Duration,
+    allow_private: bool,
+) -> Result<axum::body::Bytes, FetchError> {
+    let allowed = |ip: IpAddr| allow_private || is_public(ip);
+    fetch_with(url, max_bytes, timeout, &allowed).await
+}
+
+/// Resolve the host of `url` and check that all its addresses are allowed.
+async fn resolve(
+    url: &reqwest::Url,
+    allowed: &(dyn Fn(IpAddr) -> bool + Sync),
+) -> Result<Vec<SocketAddr>, FetchError> {
+    let host = url.host_str().ok_or_else(|| FetchError::InvalidUrl("missing host".into()))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let literal = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = match literal.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|err| FetchError::InvalidUrl(format!("cannot resolve {host}: {err}")))?
+            .collect(),
+    };
+    if addrs.is_empty() {
+        return Err(FetchError::InvalidUrl(format!("cannot resolve {host}")));
+    }
+    if let Some(addr) = addrs.iter().find(|addr| !allowed(addr.ip())) {
+        return Err(FetchError::Blocked(addr.ip()));
+    }
+    Ok(addrs)
+}
+
+async fn fetch_with(
+    url: &str,
+    max_bytes: usize,
+    timeout: std::time::Duration,
+    allowed: &(dyn Fn(IpAddr) -> bool + Sync),
+) -> Result<axum::body::Bytes, FetchError> {
+    let mut url = reqwest::Url::parse(url).map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+    let deadline = std::time::Instant::now() + timeout;
+    let mut redirects = 0;
+    let mut response = loop {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(FetchError::InvalidUrl(format!("unsupported scheme {}", url.scheme())));
+        }
+        let addrs = resolve(&url, allowed).await?;
+        // The connection goes to the checked addresses, a second resolution could differ.
+        let host = url.host_str().unwrap_or_default().to_string();
+        let client = reqwest::Client::builder()
+            .timeout(deadline.saturating_duration_since(std::time::Instant::now()))
+            .redirect(reqwest::redirect::Policy::none())
+            .resolve_to_addrs(&host, &addrs)
+            .build()
+            .map_err(FetchError::Http)?;
+        let response = client.get(url.clone()).send().await.map_err(FetchError::Http)?;
+        if !response.status().is_redirection() {
+            break response;
+        }
+        redirects += 1;
+        if redirects > MAX_REDIRECTS {
+            return Err(FetchError::InvalidUrl(format!("more than {MAX_REDIRECTS} redirects")));
+        }
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or(FetchError::Status(response.status().as_u16()))?;
+        url = url.join(location).map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+    };
+    if !response.status().is_success() {
+        return Err(FetchError::Status(response.status().as_u16()));
+    }
+    if response.content_length().is_some_and(|len| len > max_bytes as u64) {
+        return Err(FetchError::TooLarge { max_bytes });
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(FetchError::Http)? {
+        if body.len() + chunk.len() > max_bytes {
+            return Err(FetchError::TooLarge { max_bytes });
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_query() {
+        let q: UrlQuery = serde_json::from_str(r#"{"url": "https://a.b/c.m4a"}"#).unwrap();
+        assert_eq!(q.format, AudioFormat::Auto);
+        let q: UrlQuery = serde_json::from_str(r#"{"url": "x", "format": "mp3"}"#).unwrap();
+        assert_eq!(q.format.extension(), Some("mp3"));
+        assert!(serde_json::from_str::<UrlQuery>(r#"{"url": "x", "format": "aiff"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_other_schemes() {
+        let timeout = std::time::Duration::from_secs(1);
+        let err = fetch("file:///etc/passwd", 1024, timeout, false).await.unwrap_err();
+        assert!(matches!(err, FetchError::InvalidUrl(_)));
+        let err = fetch("not a url", 1024, timeout, false).await.unwrap_err();
+        assert!(matches!(err, FetchError::InvalidUrl(_)));
+    }
+
+    #[test]
+    fn public_addresses() {
+        for ip in ["8.8.8.8", "2a00:1450:4007:80e::200e", "::ffff:1.1.1.1", "64:ff9b::808:808"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a00:1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn blocks_private_hosts() {
+        let timeout = std::time::Duration::from_secs(1);
+        for url in [
+            "http://127.0.0.1:1/a.wav",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://[::1]:1/a.wav",
+            "http://localhost:1/a.wav",
+        ] {
+            let err = fetch(url, 1024, timeout, false).await.unwrap_err();
+            assert!(matches!(err, FetchError::Blocked(_)), "{url}: {err}");
+            assert_eq!(err.status(), axum::http::StatusCode::FORBIDDEN);
+        }
+    }
+
+    /// Serve `response` to every connection on a loopback port, returns the port.
+    async fn serve(response: &'static str) -> u16 {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn checks_each_redirect() {
+        let timeout = std::time::Duration::from_secs(5);
+        // The test servers are on loopback, allow it but nothing else private.
+        let allowed = |ip: IpAddr| ip.is_loopback() || is_public(ip);
+        let ok =
+            serve("HTTP/1.1 200 OK\r\ncontent-length: 4\r\nconnection: close\r\n\r\nRIFF").await;
+        let url = format!("http://127.0.0.1:{ok}/a.wav");
+        let body = fetch_with(&url, 1024, timeout, &allowed).await.unwrap();
+        assert_eq!(body.as_ref(), b"RIFF");
+
+        let redirect = "HTTP/1.1 302 Found\r\nlocation: http://169.254.169.254/latest/meta-data/\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+        let port = serve(redirect).await;
+        let url = format!("http://127.0.0.1:{port}/a.wav");
+        let err = fetch_with(&url, 1024, timeout, &allowed).await.unwrap_err();
+        assert!(matches!(err, FetchError::Blocked(ip) if ip.to_string() == "169.254.169.254"));
+
+        let to_file = "HTTP/1.1 301 Moved\r\nlocation: file:///etc/passwd\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+        let port = serve(to_file).await;
+        let url = format!("http://127.0.0.1:{port}/a.wav");
+        let err = fetch_with(&url, 1024, timeout, &allowed).await.unwrap_err();
+        assert!(matches!(err, FetchError::InvalidUrl(_)));
+    }
+}
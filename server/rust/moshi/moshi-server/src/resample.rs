@@ -0,0 +1,185 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Resampling of the `Audio` messages of clients that do not capture at 24kHz.
+//!
+//! The rate is announced with `SetSampleRate { hz }` and can change mid-stream, e.g. when a
+//! headset is plugged in. The audio buffered at the previous rate is flushed before switching
+//! so that no sample is dropped or played at the wrong rate.
+//!
+//! The output is delayed by the resampling filter and the flush pads the buffered audio to a
+//! full chunk, so the output strays from the time of the source audio. The resampler keeps
+//! track of it to correct the timestamps of the words, see [`crate::drift`].
+
+use anyhow::Result;
+use rubato::Resampler as _;
+
+pub const MODEL_SAMPLE_RATE: u32 = 24_000;
+pub const MIN_SAMPLE_RATE: u32 = 8_000;
+pub const MAX_SAMPLE_RATE: u32 = 192_000;
+
+struct Inner {
+    resampler: rubato::FftFixedInOut<f32>,
+    input: Vec<Vec<f32>>,
+    output: Vec<Vec<f32>>,
+    pending: Vec<f32>,
+}
+
+impl Inner {
+    fn new(rate: u32) -> Result<Self> {
+        // 20ms chunks, rubato adjusts the size to the ratio.
+        let resampler = rubato::FftFixedInOut::<f32>::new(
+            rate as usize,
+            MODEL_SAMPLE_RATE as usize,
+            rate as usize / 50,
+            1,
+        )?;
+        let input = resampler.input_buffer_allocate(true);
+        let output = resampler.output_buffer_allocate(true);
+        Ok(Self { resampler, input, output, pending: vec![] })
+    }
+
+    fn process(&mut self, pcm: &[f32], out: &mut Vec<f32>) -> Result<()> {
+        self.pending.extend_from_slice(pcm);
+        let mut start = 0;
+        while self.pending.len() - start >= self.resampler.input_frames_next() {
+            let n = self.resampler.input_frames_next();
+            self.input[0].copy_from_slice(&self.pending[start..start + n]);
+            let (_, out_len) =
+                self.resampler.process_into_buffer(&self.input, &mut self.output, None)?;
+            out.extend_from_slice(&self.output[0][..out_len]);
+            start += n;
+        }
+        self.pending.drain(..start);
+        Ok(())
+    }
+
+    fn flush(&mut self, out: &mut Vec<f32>) -> Result<()> {
+        if !self.pending.is_empty() {
+            let (_, out_len) = self.resampler.process_partial_into_buffer(
+                Some(&[&self.pending]),
+                &mut self.output,
+                None,
+            )?;
+            out.extend_from_slice(&self.output[0][..out_len]);
+            self.pending.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Per-session conversion of the client audio to the model rate.
+pub struct InputResampler {
+    rate: u32,
+    inner: Option<Inner>,
+    /// Source time when the current rate was set, and samples received at this rate since.
+    source_s: f64,
+    received: u64,
+    /// Samples produced at 24kHz.
+    produced: u64,
+    correction_s: f64,
+}
+
+impl Default for InputResampler {
+    fn default() -> Self {
+        Self {
+            rate: MODEL_SAMPLE_RATE,
+            inner: None,
+            source_s: 0.0,
+            received: 0,
+            produced: 0,
+            correction_s: 0.0,
+        }
+    }
+}
+
+impl InputResampler {
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Difference between the source time of the audio produced at the current rate and its
+    /// time at 24kHz, only changes with the rate.
+    pub fn correction_s(&self) -> f64 {
+        self.correction_s
+    }
+
+    /// Switch the input rate, the audio still buffered at the previous rate is returned.
+    pub fn set_rate(&mut self, hz: u32) -> Result<Vec<f32>> {
+        if !(MIN_SAMPLE_RATE..=MAX_SAMPLE_RATE).contains(&hz) {
+            anyhow::bail!(
+                "unsupported sample rate {hz}, expected {MIN_SAMPLE_RATE} to {MAX_SAMPLE_RATE}"
+            )
+        }
+        let mut tail = vec![];
+        if hz == self.rate {
+            return Ok(tail);
+        }
+        if let Some(inner) = self.inner.as_mut() {
+            inner.flush(&mut tail)?;
+        }
+        self.inner = if hz == MODEL_SAMPLE_RATE { None } else { Some(Inner::new(hz)?) };
+        self.source_s += self.received as f64 / self.rate as f64;
+        self.received = 0;
+        self.produced += tail.len() as u64;
+        // Within a rate the output follows the source exactly, the filter delay aside.
+        let delay = self.inner.as_ref().map_or(0, |i| i.resampler.output_delay());
+        self.correction_s =
+            self.source_s - (self.produced + delay as u64) as f64 / MODEL_SAMPLE_RATE as f64;
+        self.rate = hz;
+        Ok(tail)
+    }
+
+    /// Convert `pcm`, sampled at the current rate, to 24kHz.
+    pub fn process(&mut self, pcm: Vec<f32>) -> Result<Vec<f32>> {
+        self.received += pcm.len() as u64;
+        let out = match self.inner.as_mut() {
+            None => pcm,
+            Some(inner) => {
+                let mut out = Vec::with_capacity(
+                    pcm.len() * MODEL_SAMPLE_RATE as usize / self.rate as usize + 1,
+                );
+                inner.process(&pcm, &mut out)?;
+                out
+            }
+        };
+        self.produced += out.len() as u64;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_changes_mid_stream() {
+        let mut r = InputResampler::default();
+        assert_eq!(r.process(vec![0.5; 100]).unwrap().len(), 100);
+
+        // One second at 48kHz then one second at 16kHz, both end up as 24kHz audio.
+        assert!(r.set_rate(48_000).unwrap().is_empty());
+        let tone = |sr: usize| -> Vec<f32> {
+            (0..sr).map(|i| (i as f32 * 440.0 * std::f32::consts::TAU / sr as f32).sin()).collect()
+        };
+        let mut out = vec![];
+        for chunk in tone(48_000).chunks(1000) {
+            out.extend(r.process(chunk.to_vec()).unwrap());
+        }
+        out.extend(r.set_rate(16_000).unwrap());
+        assert_eq!(r.rate(), 16_000);
+        let first = out.len();
+        assert!((23_000..=24_100).contains(&first), "{first}");
+        for chunk in tone(16_000).chunks(700) {
+            out.extend(r.process(chunk.to_vec()).unwrap());
+        }
+        let second = out.len() - first;
+        assert!((23_000..=24_100).contains(&second), "{second}");
+
+        assert!(r.set_rate(4_000).is_err());
+        assert_eq!(r.rate(), 16_000);
+        assert!(r.set_rate(24_000).unwrap().len() < 1000);
+        assert_eq!(r.process(vec![0.5; 10]).unwrap().len(), 10);
+    }
+}
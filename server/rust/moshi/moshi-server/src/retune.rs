@@ -0,0 +1,99 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Runtime retuning of the ASR modules with `POST <path>/config`.
+//!
+//! The module keeps its current settings in a watch channel. The model loops pick the new
+//! values up before their next step and the live sessions get a `ConfigUpdate` message, so
+//! that an admin change never affects a transcription silently.
+
+/// Largest delay that can be set, 8s of audio.
+const MAX_ASR_DELAY: usize = 100;
+const MAX_TEMPERATURE: f64 = 2.0;
+
+/// Settings of an ASR module that can be changed while it runs.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct Tuning {
+    /// Delay of the text behind the audio, in 80ms steps.
+    pub asr_delay: usize,
+    pub temperature: f64,
+}
+
+/// Body of `POST <path>/config`, the fields left out keep their value.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetuneQuery {
+    pub asr_delay: Option<usize>,
+    pub temperature: Option<f64>,
+}
+
+impl Tuning {
+    /// The settings after `query`. The delay of a batched module cannot change, its markers
+    /// and segments are scheduled with it ahead of the model steps.
+    pub fn retune(self, query: &RetuneQuery, fixed_delay: bool) -> Result<Self, String> {
+        let mut tuning = self;
+        if let Some(asr_delay) = query.asr_delay {
+            if fixed_delay && asr_delay != self.asr_delay {
+                return Err("asr_delay cannot be changed on a batched module".to_string());
+            }
+            if !(1..=MAX_ASR_DELAY).contains(&asr_delay) {
+                return Err(format!("asr_delay must be between 1 and {MAX_ASR_DELAY}"));
+            }
+            tuning.asr_delay = asr_delay;
+        }
+        if let Some(temperature) = query.temperature {
+            if !(0.0..=MAX_TEMPERATURE).contains(&temperature) {
+                return Err(format!("temperature must be between 0 and {MAX_TEMPERATURE}"));
+            }
+            tuning.temperature = temperature;
+        }
+        Ok(tuning)
+    }
+
+    /// Apply the settings to a model state.
+    pub fn apply(&self, state: &mut moshi::asr::State) {
+        state.asr_delay_in_tokens = self.asr_delay;
+        state.temperature = self.temperature;
+    }
+}
+
+/// Settings of the `<path>/config` endpoints of the ASR modules.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct RetuneConfig {
+    #[serde(default = "default_auth")]
+    pub auth: crate::auth::AuthPolicy,
+}
+
+fn default_auth() -> crate::auth::AuthPolicy {
+    crate::auth::AuthPolicy::Admin
+}
+
+impl Default for RetuneConfig {
+    fn default() -> Self {
+        Self { auth: default_auth() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retune_validates_and_keeps_unset_fields() {
+        let tuning = Tuning { asr_delay: 6, temperature: 0.0 };
+        let query = RetuneQuery { temperature: Some(0.5), ..Default::default() };
+        assert_eq!(tuning.retune(&query, true), Ok(Tuning { asr_delay: 6, temperature: 0.5 }));
+        let query = RetuneQuery { asr_delay: Some(12), temperature: None };
+        assert_eq!(tuning.retune(&query, false), Ok(Tuning { asr_delay: 12, temperature: 0.0 }));
+        assert!(tuning.retune(&query, true).is_err());
+        let same = RetuneQuery { asr_delay: Some(6), temperature: None };
+        assert!(tuning.retune(&same, true).is_ok());
+        let zero = RetuneQuery { asr_delay: Some(0), temperature: None };
+        assert!(tuning.retune(&zero, false).is_err());
+        let nan = RetuneQuery { asr_delay: None, temperature: Some(f64::NAN) };
+        assert!(tuning.retune(&nan, false).is_err());
+        let hot = RetuneQuery { asr_delay: None, temperature: Some(3.0) };
+        assert!(tuning.retune(&hot, false).is_err());
+    }
+}
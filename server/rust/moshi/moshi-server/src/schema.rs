@@ -0,0 +1,239 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Versions of the JSON files written by the server.
+//!
+//! Each file, and each line of the access log, carries a `schema_version`. Fields can be added
+//! within a version, readers have to ignore the fields they do not know. Renaming or removing a
+//! field, or changing its meaning, bumps the version of the artifact and adds a step to
+//! [`migrate`], so that `moshi-server migrate` brings older files to the current schema. Files
+//! written before the versioning are version 0.
+
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+use std::path::Path;
+
+pub const VERSION_FIELD: &str = "schema_version";
+
+/// Kind of a JSON file written by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Artifact {
+    /// Words of a segment of a batched asr session, `segment_dir`.
+    Segment,
+    /// Lines of the `[access_log]`.
+    AccessLog,
+    /// Calibrations of `autotune.json`.
+    Autotune,
+    /// Query of a session dumped next to its tokens in `log_dir`.
+    SessionLog,
+    /// Summary of a batched asr session, `summary_path`.
+    SessionSummary,
+}
+
+impl Artifact {
+    /// Version written by this server.
+    pub fn version(self) -> u64 {
+        match self {
+            Self::Segment
+            | Self::AccessLog
+            | Self::Autotune
+            | Self::SessionLog
+            | Self::SessionSummary => 1,
+        }
+    }
+
+    /// Whether the file is made of one JSON document per line.
+    fn lines(self) -> bool {
+        self == Self::AccessLog
+    }
+
+    /// Guess the kind of a file from its name and its first document, session logs hold the
+    /// query of their module and cannot be told apart from other files.
+    pub fn detect(path: &Path, first: &Value) -> Option<Self> {
+        if path.file_name().is_some_and(|n| n == "autotune.json") {
+            return Some(Self::Autotune);
+        }
+        let has = |field: &str| first.get(field).is_some();
+        if has("index") && has("start_s") && has("words") {
+            Some(Self::Segment)
+        } else if has("per_second") {
+            Some(Self::SessionSummary)
+        } else if has("method") && has("path") && has("status") {
+            Some(Self::AccessLog)
+        } else {
+            None
+        }
+    }
+}
+
+fn version_of(value: &Value) -> u64 {
+    value.get(VERSION_FIELD).and_then(Value::as_u64).unwrap_or(0)
+}
+
+/// Bring `value`, of any version up to the current one, to the current schema.
+pub fn migrate(artifact: Artifact, value: Value) -> Result<Value> {
+    let version = version_of(&value);
+    if version > artifact.version() {
+        anyhow::bail!(
+            "{artifact:?} of version {version}, this server writes version {}",
+            artifact.version()
+        )
+    }
+    let mut value = value;
+    for from in version..artifact.version() {
+        value = step(artifact, from, value)?;
+    }
+    if let Value::Object(map) = &mut value {
+        map.insert(VERSION_FIELD.to_string(), artifact.version().into());
+    }
+    Ok(value)
+}
+
+/// Migration of an artifact from version `from` to the next one.
+fn step(artifact: Artifact, from: u64, value: Value) -> Result<Value> {
+    match (artifact, from, value) {
+        // The LM modules dumped a null query.
+        (Artifact::SessionLog, 0, query @ Value::Null) => {
+            Ok(Value::Object(Map::from_iter([("query".to_string(), query)])))
+        }
+        (_, 0, value @ Value::Object(_)) => Ok(value),
+        (artifact, from, _) => anyhow::bail!("unexpected {artifact:?} of version {from}"),
+    }
+}
+
+/// Serialize `value`, as written before the versioning, in the current schema.
+pub fn stamp<T: serde::Serialize>(artifact: Artifact, value: &T) -> Result<Value> {
+    migrate(artifact, serde_json::to_value(value)?)
+}
+
+/// Outcome of [`migrate_file`], the versions found in the file and whether it changed.
+#[derive(Debug)]
+pub struct FileMigration {
+    pub artifact: Artifact,
+    pub versions: std::collections::BTreeSet<u64>,
+    pub changed: bool,
+}
+
+/// Migrate a file to the current schema, `artifact` is detected when unset. The file is
+/// replaced through a rename, unless `dry_run` is set.
+pub fn migrate_file(
+    path: &Path,
+    artifact: Option<Artifact>,
+    dry_run: bool,
+) -> Result<FileMigration> {
+    let content = std::fs::read_to_string(path).with_context(|| path.display().to_string())?;
+    let documents: Vec<Value> = match serde_json::from_str(&content) {
+        Ok(document) => vec![document],
+        // Several documents, one per line.
+        Err(_) => content
+            .lines()
+            .enumerate()
+            .filter(|(_, l)| !l.trim().is_empty())
+            .map(|(i, l)| serde_json::from_str(l).with_context(|| format!("line {}", i + 1)))
+            .collect::<Result<_>>()?,
+    };
+    let first = documents.first().cloned().unwrap_or_default();
+    let artifact = match artifact.or_else(|| Artifact::detect(path, &first)) {
+        Some(artifact) => artifact,
+        None => anyhow::bail!("cannot tell the kind of {}, pass --kind", path.display()),
+    };
+    if documents.len() > 1 && !artifact.lines() {
+        anyhow::bail!("{} holds several documents, expected a single {artifact:?}", path.display())
+    }
+    let versions = documents.iter().map(version_of).collect();
+    let migrated =
+        documents.iter().map(|d| migrate(artifact, d.clone())).collect::<Result<Vec<_>>>()?;
+    let changed = migrated != documents;
+    if changed && !dry_run {
+        let mut out = String::new();
+        for document in migrated.iter() {
+            if artifact.lines() {
+                out.push_str(&serde_json::to_string(document)?)
+            } else {
+                out.push_str(&serde_json::to_string_pretty(document)?)
+            }
+            out.push('\n');
+        }
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        std::fs::write(&tmp, out)?;
+        std::fs::rename(&tmp, path)?;
+    }
+    Ok(FileMigration { artifact, versions, changed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn migrates_unversioned_files() {
+        let cache = json!({"schema_version": 1, "entries": {"gpu-model": {"dtype": "bf16"}}});
+        assert_eq!(migrate(Artifact::Autotune, cache.clone()).unwrap(), cache);
+
+        let segment = json!({"index": 3, "start_s": 30.0, "words": []});
+        assert_eq!(
+            Artifact::detect(Path::new("seg-00003.json"), &segment),
+            Some(Artifact::Segment)
+        );
+        assert_eq!(migrate(Artifact::Segment, segment).unwrap()[VERSION_FIELD], 1);
+        let summary = json!({"start_s": 0.0, "per_second": {"words": [2, 3]}});
+        assert_eq!(
+            Artifact::detect(Path::new("asr-1700000000-3.json"), &summary),
+            Some(Artifact::SessionSummary)
+        );
+        assert_eq!(
+            stamp(Artifact::SessionLog, &()).unwrap(),
+            json!({"query": null, "schema_version": 1})
+        );
+
+        let newer = json!({"index": 0, "schema_version": 99});
+        assert!(migrate(Artifact::Segment, newer).is_err());
+    }
+
+    #[test]
+    fn migrates_files_in_place() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("moshi-schema-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+
+        let segment = dir.join("seg-00001.json");
+        std::fs::write(&segment, r#"{"index": 1, "start_s": 10.0, "words": []}"#)?;
+        let m = migrate_file(&segment, None, true)?;
+        assert_eq!((m.artifact, m.changed), (Artifact::Segment, true));
+        assert_eq!(m.versions, [0].into());
+        // A dry run leaves the file as it was.
+        assert!(!std::fs::read_to_string(&segment)?.contains(VERSION_FIELD));
+        assert!(migrate_file(&segment, None, false)?.changed);
+        let migrated: Value = serde_json::from_str(&std::fs::read_to_string(&segment)?)?;
+        assert_eq!(migrated[VERSION_FIELD], 1);
+        assert!(!migrate_file(&segment, None, false)?.changed);
+
+        // The access log keeps one document per line, mixing versions.
+        let log = dir.join("access.log");
+        let lines = [
+            r#"{"method":"GET","path":"/api/status","status":200}"#,
+            r#"{"method":"GET","path":"/api/status","status":200,"schema_version":1}"#,
+        ];
+        std::fs::write(&log, lines.join("\n"))?;
+        let m = migrate_file(&log, None, false)?;
+        assert_eq!((m.artifact, m.versions), (Artifact::AccessLog, [0, 1].into()));
+        let content = std::fs::read_to_string(&log)?;
+        assert_eq!(content.lines().count(), 2);
+        for line in content.lines() {
+            assert_eq!(serde_json::from_str::<Value>(line)?[VERSION_FIELD], 1);
+        }
+
+        // Session logs cannot be detected, several documents need a line based artifact.
+        let query = dir.join("asr-query.json");
+        std::fs::write(&query, "{}\n{}\n")?;
+        assert!(migrate_file(&query, None, true).is_err());
+        assert!(migrate_file(&query, Some(Artifact::SessionLog), true).is_err());
+        std::fs::write(&query, "null")?;
+        assert!(migrate_file(&query, Some(Artifact::SessionLog), false)?.changed);
+        let migrated: Value = serde_json::from_str(&std::fs::read_to_string(&query)?)?;
+        assert_eq!(migrated, json!({"query": null, "schema_version": 1}));
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,328 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Embedding of the server in another axum application.
+//!
+//! ```ignore
+//! let config = moshi_server::Config::load("config.toml")?;
+//! let asr = moshi_server::ServerBuilder::new(config).static_files(false).router().await?;
+//! let app = axum::Router::new().nest("/speech", asr).layer(my_auth_layer);
+//! axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>())
+//!     .await?;
+//! ```
+//!
+//! The status endpoints read the peer address, the router has to be served with
+//! `into_make_service_with_connect_info::<SocketAddr>` as above. Logging is left to the
+//! embedding application, the modules only emit `tracing` events, and the access log of the
+//! REST endpoints is only written when the `[access_log]` section enables it.
+
+use crate::{access_log, admin, autotune, compute, limits, memory, privacy, storage, utils};
+use crate::{AppState, AppStateInner, Config, ModuleConfig, SharedState, SharedStateInner};
+use anyhow::Result;
+use candle::Device;
+use std::sync::Arc;
+
+fn device(cpu: bool) -> Result<Device> {
+    if cpu {
+        Ok(Device::Cpu)
+    } else if candle::utils::cuda_is_available() {
+        Ok(Device::new_cuda(0)?)
+    } else if candle::utils::metal_is_available() {
+        Ok(Device::new_metal(0)?)
+    } else {
+        Ok(Device::Cpu)
+    }
+}
+
+/// Options of a server, [`ServerBuilder::build`] loads its modules.
+#[derive(Debug, Clone)]
+pub struct ServerBuilder {
+    config: Config,
+    cpu: bool,
+    disable_cuda_events: bool,
+    enable_tf32: bool,
+    static_files: bool,
+}
+
+impl ServerBuilder {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            cpu: false,
+            disable_cuda_events: false,
+            enable_tf32: true,
+            static_files: true,
+        }
+    }
+
+    /// Run the models on the CPU even when a GPU is available.
+    pub fn cpu(mut self, cpu: bool) -> Self {
+        self.cpu = cpu;
+        self
+    }
+
+    /// Disable the CUDA event tracking to reduce the overhead of the model steps.
+    pub fn disable_cuda_events(mut self, disable: bool) -> Self {
+        self.disable_cuda_events = disable;
+        self
+    }
+
+    /// Use TF32 for the CUDA matmuls, enabled by default.
+    pub fn enable_tf32(mut self, enable: bool) -> Self {
+        self.enable_tf32 = enable;
+        self
+    }
+
+    /// Serve the files of `static_dir` for the paths that match no route, enabled by default.
+    /// Embedding applications usually have their own fallback.
+    pub fn static_files(mut self, enable: bool) -> Self {
+        self.static_files = enable;
+        self
+    }
+
+    /// Pick the dtypes for the detected GPU, plan the memory budget and load the modules.
+    pub async fn build(self) -> Result<Server> {
+        let Self { mut config, cpu, disable_cuda_events, enable_tf32, static_files } = self;
+        privacy::init(&config.privacy);
+        let gpu_info = utils::get_gpu_info().ok();
+        let mut autotune = None;
+        match gpu_info.as_ref() {
+            Some(gpu_info) => {
+                if config.autotune.enabled {
+                    let cache = autotune::Cache::load(config.autotune.path());
+                    let pending = autotune::apply(&cache, &mut config.modules, gpu_info);
+                    autotune = Some((cache, pending));
+                }
+                auto_dtype(&mut config, gpu_info)
+            }
+            None => tracing::warn!("Could not detect GPU capabilities. Using configured values."),
+        }
+        let memory = memory::plan(&config.memory, &mut config.modules, gpu_info.as_ref());
+        memory.log_summary();
+        let memory = Arc::new(memory);
+
+        let device = device(cpu)?;
+        let mut devices = std::collections::HashMap::new();
+        for (name, module) in config.modules.iter() {
+            let device = compute::module_device(module.compute(), &device)?;
+            #[cfg(feature = "cuda")]
+            if let candle::Device::Cuda(d) = &device {
+                unsafe {
+                    if disable_cuda_events {
+                        tracing::info!(module = name, "disabling CUDA event tracking");
+                        d.disable_event_tracking();
+                    }
+                    if enable_tf32 {
+                        tracing::info!(module = name, "enabling TF32");
+                        d.set_tf32(true);
+                    }
+                }
+            };
+            devices.insert(name.clone(), device);
+        }
+        #[cfg(not(feature = "cuda"))]
+        let _ = (disable_cuda_events, enable_tf32);
+
+        let static_dir =
+            if static_files { Some(utils::resolve_or_download(&config.static_dir)?) } else { None };
+        let access_log = if config.access_log.enabled {
+            Some(Arc::new(access_log::AccessLog::open(&config.access_log, &config.log_dir)?))
+        } else {
+            None
+        };
+        let admin = config.admin.storage_dir.as_ref().map(|dir| {
+            tracing::info!(dir, "admin endpoints enabled");
+            Arc::new(admin::AdminStore::new(Box::new(storage::DirStorage::new(dir))))
+        });
+        let shared_state =
+            Arc::new(SharedStateInner { config: config.clone(), memory: memory.clone(), admin });
+        let state = Arc::new(AppStateInner::new(config, memory.clone(), devices).await?);
+        if let Some((cache, pending)) = autotune.filter(|(_, pending)| !pending.is_empty()) {
+            autotune::calibrate(cache, &pending, &shared_state.config, &state.modules).await;
+        }
+        crate::init_server_start_time();
+        crate::spawn_metrics_updater(memory);
+        Ok(Server { state, shared_state, static_dir, gpu_info, access_log })
+    }
+
+    /// Load the modules and return the router serving them, see [`Server::router`].
+    pub async fn router(self) -> Result<axum::Router> {
+        self.build().await?.router()
+    }
+}
+
+/// Set the dtype of the modules that do not override it to the one recommended for the GPU.
+fn auto_dtype(config: &mut Config, gpu_info: &utils::GpuInfo) {
+    // Extract model info from the first LM-bearing module for logging
+    let model_info = config.modules.values().find_map(|m| match m {
+        ModuleConfig::Lm { config: c, .. } => Some(utils::ModelInfo::from_lm_config(c)),
+        ModuleConfig::Asr { config: c, .. } | ModuleConfig::Vad { config: c, .. } => {
+            Some(utils::ModelInfo::from_asr_config(c))
+        }
+        ModuleConfig::BatchedAsr { config: c, .. } => Some(utils::ModelInfo::from_asr_config(c)),
+        ModuleConfig::Tts { config: c, .. } => Some(utils::ModelInfo::from_tts_config(c)),
+        _ => None,
+    });
+
+    // Log combined GPU and model summary
+    gpu_info.log_combined_summary(model_info.as_ref());
+
+    // Get recommended dtype based on GPU compute capability
+    let auto_dtype = gpu_info.recommended_dtype();
+
+    for (name, module_cfg) in config.modules.iter_mut() {
+        match module_cfg {
+            ModuleConfig::BatchedAsr { config: asr_config, .. }
+                if asr_config.dtype_override.is_none() =>
+            {
+                tracing::info!(
+                    module = name,
+                    dtype = auto_dtype,
+                    "Auto-setting dtype_override for BatchedAsr"
+                );
+                asr_config.dtype_override = Some(auto_dtype.to_string());
+            }
+            ModuleConfig::Asr { config: asr_config, .. }
+            | ModuleConfig::Vad { config: asr_config, .. }
+                if asr_config.dtype_override.is_none() =>
+            {
+                tracing::info!(
+                    module = name,
+                    dtype = auto_dtype,
+                    "Auto-setting dtype_override for Asr"
+                );
+                asr_config.dtype_override = Some(auto_dtype.to_string());
+            }
+            ModuleConfig::Tts { config: tts_config, .. } if tts_config.dtype_override.is_none() => {
+                tracing::info!(
+                    module = name,
+                    dtype = auto_dtype,
+                    "Auto-setting dtype_override for Tts"
+                );
+                tts_config.dtype_override = Some(auto_dtype.to_string());
+            }
+            ModuleConfig::Lm { config: lm_config, .. } if lm_config.dtype_override.is_none() => {
+                tracing::info!(
+                    module = name,
+                    dtype = auto_dtype,
+                    "Auto-setting dtype_override for Lm"
+                );
+                lm_config.dtype_override = Some(auto_dtype.to_string());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Server whose modules are loaded, the models are shared by all the routers it returns.
+pub struct Server {
+    state: AppState,
+    shared_state: SharedState,
+    static_dir: Option<String>,
+    gpu_info: Option<utils::GpuInfo>,
+    access_log: Option<Arc<access_log::AccessLog>>,
+}
+
+impl Server {
+    /// Configuration of the modules, with the dtypes and batch sizes picked at startup.
+    pub fn config(&self) -> &Config {
+        &self.shared_state.config
+    }
+
+    pub fn gpu_info(&self) -> Option<&utils::GpuInfo> {
+        self.gpu_info.as_ref()
+    }
+
+    /// Ramp synthetic sessions on the first batched asr module, for `server bench --soak`.
+    pub async fn soak(&self, cfg: &crate::bench::SoakConfig) -> Result<crate::bench::SoakReport> {
+        let asr = self.state.modules.iter().find_map(|m| match m {
+            crate::Module::BatchedAsr { path, m, .. } => Some((path, m)),
+            _ => None,
+        });
+        let Some((path, asr)) = asr else { anyhow::bail!("no BatchedAsr module to soak") };
+        crate::bench::soak(path, asr.clone(), cfg).await
+    }
+
+    /// Router serving the status endpoints, `/metrics` and the paths of the modules.
+    pub fn router(&self) -> Result<axum::Router> {
+        use axum::routing::get;
+
+        let mut app = axum::Router::new()
+            .route("/api/status", get(crate::server_status))
+            .route("/api/events", get(crate::server_events))
+            .route("/api/bench/latency", get(crate::bench_latency))
+            .route("/api/health", get(crate::health_check))
+            .route("/api/build_info", get(crate::build_info))
+            .route("/api/modules_info", get(crate::modules_info))
+            .route("/api/capabilities", get(crate::capabilities))
+            .route("/metrics", get(crate::metrics));
+        let static_dir = self.static_dir.clone();
+        app = app.route(
+            "/api/client_version",
+            get(move || async move {
+                let version = utils::ClientVersion::new(static_dir.as_deref()).await;
+                ([(axum::http::header::CACHE_CONTROL, "no-cache")], axum::Json(version))
+            }),
+        );
+        if let Some(static_dir) = self.static_dir.as_ref() {
+            app = app.fallback_service(utils::static_files(static_dir));
+        }
+        let mut app = app
+            .layer(
+                tower::ServiceBuilder::new()
+                    .layer(tower_http::request_id::SetRequestIdLayer::x_request_id(
+                        tower_http::request_id::MakeRequestUuid,
+                    ))
+                    .layer(tower_http::trace::TraceLayer::new_for_http()),
+            )
+            .with_state(self.state.clone());
+        for module in self.state.modules.iter() {
+            app = app.merge(module.router(&self.shared_state)?)
+        }
+        if let Some(store) = self.shared_state.admin.as_ref() {
+            app = app.merge(crate::admin_router(store.clone()));
+        }
+        let max_body_bytes = self.shared_state.config.limits.max_http_body_bytes;
+        let mut app = app
+            .layer(axum::middleware::map_response_with_state(
+                max_body_bytes,
+                limits::payload_too_large_json,
+            ))
+            .layer(axum::extract::DefaultBodyLimit::max(max_body_bytes));
+        if let Some(compression) = self.shared_state.config.compression.layer() {
+            app = app.layer(compression);
+        }
+        if let Some(log) = self.access_log.as_ref() {
+            app = app
+                .layer(axum::middleware::from_fn_with_state(log.clone(), access_log::log_request));
+        }
+        Ok(app)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn build_and_serve_without_modules() -> Result<()> {
+        let config = "static_dir = \"\"\nlog_dir = \"/tmp\"\ninstance_name = \"test\"\n";
+        let config: Config = toml::from_str(config)?;
+        let server = ServerBuilder::new(config).cpu(true).static_files(false).build().await?;
+        assert!(server.config().modules.is_empty());
+        let app = axum::Router::new().nest("/speech", server.router()?);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let app = app.into_make_service_with_connect_info::<std::net::SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        for path in ["/speech/api/health", "/speech/api/status"] {
+            let resp = reqwest::get(format!("http://{addr}{path}")).await?;
+            assert_eq!(resp.status(), reqwest::StatusCode::OK, "{path}");
+        }
+        let resp = reqwest::get(format!("http://{addr}/speech/api/asr-streaming")).await?;
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
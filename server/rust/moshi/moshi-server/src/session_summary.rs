@@ -0,0 +1,206 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Summary of a batched asr session, written as JSON when the session ends, or at each segment
+//! boundary when the path holds `{segment}`. It holds per second of audio the probability of a
+//! pause from the voice activity head, the number of words and the audio level, so that the
+//! quality of calls can be analysed across sessions without keeping their audio or transcript.
+
+use crate::audio_stats::{to_db, CLIP_LEVEL};
+use crate::schema::Artifact;
+
+const SAMPLE_RATE: usize = 24000;
+const FRAME_SIZE: usize = 1920;
+
+/// Accumulators of one second of audio.
+#[derive(Debug, Default, Clone)]
+struct Second {
+    samples: usize,
+    sum_sq: f64,
+    peak: f32,
+    clipped: u32,
+    pause_sum: f64,
+    pauses: u32,
+    words: u32,
+}
+
+impl Second {
+    fn pause_prob(&self) -> Option<f64> {
+        (self.pauses > 0).then(|| round(self.pause_sum / self.pauses as f64))
+    }
+
+    fn rms_db(&self) -> f64 {
+        round(to_db(self.sum_sq / self.samples.max(1) as f64) as f64)
+    }
+}
+
+fn round(v: f64) -> f64 {
+    (v * 1000.0).round() / 1000.0
+}
+
+/// Path of the summary of a session, from a template with `{user}` and `{session}`.
+pub fn path(template: &str, user: Option<&str>, session: &str) -> String {
+    template.replace("{user}", user.unwrap_or("anonymous")).replace("{session}", session)
+}
+
+#[derive(Debug)]
+pub struct SessionSummary {
+    /// Path of the artifact, `{segment}` is replaced by the index of the segment.
+    path: String,
+    vad_head: usize,
+    /// Session time of the first second, and the audio received since then.
+    origin_s: f64,
+    samples: usize,
+    seconds: Vec<Second>,
+    segment: usize,
+}
+
+impl SessionSummary {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            vad_head: crate::vad::default_vad_head(),
+            origin_s: 0.0,
+            samples: 0,
+            seconds: vec![],
+            segment: 0,
+        }
+    }
+
+    /// Continue from session time `start_s`, for restored sessions.
+    pub fn restart_at(&mut self, start_s: f64) {
+        self.origin_s = start_s;
+        self.samples = 0;
+        self.seconds.clear();
+    }
+
+    fn second(&mut self, idx: usize) -> &mut Second {
+        if self.seconds.len() <= idx {
+            self.seconds.resize(idx + 1, Second::default());
+        }
+        &mut self.seconds[idx]
+    }
+
+    pub fn push(&mut self, pcm: &[f32]) {
+        let mut pcm = pcm;
+        while !pcm.is_empty() {
+            let len = pcm.len().min(SAMPLE_RATE - self.samples % SAMPLE_RATE);
+            let (chunk, rest) = pcm.split_at(len);
+            let second = self.second(self.samples / SAMPLE_RATE);
+            for &v in chunk {
+                second.sum_sq += v as f64 * v as f64;
+                second.peak = second.peak.max(v.abs());
+                second.clipped += (v.abs() >= CLIP_LEVEL) as u32;
+            }
+            second.samples += len;
+            self.samples += len;
+            pcm = rest;
+        }
+    }
+
+    /// Account for a model step, `buffered_pcm` is the audio received but not fed yet.
+    pub fn step(&mut self, prs: &[f32], buffered_pcm: usize) {
+        let Some(&pr) = prs.get(self.vad_head) else { return };
+        let fed = self.samples.saturating_sub(buffered_pcm + FRAME_SIZE);
+        let second = self.second(fed / SAMPLE_RATE);
+        second.pause_sum += pr as f64;
+        second.pauses += 1;
+    }
+
+    pub fn word(&mut self, start_s: f64) {
+        let idx = (start_s - self.origin_s).max(0.0) as usize;
+        self.second(idx).words += 1;
+    }
+
+    /// A new segment starts at session time `start_s`, the seconds before it are written when
+    /// the summary is split by segment.
+    pub fn segment(&mut self, start_s: f64) {
+        if !self.path.contains("{segment}") {
+            return;
+        }
+        let n = ((start_s - self.origin_s).max(0.0) as usize).min(self.seconds.len());
+        let seconds: Vec<_> = self.seconds.drain(..n).collect();
+        self.write(&seconds);
+        self.origin_s += n as f64;
+        self.samples = self.samples.saturating_sub(n * SAMPLE_RATE);
+        self.segment += 1;
+    }
+
+    /// Write the seconds not written yet, when the session ends.
+    pub fn save(&mut self) {
+        let seconds = std::mem::take(&mut self.seconds);
+        self.write(&seconds);
+    }
+
+    fn json(&self, seconds: &[Second]) -> serde_json::Value {
+        let samples: usize = seconds.iter().map(|s| s.samples).sum();
+        let sum_sq: f64 = seconds.iter().map(|s| s.sum_sq).sum();
+        let segment = self.path.contains("{segment}").then_some(self.segment);
+        serde_json::json!({
+            "schema_version": Artifact::SessionSummary.version(),
+            "segment": segment,
+            "start_s": self.origin_s,
+            "duration_s": samples as f64 / SAMPLE_RATE as f64,
+            "words": seconds.iter().map(|s| s.words).sum::<u32>(),
+            "rms_db": round(to_db(sum_sq / samples.max(1) as f64) as f64),
+            "peak": round(seconds.iter().map(|s| s.peak).fold(0.0, f32::max) as f64),
+            "clipped": seconds.iter().map(|s| s.clipped).sum::<u32>(),
+            "per_second": {
+                "pause_prob": seconds.iter().map(Second::pause_prob).collect::<Vec<_>>(),
+                "words": seconds.iter().map(|s| s.words).collect::<Vec<_>>(),
+                "rms_db": seconds.iter().map(Second::rms_db).collect::<Vec<_>>(),
+                "peak": seconds.iter().map(|s| round(s.peak as f64)).collect::<Vec<_>>(),
+                "clipped": seconds.iter().map(|s| s.clipped).collect::<Vec<_>>(),
+            },
+        })
+    }
+
+    /// Write a summary of `seconds`, off the calling thread.
+    fn write(&self, seconds: &[Second]) {
+        if seconds.is_empty() {
+            return;
+        }
+        let json = self.json(seconds);
+        let path = self.path.replace("{segment}", &format!("{:05}", self.segment));
+        std::thread::spawn(move || {
+            let path = std::path::Path::new(&path);
+            if let Some(dir) = path.parent() {
+                let _ = std::fs::create_dir_all(dir);
+            }
+            if let Err(err) = std::fs::write(path, json.to_string()) {
+                tracing::error!(?err, ?path, "cannot write asr session summary")
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_second_bins() {
+        let mut summary = SessionSummary::new("unused-{segment}.json".to_string());
+        summary.push(&vec![0.5; SAMPLE_RATE + SAMPLE_RATE / 2]);
+        summary.push(&vec![1.0; SAMPLE_RATE]);
+        // Steps of the first and of the third second, most of the audio being buffered at the
+        // first one.
+        summary.step(&[0.0, 0.0, 0.2], 2 * SAMPLE_RATE);
+        summary.step(&[0.0, 0.0, 0.4], 0);
+        summary.word(0.3);
+        summary.word(2.1);
+        summary.word(2.2);
+
+        let json = summary.json(&summary.seconds);
+        assert_eq!(json["duration_s"], 2.5);
+        assert_eq!(json["words"], 3);
+        assert_eq!(json["segment"], 0);
+        let per_second = &json["per_second"];
+        assert_eq!(per_second["pause_prob"], serde_json::json!([0.2, null, 0.4]));
+        assert_eq!(per_second["words"], serde_json::json!([1, 0, 2]));
+        assert_eq!(per_second["rms_db"][0], -6.021);
+        assert_eq!(per_second["clipped"], serde_json::json!([0, 12000, 12000]));
+        assert_eq!(path("a/{user}/{session}.json", None, "s-1"), "a/anonymous/s-1.json");
+    }
+}
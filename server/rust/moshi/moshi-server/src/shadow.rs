@@ -0,0 +1,306 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Shadow model of an asr module, to evaluate a new checkpoint on real traffic before switching
+//! to it. A sampled share of the websocket sessions also feed their audio to the shadow model,
+//! whose words are never sent to the client: once the session ends they are compared with the
+//! words sent by the production model, the difference is logged and exported as the
+//! `asr_shadow_*` metrics.
+//!
+//! The shadow words go through the inverse text normalization when the session asked for it,
+//! as the production ones do, so that numbers written with digits are not counted as errors.
+//!
+//! The shadow does not slow the session down. When it falls behind, the audio it misses is
+//! dropped and the session is left out of the comparison.
+
+use crate::asr::OutMsg;
+use anyhow::Result;
+use candle::{Device, Tensor};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShadowConfig {
+    pub lm_model_file: String,
+    /// The tokenizers, model config and delay default to the ones of the production model.
+    #[serde(default)]
+    pub text_tokenizer_file: Option<String>,
+    #[serde(default)]
+    pub audio_tokenizer_file: Option<String>,
+    #[serde(default)]
+    pub model: Option<moshi::lm::Config>,
+    #[serde(default)]
+    pub asr_delay_in_tokens: Option<usize>,
+    /// Percentage of the websocket sessions that are shadowed.
+    #[serde(default = "default_sample_percent")]
+    pub sample_percent: f64,
+    /// Sessions shadowed at once, the sampled sessions beyond are not.
+    #[serde(default = "default_max_sessions")]
+    pub max_sessions: usize,
+}
+
+fn default_sample_percent() -> f64 {
+    5.0
+}
+
+fn default_max_sessions() -> usize {
+    4
+}
+
+/// Chunks of audio queued for a shadow session before it counts as behind.
+const QUEUE_LEN: usize = 100;
+
+#[derive(Debug)]
+pub struct Shadow {
+    asr: crate::asr::Asr,
+    model_id: String,
+    sample_percent: f64,
+    max_sessions: usize,
+    active: AtomicUsize,
+}
+
+impl Shadow {
+    /// The shadow of the module, if its config has a `shadow` section.
+    pub fn new(
+        asr: &crate::AsrConfig,
+        config: &crate::Config,
+        dev: &Device,
+    ) -> Result<Option<Arc<Self>>> {
+        let Some(shadow) = asr.shadow.as_ref() else { return Ok(None) };
+        let cfg = crate::AsrConfig {
+            lm_model_file: shadow.lm_model_file.clone(),
+            text_tokenizer_file: shadow
+                .text_tokenizer_file
+                .clone()
+                .unwrap_or_else(|| asr.text_tokenizer_file.clone()),
+            audio_tokenizer_file: shadow
+                .audio_tokenizer_file
+                .clone()
+                .unwrap_or_else(|| asr.audio_tokenizer_file.clone()),
+            model: shadow.model.clone().unwrap_or_else(|| asr.model.clone()),
+            asr_delay_in_tokens: shadow.asr_delay_in_tokens.unwrap_or(asr.asr_delay_in_tokens),
+            shadow: None,
+            ..asr.clone()
+        };
+        let model_id = std::path::Path::new(&shadow.lm_model_file)
+            .file_name()
+            .map_or_else(|| shadow.lm_model_file.clone(), |f| f.to_string_lossy().into_owned());
+        tracing::info!(model_id, sample_percent = shadow.sample_percent, "loading asr shadow");
+        let asr = crate::asr::Asr::new(&cfg, config, dev)?;
+        Ok(Some(Arc::new(Self {
+            asr,
+            model_id,
+            sample_percent: shadow.sample_percent,
+            max_sessions: shadow.max_sessions,
+            active: AtomicUsize::new(0),
+        })))
+    }
+
+    /// Shadow a new session if it is sampled and fewer than `max_sessions` are shadowed, `itn`
+    /// tells whether the session writes its numbers with digits.
+    pub fn session(self: &Arc<Self>, itn: bool) -> Option<ShadowSession> {
+        if rand::random::<f64>() * 100.0 >= self.sample_percent {
+            return None;
+        }
+        let max = self.max_sessions;
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < max).then_some(n + 1))
+            .ok()?;
+        let mut state = match self.asr.new_state() {
+            Ok(state) => state,
+            Err(err) => {
+                tracing::warn!(?err, "cannot start asr shadow session");
+                self.active.fetch_sub(1, Ordering::SeqCst);
+                return None;
+            }
+        };
+        let (pcm_tx, pcm_rx) = std::sync::mpsc::sync_channel::<Vec<f32>>(QUEUE_LEN);
+        let (word_tx, word_rx) = std::sync::mpsc::channel::<String>();
+        let lost = Arc::new(AtomicBool::new(false));
+        let (shadow, shadow_lost) = (self.clone(), lost.clone());
+        crate::utils::spawn_blocking("asr_shadow_loop", move || {
+            let words = shadow.transcribe(&mut state, pcm_rx, &shadow_lost);
+            shadow.active.fetch_sub(1, Ordering::SeqCst);
+            let words = written(words?, itn);
+            // Ends with the session, once both of its loops dropped their handle.
+            let production = word_rx.iter().collect::<Vec<_>>();
+            if shadow_lost.load(Ordering::Relaxed) {
+                tracing::info!(model_id = shadow.model_id, "asr shadow fell behind, not compared");
+                crate::metrics::asr::SHADOW_LOST.inc();
+                return Ok(());
+            }
+            let diff = Diff::new(&production, &words);
+            crate::metrics::asr::SHADOW_SESSIONS.inc();
+            crate::metrics::asr::SHADOW_WER.observe(diff.wer());
+            tracing::info!(
+                model_id = shadow.model_id,
+                words = diff.words,
+                shadow_words = words.len(),
+                edits = diff.edits,
+                wer = diff.wer(),
+                production = %crate::privacy::Sensitive::new(production.join(" ")),
+                shadow = %crate::privacy::Sensitive::new(words.join(" ")),
+                "asr shadow diff"
+            );
+            Ok(())
+        });
+        Some(ShadowSession { pcm: pcm_tx, words: word_tx, lost })
+    }
+
+    fn transcribe(
+        &self,
+        state: &mut moshi::asr::State,
+        pcm_rx: std::sync::mpsc::Receiver<Vec<f32>>,
+        lost: &AtomicBool,
+    ) -> Result<Vec<String>> {
+        let dev = state.device().clone();
+        let conditions = self.asr.conditions();
+        let mut words = vec![];
+        for pcm in pcm_rx {
+            if lost.load(Ordering::Relaxed) {
+                break;
+            }
+            let pcm_len = pcm.len();
+            let pcm = Tensor::from_vec(pcm, (1, 1, pcm_len), &dev)?;
+            let msgs = self
+                .asr
+                .steps()
+                .install(|| state.step_pcm(pcm, conditions, &().into(), |_, _, _| ()))?;
+            for msg in msgs {
+                if let moshi::asr::AsrMsg::Word { tokens, .. } = msg {
+                    words.push(self.asr.text_tokenizer().decode_piece_ids(&tokens)?);
+                }
+            }
+        }
+        Ok(words)
+    }
+}
+
+/// The handle of a shadowed session, shared by its recv and send loops. The comparison runs
+/// once all the clones are dropped.
+#[derive(Clone)]
+pub struct ShadowSession {
+    pcm: std::sync::mpsc::SyncSender<Vec<f32>>,
+    words: std::sync::mpsc::Sender<String>,
+    lost: Arc<AtomicBool>,
+}
+
+impl ShadowSession {
+    /// Audio of the session at 24kHz, as given to the production model.
+    pub fn pcm(&self, pcm: &[f32]) {
+        if !self.lost.load(Ordering::Relaxed) && self.pcm.try_send(pcm.to_vec()).is_err() {
+            self.lose()
+        }
+    }
+
+    /// A word sent to the client by the production model.
+    pub fn word(&self, text: &str) {
+        let _ = self.words.send(text.to_string());
+    }
+
+    /// The production model got input that the shadow cannot, e.g. mimi codes.
+    pub fn lose(&self) {
+        self.lost.store(true, Ordering::Relaxed)
+    }
+}
+
+/// Word level edit distance from the production transcript to the shadow one, the words being
+/// compared lowercased and without punctuation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Diff {
+    /// Words of the production transcript.
+    pub words: usize,
+    /// Substitutions, insertions and deletions.
+    pub edits: usize,
+}
+
+impl Diff {
+    pub fn new(production: &[String], shadow: &[String]) -> Self {
+        let (production, shadow) = (normalize(production), normalize(shadow));
+        // Distances from the first production words to the first `j` shadow words.
+        let mut row = (0..=shadow.len()).collect::<Vec<_>>();
+        for (i, p) in production.iter().enumerate() {
+            let mut diag = row[0];
+            row[0] = i + 1;
+            for (j, s) in shadow.iter().enumerate() {
+                let sub = diag + usize::from(p != s);
+                diag = row[j + 1];
+                row[j + 1] = sub.min(row[j] + 1).min(diag + 1);
+            }
+        }
+        Self { words: production.len(), edits: row[shadow.len()] }
+    }
+
+    /// Word error rate of the shadow with the production transcript as the reference.
+    pub fn wer(&self) -> f64 {
+        self.edits as f64 / self.words.max(1) as f64
+    }
+}
+
+/// The `words` of the shadow as the production model sends them, with their numbers written
+/// with digits when `itn` is set.
+fn written(words: Vec<String>, itn: bool) -> Vec<String> {
+    if !itn {
+        return words;
+    }
+    let mut itn = crate::itn::Itn::default();
+    let mut msgs = vec![];
+    for text in words {
+        let word = OutMsg::Word { text, start_time: 0., lang: None, tokens: None };
+        msgs.extend(itn.push(word));
+    }
+    msgs.extend(itn.flush());
+    msgs.into_iter()
+        .filter_map(|msg| match msg {
+            OutMsg::Word { text, .. } => Some(text),
+            _ => None,
+        })
+        .collect()
+}
+
+fn normalize(words: &[String]) -> Vec<String> {
+    words
+        .iter()
+        .flat_map(|w| w.split_whitespace())
+        .map(|w| {
+            w.chars()
+                .filter(|c| c.is_alphanumeric() || *c == '\'')
+                .flat_map(char::to_lowercase)
+                .collect::<String>()
+        })
+        .filter(|w| !w.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Vec<String> {
+        text.split(' ').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn diff_counts_word_edits() {
+        let production = words("Hello, world. The cat sat on the mat.");
+        let diff = Diff::new(&production, &words("hello world the cat sat on the mat"));
+        assert_eq!(diff, Diff { words: 8, edits: 0 });
+        // One substitution, one deletion and one insertion.
+        let diff = Diff::new(&production, &words("hello word the cat on the mat today"));
+        assert_eq!(diff, Diff { words: 8, edits: 3 });
+        assert_eq!(diff.wer(), 3.0 / 8.0);
+        assert_eq!(Diff::new(&[], &words("noise")), Diff { words: 0, edits: 1 });
+        assert_eq!(Diff::new(&words("hi there"), &[]).wer(), 1.0);
+    }
+
+    #[test]
+    fn shadow_words_follow_the_itn_of_the_session() {
+        let production = words("It costs $23 for two people.");
+        let shadow = words("it costs twenty three dollars for two people");
+        assert_eq!(Diff::new(&production, &written(shadow.clone(), false)).edits, 3);
+        let diff = Diff::new(&production, &written(shadow, true));
+        assert_eq!(diff, Diff { words: 6, edits: 0 });
+    }
+}
@@ -0,0 +1,332 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Weight snapshots for `--fast-restart`.
+//!
+//! The first load of a checkpoint writes its weights, already converted to the dtype of the
+//! module, to a safetensors file in the snapshot directory. The next loads, after a restart or
+//! in a second worker on the same machine, map this file instead: no conversion, and when the
+//! directory is in `/dev/shm` the pages are shared by the workers and stay in memory across
+//! restarts. The snapshot is written one tensor at a time, so converting a checkpoint never
+//! holds more than one of its tensors in memory.
+//!
+//! Device memory does not outlive a process, so each worker still copies the weights to its
+//! GPU, but only once: the modules of a worker mapping the same weights on the same device
+//! share the uploaded tensors.
+//!
+//! The snapshots are keyed by the path, size and modification time of the checkpoint and by
+//! the dtype, a new checkpoint gets a new snapshot. Before a snapshot is written, the least
+//! recently mapped ones are removed to keep the directory under `--snapshot-max-gb`.
+
+use anyhow::{Context, Result};
+use candle::{DType, Device, DeviceLocation, Shape, Tensor};
+use candle_nn::var_builder::SimpleBackend;
+use candle_nn::VarBuilder;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+
+/// Snapshot settings of a worker started with `--fast-restart`.
+#[derive(Debug, Clone)]
+pub struct Snapshots {
+    pub dir: PathBuf,
+    /// Total size of the snapshots in `dir`, older ones are evicted beyond it.
+    pub max_bytes: u64,
+}
+
+/// Where the snapshots go when `--snapshot-dir` is not set: shared memory when available,
+/// the user cache otherwise.
+pub fn default_dir() -> PathBuf {
+    let shm = Path::new("/dev/shm");
+    if shm.is_dir() {
+        return shm.join("moshi-server-snapshots");
+    }
+    dirs::cache_dir().unwrap_or_else(std::env::temp_dir).join("moshi-server").join("snapshots")
+}
+
+/// Snapshot file of `file` converted to `dtype`, the name changes with the checkpoint.
+fn snapshot_path(dir: &Path, file: &Path, dtype: DType) -> Result<PathBuf> {
+    let file = file.canonicalize().with_context(|| file.display().to_string())?;
+    let meta = std::fs::metadata(&file)?;
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    file.hash(&mut hasher);
+    meta.len().hash(&mut hasher);
+    meta.modified().ok().hash(&mut hasher);
+    let stem = file.file_stem().and_then(|s| s.to_str()).unwrap_or("model");
+    Ok(dir.join(format!("{stem}.{}.{:016x}.safetensors", dtype.as_str(), hasher.finish())))
+}
+
+/// Converts the float tensors of `file` to `dtype` and writes them to `dst`, one tensor at a
+/// time. The file is renamed in place once complete so that a crash or a concurrent worker
+/// never maps a partial snapshot.
+fn write_snapshot(file: &Path, dst: &Path, dtype: DType) -> Result<()> {
+    let st = unsafe { candle::safetensors::MmapedSafetensors::new(file)? };
+    let mut names: Vec<_> = st.tensors().into_iter().map(|(name, _)| name).collect();
+    names.sort();
+    // The header lists the converted dtype and the offsets of each tensor, it is written
+    // before the data so it only uses the metadata of the checkpoint.
+    let mut header = serde_json::Map::new();
+    let mut offset = 0;
+    for name in names.iter() {
+        let view = st.get(name)?;
+        let src_dtype = DType::try_from(view.dtype())?;
+        let dtype = if src_dtype.is_float() { dtype } else { src_dtype };
+        let len = view.shape().iter().product::<usize>() * dtype.size_in_bytes();
+        // The debug names of the candle dtypes are the safetensors ones, e.g. `BF16`.
+        let info = serde_json::json!({
+            "dtype": format!("{dtype:?}"),
+            "shape": view.shape(),
+            "data_offsets": [offset, offset + len],
+        });
+        header.insert(name.clone(), info);
+        offset += len;
+    }
+    let mut header = serde_json::to_vec(&header)?;
+    header.resize(header.len().next_multiple_of(8), b' ');
+
+    let tmp = dst.with_extension(format!("tmp{}", std::process::id()));
+    let write = || -> Result<()> {
+        let mut w = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+        w.write_all(&(header.len() as u64).to_le_bytes())?;
+        w.write_all(&header)?;
+        for name in names.iter() {
+            let tensor = st.load(name, &Device::Cpu)?;
+            let tensor = if tensor.dtype().is_float() { tensor.to_dtype(dtype)? } else { tensor };
+            tensor.write_bytes(&mut w)?;
+        }
+        w.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        Ok(())
+    };
+    if let Err(err) = write() {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(err);
+    }
+    std::fs::rename(&tmp, dst)?;
+    Ok(())
+}
+
+/// Maps the weights of `file` in `dtype`, through a snapshot when `snapshots` is set.
+///
+/// Snapshot errors are logged and the checkpoint is loaded directly, a full or read-only
+/// snapshot directory should not keep the server from starting.
+pub fn var_builder(
+    file: &str,
+    dtype: DType,
+    dev: &Device,
+    snapshots: Option<&Snapshots>,
+) -> Result<VarBuilder<'static>> {
+    let path = Path::new(file);
+    let snapshot = match snapshots {
+        None => None,
+        Some(snapshots) => match snapshot(snapshots, path, dtype) {
+            Ok(snapshot) => Some(snapshot),
+            Err(err) => {
+                tracing::warn!(?err, file, "cannot snapshot weights, loading the checkpoint");
+                None
+            }
+        },
+    };
+    let path = snapshot.as_deref().unwrap_or(path);
+    let backend = Uploaded::for_file(path)?;
+    Ok(VarBuilder::from_backend(Box::new(backend), dtype, dev.clone()))
+}
+
+fn snapshot(snapshots: &Snapshots, file: &Path, dtype: DType) -> Result<PathBuf> {
+    let dir = &snapshots.dir;
+    let dst = snapshot_path(dir, file, dtype)?;
+    if dst.exists() {
+        tracing::info!(snapshot = %dst.display(), "mapping weight snapshot");
+        // The modification time orders the snapshots for the eviction.
+        let _ = std::fs::File::options()
+            .write(true)
+            .open(&dst)
+            .and_then(|f| f.set_modified(std::time::SystemTime::now()));
+        return Ok(dst);
+    }
+    std::fs::create_dir_all(dir).with_context(|| dir.display().to_string())?;
+    let size = snapshot_size(file, dtype)?;
+    if size > snapshots.max_bytes {
+        anyhow::bail!("snapshot of {size} bytes over --snapshot-max-gb")
+    }
+    evict(dir, snapshots.max_bytes - size)?;
+    let start = std::time::Instant::now();
+    write_snapshot(file, &dst, dtype)?;
+    tracing::info!(
+        snapshot = %dst.display(),
+        elapsed_s = start.elapsed().as_secs_f64(),
+        "wrote weight snapshot"
+    );
+    Ok(dst)
+}
+
+/// Size of the data of the snapshot of `file` in `dtype`.
+fn snapshot_size(file: &Path, dtype: DType) -> Result<u64> {
+    let st = unsafe { candle::safetensors::MmapedSafetensors::new(file)? };
+    let mut size = 0;
+    for (_, view) in st.tensors() {
+        let src_dtype = DType::try_from(view.dtype())?;
+        let dtype = if src_dtype.is_float() { dtype } else { src_dtype };
+        size += (view.shape().iter().product::<usize>() * dtype.size_in_bytes()) as u64;
+    }
+    Ok(size)
+}
+
+/// Remove the least recently mapped snapshots of `dir` until they take at most `max_bytes`.
+/// Workers mapping a removed snapshot keep their pages until they exit.
+fn evict(dir: &Path, max_bytes: u64) -> Result<()> {
+    let mut snapshots = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "safetensors") {
+            continue;
+        }
+        let meta = entry.metadata()?;
+        snapshots.push((meta.modified()?, meta.len(), path));
+    }
+    snapshots.sort();
+    let mut total: u64 = snapshots.iter().map(|(_, len, _)| len).sum();
+    for (_, len, path) in snapshots {
+        if total <= max_bytes {
+            break;
+        }
+        tracing::info!(snapshot = %path.display(), len, "evicting weight snapshot");
+        std::fs::remove_file(&path)?;
+        total -= len;
+    }
+    Ok(())
+}
+
+/// Weights of a safetensors file uploaded to the devices, shared by the var builders of the
+/// process mapping this file.
+#[derive(Clone)]
+struct Uploaded(Arc<UploadedFile>);
+
+struct UploadedFile {
+    st: candle::safetensors::MmapedSafetensors,
+    tensors: Mutex<HashMap<(String, DType, DeviceLocation), Tensor>>,
+}
+
+static UPLOADED: LazyLock<Mutex<HashMap<PathBuf, Uploaded>>> = LazyLock::new(Default::default);
+
+impl Uploaded {
+    fn for_file(path: &Path) -> Result<Self> {
+        let key = path.canonicalize().with_context(|| path.display().to_string())?;
+        let mut uploaded = UPLOADED.lock().unwrap();
+        if let Some(u) = uploaded.get(&key) {
+            return Ok(u.clone());
+        }
+        let st = unsafe { candle::safetensors::MmapedSafetensors::new(&key)? };
+        let u = Self(Arc::new(UploadedFile { st, tensors: Mutex::new(HashMap::new()) }));
+        uploaded.insert(key, u.clone());
+        Ok(u)
+    }
+}
+
+impl SimpleBackend for Uploaded {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        _: candle_nn::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> candle::Result<Tensor> {
+        let key = (name.to_string(), dtype, dev.location());
+        let cached = self.0.tensors.lock().unwrap().get(&key).cloned();
+        let tensor = match cached {
+            Some(tensor) => tensor,
+            None => {
+                let tensor = self.0.st.load(name, dev)?.to_dtype(dtype)?;
+                self.0.tensors.lock().unwrap().insert(key, tensor.clone());
+                tensor
+            }
+        };
+        if tensor.shape() != &s {
+            Err(candle::Error::UnexpectedShape {
+                msg: format!("shape mismatch for {name}"),
+                expected: s,
+                got: tensor.shape().clone(),
+            }
+            .bt())?
+        }
+        Ok(tensor)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.0.st.get(name).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_roundtrip() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("moshi-snapshot-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let ckpt = dir.join("ckpt.safetensors");
+        let mut tensors = std::collections::HashMap::new();
+        tensors.insert("w", Tensor::new(&[1.5f32, -2.25, 3.0], &Device::Cpu)?);
+        tensors.insert("idx", Tensor::new(&[7u32, 8], &Device::Cpu)?);
+        candle::safetensors::save(&tensors, &ckpt)?;
+        let snapshots = Snapshots { dir: dir.join("snapshots"), max_bytes: 1 << 20 };
+
+        let file = ckpt.to_str().unwrap();
+        let vb = var_builder(file, DType::BF16, &Device::Cpu, Some(&snapshots))?;
+        let w = vb.get(3, "w")?;
+        assert_eq!(w.dtype(), DType::BF16);
+        let path = snapshot_path(&snapshots.dir, &ckpt, DType::BF16)?;
+        let st = unsafe { candle::safetensors::MmapedSafetensors::new(&path)? };
+        assert_eq!(st.load("w", &Device::Cpu)?.dtype(), DType::BF16);
+        assert_eq!(st.load("idx", &Device::Cpu)?.dtype(), DType::U32);
+        assert_eq!(snapshot_size(&ckpt, DType::BF16)?, 3 * 2 + 2 * 4);
+        // Mapped from the snapshot on the second load, the weights are only uploaded once.
+        let vb = var_builder(file, DType::BF16, &Device::Cpu, Some(&snapshots))?;
+        let w2 = vb.get(3, "w")?;
+        assert_eq!(w2.id(), w.id());
+        assert_eq!(w2.to_dtype(DType::F32)?.to_vec1::<f32>()?, [1.5, -2.25, 3.0]);
+        assert_eq!(std::fs::read_dir(&snapshots.dir)?.count(), 1);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn least_recently_used_snapshots_are_evicted() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("moshi-snapshot-evict-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let snapshots = Snapshots { dir: dir.join("snapshots"), max_bytes: 2 * 4 * 1024 + 512 };
+        let mut ckpts = vec![];
+        for i in 0..3 {
+            let ckpt = dir.join(format!("ckpt{i}.safetensors"));
+            let w = Tensor::full(i as f32, 1024, &Device::Cpu)?;
+            candle::safetensors::save(&HashMap::from([("w", w)]), &ckpt)?;
+            ckpts.push(ckpt);
+        }
+        let load = |i: usize| {
+            let file = ckpts[i].to_str().unwrap();
+            var_builder(file, DType::F32, &Device::Cpu, Some(&snapshots))
+        };
+        let exists =
+            |i: usize| snapshot_path(&snapshots.dir, &ckpts[i], DType::F32).unwrap().exists();
+        load(0)?;
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        load(1)?;
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        // Mapping the first snapshot again makes the second one the least recently used.
+        load(0)?;
+        load(2)?;
+        assert!(exists(0) && !exists(1) && exists(2));
+        // A snapshot larger than the budget is skipped, the checkpoint is loaded directly.
+        let small = Snapshots { max_bytes: 1024, ..snapshots.clone() };
+        let vb = var_builder(ckpts[1].to_str().unwrap(), DType::F32, &Device::Cpu, Some(&small))?;
+        assert_eq!(vb.get(1024, "w")?.to_vec1::<f32>()?[0], 1.0);
+        assert!(exists(0) && !exists(1) && exists(2));
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Detection of the format of the audio posted for transcription. When a file cannot be
+//! decoded, the container and codec found in it tell whether it is a format the server does
+//! not support, e.g. the WebM/Opus recordings of browsers, answered with 415 and the supported
+//! formats, or a supported one that is corrupted, answered with 400.
+
+/// Containers the decoder reads, as reported in `detected.container`.
+pub const CONTAINERS: &[&str] =
+    &["wav", "aiff", "mp3", "aac", "flac", "ogg", "mp4", "webm", "mkv", "caf"];
+
+/// Codecs the decoder reads, as reported in `detected.codec`.
+pub const CODECS: &[&str] = &["pcm", "adpcm", "mp3", "aac", "alac", "flac", "vorbis"];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct Detected {
+    /// `None` when the content is not recognized, e.g. raw samples.
+    pub container: Option<&'static str>,
+    pub codec: Option<&'static str>,
+}
+
+impl Detected {
+    /// Whether the decoder reads this format, unknown codecs of known containers included.
+    pub fn is_supported(&self) -> bool {
+        self.container.is_some_and(|c| CONTAINERS.contains(&c))
+            && self.codec.is_none_or(|c| CODECS.contains(&c))
+    }
+}
+
+impl std::fmt::Display for Detected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.container, self.codec) {
+            (None, _) => write!(f, "unrecognized content"),
+            (Some(container), None) => write!(f, "{container}"),
+            (Some(container), Some(codec)) => write!(f, "{container} with {codec}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Supported {
+    pub containers: &'static [&'static str],
+    pub codecs: &'static [&'static str],
+}
+
+pub const SUPPORTED: Supported = Supported { containers: CONTAINERS, codecs: CODECS };
+
+fn contains(bytes: &[u8], needle: &[u8], within: usize) -> bool {
+    bytes[..bytes.len().min(within)].windows(needle.len()).any(|w| w == needle)
+}
+
+/// Container from the magic bytes at the start of the file.
+fn container(bytes: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| bytes.get(offset..offset + magic.len()) == Some(magic);
+    let container = match bytes {
+        _ if at(0, b"RIFF") && at(8, b"WAVE") => "wav",
+        _ if at(0, b"RF64") => "wav",
+        _ if at(0, b"FORM") && (at(8, b"AIFF") || at(8, b"AIFC")) => "aiff",
+        _ if at(0, b"fLaC") => "flac",
+        _ if at(0, b"OggS") => "ogg",
+        _ if at(0, b"ID3") => "mp3",
+        _ if at(4, b"ftyp") => "mp4",
+        _ if at(0, b"caff") => "caf",
+        _ if at(0, b"\x1a\x45\xdf\xa3") => {
+            if contains(bytes, b"webm", 64) {
+                "webm"
+            } else {
+                "mkv"
+            }
+        }
+        _ if at(0, b"#!AMR") => "amr",
+        _ if at(0, b".snd") => "au",
+        _ if at(0, b"\x30\x26\xb2\x75\x8e\x66\xcf\x11") => "asf",
+        _ if at(0, b"%PDF") => "pdf",
+        _ if at(0, b"\x89PNG") => "png",
+        _ if at(0, b"\xff\xd8\xff") => "jpeg",
+        // Frame sync, the layer bits are zero for ADTS.
+        [0xff, b, ..] if b & 0xf0 == 0xf0 && b & 0x06 == 0 => "aac",
+        [0xff, b, ..] if b & 0xe0 == 0xe0 => "mp3",
+        [b'{' | b'[', ..] => "json",
+        [b'<', ..] => "html",
+        _ => return None,
+    };
+    Some(container)
+}
+
+/// Codec of the first audio track, read by symphonia when it opens the container.
+fn probe_codec(bytes: &[u8]) -> Option<&'static str> {
+    use symphonia::core::codecs;
+
+    let source = std::io::Cursor::new(bytes.to_vec());
+    let mss = symphonia::core::io::MediaSourceStream::new(Box::new(source), Default::default());
+    let hint = symphonia::core::probe::Hint::new();
+    let probed = symphonia::default::get_probe()
+        .format(&hint, mss, &Default::default(), &Default::default())
+        .ok()?;
+    let track =
+        probed.format.tracks().iter().find(|t| t.codec_params.codec != codecs::CODEC_TYPE_NULL)?;
+    let codec = track.codec_params.codec;
+    let codec = match codec {
+        codecs::CODEC_TYPE_OPUS => "opus",
+        codecs::CODEC_TYPE_MP3 => "mp3",
+        codecs::CODEC_TYPE_AAC => "aac",
+        codecs::CODEC_TYPE_ALAC => "alac",
+        codecs::CODEC_TYPE_FLAC => "flac",
+        codecs::CODEC_TYPE_VORBIS => "vorbis",
+        codecs::CODEC_TYPE_ADPCM_MS | codecs::CODEC_TYPE_ADPCM_IMA_WAV => "adpcm",
+        _ => match symphonia::default::get_codecs().get_codec(codec) {
+            Some(desc) if desc.short_name.starts_with("pcm") => "pcm",
+            _ => return None,
+        },
+    };
+    Some(codec)
+}
+
+/// Codec from the markers of the containers that symphonia cannot open.
+fn marker_codec(bytes: &[u8], container: Option<&str>) -> Option<&'static str> {
+    let markers: &[(&[u8], &'static str)] = match container? {
+        "ogg" => &[
+            (b"OpusHead", "opus"),
+            (b"\x01vorbis", "vorbis"),
+            (b"\x7fFLAC", "flac"),
+            (b"Speex", "speex"),
+        ],
+        "webm" | "mkv" => &[
+            (b"A_OPUS", "opus"),
+            (b"A_VORBIS", "vorbis"),
+            (b"A_AAC", "aac"),
+            (b"A_MPEG/L3", "mp3"),
+            (b"A_FLAC", "flac"),
+            (b"A_PCM", "pcm"),
+        ],
+        "mp4" => &[
+            (b"mp4a", "aac"),
+            (b"alac", "alac"),
+            (b"Opus", "opus"),
+            (b"fLaC", "flac"),
+            (b"ac-3", "ac3"),
+            (b"ec-3", "eac3"),
+            (b"samr", "amr"),
+        ],
+        "amr" => return Some("amr"),
+        _ => return None,
+    };
+    markers.iter().find(|(m, _)| contains(bytes, m, 4096)).map(|(_, codec)| *codec)
+}
+
+pub fn sniff(bytes: &[u8]) -> Detected {
+    let container = container(bytes);
+    let codec = probe_codec(bytes).or_else(|| marker_codec(bytes, container));
+    Detected { container, codec }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_containers_and_codecs() {
+        let mut webm = b"\x1a\x45\xdf\xa3\x9f\x42\x86\x81\x01\x42\x82\x84webm".to_vec();
+        webm.extend_from_slice(b"\x86\x86A_OPUS");
+        let detected = sniff(&webm);
+        assert_eq!(detected, Detected { container: Some("webm"), codec: Some("opus") });
+        assert!(!detected.is_supported());
+        assert_eq!(detected.to_string(), "webm with opus");
+
+        let mut ogg = b"OggS\x00\x02".to_vec();
+        ogg.extend_from_slice(&[0; 22]);
+        ogg.extend_from_slice(b"\x13OpusHead\x01\x01");
+        assert_eq!(sniff(&ogg).codec, Some("opus"));
+
+        let detected = sniff(br#"{"url": "https://example.com/a.wav"}"#);
+        assert_eq!(detected.container, Some("json"));
+        assert!(!detected.is_supported());
+        assert_eq!(sniff(&[0x12, 0x34]).to_string(), "unrecognized content");
+
+        // A truncated wav is a supported format that fails to decode.
+        let detected = sniff(b"RIFF\x24\x00\x00\x00WAVEfmt ");
+        assert_eq!(detected.container, Some("wav"));
+        assert!(detected.is_supported());
+    }
+}
@@ -0,0 +1,385 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Target-speaker transcription.
+//!
+//! A session can enroll the voice it transcribes with a few seconds of audio. The words whose
+//! audio does not match that voice are then replaced by `[other]` or dropped, which keeps the
+//! conversations around a user dictating in a shared office out of their transcript.
+//!
+//! A voice is summarized by the mean and spread of its mel cepstrum over the voiced frames,
+//! and the audio around each word by its mean cepstrum, whose distance to the voice is
+//! measured in standard deviations of the voice. This is much lighter than a neural speaker
+//! model and tells voices apart when they go through the same microphone, but it can confuse
+//! similar voices and does not follow a voice through another channel.
+
+use crate::asr::OutMsg;
+use crate::protocol::OtherSpeech;
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+pub const SAMPLE_RATE: usize = 24000;
+const FFT_SIZE: usize = 512;
+/// Frames of 21ms every 20ms.
+pub const HOP: usize = 480;
+pub const MEL_BANDS: usize = 40;
+/// Cepstral coefficients, c0 is left out as it only depends on the level.
+pub const CEPSTRA: usize = 19;
+const MIN_HZ: f32 = 80.0;
+const MAX_HZ: f32 = 7600.0;
+/// Frames below this level, or that far below the loudest frame, are not voiced.
+const SILENCE_DB: f32 = -55.0;
+const DYNAMIC_DB: f32 = 30.0;
+/// Lower bound of the spread of a cepstral coefficient, for voices enrolled with very
+/// steady sounds.
+pub const MIN_STD: f32 = 0.1;
+/// Enrollment clips hold at least 1s of voiced audio and are at most 10s long.
+const MIN_ENROLL_FRAMES: usize = 50;
+pub const MAX_ENROLL_S: f64 = 10.0;
+/// Audio around the start of a word compared with the enrolled voice.
+const WORD_BEFORE_S: f64 = 1.0;
+const WORD_AFTER_S: f64 = 0.5;
+/// Words with less voiced audio than that around them are attributed to the enrolled voice.
+const MIN_WORD_FRAMES: usize = 10;
+const BUFFER_S: f64 = WORD_BEFORE_S + 10.0;
+
+/// In-place radix-2 FFT, the length is a power of two.
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let step = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (step * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let tr = re[b] * cos - im[b] * sin;
+                let ti = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10f32.powf(mel / 2595.0) - 1.0)
+}
+
+pub struct Cepstrum {
+    window: Vec<f32>,
+    /// Triangular mel filters as (first bin, weights).
+    filters: Vec<(usize, Vec<f32>)>,
+}
+
+impl Cepstrum {
+    pub fn new() -> Self {
+        let window = (0..FFT_SIZE)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / (FFT_SIZE - 1) as f32).cos())
+            .collect();
+        let bin_hz = SAMPLE_RATE as f32 / FFT_SIZE as f32;
+        let (lo, hi) = (hz_to_mel(MIN_HZ), hz_to_mel(MAX_HZ));
+        let edges: Vec<f32> = (0..MEL_BANDS + 2)
+            .map(|i| mel_to_hz(lo + (hi - lo) * i as f32 / (MEL_BANDS + 1) as f32) / bin_hz)
+            .collect();
+        let filters = edges
+            .windows(3)
+            .map(|e| {
+                let first = e[0].ceil() as usize;
+                let weights = (first..=e[2].floor() as usize)
+                    .map(|k| {
+                        let k = k as f32;
+                        if k <= e[1] {
+                            (k - e[0]) / (e[1] - e[0])
+                        } else {
+                            (e[2] - k) / (e[2] - e[1])
+                        }
+                    })
+                    .collect();
+                (first, weights)
+            })
+            .collect();
+        Self { window, filters }
+    }
+
+    /// Cepstra of the voiced frames of `pcm`.
+    pub fn voiced(&self, pcm: &[f32]) -> Vec<[f32; CEPSTRA]> {
+        self.voiced_log_mel(pcm).iter().map(cepstrum).collect()
+    }
+
+    /// Log mel energies of the voiced frames of `pcm`.
+    pub fn voiced_log_mel(&self, pcm: &[f32]) -> Vec<[f32; MEL_BANDS]> {
+        let frames: Vec<&[f32]> = pcm.windows(FFT_SIZE).step_by(HOP).collect();
+        let levels: Vec<f32> = frames
+            .iter()
+            .map(|f| {
+                let mean_sq = f.iter().map(|v| v * v).sum::<f32>() / FFT_SIZE as f32;
+                10.0 * mean_sq.max(1e-12).log10()
+            })
+            .collect();
+        let loudest = levels.iter().copied().fold(f32::MIN, f32::max);
+        let threshold = SILENCE_DB.max(loudest - DYNAMIC_DB);
+        let (mut re, mut im) = (vec![0f32; FFT_SIZE], vec![0f32; FFT_SIZE]);
+        frames
+            .iter()
+            .zip(levels.iter())
+            .filter(|(_, &level)| level >= threshold)
+            .map(|(frame, _)| {
+                for (i, (&v, &w)) in frame.iter().zip(self.window.iter()).enumerate() {
+                    re[i] = v * w;
+                    im[i] = 0.0;
+                }
+                fft(&mut re, &mut im);
+                let mut log_mel = [0f32; MEL_BANDS];
+                for (m, (first, weights)) in self.filters.iter().enumerate() {
+                    let energy: f32 = weights
+                        .iter()
+                        .enumerate()
+                        .map(|(i, w)| w * (re[first + i].powi(2) + im[first + i].powi(2)))
+                        .sum();
+                    log_mel[m] = energy.max(1e-10).ln();
+                }
+                log_mel
+            })
+            .collect()
+    }
+}
+
+/// Cosine transform of the log mel energies, c0 left out.
+pub fn cepstrum(log_mel: &[f32; MEL_BANDS]) -> [f32; CEPSTRA] {
+    let mut cepstrum = [0f32; CEPSTRA];
+    for (n, c) in cepstrum.iter_mut().enumerate() {
+        let n = (n + 1) as f32;
+        *c = log_mel
+            .iter()
+            .enumerate()
+            .map(|(m, v)| v * (PI * n * (m as f32 + 0.5) / MEL_BANDS as f32).cos())
+            .sum();
+    }
+    cepstrum
+}
+
+pub fn mean<const N: usize>(frames: &[[f32; N]]) -> [f32; N] {
+    let mut mean = [0f32; N];
+    for f in frames {
+        for (m, v) in mean.iter_mut().zip(f.iter()) {
+            *m += v / frames.len() as f32;
+        }
+    }
+    mean
+}
+
+/// The enrolled voice.
+#[derive(Debug, Clone)]
+struct Voice {
+    mean: [f32; CEPSTRA],
+    std: [f32; CEPSTRA],
+    voiced_s: f64,
+}
+
+impl Voice {
+    fn new(cepstrum: &Cepstrum, pcm: &[f32]) -> Result<Self> {
+        if pcm.len() as f64 > MAX_ENROLL_S * SAMPLE_RATE as f64 {
+            anyhow::bail!("enrollment clip longer than {MAX_ENROLL_S}s")
+        }
+        let frames = cepstrum.voiced(pcm);
+        if frames.len() < MIN_ENROLL_FRAMES {
+            anyhow::bail!("enrollment clip with too little speech")
+        }
+        let mean = mean(&frames);
+        let mut std = [0f32; CEPSTRA];
+        for (i, s) in std.iter_mut().enumerate() {
+            let var = frames.iter().map(|f| (f[i] - mean[i]).powi(2)).sum::<f32>();
+            *s = (var / frames.len() as f32).sqrt().max(MIN_STD);
+        }
+        let voiced_s = (frames.len() * HOP) as f64 / SAMPLE_RATE as f64;
+        Ok(Self { mean, std, voiced_s })
+    }
+
+    /// Root mean square distance of `mean` to the voice, in standard deviations.
+    fn distance(&self, mean: &[f32; CEPSTRA]) -> f32 {
+        let sum: f32 = (0..CEPSTRA).map(|i| ((mean[i] - self.mean[i]) / self.std[i]).powi(2)).sum();
+        (sum / CEPSTRA as f32).sqrt()
+    }
+}
+
+/// Tags or drops the words of a session that are not spoken by its enrolled voice.
+pub struct SpeakerFilter {
+    cepstrum: Cepstrum,
+    voice: Voice,
+    other: OtherSpeech,
+    max_distance: f32,
+    audio: VecDeque<f32>,
+    /// Session time of the first buffered sample, in samples.
+    start: u64,
+    /// The last word was from another speaker.
+    in_other: bool,
+    dropping_end: bool,
+}
+
+impl SpeakerFilter {
+    /// Enroll the voice of `pcm`, the following audio starts at session time `now_s`.
+    pub fn enroll(pcm: &[f32], other: OtherSpeech, max_distance: f32, now_s: f64) -> Result<Self> {
+        let cepstrum = Cepstrum::new();
+        let voice = Voice::new(&cepstrum, pcm)?;
+        Ok(Self {
+            cepstrum,
+            voice,
+            other,
+            max_distance,
+            audio: VecDeque::new(),
+            start: (now_s * SAMPLE_RATE as f64).round() as u64,
+            in_other: false,
+            dropping_end: false,
+        })
+    }
+
+    /// Duration of the speech found in the enrollment clip.
+    pub fn voiced_s(&self) -> f64 {
+        self.voice.voiced_s
+    }
+
+    /// Continue from session time `start_s`, for restored sessions.
+    pub fn restart_at(&mut self, start_s: f64) {
+        self.audio.clear();
+        self.start = (start_s * SAMPLE_RATE as f64).round() as u64;
+    }
+
+    pub fn push(&mut self, pcm: &[f32]) {
+        self.audio.extend(pcm);
+        let excess = self.audio.len().saturating_sub((BUFFER_S * SAMPLE_RATE as f64) as usize);
+        self.audio.drain(..excess);
+        self.start += excess as u64;
+    }
+
+    fn is_enrolled_voice(&self, start_s: f64) -> bool {
+        let at = |t: f64| {
+            let t = (t.max(0.0) * SAMPLE_RATE as f64) as u64;
+            (t.saturating_sub(self.start) as usize).min(self.audio.len())
+        };
+        let (from, to) = (at(start_s - WORD_BEFORE_S), at(start_s + WORD_AFTER_S));
+        let pcm: Vec<f32> = self.audio.range(from..to).copied().collect();
+        let frames = self.cepstrum.voiced(&pcm);
+        frames.len() < MIN_WORD_FRAMES || self.voice.distance(&mean(&frames)) <= self.max_distance
+    }
+
+    /// Pass the words of the enrolled voice, tag or drop the others with their `EndWord`.
+    pub fn filter(&mut self, msg: OutMsg) -> Option<OutMsg> {
+        match msg {
+            OutMsg::Word { text, start_time, lang, tokens } => {
+                if self.is_enrolled_voice(start_time) {
+                    self.in_other = false;
+                    self.dropping_end = false;
+                    return Some(OutMsg::Word { text, start_time, lang, tokens });
+                }
+                crate::metrics::asr::OTHER_SPEAKER_WORDS.inc();
+                let first = !self.in_other;
+                self.in_other = true;
+                self.dropping_end = !first || self.other == OtherSpeech::Suppress;
+                if self.dropping_end {
+                    return None;
+                }
+                let text = "[other]".to_string();
+                Some(OutMsg::Word { text, start_time, lang: None, tokens: None })
+            }
+            OutMsg::EndWord { .. } if self.dropping_end => {
+                self.dropping_end = false;
+                None
+            }
+            msg => Some(msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A vowel-like sound: harmonics of `f0` shaped by a resonance at `formant`, with some
+    /// jitter so that the frames are not all identical.
+    fn voice(f0: f32, formant: f32, seconds: f64, seed: u32) -> Vec<f32> {
+        let mut seed = seed;
+        (0..(seconds * SAMPLE_RATE as f64) as usize)
+            .map(|i| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                let noise = (seed >> 8) as f32 / (1 << 24) as f32 - 0.5;
+                let t = i as f32 / SAMPLE_RATE as f32;
+                let sum: f32 = (1..40)
+                    .map(|k| {
+                        let f = f0 * k as f32;
+                        let gain = 1.0 / (1.0 + ((f - formant) / 300.0).powi(2));
+                        gain * (2.0 * PI * f * t).sin()
+                    })
+                    .sum();
+                0.05 * sum + 0.002 * noise
+            })
+            .collect()
+    }
+
+    fn word(start_time: f64) -> OutMsg {
+        OutMsg::Word { text: "hi".to_string(), start_time, lang: None, tokens: None }
+    }
+
+    #[test]
+    fn fft_finds_the_frequency() {
+        let mut re: Vec<f32> = (0..64).map(|i| (2.0 * PI * 5.0 * i as f32 / 64.0).cos()).collect();
+        let mut im = vec![0f32; 64];
+        fft(&mut re, &mut im);
+        let mags: Vec<f32> = re.iter().zip(im.iter()).map(|(r, i)| r.hypot(*i)).collect();
+        assert!((mags[5] - 32.0).abs() < 1e-3, "{}", mags[5]);
+        assert!(mags.iter().enumerate().all(|(k, m)| k == 5 || k == 59 || *m < 1e-3));
+    }
+
+    #[test]
+    fn tags_and_suppresses_other_voices() {
+        let enrolled = voice(120.0, 700.0, 3.0, 1);
+        assert!(SpeakerFilter::enroll(&[0.0; 48000], OtherSpeech::Tag, 1.5, 0.0).is_err());
+        let mut f = SpeakerFilter::enroll(&enrolled, OtherSpeech::Tag, 1.5, 0.0).unwrap();
+        assert!(f.voiced_s() > 2.5);
+        // Two seconds of the enrolled voice then two seconds of another one.
+        f.push(&voice(120.0, 700.0, 2.0, 2));
+        f.push(&voice(220.0, 2400.0, 2.0, 3));
+        assert!(f
+            .filter(word(1.2))
+            .is_some_and(|m| matches!(m, OutMsg::Word { text, .. } if text == "hi")));
+        let Some(OutMsg::Word { text, .. }) = f.filter(word(3.0)) else {
+            panic!("no [other] word")
+        };
+        assert_eq!(text, "[other]");
+        assert!(f.filter(OutMsg::EndWord { stop_time: 3.2 }).is_some());
+        // Consecutive other words collapse into one tag.
+        assert!(f.filter(word(3.4)).is_none());
+        assert!(f.filter(OutMsg::EndWord { stop_time: 3.5 }).is_none());
+        assert!(f.filter(OutMsg::Marker { id: 1, sample_idx: 0, step_idx: 0 }).is_some());
+
+        let mut f = SpeakerFilter::enroll(&enrolled, OtherSpeech::Suppress, 1.5, 10.0).unwrap();
+        f.push(&voice(220.0, 2400.0, 2.0, 4));
+        assert!(f.filter(word(11.0)).is_none());
+        assert!(f.filter(OutMsg::EndWord { stop_time: 11.2 }).is_none());
+        // Too little audio to judge, the word is kept.
+        f.restart_at(20.0);
+        assert!(f.filter(word(20.5)).is_some());
+    }
+}
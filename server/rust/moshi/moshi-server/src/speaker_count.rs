@@ -0,0 +1,296 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Speaker-count and overlap diagnostics, reported to clients that ask for them.
+//!
+//! These tell a user whether a recording holds several voices, and how often they talk over
+//! each other, before reaching for diarization or another workflow. Each second of audio with
+//! enough speech is summarized by its mean cepstrum, as for target speakers, and the seconds
+//! are grouped by a single clustering pass: a second far from every known voice starts a new
+//! one. Distances are in units of the spread of the cepstra over the whole session.
+//!
+//! Two voices speaking at once add their energies in each mel band, a second whose spectrum
+//! is better explained by the sum of two voices than by either of them counts as overlap, and
+//! a group made of such seconds is not counted as a speaker. This is a rough estimate: similar
+//! voices merge, a voice that changes a lot through the session may split.
+
+use crate::asr::OutMsg;
+use crate::speaker::{cepstrum, mean, Cepstrum, CEPSTRA, MEL_BANDS, MIN_STD, SAMPLE_RATE};
+use std::collections::VecDeque;
+
+/// Audio summarized by one spectrum, with at least `MIN_SEGMENT_FRAMES` voiced 20ms frames.
+const SEGMENT: usize = SAMPLE_RATE;
+const MIN_SEGMENT_FRAMES: usize = 15;
+/// A segment further than this from every voice starts a new one.
+const NEW_VOICE_DISTANCE: f32 = 1.0;
+/// A segment, or a voice, closer than this to the sum of two voices mixes them.
+const MIX_DISTANCE: f32 = 0.8;
+/// Relative levels of the two voices tried for a mix, in dB.
+const MIX_GAINS_DB: [f32; 5] = [-6.0, -3.0, 0.0, 3.0, 6.0];
+/// Voices heard for less than this many segments are not counted.
+const MIN_VOICE_SEGMENTS: usize = 3;
+/// Only the last hour of the session is kept.
+const MAX_SEGMENTS: usize = 3600;
+
+type Ceps = [f32; CEPSTRA];
+type Mel = [f32; MEL_BANDS];
+
+/// Mean log mel energies of a segment or of a voice, and their cepstrum.
+#[derive(Debug, Clone)]
+struct Spectrum {
+    log_mel: Mel,
+    ceps: Ceps,
+}
+
+impl Spectrum {
+    fn new(log_mel: Mel) -> Self {
+        Self { ceps: cepstrum(&log_mel), log_mel }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Voice {
+    spectrum: Spectrum,
+    segments: usize,
+}
+
+pub struct SpeakerStats {
+    cepstrum: Cepstrum,
+    interval: usize,
+    since_report: usize,
+    pending: Vec<f32>,
+    segments: VecDeque<Spectrum>,
+    /// Segments added since the last report.
+    recent: usize,
+    /// Sums over the voiced frames of the kept segments, for the spread of each coefficient.
+    frames: VecDeque<(usize, Ceps, Ceps)>,
+}
+
+fn distance(a: &Ceps, b: &Ceps, std: &Ceps) -> f32 {
+    let sum: f32 = (0..CEPSTRA).map(|i| ((a[i] - b[i]) / std[i]).powi(2)).sum();
+    (sum / CEPSTRA as f32).sqrt()
+}
+
+/// Distance of `s` to the sum of the voices `a` and `b`, at the relative level that fits best.
+fn mix_distance(s: &Spectrum, a: &Spectrum, b: &Spectrum, std: &Ceps) -> f32 {
+    MIX_GAINS_DB
+        .iter()
+        .map(|gain_db| {
+            let gain = gain_db * std::f32::consts::LN_10 / 10.0;
+            let mix: Mel = std::array::from_fn(|i| {
+                let (x, y) = (a.log_mel[i], b.log_mel[i] + gain);
+                let max = x.max(y);
+                max + ((x - max).exp() + (y - max).exp()).ln()
+            });
+            distance(&s.ceps, &cepstrum(&mix), std)
+        })
+        .fold(f32::INFINITY, f32::min)
+}
+
+/// `s` is closer to the sum of two of `voices` than to any of them.
+fn is_mix(s: &Spectrum, voices: &[&Voice], std: &Ceps) -> bool {
+    let nearest = voices
+        .iter()
+        .map(|v| distance(&s.ceps, &v.spectrum.ceps, std))
+        .fold(f32::INFINITY, f32::min);
+    voices.iter().enumerate().any(|(i, a)| {
+        voices[i + 1..].iter().any(|b| {
+            let d = mix_distance(s, &a.spectrum, &b.spectrum, std);
+            d < MIX_DISTANCE && d < nearest
+        })
+    })
+}
+
+impl SpeakerStats {
+    pub fn new(interval_s: f64) -> Self {
+        let interval_s = interval_s.max(crate::audio_stats::MIN_INTERVAL_S);
+        Self {
+            cepstrum: Cepstrum::new(),
+            interval: (interval_s * SAMPLE_RATE as f64).round() as usize,
+            since_report: 0,
+            pending: Vec::with_capacity(SEGMENT),
+            segments: VecDeque::new(),
+            recent: 0,
+            frames: VecDeque::new(),
+        }
+    }
+
+    /// Account for the received audio, returns an `Overlap` message when a report is due.
+    pub fn push(&mut self, pcm: &[f32]) -> Option<OutMsg> {
+        let mut report = None;
+        for chunk in pcm.chunks(SEGMENT) {
+            let take = chunk.len().min(SEGMENT - self.pending.len());
+            self.pending.extend_from_slice(&chunk[..take]);
+            if self.pending.len() == SEGMENT {
+                self.add_segment();
+                self.pending.extend_from_slice(&chunk[take..]);
+            }
+            self.since_report += chunk.len();
+            if self.since_report >= self.interval {
+                self.since_report -= self.interval;
+                report = Some(OutMsg::Overlap { ratio: self.overlap_ratio() });
+                self.recent = 0;
+            }
+        }
+        report
+    }
+
+    fn add_segment(&mut self) {
+        let frames = self.cepstrum.voiced_log_mel(&self.pending);
+        self.pending.clear();
+        if frames.len() < MIN_SEGMENT_FRAMES {
+            return;
+        }
+        let (mut sum, mut sum_sq) = ([0f32; CEPSTRA], [0f32; CEPSTRA]);
+        for f in frames.iter() {
+            let c = cepstrum(f);
+            for i in 0..CEPSTRA {
+                sum[i] += c[i];
+                sum_sq[i] += c[i] * c[i];
+            }
+        }
+        self.segments.push_back(Spectrum::new(mean(&frames)));
+        self.frames.push_back((frames.len(), sum, sum_sq));
+        self.recent += 1;
+        if self.segments.len() > MAX_SEGMENTS {
+            self.segments.pop_front();
+            self.frames.pop_front();
+        }
+    }
+
+    fn std(&self) -> Ceps {
+        let n: usize = self.frames.iter().map(|(n, _, _)| n).sum();
+        let n = n.max(1) as f32;
+        std::array::from_fn(|i| {
+            let sum: f32 = self.frames.iter().map(|(_, s, _)| s[i]).sum();
+            let sum_sq: f32 = self.frames.iter().map(|(_, _, s)| s[i]).sum();
+            let mean = sum / n;
+            (sum_sq / n - mean * mean).max(0.0).sqrt().max(MIN_STD)
+        })
+    }
+
+    /// The voices of the session, the groups of segments that mix two voices left out.
+    fn voices(&self, std: &Ceps) -> Vec<Voice> {
+        let mut groups: Vec<Voice> = vec![];
+        for s in self.segments.iter() {
+            let nearest = groups
+                .iter_mut()
+                .map(|v| (distance(&s.ceps, &v.spectrum.ceps, std), v))
+                .min_by(|a, b| a.0.total_cmp(&b.0));
+            match nearest {
+                Some((d, v)) if d < NEW_VOICE_DISTANCE => {
+                    v.segments += 1;
+                    let w = 1.0 / v.segments as f32;
+                    for (m, x) in v.spectrum.log_mel.iter_mut().zip(s.log_mel.iter()) {
+                        *m += (x - *m) * w;
+                    }
+                    v.spectrum = Spectrum::new(v.spectrum.log_mel);
+                }
+                _ => groups.push(Voice { spectrum: s.clone(), segments: 1 }),
+            }
+        }
+        groups.retain(|v| v.segments >= MIN_VOICE_SEGMENTS);
+        let mixes: Vec<bool> = (0..groups.len())
+            .map(|k| {
+                let others: Vec<&Voice> =
+                    groups.iter().enumerate().filter(|(j, _)| *j != k).map(|(_, v)| v).collect();
+                is_mix(&groups[k].spectrum, &others, std)
+            })
+            .collect();
+        groups.into_iter().zip(mixes).filter(|(_, mix)| !mix).map(|(v, _)| v).collect()
+    }
+
+    /// Fraction of the segments since the last report in which two voices overlap.
+    fn overlap_ratio(&self) -> f32 {
+        let recent = self.recent.min(self.segments.len());
+        if recent == 0 {
+            return 0.0;
+        }
+        let std = self.std();
+        let voices = self.voices(&std);
+        let voices: Vec<&Voice> = voices.iter().collect();
+        let start = self.segments.len() - recent;
+        let overlapped = self.segments.range(start..).filter(|s| is_mix(s, &voices, &std)).count();
+        overlapped as f32 / recent as f32
+    }
+
+    /// Number of voices heard over the session, sent when the client ends its audio with a
+    /// `Marker`.
+    pub fn estimate(&self) -> OutMsg {
+        let n = if self.segments.is_empty() { 0 } else { self.voices(&self.std()).len() };
+        OutMsg::SpeakerCountEstimate { n }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    /// Harmonics of `f0` shaped by a resonance at `formant`, see the target speaker tests.
+    fn voice(f0: f32, formant: f32, seconds: f64, seed: u32) -> Vec<f32> {
+        let mut seed = seed;
+        (0..(seconds * SAMPLE_RATE as f64) as usize)
+            .map(|i| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                let noise = (seed >> 8) as f32 / (1 << 24) as f32 - 0.5;
+                let t = i as f32 / SAMPLE_RATE as f32;
+                let sum: f32 = (1..40)
+                    .map(|k| {
+                        let f = f0 * k as f32;
+                        let gain = 1.0 / (1.0 + ((f - formant) / 300.0).powi(2));
+                        gain * (2.0 * PI * f * t).sin()
+                    })
+                    .sum();
+                0.05 * sum + 0.002 * noise
+            })
+            .collect()
+    }
+
+    fn count(stats: &SpeakerStats) -> usize {
+        match stats.estimate() {
+            OutMsg::SpeakerCountEstimate { n } => n,
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    }
+
+    #[test]
+    fn counts_voices_and_overlap() {
+        let mut stats = SpeakerStats::new(4.0);
+        assert_eq!(count(&stats), 0);
+        let a = voice(120.0, 700.0, 4.0, 1);
+        let b = voice(220.0, 2400.0, 4.0, 2);
+        let mut reports = vec![];
+        for pcm in [&a, &vec![0.0; 4 * SAMPLE_RATE], &b] {
+            for chunk in pcm.chunks(1920) {
+                reports.extend(stats.push(chunk));
+            }
+        }
+        assert_eq!(count(&stats), 2);
+        assert!(
+            reports.iter().all(|m| matches!(m, OutMsg::Overlap { ratio } if *ratio == 0.0)),
+            "{reports:?}"
+        );
+        assert_eq!(reports.len(), 3);
+
+        // Both voices at once for four seconds, in a single push.
+        let c = voice(120.0, 700.0, 4.0, 3);
+        let d = voice(220.0, 2400.0, 4.0, 4);
+        let mixed: Vec<f32> = c.iter().zip(d.iter()).map(|(x, y)| x + y).collect();
+        let Some(OutMsg::Overlap { ratio }) = stats.push(&mixed) else {
+            panic!("no overlap report")
+        };
+        assert!(ratio > 0.7, "{ratio}");
+        assert_eq!(count(&stats), 2);
+    }
+
+    #[test]
+    fn single_voice() {
+        let mut stats = SpeakerStats::new(1.0);
+        let mut pcm = voice(150.0, 1200.0, 5.0, 5);
+        pcm.extend(voice(150.0, 1200.0, 5.0, 6).iter().map(|v| v * 0.3));
+        stats.push(&pcm);
+        assert_eq!(count(&stats), 1);
+    }
+}
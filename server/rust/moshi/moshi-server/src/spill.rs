@@ -0,0 +1,208 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! WAV output of the TTS queries. The samples are kept in memory up to a threshold, longer
+//! outputs continue in an unnamed temporary file that is sent back in chunks, so that a long
+//! synthesis does not hold all of its audio in RAM.
+
+use anyhow::Result;
+use std::io::{Read, Seek, Write};
+
+/// Size of the chunks read back from a spilled file, a multiple of 3 for base64.
+const READ_CHUNK: usize = 3 * 21_846;
+const HEADER_BYTES: u64 = 44;
+
+pub struct WavWriter {
+    sample_rate: u32,
+    layout: crate::tts_channels::ChannelLayout,
+    threshold_bytes: usize,
+    dir: std::path::PathBuf,
+    /// Samples as 16-bit PCM, until the threshold is reached.
+    data: Vec<u8>,
+    file: Option<std::io::BufWriter<std::fs::File>>,
+    data_bytes: u64,
+}
+
+/// Finished WAV file.
+pub enum Wav {
+    Memory(Vec<u8>),
+    /// Spilled file, at its start, and its size.
+    File(std::fs::File, u64),
+}
+
+impl WavWriter {
+    /// Spill to a temporary file in `dir` once the samples take `threshold_bytes`. The mono
+    /// samples that are pushed are written in `layout`.
+    pub fn new(
+        sample_rate: u32,
+        layout: crate::tts_channels::ChannelLayout,
+        threshold_bytes: usize,
+        dir: Option<&std::path::Path>,
+    ) -> Self {
+        Self {
+            sample_rate,
+            layout,
+            threshold_bytes,
+            dir: dir.map_or_else(std::env::temp_dir, |d| d.to_path_buf()),
+            data: vec![],
+            file: None,
+            data_bytes: 0,
+        }
+    }
+
+    pub fn push(&mut self, pcm: &[f32]) -> Result<()> {
+        let pcm = self.layout.interleave(pcm);
+        let pcm = pcm.as_ref();
+        match self.file.as_mut() {
+            Some(file) => moshi::wav::write_pcm_in_wav(file, pcm)?,
+            None => moshi::wav::write_pcm_in_wav(&mut self.data, pcm)?,
+        };
+        self.data_bytes += pcm.len() as u64 * 2;
+        if self.file.is_none() && self.data.len() >= self.threshold_bytes {
+            self.spill()?
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        let file = tempfile::tempfile_in(&self.dir)?;
+        let mut file = std::io::BufWriter::new(file);
+        // The sizes are only known at the end, the header is written again then.
+        self.write_header(&mut file, 0, 0)?;
+        file.write_all(&self.data)?;
+        tracing::info!(dir = %self.dir.display(), bytes = self.data.len(), "spilling tts output");
+        crate::metrics::tts::SPILLED.inc();
+        self.data = vec![];
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn write_header<W: Write>(&self, w: &mut W, chunk_size: u32, data_size: u32) -> Result<()> {
+        let channels = self.layout.channels() as u16;
+        moshi::wav::write_wav_header_with_channels(
+            w,
+            self.sample_rate,
+            channels,
+            chunk_size,
+            data_size,
+        )?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<Wav> {
+        // The header holds 32-bit sizes, longer outputs (above 12h) have saturated ones.
+        let data_size = u32::try_from(self.data_bytes).unwrap_or(u32::MAX - 36);
+        let chunk_size = data_size + 36;
+        match self.file.take() {
+            None => {
+                let mut wav = Vec::with_capacity(HEADER_BYTES as usize + self.data.len());
+                self.write_header(&mut wav, chunk_size, data_size)?;
+                wav.extend_from_slice(&self.data);
+                Ok(Wav::Memory(wav))
+            }
+            Some(file) => {
+                let mut file = file.into_inner().map_err(|e| e.into_error())?;
+                file.rewind()?;
+                self.write_header(&mut file, chunk_size, data_size)?;
+                file.rewind()?;
+                Ok(Wav::File(file, HEADER_BYTES + self.data_bytes))
+            }
+        }
+    }
+}
+
+impl Wav {
+    pub fn len(&self) -> u64 {
+        match self {
+            Self::Memory(wav) => wav.len() as u64,
+            Self::File(_, len) => *len,
+        }
+    }
+
+    /// The whole file in memory.
+    pub fn into_vec(self) -> Result<Vec<u8>> {
+        match self {
+            Self::Memory(wav) => Ok(wav),
+            Self::File(mut file, len) => {
+                let mut wav = Vec::with_capacity(len as usize);
+                file.read_to_end(&mut wav)?;
+                Ok(wav)
+            }
+        }
+    }
+
+    /// The file in chunks, read from disk as they are sent. `map` is applied to each chunk,
+    /// all of them but the last one hold a multiple of 3 bytes.
+    pub fn into_stream<F>(
+        self,
+        map: F,
+    ) -> impl futures_util::Stream<Item = std::io::Result<bytes::Bytes>> + Send
+    where
+        F: Fn(Vec<u8>) -> bytes::Bytes + Send + Sync + 'static,
+    {
+        let map = std::sync::Arc::new(map);
+        let reader: Box<dyn Read + Send> = match self {
+            Self::Memory(wav) => Box::new(std::io::Cursor::new(wav)),
+            Self::File(file, _) => Box::new(file),
+        };
+        futures_util::stream::try_unfold(reader, move |mut reader| {
+            let map = map.clone();
+            async move {
+                let (chunk, reader) = tokio::task::spawn_blocking(move || {
+                    let mut chunk = Vec::with_capacity(READ_CHUNK);
+                    let n = (&mut reader).take(READ_CHUNK as u64).read_to_end(&mut chunk)?;
+                    Ok::<_, std::io::Error>((chunk, if n == 0 { None } else { Some(reader) }))
+                })
+                .await
+                .map_err(std::io::Error::other)??;
+                Ok(reader.map(|reader| (map(chunk), reader)))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tts_channels::ChannelLayout;
+
+    #[test]
+    fn spilled_output_matches_memory() -> Result<()> {
+        let pcm: Vec<f32> = (0..10_000).map(|i| (i as f32 * 0.01).sin()).collect();
+        let mut memory = WavWriter::new(24_000, ChannelLayout::Mono, usize::MAX, None);
+        let mut spilled = WavWriter::new(24_000, ChannelLayout::Mono, 4096, None);
+        for chunk in pcm.chunks(1920) {
+            memory.push(chunk)?;
+            spilled.push(chunk)?;
+        }
+        let (memory, spilled) = (memory.finish()?, spilled.finish()?);
+        assert!(matches!(memory, Wav::Memory(_)));
+        assert!(matches!(spilled, Wav::File(..)));
+        assert_eq!(spilled.len(), 44 + 20_000);
+        let mut expected = vec![];
+        moshi::wav::write_pcm_as_wav(&mut expected, &pcm, 24_000)?;
+        assert_eq!(memory.into_vec()?, expected);
+        assert_eq!(spilled.into_vec()?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn stereo_output() -> Result<()> {
+        let layout = ChannelLayout::new(Some(2), Some(0.5)).unwrap();
+        let mut stereo = WavWriter::new(24_000, layout, 64, None);
+        stereo.push(&[0.5; 100])?;
+        let wav = stereo.finish()?;
+        assert!(matches!(wav, Wav::File(..)));
+        let wav = wav.into_vec()?;
+        assert_eq!(wav.len(), 44 + 400);
+        // Channels, byte rate and block align.
+        assert_eq!(u16::from_le_bytes([wav[22], wav[23]]), 2);
+        assert_eq!(u32::from_le_bytes(wav[28..32].try_into()?), 24_000 * 4);
+        assert_eq!(u16::from_le_bytes([wav[32], wav[33]]), 4);
+        let left = i16::from_le_bytes([wav[44], wav[45]]);
+        let right = i16::from_le_bytes([wav[46], wav[47]]);
+        assert_eq!((left, right), (8191, 16383));
+        Ok(())
+    }
+}
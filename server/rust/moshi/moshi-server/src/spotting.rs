@@ -0,0 +1,190 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Spotting of command phrases in the asr words, chosen per session with the `spot` query
+//! parameter, e.g. `spot=["next slide","stop recording"]`.
+//!
+//! Each word is compared with the last words of the phrases as soon as it is emitted, so that
+//! `PhraseSpotted` follows the last word of the phrase without waiting for its end. Words are
+//! compared without casing and punctuation and allow for a few misspelled letters, one for
+//! words of 4 to 7 letters and two for longer ones, since the model may spell an uncommon
+//! word differently. The words of a spotted phrase are not reused for the next one.
+
+use crate::asr::OutMsg;
+use std::collections::VecDeque;
+
+pub const MAX_PHRASES: usize = 32;
+pub const MAX_PHRASE_WORDS: usize = 8;
+
+/// Lowercase words of `text`, hyphens and spaces separate words, other punctuation is dropped.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| c.is_whitespace() || c == '-')
+        .map(|w| w.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect())
+        .filter(|w: &String| !w.is_empty())
+}
+
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut row = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let sub = prev[j] + usize::from(ca != cb);
+            row[j + 1] = sub.min(prev[j + 1] + 1).min(row[j] + 1);
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
+
+fn similar(heard: &str, expected: &[char]) -> bool {
+    let max_edits = match expected.len() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    };
+    let heard: Vec<char> = heard.chars().collect();
+    heard.len().abs_diff(expected.len()) <= max_edits
+        && edit_distance(&heard, expected) <= max_edits
+}
+
+/// Read the `spot` query parameter, either a JSON list of strings or a comma separated list.
+pub fn deserialize_phrases<'de, D>(d: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    use serde::Deserialize;
+
+    let Some(spot) = Option::<String>::deserialize(d)? else { return Ok(None) };
+    let phrases: Vec<String> = if spot.trim_start().starts_with('[') {
+        serde_json::from_str(&spot).map_err(D::Error::custom)?
+    } else {
+        spot.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect()
+    };
+    if phrases.len() > MAX_PHRASES {
+        return Err(D::Error::custom(format!("at most {MAX_PHRASES} phrases can be spotted")));
+    }
+    for phrase in phrases.iter() {
+        let n = words(phrase).count();
+        if n == 0 || n > MAX_PHRASE_WORDS {
+            let msg = format!("phrase {phrase:?} must have 1 to {MAX_PHRASE_WORDS} words");
+            return Err(D::Error::custom(msg));
+        }
+    }
+    Ok(Some(phrases))
+}
+
+#[derive(Debug)]
+struct Phrase {
+    text: String,
+    words: Vec<Vec<char>>,
+}
+
+#[derive(Debug)]
+pub struct PhraseSpotter {
+    /// Longest phrases first, so that `stop recording now` wins over `recording now`.
+    phrases: Vec<Phrase>,
+    /// Last words heard with their start time, as many as the longest phrase.
+    recent: VecDeque<(String, f64)>,
+}
+
+impl PhraseSpotter {
+    pub fn new(phrases: &[String]) -> Self {
+        let mut phrases: Vec<_> = phrases
+            .iter()
+            .map(|text| Phrase {
+                text: text.clone(),
+                words: words(text).map(|w| w.chars().collect()).collect(),
+            })
+            .filter(|p| !p.words.is_empty())
+            .collect();
+        phrases.sort_by_key(|p| std::cmp::Reverse(p.words.len()));
+        Self { phrases, recent: VecDeque::new() }
+    }
+
+    /// Feed a word of the transcript, returns a `PhraseSpotted` message when it ends a phrase.
+    pub fn push(&mut self, text: &str, start_time: f64) -> Option<OutMsg> {
+        let max_words = self.phrases.first().map_or(0, |p| p.words.len());
+        let mut spotted = None;
+        for word in words(text) {
+            self.recent.push_back((word, start_time));
+            if self.recent.len() > max_words {
+                self.recent.pop_front();
+            }
+            if let Some(msg) = self.spot() {
+                self.recent.clear();
+                spotted = Some(msg);
+            }
+        }
+        spotted
+    }
+
+    fn spot(&self) -> Option<OutMsg> {
+        let phrase = self.phrases.iter().find(|p| {
+            let n = p.words.len();
+            n <= self.recent.len()
+                && self
+                    .recent
+                    .range(self.recent.len() - n..)
+                    .zip(p.words.iter())
+                    .all(|((heard, _), expected)| similar(heard, expected))
+        })?;
+        let (_, time_s) = self.recent[self.recent.len() - phrase.words.len()];
+        Some(OutMsg::PhraseSpotted { phrase: phrase.text.clone(), time_s })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spot(spotter: &mut PhraseSpotter, text: &str) -> Vec<(String, f64)> {
+        text.split(' ')
+            .enumerate()
+            .filter_map(|(i, w)| match spotter.push(w, i as f64) {
+                Some(OutMsg::PhraseSpotted { phrase, time_s }) => Some((phrase, time_s)),
+                Some(msg) => panic!("unexpected message {msg:?}"),
+                None => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn spots_phrases_in_the_words() {
+        let phrases = ["next slide".into(), "stop recording".into(), "go back".into()];
+        let mut spotter = PhraseSpotter::new(&phrases);
+        let spotted = spot(&mut spotter, "okay, Next slide. please and then stop recordin");
+        assert_eq!(spotted, [("next slide".to_string(), 1.0), ("stop recording".to_string(), 6.0)]);
+        // Short words must match exactly.
+        assert!(spot(&mut spotter, "so back or go bake").is_empty());
+        assert_eq!(spot(&mut spotter, "nest-slide").len(), 1);
+    }
+
+    #[test]
+    fn longest_phrase_wins_and_words_are_not_reused() {
+        let phrases = ["slide".to_string(), "next slide".to_string()];
+        let mut spotter = PhraseSpotter::new(&phrases);
+        let spotted = spot(&mut spotter, "next slide slide");
+        assert_eq!(spotted, [("next slide".to_string(), 0.0), ("slide".to_string(), 2.0)]);
+    }
+
+    #[test]
+    fn query_parameter() {
+        #[derive(serde::Deserialize)]
+        struct Query {
+            #[serde(default, deserialize_with = "deserialize_phrases")]
+            spot: Option<Vec<String>>,
+        }
+        let parse = |q: &str| {
+            let uri: axum::http::Uri = format!("/api/asr-streaming?{q}").parse().unwrap();
+            axum::extract::Query::<Query>::try_from_uri(&uri).map(|q| q.0.spot)
+        };
+        let json = "spot=%5B%22next+slide%22%2C%22stop+recording%22%5D";
+        assert_eq!(parse(json).unwrap().unwrap(), ["next slide", "stop recording"]);
+        assert_eq!(parse("spot=next+slide,stop").unwrap().unwrap(), ["next slide", "stop"]);
+        assert_eq!(parse("").unwrap(), None);
+        assert!(parse("spot=%5B%22...%22%5D").is_err());
+        assert!(parse("spot=%5Bnext%5D").is_err());
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Storage of the small documents that the server manages at runtime, e.g. the voices and
+//! lexicons edited through the admin endpoints.
+//!
+//! Documents are addressed by a prefix made of `/` separated path segments chosen by the
+//! server and a name that can be any string. [`DirStorage`] keeps each document in a file, the
+//! name being base64 encoded so that names holding `/` or `..` stay in their directory.
+
+use anyhow::{Context, Result};
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use std::path::PathBuf;
+
+/// Longest name, in bytes, that [`DirStorage`] can store: its base64 encoding with the
+/// extension of the temporary file stays within the 255 bytes of a file name.
+pub const MAX_NAME_BYTES: usize = 180;
+
+pub trait Storage: Send + Sync {
+    fn get(&self, prefix: &str, name: &str) -> Result<Option<Vec<u8>>>;
+    fn put(&self, prefix: &str, name: &str, value: &[u8]) -> Result<()>;
+    /// Returns whether there was a document to delete.
+    fn delete(&self, prefix: &str, name: &str) -> Result<bool>;
+    /// Names of the documents under `prefix`, sorted.
+    fn list(&self, prefix: &str) -> Result<Vec<String>>;
+}
+
+/// Documents stored as `<dir>/<prefix>/<base64 name>.json`, written through a temporary file
+/// so that readers never see a partial document.
+#[derive(Debug, Clone)]
+pub struct DirStorage {
+    dir: PathBuf,
+}
+
+impl DirStorage {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn prefix_dir(&self, prefix: &str) -> Result<PathBuf> {
+        let mut dir = self.dir.clone();
+        for segment in prefix.split('/') {
+            if segment.is_empty() || segment.starts_with('.') || segment.contains('\\') {
+                anyhow::bail!("invalid storage prefix {prefix}")
+            }
+            dir.push(segment)
+        }
+        Ok(dir)
+    }
+
+    fn path(&self, prefix: &str, name: &str) -> Result<PathBuf> {
+        if name.len() > MAX_NAME_BYTES {
+            anyhow::bail!("storage name over {MAX_NAME_BYTES} bytes")
+        }
+        let file = format!("{}.json", BASE64_URL_SAFE_NO_PAD.encode(name));
+        Ok(self.prefix_dir(prefix)?.join(file))
+    }
+}
+
+impl Storage for DirStorage {
+    fn get(&self, prefix: &str, name: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(prefix, name)?;
+        match std::fs::read(&path) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("cannot read {}", path.display())),
+        }
+    }
+
+    fn put(&self, prefix: &str, name: &str, value: &[u8]) -> Result<()> {
+        let path = self.path(prefix, name)?;
+        let dir = self.prefix_dir(prefix)?;
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("cannot create {}", dir.display()))?;
+        let tmp = path.with_extension(format!("tmp-{}", std::process::id()));
+        std::fs::write(&tmp, value).with_context(|| format!("cannot write {}", tmp.display()))?;
+        std::fs::rename(&tmp, &path).with_context(|| format!("cannot write {}", path.display()))
+    }
+
+    fn delete(&self, prefix: &str, name: &str) -> Result<bool> {
+        let path = self.path(prefix, name)?;
+        match std::fs::remove_file(&path) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err).with_context(|| format!("cannot delete {}", path.display())),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let dir = self.prefix_dir(prefix)?;
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err).with_context(|| format!("cannot list {}", dir.display())),
+        };
+        let mut names = vec![];
+        for entry in entries {
+            let file = entry?.file_name();
+            let Some(name) = file.to_str().and_then(|f| f.strip_suffix(".json")) else { continue };
+            let Ok(name) = BASE64_URL_SAFE_NO_PAD.decode(name) else { continue };
+            if let Ok(name) = String::from_utf8(name) {
+                names.push(name)
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dir_storage_round_trip() {
+        let dir = std::env::temp_dir().join(format!("moshi-storage-test-{}", std::process::id()));
+        let storage = DirStorage::new(&dir);
+        assert_eq!(storage.list("acme/voices").unwrap(), Vec::<String>::new());
+        storage.put("acme/voices", "vctk/p225_023.wav", b"{}").unwrap();
+        storage.put("acme/voices", "../default", b"{\"tags\":[]}").unwrap();
+        storage.put("other/voices", "default", b"{}").unwrap();
+        assert_eq!(storage.list("acme/voices").unwrap(), ["../default", "vctk/p225_023.wav"]);
+        let value = storage.get("acme/voices", "../default").unwrap();
+        assert_eq!(value.as_deref(), Some(&b"{\"tags\":[]}"[..]));
+        assert!(storage.delete("acme/voices", "../default").unwrap());
+        assert!(!storage.delete("acme/voices", "../default").unwrap());
+        assert_eq!(storage.get("acme/voices", "../default").unwrap(), None);
+        assert!(storage.put("../acme", "default", b"{}").is_err());
+        let longest = "é".repeat(MAX_NAME_BYTES / 2);
+        storage.put("acme/voices", &longest, b"{}").unwrap();
+        assert_eq!(storage.get("acme/voices", &longest).unwrap().as_deref(), Some(&b"{}"[..]));
+        assert!(storage.put("acme/voices", &format!("{longest}a"), b"{}").is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
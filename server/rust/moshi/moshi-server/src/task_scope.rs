@@ -0,0 +1,158 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! The tasks of a websocket session, stopped together.
+//!
+//! Every session runs a reader, a sender and some blocking model loops. They are spawned in a
+//! [`TaskScope`] that the handler awaits: as soon as an async task ends, the socket closed or
+//! failed, the scope is cancelled and the other tasks stop at their next await point, dropping
+//! their channels. The blocking loops cannot be interrupted, they see their channels close or
+//! check the cancellation token between steps. Dropping the scope cancels it too, so that no
+//! task outlives its session even when the handler itself is dropped.
+
+use anyhow::Result;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+/// Counts the running session tasks in the `session_tasks` gauge, aborted tasks included.
+struct Running;
+
+impl Running {
+    fn new() -> Self {
+        crate::metrics::tasks::SESSION_TASKS.inc();
+        Self
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        crate::metrics::tasks::SESSION_TASKS.dec();
+    }
+}
+
+fn log_result(name: &'static str, result: Result<()>) {
+    match result {
+        Ok(()) => tracing::debug!(?name, "task completed successfully"),
+        Err(err) => tracing::error!(?name, ?err, "task failed"),
+    }
+}
+
+pub struct TaskScope {
+    token: CancellationToken,
+    tasks: JoinSet<()>,
+}
+
+impl Default for TaskScope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TaskScope {
+    pub fn new() -> Self {
+        Self { token: CancellationToken::new(), tasks: JoinSet::new() }
+    }
+
+    /// Runs `future` until it completes or the scope is cancelled. The scope is cancelled when
+    /// the future completes, whatever the outcome.
+    pub fn spawn<F>(&mut self, name: &'static str, future: F)
+    where
+        F: std::future::Future<Output = Result<()>> + Send + 'static,
+    {
+        use tracing::Instrument;
+        let token = self.token.clone();
+        let running = Running::new();
+        self.tasks.spawn(
+            async move {
+                let _running = running;
+                let _cancel = token.clone().drop_guard();
+                tokio::select! {
+                    result = future => log_result(name, result),
+                    _ = token.cancelled() => tracing::debug!(?name, "task cancelled"),
+                }
+            }
+            .instrument(tracing::Span::current()),
+        );
+    }
+
+    /// Runs `f` on the blocking pool with the token of the scope, to check between steps. Its end
+    /// does not cancel the scope: the blocking loops end when their input closes and their
+    /// output channels tell the async tasks.
+    pub fn spawn_blocking<F>(&mut self, name: &'static str, f: F)
+    where
+        F: FnOnce(CancellationToken) -> Result<()> + Send + 'static,
+    {
+        let token = self.token.clone();
+        let running = Running::new();
+        let span = tracing::Span::current();
+        self.tasks.spawn_blocking(move || {
+            let _running = running;
+            let _enter = span.enter();
+            crate::profiler::name_thread(name);
+            log_result(name, f(token))
+        });
+    }
+
+    /// Waits until a task cancels the scope or for `max_duration`, then cancels the scope and
+    /// gives the tasks `grace` to finish. The tasks still running after that are aborted, the
+    /// blocking ones keep their thread until their next check of the token.
+    pub async fn run(mut self, max_duration: Option<Duration>, grace: Duration) {
+        let cancelled = self.token.cancelled();
+        match max_duration {
+            None => cancelled.await,
+            Some(d) => {
+                if tokio::time::timeout(d, cancelled).await.is_err() {
+                    tracing::error!("reached timeout, cancelling session tasks");
+                }
+            }
+        }
+        self.token.cancel();
+        let tasks = &mut self.tasks;
+        let drained =
+            tokio::time::timeout(grace, async { while tasks.join_next().await.is_some() {} });
+        if drained.await.is_err() {
+            tracing::warn!(remaining = self.tasks.len(), "session tasks still running, aborting");
+            self.tasks.abort_all();
+        }
+    }
+}
+
+impl Drop for TaskScope {
+    fn drop(&mut self) {
+        self.token.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn first_task_to_end_stops_the_others() {
+        let mut scope = TaskScope::new();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<()>();
+        // Stands for a sender blocked on a half-open socket.
+        scope.spawn("stuck", async move {
+            std::future::pending::<()>().await;
+            drop(tx);
+            Ok(())
+        });
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        scope.spawn_blocking("loop", move |token| {
+            while !token.is_cancelled() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            done_tx.send(())?;
+            Ok(())
+        });
+        scope.spawn("reader", async { anyhow::bail!("connection reset") });
+        let start = std::time::Instant::now();
+        scope.run(Some(Duration::from_secs(60)), Duration::from_secs(5)).await;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(done_rx.try_recv().is_ok());
+        // The channel of the stuck task was dropped.
+        assert!(rx.recv().await.is_none());
+    }
+}
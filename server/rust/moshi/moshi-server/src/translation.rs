@@ -0,0 +1,184 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Machine translation of the TTS input text.
+//!
+//! When a TTS request sets both `input_language` and `speak_language` to different values,
+//! the text is sent to a LibreTranslate compatible backend before synthesis. Streaming
+//! requests are translated sentence by sentence as the text comes in.
+
+use anyhow::{Context, Result};
+
+fn default_timeout_s() -> f64 {
+    10.0
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct TranslationConfig {
+    /// Translate endpoint, e.g. `http://localhost:5000/translate`.
+    pub url: String,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(default = "default_timeout_s")]
+    pub timeout_s: f64,
+}
+
+/// Source and target languages of a request, `None` when no translation is needed.
+pub fn language_pair(input: Option<&str>, speak: Option<&str>) -> Option<(String, String)> {
+    match (input, speak) {
+        (Some(i), Some(s)) if !i.trim().eq_ignore_ascii_case(s.trim()) => {
+            Some((i.trim().to_lowercase(), s.trim().to_lowercase()))
+        }
+        _ => None,
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Request<'a> {
+    q: &'a [String],
+    source: &'a str,
+    target: &'a str,
+    format: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_key: Option<&'a str>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+    translated_text: Vec<String>,
+}
+
+pub struct Translator {
+    client: reqwest::Client,
+    config: TranslationConfig,
+}
+
+impl Translator {
+    pub fn new(config: &TranslationConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs_f64(config.timeout_s))
+            .build()?;
+        Ok(Self { client, config: config.clone() })
+    }
+
+    pub async fn translate(
+        &self,
+        texts: &[String],
+        source: &str,
+        target: &str,
+    ) -> Result<Vec<String>> {
+        if texts.is_empty() {
+            return Ok(vec![]);
+        }
+        let start = std::time::Instant::now();
+        let req = Request {
+            q: texts,
+            source,
+            target,
+            format: "text",
+            api_key: self.config.api_key.as_deref(),
+        };
+        let resp = self
+            .client
+            .post(&self.config.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&req)?)
+            .send()
+            .await
+            .context("translation backend unreachable")?;
+        let status = resp.status();
+        let body = resp.bytes().await?;
+        if !status.is_success() {
+            anyhow::bail!(
+                "translation backend returned {status}: {}",
+                String::from_utf8_lossy(&body)
+            )
+        }
+        let resp: Response = serde_json::from_slice(&body)?;
+        if resp.translated_text.len() != texts.len() {
+            anyhow::bail!(
+                "translation backend returned {} texts for {}",
+                resp.translated_text.len(),
+                texts.len()
+            )
+        }
+        crate::metrics::tts::TRANSLATION_DURATION.observe(start.elapsed().as_secs_f64());
+        Ok(resp.translated_text)
+    }
+}
+
+/// Languages of a translated request along with the backend to use.
+pub struct Translation {
+    pub translator: std::sync::Arc<Translator>,
+    pub source: String,
+    pub target: String,
+}
+
+impl Translation {
+    pub async fn translate(&self, texts: &[String]) -> Result<Vec<String>> {
+        self.translator.translate(texts, &self.source, &self.target).await
+    }
+}
+
+/// Original and translated text, returned next to the transcript.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct TranslatedText {
+    pub input_language: String,
+    pub speak_language: String,
+    pub original: Vec<String>,
+    pub translated: Vec<String>,
+}
+
+/// Groups the streamed words into sentences so that each one is translated with its context.
+#[derive(Debug, Default)]
+pub struct SentenceBuffer {
+    text: String,
+}
+
+impl SentenceBuffer {
+    /// Add a text message, returns the sentence if this completes it.
+    pub fn push(&mut self, msg: &str) -> Option<String> {
+        let msg = msg.trim();
+        if msg.is_empty() {
+            return None;
+        }
+        if !self.text.is_empty() {
+            self.text.push(' ');
+        }
+        self.text.push_str(msg);
+        if msg.ends_with(['.', '!', '?', '。', '！', '？']) {
+            return self.flush();
+        }
+        None
+    }
+
+    pub fn flush(&mut self) -> Option<String> {
+        if self.text.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.text))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pair_and_sentences() {
+        assert_eq!(language_pair(Some("de"), Some("EN")), Some(("de".into(), "en".into())));
+        assert_eq!(language_pair(Some("en"), Some("EN")), None);
+        assert_eq!(language_pair(None, Some("en")), None);
+
+        let mut buf = SentenceBuffer::default();
+        assert_eq!(buf.push("Guten"), None);
+        assert_eq!(buf.push("Tag!"), Some("Guten Tag!".to_string()));
+        assert_eq!(buf.push("Wie "), None);
+        assert_eq!(buf.push("geht's"), None);
+        assert_eq!(buf.flush(), Some("Wie geht's".to_string()));
+        assert_eq!(buf.flush(), None);
+    }
+}
@@ -2,6 +2,7 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
+use crate::errors::ApiError;
 use anyhow::{Context, Result};
 use axum::extract::ws;
 use candle::{DType, Device, IndexOp, Tensor};
@@ -15,6 +16,15 @@ pub struct WordWithTimestamps {
     pub stop_s: f64,
 }
 
+/// Part of the text generated on its own, see [`crate::tts_preprocess::chunk_words`].
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct TextChunk {
+    /// Index in the transcript of the first word of the chunk.
+    pub first_word: usize,
+    pub start_s: f64,
+    pub stop_s: f64,
+}
+
 pub struct Model {
     lm: moshi::lm::LmModel,
     audio_tokenizer: moshi::mimi::Mimi,
@@ -27,6 +37,17 @@ pub struct Model {
     voice_dir: std::path::PathBuf,
     log_dir: std::path::PathBuf,
     log_tokens: bool,
+    spill_threshold_bytes: usize,
+    spill_dir: Option<std::path::PathBuf>,
+    translator: Option<std::sync::Arc<crate::translation::Translator>>,
+    voices: Vec<crate::voices::VoiceInfo>,
+    /// Preview clips generated at warmup, as wav files.
+    previews: std::sync::RwLock<std::collections::HashMap<String, Vec<u8>>>,
+    encode_pool: crate::tts_encode::EncodePool,
+    chunking: crate::tts_preprocess::ChunkingConfig,
+    steps: crate::compute::StepPool,
+    pub(crate) cache: Option<crate::tts_cache::TtsCache>,
+    pub(crate) jobs: Option<crate::tts_jobs::JobStore>,
     // Dummy way to ensure that only a single inference can happen.
     pub(crate) mutex: tokio::sync::Mutex<()>,
 }
@@ -72,8 +93,8 @@ impl DynamicVoiceCache {
 }
 
 pub enum Encoder {
-    OggOpus(kaudio::ogg_opus::Encoder),
-    OggOpusMessagePack(kaudio::ogg_opus::Encoder),
+    OggOpus(crate::ogg_opus::Encoder),
+    OggOpusMessagePack(crate::ogg_opus::Encoder),
     Pcm,
     PcmMessagePack,
 }
@@ -151,6 +172,7 @@ impl Logger {
         let base_path = log_dir.as_ref().join(format!("{instance_name}-tts-{secs}-{us}"));
         let json_filename = base_path.with_extension("json");
         let query = QueryWithTexts { query, texts };
+        let query = crate::schema::stamp(crate::schema::Artifact::SessionLog, &query)?;
         let json_content = serde_json::to_string_pretty(&query)?;
         std::fs::write(json_filename, json_content)?;
         let st_filename = base_path.with_extension("safetensors");
@@ -169,11 +191,62 @@ impl Logger {
 #[derive(serde::Serialize, serde::Deserialize)]
 #[serde(tag = "type")]
 pub enum OutMsg {
-    Text { text: String, start_s: f64, stop_s: f64 },
-    Audio { pcm: Vec<f32> },
-    OggOpus { data: Vec<u8> },
-    Error { message: String },
+    Text {
+        text: String,
+        start_s: f64,
+        stop_s: f64,
+    },
+    Audio {
+        pcm: Vec<f32>,
+    },
+    OggOpus {
+        data: Vec<u8>,
+    },
+    Error {
+        message: String,
+    },
     Ready,
+    /// Sent before the words of a translated sentence.
+    Translation {
+        original: String,
+        translated: String,
+    },
+    /// RMS energy of the upcoming audio, one value per `hop_ms` window, so that clients can
+    /// duck background audio without analyzing the pcm.
+    Envelope {
+        rms: Vec<f32>,
+        hop_ms: u32,
+    },
+}
+
+/// Computes the RMS energy of the generated audio over fixed windows, windows that straddle
+/// two pcm chunks are carried over to the next call.
+struct EnvelopeMeter {
+    hop: usize,
+    sum_sq: f32,
+    len: usize,
+}
+
+impl EnvelopeMeter {
+    fn new(hop_ms: u32, sample_rate: usize) -> Self {
+        let hop = (hop_ms as usize * sample_rate / 1000).max(1);
+        Self { hop, sum_sq: 0., len: 0 }
+    }
+
+    /// Returns the RMS of the windows completed by `pcm`.
+    fn push(&mut self, pcm: &[f32]) -> Vec<f32> {
+        let mut rms = vec![];
+        for &v in pcm {
+            self.sum_sq += v * v;
+            self.len += 1;
+            if self.len == self.hop {
+                rms.push((self.sum_sq / self.hop as f32).sqrt());
+                self.sum_sq = 0.;
+                self.len = 0;
+            }
+        }
+        rms
+    }
 }
 
 #[derive(serde::Serialize)]
@@ -182,22 +255,58 @@ enum OutMsgRef<'a> {
     Audio { pcm: &'a [f32] },
 }
 
+/// Translate a sentence of streamed text and notify the client, returns the text to speak.
+async fn translate_sentence(
+    translation: &crate::translation::Translation,
+    format: crate::StreamingOutput,
+    out_tx: &tokio::sync::mpsc::WeakUnboundedSender<Vec<u8>>,
+    sentence: String,
+) -> Result<String> {
+    let translated = translation.translate(std::slice::from_ref(&sentence)).await?;
+    let translated = translated.into_iter().next().unwrap_or_default();
+    let msg = OutMsg::Translation { original: sentence, translated: translated.clone() };
+    if let (Some(msg), Some(out_tx)) = (Encoder::message_pack(format, &msg)?, out_tx.upgrade()) {
+        out_tx.send(msg)?;
+    }
+    Ok(translated)
+}
+
 impl Encoder {
-    pub fn new(format: crate::StreamingOutput) -> Result<Self> {
+    /// Serialize a message for the formats that carry messagepack, `None` for raw audio.
+    pub fn message_pack(format: crate::StreamingOutput, msg: &OutMsg) -> Result<Option<Vec<u8>>> {
+        use serde::Serialize;
+        match format {
+            crate::StreamingOutput::OggOpus | crate::StreamingOutput::Pcm => Ok(None),
+            crate::StreamingOutput::OggOpusMessagePack | crate::StreamingOutput::PcmMessagePack => {
+                let mut buf = vec![];
+                msg.serialize(
+                    &mut rmp_serde::Serializer::new(&mut buf)
+                        .with_human_readable()
+                        .with_struct_map(),
+                )?;
+                Ok(Some(buf))
+            }
+        }
+    }
+
+    /// Encoder of audio with `channels` interleaved channels.
+    pub fn new(format: crate::StreamingOutput, channels: usize) -> Result<Self> {
         match format {
-            crate::StreamingOutput::OggOpus => Self::ogg_opus(24000),
-            crate::StreamingOutput::OggOpusMessagePack => Self::ogg_opus_message_pack(24000),
+            crate::StreamingOutput::OggOpus => Self::ogg_opus(24000, channels),
+            crate::StreamingOutput::OggOpusMessagePack => {
+                Self::ogg_opus_message_pack(24000, channels)
+            }
             crate::StreamingOutput::Pcm => Ok(Self::pcm()),
             crate::StreamingOutput::PcmMessagePack => Ok(Self::pcm_message_pack()),
         }
     }
 
-    fn ogg_opus(sample_rate: usize) -> Result<Self> {
-        Ok(Self::OggOpus(kaudio::ogg_opus::Encoder::new(sample_rate)?))
+    fn ogg_opus(sample_rate: usize, channels: usize) -> Result<Self> {
+        Ok(Self::OggOpus(crate::ogg_opus::Encoder::new(sample_rate, channels)?))
     }
 
-    fn ogg_opus_message_pack(sample_rate: usize) -> Result<Self> {
-        Ok(Self::OggOpusMessagePack(kaudio::ogg_opus::Encoder::new(sample_rate)?))
+    fn ogg_opus_message_pack(sample_rate: usize, channels: usize) -> Result<Self> {
+        Ok(Self::OggOpusMessagePack(crate::ogg_opus::Encoder::new(sample_rate, channels)?))
     }
 
     fn pcm_message_pack() -> Self {
@@ -282,6 +391,26 @@ impl Encoder {
         Ok(buf)
     }
 
+    /// End the utterance: flush the buffered audio and close the Ogg logical stream.
+    pub fn finish(&mut self) -> Result<Option<Vec<u8>>> {
+        use serde::Serialize;
+        let buf = match self {
+            Self::OggOpus(oo) => Some(oo.finish()?),
+            Self::OggOpusMessagePack(oo) => {
+                let data = oo.finish()?;
+                let mut buf = vec![];
+                OutMsg::OggOpus { data }.serialize(
+                    &mut rmp_serde::Serializer::new(&mut buf)
+                        .with_human_readable()
+                        .with_struct_map(),
+                )?;
+                Some(buf)
+            }
+            Self::Pcm | Self::PcmMessagePack => None,
+        };
+        Ok(buf)
+    }
+
     #[allow(dead_code)]
     pub fn encode_msg(&mut self, msg: OutMsg) -> Result<Option<Vec<u8>>> {
         use serde::Serialize;
@@ -322,14 +451,22 @@ impl Model {
         } else {
             moshi::mimi::load(&tts.speaker_tokenizer_file, None, dev)?
         };
-        let vb_lm =
-            unsafe { VarBuilder::from_mmaped_safetensors(&[&tts.lm_model_file], dtype, dev)? };
+        let vb_lm = crate::snapshot::var_builder(
+            &tts.lm_model_file,
+            dtype,
+            dev,
+            config.snapshots.as_ref(),
+        )?;
+        // The speaker encoder runs in f32, read from the checkpoint rather than from a
+        // snapshot that may hold lower precision weights.
+        let vb_speaker =
+            unsafe { VarBuilder::from_mmaped_safetensors(&[&tts.lm_model_file], DType::F32, dev)? };
         let speaker_encoder = moshi::tts_streaming::SpeakerEncoder::new(
             speaker_tokenizer,
             tts.generation.speaker_cond_dim,
             tts.generation.speaker_cond_n_speakers,
             dtype,
-            vb_lm.to_dtype(DType::F32),
+            vb_speaker,
         )?;
         let text_tokenizer = sentencepiece::SentencePieceProcessor::open(&tts.text_tokenizer_file)
             .with_context(|| tts.text_tokenizer_file.clone())?;
@@ -348,6 +485,11 @@ impl Model {
         )?;
         let voice_dir = std::fs::canonicalize(&tts.voice_dir)
             .unwrap_or_else(|_| std::path::PathBuf::from(&tts.voice_dir));
+        let translator = match tts.translation.as_ref() {
+            None => None,
+            Some(t) => Some(std::sync::Arc::new(crate::translation::Translator::new(t)?)),
+        };
+        let voices = crate::voices::load(tts.voices.keys(), tts.voice_catalog.as_deref())?;
         Ok(Self {
             lm,
             audio_tokenizer,
@@ -359,14 +501,46 @@ impl Model {
             instance_name: config.instance_name.to_string(),
             log_dir: config.log_dir.clone().into(),
             voice_dir,
-            log_tokens: tts.log_tokens,
+            log_tokens: tts.log_tokens && crate::privacy::allow_token_dumps("log_tokens"),
+            spill_threshold_bytes: tts.spill_threshold_mb << 20,
+            spill_dir: tts.spill_dir.as_ref().map(std::path::PathBuf::from),
+            translator,
+            voices,
+            previews: std::sync::RwLock::new(std::collections::HashMap::new()),
+            encode_pool: crate::tts_encode::EncodePool::new(&tts.encode_pool)?,
+            chunking: tts.chunking.clone(),
+            steps: crate::compute::StepPool::new(&tts.compute, "tts")?,
+            cache: tts.cache.as_ref().map(crate::tts_cache::TtsCache::new),
+            jobs: tts.jobs.as_ref().map(crate::tts_jobs::JobStore::new),
             mutex: tokio::sync::Mutex::new(()),
         })
     }
 
+    /// The translation to apply to the input text, `None` when both languages match or are
+    /// not set.
+    pub fn translation(
+        &self,
+        input_language: Option<&str>,
+        speak_language: Option<&str>,
+    ) -> Result<Option<crate::translation::Translation>> {
+        let Some((source, target)) =
+            crate::translation::language_pair(input_language, speak_language)
+        else {
+            return Ok(None);
+        };
+        match self.translator.as_ref() {
+            None => anyhow::bail!("translation from {source} to {target} requested but no translation backend is configured"),
+            Some(t) => Ok(Some(crate::translation::Translation {
+                translator: t.clone(),
+                source,
+                target,
+            })),
+        }
+    }
+
     pub async fn handle_socket(
         &self,
-        socket: ws::WebSocket,
+        socket: crate::limits::LimitedSocket,
         query: crate::TtsStreamingQuery,
     ) -> Result<()> {
         use futures_util::{SinkExt, StreamExt};
@@ -423,7 +597,11 @@ impl Model {
         };
 
         let mut last_text_token = config.text_start_token;
-        let ca_src = self.voice_ca_src(query.voice.as_ref(), query.voices.as_ref())?;
+        let ca_src = self.voice_ca_src(
+            query.voice.as_ref(),
+            query.voices.as_ref(),
+            query.voice_weights.as_ref(),
+        )?;
         let ca_src = if query.cfg_alpha.is_some() {
             let lp = self.speaker_encoder.empty()?;
             Tensor::cat(&[ca_src, lp], 0)?
@@ -442,13 +620,39 @@ impl Model {
         );
         let text_tokenizer = self.text_tokenizer.clone();
 
+        let translation =
+            self.translation(query.input_language.as_deref(), query.speak_language.as_deref())?;
         let (mut sender, mut receiver) = socket.split();
         let (in_tx, in_rx) = std::sync::mpsc::channel();
         let (out_tx, mut out_rx) = tokio::sync::mpsc::unbounded_channel();
         let text_bos_token = state.config().text_bos_token;
         let text_tokenizer_recv = self.text_tokenizer.clone();
-        let recv_loop = tokio::task::spawn(async move {
+        // Weak so that the output closes, and the send loop ends, with the audio loop.
+        let out_tx_recv = out_tx.downgrade();
+        let format = query.format;
+        let envelope_hop_ms = query.envelope_hop_ms.filter(|&v| v > 0);
+        let mut scope = crate::task_scope::TaskScope::new();
+        scope.spawn("recv_loop", async move {
             let mut inserted_bos = false;
+            let mut push_text = |text: &str| -> Result<()> {
+                for word in text.split(' ') {
+                    if word.is_empty() {
+                        continue;
+                    }
+                    let mut word_tokens: Vec<_> =
+                        text_tokenizer_recv.encode(word)?.into_iter().map(|v| v.id).collect();
+                    if !inserted_bos {
+                        inserted_bos = true;
+                        word_tokens.insert(0, text_bos_token)
+                    }
+                    if let Some(tx) = log_tx2.as_ref() {
+                        tx.send_text(word.to_string());
+                    }
+                    in_tx.send(Some(word_tokens))?;
+                }
+                Ok(())
+            };
+            let mut sentences = crate::translation::SentenceBuffer::default();
             while let Some(msg) = receiver.next().await {
                 let msg = match msg? {
                     ws::Message::Text(x) => {
@@ -467,6 +671,10 @@ impl Model {
                         // the connection.
                         if x.as_ref() == b"\0" {
                             log::info!("received end of stream");
+                            if let (Some(tr), Some(sentence)) = (&translation, sentences.flush()) {
+                                let text = translate_sentence(tr, format, &out_tx_recv, sentence);
+                                push_text(&text.await?)?;
+                            }
                             in_tx.send(None)?;
                         }
                         continue;
@@ -477,20 +685,14 @@ impl Model {
                     ws::Message::Close(_) => break,
                 };
 
-                for word in msg.split(' ') {
-                    if word.is_empty() {
-                        continue;
-                    }
-                    let mut word_tokens: Vec<_> =
-                        text_tokenizer_recv.encode(word)?.into_iter().map(|v| v.id).collect();
-                    if !inserted_bos {
-                        inserted_bos = true;
-                        word_tokens.insert(0, text_bos_token)
-                    }
-                    if let Some(tx) = log_tx2.as_ref() {
-                        tx.send_text(word.to_string());
+                match &translation {
+                    None => push_text(&msg)?,
+                    Some(tr) => {
+                        if let Some(sentence) = sentences.push(&msg) {
+                            let text = translate_sentence(tr, format, &out_tx_recv, sentence);
+                            push_text(&text.await?)?;
+                        }
                     }
-                    in_tx.send(Some(word_tokens))?;
                 }
             }
             tracing::info!("recv loop exited - connection closed");
@@ -502,131 +704,153 @@ impl Model {
         let state_cfg = state.config().clone();
         let audio_codebooks = state.audio_codebooks();
         let conditions = conditions.clone();
-        let format = query.format;
+        let steps = self.steps.clone();
         enum AudioMessage {
             Tokens(Option<Vec<u32>>, u32, usize),
             Word(WordWithTimestamps),
         }
         let (audio_token_tx, audio_token_rx) = std::sync::mpsc::sync_channel::<AudioMessage>(100);
         let log_tx_audio = log_tx.clone();
-        let _audio_processing_loop = tokio::task::spawn_blocking(move || {
-            let err = (|| {
-                let mut encoder = Encoder::new(format)?;
-                if let Some(header) = encoder.header()? {
-                    out_tx.send(header)?
-                }
-                let text_audio_delay_in_tokens = state_cfg.text_audio_delay_in_tokens;
-                let acoustic_delay = state_cfg.acoustic_delay;
-
-                for msg in audio_token_rx {
-                    match msg {
-                        AudioMessage::Word(wwts) => {
-                            if let Some(oo) = encoder.encode_word(wwts)? {
-                                out_tx.send(oo)?;
-                            }
-                        }
-                        AudioMessage::Tokens(audio_tokens_vec, last_text_token, step_idx) => {
-                            if let Some(audio_tokens_vec) = audio_tokens_vec {
-                                let cb = audio_tokens_vec.len();
-                                // Using Tensor::from_vec is faster.
-                                let audio_tokens = candle::Tensor::from_vec(
-                                    audio_tokens_vec.clone(),
-                                    (1, cb, 1),
-                                    &device,
-                                )?;
-                                if step_idx >= text_audio_delay_in_tokens + acoustic_delay {
-                                    let pcm = audio_tokenizer
-                                        .decode_step(&audio_tokens.into(), &().into())?;
-                                    if let Some(pcm) = pcm.as_option() {
-                                        let pcm = pcm.flatten_all()?.to_vec1::<f32>()?;
-                                        let oo = encoder.encode(&pcm)?;
-                                        out_tx.send(oo)?;
+        let layout = query.layout()?;
+        let mut stretcher = crate::tts_speed::Stretcher::new(query.speed()?);
+        let encoder = Encoder::new(format, layout.channels())?;
+        if let Some(header) = encoder.header()? {
+            out_tx.send(header)?
+        }
+        let encoder = self.encode_pool.stream(encoder, layout, out_tx);
+        scope.spawn_blocking("tts_audio", move |_| {
+            let mut envelope = envelope_hop_ms.map(|v| (v, EnvelopeMeter::new(v, 24000)));
+            let text_audio_delay_in_tokens = state_cfg.text_audio_delay_in_tokens;
+            let acoustic_delay = state_cfg.acoustic_delay;
+
+            for msg in audio_token_rx {
+                match msg {
+                    AudioMessage::Word(mut wwts) => {
+                        wwts.start_s = stretcher.time(wwts.start_s);
+                        wwts.stop_s = stretcher.time(wwts.stop_s);
+                        encoder.send_word(wwts)?
+                    }
+                    AudioMessage::Tokens(audio_tokens_vec, last_text_token, step_idx) => {
+                        if let Some(audio_tokens_vec) = audio_tokens_vec {
+                            let cb = audio_tokens_vec.len();
+                            // Using Tensor::from_vec is faster.
+                            let audio_tokens = candle::Tensor::from_vec(
+                                audio_tokens_vec.clone(),
+                                (1, cb, 1),
+                                &device,
+                            )?;
+                            if step_idx >= text_audio_delay_in_tokens + acoustic_delay {
+                                let pcm = audio_tokenizer
+                                    .decode_step(&audio_tokens.into(), &().into())?;
+                                if let Some(pcm) = pcm.as_option() {
+                                    let pcm = pcm.flatten_all()?.to_vec1::<f32>()?;
+                                    let pcm = stretcher.push(&pcm);
+                                    // Sent ahead of the audio it describes.
+                                    if let Some((hop_ms, meter)) = envelope.as_mut() {
+                                        let rms = meter.push(&pcm);
+                                        if !rms.is_empty() {
+                                            let msg = OutMsg::Envelope { rms, hop_ms: *hop_ms };
+                                            if let Some(msg) =
+                                                Encoder::message_pack(format, &msg)?
+                                            {
+                                                encoder.send(msg)?;
+                                            }
+                                        }
                                     }
-                                    if let Some(tx) = log_tx_audio.as_ref() {
-                                        tx.send_slice(last_text_token, audio_tokens_vec)
+                                    // The stretcher holds back the start of the audio.
+                                    if !pcm.is_empty() {
+                                        encoder.encode(pcm)?;
                                     }
-                                } else if let Some(tx) = log_tx_audio.as_ref() {
+                                }
+                                if let Some(tx) = log_tx_audio.as_ref() {
                                     tx.send_slice(last_text_token, audio_tokens_vec)
                                 }
                             } else if let Some(tx) = log_tx_audio.as_ref() {
-                                let cb = audio_codebooks;
-                                let audio_tokens_vec = vec![0u32; cb];
                                 tx.send_slice(last_text_token, audio_tokens_vec)
                             }
+                        } else if let Some(tx) = log_tx_audio.as_ref() {
+                            let cb = audio_codebooks;
+                            let audio_tokens_vec = vec![0u32; cb];
+                            tx.send_slice(last_text_token, audio_tokens_vec)
                         }
                     }
                 }
-                Ok::<(), anyhow::Error>(())
-            })();
-            if let Err(err) = err {
-                tracing::error!(?err, "audio processing loop exited with error");
             }
+            let pcm = stretcher.finish();
+            if !pcm.is_empty() {
+                encoder.encode(pcm)?;
+            }
+            encoder.finish()?;
+            Ok::<(), anyhow::Error>(())
         });
 
-        let process_loop = tokio::task::spawn_blocking(move || {
-            let err = (|| {
-                tracing::info!("starting the inference loop");
-                let text_audio_delay_in_tokens = state.config().text_audio_delay_in_tokens;
-                let text_eop_token = state.config().text_eop_token;
-                let text_pad_token = state.config().text_pad_token;
-                let extra_steps = state.config().extra_steps;
-
-                let mut token_idx = 0;
-                let mut step_past_last_token = 0;
-                // Start with an empty list to trigger the first bos.
-                let mut word_tokens = Some(vec![]);
-
-                let mut last_epad_index = 0usize;
-                for step_idx in 0..max_seq_len {
-                    let allowed_tokens = match word_tokens.as_ref() {
-                        None => {
-                            step_past_last_token += 1;
-                            if step_past_last_token > extra_steps + text_audio_delay_in_tokens {
-                                break;
-                            }
-                            moshi::tts_streaming::AllowedTokens::Pad
-                        }
-                        Some(word_tokens) => match word_tokens.get(token_idx) {
-                            None => moshi::tts_streaming::AllowedTokens::PadOrEpad,
-                            Some(id) => moshi::tts_streaming::AllowedTokens::Text(*id),
-                        },
-                    };
-                    last_text_token =
-                        state.step(last_text_token, allowed_tokens, conditions.as_ref())?;
-                    if last_text_token == text_eop_token {
-                        if let Some(vs) = word_tokens {
-                            if let Ok(text) = text_tokenizer.decode_piece_ids(&vs) {
-                                let start_s = last_epad_index as f64 / 12.5;
-                                let stop_s = step_idx as f64 / 12.5;
-                                let wwts = WordWithTimestamps { text, start_s, stop_s };
-                                audio_token_tx.send(AudioMessage::Word(wwts))?;
-                            }
+        scope.spawn_blocking("tts_inference", move |token| {
+            tracing::info!("starting the inference loop");
+            let text_audio_delay_in_tokens = state.config().text_audio_delay_in_tokens;
+            let text_eop_token = state.config().text_eop_token;
+            let text_pad_token = state.config().text_pad_token;
+            let extra_steps = state.config().extra_steps;
+
+            let mut token_idx = 0;
+            let mut step_past_last_token = 0;
+            // Start with an empty list to trigger the first bos.
+            let mut word_tokens = Some(vec![]);
+
+            let mut last_epad_index = 0usize;
+            for step_idx in 0..max_seq_len {
+                if token.is_cancelled() {
+                    break;
+                }
+                let allowed_tokens = match word_tokens.as_ref() {
+                    None => {
+                        step_past_last_token += 1;
+                        if step_past_last_token > extra_steps + text_audio_delay_in_tokens {
+                            break;
                         }
-                        last_epad_index = step_idx;
-                        word_tokens = in_rx.recv()?;
-                        if word_tokens.is_none() {
-                            // We teacher force a pad instead of tho eop for the last word.
-                            state.overwrite_last_text_token(text_pad_token)?;
+                        moshi::tts_streaming::AllowedTokens::Pad
+                    }
+                    Some(word_tokens) => match word_tokens.get(token_idx) {
+                        None => moshi::tts_streaming::AllowedTokens::PadOrEpad,
+                        Some(id) => moshi::tts_streaming::AllowedTokens::Text(*id),
+                    },
+                };
+                last_text_token = {
+                    let _step =
+                        tracing::span!(tracing::Level::TRACE, crate::profiler::STEP).entered();
+                    steps.install(|| {
+                        state.step(last_text_token, allowed_tokens, conditions.as_ref())
+                    })?
+                };
+                if last_text_token == text_eop_token {
+                    if let Some(vs) = word_tokens {
+                        if let Ok(text) = text_tokenizer.decode_piece_ids(&vs) {
+                            let start_s = last_epad_index as f64 / 12.5;
+                            let stop_s = step_idx as f64 / 12.5;
+                            let wwts = WordWithTimestamps { text, start_s, stop_s };
+                            audio_token_tx.send(AudioMessage::Word(wwts))?;
                         }
-                        token_idx = 0;
-                    } else if last_text_token != text_pad_token {
-                        token_idx += 1;
                     }
-                    let last_audio_tokens = state.last_audio_tokens();
-                    audio_token_tx.send(AudioMessage::Tokens(
-                        last_audio_tokens,
-                        last_text_token,
-                        step_idx,
-                    ))?;
+                    last_epad_index = step_idx;
+                    word_tokens = in_rx.recv()?;
+                    if word_tokens.is_none() {
+                        // We teacher force a pad instead of tho eop for the last word.
+                        state.overwrite_last_text_token(text_pad_token)?;
+                    }
+                    token_idx = 0;
+                } else if last_text_token != text_pad_token {
+                    token_idx += 1;
                 }
-                Ok::<(), anyhow::Error>(())
-            })();
-            match err {
-                Err(err) => tracing::error!(?err, "process loop exited"),
-                Ok(()) => tracing::info!("process loop exited"),
+                let last_audio_tokens = state.last_audio_tokens();
+                audio_token_tx.send(AudioMessage::Tokens(
+                    last_audio_tokens,
+                    last_text_token,
+                    step_idx,
+                ))?;
             }
+            tracing::info!("process loop exited");
+            Ok::<(), anyhow::Error>(())
         });
-        let send_loop = tokio::task::spawn(async move {
+        scope.spawn("send_loop", async move {
             use tokio::time::{timeout, Duration};
             loop {
                 // The recv method is cancel-safe so can be wrapped in a timeout.
@@ -650,34 +874,16 @@ impl Model {
             drop(sender);
             Ok::<(), anyhow::Error>(())
         });
-        // select should ensure that all the threads get aborted on timeout.
-        // TODO(laurent): this actually doesn't work as expected, and the background threads don't
-        // appear to be cancelled properly (at least the websocket connection remains open.
-        let sleep = tokio::time::sleep(std::time::Duration::from_secs(360));
-        tokio::pin!(sleep);
-
-        let mut recv_handle = recv_loop;
-        let mut process_handle = process_loop;
-        let mut send_handle = send_loop;
-
-        tokio::select! {
-            _ = &mut sleep => {
-                tracing::error!("reached timeout");
-            }
-            _ = &mut recv_handle => {}
-            _ = &mut process_handle => {}
-            _ = &mut send_handle => {}
-        }
+        // When the generation ends, the audio loop finishes the stream and the send loop ends
+        // once it has flushed it. A client that goes away ends the recv or send loop, and the
+        // model loops stop at their next step.
+        scope
+            .run(Some(std::time::Duration::from_secs(360)), std::time::Duration::from_millis(500))
+            .await;
         tracing::info!("exiting handle-socket");
 
-        // Wait briefly for aborted tasks to clean up and ensure logs are saved
+        // Ensure logs are saved.
         let _ = tokio::time::timeout(std::time::Duration::from_millis(500), async {
-            recv_handle.abort();
-            process_handle.abort();
-            send_handle.abort();
-            let _ = recv_handle.await;
-            let _ = process_handle.await;
-            let _ = send_handle.await;
             drop(log_tx); // Close log channel
             if let Some(handle) = logger_handle {
                 let _ = handle.await;
@@ -689,81 +895,153 @@ impl Model {
         Ok(())
     }
 
+    /// The voice catalog, `preview` is set for the voices that have a cached clip.
+    pub fn voices(&self) -> Vec<crate::voices::VoiceInfo> {
+        let previews = self.previews.read().unwrap_or_else(|e| e.into_inner());
+        let mut voices = self.voices.clone();
+        for v in voices.iter_mut() {
+            v.preview = previews.contains_key(&v.name);
+        }
+        voices
+    }
+
+    pub fn preview(&self, voice: &str) -> Option<Vec<u8>> {
+        let previews = self.previews.read().unwrap_or_else(|e| e.into_inner());
+        previews.get(voice).cloned()
+    }
+
+    /// Generate and cache a clip of about `duration_s` of `text` for each catalog voice. Voices
+    /// that fail to load are skipped with a warning.
+    pub fn generate_previews(&self, text: &str, duration_s: f64) {
+        let text: Vec<String> = text.split_whitespace().map(|w| w.to_string()).collect();
+        let max_seq_len =
+            self.tts_config.text_audio_delay_in_tokens + (duration_s * 12.5).ceil() as usize;
+        for voice in self.voices.iter() {
+            let query = crate::TtsQuery {
+                text: crate::privacy::Sensitive::new(text.clone()),
+                seed: 42,
+                temperature: 0.6,
+                top_k: 250,
+                voice: Some(voice.name.clone()),
+                voices: None,
+                voice_weights: None,
+                max_seq_len: Some(max_seq_len),
+                chunk_max_tokens: None,
+                return_timestamps: None,
+                cfg_alpha: None,
+                input_language: None,
+                speak_language: None,
+                channels: None,
+                pan: None,
+                speed: None,
+            };
+            match self.run(&query).and_then(|(wav, _, _)| wav.into_vec()) {
+                Ok(wav) => {
+                    let mut previews = self.previews.write().unwrap_or_else(|e| e.into_inner());
+                    previews.insert(voice.name.clone(), wav);
+                }
+                Err(err) => tracing::warn!(?err, voice = voice.name, "cannot generate preview"),
+            }
+        }
+    }
+
+    /// Resolve `name` or `name+delay` to a file in the voice directory and the start of the
+    /// conditioning segment in seconds.
+    fn voice_path(&self, voice: &str) -> Result<(std::path::PathBuf, f64)> {
+        let voice_dir = &self.voice_dir;
+        let (voice, speaker_cond_start_s) = match voice.split_once('+') {
+            None => (voice, 0.0),
+            Some((v, delay)) => {
+                let delay = match delay.parse::<f64>() {
+                    Ok(delay) => delay,
+                    Err(_) => anyhow::bail!(ApiError::InvalidVoice(format!(
+                        "unexpected format for delay in {voice}: '{delay}'"
+                    ))),
+                };
+                (v, delay)
+            }
+        };
+        let unknown = || ApiError::InvalidVoice(format!("unknown voice file '{voice}'"));
+        let path = std::fs::canonicalize(voice_dir.join(voice)).map_err(|_| unknown())?;
+        if !path.starts_with(voice_dir) {
+            tracing::error!(?voice_dir, ?path, "unable to access voice file");
+            anyhow::bail!(unknown())
+        }
+        Ok((path, speaker_cond_start_s))
+    }
+
+    fn single_voice_ca_src(&self, voice: &String) -> Result<Tensor> {
+        if let Some(v) = self.ca_srcs.get(voice) {
+            return Ok(v.clone());
+        }
+        let (path, speaker_cond_start_s) = self.voice_path(voice)?;
+        let cache_key = format!("{}|{speaker_cond_start_s}", path.to_string_lossy());
+        if let Ok(mut cache) = self.dynamic_ca_srcs.lock() {
+            if let Some(v) = cache.get(&cache_key) {
+                return Ok(v);
+            }
+        }
+        let pcm = speaker_pcm(
+            self.speaker_encoder.sample_rate(),
+            speaker_cond_start_s,
+            self.tts_config.speaker_cond_duration_s,
+            path,
+            self.lm.device(),
+        )?;
+        let ca_src = self.speaker_encoder.encode(&[pcm.clone(), pcm])?;
+        if let Ok(mut cache) = self.dynamic_ca_srcs.lock() {
+            cache.insert(cache_key, ca_src.clone());
+        }
+        Ok(ca_src)
+    }
+
+    /// Interpolate the speaker embeddings of `voices`, each voice is encoded on its own as for
+    /// a single `voice` and the results are averaged with the normalized `weights`.
+    fn mixed_voice_ca_src(&self, voices: &[String], weights: &[f32]) -> Result<Tensor> {
+        let weights = normalize_voice_weights(voices.len(), weights)?;
+        let mut mixed: Option<Tensor> = None;
+        let mut dtype = candle::DType::F32;
+        for (voice, weight) in voices.iter().zip(weights) {
+            if weight == 0. {
+                continue;
+            }
+            let ca_src = self.single_voice_ca_src(voice)?;
+            dtype = ca_src.dtype();
+            let ca_src = (ca_src.to_dtype(candle::DType::F32)? * weight as f64)?;
+            mixed = Some(match mixed {
+                None => ca_src,
+                Some(mixed) => (mixed + ca_src)?,
+            });
+        }
+        match mixed {
+            // normalize_voice_weights ensures that at least one weight is positive.
+            None => anyhow::bail!("voice_weights are all zero"),
+            Some(mixed) => Ok(mixed.to_dtype(dtype)?),
+        }
+    }
+
     pub fn voice_ca_src(
         &self,
         voice: Option<&String>,
         voices: Option<&Vec<String>>,
+        voice_weights: Option<&Vec<f32>>,
     ) -> Result<Tensor> {
-        match (voice, voices) {
-            (None, None) => anyhow::bail!("either voice or voices has to be set"),
-            (Some(_), Some(_)) => {
-                anyhow::bail!("voice and voices should not be set at the same time")
+        match (voice, voices, voice_weights) {
+            (None, None, _) => {
+                anyhow::bail!(ApiError::InvalidVoice("either voice or voices has to be set".into()))
             }
-            (Some(voice), None) => match self.ca_srcs.get(voice) {
-                None => {
-                    let voice_dir = &self.voice_dir;
-                    let mut pcms = vec![];
-                    let (voice, speaker_cond_start_s) = match voice.split_once('+') {
-                        None => (voice.as_str(), 0.0),
-                        Some((v, delay)) => {
-                            let delay = match delay.parse::<f64>() {
-                                Ok(delay) => delay,
-                                Err(_) => anyhow::bail!(
-                                    "unexpected format for delay in {voice}: '{delay}'"
-                                ),
-                            };
-                            (v, delay)
-                        }
-                    };
-                    let path = std::fs::canonicalize(voice_dir.join(voice))?;
-                    if !path.starts_with(voice_dir) {
-                        tracing::error!(?voice_dir, ?path, "unable to access voice file");
-                        anyhow::bail!("unknown voice file '{voice}'")
-                    }
-                    let cache_key = format!("{}|{speaker_cond_start_s}", path.to_string_lossy());
-                    if let Ok(mut cache) = self.dynamic_ca_srcs.lock() {
-                        if let Some(v) = cache.get(&cache_key) {
-                            return Ok(v);
-                        }
-                    }
-                    let pcm = speaker_pcm(
-                        self.speaker_encoder.sample_rate(),
-                        speaker_cond_start_s,
-                        self.tts_config.speaker_cond_duration_s,
-                        path,
-                        self.lm.device(),
-                    )?;
-                    pcms.push(pcm.clone());
-                    pcms.push(pcm);
-                    let ca_src = self.speaker_encoder.encode(&pcms)?;
-                    if let Ok(mut cache) = self.dynamic_ca_srcs.lock() {
-                        cache.insert(cache_key, ca_src.clone());
-                    }
-                    Ok(ca_src)
-                }
-                Some(v) => Ok(v.clone()),
-            },
-            (None, Some(voices)) => {
-                let voice_dir = &self.voice_dir;
+            (Some(_), Some(_), _) => anyhow::bail!(ApiError::InvalidVoice(
+                "voice and voices should not be set at the same time".into()
+            )),
+            (Some(_), None, Some(_)) => anyhow::bail!(ApiError::InvalidVoice(
+                "voice_weights can only be used together with voices".into()
+            )),
+            (Some(voice), None, None) => self.single_voice_ca_src(voice),
+            (None, Some(voices), Some(weights)) => self.mixed_voice_ca_src(voices, weights),
+            (None, Some(voices), None) => {
                 let mut pcms = vec![];
                 for voice in voices.iter() {
-                    let (voice, speaker_cond_start_s) = match voice.split_once('+') {
-                        None => (voice.as_str(), 0.0),
-                        Some((v, delay)) => {
-                            let delay = match delay.parse::<f64>() {
-                                Ok(delay) => delay,
-                                Err(_) => anyhow::bail!(
-                                    "unexpected format for delay in {voice}: '{delay}'"
-                                ),
-                            };
-                            (v, delay)
-                        }
-                    };
-                    let path = std::fs::canonicalize(voice_dir.join(voice))?;
-                    if !path.starts_with(voice_dir) {
-                        tracing::error!(?voice_dir, ?path, "unable to access voice file");
-                        anyhow::bail!("unknown voice file '{voice}'")
-                    }
+                    let (path, speaker_cond_start_s) = self.voice_path(voice)?;
                     let pcm = speaker_pcm(
                         self.speaker_encoder.sample_rate(),
                         speaker_cond_start_s,
@@ -778,143 +1056,114 @@ impl Model {
         }
     }
 
-    pub fn run(&self, query: &crate::TtsQuery) -> Result<(Vec<u8>, Vec<WordWithTimestamps>)> {
+    /// Synthesize `query` to a WAV file, kept in memory unless it is above
+    /// `spill_threshold_mb`.
+    pub fn run(
+        &self,
+        query: &crate::TtsQuery,
+    ) -> Result<(crate::spill::Wav, Vec<WordWithTimestamps>, Vec<TextChunk>)> {
+        let layout = query.layout()?;
+        let mut stretcher = crate::tts_speed::Stretcher::new(query.speed()?);
         let config = &self.tts_config;
-        let text_audio_delay_in_tokens = config.text_audio_delay_in_tokens;
         let text_bos_token = config.text_bos_token;
-        let text_eos_token = config.text_eos_token;
-        let text_eop_token = config.text_eop_token;
-        let text_pad_token = config.text_pad_token;
-        let mut prompt = moshi::tts_streaming::tokenize_prompt(
+        let prompt = moshi::tts_streaming::tokenize_prompt(
             &query.text,
             text_bos_token,
-            text_eos_token,
+            config.text_eos_token,
             |s| self.text_tokenizer.encode(s).map(|v| v.into_iter().map(|v| v.id).collect()),
         )?;
-        // Insert an empty word to start with and trigger the first bos.
-        prompt.insert(0, (vec![], Speaker::Other));
-        tracing::debug!(?prompt, "starting tts");
-        let mut transcript = vec![];
+        let prompt: Vec<Vec<u32>> = prompt.into_iter().map(|(tokens, _)| tokens).collect();
+        let words: Vec<String> = prompt
+            .iter()
+            .map(|tokens| self.text_tokenizer.decode_piece_ids(tokens).unwrap_or_default())
+            .collect();
+        let words: Vec<(&str, usize)> =
+            words.iter().zip(prompt.iter()).map(|(w, t)| (w.as_str(), t.len())).collect();
+        let chunking = crate::tts_preprocess::ChunkingConfig {
+            max_tokens: query.chunk_max_tokens.unwrap_or(self.chunking.max_tokens),
+            ..self.chunking.clone()
+        };
+        let chunks = crate::tts_preprocess::chunk_words(&words, &chunking);
+        tracing::debug!(
+            prompt = ?crate::privacy::Sensitive::new(&prompt),
+            chunks = chunks.len(),
+            "starting tts"
+        );
         let (log_tx, log_rx) = if self.log_tokens {
             let (tx, rx) = logger();
             (Some(tx), Some(rx))
         } else {
             (None, None)
         };
-        let all_audio_tokens = {
-            let start_time = std::time::Instant::now();
-            let sampling = if query.temperature <= 0. || query.top_k <= 1 {
-                candle_transformers::generation::Sampling::ArgMax
-            } else {
-                candle_transformers::generation::Sampling::TopK {
-                    k: query.top_k,
-                    temperature: query.temperature,
-                }
-            };
-
-            let text_lp = candle_transformers::generation::LogitsProcessor::from_sampling(
-                query.seed,
-                sampling.clone(),
-            );
-            let audio_lp = candle_transformers::generation::LogitsProcessor::from_sampling(
-                query.seed, sampling,
-            );
-            let conditions = match self.lm.condition_provider() {
-                None => None,
-                Some(cp) => {
-                    let conditions = cp.condition_lut("control", "also_good")?;
-                    tracing::info!(?conditions, "generated conditions");
-                    Some(conditions)
-                }
-            };
-
-            let mut last_text_token = config.text_start_token;
-            let ca_src = self.voice_ca_src(query.voice.as_ref(), query.voices.as_ref())?;
-            let ca_src = if query.cfg_alpha.is_some() {
-                let lp = self.speaker_encoder.empty()?;
-                Tensor::cat(&[ca_src, lp], 0)?
-            } else {
-                ca_src
-            };
-            let max_seq_len = query.max_seq_len.unwrap_or(2048);
-            let config = config.clone();
-            let mut state = moshi::tts_streaming::State::new(
-                self.lm.clone(),
-                Some(moshi::transformer::CaSrc::Tokens(ca_src)),
-                max_seq_len,
-                audio_lp,
-                text_lp,
-                query.cfg_alpha,
-                config.clone(),
-            );
-            let mut all_audio_tokens = vec![];
-            tracing::info!("starting the inference loop");
-            let mut word_idx = 0;
-            let mut token_idx = 0;
-            let mut step_past_last_token = 0;
-            let mut last_epad_index = 0usize;
-            for step_idx in 0..max_seq_len {
-                let word_tokens = prompt.get(word_idx);
-                let allowed_tokens = match word_tokens.as_ref() {
-                    None => {
-                        step_past_last_token += 1;
-                        if step_past_last_token > 5 + text_audio_delay_in_tokens {
-                            break;
-                        }
-                        moshi::tts_streaming::AllowedTokens::Pad
-                    }
-                    Some(word_tokens) => match word_tokens.0.get(token_idx) {
-                        None => moshi::tts_streaming::AllowedTokens::PadOrEpad,
-                        Some(id) => moshi::tts_streaming::AllowedTokens::Text(*id),
-                    },
-                };
-                last_text_token =
-                    state.step(last_text_token, allowed_tokens, conditions.as_ref())?;
-                if last_text_token == text_eop_token {
-                    if let Some(vs) = word_tokens {
-                        if let Ok(text) = self.text_tokenizer.decode_piece_ids(&vs.0) {
-                            let start_s = last_epad_index as f64 / 12.5;
-                            let stop_s = step_idx as f64 / 12.5;
-                            transcript.push(WordWithTimestamps { text, start_s, stop_s })
-                        }
-                    }
-                    last_epad_index = step_idx;
-                    word_idx += 1;
-                    token_idx = 0;
-                } else if last_text_token != text_pad_token {
-                    token_idx += 1;
-                }
-                if let Some(audio_tokens_vec) = state.last_audio_tokens() {
-                    let cb = audio_tokens_vec.len();
-                    let audio_tokens = candle::Tensor::from_vec(
-                        audio_tokens_vec.clone(),
-                        (1, cb, 1),
-                        state.device(),
-                    )?;
-                    if let Some(tx) = log_tx.as_ref() {
-                        if step_idx >= text_audio_delay_in_tokens {
-                            all_audio_tokens.push(audio_tokens)
-                        }
-                        tx.send_slice(last_text_token, audio_tokens_vec)
-                    } else if step_idx >= text_audio_delay_in_tokens {
-                        all_audio_tokens.push(audio_tokens)
-                    }
-                } else if let Some(tx) = log_tx.as_ref() {
-                    let cb = state.audio_codebooks();
-                    let audio_tokens_vec = vec![0u32; cb];
-                    tx.send_slice(last_text_token, audio_tokens_vec)
-                }
+        let start_time = std::time::Instant::now();
+        let conditions = match self.lm.condition_provider() {
+            None => None,
+            Some(cp) => {
+                let conditions = cp.condition_lut("control", "also_good")?;
+                tracing::info!(?conditions, "generated conditions");
+                Some(conditions)
             }
-            let dt = start_time.elapsed().as_secs_f64();
-            let total = all_audio_tokens.len();
-            tracing::info!(
-                "processed {total} total steps in {dt:.2}s, {:.2} steps/s",
-                total as f64 / dt
-            );
-            Tensor::cat(&all_audio_tokens, candle::D::Minus1)?
         };
+        let ca_src = self.voice_ca_src(
+            query.voice.as_ref(),
+            query.voices.as_ref(),
+            query.voice_weights.as_ref(),
+        )?;
+        let ca_src = if query.cfg_alpha.is_some() {
+            let lp = self.speaker_encoder.empty()?;
+            Tensor::cat(&[ca_src, lp], 0)?
+        } else {
+            ca_src
+        };
+        let mut transcript = vec![];
+        let mut text_chunks = vec![];
+        let mut all_audio_tokens = vec![];
+        for (idx, chunk) in chunks.iter().enumerate() {
+            let mut chunk_prompt = prompt[chunk.context.start..chunk.words.end].to_vec();
+            // Each chunk is generated as a text of its own, starting with a bos.
+            if let Some(first) = chunk_prompt.first_mut() {
+                if first.first() != Some(&text_bos_token) {
+                    first.insert(0, text_bos_token)
+                }
+            }
+            // Insert an empty word to start with and trigger the first bos.
+            chunk_prompt.insert(0, vec![]);
+            let (audio_tokens, words) = self.generate(
+                query,
+                &chunk_prompt,
+                chunk.context.len(),
+                &ca_src,
+                conditions.as_ref(),
+                query.seed.wrapping_add(idx as u64),
+                log_tx.as_ref(),
+            )?;
+            let start_s = all_audio_tokens.len() as f64 / 12.5;
+            let stop_s = start_s + audio_tokens.len() as f64 / 12.5;
+            let time = |t: f64| stretcher.time(t);
+            text_chunks.push(TextChunk {
+                first_word: transcript.len(),
+                start_s: time(start_s),
+                stop_s: time(stop_s),
+            });
+            transcript.extend(words.into_iter().map(|w| WordWithTimestamps {
+                text: w.text,
+                start_s: time(start_s + w.start_s),
+                stop_s: time(start_s + w.stop_s),
+            }));
+            all_audio_tokens.extend(audio_tokens);
+        }
+        let dt = start_time.elapsed().as_secs_f64();
+        let total = all_audio_tokens.len();
+        tracing::info!(
+            chunks = chunks.len(),
+            "processed {total} total steps in {dt:.2}s, {:.2} steps/s",
+            total as f64 / dt
+        );
+        let all_audio_tokens = Tensor::cat(&all_audio_tokens, candle::D::Minus1)?;
         let (_one, _codebooks, total_steps) = all_audio_tokens.dims3()?;
-        let mut all_pcm_chunks = vec![];
+        let spill_dir = self.spill_dir.as_deref();
+        let mut wav =
+            crate::spill::WavWriter::new(24_000, layout, self.spill_threshold_bytes, spill_dir);
         let chunk_by = 25;
         let mut mimi = self.audio_tokenizer.clone();
         for start_step in (0..total_steps).step_by(chunk_by) {
@@ -924,9 +1173,10 @@ impl Model {
                 &().into(),
             )?;
             if let Some(pcm) = pcm.as_option() {
-                all_pcm_chunks.push(pcm.clone())
+                wav.push(&stretcher.push(&pcm.i((0, 0))?.to_vec1::<f32>()?))?
             }
         }
+        wav.push(&stretcher.finish())?;
         // Close the log stream so that log_rx.save does not block.
         std::mem::drop(log_tx);
         if let Some(log_rx) = log_rx {
@@ -934,12 +1184,117 @@ impl Model {
                 tracing::error!(?err, "cannot save logs")
             };
         }
+        Ok((wav.finish()?, transcript, text_chunks))
+    }
 
-        let pcm = Tensor::cat(&all_pcm_chunks, 2)?;
-        let pcm = pcm.i((0, 0))?.to_vec1::<f32>()?;
-        let mut wav = vec![];
-        moshi::wav::write_pcm_as_wav(&mut wav, &pcm, 24_000)?;
-        Ok((wav, transcript))
+    /// Generate the audio tokens of the words of `prompt`, the first of which is the empty
+    /// word triggering the bos. The `context` words after it only set the prosody, their
+    /// audio and timestamps are dropped and the timestamps start with the returned audio.
+    #[allow(clippy::too_many_arguments)]
+    fn generate(
+        &self,
+        query: &crate::TtsQuery,
+        prompt: &[Vec<u32>],
+        context: usize,
+        ca_src: &Tensor,
+        conditions: Option<&moshi::conditioner::Condition>,
+        seed: u64,
+        log_tx: Option<&LogSender>,
+    ) -> Result<(Vec<Tensor>, Vec<WordWithTimestamps>)> {
+        let config = &self.tts_config;
+        let text_audio_delay_in_tokens = config.text_audio_delay_in_tokens;
+        let text_eop_token = config.text_eop_token;
+        let text_pad_token = config.text_pad_token;
+        let sampling = if query.temperature <= 0. || query.top_k <= 1 {
+            candle_transformers::generation::Sampling::ArgMax
+        } else {
+            candle_transformers::generation::Sampling::TopK {
+                k: query.top_k,
+                temperature: query.temperature,
+            }
+        };
+        let text_lp =
+            candle_transformers::generation::LogitsProcessor::from_sampling(seed, sampling.clone());
+        let audio_lp =
+            candle_transformers::generation::LogitsProcessor::from_sampling(seed, sampling);
+        let max_seq_len = query.max_seq_len.unwrap_or(2048);
+        let mut state = moshi::tts_streaming::State::new(
+            self.lm.clone(),
+            Some(moshi::transformer::CaSrc::Tokens(ca_src.clone())),
+            max_seq_len,
+            audio_lp,
+            text_lp,
+            query.cfg_alpha,
+            config.clone(),
+        );
+        let mut last_text_token = config.text_start_token;
+        let mut audio_tokens = vec![];
+        let mut transcript = vec![];
+        tracing::info!("starting the inference loop");
+        let mut word_idx = 0;
+        let mut token_idx = 0;
+        let mut step_past_last_token = 0;
+        let mut last_epad_index = 0usize;
+        // Step at which the last context word ends, the audio starts there.
+        let mut context_end = 0usize;
+        for step_idx in 0..max_seq_len {
+            let word_tokens = prompt.get(word_idx);
+            let allowed_tokens = match word_tokens.as_ref() {
+                None => {
+                    step_past_last_token += 1;
+                    if step_past_last_token > 5 + text_audio_delay_in_tokens {
+                        break;
+                    }
+                    moshi::tts_streaming::AllowedTokens::Pad
+                }
+                Some(word_tokens) => match word_tokens.get(token_idx) {
+                    None => moshi::tts_streaming::AllowedTokens::PadOrEpad,
+                    Some(id) => moshi::tts_streaming::AllowedTokens::Text(*id),
+                },
+            };
+            last_text_token = {
+                let _step = tracing::span!(tracing::Level::TRACE, crate::profiler::STEP).entered();
+                self.steps.install(|| state.step(last_text_token, allowed_tokens, conditions))?
+            };
+            if last_text_token == text_eop_token {
+                match word_tokens {
+                    Some(vs) if word_idx > context => {
+                        if let Ok(text) = self.text_tokenizer.decode_piece_ids(vs) {
+                            let start_s = (last_epad_index - context_end) as f64 / 12.5;
+                            let stop_s = (step_idx - context_end) as f64 / 12.5;
+                            transcript.push(WordWithTimestamps { text, start_s, stop_s })
+                        }
+                    }
+                    _ => {}
+                }
+                if context > 0 && word_idx == context {
+                    context_end = step_idx
+                }
+                last_epad_index = step_idx;
+                word_idx += 1;
+                token_idx = 0;
+            } else if last_text_token != text_pad_token {
+                token_idx += 1;
+            }
+            if let Some(audio_tokens_vec) = state.last_audio_tokens() {
+                let cb = audio_tokens_vec.len();
+                let tokens =
+                    candle::Tensor::from_vec(audio_tokens_vec.clone(), (1, cb, 1), state.device())?;
+                if step_idx >= text_audio_delay_in_tokens {
+                    audio_tokens.push(tokens)
+                }
+                if let Some(tx) = log_tx {
+                    tx.send_slice(last_text_token, audio_tokens_vec)
+                }
+            } else if let Some(tx) = log_tx {
+                let cb = state.audio_codebooks();
+                let audio_tokens_vec = vec![0u32; cb];
+                tx.send_slice(last_text_token, audio_tokens_vec)
+            }
+        }
+        // The audio of text step `s` is the audio token `s`, once the delay is accounted for.
+        audio_tokens.drain(..context_end.min(audio_tokens.len()));
+        Ok((audio_tokens, transcript))
     }
 }
 
@@ -962,3 +1317,59 @@ pub fn speaker_pcm<P: AsRef<std::path::Path>>(
     let pcm = Tensor::new(pcm, dev)?.reshape((1, 1, ()))?;
     Ok(pcm)
 }
+
+/// Check that `weights` has one finite, non-negative entry per voice with a positive sum and
+/// rescale them to sum to one.
+pub fn normalize_voice_weights(n_voices: usize, weights: &[f32]) -> Result<Vec<f32>> {
+    let invalid = |msg: String| Err(ApiError::InvalidVoice(msg).into());
+    if n_voices == 0 {
+        return invalid("voices cannot be empty".to_string());
+    }
+    if weights.len() != n_voices {
+        return invalid(format!("got {} voice_weights for {n_voices} voices", weights.len()));
+    }
+    if let Some(w) = weights.iter().find(|w| !w.is_finite() || **w < 0.) {
+        return invalid(format!("voice_weights must be finite and non-negative, got {w}"));
+    }
+    let sum: f32 = weights.iter().sum();
+    if sum <= 0. {
+        return invalid("voice_weights must not all be zero".to_string());
+    }
+    Ok(weights.iter().map(|w| w / sum).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn voice_weights_are_normalized() {
+        let w = normalize_voice_weights(2, &[0.7, 0.3]).unwrap();
+        assert!((w[0] - 0.7).abs() < 1e-6 && (w[1] - 0.3).abs() < 1e-6);
+        let w = normalize_voice_weights(3, &[2., 0., 2.]).unwrap();
+        assert_eq!(w, vec![0.5, 0., 0.5]);
+    }
+
+    #[test]
+    fn envelope_windows_span_chunks() {
+        // 10ms at 24kHz is 240 samples.
+        let mut meter = EnvelopeMeter::new(10, 24000);
+        assert!(meter.push(&[0.5; 200]).is_empty());
+        let rms = meter.push(&[0.5; 520]);
+        assert_eq!(rms.len(), 3);
+        assert!(rms.iter().all(|v| (v - 0.5).abs() < 1e-5));
+        let mut pcm = vec![1.0; 120];
+        pcm.extend(vec![-1.0; 120]);
+        assert!((meter.push(&pcm)[0] - 1.0).abs() < 1e-5);
+        assert!(meter.push(&[0.0; 240])[0].abs() < 1e-6);
+    }
+
+    #[test]
+    fn invalid_voice_weights() {
+        assert!(normalize_voice_weights(2, &[1.0]).is_err());
+        assert!(normalize_voice_weights(0, &[]).is_err());
+        assert!(normalize_voice_weights(2, &[0.5, -0.5]).is_err());
+        assert!(normalize_voice_weights(2, &[0.5, f32::NAN]).is_err());
+        assert!(normalize_voice_weights(2, &[0., 0.]).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Cache of the `/api/tts` outputs, for the workloads that synthesize the same prompts over
+//! and over, e.g. IVR menus. Entries are keyed by the sha3 of the request with everything
+//! that changes the audio, i.e. the text, the voices, the sampling parameters and the seed,
+//! and evicted least recently used first once the cache is over `max_mb`.
+
+use sha3::Digest;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TtsCacheConfig {
+    /// Seconds after which an entry is synthesized again, e.g. to pick up new voice files.
+    #[serde(default = "default_ttl_s")]
+    pub ttl_s: u64,
+    #[serde(default = "default_max_mb")]
+    pub max_mb: usize,
+}
+
+fn default_ttl_s() -> u64 {
+    24 * 3600
+}
+
+fn default_max_mb() -> usize {
+    256
+}
+
+pub type Key = [u8; 32];
+
+/// Key of a request, `request` holding all the parameters that change the output.
+pub fn key(request: &impl serde::Serialize) -> anyhow::Result<Key> {
+    let json = serde_json::to_vec(request)?;
+    Ok(sha3::Sha3_256::digest(&json).into())
+}
+
+#[derive(Debug, Clone)]
+pub struct Cached {
+    pub wav: bytes::Bytes,
+    pub transcript: Vec<crate::tts::WordWithTimestamps>,
+    pub chunks: Vec<crate::tts::TextChunk>,
+    pub translation: Option<crate::translation::TranslatedText>,
+}
+
+impl Cached {
+    fn size(&self) -> usize {
+        let words: usize = self.transcript.iter().map(|w| w.text.len() + 32).sum();
+        self.wav.len() + words + self.chunks.len() * 24
+    }
+}
+
+struct Entry {
+    value: Cached,
+    size: usize,
+    inserted: Instant,
+    /// Position in the recency order.
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<Key, Entry>,
+    /// Keys by last use, oldest first.
+    recency: BTreeMap<u64, Key>,
+    tick: u64,
+    bytes: usize,
+}
+
+impl Inner {
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.tick);
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+pub struct TtsCache {
+    ttl: Duration,
+    max_bytes: usize,
+    inner: std::sync::Mutex<Inner>,
+}
+
+impl TtsCache {
+    pub fn new(cfg: &TtsCacheConfig) -> Self {
+        Self {
+            ttl: Duration::from_secs(cfg.ttl_s),
+            max_bytes: cfg.max_mb << 20,
+            inner: Default::default(),
+        }
+    }
+
+    pub fn get(&self, key: &Key) -> Option<Cached> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let inner = &mut *inner;
+        let value = match inner.entries.get_mut(key) {
+            Some(entry) if entry.inserted.elapsed() < self.ttl => {
+                inner.tick += 1;
+                inner.recency.remove(&entry.tick);
+                entry.tick = inner.tick;
+                inner.recency.insert(inner.tick, *key);
+                Some(entry.value.clone())
+            }
+            Some(_) => {
+                inner.remove(key);
+                None
+            }
+            None => None,
+        };
+        match value {
+            Some(_) => crate::metrics::tts::CACHE_HITS.inc(),
+            None => crate::metrics::tts::CACHE_MISSES.inc(),
+        }
+        self.update_gauges(inner);
+        value
+    }
+
+    /// Add an entry, evicting the least recently used ones to make room. Outputs larger than
+    /// the whole cache are not kept.
+    pub fn insert(&self, key: Key, value: Cached) {
+        let size = value.size();
+        if size > self.max_bytes {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let inner = &mut *inner;
+        inner.remove(&key);
+        while inner.bytes + size > self.max_bytes {
+            let Some((_, oldest)) = inner.recency.pop_first() else { break };
+            if let Some(entry) = inner.entries.remove(&oldest) {
+                inner.bytes -= entry.size;
+                crate::metrics::tts::CACHE_EVICTIONS.inc();
+            }
+        }
+        inner.tick += 1;
+        let entry = Entry { value, size, inserted: Instant::now(), tick: inner.tick };
+        inner.recency.insert(inner.tick, key);
+        inner.entries.insert(key, entry);
+        inner.bytes += size;
+        self.update_gauges(inner);
+    }
+
+    fn update_gauges(&self, inner: &Inner) {
+        crate::metrics::tts::CACHE_ENTRIES.set(inner.entries.len() as f64);
+        crate::metrics::tts::CACHE_BYTES.set(inner.bytes as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached(bytes: usize) -> Cached {
+        Cached {
+            wav: vec![0u8; bytes].into(),
+            transcript: vec![],
+            chunks: vec![],
+            translation: None,
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used_and_expires() {
+        let cache = TtsCache::new(&TtsCacheConfig { ttl_s: 3600, max_mb: 1 });
+        let (a, b, c) = ([1; 32], [2; 32], [3; 32]);
+        cache.insert(a, cached(400 << 10));
+        cache.insert(b, cached(400 << 10));
+        assert!(cache.get(&a).is_some());
+        // Over 1MB, b was used last before a.
+        cache.insert(c, cached(400 << 10));
+        assert!(cache.get(&b).is_none());
+        assert_eq!(cache.get(&a).unwrap().wav.len(), 400 << 10);
+        assert!(cache.get(&c).is_some());
+        cache.insert(b, cached(2 << 20));
+        assert!(cache.get(&b).is_none());
+
+        let cache = TtsCache::new(&TtsCacheConfig { ttl_s: 0, max_mb: 1 });
+        cache.insert(a, cached(10));
+        assert!(cache.get(&a).is_none());
+        assert_eq!(cache.inner.lock().unwrap().bytes, 0);
+
+        let params =
+            |seed: u64| serde_json::json!({ "text": ["Press one for sales."], "seed": seed });
+        assert_eq!(key(&params(1)).unwrap(), key(&params(1)).unwrap());
+        assert_ne!(key(&params(1)).unwrap(), key(&params(2)).unwrap());
+    }
+}
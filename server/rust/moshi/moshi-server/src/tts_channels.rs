@@ -0,0 +1,82 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Channel layout of the tts output, set with the `channels` and `pan` request options. The
+//! model generates mono audio, a stereo output either duplicates it on both channels or pans
+//! it, for the players and telephony stacks that only take stereo. The samples of the stereo
+//! outputs are interleaved, left first.
+
+use crate::errors::ApiError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelLayout {
+    Mono,
+    /// Gains of the left and right channels.
+    Stereo {
+        left: f32,
+        right: f32,
+    },
+}
+
+impl ChannelLayout {
+    /// `channels` defaults to 1. `pan` goes from -1 (left only) to 1 (right only), the other
+    /// channel being attenuated linearly, and is only valid with 2 channels.
+    pub fn new(channels: Option<u16>, pan: Option<f32>) -> Result<Self, ApiError> {
+        let invalid = |msg: String| Err(ApiError::InvalidRequest(msg));
+        match (channels.unwrap_or(1), pan) {
+            (1, None) => Ok(Self::Mono),
+            (1, Some(_)) => invalid("pan requires channels=2".to_string()),
+            (2, pan) => {
+                let pan = pan.unwrap_or(0.0);
+                if !(-1.0..=1.0).contains(&pan) {
+                    return invalid(format!("pan must be between -1 and 1, got {pan}"));
+                }
+                Ok(Self::Stereo { left: (1.0 - pan).min(1.0), right: (1.0 + pan).min(1.0) })
+            }
+            (channels, _) => invalid(format!("channels must be 1 or 2, got {channels}")),
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        match self {
+            Self::Mono => 1,
+            Self::Stereo { .. } => 2,
+        }
+    }
+
+    /// The mono `pcm` in this layout.
+    pub fn interleave<'a>(&self, pcm: &'a [f32]) -> std::borrow::Cow<'a, [f32]> {
+        match *self {
+            Self::Mono => pcm.into(),
+            Self::Stereo { left, right } => {
+                pcm.iter().flat_map(|&v| [v * left, v * right]).collect::<Vec<_>>().into()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layouts() {
+        let pcm = [0.5, -1.0];
+        let mono = ChannelLayout::new(None, None).unwrap();
+        assert_eq!((mono.channels(), &*mono.interleave(&pcm)), (1, &pcm[..]));
+        let dup = ChannelLayout::new(Some(2), None).unwrap();
+        assert_eq!((dup.channels(), &*dup.interleave(&pcm)), (2, &[0.5, 0.5, -1.0, -1.0][..]));
+        let left = ChannelLayout::new(Some(2), Some(-0.5)).unwrap();
+        assert_eq!(left, ChannelLayout::Stereo { left: 1.0, right: 0.5 });
+        assert_eq!(&*left.interleave(&pcm), &[0.5, 0.25, -1.0, -0.5]);
+        let right = ChannelLayout::new(Some(2), Some(1.0)).unwrap();
+        assert_eq!(right, ChannelLayout::Stereo { left: 0.0, right: 1.0 });
+
+        assert!(ChannelLayout::new(Some(6), None).is_err());
+        assert!(ChannelLayout::new(Some(1), Some(0.5)).is_err());
+        assert!(ChannelLayout::new(None, Some(0.5)).is_err());
+        assert!(ChannelLayout::new(Some(2), Some(1.5)).is_err());
+        assert!(ChannelLayout::new(Some(2), Some(f32::NAN)).is_err());
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Encoding of the streamed tts audio on a pool of worker threads. The audio loop of a session
+//! queues the pcm of each step and the messages to interleave with it, a worker encodes them in
+//! order and sends the result to the send loop, so that the Opus encoder does not hold back the
+//! mimi decoding. The pcm queue of a stream is bounded: when the workers fall behind, the oldest
+//! pending chunk is dropped rather than letting the latency grow. The Ogg granule positions
+//! count the encoded samples, so a stream with dropped chunks is still valid, only shorter.
+
+use crate::tts::{Encoder, WordWithTimestamps};
+use crate::tts_channels::ChannelLayout;
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+fn default_workers() -> usize {
+    2
+}
+
+fn default_queue_chunks() -> usize {
+    25
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncodePoolConfig {
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    /// Pcm chunks, of 80ms each, waiting to be encoded for a stream.
+    #[serde(default = "default_queue_chunks")]
+    pub queue_chunks: usize,
+}
+
+impl Default for EncodePoolConfig {
+    fn default() -> Self {
+        Self { workers: default_workers(), queue_chunks: default_queue_chunks() }
+    }
+}
+
+enum Job {
+    Pcm(Vec<f32>),
+    /// Serialized message, sent after the audio queued before it.
+    Msg(Vec<u8>),
+    Finish,
+}
+
+/// Pending jobs of a stream, `scheduled` is set while a worker drains them.
+struct Queue {
+    jobs: VecDeque<Job>,
+    pcm: usize,
+    capacity: usize,
+    scheduled: bool,
+    dropped: usize,
+}
+
+impl Queue {
+    fn new(capacity: usize) -> Self {
+        Self { jobs: VecDeque::new(), pcm: 0, capacity, scheduled: false, dropped: 0 }
+    }
+
+    /// Queue a job, dropping the oldest pcm chunk when full. Returns true when a worker has to
+    /// be scheduled to drain the queue.
+    fn push(&mut self, job: Job) -> bool {
+        if matches!(job, Job::Pcm(_)) {
+            if self.pcm >= self.capacity {
+                if let Some(idx) = self.jobs.iter().position(|j| matches!(j, Job::Pcm(_))) {
+                    self.jobs.remove(idx);
+                    self.pcm -= 1;
+                    self.dropped += 1;
+                }
+            }
+            self.pcm += 1;
+        }
+        self.jobs.push_back(job);
+        !std::mem::replace(&mut self.scheduled, true)
+    }
+
+    /// Next job to run, the queue is no longer scheduled once empty.
+    fn pop(&mut self) -> Option<Job> {
+        let job = self.jobs.pop_front();
+        match job {
+            None => self.scheduled = false,
+            Some(Job::Pcm(_)) => self.pcm -= 1,
+            Some(_) => {}
+        }
+        job
+    }
+}
+
+pub struct EncodePool {
+    pool: Arc<rayon::ThreadPool>,
+    queue_chunks: usize,
+}
+
+impl EncodePool {
+    pub fn new(config: &EncodePoolConfig) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(config.workers.max(1))
+            .thread_name(|idx| format!("tts-encode-{idx}"))
+            .build()?;
+        Ok(Self { pool: Arc::new(pool), queue_chunks: config.queue_chunks.max(1) })
+    }
+
+    /// Encode a stream with `encoder`, its output goes to `out_tx` in the order of the calls.
+    /// The mono pcm is put in `layout` by the workers.
+    pub fn stream(
+        &self,
+        encoder: Encoder,
+        layout: ChannelLayout,
+        out_tx: tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
+    ) -> EncodeStream {
+        let shared = Shared {
+            queue: Mutex::new(Queue::new(self.queue_chunks)),
+            encoder: Mutex::new(encoder),
+            layout,
+            out_tx,
+            failed: AtomicBool::new(false),
+        };
+        EncodeStream { shared: Arc::new(shared), pool: self.pool.clone() }
+    }
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    encoder: Mutex<Encoder>,
+    layout: ChannelLayout,
+    out_tx: tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
+    failed: AtomicBool,
+}
+
+impl Shared {
+    fn drain(&self) {
+        loop {
+            let job = self.queue.lock().unwrap().pop();
+            let Some(job) = job else { return };
+            if let Err(err) = self.run(job) {
+                // The session is going away when the send loop is gone.
+                if !self.out_tx.is_closed() {
+                    tracing::error!(?err, "tts encoding failed");
+                }
+                self.failed.store(true, Ordering::Relaxed);
+                let mut queue = self.queue.lock().unwrap();
+                queue.jobs.clear();
+                queue.pcm = 0;
+                queue.scheduled = false;
+                return;
+            }
+        }
+    }
+
+    fn run(&self, job: Job) -> Result<()> {
+        let buf = match job {
+            Job::Pcm(pcm) => {
+                let start = std::time::Instant::now();
+                let pcm = self.layout.interleave(&pcm);
+                let buf = self.encoder.lock().unwrap().encode(&pcm)?;
+                crate::metrics::tts::ENCODE_DURATION.observe(start.elapsed().as_secs_f64());
+                Some(buf)
+            }
+            Job::Msg(msg) => Some(msg),
+            Job::Finish => {
+                let dropped = self.queue.lock().unwrap().dropped;
+                if dropped > 0 {
+                    tracing::warn!(dropped, "tts audio chunks dropped by a lagging encoder");
+                }
+                self.encoder.lock().unwrap().finish()?
+            }
+        };
+        if let Some(buf) = buf {
+            self.out_tx.send(buf)?
+        }
+        Ok(())
+    }
+}
+
+/// Handle of the audio loop on its stream. The output closes once the handle is dropped and
+/// the queued jobs are done.
+pub struct EncodeStream {
+    shared: Arc<Shared>,
+    pool: Arc<rayon::ThreadPool>,
+}
+
+impl EncodeStream {
+    fn push(&self, job: Job) -> Result<()> {
+        if self.shared.failed.load(Ordering::Relaxed) || self.shared.out_tx.is_closed() {
+            anyhow::bail!("tts encoding stopped")
+        }
+        let (schedule, dropped) = {
+            let mut queue = self.shared.queue.lock().unwrap();
+            let dropped = queue.dropped;
+            (queue.push(job), queue.dropped - dropped)
+        };
+        if dropped > 0 {
+            crate::metrics::tts::ENCODE_DROPPED.inc_by(dropped as u64);
+        }
+        if schedule {
+            let shared = self.shared.clone();
+            self.pool.spawn(move || shared.drain());
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self, pcm: Vec<f32>) -> Result<()> {
+        self.push(Job::Pcm(pcm))
+    }
+
+    pub fn send(&self, msg: Vec<u8>) -> Result<()> {
+        self.push(Job::Msg(msg))
+    }
+
+    pub fn send_word(&self, wwts: WordWithTimestamps) -> Result<()> {
+        let msg = self.shared.encoder.lock().unwrap().encode_word(wwts)?;
+        match msg {
+            Some(msg) => self.send(msg),
+            None => Ok(()),
+        }
+    }
+
+    /// End the utterance once the queued audio is encoded.
+    pub fn finish(self) -> Result<()> {
+        self.push(Job::Finish)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_queue_drops_the_oldest_pcm() {
+        let mut queue = Queue::new(2);
+        assert!(queue.push(Job::Pcm(vec![0.])));
+        assert!(!queue.push(Job::Msg(vec![1])));
+        assert!(!queue.push(Job::Pcm(vec![2.])));
+        assert!(!queue.push(Job::Pcm(vec![3.])));
+        assert!(!queue.push(Job::Finish));
+        assert_eq!(queue.dropped, 1);
+        let mut jobs = vec![];
+        while let Some(job) = queue.pop() {
+            jobs.push(match job {
+                Job::Pcm(pcm) => pcm[0] as u8,
+                Job::Msg(msg) => msg[0],
+                Job::Finish => u8::MAX,
+            });
+        }
+        assert_eq!(jobs, vec![1, 2, 3, u8::MAX]);
+        assert_eq!(queue.pcm, 0);
+        assert!(!queue.scheduled);
+        assert!(queue.push(Job::Pcm(vec![4.])));
+    }
+}
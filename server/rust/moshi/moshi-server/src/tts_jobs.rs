@@ -0,0 +1,374 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Background jobs of the HTTP TTS endpoint, for the texts that take hours to synthesize, e.g.
+//! audiobooks. The text of a job is split in chunks of whole paragraphs that a single worker
+//! synthesizes one after the other. Each job is kept under `<dir>/<job id>/`, its state in
+//! `job.json` and the audio of each chunk in a WAV file of its own, so that a restarted server
+//! resumes the unfinished jobs where they stopped.
+//!
+//! A chunk file is named after the index of the chunk and the hash of its text and of the
+//! parameters that change the audio, and is written through a temporary file. A chunk whose
+//! file is there is never synthesized again. The job id is the hash of the query and of its
+//! submitter, so that submitting the same query twice returns the existing job.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const JOB_FILE: &str = "job.json";
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TtsJobsConfig {
+    /// Directory of the jobs, it has to outlive the server for the jobs to be resumed.
+    pub dir: String,
+    /// Words above which a chunk is closed, chunks are made of whole paragraphs.
+    #[serde(default = "default_chunk_words")]
+    pub chunk_words: usize,
+}
+
+fn default_chunk_words() -> usize {
+    400
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct Chunk {
+    /// Turns of the chunk, as in the `text` of the query.
+    text: Vec<String>,
+    /// WAV file of the chunk in the job directory.
+    file: String,
+    done: bool,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Job {
+    id: String,
+    /// User id of the submitter, `None` for the requests without JWT claims.
+    owner: Option<String>,
+    status: JobStatus,
+    /// Query of the job, its text being held by `chunks`.
+    query: crate::TtsQuery,
+    chunks: Vec<Chunk>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// Progress of a job, as returned by the job endpoints.
+#[derive(Debug, serde::Serialize)]
+pub struct JobInfo {
+    pub id: String,
+    pub status: JobStatus,
+    pub chunks: usize,
+    /// Chunks whose audio can be fetched, the first ones of the job.
+    pub done_chunks: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Job {
+    pub fn owner(&self) -> Option<&str> {
+        self.owner.as_deref()
+    }
+
+    pub fn info(&self) -> JobInfo {
+        JobInfo {
+            id: self.id.clone(),
+            status: self.status,
+            chunks: self.chunks.len(),
+            done_chunks: self.chunks.iter().filter(|c| c.done).count(),
+            error: self.error.clone(),
+        }
+    }
+}
+
+pub struct JobStore {
+    dir: PathBuf,
+    chunk_words: usize,
+    /// Wakes the worker up on a new job.
+    notify: tokio::sync::Notify,
+}
+
+impl JobStore {
+    pub fn new(cfg: &TtsJobsConfig) -> Self {
+        Self {
+            dir: PathBuf::from(&cfg.dir),
+            chunk_words: cfg.chunk_words,
+            notify: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// Directory of job `id`, `None` for the ids that this store did not generate.
+    fn job_dir(&self, id: &str) -> Option<PathBuf> {
+        let valid = id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit());
+        valid.then(|| self.dir.join(id))
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<Job>> {
+        let Some(dir) = self.job_dir(id) else { return Ok(None) };
+        let path = dir.join(JOB_FILE);
+        match std::fs::read(&path) {
+            Ok(json) => {
+                let job = serde_json::from_slice(&json)
+                    .with_context(|| format!("invalid job {}", path.display()))?;
+                Ok(Some(job))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("cannot read {}", path.display())),
+        }
+    }
+
+    fn save(&self, job: &Job) -> Result<()> {
+        let Some(dir) = self.job_dir(&job.id) else { anyhow::bail!("invalid job id {}", job.id) };
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("cannot create {}", dir.display()))?;
+        write_file(&dir.join(JOB_FILE), &serde_json::to_vec(job)?)
+    }
+
+    /// Create the job of `query`, or return the existing one. A failed job is run again.
+    pub fn submit(&self, query: &crate::TtsQuery, owner: Option<String>) -> Result<Job> {
+        let query = crate::TtsQuery { return_timestamps: None, ..query.clone() };
+        let key = crate::tts_cache::key(&(&owner, &query, self.chunk_words))?;
+        let id: String = key[..16].iter().map(|b| format!("{b:02x}")).collect();
+        if let Some(mut job) = self.get(&id)? {
+            if job.status == JobStatus::Failed {
+                job.status = JobStatus::Pending;
+                job.error = None;
+                self.save(&job)?;
+                self.notify.notify_one();
+            }
+            return Ok(job);
+        }
+        let chunks = split(&query.text, self.chunk_words);
+        let query = crate::TtsQuery { text: crate::privacy::Sensitive::new(vec![]), ..query };
+        let chunks = chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, text)| {
+                let file = chunk_file(index, &query, &text)?;
+                Ok(Chunk { text, file, done: false })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let job = Job { id, owner, status: JobStatus::Pending, query, chunks, error: None };
+        self.save(&job)?;
+        tracing::info!(id = job.id, chunks = job.chunks.len(), "tts job submitted");
+        self.notify.notify_one();
+        Ok(job)
+    }
+
+    /// Audio of chunk `index` of `job`, `None` when it has not been synthesized yet.
+    pub fn chunk(&self, job: &Job, index: usize) -> Result<Option<Vec<u8>>> {
+        let Some(chunk) = job.chunks.get(index).filter(|c| c.done) else { return Ok(None) };
+        let Some(dir) = self.job_dir(&job.id) else { return Ok(None) };
+        let path = dir.join(&chunk.file);
+        let wav =
+            std::fs::read(&path).with_context(|| format!("cannot read {}", path.display()))?;
+        Ok(Some(wav))
+    }
+
+    /// The jobs left to run, e.g. by a previous run of the server.
+    fn pending(&self) -> Result<Vec<Job>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => {
+                return Err(err).with_context(|| format!("cannot list {}", self.dir.display()))
+            }
+        };
+        let mut jobs = vec![];
+        for entry in entries {
+            let name = entry?.file_name();
+            let Some(id) = name.to_str() else { continue };
+            match self.get(id) {
+                Ok(Some(job)) if job.status == JobStatus::Pending => jobs.push(job),
+                Ok(_) => {}
+                Err(err) => tracing::error!(?err, id, "cannot load tts job"),
+            }
+        }
+        jobs.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(jobs)
+    }
+}
+
+/// Split `text` in chunks of about `max_words` words. A single turn is cut between its
+/// paragraphs, i.e. at the blank lines, several turns are cut between pairs of turns so that
+/// each chunk starts with the main speaker.
+fn split(text: &[String], max_words: usize) -> Vec<Vec<String>> {
+    let units: Vec<Vec<String>> = match text {
+        [turn] => {
+            let mut paragraphs = vec![];
+            let mut current: Vec<&str> = vec![];
+            for line in turn.lines().map(str::trim) {
+                if line.is_empty() {
+                    if !current.is_empty() {
+                        paragraphs.push(vec![current.join(" ")]);
+                        current.clear()
+                    }
+                } else {
+                    current.push(line)
+                }
+            }
+            if !current.is_empty() {
+                paragraphs.push(vec![current.join(" ")])
+            }
+            paragraphs
+        }
+        turns => turns.chunks(2).map(|t| t.to_vec()).collect(),
+    };
+    let single_turn = text.len() == 1;
+    let mut chunks: Vec<Vec<String>> = vec![];
+    let mut words = 0;
+    for unit in units {
+        let n: usize = unit.iter().map(|t| t.split_whitespace().count()).sum();
+        match chunks.last_mut() {
+            Some(last) if words + n <= max_words => {
+                if single_turn {
+                    last[0].push(' ');
+                    last[0].push_str(&unit[0])
+                } else {
+                    last.extend(unit)
+                }
+            }
+            _ => {
+                chunks.push(unit);
+                words = 0
+            }
+        }
+        words += n
+    }
+    chunks
+}
+
+/// Name of the file of chunk `index`, the same for the same text and parameters.
+fn chunk_file(index: usize, query: &crate::TtsQuery, text: &[String]) -> Result<String> {
+    let key = crate::tts_cache::key(&(query, text))?;
+    let hash: String = key[..8].iter().map(|b| format!("{b:02x}")).collect();
+    Ok(format!("{index:05}-{hash}.wav"))
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = path.with_extension(format!("tmp-{}", std::process::id()));
+    std::fs::write(&tmp, data).with_context(|| format!("cannot write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("cannot write {}", path.display()))
+}
+
+/// Run the jobs of `model` one after the other, starting with the ones left pending by a
+/// previous run of the server.
+pub async fn run(model: Arc<crate::tts::Model>) {
+    let Some(store) = model.jobs.as_ref() else { return };
+    loop {
+        let jobs = store.pending().unwrap_or_else(|err| {
+            tracing::error!(?err, "cannot list the tts jobs");
+            vec![]
+        });
+        for mut job in jobs {
+            if let Err(err) = run_job(&model, store, &mut job).await {
+                tracing::error!(?err, id = job.id, "tts job failed");
+                job.status = JobStatus::Failed;
+                job.error = Some(err.to_string());
+                if let Err(err) = store.save(&job) {
+                    tracing::error!(?err, id = job.id, "cannot save tts job")
+                }
+            }
+        }
+        store.notify.notified().await
+    }
+}
+
+async fn run_job(model: &Arc<crate::tts::Model>, store: &JobStore, job: &mut Job) -> Result<()> {
+    let Some(dir) = store.job_dir(&job.id) else { anyhow::bail!("invalid job id {}", job.id) };
+    let done = job.chunks.iter().filter(|c| c.done).count();
+    tracing::info!(id = job.id, chunks = job.chunks.len(), done, "running tts job");
+    for index in 0..job.chunks.len() {
+        let chunk = &job.chunks[index];
+        if chunk.done {
+            continue;
+        }
+        // The file is there when the server stopped before saving the job.
+        let path = dir.join(&chunk.file);
+        if !path.exists() {
+            let query = crate::TtsQuery {
+                text: crate::privacy::Sensitive::new(chunk.text.clone()),
+                ..job.query.clone()
+            };
+            let wav = {
+                let _guard = model.mutex.lock().await;
+                let model = model.clone();
+                let run = move || model.run(&query).and_then(|(wav, _, _)| wav.into_vec());
+                tokio::task::spawn_blocking(run).await??
+            };
+            write_file(&path, &wav)?;
+        }
+        job.chunks[index].done = true;
+        store.save(job)?;
+    }
+    job.status = JobStatus::Done;
+    store.save(job)?;
+    tracing::info!(id = job.id, "tts job done");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(text: &str) -> crate::TtsQuery {
+        let query = serde_json::json!({
+            "text": [text],
+            "seed": 42,
+            "temperature": 0.8,
+            "top_k": 250,
+            "voice": "default.wav",
+        });
+        serde_json::from_value(query).unwrap()
+    }
+
+    #[test]
+    fn split_paragraphs_and_turns() {
+        let text = ["one two\nthree\n\n\nfour five\r\n\r\nsix".to_string()];
+        assert_eq!(split(&text, 3), [vec!["one two three"], vec!["four five six"]]);
+        assert_eq!(split(&text, 100), [vec!["one two three four five six"]]);
+        let turns: Vec<String> = ["a b", "c", "d e", "f", "g"].map(String::from).into();
+        assert_eq!(split(&turns, 3), [vec!["a b", "c"], vec!["d e", "f"], vec!["g"]]);
+        assert_eq!(split(&turns, 5), [vec!["a b", "c"], vec!["d e", "f", "g"]]);
+    }
+
+    #[test]
+    fn jobs_are_resumed_without_duplicates() {
+        let dir = std::env::temp_dir().join(format!("moshi-tts-jobs-test-{}", std::process::id()));
+        let cfg = TtsJobsConfig { dir: dir.to_string_lossy().to_string(), chunk_words: 3 };
+        let store = JobStore::new(&cfg);
+        let q = query("one two three\n\nfour five six");
+        let job = store.submit(&q, Some("alice".to_string())).unwrap();
+        assert_eq!(job.info().chunks, 2);
+        assert_eq!(job.chunks[0].file, chunk_file(0, &job.query, &job.chunks[0].text).unwrap());
+        assert_ne!(job.chunks[0].file[6..], job.chunks[1].file[6..]);
+        assert_eq!(store.submit(&q, Some("alice".to_string())).unwrap().id, job.id);
+        assert_ne!(store.submit(&q, Some("bob".to_string())).unwrap().id, job.id);
+        assert_eq!(store.pending().unwrap().len(), 2);
+
+        // A restart after writing the first chunk, but before saving the job.
+        let wav_dir = store.job_dir(&job.id).unwrap();
+        std::fs::write(wav_dir.join(&job.chunks[0].file), b"RIFF").unwrap();
+        let store = JobStore::new(&cfg);
+        let mut resumed = store.get(&job.id).unwrap().unwrap();
+        assert_eq!(resumed.info().done_chunks, 0);
+        assert!(wav_dir.join(&resumed.chunks[0].file).exists());
+        resumed.chunks[0].done = true;
+        store.save(&resumed).unwrap();
+        let resumed = store.get(&job.id).unwrap().unwrap();
+        assert_eq!(store.chunk(&resumed, 0).unwrap().as_deref(), Some(&b"RIFF"[..]));
+        assert_eq!(store.chunk(&resumed, 1).unwrap(), None);
+        assert!(store.get("../etc").unwrap().is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Responses of the TTS POST endpoint carrying both the raw WAV and the transcript, so that
+//! callers needing the timestamps do not have to decode the base64 WAV of the JSON response.
+//! `Accept: multipart/mixed` gets a `multipart/mixed` body holding the JSON transcript then
+//! the WAV. With `return_timestamps`, `Accept: audio/wav` gets the WAV with the transcript as
+//! base64 JSON in the `X-Transcript-Json` header, which suits short texts as proxies limit
+//! the size of headers.
+
+use crate::spill;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use futures_util::StreamExt;
+
+pub const TRANSCRIPT_HEADER: &str = "x-transcript-json";
+
+/// Whether one of the media types of the `Accept` headers is `mime`, parameters aside.
+fn accepts(headers: &HeaderMap, mime: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|t| t.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(mime))
+}
+
+pub fn wants_multipart(headers: &HeaderMap) -> bool {
+    accepts(headers, "multipart/mixed")
+}
+
+pub fn wants_wav(headers: &HeaderMap) -> bool {
+    accepts(headers, "audio/wav")
+}
+
+/// `multipart/mixed` response with the `json` transcript part then the WAV part, the WAV
+/// being streamed from memory or from its spill file.
+pub fn multipart(json: Vec<u8>, wav: spill::Wav) -> Response {
+    let boundary = format!("tts-{:032x}", rand::random::<u128>());
+    let mut head = format!("--{boundary}\r\nContent-Type: application/json\r\n\r\n").into_bytes();
+    head.extend_from_slice(&json);
+    head.extend_from_slice(
+        format!("\r\n--{boundary}\r\nContent-Type: audio/wav\r\n\r\n").as_bytes(),
+    );
+    let tail = bytes::Bytes::from(format!("\r\n--{boundary}--\r\n"));
+    let head = bytes::Bytes::from(head);
+    let body = futures_util::stream::once(async { Ok(head) })
+        .chain(wav.into_stream(bytes::Bytes::from))
+        .chain(futures_util::stream::once(async { Ok(tail) }));
+    let content_type = format!("multipart/mixed; boundary={boundary}");
+    (StatusCode::OK, [(header::CONTENT_TYPE, content_type)], axum::body::Body::from_stream(body))
+        .into_response()
+}
+
+/// WAV response with the `json` transcript, base64 encoded, in the `X-Transcript-Json` header.
+pub fn wav_with_transcript(json: &[u8], wav: spill::Wav) -> anyhow::Result<Response> {
+    let transcript = base64::prelude::BASE64_STANDARD.encode(json);
+    let transcript = HeaderValue::from_str(&transcript)?;
+    let body = axum::body::Body::from_stream(wav.into_stream(bytes::Bytes::from));
+    let mut response =
+        (StatusCode::OK, [(header::CONTENT_TYPE, "audio/wav")], body).into_response();
+    response.headers_mut().insert(TRANSCRIPT_HEADER, transcript);
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_negotiation() {
+        let headers =
+            |accept: &str| HeaderMap::from_iter([(header::ACCEPT, accept.parse().unwrap())]);
+        assert!(wants_multipart(&headers("application/json, multipart/mixed;q=0.9")));
+        assert!(!wants_multipart(&headers("multipart/form-data")));
+        assert!(wants_wav(&headers("Audio/WAV")));
+        assert!(!wants_wav(&HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn multipart_body_layout() {
+        let response =
+            multipart(br#"{"transcript":[]}"#.to_vec(), spill::Wav::Memory(b"RIFF".to_vec()));
+        let content_type = response.headers()[header::CONTENT_TYPE].to_str().unwrap().to_string();
+        let boundary = content_type.strip_prefix("multipart/mixed; boundary=").unwrap().to_string();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let expected = format!(
+            "--{boundary}\r\nContent-Type: application/json\r\n\r\n{{\"transcript\":[]}}\r\n\
+             --{boundary}\r\nContent-Type: audio/wav\r\n\r\nRIFF\r\n--{boundary}--\r\n"
+        );
+        assert_eq!(body, expected.as_bytes());
+    }
+}
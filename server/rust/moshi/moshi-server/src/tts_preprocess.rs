@@ -101,6 +101,128 @@ impl Tokenizer {
     }
 }
 
+/// Chunking of the long texts of `/api/tts`, each chunk is generated on its own so that long
+/// paragraphs are not cut at `max_seq_len` and the prosody does not drift.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChunkingConfig {
+    /// Text tokens of a chunk, the text is cut at the end of a sentence, or at the end of a
+    /// phrase for longer sentences. 0 generates the whole text at once.
+    #[serde(default = "default_max_tokens")]
+    pub max_tokens: usize,
+    /// Last words of the previous chunk that a chunk starts with so that its prosody carries
+    /// on, their audio is dropped.
+    #[serde(default = "default_context_words")]
+    pub context_words: usize,
+}
+
+fn default_max_tokens() -> usize {
+    256
+}
+
+fn default_context_words() -> usize {
+    8
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        Self { max_tokens: default_max_tokens(), context_words: default_context_words() }
+    }
+}
+
+/// Places where a text can be cut, from the worst to the best.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Cut {
+    Word,
+    Phrase,
+    Sentence,
+}
+
+fn cut_after(word: &str) -> Cut {
+    let word = word.trim_end_matches(['"', '\'', '”', '’', '»', ')', ']']);
+    match word.chars().last() {
+        Some('.' | '!' | '?' | '…' | '。' | '！' | '？') => Cut::Sentence,
+        Some(',' | ';' | ':' | '—' | '–' | '，' | '；' | '、') => Cut::Phrase,
+        _ => Cut::Word,
+    }
+}
+
+/// Words of a chunk, preceded by the words of the previous chunk repeated as context.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub context: std::ops::Range<usize>,
+    pub words: std::ops::Range<usize>,
+}
+
+/// Split `words`, given with their number of tokens, in chunks of at most `max_tokens` tokens
+/// context excluded, a longer word making a chunk on its own. There is always at least one
+/// chunk, empty for an empty text.
+pub fn chunk_words(words: &[(&str, usize)], cfg: &ChunkingConfig) -> Vec<Chunk> {
+    let mut chunks: Vec<Chunk> = vec![];
+    let mut start = 0;
+    loop {
+        let mut end = start;
+        let mut tokens = 0;
+        let mut best = (Cut::Word, start);
+        while end < words.len()
+            && (end == start || cfg.max_tokens == 0 || tokens + words[end].1 <= cfg.max_tokens)
+        {
+            tokens += words[end].1;
+            end += 1;
+            let cut = cut_after(words[end - 1].0);
+            if cut >= best.0 {
+                best = (cut, end)
+            }
+        }
+        let end = if end == words.len() { end } else { best.1 };
+        let context = match chunks.last() {
+            None => start..start,
+            Some(prev) => start.saturating_sub(cfg.context_words).max(prev.words.start)..start,
+        };
+        chunks.push(Chunk { context, words: start..end });
+        start = end;
+        if start >= words.len() {
+            break;
+        }
+    }
+    chunks
+}
+
+#[test]
+fn test_chunk_words() {
+    let cfg = ChunkingConfig { max_tokens: 8, context_words: 1 };
+    let words = [
+        ("Hello", 2),
+        ("there.", 2),
+        ("How", 1),
+        ("are", 1),
+        ("you,", 2),
+        ("my", 1),
+        ("friend?\"", 3),
+        ("Fine.", 1),
+    ];
+    let chunks = chunk_words(&words, &cfg);
+    assert_eq!(
+        chunks,
+        vec![
+            Chunk { context: 0..0, words: 0..2 },
+            Chunk { context: 1..2, words: 2..7 },
+            Chunk { context: 6..7, words: 7..8 },
+        ]
+    );
+
+    // Phrase ends are used when no sentence fits, single words longer than a chunk are kept.
+    let words = [("a,", 2), ("b", 2), ("c", 2), ("d", 2), ("supercalifragilistic", 20), ("e", 1)];
+    let cfg = ChunkingConfig { max_tokens: 6, context_words: 2 };
+    let ranges: Vec<_> = chunk_words(&words, &cfg).into_iter().map(|c| c.words).collect();
+    assert_eq!(ranges, vec![0..1, 1..4, 4..5, 5..6]);
+    assert_eq!(chunk_words(&words, &cfg)[2].context, 2..4);
+
+    let cfg = ChunkingConfig { max_tokens: 0, context_words: 2 };
+    assert_eq!(chunk_words(&words, &cfg), vec![Chunk { context: 0..0, words: 0..6 }]);
+    assert_eq!(chunk_words(&[], &cfg), vec![Chunk { context: 0..0, words: 0..0 }]);
+}
+
 #[test]
 fn test_segment_parser() {
     let input = r#"Hello <break time="0.5s"/> world <break time="1.0s"/>!"#;
@@ -0,0 +1,219 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Time-stretching of the tts output for the `speed` request option, faster or slower speech
+//! with the same pitch.
+//!
+//! This uses WSOLA: the output is an overlap-add of Hann windows taken from the input every
+//! `speed` output hops, each one shifted by up to `TOLERANCE` samples to the position that best
+//! continues the previous window, so that the pitch periods stay aligned. The stretcher works
+//! on the streamed chunks with a latency of about a window.
+
+use crate::errors::ApiError;
+
+pub const MIN_SPEED: f32 = 0.5;
+pub const MAX_SPEED: f32 = 2.0;
+
+/// 30ms windows at 24kHz.
+const WINDOW: usize = 720;
+const HOP: usize = WINDOW / 2;
+/// Largest shift of a window, 5ms, half the pitch period of the lowest voices.
+const TOLERANCE: usize = 120;
+
+/// `speed` defaults to 1.
+pub fn speed(speed: Option<f32>) -> Result<f32, ApiError> {
+    let speed = speed.unwrap_or(1.0);
+    if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+        let msg = format!("speed must be between {MIN_SPEED} and {MAX_SPEED}, got {speed}");
+        return Err(ApiError::InvalidRequest(msg));
+    }
+    Ok(speed)
+}
+
+pub struct Stretcher {
+    speed: f64,
+    window: Vec<f32>,
+    /// Input samples from the position `in_pos`.
+    input: Vec<f32>,
+    in_pos: usize,
+    /// Samples pushed so far.
+    in_len: usize,
+    /// Overlap-added windows and the sum of their weights, from the position `out_pos`.
+    out: Vec<f32>,
+    weights: Vec<f32>,
+    out_pos: usize,
+    /// Index of the next window.
+    frame: usize,
+    /// Input position of the previous window.
+    prev: Option<usize>,
+}
+
+impl Stretcher {
+    pub fn new(speed: f32) -> Self {
+        let window = (0..WINDOW)
+            .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / WINDOW as f32).cos())
+            .collect();
+        Self {
+            speed: speed as f64,
+            window,
+            input: vec![],
+            in_pos: 0,
+            in_len: 0,
+            out: vec![],
+            weights: vec![],
+            out_pos: 0,
+            frame: 0,
+            prev: None,
+        }
+    }
+
+    /// Output time of an input time, e.g. for the word timestamps.
+    pub fn time(&self, input_s: f64) -> f64 {
+        input_s / self.speed
+    }
+
+    /// Stretch `pcm`, returns the output that is complete.
+    pub fn push(&mut self, pcm: &[f32]) -> Vec<f32> {
+        if self.speed == 1.0 {
+            return pcm.to_vec();
+        }
+        self.input.extend_from_slice(pcm);
+        self.in_len += pcm.len();
+        self.process(false)
+    }
+
+    /// The rest of the output, once all the input has been pushed.
+    pub fn finish(&mut self) -> Vec<f32> {
+        if self.speed == 1.0 {
+            return vec![];
+        }
+        let mut pcm = self.process(true);
+        let emitted = self.out_pos - pcm.len();
+        let total = (self.in_len as f64 / self.speed).round() as usize;
+        pcm.truncate(total.saturating_sub(emitted));
+        pcm
+    }
+
+    fn process(&mut self, flush: bool) -> Vec<f32> {
+        let mut pcm = vec![];
+        loop {
+            let nominal = ((self.frame * HOP) as f64 * self.speed).round() as usize;
+            if flush && nominal >= self.in_len {
+                break;
+            }
+            let needed = match self.prev {
+                None => nominal + WINDOW,
+                Some(prev) => (nominal + TOLERANCE).max(prev + HOP) + WINDOW,
+            };
+            if needed > self.in_pos + self.input.len() {
+                if !flush {
+                    break;
+                }
+                self.input.resize(needed - self.in_pos, 0.0);
+            }
+            let pos = match self.prev {
+                None => nominal,
+                Some(prev) => self.best_position(prev + HOP, nominal),
+            };
+            self.add_window(pos);
+            pcm.extend(self.take_output((self.frame + 1) * HOP));
+            self.prev = Some(pos);
+            self.frame += 1;
+            // Keep the input that the next window may use.
+            let next = ((self.frame * HOP) as f64 * self.speed).round() as usize;
+            let keep = next.saturating_sub(TOLERANCE).min(pos + HOP);
+            if keep > self.in_pos {
+                self.input.drain(..keep - self.in_pos);
+                self.in_pos = keep;
+            }
+        }
+        if flush {
+            let end = self.out_pos + self.out.len();
+            pcm.extend(self.take_output(end));
+        }
+        pcm
+    }
+
+    /// Position within `TOLERANCE` of `nominal` whose window correlates best with the one at
+    /// `natural`, the continuation of the previous window.
+    fn best_position(&self, natural: usize, nominal: usize) -> usize {
+        let target = &self.input[natural - self.in_pos..][..WINDOW];
+        let lo = nominal.saturating_sub(TOLERANCE).max(self.in_pos);
+        let mut best = (f32::NEG_INFINITY, nominal);
+        for pos in lo..=nominal + TOLERANCE {
+            let candidate = &self.input[pos - self.in_pos..][..WINDOW];
+            let corr: f32 = target.iter().zip(candidate).map(|(a, b)| a * b).sum();
+            if corr > best.0 {
+                best = (corr, pos)
+            }
+        }
+        best.1
+    }
+
+    fn add_window(&mut self, pos: usize) {
+        let start = self.frame * HOP - self.out_pos;
+        if self.out.len() < start + WINDOW {
+            self.out.resize(start + WINDOW, 0.0);
+            self.weights.resize(start + WINDOW, 0.0);
+        }
+        let input = &self.input[pos - self.in_pos..][..WINDOW];
+        for (i, (&w, &v)) in self.window.iter().zip(input).enumerate() {
+            self.out[start + i] += w * v;
+            self.weights[start + i] += w;
+        }
+    }
+
+    /// The output up to the position `end`, normalized by the window weights.
+    fn take_output(&mut self, end: usize) -> Vec<f32> {
+        let n = end - self.out_pos;
+        self.out_pos = end;
+        let weights = self.weights.drain(..n);
+        let out = self.out.drain(..n);
+        out.zip(weights).map(|(v, w)| if w > 1e-6 { v / w } else { 0.0 }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(hz: f32, len: usize) -> Vec<f32> {
+        (0..len).map(|i| (2.0 * std::f32::consts::PI * hz * i as f32 / 24_000.0).sin()).collect()
+    }
+
+    /// Zero crossings per second in the middle of `pcm`.
+    fn crossings(pcm: &[f32]) -> f32 {
+        let mid = &pcm[pcm.len() / 4..pcm.len() * 3 / 4];
+        let n = mid.windows(2).filter(|w| (w[0] < 0.0) != (w[1] < 0.0)).count();
+        n as f32 * 24_000.0 / mid.len() as f32
+    }
+
+    #[test]
+    fn stretch() {
+        let pcm = sine(220.0, 24_000);
+        let mut s = Stretcher::new(1.0);
+        assert_eq!(s.push(&pcm), pcm);
+        assert!(s.finish().is_empty());
+
+        for (speed, len) in [(2.0, 12_000), (1.25, 19_200), (0.5, 48_000)] {
+            let mut s = Stretcher::new(speed);
+            let mut out = s.push(&pcm);
+            out.extend(s.finish());
+            assert_eq!(out.len(), len, "{speed}");
+            assert!((crossings(&out) - 440.0).abs() < 10.0, "{speed} {}", crossings(&out));
+            assert!((s.time(1.0) - 1.0 / speed as f64).abs() < 1e-9);
+
+            // Streamed in 80ms chunks.
+            let mut s = Stretcher::new(speed);
+            let mut chunked: Vec<f32> = pcm.chunks(1920).flat_map(|c| s.push(c)).collect();
+            chunked.extend(s.finish());
+            assert_eq!(chunked, out);
+        }
+
+        assert_eq!(speed(None).unwrap(), 1.0);
+        assert_eq!(speed(Some(0.5)).unwrap(), 0.5);
+        assert!(speed(Some(2.5)).is_err());
+        assert!(speed(Some(f32::NAN)).is_err());
+    }
+}
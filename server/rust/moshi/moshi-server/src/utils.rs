@@ -19,6 +19,12 @@ pub struct BuildInfo {
     cargo_target_triple: String,
 }
 
+impl Default for BuildInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl BuildInfo {
     pub fn new() -> BuildInfo {
         BuildInfo {
@@ -224,10 +230,7 @@ impl<T: serde::Serialize> axum::response::IntoResponse for WrapJson<T> {
     fn into_response(self) -> axum::response::Response {
         match self.0 {
             Ok(v) => axum::Json(v).into_response(),
-            Err(err) => {
-                tracing::error!(?err, "returning internal server error 500");
-                (axum::http::StatusCode::INTERNAL_SERVER_ERROR, format!("{err}")).into_response()
-            }
+            Err(err) => crate::errors::ApiError::from(err).into_response(),
         }
     }
 }
@@ -236,9 +239,7 @@ pub struct AxumError(anyhow::Error);
 
 impl axum::response::IntoResponse for AxumError {
     fn into_response(self) -> axum::response::Response {
-        let err = self.0;
-        tracing::error!(?err);
-        (axum::http::StatusCode::INTERNAL_SERVER_ERROR, format!("{err:?}")).into_response()
+        crate::errors::ApiError::from(self.0).into_response()
     }
 }
 
@@ -260,12 +261,21 @@ where
     samples.extend(data.chan(0).iter().map(|v| f32::from_sample(*v)))
 }
 
-pub fn pcm_decode(bytes: axum::body::Bytes) -> anyhow::Result<(Vec<f32>, u32)> {
+/// Decodes the first audio track of a file in any container supported by symphonia,
+/// `extension` helps the probe when the content is ambiguous.
+pub fn pcm_decode(
+    bytes: axum::body::Bytes,
+    extension: Option<&str>,
+) -> anyhow::Result<(Vec<f32>, u32)> {
+    use anyhow::Context;
     use symphonia::core::audio::{AudioBufferRef, Signal};
 
     let source = std::io::Cursor::new(bytes);
     let mss = symphonia::core::io::MediaSourceStream::new(Box::new(source), Default::default());
-    let hint = symphonia::core::probe::Hint::new();
+    let mut hint = symphonia::core::probe::Hint::new();
+    if let Some(extension) = extension {
+        hint.with_extension(extension);
+    }
     let meta_opts: symphonia::core::meta::MetadataOptions = Default::default();
     let fmt_opts: symphonia::core::formats::FormatOptions = Default::default();
     let probed = symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts)?;
@@ -274,10 +284,10 @@ pub fn pcm_decode(bytes: axum::body::Bytes) -> anyhow::Result<(Vec<f32>, u32)> {
         .tracks()
         .iter()
         .find(|t| t.codec_params.codec != symphonia::core::codecs::CODEC_TYPE_NULL)
-        .expect("no supported audio tracks");
+        .context("no supported audio tracks")?;
     let mut decoder = symphonia::default::get_codecs()
         .make(&track.codec_params, &Default::default())
-        .expect("unsupported codec");
+        .context("unsupported codec")?;
     let track_id = track.id;
     let sample_rate = track.codec_params.sample_rate.unwrap_or(0);
     let mut pcm_data = Vec::new();
@@ -304,6 +314,13 @@ pub fn pcm_decode(bytes: axum::body::Bytes) -> anyhow::Result<(Vec<f32>, u32)> {
     Ok((pcm_data, sample_rate))
 }
 
+/// Wall clock in milliseconds since the unix epoch, zero if the clock is before the epoch.
+pub fn unix_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
 pub fn spawn<F>(name: &'static str, future: F) -> tokio::task::JoinHandle<()>
 where
     F: std::future::Future<Output = Result<()>> + Send + 'static,
@@ -377,12 +394,30 @@ pub fn spawn_blocking<F>(name: &'static str, f: F) -> tokio::task::JoinHandle<()
 where
     F: FnOnce() -> Result<()> + Send + 'static,
 {
-    tokio::task::spawn_blocking(move || match f() {
-        Ok(_) => tracing::debug!(?name, "task completed successfully"),
-        Err(err) => tracing::error!(?name, ?err, "task failed"),
+    tokio::task::spawn_blocking(move || {
+        crate::profiler::name_thread(name);
+        match f() {
+            Ok(_) => tracing::debug!(?name, "task completed successfully"),
+            Err(err) => tracing::error!(?name, ?err, "task failed"),
+        }
     })
 }
 
+/// Deserialize a comma-separated query parameter such as `voices=a,b` into a list.
+pub fn comma_separated<'de, D, T>(deserializer: D) -> std::result::Result<Option<Vec<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    use serde::Deserialize;
+    let Some(s) = Option::<String>::deserialize(deserializer)? else { return Ok(None) };
+    s.split(',')
+        .map(|v| v.trim().parse::<T>().map_err(|e| serde::de::Error::custom(format!("'{v}': {e}"))))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map(Some)
+}
+
 pub fn model_dtype(over: Option<&str>, dev: &Device) -> Result<DType> {
     let dtype = match over {
         None => dev.bf16_default_to_f32(),
@@ -403,16 +438,6 @@ pub fn model_dtype(over: Option<&str>, dev: &Device) -> Result<DType> {
 /// Default set to 2560MB (2.5GB) to reduce the risk of OOM on 8GB cards.
 pub const DEFAULT_VRAM_RESERVED_MB: u64 = 2560;
 
-/// Default per-batch-item memory cost in MB.
-/// This covers activations, KV cache, and intermediate tensors per concurrent stream.
-/// Empirically tuned for Moshi ASR/TTS workloads.
-pub const DEFAULT_PER_BATCH_ITEM_MB: u64 = 600;
-
-/// Default model size in billions of parameters.
-/// Use 1.0 for stt-1b-* models, 2.6 for stt-2.6b-* models.
-/// Override with MOSHI_MODEL_PARAMS_BILLIONS env var.
-pub const DEFAULT_MODEL_PARAMS_BILLIONS: f64 = 1.0;
-
 /// Estimated memory usage for Mimi audio tokenizer in MB.
 /// Mimi (~200M params) + decoder + buffers roughly take 1GB in F32.
 pub const DEFAULT_MIMI_ESTIMATE_MB: u64 = 1024;
@@ -575,86 +600,6 @@ impl GpuInfo {
         }
     }
 
-    /// Calculates the recommended batch size based on available VRAM.
-    ///
-    /// # Memory Layout
-    /// ```text
-    /// Total Free VRAM
-    /// ├── Reserved (CUDA runtime, driver)     = MOSHI_VRAM_RESERVED_MB
-    /// ├── Model Weights (fixed cost)          = model_params × dtype_bytes
-    /// └── Per-Batch Memory (scales with N)    = N × per_batch_item_mb
-    /// ```
-    ///
-    /// # Arguments
-    /// * `model_params_billions` - Model size in billions of parameters (e.g., 1.0 for 1B)
-    /// * `per_batch_item_mb` - Memory per batch item in MB (activations, KV cache)
-    ///
-    /// # Returns
-    /// A `BatchSizeCalculation` struct with the breakdown and recommended batch size.
-    pub fn calculate_batch_size(
-        &self,
-        model_params_billions: f64,
-        per_batch_item_mb: u64,
-    ) -> BatchSizeCalculation {
-        let dtype_bytes = self.dtype_bytes();
-        let free_vram_mb = self.free_vram_mb();
-
-        // Read configuration from env or use defaults
-        let reserved_mb: u64 = std::env::var("MOSHI_VRAM_RESERVED_MB")
-            .ok()
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(DEFAULT_VRAM_RESERVED_MB);
-
-        let mimi_mb: u64 = std::env::var("MOSHI_MIMI_ESTIMATE_MB")
-            .ok()
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(DEFAULT_MIMI_ESTIMATE_MB);
-
-        // Adjust per_batch_item_mb based on dtype
-        // We assume the default (600MB) is tuned for F16/BF16 (2 bytes).
-        // For F32 (4 bytes), we double it.
-        // For Q8/Q4 (1 byte or less), we could halve it, but we'll be conservative and floor at 2 bytes divisor.
-        let adjusted_per_batch_item_mb = (per_batch_item_mb * dtype_bytes) / 2;
-
-        // Model weights in MB: params × bytes_per_param / 1M
-        let model_weights_mb = ((model_params_billions * 1e9) as u64 * dtype_bytes) / (1024 * 1024);
-
-        // Available for batching = free - reserved - model_weights - mimi
-        let available_for_batching_mb = free_vram_mb
-            .saturating_sub(reserved_mb)
-            .saturating_sub(model_weights_mb)
-            .saturating_sub(mimi_mb);
-
-        // Batch size = available / per_item_cost
-        let max_batch_size = if adjusted_per_batch_item_mb > 0 {
-            (available_for_batching_mb / adjusted_per_batch_item_mb) as usize
-        } else {
-            1
-        };
-
-        let recommended_batch_size = max_batch_size.max(1);
-
-        // Warn if we are potentially oversubscribing VRAM even at batch size 1
-        if available_for_batching_mb == 0 {
-            tracing::warn!(
-                "Available VRAM for batching is 0MB. Defaulting to batch size 1, but OOM is likely. \
-                 Consider reducing VRAM_RESERVED_MB or using a smaller model."
-            );
-        }
-
-        BatchSizeCalculation {
-            free_vram_mb,
-            reserved_mb,
-            model_weights_mb,
-            per_batch_item_mb: adjusted_per_batch_item_mb,
-            available_for_batching_mb,
-            recommended_batch_size,
-            dtype_bytes,
-            model_params_billions,
-            mimi_mb,
-        }
-    }
-
     /// Prints a formatted summary of GPU capabilities to the tracing log.
     /// Note: Prefer `log_combined_summary` when model info is available.
     #[allow(dead_code)]
@@ -688,72 +633,6 @@ impl GpuInfo {
     }
 }
 
-// ============================================================================
-// Batch Size Calculation Result
-// ============================================================================
-
-/// Detailed breakdown of batch size calculation for transparency.
-#[derive(Debug, Clone)]
-pub struct BatchSizeCalculation {
-    /// Free VRAM detected (MB)
-    pub free_vram_mb: u64,
-    /// Reserved for CUDA runtime (MB)
-    pub reserved_mb: u64,
-    /// Model weights memory footprint (MB)
-    pub model_weights_mb: u64,
-    /// Per-batch-item memory cost (MB)
-    pub per_batch_item_mb: u64,
-    /// VRAM available for batch items (MB)
-    pub available_for_batching_mb: u64,
-    /// Recommended batch size
-    pub recommended_batch_size: usize,
-    /// Bytes per parameter
-    pub dtype_bytes: u64,
-    /// Model size in billions of parameters
-    pub model_params_billions: f64,
-    /// Mimi estimate used (MB)
-    pub mimi_mb: u64,
-}
-
-impl BatchSizeCalculation {
-    /// Logs the calculation breakdown with visual formatting.
-    pub fn log_breakdown(&self) {
-        let line = "─".repeat(60);
-
-        tracing::info!("\n{}", line);
-        tracing::info!("  BATCH SIZE CALCULATION");
-        tracing::info!("{}", line);
-        tracing::info!(
-            "  Model: {:.1}B params × {} bytes = {} MB",
-            self.model_params_billions,
-            self.dtype_bytes,
-            self.model_weights_mb
-        );
-        tracing::info!("  + Mimi (est):      {:>6} MB", self.mimi_mb);
-        tracing::info!("");
-        tracing::info!("  Free VRAM:         {:>6} MB", self.free_vram_mb);
-        tracing::info!("  − Reserved:        {:>6} MB  (CUDA runtime)", self.reserved_mb);
-        tracing::info!("  − Model weights:   {:>6} MB  (fixed cost)", self.model_weights_mb);
-        tracing::info!("  ────────────────────────────");
-        tracing::info!(
-            "  = Available:       {:>6} MB  (for batching)",
-            self.available_for_batching_mb
-        );
-        tracing::info!("");
-        tracing::info!(
-            "  Per-batch cost:    {:>6} MB  (activations + KV cache)",
-            self.per_batch_item_mb
-        );
-        tracing::info!(
-            "  Max batch size:    {:>6}     ({} MB ÷ {} MB)",
-            self.recommended_batch_size,
-            self.available_for_batching_mb,
-            self.per_batch_item_mb
-        );
-        tracing::info!("{}", line);
-    }
-}
-
 // ============================================================================
 // Model Information for Logging
 // ============================================================================
@@ -797,9 +676,13 @@ impl ModelInfo {
 
     /// Creates ModelInfo from AsrConfig
     pub fn from_asr_config(config: &crate::AsrConfig) -> Self {
-        let model = &config.model;
+        Self::from_model(&config.lm_model_file, &config.model)
+    }
+
+    /// Creates ModelInfo from a model file and its config
+    pub fn from_model(model_file: &str, model: &moshi::lm::Config) -> Self {
         Self {
-            model_file: config.lm_model_file.clone(),
+            model_file: model_file.to_string(),
             d_model: model.transformer.d_model,
             num_heads: model.transformer.num_heads,
             num_layers: model.transformer.num_layers,
@@ -955,3 +838,101 @@ pub fn get_available_vram() -> Result<u64> {
 pub fn get_available_vram() -> Result<u64> {
     anyhow::bail!("CUDA not available")
 }
+
+/// Whether the name of a static file carries a content hash, e.g. `index-DiwrgTda.js` or
+/// `app.3f9a2c1b.css`: the part of its stem after the last `-` or `.` has 8 to 64 letters and
+/// digits with a digit or an uppercase letter past the first character.
+fn is_hashed_asset(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let Some((stem, _ext)) = name.rsplit_once('.') else { return false };
+    let Some((_, part)) = stem.rsplit_once(['-', '.']) else { return false };
+    (8..=64).contains(&part.len())
+        && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && part.chars().skip(1).any(|c| c.is_ascii_digit() || c.is_ascii_uppercase())
+}
+
+async fn static_cache_control(
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    use axum::http::{header, HeaderValue, StatusCode};
+
+    let immutable = is_hashed_asset(req.uri().path());
+    let mut resp = next.run(req).await;
+    if resp.status().is_success() || resp.status() == StatusCode::NOT_MODIFIED {
+        let value = if immutable { "public, max-age=31536000, immutable" } else { "no-cache" };
+        resp.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static(value));
+    }
+    resp
+}
+
+/// Files of the web client in `dir`. The `.br` and `.gz` variants of a file, when present,
+/// are sent to the browsers that accept them. Files named after their content hash are cached
+/// as immutable, the others, `index.html` first, are revalidated on each load so that a new
+/// deployment is picked up behind a CDN.
+pub fn static_files(dir: &str) -> axum::Router {
+    let serve_dir = tower_http::services::ServeDir::new(dir)
+        .append_index_html_on_directories(true)
+        .precompressed_br()
+        .precompressed_gzip();
+    axum::Router::new()
+        .fallback_service(serve_dir)
+        .layer(axum::middleware::from_fn(static_cache_control))
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ClientVersion {
+    /// Hash of the `index.html` of the web client, changes with every deployment of it.
+    pub version: Option<String>,
+    pub server_version: &'static str,
+}
+
+impl ClientVersion {
+    /// Checked at each call, so that the web client can be redeployed without a restart. The
+    /// hash is only computed again when the modification time of `index.html` changes.
+    pub async fn new(static_dir: Option<&str>) -> Self {
+        Self {
+            version: Self::index_hash(static_dir).await,
+            server_version: env!("CARGO_PKG_VERSION"),
+        }
+    }
+
+    async fn index_hash(static_dir: Option<&str>) -> Option<String> {
+        use sha3::Digest;
+
+        /// Path and modification time of the last `index.html` hashed, with its hash.
+        static LAST: std::sync::Mutex<Option<(std::path::PathBuf, std::time::SystemTime, String)>> =
+            std::sync::Mutex::new(None);
+
+        let path = std::path::Path::new(static_dir?).join("index.html");
+        let modified = tokio::fs::metadata(&path).await.ok()?.modified().ok()?;
+        if let Some((p, m, hash)) = LAST.lock().unwrap().as_ref() {
+            if *p == path && *m == modified {
+                return Some(hash.clone());
+            }
+        }
+        let index = tokio::fs::read(&path).await.ok()?;
+        let digest = sha3::Sha3_256::digest(&index);
+        let hash: String = digest[..8].iter().map(|b| format!("{b:02x}")).collect();
+        *LAST.lock().unwrap() = Some((path, modified, hash.clone()));
+        Some(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashed_assets() {
+        assert!(is_hashed_asset("/assets/index-DiwrgTda.js"));
+        assert!(is_hashed_asset("/app.3f9a2c1b.css"));
+        assert!(is_hashed_asset("/assets/logo-a1b2c3d4.svg"));
+        assert!(!is_hashed_asset("/index.html"));
+        assert!(!is_hashed_asset("/assets/reconnecting-websocket.js"));
+        assert!(!is_hashed_asset("/favicon.ico"));
+        assert!(!is_hashed_asset("/serviceWorker.js"));
+        assert!(!is_hashed_asset("/audioWorklet.js"));
+        assert!(!is_hashed_asset("/assets/TranscriptView.js"));
+    }
+}
@@ -0,0 +1,351 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Voice activity detection only endpoint.
+//!
+//! This runs the ASR model for its extra heads, which predict the probability that the
+//! speaker has paused, and returns per-frame speech probabilities plus utterance boundaries.
+//! The model step still samples the text tokens, which the heads depend on, but the words
+//! are not sent.
+
+use crate::asr::InMsg;
+use anyhow::Result;
+use axum::extract::ws;
+use candle::Tensor;
+use tokio::time::{timeout, Duration};
+
+/// Model frame rate in Hz.
+const FRAME_RATE: f64 = 12.5;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct VadConfig {
+    /// Index of the extra head used as the pause predictor.
+    #[serde(default = "default_vad_head")]
+    pub vad_head: usize,
+    /// A frame counts as speech when its speech probability reaches this threshold.
+    #[serde(default = "default_speech_threshold")]
+    pub speech_threshold: f32,
+    /// Silence duration needed to close an utterance.
+    #[serde(default = "default_min_silence_s")]
+    pub min_silence_s: f64,
+}
+
+pub(crate) fn default_vad_head() -> usize {
+    2
+}
+fn default_speech_threshold() -> f32 {
+    0.5
+}
+fn default_min_silence_s() -> f64 {
+    0.5
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type")]
+pub enum OutMsg {
+    /// The session is set up, with the ids of the upstream request when the gateway sent
+    /// them, see [`crate::otel::TraceIds`].
+    Ready {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        trace_id: Option<String>,
+    },
+    /// Speech probability for a model frame (80ms).
+    Frame {
+        step_idx: usize,
+        time: f64,
+        pr_speech: f32,
+    },
+    SpeechStart {
+        start_time: f64,
+    },
+    SpeechEnd {
+        stop_time: f64,
+    },
+    /// Answers a `Marker` once the audio sent before it has been processed, with the number of
+    /// 24kHz samples received before it and the last model step.
+    Marker {
+        id: i64,
+        sample_idx: u64,
+        step_idx: usize,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl OutMsg {
+    pub fn ready(trace: &crate::otel::TraceIds) -> Self {
+        Self::Ready { request_id: trace.request_id.clone(), trace_id: trace.trace_id.clone() }
+    }
+}
+
+/// Turns the per-frame speech probabilities into utterance boundaries.
+#[derive(Debug, Clone)]
+pub struct Endpointer {
+    threshold: f32,
+    min_silence_frames: usize,
+    in_speech: bool,
+    /// Start time and length of the current run of silent frames within an utterance.
+    silence: Option<(f64, usize)>,
+}
+
+impl Endpointer {
+    pub fn new(threshold: f32, min_silence_s: f64) -> Self {
+        let min_silence_frames = ((min_silence_s * FRAME_RATE).ceil() as usize).max(1);
+        Self { threshold, min_silence_frames, in_speech: false, silence: None }
+    }
+
+    pub fn step(&mut self, time: f64, pr_speech: f32) -> Option<OutMsg> {
+        let is_speech = pr_speech >= self.threshold;
+        if !self.in_speech {
+            if is_speech {
+                self.in_speech = true;
+                self.silence = None;
+                return Some(OutMsg::SpeechStart { start_time: time });
+            }
+            return None;
+        }
+        if is_speech {
+            self.silence = None;
+            return None;
+        }
+        let (start, frames) = self.silence.get_or_insert((time, 0));
+        *frames += 1;
+        if *frames >= self.min_silence_frames {
+            let stop_time = *start;
+            self.in_speech = false;
+            self.silence = None;
+            return Some(OutMsg::SpeechEnd { stop_time });
+        }
+        None
+    }
+
+    /// Close the pending utterance at the end of the stream.
+    pub fn flush(&mut self, time: f64) -> Option<OutMsg> {
+        if !self.in_speech {
+            return None;
+        }
+        self.in_speech = false;
+        let stop_time = self.silence.take().map_or(time, |(start, _)| start);
+        Some(OutMsg::SpeechEnd { stop_time })
+    }
+}
+
+enum Input {
+    Pcm(Vec<f32>),
+    Marker(i64),
+}
+
+pub struct Vad {
+    asr: crate::asr::Asr,
+    config: VadConfig,
+}
+
+impl Vad {
+    pub fn new(
+        asr: &crate::AsrConfig,
+        vad: &VadConfig,
+        config: &crate::Config,
+        dev: &candle::Device,
+    ) -> Result<Self> {
+        let num_heads = asr.model.extra_heads.as_ref().map_or(0, |h| h.num_heads);
+        if vad.vad_head >= num_heads {
+            anyhow::bail!(
+                "vad_head {} is out of range, the model has {num_heads} extra heads",
+                vad.vad_head
+            )
+        }
+        if asr.shadow.is_some() {
+            anyhow::bail!("shadow models are only supported by the asr and batched asr modules")
+        }
+        let asr = crate::asr::Asr::new(asr, config, dev)?;
+        Ok(Self { asr, config: vad.clone() })
+    }
+
+    pub fn warmup(&self) -> Result<()> {
+        self.asr.warmup()
+    }
+
+    pub async fn handle_socket(&self, socket: crate::limits::LimitedSocket) -> Result<()> {
+        use futures_util::{SinkExt, StreamExt};
+
+        crate::metrics::vad::CONNECT.inc();
+        let limits = socket.limits().clone();
+        let closer = socket.closer();
+        let auth = socket.auth();
+        let trace = socket.trace().clone();
+        let (mut sender, mut receiver) = socket.split();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<OutMsg>();
+        let (in_tx, in_rx) = std::sync::mpsc::sync_channel::<Input>(100);
+        let mut state = self.asr.new_state()?;
+        let conditions = self.asr.conditions().cloned();
+        let steps = self.asr.steps().clone();
+        let vad_head = self.config.vad_head;
+        let mut endpointer =
+            Endpointer::new(self.config.speech_threshold, self.config.min_silence_s);
+
+        let recv_loop = crate::utils::spawn("vad_recv_loop", async move {
+            let mut ogg_opus_decoder = kaudio::ogg_opus::Decoder::new(24000, 1920)?;
+            let mut resampler = crate::resample::InputResampler::default();
+            while let Some(msg) = receiver.next().await {
+                let msg = match msg? {
+                    ws::Message::Binary(x) => x,
+                    ws::Message::Ping(_) | ws::Message::Pong(_) | ws::Message::Text(_) => continue,
+                    ws::Message::Close(_) => break,
+                };
+                let msg = match crate::protocol::decode_in_msg(&msg) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        tracing::warn!(error = %e, msg_len = msg.len(), "failed to deserialize InMsg, skipping message");
+                        continue;
+                    }
+                };
+                let input = match msg {
+                    InMsg::Init
+                    | InMsg::Ping
+                    | InMsg::Checkpoint
+                    | InMsg::Restore { .. }
+                    | InMsg::AudioCodes { .. }
+                    | InMsg::Enroll { .. }
+                    | InMsg::Echo { .. }
+                    | InMsg::TimeCorrection { .. } => None,
+                    InMsg::Marker { id } => Some(Input::Marker(id)),
+                    InMsg::RefreshToken { jwt } => {
+                        crate::auth::SessionAuth::handle_refresh(auth.as_ref(), &jwt);
+                        None
+                    }
+                    InMsg::OggOpus { data } => {
+                        ogg_opus_decoder.decode(&data)?.map(|v| Input::Pcm(v.to_vec()))
+                    }
+                    InMsg::Audio { pcm } => match limits.check_pcm(pcm.len()) {
+                        Ok(()) => Some(Input::Pcm(resampler.process(pcm)?)),
+                        Err(err) => {
+                            closer.close(err);
+                            None
+                        }
+                    },
+                    InMsg::SetSampleRate { hz } => match resampler.set_rate(hz) {
+                        Ok(tail) => (!tail.is_empty()).then_some(Input::Pcm(tail)),
+                        Err(err) => {
+                            tracing::warn!(?err, rate = resampler.rate(), "ignoring SetSampleRate");
+                            None
+                        }
+                    },
+                };
+                if let Some(input) = input {
+                    in_tx.send(input)?;
+                }
+            }
+            Ok::<(), anyhow::Error>(())
+        });
+
+        tx.send(OutMsg::ready(&trace))?;
+        // Run the inference loop within the session span so that the steps are recorded on it.
+        let span = tracing::Span::current();
+        let inference_handle = crate::utils::spawn_blocking("vad_inference_loop", move || {
+            let _enter = span.enter();
+            let dev = state.device().clone();
+            let mut time = 0.;
+            let (mut sample_idx, mut last_step) = (0u64, 0);
+            for input in in_rx {
+                let pcm = match input {
+                    Input::Pcm(pcm) => pcm,
+                    Input::Marker(id) => {
+                        tx.send(OutMsg::Marker { id, sample_idx, step_idx: last_step })?;
+                        continue;
+                    }
+                };
+                let pcm_len = pcm.len();
+                sample_idx += pcm_len as u64;
+                let pcm = Tensor::from_vec(pcm, (1, 1, pcm_len), &dev)?;
+                let msgs = steps.install(|| {
+                    state.step_pcm(pcm, conditions.as_ref(), &().into(), |_, _, _| ())
+                })?;
+                for msg in msgs {
+                    if let moshi::asr::AsrMsg::Step { step_idx, prs } = msg {
+                        let pr_pause = prs.get(vad_head).and_then(|p| p.first()).copied();
+                        let pr_speech = 1. - pr_pause.unwrap_or(0.);
+                        time = step_idx as f64 / FRAME_RATE;
+                        last_step = step_idx;
+                        crate::otel::record_steps(step_idx);
+                        tx.send(OutMsg::Frame { step_idx, time, pr_speech })?;
+                        if let Some(msg) = endpointer.step(time, pr_speech) {
+                            crate::metrics::vad::UTTERANCE_BOUNDARIES.inc();
+                            tx.send(msg)?;
+                        }
+                    }
+                }
+            }
+            if let Some(msg) = endpointer.flush(time) {
+                tx.send(msg)?;
+            }
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let send_loop = crate::utils::spawn("vad_send_loop", async move {
+            loop {
+                // The recv method is cancel-safe so can be wrapped in a timeout.
+                let msg = match timeout(Duration::from_secs(10), rx.recv()).await {
+                    Ok(None) => break,
+                    Err(_) => ws::Message::Ping(vec![].into()),
+                    Ok(Some(msg)) => {
+                        let msg = rmp_serde::to_vec_named(&msg)?;
+                        ws::Message::Binary(msg.into())
+                    }
+                };
+                sender.send(msg).await?;
+            }
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let mut recv_handle = recv_loop;
+        let mut send_handle = send_loop;
+        tokio::select! {
+            _ = &mut recv_handle => {}
+            _ = &mut send_handle => {}
+        }
+        recv_handle.abort();
+        // Let the inference loop drain the remaining audio and close the last utterance.
+        let _ = tokio::time::timeout(Duration::from_secs(2), async {
+            let _ = inference_handle.await;
+            let _ = (&mut send_handle).await;
+        })
+        .await;
+        send_handle.abort();
+        tracing::info!("exiting vad handle-socket");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpointer_boundaries() {
+        // 0.25s of silence needed to close an utterance, i.e. 4 frames.
+        let mut ep = Endpointer::new(0.5, 0.25);
+        let prs = [0.1, 0.9, 0.8, 0.2, 0.9, 0.1, 0.1, 0.2, 0.3, 0.1];
+        let msgs = prs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &p)| ep.step(i as f64 / FRAME_RATE, p))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            msgs,
+            vec![OutMsg::SpeechStart { start_time: 0.08 }, OutMsg::SpeechEnd { stop_time: 0.4 }]
+        );
+        assert_eq!(ep.flush(1.0), None);
+    }
+
+    #[test]
+    fn endpointer_flush_closes_utterance() {
+        let mut ep = Endpointer::new(0.5, 1.0);
+        assert!(ep.step(0.0, 0.7).is_some());
+        assert_eq!(ep.step(0.08, 0.1), None);
+        assert_eq!(ep.flush(0.16), Some(OutMsg::SpeechEnd { stop_time: 0.08 }));
+    }
+}
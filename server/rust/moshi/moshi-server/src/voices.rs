@@ -0,0 +1,143 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Catalog of the voices offered by a TTS module.
+//!
+//! The metadata comes from a sidecar TOML file set with `voice_catalog`, keyed by the name
+//! that requests pass as `voice`:
+//!
+//! ```toml
+//! [voices."expresso/ex03-ex01_happy_001_channel1_334s.wav"]
+//! language = "en"
+//! tags = ["female", "happy"]
+//! description = "Upbeat, conversational."
+//! ```
+//!
+//! The catalog lists the preloaded voices of the module and the voices described in the
+//! sidecar, which can be files of the voice directory.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+
+/// Metadata of a voice, from the sidecar or from the admin endpoints.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Meta {
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Sidecar {
+    #[serde(default)]
+    voices: BTreeMap<String, Meta>,
+}
+
+/// A voice as returned by the `voices` endpoint.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct VoiceInfo {
+    pub name: String,
+    pub language: Option<String>,
+    pub tags: Vec<String>,
+    pub description: Option<String>,
+    /// Whether a preview clip can be fetched from `voices/preview`.
+    pub preview: bool,
+}
+
+/// Build the catalog from the preloaded voice names and the content of the sidecar file.
+pub fn catalog<'a>(
+    preloaded: impl IntoIterator<Item = &'a String>,
+    sidecar: Option<&str>,
+) -> Result<Vec<VoiceInfo>> {
+    let mut sidecar: Sidecar = match sidecar {
+        None => Sidecar::default(),
+        Some(text) => toml::from_str(text)?,
+    };
+    let mut names: Vec<_> = preloaded.into_iter().cloned().collect();
+    names.sort();
+    let described: Vec<_> = sidecar.voices.keys().filter(|n| !names.contains(n)).cloned().collect();
+    names.extend(described);
+    let voices = names
+        .into_iter()
+        .map(|name| {
+            let meta = sidecar.voices.remove(&name).unwrap_or_default();
+            VoiceInfo {
+                name,
+                language: meta.language,
+                tags: meta.tags,
+                description: meta.description,
+                preview: false,
+            }
+        })
+        .collect();
+    Ok(voices)
+}
+
+/// Replace the metadata of the catalog voices with the `stored` one. The stored voices that
+/// are not in the catalog are left out, the module cannot synthesize them.
+pub fn overlay(mut voices: Vec<VoiceInfo>, mut stored: BTreeMap<String, Meta>) -> Vec<VoiceInfo> {
+    for voice in voices.iter_mut() {
+        if let Some(meta) = stored.remove(&voice.name) {
+            voice.language = meta.language;
+            voice.tags = meta.tags;
+            voice.description = meta.description;
+        }
+    }
+    voices
+}
+
+/// Read the sidecar file of a TTS module, if any, and build its catalog.
+pub fn load<'a>(
+    preloaded: impl IntoIterator<Item = &'a String>,
+    sidecar: Option<&str>,
+) -> Result<Vec<VoiceInfo>> {
+    match sidecar {
+        None => catalog(preloaded, None),
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("cannot read voice catalog {path}"))?;
+            catalog(preloaded, Some(&text)).with_context(|| format!("invalid voice catalog {path}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sidecar_is_merged_with_preloaded_voices() {
+        let preloaded = ["default".to_string(), "alt".to_string()];
+        let sidecar = r#"
+            [voices.default]
+            language = "en"
+            tags = ["female"]
+            description = "The default voice."
+
+            [voices."vctk/p225_023.wav"]
+            language = "en"
+            tags = ["female", "british"]
+        "#;
+        let voices = catalog(&preloaded, Some(sidecar)).unwrap();
+        let names: Vec<_> = voices.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["alt", "default", "vctk/p225_023.wav"]);
+        assert_eq!(voices[0].language, None);
+        assert_eq!(voices[1].description.as_deref(), Some("The default voice."));
+        assert_eq!(voices[2].tags, ["female", "british"]);
+
+        assert_eq!(catalog(&preloaded, None).unwrap().len(), 2);
+        let calm = Meta { tags: vec!["calm".to_string()], ..Meta::default() };
+        let stored = [("alt".to_string(), calm), ("custom".to_string(), Meta::default())];
+        let voices = overlay(voices, stored.into_iter().collect());
+        let names: Vec<_> = voices.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["alt", "default", "vctk/p225_023.wav"]);
+        assert_eq!(voices[0].tags, ["calm"]);
+        assert!(catalog(&preloaded, Some("[voices.a]\ngender = \"f\"\n")).is_err());
+    }
+}
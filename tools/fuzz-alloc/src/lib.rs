@@ -0,0 +1,65 @@
+//! Allocation tracking shared by the fuzz targets of the server and the client.
+//!
+//! The targets install [`Tracking`] as their global allocator and decode each input through
+//! [`check_alloc`], which fails when decoding a frame allocates much more than the frame size.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Allowed allocation per input byte: the internally tagged enums buffer each value in a
+/// 32 bytes serde `Content` before converting it.
+pub const ALLOC_PER_BYTE: usize = 48;
+/// Allowed allocation regardless of the input size, serde preallocates up to 1MiB for each
+/// nesting level accepted by the decoders, at most 5 for the coalesced frames of the client.
+pub const ALLOC_BASE: usize = 8 << 20;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// Global allocator keeping track of the peak allocation.
+pub struct Tracking;
+
+unsafe impl GlobalAlloc for Tracking {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let current = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(current, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Run `decode` on `data` and panic if it allocated more than the budget for `data.len()`
+/// bytes. Only meaningful when [`Tracking`] is the global allocator.
+pub fn check_alloc<T>(data: &[u8], decode: impl FnOnce(&[u8]) -> T) -> T {
+    let base = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(base, Ordering::Relaxed);
+    let res = decode(data);
+    let used = PEAK.load(Ordering::Relaxed).saturating_sub(base);
+    assert!(
+        used <= ALLOC_BASE + ALLOC_PER_BYTE * data.len(),
+        "decoding {} bytes allocated {used} bytes",
+        data.len()
+    );
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[global_allocator]
+    static GLOBAL: Tracking = Tracking;
+
+    #[test]
+    fn budget() {
+        let len = check_alloc(&[0u8; 16], |d| d.to_vec().len());
+        assert_eq!(len, 16);
+        let res = std::panic::catch_unwind(|| {
+            check_alloc(&[], |_| vec![0u8; ALLOC_BASE + 1].len());
+        });
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,262 @@
+//! Model Context Protocol server exposing the speech-to-text and text-to-speech endpoints of
+//! a moshi-server deployment as tools, so that LLM agents and IDE assistants can use them.
+//!
+//! JSON-RPC messages are exchanged either over stdio, one message per line, or over the
+//! HTTP+SSE transport: clients open `GET /sse`, receive the endpoint to post their requests
+//! to, and get the responses back as `message` events. The SSE transport requires a bearer
+//! token and keeps the files of the tools under a root directory.
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+mod sse;
+mod tools;
+
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Base URL of the moshi-server deployment.
+    #[arg(long, default_value = "http://localhost:8080")]
+    url: String,
+
+    /// Path of the batched ASR module, audio files are posted to it.
+    #[arg(long, default_value = "/api/asr-streaming")]
+    stt_path: String,
+
+    /// Path of the TTS module.
+    #[arg(long, default_value = "/api/tts")]
+    tts_path: String,
+
+    /// Bearer token sent to the server.
+    #[arg(long, env = "KYUTAI_TOKEN")]
+    token: Option<String>,
+
+    /// Serve the HTTP+SSE transport on this address instead of using stdio, 127.0.0.1:8765 when
+    /// no address is given.
+    #[arg(long, num_args = 0..=1, default_missing_value = "127.0.0.1:8765")]
+    sse: Option<std::net::SocketAddr>,
+
+    /// Bearer token that the SSE clients have to send, a random one is printed when unset.
+    #[arg(long, env = "MCP_SSE_TOKEN")]
+    sse_token: Option<String>,
+
+    /// Origin of a web page allowed to use the SSE transport, the requests with another
+    /// `Origin` header are refused.
+    #[arg(long)]
+    allow_origin: Vec<String>,
+
+    /// Host name accepted by the SSE transport besides the loopback ones and the bind address.
+    #[arg(long)]
+    allow_host: Vec<String>,
+
+    /// Directory that the files read and written by the tools have to be in. Defaults to the
+    /// current directory with `--sse`, and to no restriction with stdio.
+    #[arg(long)]
+    root: Option<std::path::PathBuf>,
+}
+
+/// Handle a JSON-RPC message, returns the response or `None` for notifications.
+async fn handle(backend: &tools::Backend, msg: Value) -> Option<Value> {
+    if let Value::Array(batch) = msg {
+        let mut responses = Vec::new();
+        for msg in batch {
+            responses.extend(Box::pin(handle(backend, msg)).await);
+        }
+        return (!responses.is_empty()).then_some(Value::Array(responses));
+    }
+    let id = msg.get("id").cloned();
+    let Some(method) = msg.get("method").and_then(Value::as_str) else {
+        return id.map(|id| error(id, -32600, "invalid request"));
+    };
+    // Notifications, e.g. `notifications/initialized`, do not get a response.
+    let id = id?;
+    let params = msg.get("params").cloned().unwrap_or(Value::Null);
+    let result = match method {
+        "initialize" => json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": { "tools": {} },
+            "serverInfo": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+        }),
+        "ping" => json!({}),
+        "tools/list" => json!({ "tools": tools::list() }),
+        "tools/call" => {
+            let Some(name) = params.get("name").and_then(Value::as_str) else {
+                return Some(error(id, -32602, "missing tool name"));
+            };
+            let args = params
+                .get("arguments")
+                .cloned()
+                .unwrap_or_else(|| json!({}));
+            // Tool failures are reported to the model rather than as protocol errors.
+            let (text, is_error) = match backend.call(name, args).await {
+                Ok(text) => (text, false),
+                Err(err) => (format!("{err:#}"), true),
+            };
+            json!({ "content": [{ "type": "text", "text": text }], "isError": is_error })
+        }
+        _ => return Some(error(id, -32601, &format!("unknown method {method}"))),
+    };
+    Some(json!({ "jsonrpc": "2.0", "id": id, "result": result }))
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+/// Parse and handle a raw message, replying with a parse error when it is not JSON.
+async fn handle_text(backend: &tools::Backend, text: &str) -> Option<Value> {
+    match serde_json::from_str(text) {
+        Ok(msg) => handle(backend, msg).await,
+        Err(err) => Some(error(Value::Null, -32700, &err.to_string())),
+    }
+}
+
+async fn run_stdio(backend: Arc<tools::Backend>) -> Result<()> {
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Value>();
+    let writer = tokio::spawn(async move {
+        let mut stdout = tokio::io::stdout();
+        while let Some(msg) = rx.recv().await {
+            stdout.write_all(format!("{msg}\n").as_bytes()).await?;
+            stdout.flush().await?;
+        }
+        anyhow::Ok(())
+    });
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        // Tool calls can take a while, handle them concurrently so that pings still get through.
+        let backend = backend.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            if let Some(resp) = handle_text(&backend, &line).await {
+                let _ = tx.send(resp);
+            }
+        });
+    }
+    drop(tx);
+    writer.await?
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let root = match (cli.root, cli.sse) {
+        (Some(root), _) => Some(root),
+        (None, Some(_)) => Some(std::env::current_dir()?),
+        (None, None) => None,
+    };
+    let root = match root {
+        Some(root) => Some(
+            std::fs::canonicalize(&root)
+                .with_context(|| format!("cannot resolve {}", root.display()))?,
+        ),
+        None => None,
+    };
+    let backend = Arc::new(tools::Backend {
+        http: reqwest::Client::new(),
+        url: cli.url,
+        stt_path: cli.stt_path,
+        tts_path: cli.tts_path,
+        token: cli.token,
+        root,
+    });
+    match cli.sse {
+        Some(addr) => {
+            let token = cli.sse_token.unwrap_or_else(|| {
+                let token = format!("{:032x}", rand::random::<u128>());
+                eprintln!("SSE clients authenticate with: Authorization: Bearer {token}");
+                token
+            });
+            let access = sse::Access::new(token, cli.allow_origin, cli.allow_host, addr);
+            sse::serve(backend, addr, access).await
+        }
+        None => run_stdio(backend).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A backend pointing at a closed port, tool calls fail without leaving the host.
+    pub(crate) fn backend() -> Arc<tools::Backend> {
+        Arc::new(tools::Backend {
+            http: reqwest::Client::new(),
+            url: "http://127.0.0.1:9".to_string(),
+            stt_path: "/api/asr-streaming".to_string(),
+            tts_path: "/api/tts".to_string(),
+            token: None,
+            root: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn json_rpc() {
+        let backend = backend();
+        let resp = handle_text(
+            &backend,
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize"}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp["id"], 1);
+        assert_eq!(resp["result"]["protocolVersion"], PROTOCOL_VERSION);
+
+        let resp = handle_text(
+            &backend,
+            r#"{"jsonrpc":"2.0","id":2,"method":"tools/list"}"#,
+        )
+        .await
+        .unwrap();
+        let names: Vec<_> = resp["result"]["tools"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| t["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            ["transcribe_audio_file", "synthesize_speech", "list_voices"]
+        );
+
+        let notification = r#"{"jsonrpc":"2.0","method":"notifications/initialized"}"#;
+        assert!(handle_text(&backend, notification).await.is_none());
+
+        let resp = handle_text(&backend, r#"{"jsonrpc":"2.0","id":3,"method":"nope"}"#)
+            .await
+            .unwrap();
+        assert_eq!(resp["error"]["code"], -32601);
+        let resp = handle_text(&backend, "{").await.unwrap();
+        assert_eq!(resp["error"]["code"], -32700);
+        assert_eq!(resp["id"], Value::Null);
+
+        let batch = r#"[{"jsonrpc":"2.0","id":4,"method":"ping"},
+            {"jsonrpc":"2.0","method":"notifications/initialized"}]"#;
+        let resp = handle_text(&backend, batch).await.unwrap();
+        assert_eq!(resp, json!([{ "jsonrpc": "2.0", "id": 4, "result": {} }]));
+    }
+
+    #[tokio::test]
+    async fn tool_errors_are_results() {
+        let backend = backend();
+        let call = |name: &str| {
+            json!({ "jsonrpc": "2.0", "id": 5, "method": "tools/call",
+                "params": { "name": name, "arguments": { "path": "/nonexistent.wav" } } })
+        };
+        for name in ["unknown_tool", "transcribe_audio_file", "list_voices"] {
+            let resp = handle(&backend, call(name)).await.unwrap();
+            assert_eq!(resp["result"]["isError"], true, "{name}");
+            assert!(resp["result"]["content"][0]["text"].as_str().unwrap() != "");
+        }
+        let missing = json!({ "jsonrpc": "2.0", "id": 6, "method": "tools/call", "params": {} });
+        let resp = handle(&backend, missing).await.unwrap();
+        assert_eq!(resp["error"]["code"], -32602);
+    }
+}
@@ -0,0 +1,294 @@
+//! HTTP+SSE transport: each `GET /sse` stream is a session, requests are posted to
+//! `/message?sessionId=...` and their responses are pushed on the session stream.
+//!
+//! The tools read and write local files and spend the server token, so every request needs the
+//! bearer token of the transport. Requests from web pages are refused unless their `Origin` is
+//! allowed, and the `Host` has to be a loopback name, the bind address or an allowed host, so
+//! that a page cannot reach the transport through DNS rebinding.
+
+use crate::tools::Backend;
+use anyhow::Result;
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::IntoResponse;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Who may use the transport.
+pub struct Access {
+    pub token: String,
+    /// Origins of the web pages allowed to connect.
+    pub origins: Vec<String>,
+    /// Host names accepted in the `Host` header, without the port.
+    pub hosts: Vec<String>,
+}
+
+impl Access {
+    /// Loopback names, the bind address and `hosts`.
+    pub fn new(
+        token: String,
+        origins: Vec<String>,
+        hosts: Vec<String>,
+        addr: std::net::SocketAddr,
+    ) -> Self {
+        let mut all = vec![
+            "localhost".to_string(),
+            "127.0.0.1".to_string(),
+            "[::1]".to_string(),
+        ];
+        if !addr.ip().is_unspecified() {
+            all.push(match addr {
+                std::net::SocketAddr::V4(a) => a.ip().to_string(),
+                std::net::SocketAddr::V6(a) => format!("[{}]", a.ip()),
+            });
+        }
+        all.extend(hosts.into_iter().map(|h| h.to_ascii_lowercase()));
+        Self {
+            token,
+            origins,
+            hosts: all,
+        }
+    }
+
+    fn check(&self, headers: &HeaderMap) -> Result<(), (StatusCode, &'static str)> {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        let host = header(header::HOST)
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        // Drop the port, IPv6 addresses being in brackets.
+        let host = match host.find(']') {
+            Some(end) => &host[..end + 1],
+            None => host.split(':').next().unwrap_or_default(),
+        };
+        if !self.hosts.iter().any(|h| h == host) {
+            return Err((StatusCode::FORBIDDEN, "host not allowed"));
+        }
+        if let Some(origin) = header(header::ORIGIN) {
+            if !self.origins.iter().any(|o| o == origin) {
+                return Err((StatusCode::FORBIDDEN, "origin not allowed"));
+            }
+        }
+        let token = header(header::AUTHORIZATION).and_then(|v| v.strip_prefix("Bearer "));
+        if !token.is_some_and(|t| constant_time_eq(t.as_bytes(), self.token.as_bytes())) {
+            return Err((StatusCode::UNAUTHORIZED, "missing or invalid bearer token"));
+        }
+        Ok(())
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn check_access(
+    State(access): State<Arc<Access>>,
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    match access.check(req.headers()) {
+        Ok(()) => next.run(req).await,
+        Err(err) => err.into_response(),
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    backend: Arc<Backend>,
+    sessions: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Value>>>>,
+}
+
+#[derive(serde::Deserialize)]
+struct MessageQuery {
+    #[serde(rename = "sessionId")]
+    session_id: String,
+}
+
+/// Removes the session once its event stream is dropped by the client.
+struct SessionGuard {
+    id: String,
+    sessions: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Value>>>>,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+async fn open(State(state): State<AppState>) -> impl IntoResponse {
+    // Anyone knowing the id can post requests to the session, so it must not be guessable.
+    let id = format!("{:032x}", rand::random::<u128>());
+    let (tx, rx) = mpsc::unbounded_channel();
+    state.sessions.lock().unwrap().insert(id.clone(), tx);
+    let endpoint = Event::default()
+        .event("endpoint")
+        .data(format!("/message?sessionId={id}"));
+    let guard = SessionGuard {
+        id,
+        sessions: state.sessions.clone(),
+    };
+    let messages = futures_util::stream::unfold((rx, guard), |(mut rx, guard)| async move {
+        let msg = rx.recv().await?;
+        let event = Event::default().event("message").data(msg.to_string());
+        Some((Ok::<_, std::convert::Infallible>(event), (rx, guard)))
+    });
+    let events = futures_util::StreamExt::chain(
+        futures_util::stream::once(async move { Ok(endpoint) }),
+        messages,
+    );
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+async fn message(
+    State(state): State<AppState>,
+    Query(query): Query<MessageQuery>,
+    body: String,
+) -> impl IntoResponse {
+    let Some(tx) = state
+        .sessions
+        .lock()
+        .unwrap()
+        .get(&query.session_id)
+        .cloned()
+    else {
+        return (StatusCode::NOT_FOUND, "unknown session").into_response();
+    };
+    tokio::spawn(async move {
+        if let Some(resp) = crate::handle_text(&state.backend, &body).await {
+            let _ = tx.send(resp);
+        }
+    });
+    StatusCode::ACCEPTED.into_response()
+}
+
+fn router(backend: Arc<Backend>, access: Access) -> axum::Router {
+    let state = AppState {
+        backend,
+        sessions: Default::default(),
+    };
+    axum::Router::new()
+        .route("/sse", axum::routing::get(open))
+        .route("/message", axum::routing::post(message))
+        .with_state(state)
+        .layer(axum::middleware::from_fn_with_state(
+            Arc::new(access),
+            check_access,
+        ))
+}
+
+pub async fn serve(
+    backend: Arc<Backend>,
+    addr: std::net::SocketAddr,
+    access: Access,
+) -> Result<()> {
+    let app = router(backend, access);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    eprintln!("MCP server listening on http://{addr}/sse");
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read the event stream until an event of type `event`, returns its data.
+    async fn next_event(resp: &mut reqwest::Response, buf: &mut String, event: &str) -> String {
+        loop {
+            if let Some(end) = buf.find("\n\n") {
+                let block: String = buf.drain(..end + 2).collect();
+                let mut lines = block.lines();
+                if lines.next() == Some(&format!("event: {event}")) {
+                    let data = lines.next().unwrap();
+                    return data.strip_prefix("data: ").unwrap().to_string();
+                }
+                continue;
+            }
+            let chunk = resp.chunk().await.unwrap().expect("event stream closed");
+            buf.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn session_round_trip() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let access = Access::new("secret".to_string(), vec![], vec![], addr);
+        let app = router(crate::tests::backend(), access);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        let http = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .unwrap();
+        let base = format!("http://{addr}");
+
+        let mut buf = String::new();
+        let mut sse = http.get(format!("{base}/sse")).send().await.unwrap();
+        let endpoint = next_event(&mut sse, &mut buf, "endpoint").await;
+        let id = endpoint.strip_prefix("/message?sessionId=").unwrap();
+        assert_eq!(id.len(), 32);
+        let mut other = http.get(format!("{base}/sse")).send().await.unwrap();
+        assert_ne!(
+            next_event(&mut other, &mut String::new(), "endpoint").await,
+            endpoint
+        );
+
+        let resp = http
+            .post(format!("{base}{endpoint}"))
+            .body(r#"{"jsonrpc":"2.0","id":7,"method":"ping"}"#)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let msg: Value =
+            serde_json::from_str(&next_event(&mut sse, &mut buf, "message").await).unwrap();
+        assert_eq!(msg["id"], 7);
+        assert_eq!(msg["result"], serde_json::json!({}));
+
+        let resp = http
+            .post(format!("{base}/message?sessionId=0"))
+            .body(r#"{"jsonrpc":"2.0","id":1,"method":"ping"}"#)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn access_is_checked() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let origins = vec!["http://localhost:3000".to_string()];
+        let access = Access::new("secret".to_string(), origins, vec![], addr);
+        let app = router(crate::tests::backend(), access);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let http = reqwest::Client::new();
+        let status = |headers: &[(header::HeaderName, &str)]| {
+            let mut req = http.get(format!("http://{addr}/sse"));
+            for (name, value) in headers {
+                req = req.header(name, *value);
+            }
+            async move { req.send().await.unwrap().status() }
+        };
+        let auth = (header::AUTHORIZATION, "Bearer secret");
+        assert_eq!(status(&[]).await, StatusCode::UNAUTHORIZED);
+        let wrong = (header::AUTHORIZATION, "Bearer secreT");
+        assert_eq!(status(&[wrong]).await, StatusCode::UNAUTHORIZED);
+        let rebound = (header::HOST, "attacker.example:8765");
+        assert_eq!(
+            status(&[auth.clone(), rebound]).await,
+            StatusCode::FORBIDDEN
+        );
+        let page = (header::ORIGIN, "http://attacker.example");
+        assert_eq!(status(&[auth.clone(), page]).await, StatusCode::FORBIDDEN);
+        let page = (header::ORIGIN, "http://localhost:3000");
+        assert_eq!(status(&[auth.clone(), page]).await, StatusCode::OK);
+        let host = (header::HOST, "localhost:8765");
+        assert_eq!(status(&[auth, host]).await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,245 @@
+//! The tools exposed to MCP clients, backed by the HTTP endpoints of a moshi-server.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+
+/// Where the moshi-server modules are mounted.
+pub struct Backend {
+    pub http: reqwest::Client,
+    pub url: String,
+    pub stt_path: String,
+    pub tts_path: String,
+    pub token: Option<String>,
+    /// Canonical directory that the files read and written by the tools have to be in.
+    pub root: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct TranscribeArgs {
+    path: PathBuf,
+    #[serde(default)]
+    timestamps: bool,
+}
+
+#[derive(Deserialize)]
+struct SynthesizeArgs {
+    text: String,
+    voice: Option<String>,
+    output_path: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum AsrMsg {
+    Word {
+        text: String,
+        start_time: f64,
+    },
+    Error {
+        message: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// The `tools/list` descriptions, with the JSON schema of the arguments of each tool.
+pub fn list() -> Value {
+    json!([
+        {
+            "name": "transcribe_audio_file",
+            "description": "Transcribe a local audio file (wav, mp3, ogg, flac) with the speech-to-text server.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "Path of the audio file." },
+                    "timestamps": { "type": "boolean", "description": "Return one word per line prefixed with its start time in seconds." }
+                },
+                "required": ["path"]
+            }
+        },
+        {
+            "name": "synthesize_speech",
+            "description": "Synthesize speech from text with the text-to-speech server and write it to a wav file.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "text": { "type": "string", "description": "Text to speak." },
+                    "voice": { "type": "string", "description": "Voice name, see list_voices." },
+                    "output_path": { "type": "string", "description": "Where to write the wav file, defaults to a temporary file." }
+                },
+                "required": ["text"]
+            }
+        },
+        {
+            "name": "list_voices",
+            "description": "List the voices offered by the text-to-speech server with their language, tags and description.",
+            "inputSchema": { "type": "object", "properties": {} }
+        }
+    ])
+}
+
+impl Backend {
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}{}", self.url.trim_end_matches('/'), path)
+    }
+
+    fn authorize(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.token {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        }
+    }
+
+    async fn send(&self, req: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let resp = self.authorize(req).send().await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            anyhow::bail!("server returned {status}: {}", body.trim());
+        }
+        Ok(resp)
+    }
+
+    /// `path` within the root, relative paths being taken from it. The paths leading out of the
+    /// root are refused, through `..` or symlinks alike. A path to write to only needs its
+    /// directory to exist.
+    async fn in_root(&self, path: &Path, existing: bool) -> Result<PathBuf> {
+        let Some(root) = self.root.as_ref() else {
+            return Ok(path.to_path_buf());
+        };
+        let path = root.join(path);
+        let resolved = if existing {
+            tokio::fs::canonicalize(&path).await
+        } else {
+            let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+                anyhow::bail!("invalid path {}", path.display())
+            };
+            let resolved = tokio::fs::canonicalize(dir).await.map(|dir| dir.join(name));
+            // Writing through an existing symlink would follow it.
+            if let Ok(resolved) = resolved.as_ref() {
+                let meta = tokio::fs::symlink_metadata(resolved).await;
+                if meta.is_ok_and(|m| m.file_type().is_symlink()) {
+                    anyhow::bail!("{} is a symlink", path.display())
+                }
+            }
+            resolved
+        }
+        .with_context(|| format!("cannot resolve {}", path.display()))?;
+        if !resolved.starts_with(root) {
+            anyhow::bail!("{} is outside of {}", path.display(), root.display())
+        }
+        Ok(resolved)
+    }
+
+    /// Run the tool `name`, the returned text is what the MCP client gets back.
+    pub async fn call(&self, name: &str, args: Value) -> Result<String> {
+        match name {
+            "transcribe_audio_file" => self.transcribe(serde_json::from_value(args)?).await,
+            "synthesize_speech" => self.synthesize(serde_json::from_value(args)?).await,
+            "list_voices" => self.list_voices().await,
+            _ => anyhow::bail!("unknown tool {name}"),
+        }
+    }
+
+    async fn transcribe(&self, args: TranscribeArgs) -> Result<String> {
+        let path = self.in_root(&args.path, true).await?;
+        let audio = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("cannot read {}", path.display()))?;
+        let req = self.http.post(self.endpoint(&self.stt_path)).body(audio);
+        let msgs: Vec<AsrMsg> = serde_json::from_slice(&self.send(req).await?.bytes().await?)?;
+        let mut words = Vec::new();
+        for msg in msgs {
+            match msg {
+                AsrMsg::Word { text, start_time } if args.timestamps => {
+                    words.push(format!("{start_time:.2} {text}"))
+                }
+                AsrMsg::Word { text, .. } => words.push(text),
+                AsrMsg::Error { message } => anyhow::bail!("transcription failed: {message}"),
+                AsrMsg::Other => {}
+            }
+        }
+        Ok(words.join(if args.timestamps { "\n" } else { " " }))
+    }
+
+    async fn synthesize(&self, args: SynthesizeArgs) -> Result<String> {
+        let query = json!({
+            "text": [args.text],
+            "seed": 42,
+            "temperature": 0.8,
+            "top_k": 250,
+            "voice": args.voice,
+        });
+        let req = self
+            .http
+            .post(self.endpoint(&self.tts_path))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(query.to_string());
+        let wav = self.send(req).await?.bytes().await?;
+        let path = match args.output_path {
+            Some(path) => self.in_root(&path, false).await?,
+            None => {
+                let ms = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis());
+                std::env::temp_dir().join(format!("kyutai-tts-{ms}.wav"))
+            }
+        };
+        tokio::fs::write(&path, &wav)
+            .await
+            .with_context(|| format!("cannot write {}", path.display()))?;
+        Ok(format!(
+            "Wrote {} bytes of audio to {}",
+            wav.len(),
+            path.display()
+        ))
+    }
+
+    async fn list_voices(&self) -> Result<String> {
+        let req = self
+            .http
+            .get(self.endpoint(&format!("{}/voices", self.tts_path)));
+        let voices: Value = serde_json::from_slice(&self.send(req).await?.bytes().await?)?;
+        Ok(serde_json::to_string_pretty(&voices)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn paths_stay_in_the_root() {
+        let dir = std::env::temp_dir().join(format!("mcp-root-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("audio")).unwrap();
+        std::fs::write(root.join("audio/a.wav"), b"").unwrap();
+        std::fs::write(dir.join("secret"), b"").unwrap();
+        let root = std::fs::canonicalize(&root).unwrap();
+        let mut backend = crate::tests::backend();
+        Arc::get_mut(&mut backend).unwrap().root = Some(root.clone());
+
+        let read = |p: &'static str| backend.in_root(Path::new(p), true);
+        assert_eq!(read("audio/a.wav").await.unwrap(), root.join("audio/a.wav"));
+        let inside = root.join("audio/../audio/a.wav");
+        assert!(backend.in_root(&inside, true).await.is_ok());
+        for path in ["../secret", "/etc/passwd", "audio/missing.wav"] {
+            assert!(read(path).await.is_err(), "{path}");
+        }
+
+        let write = |p: &'static str| backend.in_root(Path::new(p), false);
+        assert_eq!(write("out.wav").await.unwrap(), root.join("out.wav"));
+        assert!(write("../out.wav").await.is_err());
+        assert!(write("missing/out.wav").await.is_err());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("secret"), root.join("link.wav")).unwrap();
+            assert!(write("link.wav").await.is_err());
+            assert!(read("link.wav").await.is_err());
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
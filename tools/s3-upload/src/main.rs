@@ -1,13 +1,23 @@
 use anyhow::{Context, Result};
 use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_s3::primitives::ByteStream;
-use aws_sdk_s3::{Client, types::ObjectCannedAcl};
+use aws_sdk_s3::primitives::{ByteStream, Length};
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart, Delete, ObjectIdentifier};
+use aws_sdk_s3::{types::ObjectCannedAcl, Client};
 use clap::Parser;
-use std::fs::{self, File};
+use std::collections::HashSet;
+use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use walkdir::WalkDir;
 
+const MB: u64 = 1024 * 1024;
+/// Smallest part accepted by S3, except for the last one.
+const MIN_PART_SIZE: u64 = 5 * MB;
+/// Keys per DeleteObjects request, the S3 maximum.
+const DELETE_BATCH: usize = 1000;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
@@ -27,7 +37,7 @@ struct Cli {
     #[arg(long)]
     include_raw: bool,
 
-    /// Print the actions without uploading anything.
+    /// Print the actions without uploading or deleting anything.
     #[arg(long)]
     dry_run: bool,
 
@@ -35,6 +45,31 @@ struct Cli {
     #[arg(long)]
     force: bool,
 
+    /// Delete the remote objects under the prefix that no longer exist locally.
+    #[arg(long)]
+    delete: bool,
+
+    /// Allow `--delete` without a prefix, which deletes every object of the bucket that does
+    /// not exist locally.
+    #[arg(long)]
+    delete_whole_bucket: bool,
+
+    /// Number of files uploaded concurrently.
+    #[arg(long, default_value_t = 4)]
+    jobs: usize,
+
+    /// Attempts for each S3 request before giving up on a file, with exponential backoff.
+    #[arg(long, default_value_t = 5)]
+    retries: u32,
+
+    /// Files above this size (in MB) are sent as multi-part uploads.
+    #[arg(long, default_value_t = 100)]
+    multipart_threshold_mb: u64,
+
+    /// Size of the parts of multi-part uploads, in MB (at least 5).
+    #[arg(long, default_value_t = 16)]
+    part_size_mb: u64,
+
     /// Optional AWS profile name.
     #[arg(long)]
     profile: Option<String>,
@@ -58,14 +93,48 @@ struct PublishStats {
     skipped: usize,
     dry_run_uploads: usize,
     bytes_uploaded: u64,
+    deleted: usize,
+    dry_run_deletes: usize,
+    failed: usize,
+}
+
+/// Settings shared by the upload tasks.
+struct Uploader {
+    client: Client,
+    bucket: String,
+    acl: Option<ObjectCannedAcl>,
+    dry_run: bool,
+    force: bool,
+    retries: u32,
+    multipart_threshold: u64,
+    part_size: u64,
+}
+
+/// A local file and the key it is uploaded to.
+struct Upload {
+    path: PathBuf,
+    rel_path: String,
+    key: String,
+}
+
+enum Outcome {
+    Uploaded(u64),
+    Skipped,
+    DryRun,
 }
 
 fn compute_md5(path: &Path) -> Result<String> {
     let mut file = File::open(path)?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
-    let digest = md5::compute(&buffer);
-    Ok(format!("{:x}", digest))
+    let mut context = md5::Context::new();
+    let mut buffer = vec![0u8; MB as usize];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        context.consume(&buffer[..n]);
+    }
+    Ok(format!("{:x}", context.finalize()))
 }
 
 fn human_bytes(value: u64) -> String {
@@ -80,6 +149,320 @@ fn human_bytes(value: u64) -> String {
     format!("{} B", value)
 }
 
+fn parse_acl(acl: &str) -> ObjectCannedAcl {
+    match acl {
+        "private" => ObjectCannedAcl::Private,
+        "public-read" => ObjectCannedAcl::PublicRead,
+        // Add others if needed, clap could handle enum
+        _ => ObjectCannedAcl::Private,
+    }
+}
+
+/// Whether a path relative to the source holds raw traces.
+fn is_raw(rel_path: &Path) -> bool {
+    rel_path.components().any(|c| c.as_os_str() == "raw")
+}
+
+/// Prefix of the keys listed for `--delete`, with a trailing slash so that `logs` does not
+/// match `logs-old/`.
+fn list_prefix(prefix: &str) -> String {
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{prefix}/")
+    }
+}
+
+/// The keys listed under `list_prefix` that are not in `keep`. Raw traces are only synced
+/// along with the other files.
+fn stale_keys<'a>(
+    list_prefix: &str,
+    listed: impl IntoIterator<Item = &'a str>,
+    keep: &HashSet<String>,
+    include_raw: bool,
+) -> Vec<String> {
+    listed
+        .into_iter()
+        .filter(|key| {
+            let Some(rel_path) = key.strip_prefix(list_prefix) else {
+                return false;
+            };
+            !keep.contains(*key) && (include_raw || !is_raw(Path::new(rel_path)))
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+/// Run `f` up to `attempts` times, waiting 500ms then twice longer after each failure, up to
+/// 30s.
+async fn with_retries<T, F, Fut>(attempts: u32, what: &str, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut delay = Duration::from_millis(500);
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Ok(v) => return Ok(v),
+            Err(err) if attempt >= attempts.max(1) => {
+                return Err(err.context(format!("{what} failed after {attempt} attempts")));
+            }
+            Err(err) => {
+                println!("[retry] {what} (attempt {attempt}): {err:#}");
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(Duration::from_secs(30));
+                attempt += 1;
+            }
+        }
+    }
+}
+
+impl Uploader {
+    async fn unchanged(&self, key: &str, md5_hex: &str) -> bool {
+        let resp = match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(resp) => resp,
+            Err(_) => return false, // NotFound or other error -> try upload
+        };
+        let metadata_match = resp
+            .metadata()
+            .and_then(|m| m.get("local-md5"))
+            .is_some_and(|m| m == md5_hex);
+        let etag_match = resp
+            .e_tag()
+            .is_some_and(|etag| etag.trim_matches('"') == md5_hex);
+        metadata_match || etag_match
+    }
+
+    async fn upload(&self, file: &Upload) -> Result<Outcome> {
+        let path = file.path.clone();
+        let md5_hex = tokio::task::spawn_blocking(move || compute_md5(&path)).await??;
+
+        if !self.force && self.unchanged(&file.key, &md5_hex).await {
+            println!(
+                "[skip] {} unchanged (s3://{}/{})",
+                file.rel_path, self.bucket, file.key
+            );
+            return Ok(Outcome::Skipped);
+        }
+        if self.dry_run {
+            println!(
+                "[dry-run] Would upload {} -> s3://{}/{} (md5={})",
+                file.rel_path, self.bucket, file.key, md5_hex
+            );
+            return Ok(Outcome::DryRun);
+        }
+
+        let size = std::fs::metadata(&file.path)?.len();
+        if size > self.multipart_threshold {
+            self.upload_multipart(file, &md5_hex, size).await?;
+        } else {
+            with_retries(
+                self.retries,
+                &format!("upload of {}", file.rel_path),
+                || async {
+                    let body = ByteStream::from_path(&file.path).await?;
+                    let mut req = self
+                        .client
+                        .put_object()
+                        .bucket(&self.bucket)
+                        .key(&file.key)
+                        .body(body)
+                        .metadata("local-md5", &md5_hex)
+                        .content_type("text/plain");
+                    if let Some(acl) = &self.acl {
+                        req = req.acl(acl.clone());
+                    }
+                    req.send().await?;
+                    Ok(())
+                },
+            )
+            .await?;
+        }
+        println!(
+            "[upload] {} -> s3://{}/{} ({})",
+            file.rel_path,
+            self.bucket,
+            file.key,
+            human_bytes(size)
+        );
+        Ok(Outcome::Uploaded(size))
+    }
+
+    /// Upload a large file in parts, each part being retried on its own. The upload is
+    /// aborted if a part cannot be sent, so that no incomplete parts are left billed.
+    async fn upload_multipart(&self, file: &Upload, md5_hex: &str, size: u64) -> Result<()> {
+        let what = format!("multi-part upload of {}", file.rel_path);
+        let upload_id = with_retries(self.retries, &what, || async {
+            let mut req = self
+                .client
+                .create_multipart_upload()
+                .bucket(&self.bucket)
+                .key(&file.key)
+                .metadata("local-md5", md5_hex)
+                .content_type("text/plain");
+            if let Some(acl) = &self.acl {
+                req = req.acl(acl.clone());
+            }
+            let resp = req.send().await?;
+            resp.upload_id()
+                .map(str::to_string)
+                .context("no upload id in the response")
+        })
+        .await?;
+
+        match self.upload_parts(file, &upload_id, size).await {
+            Ok(parts) => {
+                let parts = CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build();
+                with_retries(self.retries, &what, || async {
+                    self.client
+                        .complete_multipart_upload()
+                        .bucket(&self.bucket)
+                        .key(&file.key)
+                        .upload_id(&upload_id)
+                        .multipart_upload(parts.clone())
+                        .send()
+                        .await?;
+                    Ok(())
+                })
+                .await
+            }
+            Err(err) => {
+                let abort = self
+                    .client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(&file.key)
+                    .upload_id(&upload_id)
+                    .send()
+                    .await;
+                if let Err(abort_err) = abort {
+                    println!("[error] Failed to abort {what}: {abort_err}");
+                }
+                Err(err)
+            }
+        }
+    }
+
+    async fn upload_parts(
+        &self,
+        file: &Upload,
+        upload_id: &str,
+        size: u64,
+    ) -> Result<Vec<CompletedPart>> {
+        let mut parts = vec![];
+        for (index, offset) in (0..size).step_by(self.part_size as usize).enumerate() {
+            let part_number = index as i32 + 1;
+            let length = self.part_size.min(size - offset);
+            let what = format!("part {part_number} of {}", file.rel_path);
+            let e_tag = with_retries(self.retries, &what, || async {
+                let body = ByteStream::read_from()
+                    .path(&file.path)
+                    .offset(offset)
+                    .length(Length::Exact(length))
+                    .build()
+                    .await?;
+                let resp = self
+                    .client
+                    .upload_part()
+                    .bucket(&self.bucket)
+                    .key(&file.key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .body(body)
+                    .send()
+                    .await?;
+                Ok(resp.e_tag().map(str::to_string))
+            })
+            .await?;
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(e_tag)
+                    .part_number(part_number)
+                    .build(),
+            );
+        }
+        Ok(parts)
+    }
+
+    /// Delete the objects under `prefix` whose key is not in `keep`.
+    async fn delete_stale(
+        &self,
+        prefix: &str,
+        keep: &HashSet<String>,
+        include_raw: bool,
+        stats: &mut PublishStats,
+    ) -> Result<()> {
+        let list_prefix = list_prefix(prefix);
+        let mut stale = vec![];
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(&list_prefix)
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            let page = page.context("Failed to list the remote objects")?;
+            let listed = page.contents().iter().filter_map(|o| o.key());
+            stale.extend(stale_keys(&list_prefix, listed, keep, include_raw));
+        }
+
+        for key in stale.iter() {
+            if self.dry_run {
+                println!("[dry-run] Would delete s3://{}/{}", self.bucket, key);
+            } else {
+                println!("[delete] s3://{}/{}", self.bucket, key);
+            }
+        }
+        if self.dry_run {
+            stats.dry_run_deletes += stale.len();
+            return Ok(());
+        }
+        for batch in stale.chunks(DELETE_BATCH) {
+            let objects = batch
+                .iter()
+                .map(|key| ObjectIdentifier::builder().key(key).build())
+                .collect::<Result<Vec<_>, _>>()?;
+            let delete = Delete::builder()
+                .set_objects(Some(objects))
+                .quiet(true)
+                .build()?;
+            let resp = with_retries(self.retries, "delete of stale objects", || async {
+                let resp = self
+                    .client
+                    .delete_objects()
+                    .bucket(&self.bucket)
+                    .delete(delete.clone())
+                    .send()
+                    .await?;
+                Ok(resp)
+            })
+            .await?;
+            for err in resp.errors() {
+                println!(
+                    "[error] Failed to delete s3://{}/{}: {}",
+                    self.bucket,
+                    err.key().unwrap_or_default(),
+                    err.message().unwrap_or_default()
+                );
+                stats.failed += 1;
+            }
+            stats.deleted += batch.len() - resp.errors().len();
+        }
+        Ok(())
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
@@ -87,6 +470,15 @@ async fn main() -> Result<()> {
     if !cli.source.exists() {
         anyhow::bail!("Source directory {:?} does not exist.", cli.source);
     }
+    if cli.part_size_mb * MB < MIN_PART_SIZE {
+        anyhow::bail!("--part-size-mb must be at least {}", MIN_PART_SIZE / MB);
+    }
+    if cli.delete && cli.prefix.trim_matches('/').is_empty() && !cli.delete_whole_bucket {
+        anyhow::bail!(
+            "--delete without a --prefix would delete every object of the bucket missing \
+             locally, pass --delete-whole-bucket to confirm"
+        );
+    }
 
     let region_provider = RegionProviderChain::first_try(cli.region.map(aws_types::region::Region::new))
         .or_default_provider()
@@ -111,6 +503,7 @@ async fn main() -> Result<()> {
     let mut stats = PublishStats::default();
     let prefix = cli.prefix.trim_matches('/');
 
+    let mut files = vec![];
     for entry in WalkDir::new(&cli.source) {
         let entry = entry?;
         if !entry.file_type().is_file() {
@@ -118,97 +511,111 @@ async fn main() -> Result<()> {
         }
 
         let path = entry.path();
-        if !cli.include_raw {
-            if let Ok(rel) = path.strip_prefix(&cli.source) {
-                 if rel.components().any(|c| c.as_os_str() == "raw") {
-                     continue;
-                 }
-            }
+        let rel_path = path.strip_prefix(&cli.source)?;
+        if !cli.include_raw && is_raw(rel_path) {
+            continue;
         }
 
-        let rel_path = path.strip_prefix(&cli.source)?;
         let rel_path_str = rel_path.to_string_lossy().replace("\\", "/");
-        let s3_key = if prefix.is_empty() {
-             rel_path_str
+        let key = if prefix.is_empty() {
+            rel_path_str.clone()
         } else {
-             format!("{}/{}", prefix, rel_path_str)
+            format!("{}/{}", prefix, rel_path_str)
         };
-
-        let md5_hex = compute_md5(path)?;
-
-        let needs_upload = if cli.force {
-            true
-        } else {
-            match client.head_object().bucket(&cli.bucket).key(&s3_key).send().await {
-                Ok(resp) => {
-                    let mut remote_match = false;
-                    if let Some(metadata) = resp.metadata() {
-                        if let Some(remote_md5) = metadata.get("local-md5") {
-                            if remote_md5 == &md5_hex {
-                                remote_match = true;
-                            }
-                        }
-                    }
-                    if !remote_match {
-                         if let Some(etag) = resp.e_tag() {
-                             let etag_clean = etag.trim_matches('"');
-                             if etag_clean == md5_hex {
-                                 remote_match = true;
-                             }
-                         }
-                    }
-
-                    if remote_match {
-                        stats.skipped += 1;
-                        println!("[skip] {} unchanged (s3://{}/{})", rel_path.display(), cli.bucket, s3_key);
-                        false
-                    } else {
-                        true
-                    }
-                }
-                Err(_) => true, // NotFound or other error -> try upload
-            }
-        };
-
-        if needs_upload {
-            if cli.dry_run {
-                stats.dry_run_uploads += 1;
-                println!("[dry-run] Would upload {} -> s3://{}/{} (md5={})", rel_path.display(), cli.bucket, s3_key, md5_hex);
-            } else {
-                let body = ByteStream::from_path(path).await?;
-                let mut req = client.put_object()
-                    .bucket(&cli.bucket)
-                    .key(&s3_key)
-                    .body(body)
-                    .metadata("local-md5", &md5_hex)
-                    .content_type("text/plain");
-
-                if let Some(acl_str) = &cli.acl {
-                    let acl = match acl_str.as_str() {
-                        "private" => ObjectCannedAcl::Private,
-                        "public-read" => ObjectCannedAcl::PublicRead,
-                        // Add others if needed, clap could handle enum
-                        _ => ObjectCannedAcl::Private,
-                    };
-                    req = req.acl(acl);
-                }
-
-                req.send().await.context(format!("Failed to upload {}", path.display()))?;
-
-                let size = fs::metadata(path)?.len();
+        files.push(Upload { path: path.to_path_buf(), rel_path: rel_path_str, key });
+    }
+    let keys: HashSet<String> = files.iter().map(|f| f.key.clone()).collect();
+
+    let uploader = Arc::new(Uploader {
+        client,
+        bucket: cli.bucket,
+        acl: cli.acl.as_deref().map(parse_acl),
+        dry_run: cli.dry_run,
+        force: cli.force,
+        retries: cli.retries,
+        multipart_threshold: cli.multipart_threshold_mb * MB,
+        part_size: cli.part_size_mb * MB,
+    });
+    let permits = Arc::new(tokio::sync::Semaphore::new(cli.jobs.max(1)));
+    let mut tasks = tokio::task::JoinSet::new();
+    for file in files {
+        let (uploader, permits) = (uploader.clone(), permits.clone());
+        tasks.spawn(async move {
+            let _permit = permits.acquire_owned().await?;
+            let outcome = uploader.upload(&file).await;
+            Ok::<_, anyhow::Error>((file, outcome))
+        });
+    }
+    while let Some(task) = tasks.join_next().await {
+        let (file, outcome) = task??;
+        match outcome {
+            Ok(Outcome::Uploaded(size)) => {
                 stats.uploaded += 1;
                 stats.bytes_uploaded += size;
-                println!("[upload] {} -> s3://{}/{} ({})", rel_path.display(), cli.bucket, s3_key, human_bytes(size));
+            }
+            Ok(Outcome::Skipped) => stats.skipped += 1,
+            Ok(Outcome::DryRun) => stats.dry_run_uploads += 1,
+            Err(err) => {
+                println!("[error] Failed to upload {}: {err:#}", file.path.display());
+                stats.failed += 1;
             }
         }
     }
 
+    // Deleting after a failed upload could remove the only remaining copy of a file that was
+    // renamed locally.
+    if cli.delete && stats.failed == 0 {
+        uploader
+            .delete_stale(prefix, &keys, cli.include_raw, &mut stats)
+            .await?;
+    } else if cli.delete {
+        println!("[skip] Not deleting stale objects after failed uploads.");
+    }
+
     println!();
     println!("Summary:");
     println!("  Uploaded: {}", stats.uploaded);
     println!("  Skipped (unchanged): {}", stats.skipped);
     println!("  Dry-run uploads: {}", stats.dry_run_uploads);
     println!("  Bytes uploaded: {}", human_bytes(stats.bytes_uploaded));
+    if cli.delete {
+        println!("  Deleted: {}", stats.deleted);
+        println!("  Dry-run deletes: {}", stats.dry_run_deletes);
+    }
+    println!("  Failed: {}", stats.failed);
 
+    if stats.failed > 0 {
+        anyhow::bail!("{} operations failed", stats.failed);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_keys_under_the_prefix() {
+        let keep: HashSet<String> = ["logs/a.log".to_string()].into();
+        let listed = [
+            "logs/a.log",
+            "logs/b.log",
+            "logs/raw/c.bin",
+            "logs-old/d.log",
+        ];
+        let prefix = list_prefix("logs");
+        assert_eq!(stale_keys(&prefix, listed, &keep, false), ["logs/b.log"]);
+        assert_eq!(
+            stale_keys(&prefix, listed, &keep, true),
+            ["logs/b.log", "logs/raw/c.bin"]
+        );
+
+        // Without a prefix every key of the bucket is a candidate.
+        let keep: HashSet<String> = ["a.log".to_string()].into();
+        let listed = ["a.log", "b.log", "other/raw/c.bin"];
+        assert_eq!(
+            stale_keys(&list_prefix(""), listed, &keep, false),
+            ["b.log"]
+        );
+    }
+}
@@ -1,10 +1,14 @@
 use anyhow::{Context, Result};
 use clap::Parser;
+use hf_hub::{api::sync::Api, Repo, RepoType};
 use nvml_wrapper::Nvml;
+use serde::Serialize;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, ExitCode};
 
+mod verify;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
@@ -21,7 +25,10 @@ struct Cli {
     input_path: Option<PathBuf>,
 
     /// Destination path for the fp16 checkpoint.
-    #[arg(long, default_value = "assets/fp16/stt-1b-en_fr-candle.fp16.safetensors")]
+    #[arg(
+        long,
+        default_value = "assets/fp16/stt-1b-en_fr-candle.fp16.safetensors"
+    )]
     output: PathBuf,
 
     /// Target dtype for converted tensors.
@@ -43,6 +50,111 @@ struct Cli {
     /// Only print the converter command instead of executing it.
     #[arg(long)]
     dry_run: bool,
+
+    /// Checkpoint to convert, repeatable: a local file, a Hugging Face repo (with
+    /// --model-file) or `repo:file`. Replaces --hf-repo and --input-path.
+    #[arg(long = "checkpoint")]
+    checkpoints: Vec<String>,
+
+    /// Directory of the converted checkpoints given with --checkpoint.
+    #[arg(long, default_value = "assets/fp16")]
+    output_dir: PathBuf,
+
+    /// Compare each converted checkpoint with its source after the conversion.
+    #[arg(long)]
+    verify: bool,
+
+    /// Random inputs multiplied by each weight matrix during the verification.
+    #[arg(long, default_value_t = 4)]
+    verify_inputs: usize,
+
+    /// Largest relative error of the outputs of a tensor accepted by the verification.
+    #[arg(long, default_value_t = 1e-2)]
+    tolerance: f64,
+
+    /// Write a JSON report of the conversions and verifications to this file.
+    #[arg(long)]
+    report: Option<PathBuf>,
+}
+
+/// Where a checkpoint comes from.
+#[derive(Clone, Debug)]
+enum Source {
+    Local(PathBuf),
+    Hub { repo: String, file: String },
+}
+
+impl Source {
+    /// A local file if it exists, else `repo` or `repo:file` on the Hugging Face hub.
+    fn parse(spec: &str, default_file: &str) -> Self {
+        if Path::new(spec).exists() {
+            return Self::Local(PathBuf::from(spec));
+        }
+        match spec.split_once(':') {
+            Some((repo, file)) => Self::Hub {
+                repo: repo.to_string(),
+                file: file.to_string(),
+            },
+            None => Self::Hub {
+                repo: spec.to_string(),
+                file: default_file.to_string(),
+            },
+        }
+    }
+
+    /// Name of the converted file, e.g. `stt-1b-en_fr-candle.fp16.safetensors`.
+    fn output_name(&self, dtype: &str) -> String {
+        let suffix = match dtype {
+            "float32" => "fp32",
+            _ => "fp16",
+        };
+        let stem = match self {
+            Self::Local(path) => path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            Self::Hub { repo, file } => {
+                let name = repo.rsplit('/').next().unwrap_or(repo);
+                match file.strip_suffix(".safetensors") {
+                    Some("model") | None => name.to_string(),
+                    Some(stem) => format!("{name}-{stem}"),
+                }
+            }
+        };
+        format!("{stem}.{suffix}.safetensors")
+    }
+
+    /// Path of the source checkpoint, from the Hugging Face cache for hub checkpoints.
+    fn local_path(&self) -> Result<PathBuf> {
+        match self {
+            Self::Local(path) => Ok(path.clone()),
+            Self::Hub { repo, file } => {
+                let api = Api::new()?;
+                Ok(api
+                    .repo(Repo::new(repo.clone(), RepoType::Model))
+                    .get(file)?)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Local(path) => write!(f, "{}", path.display()),
+            Self::Hub { repo, file } => write!(f, "hf://{repo}/{file}"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CheckpointReport {
+    source: String,
+    output: PathBuf,
+    converted: bool,
+    error: Option<String>,
+    verification: Option<verify::VerifyReport>,
 }
 
 struct DeviceCapability {
@@ -63,14 +175,14 @@ fn detect_devices(simulate: Option<&String>) -> Vec<DeviceCapability> {
         // Simple simulation parsing: sm75 -> major=7, minor=5
         let sim = sim.trim().trim_start_matches("sm");
         if sim.len() >= 2 {
-             if let (Ok(major), Ok(minor)) = (sim[0..1].parse(), sim[1..2].parse()) {
-                 return vec![DeviceCapability {
-                     index: 0,
-                     name: format!("Simulated sm{}{}", major, minor),
-                     major,
-                     minor,
-                 }];
-             }
+            if let (Ok(major), Ok(minor)) = (sim[0..1].parse(), sim[1..2].parse()) {
+                return vec![DeviceCapability {
+                    index: 0,
+                    name: format!("Simulated sm{}{}", major, minor),
+                    major,
+                    minor,
+                }];
+            }
         }
     }
 
@@ -79,15 +191,15 @@ fn detect_devices(simulate: Option<&String>) -> Vec<DeviceCapability> {
         if let Ok(count) = nvml.device_count() {
             for i in 0..count {
                 if let Ok(device) = nvml.device_by_index(i) {
-                     if let Ok(cap) = device.cuda_compute_capability() {
-                         let name = device.name().unwrap_or_else(|_| "Unknown".to_string());
-                         devices.push(DeviceCapability {
-                             index: i,
-                             name,
-                             major: cap.major,
-                             minor: cap.minor,
-                         });
-                     }
+                    if let Ok(cap) = device.cuda_compute_capability() {
+                        let name = device.name().unwrap_or_else(|_| "Unknown".to_string());
+                        devices.push(DeviceCapability {
+                            index: i,
+                            name,
+                            major: cap.major,
+                            minor: cap.minor,
+                        });
+                    }
                 }
             }
         }
@@ -97,58 +209,147 @@ fn detect_devices(simulate: Option<&String>) -> Vec<DeviceCapability> {
 
 fn main() -> Result<ExitCode> {
     let cli = Cli::parse();
-    
+
     // We assume bf16-to-fp16 is built and available via cargo run
     // In a real installed scenario, we'd expect the binary in PATH.
     // For this repo, we use cargo run --bin bf16-to-fp16
-    
+
     let devices = detect_devices(cli.simulate.as_ref());
-    
+
     if devices.is_empty() {
         println!("No CUDA devices detected (or simulation failed).");
         if cli.skip_when_undetected && !cli.force {
-             println!("Skipping conversion.");
-             return Ok(ExitCode::SUCCESS);
+            println!("Skipping conversion.");
+            return Ok(ExitCode::SUCCESS);
         }
     } else {
         println!("Detected CUDA devices:");
         for dev in &devices {
-            let status = if dev.is_pre_ampere() { "PRE-AMPERE" } else { "Ampere+" };
-            println!("- #{} {} (sm{}{}, {})", dev.index, dev.name, dev.major, dev.minor, status);
+            let status = if dev.is_pre_ampere() {
+                "PRE-AMPERE"
+            } else {
+                "Ampere+"
+            };
+            println!(
+                "- #{} {} (sm{}{}, {})",
+                dev.index, dev.name, dev.major, dev.minor, status
+            );
         }
     }
 
-    let needs_conversion = cli.force || devices.iter().any(|d| d.is_pre_ampere()) || (devices.is_empty() && !cli.skip_when_undetected);
+    let needs_conversion = cli.force
+        || devices.iter().any(|d| d.is_pre_ampere())
+        || (devices.is_empty() && !cli.skip_when_undetected);
 
     if !needs_conversion {
         println!("No pre-Ampere GPUs detected and --force not set; skipping the conversion.");
         return Ok(ExitCode::SUCCESS);
     }
 
-    let mut cmd = Command::new("cargo");
-    cmd.arg("run").arg("--quiet").arg("--bin").arg("bf16-to-fp16").arg("--");
-    
-    cmd.arg("--hf-repo").arg(&cli.hf_repo);
-    cmd.arg("--model-file").arg(&cli.model_file);
-    cmd.arg("--output").arg(&cli.output);
-    if let Some(input) = &cli.input_path {
-        cmd.arg("--input-path").arg(input);
+    let jobs: Vec<(Source, PathBuf)> = if cli.checkpoints.is_empty() {
+        let source = match &cli.input_path {
+            Some(input) => Source::Local(input.clone()),
+            None => Source::Hub {
+                repo: cli.hf_repo.clone(),
+                file: cli.model_file.clone(),
+            },
+        };
+        vec![(source, cli.output.clone())]
+    } else {
+        cli.checkpoints
+            .iter()
+            .map(|spec| {
+                let source = Source::parse(spec, &cli.model_file);
+                let output = cli.output_dir.join(source.output_name(&cli.dtype));
+                (source, output)
+            })
+            .collect()
+    };
+
+    let mut reports = vec![];
+    for (source, output) in jobs {
+        let mut cmd = Command::new("cargo");
+        cmd.arg("run")
+            .arg("--quiet")
+            .arg("--bin")
+            .arg("bf16-to-fp16")
+            .arg("--");
+        match &source {
+            Source::Local(path) => cmd.arg("--input-path").arg(path),
+            Source::Hub { repo, file } => {
+                cmd.arg("--hf-repo").arg(repo).arg("--model-file").arg(file)
+            }
+        };
+        cmd.arg("--output").arg(&output);
+        // bf16-to-fp16 takes enum, so we pass string that matches ValueEnum
+        cmd.arg("--dtype").arg(&cli.dtype);
+
+        if cli.dry_run {
+            println!("[dry-run] Would execute: {:?}", cmd);
+            continue;
+        }
+
+        println!("Running conversion of {source}...");
+        let mut report = CheckpointReport {
+            source: source.to_string(),
+            output: output.clone(),
+            converted: false,
+            error: None,
+            verification: None,
+        };
+        let status = cmd.status().context("Failed to execute bf16-to-fp16")?;
+        if !status.success() {
+            println!("Conversion of {source} failed with status: {status}");
+            report.error = Some(format!("conversion failed with status: {status}"));
+            reports.push(report);
+            continue;
+        }
+        report.converted = true;
+        println!("{} checkpoint ready at {:?}.", cli.dtype, output);
+
+        if cli.verify {
+            println!("Verifying {:?} against {source}...", output);
+            let verification = source
+                .local_path()
+                .and_then(|src| verify::verify(&src, &output, cli.verify_inputs, cli.tolerance));
+            match verification {
+                Ok(v) => {
+                    println!(
+                        "{} tensors ({} converted), max relative error {:.2e}{}, {} overflows, {} underflows: {}",
+                        v.tensors,
+                        v.converted_tensors,
+                        v.max_rel_error,
+                        v.worst_tensor.as_deref().map(|t| format!(" ({t})")).unwrap_or_default(),
+                        v.overflows,
+                        v.underflows,
+                        if v.passed { "PASSED" } else { "FAILED" },
+                    );
+                    for failure in v.failures.iter() {
+                        println!("- {}: {}", failure.name, failure.reason);
+                    }
+                    report.verification = Some(v);
+                }
+                Err(err) => {
+                    println!("Verification of {:?} failed: {err:#}", output);
+                    report.error = Some(format!("verification failed: {err:#}"));
+                }
+            }
+        }
+        reports.push(report);
     }
-    // bf16-to-fp16 takes enum, so we pass string that matches ValueEnum
-    cmd.arg("--dtype").arg(&cli.dtype);
-    
-    if cli.dry_run {
-        println!("[dry-run] Would execute: {:?}", cmd);
-        return Ok(ExitCode::SUCCESS);
+
+    if let Some(path) = &cli.report {
+        std::fs::write(path, serde_json::to_string_pretty(&reports)?)
+            .with_context(|| format!("Failed to write the report {:?}", path))?;
+        println!("Report written to {:?}.", path);
     }
 
-    println!("Running conversion...");
-    let status = cmd.status().context("Failed to execute bf16-to-fp16")?;
-    
-    if status.success() {
-        println!("fp16 checkpoint ready at {:?}.", cli.output);
-        Ok(ExitCode::SUCCESS)
-    } else {
-        anyhow::bail!("Conversion failed with status: {}", status);
+    let failed = reports
+        .iter()
+        .any(|r| r.error.is_some() || r.verification.as_ref().is_some_and(|v| !v.passed));
+    if failed {
+        println!("Some checkpoints failed, see above.");
+        return Ok(ExitCode::FAILURE);
     }
+    Ok(ExitCode::SUCCESS)
 }
@@ -0,0 +1,252 @@
+use anyhow::{Context, Result};
+use candle_core::safetensors::MmapedSafetensors;
+use candle_core::{DType, Device, Tensor};
+use serde::Serialize;
+use std::path::Path;
+
+/// Outcome of the comparison of a converted checkpoint with its source.
+#[derive(Serialize)]
+pub struct VerifyReport {
+    pub tensors: usize,
+    pub converted_tensors: usize,
+    /// Largest relative L2 error of the outputs of a tensor, see [`compare`].
+    pub max_rel_error: f64,
+    pub worst_tensor: Option<String>,
+    /// Finite source values that became infinite, out of the range of the target dtype.
+    pub overflows: usize,
+    /// Non-zero source values that became zero.
+    pub underflows: usize,
+    /// Tensors missing, reshaped, overflowing or above the tolerance.
+    pub failures: Vec<TensorFailure>,
+    pub passed: bool,
+}
+
+#[derive(Serialize)]
+pub struct TensorFailure {
+    pub name: String,
+    pub reason: String,
+}
+
+struct TensorStats {
+    rel_error: f64,
+    overflows: usize,
+    underflows: usize,
+}
+
+fn rel_l2(reference: &Tensor, other: &Tensor) -> Result<f64> {
+    let diff = (other - reference)?.sqr()?.sum_all()?.to_scalar::<f32>()? as f64;
+    let norm = reference.sqr()?.sum_all()?.to_scalar::<f32>()? as f64;
+    let error = if norm > 0.0 {
+        (diff / norm).sqrt()
+    } else {
+        diff.sqrt()
+    };
+    // NaN comes from infinite outputs, it fails the comparison too.
+    Ok(if error.is_nan() { f64::INFINITY } else { error })
+}
+
+/// Compare a converted tensor with its source. Matrices, and higher-rank weights flattened to
+/// one, are multiplied by `inputs` random vectors, the converted one in its own dtype as it
+/// would on the GPU, and the outputs compared. Vectors such as norms and biases are compared
+/// directly.
+fn compare(source: &Tensor, converted: &Tensor, inputs: usize) -> Result<TensorStats> {
+    let reference = source.to_dtype(DType::F32)?;
+    let values = reference.flatten_all()?.to_vec1::<f32>()?;
+    let converted_values = converted
+        .flatten_all()?
+        .to_dtype(DType::F32)?
+        .to_vec1::<f32>()?;
+    let mut overflows = 0;
+    let mut underflows = 0;
+    for (&v, &c) in values.iter().zip(converted_values.iter()) {
+        overflows += (v.is_finite() && !c.is_finite()) as usize;
+        underflows += (v != 0.0 && c == 0.0) as usize;
+    }
+
+    let rel_error = match source.dims() {
+        [rows, ..] if source.rank() >= 2 && *rows > 0 => {
+            let cols = source.elem_count() / rows;
+            let weight = reference.reshape((*rows, cols))?;
+            let x = Tensor::randn(0f32, 1f32, (inputs, cols), &Device::Cpu)?;
+            let expected = x.matmul(&weight.t()?.contiguous()?)?;
+            let weight = converted.reshape((*rows, cols))?;
+            let actual = x
+                .to_dtype(converted.dtype())?
+                .matmul(&weight.t()?.contiguous()?)?
+                .to_dtype(DType::F32)?;
+            rel_l2(&expected, &actual)?
+        }
+        _ => rel_l2(&reference, &converted.to_dtype(DType::F32)?)?,
+    };
+    Ok(TensorStats {
+        rel_error,
+        overflows,
+        underflows,
+    })
+}
+
+/// Load both checkpoints one tensor at a time and compare every tensor of `source` with the
+/// one of the same name in `converted`.
+pub fn verify(
+    source: &Path,
+    converted: &Path,
+    inputs: usize,
+    tolerance: f64,
+) -> Result<VerifyReport> {
+    // Safety: the files are not modified while they are mapped.
+    let src = unsafe { MmapedSafetensors::new(source) }
+        .with_context(|| format!("Failed to open {:?}", source))?;
+    let dst = unsafe { MmapedSafetensors::new(converted) }
+        .with_context(|| format!("Failed to open {:?}", converted))?;
+    let mut names: Vec<String> = src.tensors().into_iter().map(|(name, _)| name).collect();
+    names.sort();
+
+    let mut report = VerifyReport {
+        tensors: names.len(),
+        converted_tensors: 0,
+        max_rel_error: 0.0,
+        worst_tensor: None,
+        overflows: 0,
+        underflows: 0,
+        failures: vec![],
+        passed: true,
+    };
+    let fail = |report: &mut VerifyReport, name: &str, reason: String| {
+        report.failures.push(TensorFailure {
+            name: name.to_string(),
+            reason,
+        });
+        report.passed = false;
+    };
+    for name in names.iter() {
+        let source = src.load(name, &Device::Cpu)?;
+        let converted = match dst.load(name, &Device::Cpu) {
+            Ok(t) => t,
+            Err(_) => {
+                fail(
+                    &mut report,
+                    name,
+                    "missing from the converted checkpoint".to_string(),
+                );
+                continue;
+            }
+        };
+        if source.dims() != converted.dims() {
+            let reason = format!("shape {:?} became {:?}", source.dims(), converted.dims());
+            fail(&mut report, name, reason);
+            continue;
+        }
+        if source.dtype() != converted.dtype() {
+            report.converted_tensors += 1;
+        }
+        if !source.dtype().is_float() {
+            continue;
+        }
+        let stats = compare(&source, &converted, inputs)?;
+        report.overflows += stats.overflows;
+        report.underflows += stats.underflows;
+        if stats.rel_error > report.max_rel_error || report.worst_tensor.is_none() {
+            report.max_rel_error = stats.rel_error;
+            report.worst_tensor = Some(name.clone());
+        }
+        if stats.overflows > 0 {
+            fail(
+                &mut report,
+                name,
+                format!("{} values overflow", stats.overflows),
+            );
+        } else if stats.rel_error > tolerance {
+            fail(
+                &mut report,
+                name,
+                format!("relative error {:.2e}", stats.rel_error),
+            );
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn save(dir: &Path, file: &str, tensors: &[(&str, Tensor)]) -> Result<std::path::PathBuf> {
+        let path = dir.join(file);
+        let tensors: HashMap<String, Tensor> = tensors
+            .iter()
+            .map(|(n, t)| (n.to_string(), t.clone()))
+            .collect();
+        candle_core::safetensors::save(&tensors, &path)?;
+        Ok(path)
+    }
+
+    #[test]
+    fn verify_converted_checkpoints() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sm75-verify-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let weight = Tensor::randn(0f32, 0.02, (16, 8), &Device::Cpu)?.to_dtype(DType::BF16)?;
+        let norm = Tensor::ones(8, DType::BF16, &Device::Cpu)?;
+        let ids = Tensor::new(&[1u32, 2, 3], &Device::Cpu)?;
+        let source = save(
+            &dir,
+            "source.safetensors",
+            &[
+                ("weight", weight.clone()),
+                ("norm", norm.clone()),
+                ("ids", ids.clone()),
+            ],
+        )?;
+
+        let converted = save(
+            &dir,
+            "f16.safetensors",
+            &[
+                ("weight", weight.to_dtype(DType::F16)?),
+                ("norm", norm.to_dtype(DType::F16)?),
+                ("ids", ids.clone()),
+            ],
+        )?;
+        let report = verify(&source, &converted, 4, 1e-2)?;
+        assert!(
+            report.passed,
+            "{:?}",
+            report
+                .failures
+                .iter()
+                .map(|f| &f.reason)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!((report.tensors, report.converted_tensors), (3, 2));
+        assert!(report.max_rel_error < 1e-2);
+        assert_eq!(report.overflows, 0);
+
+        // Values out of the f16 range, a reshaped tensor and a missing one all fail.
+        let large = (Tensor::ones((16, 8), DType::F32, &Device::Cpu)? * 1e6)?;
+        let source = save(
+            &dir,
+            "large.safetensors",
+            &[
+                ("weight", large.to_dtype(DType::BF16)?),
+                ("norm", norm.clone()),
+                ("ids", ids),
+            ],
+        )?;
+        let converted = save(
+            &dir,
+            "broken.safetensors",
+            &[
+                ("weight", large.to_dtype(DType::F16)?),
+                ("norm", norm.reshape((2, 4))?),
+            ],
+        )?;
+        let report = verify(&source, &converted, 4, 1e-2)?;
+        assert!(!report.passed);
+        assert_eq!(report.overflows, 16 * 8);
+        let failures: Vec<_> = report.failures.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(failures, ["ids", "norm", "weight"]);
+        assert_eq!(report.worst_tensor.as_deref(), Some("weight"));
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}